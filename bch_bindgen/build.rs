@@ -54,7 +54,12 @@ fn main() {
         .allowlist_function(".*bch2_.*")
         .allowlist_function("bcache_fs_open")
         .allowlist_function("bcache_fs_close")
+        .allowlist_function("blkdev_overlay_enable")
+        .allowlist_function("blkdev_fault_inject_add")
+        .allowlist_function("bch_mount_opts_get")
+        .allowlist_function("tools_.*")
         .allowlist_function("bio_.*")
+        .allowlist_function("__genradix_iter_peek")
         .allowlist_function("derive_passphrase")
         .allowlist_function("request_key")
         .allowlist_function("add_key")
@@ -75,6 +80,7 @@ fn main() {
         .allowlist_type("bch_.*")
         .allowlist_type("fsck_err_opts")
         .rustified_enum("fsck_err_opts")
+        .bitfield_enum("opt_flags")
         .allowlist_type("nonce")
         .no_debug("bch_replicas_padded")
         .newtype_enum("bch_kdf_types")
@@ -118,6 +124,156 @@ fn main() {
     bindings
         .write_to_file(out_dir.join("keyutils.rs"))
         .expect("Writing to output file failed for: `keyutils.rs`");
+
+    println!("cargo:rerun-if-changed=../libbcachefs/opts.h");
+    let opts_h = std::fs::read_to_string(top_dir.join("../libbcachefs/opts.h"))
+        .expect("Reading libbcachefs/opts.h failed");
+    std::fs::write(out_dir.join("opts_builder.rs"), opts_builder(&opts_h))
+        .expect("Writing to output file failed for: `opts_builder.rs`");
+}
+
+/// One entry of the BCH_OPTS() x-macro in libbcachefs/opts.h
+struct OptDesc {
+    name:    String,
+    ty:      String,
+    is_bool: bool,
+    help:    Option<String>,
+}
+
+// Split a macro argument list on commas that aren't nested in parentheses or
+// string literals
+fn split_macro_args(args: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut cur = String::new();
+    let mut depth = 0;
+    let mut in_str = false;
+    let mut prev = ' ';
+
+    for c in args.chars() {
+        match c {
+            '"' if prev != '\\' => in_str = !in_str,
+            '(' if !in_str => depth += 1,
+            ')' if !in_str => depth -= 1,
+            ',' if !in_str && depth == 0 => {
+                ret.push(cur.trim().to_owned());
+                cur.clear();
+                prev = c;
+                continue;
+            }
+            _ => {}
+        }
+        cur.push(c);
+        prev = c;
+    }
+    ret.push(cur.trim().to_owned());
+    ret
+}
+
+// Concatenate adjacent C string literals; C escapes we use are valid Rust escapes
+fn c_str_literals(s: &str) -> Option<String> {
+    let mut ret = String::new();
+    let mut in_str = false;
+    let mut prev = ' ';
+
+    for c in s.chars() {
+        if c == '"' && prev != '\\' {
+            in_str = !in_str;
+        } else if in_str {
+            ret.push(c);
+        }
+        prev = c;
+    }
+
+    if ret.is_empty() {
+        None
+    } else {
+        Some(ret)
+    }
+}
+
+fn parse_bch_opts(opts_h: &str) -> Vec<OptDesc> {
+    let start = opts_h
+        .find("#define BCH_OPTS()")
+        .expect("BCH_OPTS() not found in opts.h");
+
+    let mut body = String::new();
+    for line in opts_h[start..].lines().skip(1) {
+        body.push_str(line.trim_end_matches('\\'));
+        body.push(' ');
+        if !line.ends_with('\\') {
+            break;
+        }
+    }
+
+    let mut ret = Vec::new();
+    let mut rest = body.as_str();
+    while let Some(idx) = rest.find("x(") {
+        let entry = &rest[idx + 2..];
+        let mut depth = 0;
+        let mut in_str = false;
+        let mut prev = ' ';
+        let mut end = entry.len();
+
+        for (i, c) in entry.char_indices() {
+            match c {
+                '"' if prev != '\\' => in_str = !in_str,
+                '(' if !in_str => depth += 1,
+                ')' if !in_str && depth == 0 => {
+                    end = i;
+                    break;
+                }
+                ')' if !in_str => depth -= 1,
+                _ => {}
+            }
+            prev = c;
+        }
+
+        let args = split_macro_args(&entry[..end]);
+        assert!(args.len() == 8, "bad BCH_OPTS() entry: {}", &entry[..end]);
+
+        ret.push(OptDesc {
+            name:    args[0].clone(),
+            ty:      args[1].clone(),
+            is_bool: args[3].starts_with("OPT_BOOL"),
+            help:    c_str_literals(&args[7]),
+        });
+        rest = &entry[end..];
+    }
+    ret
+}
+
+fn opts_builder(opts_h: &str) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::from("impl OptsBuilder {\n");
+
+    for opt in parse_bch_opts(opts_h) {
+        let OptDesc {
+            name,
+            ty,
+            is_bool,
+            help,
+        } = opt;
+
+        if let Some(help) = help {
+            writeln!(out, "    #[doc = \"{}\"]", help.trim_end_matches("\\n")).unwrap();
+        }
+
+        let (arg_ty, val) = if is_bool {
+            ("bool", format!("v as {ty}"))
+        } else {
+            (ty.as_str(), "v".to_owned())
+        };
+
+        writeln!(out, "    pub fn {name}(mut self, v: {arg_ty}) -> Self {{").unwrap();
+        writeln!(out, "        self.opts.{name} = {val};").unwrap();
+        writeln!(out, "        self.opts.set_{name}_defined(1);").unwrap();
+        writeln!(out, "        self").unwrap();
+        writeln!(out, "    }}").unwrap();
+    }
+
+    out.push_str("}\n");
+    out
 }
 
 // rustc has a limitation where it does not allow structs with a "packed" attribute to contain a
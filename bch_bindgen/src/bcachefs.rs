@@ -18,6 +18,12 @@ bitfield! {
 }
 use memoffset::offset_of;
 impl bch_sb_field_crypt {
+    /// The passphrase KDF, if it's one we know
+    pub fn kdf_type(&self) -> Option<bch_kdf_types> {
+        use std::convert::TryInto;
+        let t = bch_crypt_flags(self.flags).TYPE().try_into().ok()?;
+        (t < bch_kdf_types::BCH_KDF_NR.0).then_some(bch_kdf_types(t))
+    }
     pub fn scrypt_flags(&self) -> Option<bch_scrypt_flags> {
         use std::convert::TryInto;
         match bch_kdf_types(bch_crypt_flags(self.flags).TYPE().try_into().ok()?) {
@@ -82,6 +88,12 @@ impl bch_sb_handle {
     pub fn bdev(&self) -> &block_device {
         unsafe { &*self.bdev }
     }
+
+    /// The superblock and its fields, for [`crate::sb_parse::Superblock::parse`]
+    pub fn bytes(&self) -> &[u8] {
+        let bytes = std::mem::size_of::<bch_sb>() + self.sb().u64s as usize * 8;
+        unsafe { std::slice::from_raw_parts(self.sb.cast::<u8>(), bytes) }
+    }
 }
 
 // #[repr(u8)]
@@ -3,7 +3,7 @@
 use crate::btree::BtreeIter;
 use crate::c;
 use crate::fs::Fs;
-use crate::printbuf_to_formatter;
+use crate::{printbuf_to_formatter, printbuf_to_string};
 use std::fmt;
 use std::marker::PhantomData;
 use std::mem::transmute;
@@ -63,6 +63,16 @@ impl<'a, 'b> BkeySC<'a> {
         BkeySCToText { k: self, fs }
     }
 
+    /// The key and value, formatted by bch2_bkey_val_to_text()
+    pub fn val_to_string(&self, fs: &Fs) -> String {
+        printbuf_to_string(|buf| unsafe { c::bch2_bkey_val_to_text(buf, fs.raw, self.to_raw()) })
+    }
+
+    /// Just the key (position, size, version, type), formatted by bch2_bkey_to_text()
+    pub fn key_to_string(&self) -> String {
+        printbuf_to_string(|buf| unsafe { c::bch2_bkey_to_text(buf, self.k) })
+    }
+
     pub fn v(&'a self) -> BkeyValC {
         unsafe {
             let ty: c::bch_bkey_type = transmute(self.k.type_ as u32);
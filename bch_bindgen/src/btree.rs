@@ -1,13 +1,13 @@
 use crate::bkey::BkeySC;
 use crate::c;
-use crate::errcode::{bch_errcode, errptr_to_result_c};
+use crate::errcode::{errptr_to_result_c, BchError};
 use crate::fs::Fs;
 use crate::printbuf_to_formatter;
-use crate::SPOS_MAX;
+use crate::{POS_MIN, SPOS_MAX};
 use bitflags::bitflags;
 use std::fmt;
 use std::marker::PhantomData;
-use std::mem::MaybeUninit;
+use std::mem::{size_of, MaybeUninit};
 
 pub struct BtreeTrans<'f> {
     raw: *mut c::btree_trans,
@@ -51,9 +51,18 @@ bitflags! {
     }
 }
 
+fn readahead_new(nr: u32) -> c::btree_readahead {
+    c::btree_readahead {
+        nr,
+        b: std::ptr::null_mut(),
+        prefetched_to: POS_MIN,
+    }
+}
+
 pub struct BtreeIter<'t> {
-    raw:   c::btree_iter,
-    trans: PhantomData<&'t BtreeTrans<'t>>,
+    raw:       c::btree_iter,
+    readahead: Option<c::btree_readahead>,
+    trans:     PhantomData<&'t BtreeTrans<'t>>,
 }
 
 impl<'t> BtreeIter<'t> {
@@ -75,13 +84,29 @@ impl<'t> BtreeIter<'t> {
             );
 
             BtreeIter {
-                raw:   iter.assume_init(),
-                trans: PhantomData,
+                raw:       iter.assume_init(),
+                readahead: None,
+                trans:     PhantomData,
             }
         }
     }
 
-    pub fn peek_upto<'i>(&'i mut self, end: c::bpos) -> Result<Option<BkeySC>, bch_errcode> {
+    /// Read ahead `nr` leaf nodes: for walking a whole btree offline, on
+    /// devices where the latency of each node read adds up
+    pub fn readahead(mut self, nr: u32) -> Self {
+        self.readahead = (nr > 0).then(|| readahead_new(nr));
+        self
+    }
+
+    fn do_readahead(&mut self) {
+        if let Some(ra) = &mut self.readahead {
+            unsafe { c::bch2_btree_iter_readahead(&mut self.raw, ra) }
+        }
+    }
+
+    pub fn peek_upto<'i>(&'i mut self, end: c::bpos) -> Result<Option<BkeySC>, BchError> {
+        self.do_readahead();
+
         unsafe {
             let k = c::bch2_btree_iter_peek_upto(&mut self.raw, end);
             errptr_to_result_c(k.k).map(|_| {
@@ -98,11 +123,13 @@ impl<'t> BtreeIter<'t> {
         }
     }
 
-    pub fn peek(&mut self) -> Result<Option<BkeySC>, bch_errcode> {
+    pub fn peek(&mut self) -> Result<Option<BkeySC>, BchError> {
         self.peek_upto(SPOS_MAX)
     }
 
-    pub fn peek_and_restart(&mut self) -> Result<Option<BkeySC>, bch_errcode> {
+    pub fn peek_and_restart(&mut self) -> Result<Option<BkeySC>, BchError> {
+        self.do_readahead();
+
         unsafe {
             let k = c::bch2_btree_iter_peek_and_restart_outlined(&mut self.raw);
 
@@ -125,6 +152,21 @@ impl<'t> BtreeIter<'t> {
             c::bch2_btree_iter_advance(&mut self.raw);
         }
     }
+
+    /// The leaf node the iterator is currently positioned in
+    pub fn leaf(&self) -> Option<&c::btree> {
+        unsafe {
+            let path = (*self.raw.trans).paths.add(self.raw.path as usize);
+            let b = (*path).l[0].b;
+
+            // unlocked levels hold error pointers, not nodes
+            if b.is_null() || b as usize > usize::MAX - 4095 {
+                None
+            } else {
+                Some(&*b)
+            }
+        }
+    }
 }
 
 impl<'t> Drop for BtreeIter<'t> {
@@ -134,8 +176,9 @@ impl<'t> Drop for BtreeIter<'t> {
 }
 
 pub struct BtreeNodeIter<'t> {
-    raw:   c::btree_iter,
-    trans: PhantomData<&'t BtreeTrans<'t>>,
+    raw:       c::btree_iter,
+    readahead: Option<c::btree_readahead>,
+    trans:     PhantomData<&'t BtreeTrans<'t>>,
 }
 
 impl<'t> BtreeNodeIter<'t> {
@@ -160,20 +203,37 @@ impl<'t> BtreeNodeIter<'t> {
             );
 
             BtreeNodeIter {
-                raw:   iter.assume_init(),
-                trans: PhantomData,
+                raw:       iter.assume_init(),
+                readahead: None,
+                trans:     PhantomData,
             }
         }
     }
 
-    pub fn peek<'i>(&'i mut self) -> Result<Option<&'i c::btree>, bch_errcode> {
+    /// Read ahead `nr` nodes at the iterator's depth
+    pub fn readahead(mut self, nr: u32) -> Self {
+        self.readahead = (nr > 0).then(|| readahead_new(nr));
+        self
+    }
+
+    fn do_readahead(&mut self) {
+        if let Some(ra) = &mut self.readahead {
+            unsafe { c::bch2_btree_iter_readahead(&mut self.raw, ra) }
+        }
+    }
+
+    pub fn peek<'i>(&'i mut self) -> Result<Option<&'i c::btree>, BchError> {
+        self.do_readahead();
+
         unsafe {
             let b = c::bch2_btree_iter_peek_node(&mut self.raw);
             errptr_to_result_c(b).map(|b| if !b.is_null() { Some(&*b) } else { None })
         }
     }
 
-    pub fn peek_and_restart<'i>(&'i mut self) -> Result<Option<&'i c::btree>, bch_errcode> {
+    pub fn peek_and_restart<'i>(&'i mut self) -> Result<Option<&'i c::btree>, BchError> {
+        self.do_readahead();
+
         unsafe {
             let b = c::bch2_btree_iter_peek_node_and_restart(&mut self.raw);
             errptr_to_result_c(b).map(|b| if !b.is_null() { Some(&*b) } else { None })
@@ -186,7 +246,9 @@ impl<'t> BtreeNodeIter<'t> {
         }
     }
 
-    pub fn next<'i>(&'i mut self) -> Result<Option<&'i c::btree>, bch_errcode> {
+    pub fn next<'i>(&'i mut self) -> Result<Option<&'i c::btree>, BchError> {
+        self.do_readahead();
+
         unsafe {
             let b = c::bch2_btree_iter_next_node(&mut self.raw);
             errptr_to_result_c(b).map(|b| if !b.is_null() { Some(&*b) } else { None })
@@ -210,6 +272,53 @@ impl<'b, 'f> c::btree {
     }
 }
 
+impl c::btree {
+    /// Replicas of this node on disk, as (device, sector)
+    pub fn ptrs(&self) -> Vec<(u32, u64)> {
+        let k = &self.key;
+        let key_u64s = size_of::<c::bkey>() / 8;
+        let skip = if k.k.type_ == c::bch_bkey_type::KEY_TYPE_btree_ptr_v2 as u8 {
+            size_of::<c::bch_btree_ptr_v2>() / 8
+        } else if k.k.type_ == c::bch_bkey_type::KEY_TYPE_btree_ptr as u8 {
+            0
+        } else {
+            return Vec::new();
+        };
+        let nr = (k.k.u64s as usize).saturating_sub(key_u64s + skip);
+
+        unsafe {
+            let start = (&k.v as *const c::bch_val as *const c::bch_extent_ptr).add(skip);
+
+            (0..nr)
+                .map(|i| {
+                    let p = &*start.add(i);
+                    (p.dev() as u32, p.offset())
+                })
+                .collect()
+        }
+    }
+
+    /// Journal sequence number of the bset containing the key with value `v`,
+    /// or None if it isn't in this node (e.g. it was overlaid from the journal)
+    ///
+    /// `v` is a pointer, not a key, so that it can be used with the iterator
+    /// the key came from
+    pub fn bset_journal_seq(&self, v: *const c::bch_val) -> Option<u64> {
+        let data = self.data as usize;
+        let v = v as usize;
+
+        self.set[..self.nsets as usize]
+            .iter()
+            .find(|t| {
+                data + t.data_offset as usize * 8 < v && v <= data + t.end_offset as usize * 8
+            })
+            .map(|t| unsafe {
+                let i = (data + t.data_offset as usize * 8) as *const c::bset;
+                u64::from_le((*i).journal_seq)
+            })
+    }
+}
+
 pub struct BtreeNodeToText<'b, 'f> {
     b:  &'b c::btree,
     fs: &'f Fs,
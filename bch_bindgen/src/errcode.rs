@@ -1,40 +1,131 @@
 use crate::bcachefs;
-use std::ffi::CStr;
+use std::ffi::{c_int, CStr};
 use std::fmt;
 
 pub use crate::c::bch_errcode;
 
+impl bch_errcode {
+    fn from_u32(err: u32) -> Option<bch_errcode> {
+        let start = bch_errcode::BCH_ERR_START as u32;
+        let max = bch_errcode::BCH_ERR_MAX as u32;
+
+        if err > start && err < max {
+            Some(unsafe { std::mem::transmute::<u32, bch_errcode>(err) })
+        } else {
+            None
+        }
+    }
+}
+
 impl fmt::Display for bch_errcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let s = unsafe { CStr::from_ptr(bcachefs::bch2_err_str(*self as i32)) };
-        write!(f, "{:?}", s)
+        write!(f, "{}", s.to_string_lossy())
+    }
+}
+
+impl std::error::Error for bch_errcode {}
+
+/// An error returned from libbcachefs: either one of the private bcachefs error
+/// codes from errcode.h, or a plain errno.
+///
+/// Private error codes all have a standard errno as their ultimate parent, so
+/// callers can either match on the exact code, or use [`BchError::matches`] to
+/// test for a whole class of errors (e.g. `EROFS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BchError {
+    Errcode(bch_errcode),
+    Errno(i32),
+}
+
+impl BchError {
+    /// Convert a (positive or negative) error return from C
+    pub fn from_raw(ret: c_int) -> BchError {
+        let err = ret.unsigned_abs();
+
+        match bch_errcode::from_u32(err) {
+            Some(e) => BchError::Errcode(e),
+            None => BchError::Errno(err as i32),
+        }
+    }
+
+    /// The error as a negative integer, as C code expects it
+    pub fn to_raw(&self) -> c_int {
+        match self {
+            BchError::Errcode(e) => -(*e as c_int),
+            BchError::Errno(e) => -*e,
+        }
+    }
+
+    /// The private error code, if this isn't a plain errno
+    pub fn errcode(&self) -> Option<bch_errcode> {
+        match self {
+            BchError::Errcode(e) => Some(*e),
+            BchError::Errno(_) => None,
+        }
+    }
+
+    /// The standard errno this error ultimately maps to
+    pub fn errno(&self) -> i32 {
+        match self {
+            BchError::Errcode(_) => -unsafe { bcachefs::__bch2_err_class(self.to_raw()) },
+            BchError::Errno(e) => *e,
+        }
+    }
+
+    /// Returns true if this error is `class`, or a descendent of it
+    pub fn matches(&self, class: bch_errcode) -> bool {
+        unsafe { bcachefs::__bch2_err_matches(self.to_raw(), class as c_int) }
+    }
+
+    /// Returns true if this error maps to the standard errno `errno`
+    pub fn matches_errno(&self, errno: i32) -> bool {
+        self.errno() == errno
+    }
+}
+
+impl From<bch_errcode> for BchError {
+    fn from(e: bch_errcode) -> BchError {
+        BchError::Errcode(e)
+    }
+}
+
+impl fmt::Display for BchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = unsafe { CStr::from_ptr(bcachefs::bch2_err_str(self.to_raw())) };
+        write!(f, "{}", s.to_string_lossy())
+    }
+}
+
+impl std::error::Error for BchError {}
+
+/// Convert an integer return code from C to a Result
+pub fn ret_to_result(ret: c_int) -> Result<(), BchError> {
+    if ret < 0 {
+        Err(BchError::from_raw(ret))
+    } else {
+        Ok(())
     }
 }
 
 /* Can we make a function generic over ptr constness? */
 
-pub fn errptr_to_result<T>(p: *mut T) -> Result<*mut T, bch_errcode> {
+pub fn errptr_to_result<T>(p: *mut T) -> Result<*mut T, BchError> {
     let addr = p as usize;
     let max_err: isize = -4096;
     if addr > max_err as usize {
-        let addr = addr as i32;
-        let err: bch_errcode = unsafe { std::mem::transmute(-addr) };
-        Err(err)
+        Err(BchError::from_raw(addr as isize as c_int))
     } else {
         Ok(p)
     }
 }
 
-pub fn errptr_to_result_c<T>(p: *const T) -> Result<*const T, bch_errcode> {
+pub fn errptr_to_result_c<T>(p: *const T) -> Result<*const T, BchError> {
     let addr = p as usize;
     let max_err: isize = -4096;
     if addr > max_err as usize {
-        let addr = addr as i32;
-        let err: bch_errcode = unsafe { std::mem::transmute(-addr) };
-        Err(err)
+        Err(BchError::from_raw(addr as isize as c_int))
     } else {
         Ok(p)
     }
 }
-
-impl std::error::Error for bch_errcode {}
@@ -1,5 +1,6 @@
 use crate::c;
-use crate::errcode::{bch_errcode, errptr_to_result};
+use crate::errcode::{bch_errcode, errptr_to_result, ret_to_result, BchError};
+use crate::journal::JournalIter;
 use std::ffi::CString;
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
@@ -8,8 +9,16 @@ pub struct Fs {
     pub raw: *mut c::bch_fs,
 }
 
+struct StateLockGuard(*mut libc::pthread_rwlock_t);
+
+impl Drop for StateLockGuard {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_rwlock_unlock(self.0) };
+    }
+}
+
 impl Fs {
-    pub fn open(devs: &Vec<PathBuf>, opts: c::bch_opts) -> Result<Fs, bch_errcode> {
+    pub fn open(devs: &Vec<PathBuf>, opts: c::bch_opts) -> Result<Fs, BchError> {
         let devs: Vec<_> = devs
             .iter()
             .map(|i| CString::new(i.as_os_str().as_bytes()).unwrap().into_raw())
@@ -19,10 +28,87 @@ impl Fs {
 
         errptr_to_result(ret).map(|fs| Fs { raw: fs })
     }
+
+    /// Iterate over the journal entries retained from recovery; see
+    /// [`JournalIter`]
+    pub fn journal_entries(&self) -> JournalIter<'_> {
+        JournalIter::new(self)
+    }
+
+    /// Test one of the `BCH_FS_*` state flags
+    pub fn test_flag(&self, flag: c::bch_fs_flags) -> bool {
+        unsafe { (*self.raw).flags & (1 << flag as u32) != 0 }
+    }
+
+    pub fn is_rw(&self) -> bool {
+        self.test_flag(c::bch_fs_flags::BCH_FS_rw)
+    }
+
+    /// Transition a filesystem that was opened (or has since gone) read-only to
+    /// read-write
+    pub fn reopen_rw(&self) -> Result<(), BchError> {
+        if self.is_rw() {
+            return Ok(());
+        }
+
+        let _lock = self.state_lock_write();
+        ret_to_result(unsafe { c::bch2_fs_read_write(self.raw) })?;
+        unsafe { (*self.raw).opts.read_only = 0 };
+        Ok(())
+    }
+
+    /// Flush everything and transition to read-only; the filesystem stays open
+    pub fn reopen_ro(&self) {
+        let _lock = self.state_lock_write();
+        unsafe {
+            c::bch2_fs_read_only(self.raw);
+            (*self.raw).opts.read_only = 1;
+        }
+    }
+
+    /// Take `c->state_lock` for write, as `bch2_fs_remount()` does around
+    /// read-only/read-write transitions; `down_write()` is a macro, so this
+    /// goes to the pthread lock underneath
+    fn state_lock_write(&self) -> StateLockGuard {
+        let lock: *mut libc::pthread_rwlock_t =
+            unsafe { std::ptr::addr_of_mut!((*self.raw).state_lock.lock).cast() };
+
+        unsafe { libc::pthread_rwlock_wrlock(lock) };
+        StateLockGuard(lock)
+    }
+
+    /// Shut down the filesystem, returning an error if it didn't go read-only
+    /// cleanly
+    pub fn close(self) -> Result<(), BchError> {
+        let raw = self.raw;
+        std::mem::forget(self);
+
+        unsafe { Self::stop(raw) }
+    }
+
+    unsafe fn stop(raw: *mut c::bch_fs) -> Result<(), BchError> {
+        c::__bch2_fs_stop(raw);
+
+        let flags = (*raw).flags;
+        let test = |flag: c::bch_fs_flags| flags & (1 << flag as u32) != 0;
+
+        let ret = if test(c::bch_fs_flags::BCH_FS_emergency_ro) {
+            Err(BchError::Errno(libc::EIO))
+        } else if test(c::bch_fs_flags::BCH_FS_errors_not_fixed) {
+            Err(bch_errcode::BCH_ERR_fsck_errors_not_fixed.into())
+        } else {
+            Ok(())
+        };
+
+        c::bch2_fs_free(raw);
+        ret
+    }
 }
 
 impl Drop for Fs {
     fn drop(&mut self) {
-        unsafe { c::bch2_fs_stop(self.raw) }
+        if let Err(e) = unsafe { Self::stop(self.raw) } {
+            eprintln!("error shutting down filesystem: {}", e);
+        }
     }
 }
@@ -0,0 +1,256 @@
+use crate::bkey::BkeySC;
+use crate::c;
+use crate::fs::Fs;
+use crate::printbuf_to_formatter;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+
+/// Iterator over the journal entries read in by recovery
+///
+/// The filesystem must have been opened with `retain_recovery_info` (and
+/// usually `read_journal_only`) so that the entries aren't freed after journal
+/// replay.
+pub struct JournalIter<'f> {
+    fs:   &'f Fs,
+    iter: c::genradix_iter,
+}
+
+impl<'f> JournalIter<'f> {
+    pub fn new(fs: &'f Fs) -> JournalIter<'f> {
+        JournalIter {
+            fs,
+            iter: c::genradix_iter::default(),
+        }
+    }
+}
+
+impl<'f> Iterator for JournalIter<'f> {
+    type Item = JournalEntry<'f>;
+
+    fn next(&mut self) -> Option<JournalEntry<'f>> {
+        /* GENRADIX_NODE_SIZE, from include/linux/generic-radix-tree.h: */
+        const NODE_SIZE: usize = 1 << 9;
+        const OBJ_SIZE: usize = size_of::<*mut c::journal_replay>();
+        const OBJS_PER_PAGE: usize = NODE_SIZE / OBJ_SIZE;
+
+        loop {
+            let p = unsafe {
+                c::__genradix_iter_peek(
+                    &mut self.iter,
+                    &mut (*self.fs.raw).journal_entries.tree,
+                    OBJS_PER_PAGE,
+                ) as *const *const c::journal_replay
+            };
+
+            if p.is_null() {
+                return None;
+            }
+
+            self.iter.offset += OBJ_SIZE;
+            self.iter.pos += 1;
+
+            let r = unsafe { *p };
+            if !r.is_null() {
+                return Some(JournalEntry {
+                    fs:  self.fs,
+                    raw: unsafe { &*r },
+                });
+            }
+        }
+    }
+}
+
+/// A single journal entry (`struct jset`), as read from disk
+pub struct JournalEntry<'f> {
+    fs:  &'f Fs,
+    raw: &'f c::journal_replay,
+}
+
+impl<'f> JournalEntry<'f> {
+    pub fn seq(&self) -> u64 {
+        u64::from_le(self.raw.j.seq)
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        u64::from_le(self.raw.j.last_seq)
+    }
+
+    pub fn version(&self) -> u32 {
+        u32::from_le(self.raw.j.version)
+    }
+
+    /// Entry was a flush write (JSET_NO_FLUSH not set)
+    pub fn is_flush(&self) -> bool {
+        (u32::from_le(self.raw.j.flags) >> 5) & 1 == 0
+    }
+
+    pub fn csum_good(&self) -> bool {
+        self.raw.csum_good
+    }
+
+    pub fn blacklisted(&self) -> bool {
+        self.raw.ignore_blacklisted
+            || unsafe { c::bch2_journal_seq_is_blacklisted(self.fs.raw, self.seq(), false) }
+    }
+
+    /// Size of the entry's payload, in u64s
+    pub fn u64s(&self) -> u32 {
+        u32::from_le(self.raw.j.u64s)
+    }
+
+    /// The jset_entries (btree keys, roots, log messages...) in this entry
+    pub fn entries(&self) -> JsetEntryIter<'f> {
+        unsafe {
+            let start = self.raw.j._data.as_ptr();
+            JsetEntryIter {
+                cur:     start.cast(),
+                end:     start.add(self.u64s() as usize).cast(),
+                phantom: PhantomData,
+            }
+        }
+    }
+
+    pub fn ptrs_to_text(&self) -> JournalPtrsToText<'_, 'f> {
+        JournalPtrsToText { e: self }
+    }
+}
+
+pub struct JournalPtrsToText<'e, 'f> {
+    e: &'e JournalEntry<'f>,
+}
+
+impl<'e, 'f> fmt::Display for JournalPtrsToText<'e, 'f> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        printbuf_to_formatter(f, |buf| unsafe {
+            c::bch2_journal_ptrs_to_text(
+                buf,
+                self.e.fs.raw,
+                self.e.raw as *const _ as *mut c::journal_replay,
+            )
+        })
+    }
+}
+
+pub struct JsetEntryIter<'j> {
+    cur:     *const c::jset_entry,
+    end:     *const c::jset_entry,
+    phantom: PhantomData<&'j c::jset_entry>,
+}
+
+impl<'j> Iterator for JsetEntryIter<'j> {
+    type Item = JsetEntry<'j>;
+
+    fn next(&mut self) -> Option<JsetEntry<'j>> {
+        if self.cur >= self.end {
+            return None;
+        }
+
+        let e = unsafe { &*self.cur };
+        self.cur = unsafe { e._data.as_ptr().add(u16::from_le(e.u64s) as usize).cast() };
+
+        Some(JsetEntry { raw: e })
+    }
+}
+
+/// A single `struct jset_entry` within a journal entry
+pub struct JsetEntry<'j> {
+    pub raw: &'j c::jset_entry,
+}
+
+impl<'j> JsetEntry<'j> {
+    pub fn type_(&self) -> u8 {
+        self.raw.type_
+    }
+
+    /// Btree keys, btree roots and overwrites all carry keys
+    pub fn has_keys(&self) -> bool {
+        use c::bch_jset_entry_type::*;
+
+        [
+            BCH_JSET_ENTRY_btree_keys,
+            BCH_JSET_ENTRY_btree_root,
+            BCH_JSET_ENTRY_overwrite,
+        ]
+        .iter()
+        .any(|t| *t as u8 == self.raw.type_)
+    }
+
+    /// Log entries mark the start of a new transaction commit
+    pub fn is_transaction_start(&self) -> bool {
+        self.raw.type_ == c::bch_jset_entry_type::BCH_JSET_ENTRY_log as u8 && self.raw.level == 0
+    }
+
+    pub fn btree_id(&self) -> c::btree_id {
+        unsafe { std::mem::transmute(self.raw.btree_id as u32) }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.raw.level
+    }
+
+    /// Size of the entry's payload, in u64s
+    pub fn u64s(&self) -> u16 {
+        u16::from_le(self.raw.u64s)
+    }
+
+    /// Keys in this entry; empty unless [`JsetEntry::has_keys`]
+    pub fn keys(&self) -> JsetEntryKeyIter<'j> {
+        unsafe {
+            let start = self.raw._data.as_ptr();
+            let end = if self.has_keys() {
+                start.add(self.u64s() as usize)
+            } else {
+                start
+            };
+
+            JsetEntryKeyIter {
+                cur:     start.cast(),
+                end:     end.cast(),
+                phantom: PhantomData,
+            }
+        }
+    }
+
+    pub fn to_text<'f>(&'j self, fs: &'f Fs) -> JsetEntryToText<'j, 'f> {
+        JsetEntryToText { e: self, fs }
+    }
+}
+
+pub struct JsetEntryToText<'j, 'f> {
+    e:  &'j JsetEntry<'j>,
+    fs: &'f Fs,
+}
+
+impl<'j, 'f> fmt::Display for JsetEntryToText<'j, 'f> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        printbuf_to_formatter(f, |buf| unsafe {
+            c::bch2_journal_entry_to_text(
+                buf,
+                self.fs.raw,
+                self.e.raw as *const _ as *mut c::jset_entry,
+            )
+        })
+    }
+}
+
+pub struct JsetEntryKeyIter<'j> {
+    cur:     *const c::bkey_i,
+    end:     *const c::bkey_i,
+    phantom: PhantomData<&'j c::bkey_i>,
+}
+
+impl<'j> Iterator for JsetEntryKeyIter<'j> {
+    type Item = BkeySC<'j>;
+
+    fn next(&mut self) -> Option<BkeySC<'j>> {
+        if self.cur >= self.end {
+            return None;
+        }
+
+        let k = unsafe { &*self.cur };
+        self.cur = unsafe { self.cur.cast::<u64>().add(k.k.u64s as usize).cast() };
+
+        Some(BkeySC::from(k))
+    }
+}
@@ -3,9 +3,11 @@ pub mod bkey;
 pub mod btree;
 pub mod errcode;
 pub mod fs;
+pub mod journal;
 pub mod keyutils;
 pub mod opts;
 pub mod sb_io;
+pub mod sb_parse;
 pub use paste::paste;
 
 pub mod c {
@@ -106,7 +108,7 @@ impl FromStr for c::btree_id {
     type Err = BchToolsErr;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = CString::new(s).unwrap();
+        let s = CString::new(s).map_err(|_| BchToolsErr::InvalidBtreeId)?;
         let p = s.as_ptr();
 
         let v = unsafe {
@@ -124,11 +126,19 @@ impl FromStr for c::btree_id {
     }
 }
 
+/// The names of all btrees, as accepted by [`c::btree_id::from_str`]
+pub fn btree_id_names() -> impl Iterator<Item = &'static str> {
+    (0..)
+        .map(|i| unsafe { *c::__bch2_btree_ids.as_ptr().add(i) })
+        .take_while(|p| !p.is_null())
+        .map(|p| unsafe { CStr::from_ptr(p) }.to_str().unwrap())
+}
+
 impl FromStr for c::bch_bkey_type {
     type Err = BchToolsErr;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = CString::new(s).unwrap();
+        let s = CString::new(s).map_err(|_| BchToolsErr::InvalidBkeyType)?;
         let p = s.as_ptr();
 
         let v = unsafe {
@@ -142,6 +152,17 @@ impl FromStr for c::bch_bkey_type {
     }
 }
 
+/// The name of bkey type `ty`, as accepted by [`c::bch_bkey_type::from_str`]
+pub fn bkey_type_name(ty: u8) -> String {
+    if (ty as u32) < c::bch_bkey_type::KEY_TYPE_MAX as u32 {
+        unsafe { CStr::from_ptr(*c::bch2_bkey_types.as_ptr().add(ty as usize)) }
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        format!("(unknown type {ty})")
+    }
+}
+
 impl c::printbuf {
     fn new() -> c::printbuf {
         let mut buf: c::printbuf = Default::default();
@@ -157,18 +178,76 @@ impl Drop for c::printbuf {
     }
 }
 
+impl c::printbuf {
+    /// The printbuf contents; empty if nothing was printed
+    fn as_str(&self) -> std::borrow::Cow<'_, str> {
+        if self.buf.is_null() {
+            "".into()
+        } else {
+            unsafe { CStr::from_ptr(self.buf) }.to_string_lossy()
+        }
+    }
+}
+
 impl fmt::Display for Bpos {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut buf = c::printbuf::new();
+        printbuf_to_formatter(f, |buf| unsafe { c::bch2_bpos_to_text(buf, *self) })
+    }
+}
 
-        unsafe { c::bch2_bpos_to_text(&mut buf, *self) };
+/// Format a bpos the way the kernel does
+pub fn bpos_to_string(p: Bpos) -> String {
+    printbuf_to_string(|buf| unsafe { c::bch2_bpos_to_text(buf, p) })
+}
 
-        let s = unsafe { CStr::from_ptr(buf.buf) };
-        let s = s.to_str().unwrap();
-        write!(f, "{}", s)
+/// Parses a bpos field, accepting the names bch2_bpos_to_text() prints for
+/// the maximum values
+fn bpos_field<T: FromStr>(s: &str, max_name: &str, max: T) -> Option<T> {
+    if s == max_name {
+        Some(max)
+    } else {
+        s.parse().ok()
     }
 }
 
+/// Parses the keyword form of a bpos: `inode=N offset=N snapshot=N`, separated
+/// by spaces or commas, in any order; missing fields are 0 and `max` is the
+/// field's maximum value
+fn bpos_from_keywords(s: &str) -> Option<Bpos> {
+    let mut ino = None;
+    let mut off = None;
+    let mut snp = None;
+
+    for field in s.split([' ', ',']).filter(|f| !f.is_empty()) {
+        let (name, v) = field.split_once('=')?;
+        let (slot, max) = match name {
+            "inode" => (&mut ino, u64::MAX),
+            "offset" => (&mut off, u64::MAX),
+            "snapshot" => (&mut snp, u32::MAX as u64),
+            _ => return None,
+        };
+
+        if slot.is_some() {
+            return None;
+        }
+
+        let v = if v == "max" { max } else { v.parse().ok()? };
+        if v > max {
+            return None;
+        }
+        *slot = Some(v);
+    }
+
+    Some(spos(
+        ino.unwrap_or(0),
+        off.unwrap_or(0),
+        snp.unwrap_or(0) as u32,
+    ))
+}
+
+/// Accepts everything [`bpos_to_string`] prints: `POS_MIN`, `POS_MAX`,
+/// `SPOS_MAX`, or `inode:offset[:snapshot]`, as well as the keyword form
+/// `inode=N offset=N snapshot=N`
 impl FromStr for c::bpos {
     type Err = BchToolsErr;
 
@@ -185,14 +264,20 @@ impl FromStr for c::bpos {
             return Ok(SPOS_MAX);
         }
 
+        if s.contains('=') {
+            return bpos_from_keywords(s).ok_or(BchToolsErr::InvalidBpos);
+        }
+
         let mut fields = s.split(':');
         let ino_str = fields.next().ok_or(BchToolsErr::InvalidBpos)?;
         let off_str = fields.next().ok_or(BchToolsErr::InvalidBpos)?;
         let snp_str = fields.next();
 
-        let ino: u64 = ino_str.parse().map_err(|_| BchToolsErr::InvalidBpos)?;
-        let off: u64 = off_str.parse().map_err(|_| BchToolsErr::InvalidBpos)?;
-        let snp: u32 = snp_str.map(|s| s.parse().ok()).flatten().unwrap_or(0);
+        let ino = bpos_field(ino_str, "U64_MAX", u64::MAX).ok_or(BchToolsErr::InvalidBpos)?;
+        let off = bpos_field(off_str, "U64_MAX", u64::MAX).ok_or(BchToolsErr::InvalidBpos)?;
+        let snp = snp_str
+            .and_then(|s| bpos_field(s, "U32_MAX", u32::MAX))
+            .unwrap_or(0);
 
         Ok(c::bpos {
             inode:    ino,
@@ -210,6 +295,35 @@ where
 
     func(&mut buf);
 
-    let s = unsafe { CStr::from_ptr(buf.buf) };
-    f.write_str(&s.to_string_lossy())
+    f.write_str(&buf.as_str())
+}
+
+/// Run a C `*_to_text()` function and return what it printed
+pub fn printbuf_to_string<F>(func: F) -> String
+where
+    F: FnOnce(*mut c::printbuf),
+{
+    let mut buf = c::printbuf::new();
+
+    func(&mut buf);
+
+    buf.as_str().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bpos_keywords() {
+        let p: Bpos = "inode=1234 offset=8 snapshot=max".parse().unwrap();
+        assert_eq!(p, spos(1234, 8, u32::MAX));
+
+        let p: Bpos = "offset=8,inode=1234".parse().unwrap();
+        assert_eq!(p, spos(1234, 8, 0));
+
+        assert!("inode=1 inode=2".parse::<Bpos>().is_err());
+        assert!("inode=1 snapshot=4294967296".parse::<Bpos>().is_err());
+        assert!("ino=1".parse::<Bpos>().is_err());
+    }
 }
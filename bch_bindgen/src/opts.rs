@@ -33,3 +33,67 @@ macro_rules! opt_get {
         }
     };
 }
+
+use crate::c;
+use crate::errcode::{bch_errcode, ret_to_result, BchError};
+use std::ffi::CString;
+
+/// Type-safe builder for [`c::bch_opts`]
+///
+/// A setter is generated for every option in the `BCH_OPTS()` table in
+/// libbcachefs/opts.h:
+///
+/// ```ignore
+/// let opts = Opts::new().read_only(true).degraded(true).build();
+/// ```
+#[derive(Default)]
+pub struct OptsBuilder {
+    opts: c::bch_opts,
+}
+
+pub type Opts = OptsBuilder;
+
+impl OptsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a comma separated option string, as the kernel does for mount
+    /// options (`name=value`, `name` and `noname` for booleans).
+    pub fn parse(mut self, options: &str) -> Result<Self, BchError> {
+        let options = CString::new(options)
+            .map_err(|_| BchError::Errcode(bch_errcode::BCH_ERR_option_value))?;
+        let options = options.into_raw();
+
+        let ret = unsafe {
+            let ret = c::bch2_parse_mount_opts(std::ptr::null_mut(), &mut self.opts, options);
+            drop(CString::from_raw(options));
+            ret
+        };
+
+        ret_to_result(ret).map(|_| self)
+    }
+
+    pub fn build(self) -> c::bch_opts {
+        self.opts
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/opts_builder.rs"));
+
+/// `bch2_opt_table[]`: every option's name, type, flags, allowed values and
+/// help, indexed by option id
+pub fn opt_table() -> &'static [c::bch_option] {
+    unsafe {
+        std::slice::from_raw_parts(
+            c::bch2_opt_table.as_ptr(),
+            c::bch_opt_id::bch2_opts_nr as usize,
+        )
+    }
+}
+
+/// Option id for an index into [`opt_table()`]
+pub fn opt_id(idx: usize) -> c::bch_opt_id {
+    assert!(idx < c::bch_opt_id::bch2_opts_nr as usize);
+    unsafe { std::mem::transmute(idx as u32) }
+}
@@ -1,28 +1,22 @@
 use crate::bcachefs;
 use crate::bcachefs::*;
-use crate::errcode::bch_errcode;
+use crate::errcode::{ret_to_result, BchError};
 use crate::path_to_cstr;
-use anyhow::anyhow;
 
 pub fn read_super_opts(
     path: &std::path::Path,
     mut opts: bch_opts,
-) -> anyhow::Result<bch_sb_handle> {
+) -> Result<bch_sb_handle, BchError> {
     let path = path_to_cstr(path);
     let mut sb = std::mem::MaybeUninit::zeroed();
 
     let ret =
         unsafe { crate::bcachefs::bch2_read_super(path.as_ptr(), &mut opts, sb.as_mut_ptr()) };
 
-    if ret != 0 {
-        let err: bch_errcode = unsafe { ::std::mem::transmute(ret) };
-        Err(anyhow!(err))
-    } else {
-        Ok(unsafe { sb.assume_init() })
-    }
+    ret_to_result(ret).map(|_| unsafe { sb.assume_init() })
 }
 
-pub fn read_super(path: &std::path::Path) -> anyhow::Result<bch_sb_handle> {
+pub fn read_super(path: &std::path::Path) -> Result<bch_sb_handle, BchError> {
     let opts = bcachefs::bch_opts::default();
     read_super_opts(path, opts)
 }
@@ -30,7 +24,7 @@ pub fn read_super(path: &std::path::Path) -> anyhow::Result<bch_sb_handle> {
 pub fn read_super_silent(
     path: &std::path::Path,
     mut opts: bch_opts,
-) -> anyhow::Result<bch_sb_handle> {
+) -> Result<bch_sb_handle, BchError> {
     let path = path_to_cstr(path);
     let mut sb = std::mem::MaybeUninit::zeroed();
 
@@ -38,10 +32,24 @@ pub fn read_super_silent(
         crate::bcachefs::bch2_read_super_silent(path.as_ptr(), &mut opts, sb.as_mut_ptr())
     };
 
-    if ret != 0 {
-        let err: bch_errcode = unsafe { ::std::mem::transmute(ret) };
-        Err(anyhow!(err))
-    } else {
-        Ok(unsafe { sb.assume_init() })
-    }
+    ret_to_result(ret).map(|_| unsafe { sb.assume_init() })
+}
+
+/// Read the raw superblock at [`BCH_SB_SECTOR`](crate::sb_parse::BCH_SB_SECTOR)
+/// without going through libbcachefs, for parsing with
+/// [`Superblock`](crate::sb_parse::Superblock)
+pub fn read_super_raw(path: &std::path::Path) -> std::io::Result<Vec<u8>> {
+    use crate::sb_parse::{BCH_SB_SECTOR, SB_HEADER_BYTES};
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut f = std::fs::File::open(path)?;
+    f.seek(SeekFrom::Start(BCH_SB_SECTOR << 9))?;
+
+    let mut buf = vec![0; SB_HEADER_BYTES];
+    f.read_exact(&mut buf)?;
+
+    let u64s = u32::from_le_bytes([buf[124], buf[125], buf[126], buf[127]]) as usize;
+    buf.resize(SB_HEADER_BYTES + u64s * 8, 0);
+    f.read_exact(&mut buf[SB_HEADER_BYTES..])?;
+    Ok(buf)
 }
@@ -0,0 +1,813 @@
+//! Superblock parsing in pure Rust
+//!
+//! Unlike [`crate::sb_io`], this doesn't go through libbcachefs: it only
+//! depends on `core`, never allocates, and validates every offset against the
+//! buffer it was given, so it's suitable for probing devices quickly and for
+//! feeding untrusted input. Checksums are not verified.
+
+use core::fmt;
+
+/// Sector the primary superblock lives at
+pub const BCH_SB_SECTOR: u64 = 8;
+/// Sector the superblock layout (list of superblock copies) lives at
+pub const BCH_SB_LAYOUT_SECTOR: u64 = 7;
+
+pub const BCACHE_MAGIC: [u8; 16] = [
+    0xc6, 0x85, 0x73, 0xf6, 0x4e, 0x1a, 0x45, 0xca, 0x82, 0x65, 0xf5, 0x7f, 0x48, 0xba, 0x6d, 0x81,
+];
+pub const BCHFS_MAGIC: [u8; 16] = [
+    0xc6, 0x85, 0x73, 0xf6, 0x66, 0xce, 0x90, 0xa9, 0xd9, 0x6a, 0x60, 0xcf, 0x80, 0x3d, 0xf7, 0xef,
+];
+
+/// Size of struct bch_sb, not including variable length fields
+pub const SB_HEADER_BYTES: usize = 752;
+const SB_LABEL_SIZE: usize = 32;
+const SB_LAYOUT_OFFSET: usize = 240;
+const SB_LAYOUT_MAX: usize = 61;
+
+const MEMBER_V1_BYTES: usize = 56;
+const MEMBER_ERROR_NR: usize = 3;
+
+const DISK_GROUP_BYTES: usize = 48;
+
+/* enum bch_sb_field_type: */
+pub const BCH_SB_FIELD_MEMBERS_V1: u32 = 1;
+pub const BCH_SB_FIELD_CRYPT: u32 = 2;
+pub const BCH_SB_FIELD_DISK_GROUPS: u32 = 5;
+pub const BCH_SB_FIELD_CLEAN: u32 = 6;
+pub const BCH_SB_FIELD_REPLICAS: u32 = 7;
+pub const BCH_SB_FIELD_COUNTERS: u32 = 10;
+pub const BCH_SB_FIELD_MEMBERS_V2: u32 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbParseError {
+    /// Buffer is smaller than what the superblock says it contains
+    TooShort {
+        need: usize,
+        have: usize,
+    },
+    BadMagic,
+    /// A field's size is zero or runs past the end of the superblock
+    BadField {
+        offset: usize,
+    },
+    /// A field is too small for its type
+    FieldTooShort {
+        field_type: u32,
+    },
+}
+
+impl fmt::Display for SbParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SbParseError::TooShort { need, have } => {
+                write!(
+                    f,
+                    "superblock truncated: need {} bytes, have {}",
+                    need, have
+                )
+            }
+            SbParseError::BadMagic => write!(f, "not a bcachefs superblock"),
+            SbParseError::BadField { offset } => {
+                write!(f, "invalid superblock field at offset {}", offset)
+            }
+            SbParseError::FieldTooShort { field_type } => {
+                write!(f, "superblock field type {} too small", field_type)
+            }
+        }
+    }
+}
+
+/* the only dependency on std: */
+impl std::error::Error for SbParseError {}
+
+type Result<T> = core::result::Result<T, SbParseError>;
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    let mut v = [0; 4];
+    v.copy_from_slice(&b[off..off + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn le64(b: &[u8], off: usize) -> u64 {
+    let mut v = [0; 8];
+    v.copy_from_slice(&b[off..off + 8]);
+    u64::from_le_bytes(v)
+}
+
+fn uuid_at(b: &[u8], off: usize) -> [u8; 16] {
+    let mut v = [0; 16];
+    v.copy_from_slice(&b[off..off + 16]);
+    v
+}
+
+fn bits(v: u64, start: u32, end: u32) -> u64 {
+    (v >> start) & (!0u64 >> (64 - (end - start)))
+}
+
+/// A parsed (and bounds checked) `struct bch_sb`
+#[derive(Clone, Copy)]
+pub struct Superblock<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Superblock<'a> {
+    /// Parse a superblock from `buf`, which must start at the beginning of the
+    /// superblock and contain all of its fields.
+    pub fn parse(buf: &'a [u8]) -> Result<Superblock<'a>> {
+        if buf.len() < SB_HEADER_BYTES {
+            return Err(SbParseError::TooShort {
+                need: SB_HEADER_BYTES,
+                have: buf.len(),
+            });
+        }
+
+        let magic = uuid_at(buf, 24);
+        if magic != BCHFS_MAGIC && magic != BCACHE_MAGIC {
+            return Err(SbParseError::BadMagic);
+        }
+
+        let bytes = SB_HEADER_BYTES + le32(buf, 124) as usize * 8;
+        if buf.len() < bytes {
+            return Err(SbParseError::TooShort {
+                need: bytes,
+                have: buf.len(),
+            });
+        }
+
+        let sb = Superblock { buf: &buf[..bytes] };
+
+        for f in sb.fields() {
+            f?;
+        }
+
+        Ok(sb)
+    }
+
+    /// Total size of the superblock, including fields
+    pub fn bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn version(&self) -> u16 {
+        le16(self.buf, 16)
+    }
+
+    pub fn version_min(&self) -> u16 {
+        le16(self.buf, 18)
+    }
+
+    pub fn magic(&self) -> [u8; 16] {
+        uuid_at(self.buf, 24)
+    }
+
+    /// Internal UUID
+    pub fn uuid(&self) -> [u8; 16] {
+        uuid_at(self.buf, 40)
+    }
+
+    /// UUID exposed to userspace (blkid, /dev/disk/by-uuid)
+    pub fn user_uuid(&self) -> [u8; 16] {
+        uuid_at(self.buf, 56)
+    }
+
+    /// Filesystem label, without trailing nuls
+    pub fn label(&self) -> &'a [u8] {
+        let label = &self.buf[72..72 + SB_LABEL_SIZE];
+        let len = label.iter().position(|c| *c == 0).unwrap_or(label.len());
+        &label[..len]
+    }
+
+    /// Sector this copy of the superblock was written to
+    pub fn offset(&self) -> u64 {
+        le64(self.buf, 104)
+    }
+
+    pub fn seq(&self) -> u64 {
+        le64(self.buf, 112)
+    }
+
+    /// Block size, in 512 byte sectors
+    pub fn block_size(&self) -> u16 {
+        le16(self.buf, 120)
+    }
+
+    pub fn dev_idx(&self) -> u8 {
+        self.buf[122]
+    }
+
+    pub fn nr_devices(&self) -> u8 {
+        self.buf[123]
+    }
+
+    pub fn time_base_lo(&self) -> u64 {
+        le64(self.buf, 128)
+    }
+
+    pub fn time_base_hi(&self) -> u32 {
+        le32(self.buf, 136)
+    }
+
+    pub fn time_precision(&self) -> u32 {
+        le32(self.buf, 140)
+    }
+
+    /// `flags[i]`, for i in 0..7
+    pub fn flags(&self, i: usize) -> u64 {
+        le64(self.buf, 144 + i * 8)
+    }
+
+    pub fn write_time(&self) -> u64 {
+        le64(self.buf, 200)
+    }
+
+    pub fn features(&self, i: usize) -> u64 {
+        le64(self.buf, 208 + i * 8)
+    }
+
+    pub fn compat(&self, i: usize) -> u64 {
+        le64(self.buf, 224 + i * 8)
+    }
+
+    pub fn initialized(&self) -> bool {
+        bits(self.flags(0), 0, 1) != 0
+    }
+
+    /// Filesystem was shut down cleanly
+    pub fn clean(&self) -> bool {
+        bits(self.flags(0), 1, 2) != 0
+    }
+
+    pub fn layout(&self) -> SbLayout<'a> {
+        SbLayout {
+            buf: &self.buf[SB_LAYOUT_OFFSET..SB_HEADER_BYTES],
+        }
+    }
+
+    /// Iterate over the variable length fields; each item is checked to lie
+    /// within the superblock
+    pub fn fields(&self) -> SbFieldIter<'a> {
+        SbFieldIter {
+            buf: self.buf,
+            pos: SB_HEADER_BYTES,
+        }
+    }
+
+    pub fn field(&self, field_type: u32) -> Option<SbField<'a>> {
+        self.fields()
+            .filter_map(|f| f.ok())
+            .find(|f| f.field_type() == field_type)
+    }
+
+    /// Member devices, from members_v2 or, on older filesystems, members_v1
+    pub fn members(&self) -> Result<Option<MemberIter<'a>>> {
+        if let Some(f) = self.field(BCH_SB_FIELD_MEMBERS_V2) {
+            let data = f.data();
+            if data.len() < 8 {
+                return Err(SbParseError::FieldTooShort {
+                    field_type: BCH_SB_FIELD_MEMBERS_V2,
+                });
+            }
+
+            let member_bytes = le16(data, 0) as usize;
+            if member_bytes < MEMBER_V1_BYTES {
+                return Err(SbParseError::FieldTooShort {
+                    field_type: BCH_SB_FIELD_MEMBERS_V2,
+                });
+            }
+
+            return Ok(Some(MemberIter {
+                buf: &data[8..],
+                member_bytes,
+                idx: 0,
+            }));
+        }
+
+        Ok(self.field(BCH_SB_FIELD_MEMBERS_V1).map(|f| MemberIter {
+            buf:          f.data(),
+            member_bytes: MEMBER_V1_BYTES,
+            idx:          0,
+        }))
+    }
+
+    pub fn crypt(&self) -> Result<Option<SbCrypt<'a>>> {
+        self.field(BCH_SB_FIELD_CRYPT)
+            .map(|f| {
+                if f.data().len() < 56 {
+                    Err(SbParseError::FieldTooShort {
+                        field_type: BCH_SB_FIELD_CRYPT,
+                    })
+                } else {
+                    Ok(SbCrypt { buf: f.data() })
+                }
+            })
+            .transpose()
+    }
+
+    /// Disk groups: the parts of device label paths, indexed by
+    /// [`Member::group`] - 1
+    pub fn disk_groups(&self) -> Option<DiskGroupIter<'a>> {
+        self.field(BCH_SB_FIELD_DISK_GROUPS).map(|f| DiskGroupIter {
+            buf: f.data(),
+            idx: 0,
+        })
+    }
+
+    pub fn replicas(&self) -> Option<ReplicasIter<'a>> {
+        self.field(BCH_SB_FIELD_REPLICAS)
+            .map(|f| ReplicasIter { buf: f.data() })
+    }
+
+    pub fn clean_section(&self) -> Result<Option<SbClean<'a>>> {
+        self.field(BCH_SB_FIELD_CLEAN)
+            .map(|f| {
+                if f.data().len() < 16 {
+                    Err(SbParseError::FieldTooShort {
+                        field_type: BCH_SB_FIELD_CLEAN,
+                    })
+                } else {
+                    Ok(SbClean { buf: f.data() })
+                }
+            })
+            .transpose()
+    }
+
+    /// Persistent counters, indexed by `enum bch_persistent_counters`
+    pub fn counters(&self) -> Option<impl Iterator<Item = u64> + 'a> {
+        self.field(BCH_SB_FIELD_COUNTERS)
+            .map(|f| f.data().chunks_exact(8).map(|c| le64(c, 0)))
+    }
+}
+
+/// `struct bch_sb_layout`: where the superblock copies live
+#[derive(Clone, Copy)]
+pub struct SbLayout<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SbLayout<'a> {
+    pub fn magic(&self) -> [u8; 16] {
+        uuid_at(self.buf, 0)
+    }
+
+    pub fn layout_type(&self) -> u8 {
+        self.buf[16]
+    }
+
+    /// log2 of the maximum superblock size, in 512 byte sectors
+    pub fn sb_max_size_bits(&self) -> u8 {
+        self.buf[17]
+    }
+
+    pub fn nr_superblocks(&self) -> u8 {
+        self.buf[18]
+    }
+
+    /// Sector offsets of each superblock copy
+    pub fn sb_offsets(&self) -> impl Iterator<Item = u64> + 'a {
+        let buf = self.buf;
+        let nr = (self.nr_superblocks() as usize).min(SB_LAYOUT_MAX);
+        (0..nr).map(move |i| le64(buf, 24 + i * 8))
+    }
+
+    /// Parse a standalone layout, as stored at [`BCH_SB_LAYOUT_SECTOR`]
+    pub fn parse(buf: &'a [u8]) -> Result<SbLayout<'a>> {
+        let bytes = SB_HEADER_BYTES - SB_LAYOUT_OFFSET;
+        if buf.len() < bytes {
+            return Err(SbParseError::TooShort {
+                need: bytes,
+                have: buf.len(),
+            });
+        }
+
+        let magic = uuid_at(buf, 0);
+        if magic != BCHFS_MAGIC && magic != BCACHE_MAGIC {
+            return Err(SbParseError::BadMagic);
+        }
+
+        Ok(SbLayout { buf: &buf[..bytes] })
+    }
+}
+
+/// A variable length superblock field (`struct bch_sb_field`)
+#[derive(Clone, Copy)]
+pub struct SbField<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SbField<'a> {
+    pub fn field_type(&self) -> u32 {
+        le32(self.buf, 4)
+    }
+
+    /// The whole field, including the 8 byte header
+    pub fn bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// Field contents after the header
+    pub fn data(&self) -> &'a [u8] {
+        &self.buf[8..]
+    }
+}
+
+pub struct SbFieldIter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for SbFieldIter<'a> {
+    type Item = Result<SbField<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+
+        let offset = self.pos;
+        let bad = || Some(Err(SbParseError::BadField { offset }));
+
+        if self.buf.len() - offset < 8 {
+            self.pos = self.buf.len();
+            return bad();
+        }
+
+        let bytes = le32(self.buf, offset) as usize * 8;
+        if bytes < 8 || bytes > self.buf.len() - offset {
+            self.pos = self.buf.len();
+            return bad();
+        }
+
+        self.pos += bytes;
+        Some(Ok(SbField {
+            buf: &self.buf[offset..offset + bytes],
+        }))
+    }
+}
+
+/// `struct bch_member`
+#[derive(Clone, Copy)]
+pub struct Member<'a> {
+    buf: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> Member<'a> {
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        uuid_at(self.buf, 0)
+    }
+
+    /// Member slots with a zero uuid are unused
+    pub fn exists(&self) -> bool {
+        self.uuid() != [0; 16]
+    }
+
+    pub fn nbuckets(&self) -> u64 {
+        le64(self.buf, 16)
+    }
+
+    pub fn first_bucket(&self) -> u16 {
+        le16(self.buf, 24)
+    }
+
+    /// Bucket size, in 512 byte sectors
+    pub fn bucket_size(&self) -> u16 {
+        le16(self.buf, 26)
+    }
+
+    pub fn last_mount(&self) -> u64 {
+        le64(self.buf, 32)
+    }
+
+    pub fn flags(&self) -> u64 {
+        le64(self.buf, 40)
+    }
+
+    /// `enum bch_member_state`: rw, ro, failed, spare
+    pub fn state(&self) -> u8 {
+        bits(self.flags(), 0, 4) as u8
+    }
+
+    pub fn discard(&self) -> bool {
+        bits(self.flags(), 14, 15) != 0
+    }
+
+    /// Bitmask of data types allowed on this device
+    pub fn data_allowed(&self) -> u8 {
+        bits(self.flags(), 15, 20) as u8
+    }
+
+    /// Disk group (label) index + 1; 0 means none
+    pub fn group(&self) -> u8 {
+        bits(self.flags(), 20, 28) as u8
+    }
+
+    pub fn durability(&self) -> u8 {
+        let d = bits(self.flags(), 28, 30) as u8;
+        if d == 0 {
+            1
+        } else {
+            d - 1
+        }
+    }
+
+    /// Read, write and checksum error counts; members_v1 doesn't have these
+    pub fn errors(&self) -> Option<[u64; MEMBER_ERROR_NR]> {
+        if self.buf.len() < 88 {
+            return None;
+        }
+
+        Some([le64(self.buf, 64), le64(self.buf, 72), le64(self.buf, 80)])
+    }
+
+    pub fn seq(&self) -> Option<u64> {
+        if self.buf.len() < 128 {
+            return None;
+        }
+
+        Some(le64(self.buf, 120))
+    }
+}
+
+pub struct MemberIter<'a> {
+    buf:          &'a [u8],
+    member_bytes: usize,
+    idx:          usize,
+}
+
+impl<'a> Iterator for MemberIter<'a> {
+    type Item = Member<'a>;
+
+    fn next(&mut self) -> Option<Member<'a>> {
+        let start = self.idx * self.member_bytes;
+        if self.buf.len() < start + self.member_bytes {
+            return None;
+        }
+
+        let m = Member {
+            buf: &self.buf[start..start + self.member_bytes],
+            idx: self.idx,
+        };
+        self.idx += 1;
+        Some(m)
+    }
+}
+
+/// `struct bch_disk_group`: one part of a label path, `fast` in `ssd.fast`
+#[derive(Clone, Copy)]
+pub struct DiskGroup<'a> {
+    buf: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> DiskGroup<'a> {
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    /// Not NUL terminated if it's the full 32 bytes
+    pub fn label(&self) -> &'a [u8] {
+        let label = &self.buf[..SB_LABEL_SIZE];
+        let len = label.iter().position(|&c| c == 0).unwrap_or(SB_LABEL_SIZE);
+        &label[..len]
+    }
+
+    pub fn flags(&self) -> u64 {
+        le64(self.buf, SB_LABEL_SIZE)
+    }
+
+    /// Deleted groups are free slots
+    pub fn deleted(&self) -> bool {
+        bits(self.flags(), 0, 1) != 0
+    }
+
+    /// Parent group index + 1; 0 for a top level label
+    pub fn parent(&self) -> usize {
+        bits(self.flags(), 6, 24) as usize
+    }
+}
+
+pub struct DiskGroupIter<'a> {
+    buf: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> Iterator for DiskGroupIter<'a> {
+    type Item = DiskGroup<'a>;
+
+    fn next(&mut self) -> Option<DiskGroup<'a>> {
+        let start = self.idx * DISK_GROUP_BYTES;
+        if self.buf.len() < start + DISK_GROUP_BYTES {
+            return None;
+        }
+
+        let g = DiskGroup {
+            buf: &self.buf[start..start + DISK_GROUP_BYTES],
+            idx: self.idx,
+        };
+        self.idx += 1;
+        Some(g)
+    }
+}
+
+/// `struct bch_sb_field_crypt`
+#[derive(Clone, Copy)]
+pub struct SbCrypt<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SbCrypt<'a> {
+    pub fn flags(&self) -> u64 {
+        le64(self.buf, 0)
+    }
+
+    /// `enum bch_kdf_types`
+    pub fn kdf_type(&self) -> u8 {
+        bits(self.flags(), 0, 4) as u8
+    }
+
+    pub fn kdf_flags(&self) -> u64 {
+        le64(self.buf, 8)
+    }
+
+    /// scrypt N, r, p parameters
+    pub fn scrypt_params(&self) -> (u64, u64, u64) {
+        let f = self.kdf_flags();
+        (bits(f, 0, 16), bits(f, 16, 32), bits(f, 32, 48))
+    }
+
+    /// `struct bch_encrypted_key`: magic followed by the 256 bit key
+    pub fn encrypted_key(&self) -> &'a [u8] {
+        &self.buf[16..56]
+    }
+}
+
+/// `struct bch_replicas_entry_v1`
+#[derive(Clone, Copy)]
+pub struct ReplicasEntry<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ReplicasEntry<'a> {
+    /// `enum bch_data_type`
+    pub fn data_type(&self) -> u8 {
+        self.buf[0]
+    }
+
+    pub fn nr_required(&self) -> u8 {
+        self.buf[2]
+    }
+
+    pub fn devs(&self) -> &'a [u8] {
+        &self.buf[3..]
+    }
+}
+
+pub struct ReplicasIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for ReplicasIter<'a> {
+    type Item = ReplicasEntry<'a>;
+
+    fn next(&mut self) -> Option<ReplicasEntry<'a>> {
+        /* entries are packed; the field is padded out with zeroes */
+        if self.buf.len() < 3 || self.buf[0] == 0 {
+            return None;
+        }
+
+        let bytes = 3 + self.buf[1] as usize;
+        if bytes > self.buf.len() {
+            return None;
+        }
+
+        let (e, rest) = self.buf.split_at(bytes);
+        self.buf = rest;
+        Some(ReplicasEntry { buf: e })
+    }
+}
+
+/// `struct bch_sb_field_clean`
+#[derive(Clone, Copy)]
+pub struct SbClean<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SbClean<'a> {
+    pub fn flags(&self) -> u32 {
+        le32(self.buf, 0)
+    }
+
+    /// Journal sequence number at clean shutdown
+    pub fn journal_seq(&self) -> u64 {
+        le64(self.buf, 8)
+    }
+
+    /// Raw jset_entries (btree roots, usage) stored at clean shutdown
+    pub fn entries(&self) -> &'a [u8] {
+        &self.buf[16..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_sb(fields: &[(u32, &[u8])]) -> [u8; 4096] {
+        let mut buf = [0u8; 4096];
+        buf[24..40].copy_from_slice(&BCHFS_MAGIC);
+        buf[72..76].copy_from_slice(b"test");
+        buf[123] = 1;
+
+        let mut pos = SB_HEADER_BYTES;
+        for (ty, data) in fields {
+            let u64s = (8 + data.len() + 7) / 8;
+            buf[pos..pos + 4].copy_from_slice(&(u64s as u32).to_le_bytes());
+            buf[pos + 4..pos + 8].copy_from_slice(&ty.to_le_bytes());
+            buf[pos + 8..pos + 8 + data.len()].copy_from_slice(data);
+            pos += u64s * 8;
+        }
+
+        let u64s = ((pos - SB_HEADER_BYTES) / 8) as u32;
+        buf[124..128].copy_from_slice(&u64s.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn parse_minimal() {
+        let buf = test_sb(&[]);
+        let sb = Superblock::parse(&buf).unwrap();
+
+        assert_eq!(sb.label(), b"test");
+        assert_eq!(sb.nr_devices(), 1);
+        assert_eq!(sb.fields().count(), 0);
+        assert!(sb.members().unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_members_v2() {
+        let mut members = [0u8; 8 + 2 * 144];
+        members[0..2].copy_from_slice(&144u16.to_le_bytes());
+        members[8] = 1;
+        members[8 + 26..8 + 28].copy_from_slice(&1024u16.to_le_bytes());
+
+        let buf = test_sb(&[(BCH_SB_FIELD_MEMBERS_V2, &members)]);
+        let sb = Superblock::parse(&buf).unwrap();
+        let m: Vec<_> = sb.members().unwrap().unwrap().collect();
+
+        assert_eq!(m.len(), 2);
+        assert!(m[0].exists());
+        assert_eq!(m[0].bucket_size(), 1024);
+        assert!(!m[1].exists());
+    }
+
+    #[test]
+    fn parse_disk_groups() {
+        let mut groups = [0u8; 2 * DISK_GROUP_BYTES];
+        groups[0..3].copy_from_slice(b"ssd");
+        groups[48..52].copy_from_slice(b"fast");
+        groups[48 + 32..48 + 40].copy_from_slice(&(1u64 << 6).to_le_bytes());
+
+        let buf = test_sb(&[(BCH_SB_FIELD_DISK_GROUPS, &groups)]);
+        let sb = Superblock::parse(&buf).unwrap();
+        let g: Vec<_> = sb.disk_groups().unwrap().collect();
+
+        assert_eq!(g.len(), 2);
+        assert_eq!(g[0].label(), b"ssd");
+        assert_eq!(g[0].parent(), 0);
+        assert_eq!(g[1].label(), b"fast");
+        assert_eq!(g[1].parent(), 1);
+    }
+
+    #[test]
+    fn reject_bad_input() {
+        let mut buf = test_sb(&[(BCH_SB_FIELD_COUNTERS, &[0; 16])]);
+        assert_eq!(
+            Superblock::parse(&buf[..100]).err(),
+            Some(SbParseError::TooShort {
+                need: SB_HEADER_BYTES,
+                have: 100,
+            })
+        );
+
+        /* field claiming to be bigger than the superblock: */
+        buf[SB_HEADER_BYTES..SB_HEADER_BYTES + 4].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(
+            Superblock::parse(&buf).err(),
+            Some(SbParseError::BadField {
+                offset: SB_HEADER_BYTES,
+            })
+        );
+
+        buf[24] = 0;
+        assert_eq!(Superblock::parse(&buf).err(), Some(SbParseError::BadMagic));
+    }
+}
@@ -28,19 +28,37 @@
 void bcachefs_usage(void)
 {
 	puts("bcachefs - tool for managing bcachefs filesystems\n"
-	     "usage: bcachefs <command> [<args>]\n"
+	     "usage: bcachefs [--image[=dir]] [--units=units] [--fault-inject=spec]\n"
+	     "                <command> [<args>]\n"
+	     "\n"
+	     "Global options:\n"
+	     "  --image[=dir]            Don't write to devices: writes go to a copy on write\n"
+	     "                           overlay, in memory or in sparse files in dir\n"
+	     "  --units=units            Print sizes in B, KiB, MiB, GiB or TiB, or human\n"
+	     "                           readable in powers of 1024 (human) or 1000 (si)\n"
+	     "  --fault-inject=spec      Fail or delay IO to part of a device, for testing:\n"
+	     "                           [device:]offset[+len][,eio][,delay=ms][,reads][,writes]\n"
 	     "\n"
 	     "Superblock commands:\n"
 	     "  format                   Format a new filesystem\n"
 	     "  show-super               Dump superblock information to stdout\n"
+	     "  probe                    Identify a member device, for udev\n"
 	     "  set-option               Set a filesystem option\n"
+	     "  options                  List options, their allowed values and defaults\n"
 	     "  reset-counters           Reset all counters on an unmounted device\n"
+	     "  compat                   Check the filesystem version against the kernel and tools\n"
+	     "  version-upgrade          Upgrade or downgrade the on disk version, offline\n"
 	     "\n"
 	     "Mount:\n"
 	     "  mount                    Mount a filesystem\n"
+	     "  initramfs                List what's needed to mount a filesystem at boot\n"
 	     "\n"
 	     "Repair:\n"
 	     "  fsck                     Check an existing filesystem for errors\n"
+	     "  salvage                  Copy out as much as possible of a damaged filesystem\n"
+	     "  strip-alloc              Drop and rebuild allocation information, offline\n"
+	     "  undelete                 Restore recently deleted files, from the journal\n"
+	     "  history                  Show the destructive commands run on a filesystem\n"
 	     "\n"
 #if 0
 	     "Startup/shutdown, assembly of multi device filesystems:\n"
@@ -52,6 +70,17 @@ void bcachefs_usage(void)
 #endif
 	     "Commands for managing a running filesystem:\n"
 	     "  fs usage                 Show disk usage\n"
+	     "  fs accounting            Show usage by replica set and compression type\n"
+	     "  fs audit-options         Find files not matching their directory's options\n"
+	     "  fs top-files             List the files using the most space (unmounted)\n"
+	     "  fs du                    Show directory sizes, as a list, tree or browser (unmounted)\n"
+	     "  fs orphans               List and delete unlinked inodes (unmounted)\n"
+	     "  fs resize                Resize the devices of a mounted filesystem\n"
+	     "  fs latency               Show latency statistics\n"
+	     "  fs counters              Show event counters, or their rates\n"
+	     "  fs gc                    Run garbage collection, and report buckets freed\n"
+	     "  status                   Summarize filesystem health\n"
+	     "  exporter                 Serve filesystem metrics for Prometheus\n"
 	     "\n"
 	     "Commands for managing devices within a running filesystem:\n"
 	     "  device add               Add a new device to an existing filesystem\n"
@@ -59,9 +88,18 @@ void bcachefs_usage(void)
 	     "  device online            Re-add an existing member to a filesystem\n"
 	     "  device offline           Take a device offline, without removing it\n"
 	     "  device evacuate          Migrate data off of a specific device\n"
+	     "  device replace           Replace a device with a new one\n"
 	     "  device set-state         Mark a device as failed\n"
+	     "  device set-label         Set a device's label\n"
 	     "  device resize            Resize filesystem on a device\n"
 	     "  device resize-journal    Resize journal on a device\n"
+	     "  device locate            Find the disk a member device is on\n"
+	     "  device scan              Check a device for unreadable data\n"
+	     "  device trim              Discard free buckets\n"
+	     "  journal resize           Grow the journal on a device\n"
+	     "  journal move             Move the journal to another device (unmounted)\n"
+	     "  label list               Show device labels, and the targets using them\n"
+	     "  label rename             Rename or move a device label\n"
 	     "\n"
 	     "Commands for managing subvolumes and snapshots:\n"
 	     "  subvolume create         Create a new subvolume\n"
@@ -70,31 +108,55 @@ void bcachefs_usage(void)
 	     "\n"
 	     "Commands for managing filesystem data:\n"
 	     "  data rereplicate         Rereplicate degraded data\n"
+	     "  data rewrite             Change the checksum or compression type of data (unmounted)\n"
 	     "  data job                 Kick off low level data jobs\n"
+	     "  ec status                Show erasure coding status and degraded stripes\n"
+	     "  ec repair                Reconstruct data in degraded stripes\n"
+	     "\n"
+	     "Commands for managing quotas:\n"
+	     "  quota show               Show quota usage and limits\n"
+	     "  quota set                Set quota limits\n"
+	     "  quota project            Set project IDs, recursively\n"
+	     "  quota rescan             Recount quota usage and check it matches\n"
 	     "\n"
 	     "Encryption:\n"
 	     "  unlock                   Unlock an encrypted filesystem prior to running/mounting\n"
+	     "  lock                     Remove an unmounted filesystem's key from the keyring\n"
 	     "  set-passphrase           Change passphrase on an existing (unmounted) filesystem\n"
 	     "  remove-passphrase        Remove passphrase on an existing (unmounted) filesystem\n"
 	     "\n"
 	     "Migrate:\n"
 	     "  migrate                  Migrate an existing filesystem to bcachefs, in place\n"
 	     "  migrate-superblock       Add default superblock, after bcachefs migrate\n"
+	     "  migrate rollback         Restore the filesystem that was migrated from\n"
 	     "\n"
 	     "Commands for operating on files in a bcachefs filesystem:\n"
 	     "  setattr                  Set various per file attributes\n"
+	     "  getattr                  Show per file attributes\n"
+	     "  attr get                 Show options set on files, as name=value\n"
+	     "  attr set                 Set or remove options on files, after checking them\n"
+	     "  nocow set                Set or clear nocow mode on files\n"
+	     "  nocow status             Check if nocow data can be written in place\n"
+	     "  cache drop               Drop cached copies of data from the promote target\n"
+	     "  cache pin                Keep files' data on the promote target\n"
+	     "  cp                       Copy files, sharing data with reflink\n"
+	     "  dedupe                   Deduplicate identical data\n"
 	     "\n"
 	     "Debug:\n"
 	     "These commands work on offline, unmounted filesystems\n"
 	     "  dump                     Dump filesystem metadata to a qcow2 image\n"
 	     "  list                     List filesystem metadata in textual form\n"
 	     "  list_journal             List contents of journal\n"
+	     "  journal-stats            Print statistics about the journal\n"
+	     "  check-nodes              Verify every replica of every btree node\n"
+	     "  verify                   Verify the checksums of every replica of a file's data\n"
 	     "\n"
 	     "FUSE:\n"
 	     "  fusemount                Mount a filesystem via FUSE\n"
 	     "\n"
 	     "Miscellaneous:\n"
          "  completions              Generate shell completions\n"
+	     "  bench                    Benchmark the userspace IO paths\n"
 	     "  version                  Display the version of the invoked bcachefs tool\n");
 }
 
@@ -119,6 +181,24 @@ int fs_cmds(int argc, char *argv[])
 	}
 	if (!strcmp(cmd, "usage"))
 		return cmd_fs_usage(argc, argv);
+	if (!strcmp(cmd, "accounting"))
+		return cmd_fs_accounting(argc, argv);
+	if (!strcmp(cmd, "audit-options"))
+		return cmd_fs_audit_options(argc, argv);
+	if (!strcmp(cmd, "top-files"))
+		return cmd_fs_top_files(argc, argv);
+	if (!strcmp(cmd, "du"))
+		return cmd_fs_du(argc, argv);
+	if (!strcmp(cmd, "orphans"))
+		return cmd_fs_orphans(argc, argv);
+	if (!strcmp(cmd, "resize"))
+		return cmd_fs_resize(argc, argv);
+	if (!strcmp(cmd, "latency"))
+		return cmd_fs_latency(argc, argv);
+	if (!strcmp(cmd, "counters"))
+		return cmd_fs_counters(argc, argv);
+	if (!strcmp(cmd, "gc"))
+		return cmd_fs_gc(argc, argv);
 
 	return 0;
 }
@@ -139,12 +219,22 @@ int device_cmds(int argc, char *argv[])
 		return cmd_device_offline(argc, argv);
 	if (!strcmp(cmd, "evacuate"))
 		return cmd_device_evacuate(argc, argv);
+	if (!strcmp(cmd, "replace"))
+		return cmd_device_replace(argc, argv);
 	if (!strcmp(cmd, "set-state"))
 		return cmd_device_set_state(argc, argv);
+	if (!strcmp(cmd, "set-label"))
+		return cmd_device_set_label(argc, argv);
 	if (!strcmp(cmd, "resize"))
 		return cmd_device_resize(argc, argv);
 	if (!strcmp(cmd, "resize-journal"))
 		return cmd_device_resize_journal(argc, argv);
+	if (!strcmp(cmd, "locate"))
+		return cmd_device_locate(argc, argv);
+	if (!strcmp(cmd, "scan"))
+		return cmd_device_scan(argc, argv);
+	if (!strcmp(cmd, "trim"))
+		return cmd_device_trim(argc, argv);
 
 	return 0;
 }
@@ -157,8 +247,112 @@ int data_cmds(int argc, char *argv[])
 		return data_usage();
 	if (!strcmp(cmd, "rereplicate"))
 		return cmd_data_rereplicate(argc, argv);
+	if (!strcmp(cmd, "rewrite"))
+		return cmd_data_rewrite(argc, argv);
 	if (!strcmp(cmd, "job"))
 		return cmd_data_job(argc, argv);
 
 	return 0;
 }
+
+int ec_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (argc < 1)
+		return ec_usage();
+	if (!strcmp(cmd, "status"))
+		return cmd_ec_status(argc, argv);
+	if (!strcmp(cmd, "repair"))
+		return cmd_ec_repair(argc, argv);
+
+	return 0;
+}
+
+int label_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (argc < 1)
+		return label_usage();
+	if (!strcmp(cmd, "list"))
+		return cmd_label_list(argc, argv);
+	if (!strcmp(cmd, "rename"))
+		return cmd_label_rename(argc, argv);
+
+	return 0;
+}
+
+int quota_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (argc < 1)
+		return quota_usage();
+	if (!strcmp(cmd, "show"))
+		return cmd_quota_show(argc, argv);
+	if (!strcmp(cmd, "set"))
+		return cmd_quota_set(argc, argv);
+	if (!strcmp(cmd, "project"))
+		return cmd_quota_project(argc, argv);
+	if (!strcmp(cmd, "rescan"))
+		return cmd_quota_rescan(argc, argv);
+
+	return 0;
+}
+
+int attr_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (argc < 1)
+		return attr_usage();
+	if (!strcmp(cmd, "get"))
+		return cmd_attr_get(argc, argv);
+	if (!strcmp(cmd, "set"))
+		return cmd_attr_set(argc, argv);
+
+	return 0;
+}
+
+int nocow_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (argc < 1)
+		return nocow_usage();
+	if (!strcmp(cmd, "set"))
+		return cmd_nocow_set(argc, argv);
+	if (!strcmp(cmd, "status"))
+		return cmd_nocow_status(argc, argv);
+
+	return 0;
+}
+
+int cache_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (argc < 1)
+		return cache_usage();
+	if (!strcmp(cmd, "drop"))
+		return cmd_cache_drop(argc, argv);
+	if (!strcmp(cmd, "pin"))
+		return cmd_cache_pin(argc, argv);
+
+	return 0;
+}
+
+int journal_cmds(int argc, char *argv[])
+{
+	char *cmd = pop_cmd(&argc, argv);
+
+	if (argc < 1)
+		return journal_usage();
+	if (!strcmp(cmd, "resize"))
+		return cmd_journal_resize(argc, argv);
+	if (!strcmp(cmd, "move"))
+		return cmd_journal_move(argc, argv);
+
+	return 0;
+}
@@ -1,4 +1,5 @@
 #include <dirent.h>
+#include <getopt.h>
 #include <stdio.h>
 #include <sys/ioctl.h>
 #include <sys/stat.h>
@@ -55,21 +56,282 @@ static void propagate_recurse(int dirfd)
 		die("readdir error: %m");
 }
 
-static void do_setattr(char *path, struct bch_opt_strs opts)
+/* Returns the value of an xattr as a nul terminated string, or NULL if unset */
+static char *attr_get(const char *path, const char *prefix, const char *name)
 {
-	unsigned i;
+	char *n = mprintf("%s.%s", prefix, name);
+	char buf[1024];
+	ssize_t ret = getxattr(path, n, buf, sizeof(buf) - 1);
 
-	for (i = 0; i < bch2_opts_nr; i++) {
-		if (!opts.by_id[i])
+	free(n);
+
+	if (ret < 0) {
+		if (errno != ENODATA)
+			fprintf(stderr, "%s: error reading %s.%s: %m\n", path, prefix, name);
+		return NULL;
+	}
+
+	buf[ret] = '\0';
+	return strdup(buf);
+}
+
+/*
+ * Filesystem wide default for an option: sysfs shows choice options as a list,
+ * with the current selection in brackets
+ */
+static char *fs_opt_get(struct bchfs_handle fs, const char *name)
+{
+	char *path = mprintf("options/%s", name);
+	char *v = read_file_str(fs.sysfs_fd, path);
+	char *l = strchr(v, '['), *r = l ? strchr(l, ']') : NULL;
+
+	free(path);
+
+	if (l && r) {
+		*r = '\0';
+		memmove(v, l + 1, r - l);
+	}
+	return v;
+}
+
+/* The option that applies to @path - its own, inherited, or the fs default */
+static char *opt_effective(struct bchfs_handle fs, const char *path, const char *name)
+{
+	return attr_get(path, "bcachefs_effective", name) ?: fs_opt_get(fs, name);
+}
+
+typedef void (*attr_fn)(const char *, void *);
+
+static void walk_recursive(const char *path, attr_fn fn, void *arg)
+{
+	struct stat st;
+
+	if (lstat(path, &st)) {
+		fprintf(stderr, "error statting %s: %m\n", path);
+		return;
+	}
+
+	if (!S_ISREG(st.st_mode) && !S_ISDIR(st.st_mode))
+		return;
+
+	fn(path, arg);
+
+	if (!S_ISDIR(st.st_mode))
+		return;
+
+	DIR *dir = opendir(path);
+	struct dirent *d;
+
+	if (!dir) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		return;
+	}
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (!strcmp(d->d_name, ".") ||
+		    !strcmp(d->d_name, ".."))
+			continue;
+
+		char *child = mprintf("%s/%s", path, d->d_name);
+		walk_recursive(child, fn, arg);
+		free(child);
+	}
+
+	if (errno)
+		fprintf(stderr, "error reading %s: %m\n", path);
+	closedir(dir);
+}
+
+struct attr_check {
+	struct bchfs_handle	fs;
+	bool			verbose;
+	u64			files;
+	u64			extents;
+	u64			bad_files;
+	u64			bad_extents;
+	u64			bad_replicas;
+	u64			bad_compression;
+	u64			bad_bytes;
+};
+
+struct file_check {
+	unsigned		want_replicas;
+	bool			want_compressed;
+	u64			extents;
+	u64			bad_extents;
+	u64			bad_replicas;
+	u64			bad_compression;
+	u64			bad_bytes;
+};
+
+static void file_check_extent(struct file_check *f, unsigned nr_replicas,
+			      bool compressed, u64 len)
+{
+	bool replicas_bad	= nr_replicas < f->want_replicas;
+	bool compression_bad	= compressed != f->want_compressed;
+
+	f->extents++;
+	f->bad_replicas		+= replicas_bad;
+	f->bad_compression	+= compression_bad;
+
+	if (replicas_bad || compression_bad) {
+		f->bad_extents++;
+		f->bad_bytes += len;
+	}
+}
+
+/*
+ * Compare a file's extents against its effective options, as far as we can
+ * tell from fiemap: the kernel reports one fiemap extent per replica, and
+ * flags compressed extents as encoded. Targets aren't visible via fiemap.
+ */
+static void check_file(const char *path, void *arg)
+{
+	struct attr_check *c = arg;
+	struct stat st = xstat(path);
+
+	if (!S_ISREG(st.st_mode))
+		return;
+
+	char *replicas		= opt_effective(c->fs, path, "data_replicas");
+	char *compression	= opt_effective(c->fs, path, "compression");
+	char *bg_compression	= opt_effective(c->fs, path, "background_compression");
+	struct file_check f = {
+		.want_replicas		= strtoul(replicas, NULL, 10) ?: 1,
+		.want_compressed	= strcmp(compression, "none") ||
+					  strcmp(bg_compression, "none"),
+	};
+
+	int fd = open(path, O_RDONLY);
+	if (fd < 0) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		goto out;
+	}
+
+	struct fiemap_iter iter;
+	struct fiemap_extent e;
+	u64 cur_logical = U64_MAX, cur_len = 0;
+	unsigned cur_nr = 0;
+	bool cur_compressed = false;
+
+	/* Replicas of the same extent are returned consecutively: */
+	fiemap_for_each(fd, iter, e) {
+		if (e.fe_flags & (FIEMAP_EXTENT_DATA_INLINE|
+				  FIEMAP_EXTENT_DELALLOC))
+			continue;
+
+		if (e.fe_logical != cur_logical) {
+			if (cur_nr)
+				file_check_extent(&f, cur_nr, cur_compressed, cur_len);
+
+			cur_logical	= e.fe_logical;
+			cur_len		= e.fe_length;
+			cur_nr		= 0;
+			cur_compressed	= false;
+		}
+
+		cur_nr++;
+		cur_compressed |= (e.fe_flags & FIEMAP_EXTENT_ENCODED) != 0;
+	}
+	if (cur_nr)
+		file_check_extent(&f, cur_nr, cur_compressed, cur_len);
+
+	fiemap_iter_exit(&iter);
+	close(fd);
+
+	c->files++;
+	c->extents += f.extents;
+
+	if (f.bad_extents) {
+		c->bad_files++;
+		c->bad_extents		+= f.bad_extents;
+		c->bad_replicas		+= f.bad_replicas;
+		c->bad_compression	+= f.bad_compression;
+		c->bad_bytes		+= f.bad_bytes;
+
+		if (c->verbose)
+			printf("%s: %llu/%llu extents need rewriting (%llu replicas, %llu compression)\n",
+			       path, f.bad_extents, f.extents,
+			       f.bad_replicas, f.bad_compression);
+	}
+out:
+	free(bg_compression);
+	free(compression);
+	free(replicas);
+}
+
+static void attr_check_to_text(struct printbuf *out, struct attr_check *c)
+{
+	prt_printf(out, "%llu files, %llu extents checked\n", c->files, c->extents);
+
+	if (!c->bad_files) {
+		prt_printf(out, "all extents match their file's options\n");
+		return;
+	}
+
+	prt_printf(out, "%llu extents in %llu files need rewriting (",
+		   c->bad_extents, c->bad_files);
+	tools_prt_units_u64(out, c->bad_bytes);
+	prt_printf(out, "):\n");
+	prt_printf(out, "  %llu extents with fewer replicas than data_replicas\n",
+		   c->bad_replicas);
+	prt_printf(out, "  %llu extents whose compression doesn't match\n",
+		   c->bad_compression);
+	prt_printf(out, "Run bcachefs data rereplicate to add replicas; rebalance applies\n"
+		   "background_compression, other changes apply when data is rewritten\n");
+}
+
+static void attr_check(const char *path, bool recursive, bool verbose,
+		       bool human_readable)
+{
+	struct attr_check c = {
+		.fs		= bcache_fs_open(path),
+		.verbose	= verbose,
+	};
+	struct printbuf buf = PRINTBUF;
+
+	if (recursive)
+		walk_recursive(path, check_file, &c);
+	else
+		check_file(path, &c);
+
+	tools_printbuf_units(&buf, human_readable);
+	attr_check_to_text(&buf, &c);
+	printf("%s", buf.buf);
+
+	printbuf_exit(&buf);
+	bcache_fs_close(c.fs);
+}
+
+static void setattr_one(const char *path, void *arg)
+{
+	struct bch_opt_strs *opts = arg;
+
+	for (unsigned i = 0; i < bch2_opts_nr; i++) {
+		if (!opts->by_id[i])
 			continue;
 
 		char *n = mprintf("bcachefs.%s", bch2_opt_table[i].attr.name);
+		const char *v = opts->by_id[i];
 
-		if (setxattr(path, n, opts.by_id[i], strlen(opts.by_id[i]), 0))
-			die("setxattr error: %m");
+		/* an empty value goes back to inheriting from the parent directory: */
+		if (*v
+		    ? setxattr(path, n, v, strlen(v), 0)
+		    : removexattr(path, n) && errno != ENODATA)
+			die("error setting %s on %s: %m", n, path);
 
 		free(n);
 	}
+}
+
+static void do_setattr(char *path, struct bch_opt_strs opts, bool recursive)
+{
+	if (recursive) {
+		walk_recursive(path, setattr_one, &opts);
+		return;
+	}
+
+	setattr_one(path, &opts);
 
 	struct stat st = xstat(path);
 	if (!S_ISDIR(st.st_mode))
@@ -88,32 +350,1015 @@ static void setattr_usage(void)
 	puts("bcachefs setattr - set attributes on files in a bcachefs filesystem\n"
 	     "Usage: bcachefs setattr [OPTIONS]... <files>\n"
 	     "\n"
+	     "Setting an option on a directory propagates it to children that don't set\n"
+	     "it themselves; --recursive sets it explicitly on every file below. An empty\n"
+	     "value (e.g. --compression=) removes an option.\n"
+	     "Existing data isn't rewritten; see --check.\n"
+	     "\n"
 	     "Options:");
 
 	bch2_opts_usage(OPT_INODE);
-	puts("  -h            Display this help and exit\n"
+	puts("  -R, --recursive              Set options on all files and directories below\n"
+	     "  -c, --check                  Report extents that don't match the new options\n"
+	     "  -v, --verbose                List each file with extents that need rewriting\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -H, --help                   Display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
 
 int cmd_setattr(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "recursive",		no_argument,		NULL, 'R' },
+		{ "check",		no_argument,		NULL, 'c' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
 	struct bch_opt_strs opts =
 		bch2_cmdline_opts_get(&argc, argv, OPT_INODE);
-	unsigned i;
+	bool recursive = false, check = false, verbose = false, human_readable = false;
+	int opt;
 
-	for (i = 1; i < argc; i++)
-		if (argv[i][0] == '-') {
-			printf("invalid option %s\n", argv[i]);
+	while ((opt = getopt_long(argc, argv, "Rcvh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'R':
+			recursive = true;
+			break;
+		case 'c':
+			check = true;
+			break;
+		case 'v':
+			verbose = true;
+			break;
+		case 'h':
+			human_readable = true;
+			break;
+		case 'H':
+			setattr_usage();
+			exit(EXIT_SUCCESS);
+		default:
 			setattr_usage();
 			exit(EXIT_FAILURE);
 		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more files");
+
+	for (unsigned i = 0; i < argc; i++) {
+		do_setattr(argv[i], opts, recursive);
+
+		if (check)
+			attr_check(argv[i], recursive, verbose, human_readable);
+	}
+	bch2_opt_strs_free(&opts);
+
+	return 0;
+}
+
+static void getattr_one(const char *path, void *arg)
+{
+	struct bchfs_handle *fs = arg;
+
+	printf("%s:\n", path);
+
+	for (unsigned i = 0; i < bch2_opts_nr; i++) {
+		if (!bch2_opt_is_inode_opt(i))
+			continue;
+
+		const struct bch_option *opt = &bch2_opt_table[i];
+		const char *name = opt->attr.name;
+		char *set	= attr_get(path, "bcachefs", name);
+		char *inherited	= set ? NULL : attr_get(path, "bcachefs_effective", name);
+
+		if (set) {
+			printf("  %-24s%s\n", name, set);
+		} else if (inherited) {
+			printf("  %-24s%s (inherited)\n", name, inherited);
+		} else if (opt->flags & OPT_FS) {
+			char *v = fs_opt_get(*fs, name);
+			printf("  %-24s%s (filesystem default)\n", name, v);
+			free(v);
+		} else {
+			printf("  %-24s(unset)\n", name);
+		}
+
+		free(inherited);
+		free(set);
+	}
+}
+
+static void getattr_usage(void)
+{
+	puts("bcachefs getattr - show attributes of files in a bcachefs filesystem\n"
+	     "Usage: bcachefs getattr [OPTIONS]... <files>\n"
+	     "\n"
+	     "Shows each per file option, and whether it was set on the file itself,\n"
+	     "inherited from a parent directory, or is the filesystem default.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -R, --recursive              Show all files and directories below\n"
+	     "  -c, --check                  Report extents that don't match their file's options\n"
+	     "  -v, --verbose                List each file with extents that need rewriting\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "--check compares data_replicas and compression against fiemap: incompressible\n"
+	     "data counts as uncompressed, and targets aren't checked.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_getattr(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "recursive",		no_argument,		NULL, 'R' },
+		{ "check",		no_argument,		NULL, 'c' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	bool recursive = false, check = false, verbose = false, human_readable = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "Rcvh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'R':
+			recursive = true;
+			break;
+		case 'c':
+			check = true;
+			break;
+		case 'v':
+			verbose = true;
+			break;
+		case 'h':
+			human_readable = true;
+			break;
+		case 'H':
+			getattr_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			getattr_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more files");
+
+	for (unsigned i = 0; i < argc; i++) {
+		if (check) {
+			attr_check(argv[i], recursive, verbose, human_readable);
+			continue;
+		}
+
+		struct bchfs_handle fs = bcache_fs_open(argv[i]);
+
+		if (recursive)
+			walk_recursive(argv[i], getattr_one, &fs);
+		else
+			getattr_one(argv[i], &fs);
+
+		bcache_fs_close(fs);
+	}
+
+	return 0;
+}
+
+/*
+ * attr get/set: the bcachefs. and bcachefs_effective. xattrs, with names and
+ * values checked against the options table - so that typos are reported as
+ * such, not as -EINVAL from setxattr
+ */
+
+int attr_usage(void)
+{
+	puts("bcachefs attr - get and set per file options\n"
+	     "Usage: bcachefs attr <CMD> [OPTION]...\n"
+	     "\n"
+	     "Commands:\n"
+	     "  get                     show options set on files\n"
+	     "  set                     set or remove options on files\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+	return 0;
+}
+
+/* An option name, or its xattr name with the bcachefs. or bcachefs_effective. prefix */
+static int attr_opt_lookup(char *name, bool *effective)
+{
+	char *n;
+
+	if ((n = strcmp_prefix(name, "bcachefs_effective."))) {
+		*effective = true;
+		name = n;
+	} else if ((n = strcmp_prefix(name, "bcachefs."))) {
+		name = n;
+	}
+
+	int id = bch2_opt_lookup(name);
+	if (id < 0 || !bch2_opt_is_inode_opt(id))
+		die("%s: not a per file option (see bcachefs setattr --help)", name);
+	return id;
+}
+
+struct attr_get {
+	struct bchfs_handle	fs;
+	bool			effective;
+	bool			print_path;
+	bool			any_wanted;
+	bool			wanted[bch2_opts_nr];
+	bool			missing;
+};
+
+static void attr_get_one(const char *path, void *arg)
+{
+	struct attr_get *g = arg;
+
+	for (unsigned i = 0; i < bch2_opts_nr; i++) {
+		if (!bch2_opt_is_inode_opt(i) ||
+		    (g->any_wanted && !g->wanted[i]))
+			continue;
+
+		const struct bch_option *opt = &bch2_opt_table[i];
+		const char *name = opt->attr.name;
+		char *v = !g->effective
+			? attr_get(path, "bcachefs", name)
+			: opt->flags & OPT_FS
+			? opt_effective(g->fs, path, name)
+			: attr_get(path, "bcachefs_effective", name);
+
+		if (v) {
+			if (g->print_path)
+				printf("%s: ", path);
+			printf("%s=%s\n", name, v);
+		} else if (g->any_wanted) {
+			g->missing = true;
+		}
+		free(v);
+	}
+}
+
+static void attr_get_usage(void)
+{
+	puts("bcachefs attr get - show options set on files\n"
+	     "Usage: bcachefs attr get [OPTION]... <file>...\n"
+	     "\n"
+	     "Prints name=value for each option set on the file itself (the bcachefs.\n"
+	     "xattrs), or with --effective, for each option that applies to it, whether\n"
+	     "set, inherited or the filesystem default (bcachefs_effective.). Options\n"
+	     "may be named with or without the xattr prefix.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -n, --name=option            Only show option; may be given more than once\n"
+	     "  -e, --effective              Show effective options\n"
+	     "  -R, --recursive              Show all files and directories below\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Exits with status 1 if an option given with --name isn't set on a file.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_attr_get(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "name",		required_argument,	NULL, 'n' },
+		{ "effective",		no_argument,		NULL, 'e' },
+		{ "recursive",		no_argument,		NULL, 'R' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct attr_get g = {};
+	bool recursive = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "n:eRh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'n':
+			g.wanted[attr_opt_lookup(optarg, &g.effective)] = true;
+			g.any_wanted = true;
+			break;
+		case 'e':
+			g.effective = true;
+			break;
+		case 'R':
+			recursive = true;
+			break;
+		case 'h':
+			attr_get_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			attr_get_usage();
+			exit(EXIT_FAILURE);
+		}
+
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more files");
+
+	g.print_path = argc > 1 || recursive;
+
+	for (unsigned i = 0; i < argc; i++) {
+		g.fs = bcache_fs_open(argv[i]);
+
+		if (recursive)
+			walk_recursive(argv[i], attr_get_one, &g);
+		else
+			attr_get_one(argv[i], &g);
+
+		bcache_fs_close(g.fs);
+	}
+
+	return g.missing;
+}
+
+static void attr_set_usage(void)
+{
+	puts("bcachefs attr set - set or remove options on files\n"
+	     "Usage: bcachefs attr set [OPTION]... <name>=<value>... <file>...\n"
+	     "\n"
+	     "Options and values are checked before anything is changed, then set as\n"
+	     "setattr does: setting an option on a directory propagates it to children\n"
+	     "that don't set it themselves. An empty value removes the option, so that\n"
+	     "the file inherits it from its directory again.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -R, --recursive              Set options on all files and directories below\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Targets are checked by the kernel, when they're set.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_attr_set(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "recursive",		no_argument,		NULL, 'R' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opt_strs opts = {};
+	bool recursive = false, effective = false, have_opts = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "Rh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'R':
+			recursive = true;
+			break;
+		case 'h':
+			attr_set_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			attr_set_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	while (argc && strchr(argv[0], '=')) {
+		char *name = arg_pop(), *val = strchr(name, '=');
+
+		*val++ = '\0';
+
+		int id = attr_opt_lookup(name, &effective);
+
+		if (*val) {
+			struct printbuf err = PRINTBUF;
+			u64 v = 0;
+
+			if (bch2_opt_parse(NULL, &bch2_opt_table[id], val, &v, &err) < 0)
+				die("invalid option %s=%s: %s", name, val, err.buf);
+			printbuf_exit(&err);
+		}
+
+		free(opts.by_id[id]);
+		opts.by_id[id] = strdup(val);
+		have_opts = true;
+	}
 
-	if (argc <= 1)
+	if (!have_opts)
+		die("Please supply one or more options to set");
+	if (!argc)
 		die("Please supply one or more files");
 
-	for (i = 1; i < argc; i++)
-		do_setattr(argv[i], opts);
+	for (unsigned i = 0; i < argc; i++)
+		do_setattr(argv[i], opts, recursive);
 	bch2_opt_strs_free(&opts);
 
 	return 0;
 }
+
+/*
+ * fs audit-options: find files that don't match their directory's data
+ * placement and compression options, without having set them themselves -
+ * inherited options are copied at create time, and only propagated to
+ * existing files by setattr
+ */
+
+static const char * const audit_opts[] = {
+	"data_replicas",
+	"compression",
+	"background_compression",
+	"foreground_target",
+	"background_target",
+	"promote_target",
+};
+
+struct audit {
+	bool			fix;
+	u64			checked;
+	u64			stale;
+	u64			fixed;
+};
+
+static bool str_eq_or_null(const char *l, const char *r)
+{
+	return l && r ? !strcmp(l, r) : l == r;
+}
+
+/*
+ * Setting an option queues a rebalance scan of the file; removing it switches
+ * back to inheriting from the parent directory
+ */
+static int audit_fix(const char *path, const char *name, const char *v)
+{
+	char *n = mprintf("bcachefs.%s", name);
+	int ret = v
+		? setxattr(path, n, v, strlen(v), 0)
+		: 0;
+
+	if (!ret)
+		ret = removexattr(path, n);
+	if (ret && errno == ENODATA)
+		ret = 0;
+
+	free(n);
+	return ret;
+}
+
+static void audit_one(struct audit *a, const char *path, char **dir_opts)
+{
+	bool stale = false, fixed = a->fix;
+
+	for (unsigned i = 0; i < ARRAY_SIZE(audit_opts); i++) {
+		char *set = attr_get(path, "bcachefs", audit_opts[i]);
+		if (set) {
+			free(set);
+			continue;
+		}
+
+		char *v = attr_get(path, "bcachefs_effective", audit_opts[i]);
+
+		if (!str_eq_or_null(v, dir_opts[i])) {
+			printf("%s: %s %s (directory: %s)",
+			       path, audit_opts[i],
+			       v ?: "(default)", dir_opts[i] ?: "(default)");
+
+			if (a->fix) {
+				if (audit_fix(path, audit_opts[i], dir_opts[i])) {
+					printf(": error fixing: %m");
+					fixed = false;
+				} else {
+					printf(": fixed");
+				}
+			}
+			putchar('\n');
+			stale = true;
+		}
+		free(v);
+	}
+
+	a->checked++;
+	a->stale += stale;
+	a->fixed += stale && fixed;
+}
+
+static void audit_dir(struct audit *a, const char *path)
+{
+	char *dir_opts[ARRAY_SIZE(audit_opts)];
+
+	for (unsigned i = 0; i < ARRAY_SIZE(audit_opts); i++)
+		dir_opts[i] = attr_get(path, "bcachefs_effective", audit_opts[i]);
+
+	DIR *dir = opendir(path);
+	struct dirent *d;
+
+	if (!dir) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		goto out;
+	}
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (!strcmp(d->d_name, ".") ||
+		    !strcmp(d->d_name, ".."))
+			continue;
+
+		char *child = mprintf("%s/%s", path, d->d_name);
+		struct stat st;
+
+		if (lstat(child, &st)) {
+			fprintf(stderr, "error statting %s: %m\n", child);
+		} else if (S_ISREG(st.st_mode) || S_ISDIR(st.st_mode)) {
+			audit_one(a, child, dir_opts);
+
+			/* after fixing, so that children compare against the new options */
+			if (S_ISDIR(st.st_mode))
+				audit_dir(a, child);
+		}
+		free(child);
+	}
+
+	if (errno)
+		fprintf(stderr, "error reading %s: %m\n", path);
+	closedir(dir);
+out:
+	for (unsigned i = 0; i < ARRAY_SIZE(audit_opts); i++)
+		free(dir_opts[i]);
+}
+
+static void audit_options_usage(void)
+{
+	puts("bcachefs fs audit-options - find files not matching their directory's options\n"
+	     "Usage: bcachefs fs audit-options [OPTION]... <path>\n"
+	     "\n"
+	     "Reports files whose effective data_replicas, compression or target options\n"
+	     "differ from their directory's, and that don't set those options themselves;\n"
+	     "e.g. files created before an option was changed on the directory.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -f, --fix                    Reset stale options to the directory's, and queue\n"
+	     "                               the files for a rebalance scan\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Rebalance applies compression and background_target; run bcachefs data\n"
+	     "rereplicate for data_replicas.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_fs_audit_options(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "fix",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct audit a = {};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "fh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'f':
+			a.fix = true;
+			break;
+		case 'h':
+			audit_options_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			audit_options_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a directory");
+	if (argc)
+		die("too many arguments");
+
+	/* Make sure it's bcachefs before we start walking it: */
+	bcache_fs_close(bcache_fs_open(path));
+
+	if (!S_ISDIR(xstat(path).st_mode))
+		die("%s is not a directory", path);
+
+	audit_dir(&a, path);
+
+	printf("%llu files checked, %llu with stale options", a.checked, a.stale);
+	if (a.fix)
+		printf(", %llu fixed", a.fixed);
+	putchar('\n');
+
+	return a.stale != a.fixed;
+}
+
+/*
+ * nocow: writes to nocow files are only done in place over extents that are
+ * unshared, uncompressed and unencrypted, not erasure coded, and have at least
+ * data_replicas replicas - anything else takes the normal COW path, until it's
+ * rewritten. Erasure coding and snapshots aren't visible via fiemap.
+ */
+
+enum nocow_reason {
+	NOCOW_SHARED,
+	NOCOW_COMPRESSED,
+	NOCOW_UNALIGNED,
+	NOCOW_INLINE,
+	NOCOW_REPLICAS,
+	NOCOW_NR,
+};
+
+static const char * const nocow_reason_strs[] = {
+	[NOCOW_SHARED]		= "shared (reflinked)",
+	[NOCOW_COMPRESSED]	= "compressed",
+	[NOCOW_UNALIGNED]	= "not block aligned",
+	[NOCOW_INLINE]		= "inline data",
+	[NOCOW_REPLICAS]	= "fewer replicas than data_replicas",
+};
+
+struct nocow_check {
+	struct bchfs_handle	fs;
+	u64			files;
+	u64			nocow_files;
+	u64			bad_files;
+	u64			bad_bytes;
+	u64			reasons[NOCOW_NR];
+};
+
+static unsigned nocow_extent_reasons(struct fiemap_extent *e, unsigned nr_replicas,
+				     unsigned want_replicas)
+{
+	unsigned ret = 0;
+
+	if (e->fe_flags & FIEMAP_EXTENT_SHARED)
+		ret |= 1U << NOCOW_SHARED;
+	if (e->fe_flags & FIEMAP_EXTENT_ENCODED)
+		ret |= 1U << NOCOW_COMPRESSED;
+	if (e->fe_flags & FIEMAP_EXTENT_NOT_ALIGNED)
+		ret |= 1U << NOCOW_UNALIGNED;
+	if (e->fe_flags & FIEMAP_EXTENT_DATA_INLINE)
+		ret |= 1U << NOCOW_INLINE;
+	if (nr_replicas < want_replicas)
+		ret |= 1U << NOCOW_REPLICAS;
+	return ret;
+}
+
+static void nocow_check_file(const char *path, void *arg)
+{
+	struct nocow_check *c = arg;
+	struct stat st = xstat(path);
+
+	if (!S_ISREG(st.st_mode))
+		return;
+
+	char *nocow	= opt_effective(c->fs, path, "nocow");
+	char *replicas	= opt_effective(c->fs, path, "data_replicas");
+	unsigned want_replicas = strtoul(replicas, NULL, 10) ?: 1;
+	bool is_nocow	= strcmp(nocow, "0");
+
+	c->files++;
+	if (!is_nocow)
+		goto out;
+	c->nocow_files++;
+
+	int fd = open(path, O_RDONLY);
+	if (fd < 0) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		goto out;
+	}
+
+	struct fiemap_iter iter;
+	struct fiemap_extent e, cur = {};
+	unsigned cur_nr = 0, reasons = 0;
+	u64 bad_bytes = 0;
+
+	/* Replicas of the same extent are returned consecutively: */
+	fiemap_for_each(fd, iter, e) {
+		if (e.fe_flags & FIEMAP_EXTENT_DELALLOC)
+			continue;
+
+		if (cur_nr && e.fe_logical != cur.fe_logical) {
+			unsigned r = nocow_extent_reasons(&cur, cur_nr, want_replicas);
+
+			if (r)
+				bad_bytes += cur.fe_length;
+			reasons |= r;
+			cur_nr = 0;
+		}
+
+		if (!cur_nr)
+			cur = e;
+		else
+			cur.fe_flags |= e.fe_flags;
+		cur_nr++;
+	}
+	if (cur_nr) {
+		unsigned r = nocow_extent_reasons(&cur, cur_nr, want_replicas);
+
+		if (r)
+			bad_bytes += cur.fe_length;
+		reasons |= r;
+	}
+
+	fiemap_iter_exit(&iter);
+	close(fd);
+
+	if (!bad_bytes)
+		goto out;
+
+	c->bad_files++;
+	c->bad_bytes += bad_bytes;
+
+	printf("%s: %llu bytes need rewriting:", path, bad_bytes);
+	for (unsigned i = 0; i < NOCOW_NR; i++)
+		if (reasons & (1U << i)) {
+			c->reasons[i]++;
+			printf(" %s", nocow_reason_strs[i]);
+		}
+	putchar('\n');
+out:
+	free(replicas);
+	free(nocow);
+}
+
+static int nocow_check(const char *path, bool recursive, bool human_readable)
+{
+	struct nocow_check c = { .fs = bcache_fs_open(path) };
+	struct printbuf buf = PRINTBUF;
+
+	if (recursive)
+		walk_recursive(path, nocow_check_file, &c);
+	else
+		nocow_check_file(path, &c);
+
+	tools_printbuf_units(&buf, human_readable);
+
+	prt_printf(&buf, "%llu files checked, %llu nocow\n", c.files, c.nocow_files);
+	if (!c.bad_files) {
+		prt_printf(&buf, "all nocow files can be written in place\n");
+	} else {
+		prt_printf(&buf, "%llu nocow files with data that must be rewritten (",
+			   c.bad_files);
+		tools_prt_units_u64(&buf, c.bad_bytes);
+		prt_printf(&buf, ") before it can be written in place:\n");
+
+		for (unsigned i = 0; i < NOCOW_NR; i++)
+			if (c.reasons[i])
+				prt_printf(&buf, "  %llu files with %s extents\n",
+					   c.reasons[i], nocow_reason_strs[i]);
+
+		prt_printf(&buf, "Copy a file (cp --reflink=never) to rewrite its data\n");
+	}
+
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+	bcache_fs_close(c.fs);
+	return c.bad_files != 0;
+}
+
+int nocow_usage(void)
+{
+	puts("bcachefs nocow - manage and check nocow mode\n"
+	     "Usage: bcachefs nocow <CMD> [OPTION]...\n"
+	     "\n"
+	     "Commands:\n"
+	     "  set                     set or clear the nocow option on files\n"
+	     "  status                  check whether existing data can be written in place\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+	return 0;
+}
+
+static void nocow_set_usage(void)
+{
+	puts("bcachefs nocow set - set or clear the nocow option\n"
+	     "Usage: bcachefs nocow set [OPTION]... <files>\n"
+	     "\n"
+	     "Sets the nocow option, as setattr --nocow would, then checks existing\n"
+	     "data as nocow status does.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -o, --off                    Clear nocow instead of setting it\n"
+	     "  -R, --recursive              Set on all files and directories below\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_nocow_set(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "off",		no_argument,		NULL, 'o' },
+		{ "recursive",		no_argument,		NULL, 'R' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct bch_opt_strs opts = {};
+	bool off = false, recursive = false, human_readable = false;
+	int opt, ret = 0;
+
+	while ((opt = getopt_long(argc, argv, "oRh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'o':
+			off = true;
+			break;
+		case 'R':
+			recursive = true;
+			break;
+		case 'h':
+			human_readable = true;
+			break;
+		case 'H':
+			nocow_set_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			nocow_set_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more files");
+
+	opts.by_id[Opt_nocow] = off ? "0" : "1";
+
+	for (unsigned i = 0; i < argc; i++) {
+		do_setattr(argv[i], opts, recursive);
+
+		if (!off)
+			ret |= nocow_check(argv[i], true, human_readable);
+	}
+
+	return ret;
+}
+
+static void nocow_status_usage(void)
+{
+	puts("bcachefs nocow status - check whether nocow files can be written in place\n"
+	     "Usage: bcachefs nocow status [OPTION]... <files>\n"
+	     "\n"
+	     "Lists nocow files with extents that writes can't overwrite in place:\n"
+	     "shared (reflinked), compressed, misaligned, inline, or with fewer replicas\n"
+	     "than data_replicas. Erasure coded and snapshotted extents can't be\n"
+	     "detected, and are also written COW.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -R, --recursive              Check all files below\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "Exits with status 1 if any nocow file has data that needs rewriting.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_nocow_status(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "recursive",		no_argument,		NULL, 'R' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	bool recursive = false, human_readable = false;
+	int opt, ret = 0;
+
+	while ((opt = getopt_long(argc, argv, "Rh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'R':
+			recursive = true;
+			break;
+		case 'h':
+			human_readable = true;
+			break;
+		case 'H':
+			nocow_status_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			nocow_status_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more files");
+
+	for (unsigned i = 0; i < argc; i++)
+		ret |= nocow_check(argv[i], recursive, human_readable);
+
+	return ret;
+}
+
+int cache_usage(void)
+{
+	puts("bcachefs cache - manage cached data on tiered filesystems\n"
+	     "Usage: bcachefs cache <CMD> [OPTION]...\n"
+	     "\n"
+	     "Commands:\n"
+	     "  drop                    drop cached copies of data from the promote target\n"
+	     "  pin                     keep files' data on the promote target\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+	return 0;
+}
+
+static void cache_pin_usage(void)
+{
+	puts("bcachefs cache pin - keep files' data on the promote target\n"
+	     "Usage: bcachefs cache pin [OPTION]... <files>\n"
+	     "\n"
+	     "Cached copies can be evicted at any time, so pinning sets the files'\n"
+	     "foreground_target and background_target to the filesystem's\n"
+	     "promote_target: rebalance then moves their data there, as normal (not\n"
+	     "cached) data, and new writes go there too.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -o, --off                    Unpin: remove the files' foreground_target\n"
+	     "                               and background_target\n"
+	     "  -R, --recursive              Pin all files and directories below\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static void cache_unpin_one(const char *path, void *arg)
+{
+	const char *names[] = { "foreground_target", "background_target" };
+
+	for (unsigned i = 0; i < ARRAY_SIZE(names); i++) {
+		char *n = mprintf("bcachefs.%s", names[i]);
+
+		if (removexattr(path, n) && errno != ENODATA)
+			die("error removing %s on %s: %m", n, path);
+		free(n);
+	}
+}
+
+static void cache_unpin(char *path, bool recursive)
+{
+	if (recursive) {
+		walk_recursive(path, cache_unpin_one, NULL);
+		return;
+	}
+
+	cache_unpin_one(path, NULL);
+
+	struct stat st = xstat(path);
+	if (!S_ISDIR(st.st_mode))
+		return;
+
+	int dirfd = open(path, O_RDONLY);
+	if (dirfd < 0)
+		die("error opening %s: %m", path);
+
+	propagate_recurse(dirfd);
+	close(dirfd);
+}
+
+int cmd_cache_pin(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "off",		no_argument,		NULL, 'o' },
+		{ "recursive",		no_argument,		NULL, 'R' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	bool off = false, recursive = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "oRh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'o':
+			off = true;
+			break;
+		case 'R':
+			recursive = true;
+			break;
+		case 'h':
+			cache_pin_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			cache_pin_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more files");
+
+	for (unsigned i = 0; i < argc; i++) {
+		if (off) {
+			cache_unpin(argv[i], recursive);
+			continue;
+		}
+
+		struct bchfs_handle fs = bcache_fs_open(argv[i]);
+		char *v = fs_opt_get(fs, "promote_target"), *target = strim(v);
+		struct bch_opt_strs opts = {};
+
+		if (!strcmp(target, "none"))
+			die("%s: filesystem has no promote_target to pin to", argv[i]);
+
+		opts.by_id[Opt_foreground_target] = target;
+		opts.by_id[Opt_background_target] = target;
+		do_setattr(argv[i], opts, recursive);
+
+		printf("%s: pinned to %s\n", argv[i], target);
+		free(v);
+		bcache_fs_close(fs);
+	}
+
+	return 0;
+}
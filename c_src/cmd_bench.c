@@ -0,0 +1,437 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include <linux/random.h>
+#include <linux/sort.h>
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/alloc_foreground.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/fs-common.h"
+#include "libbcachefs/io_write.h"
+#include "libbcachefs/journal.h"
+#include "libbcachefs/super.h"
+
+/*
+ * Microbenchmarks of the userspace stack, run against a freshly formatted
+ * filesystem: data writes go through the same path as migrate, btree inserts
+ * and journal flushes through the same path as fsck
+ */
+
+#define BENCH_WRITE_MAX		(1U << 20)
+
+static char bench_buf[BENCH_WRITE_MAX] __aligned(PAGE_SIZE);
+
+struct bench_result {
+	const char		*name;
+	u64			nr;
+	u64			bytes;
+	u64			ns;
+	DARRAY(u64)		lat;
+};
+
+typedef DARRAY(struct bench_result) bench_results;
+
+static void bench_lat_add(struct bench_result *r, u64 start)
+{
+	if (darray_push(&r->lat, ktime_get_ns() - start))
+		die("memory allocation failure");
+}
+
+static int u64_cmp(const void *_l, const void *_r)
+{
+	const u64 *l = _l, *r = _r;
+
+	return cmp_int(*l, *r);
+}
+
+static u64 bench_lat_pct(struct bench_result *r, unsigned pct)
+{
+	return r->lat.nr
+		? r->lat.data[min_t(size_t, r->lat.nr - 1, r->lat.nr * pct / 100)]
+		: 0;
+}
+
+static void bench_write(struct bch_fs *c, subvol_inum inum,
+			u64 offset, size_t len)
+{
+	struct bch_write_op op;
+	struct bio_vec bv[BENCH_WRITE_MAX / PAGE_SIZE];
+
+	bio_init(&op.wbio.bio, NULL, bv, ARRAY_SIZE(bv), 0);
+	bch2_bio_map(&op.wbio.bio, bench_buf, len);
+
+	bch2_write_op_init(&op, c, bch2_opts_to_inode_opts(c->opts));
+	op.write_point	= writepoint_hashed(0);
+	op.nr_replicas	= c->opts.data_replicas;
+	op.subvol	= inum.subvol;
+	op.pos		= SPOS(inum.inum, offset >> 9, U32_MAX);
+	op.flags |= BCH_WRITE_SYNC;
+
+	int ret = bch2_disk_reservation_get(c, &op.res, len >> 9,
+					    c->opts.data_replicas, 0);
+	if (ret)
+		die("error reserving space: %s", bch2_err_str(ret));
+
+	closure_call(&op.cl, bch2_write, NULL, NULL);
+
+	if (op.error)
+		die("write error: %s", bch2_err_str(op.error));
+}
+
+static subvol_inum bench_create_file(struct bch_fs *c, const char *name)
+{
+	struct qstr qstr = QSTR_INIT(name, strlen(name));
+	struct bch_inode_unpacked root, inode;
+	subvol_inum root_inum = { 1, BCACHEFS_ROOT_INO };
+
+	int ret = bch2_inode_find_by_inum(c, root_inum, &root);
+	if (ret)
+		die("error looking up root directory: %s", bch2_err_str(ret));
+
+	bch2_inode_init_early(c, &inode);
+
+	ret = bch2_trans_do(c, NULL, NULL, 0,
+		bch2_create_trans(trans, root_inum, &root, &inode, &qstr,
+				  0, 0, S_IFREG|0600, 0, NULL, NULL,
+				  (subvol_inum) {}, 0));
+	if (ret)
+		die("error creating %s: %s", name, bch2_err_str(ret));
+
+	return (subvol_inum) { 1, inode.bi_inum };
+}
+
+static void bench_seq_write(struct bch_fs *c, struct bench_result *r, u64 size)
+{
+	subvol_inum inum = bench_create_file(c, "seq_write");
+	u64 start = ktime_get_ns();
+
+	for (u64 offset = 0; offset < size; offset += BENCH_WRITE_MAX) {
+		u64 op_start = ktime_get_ns();
+		unsigned len = min_t(u64, size - offset, BENCH_WRITE_MAX);
+
+		bench_write(c, inum, offset, len);
+		bench_lat_add(r, op_start);
+
+		r->bytes += len;
+		r->nr++;
+	}
+
+	r->ns = ktime_get_ns() - start;
+}
+
+static void bench_rand_write(struct bch_fs *c, struct bench_result *r,
+			     u64 size, unsigned block_size)
+{
+	subvol_inum inum = bench_create_file(c, "rand_write");
+	u64 nr_blocks = max_t(u64, 1, size / block_size);
+	u64 start = ktime_get_ns();
+
+	for (u64 i = 0; i < nr_blocks; i++) {
+		u64 op_start = ktime_get_ns();
+		u64 block = get_random_u64() % nr_blocks;
+
+		bench_write(c, inum, block * block_size, block_size);
+		bench_lat_add(r, op_start);
+
+		r->bytes += block_size;
+		r->nr++;
+	}
+
+	r->ns = ktime_get_ns() - start;
+}
+
+static int bench_insert_key(struct bch_fs *c, u64 offset)
+{
+	struct bkey_i_cookie k;
+
+	bkey_cookie_init(&k.k_i);
+	k.k.p.offset	= offset;
+	k.k.p.snapshot	= U32_MAX;
+
+	return bch2_btree_insert(c, BTREE_ID_xattrs, &k.k_i, NULL, 0);
+}
+
+static void bench_btree_insert(struct bch_fs *c, struct bench_result *r, u64 nr)
+{
+	u64 start = ktime_get_ns();
+
+	for (u64 i = 0; i < nr; i++) {
+		u64 op_start = ktime_get_ns();
+
+		int ret = bench_insert_key(c, get_random_u64());
+		if (ret)
+			die("btree insert error: %s", bch2_err_str(ret));
+		bench_lat_add(r, op_start);
+
+		r->nr++;
+	}
+
+	r->ns = ktime_get_ns() - start;
+}
+
+/* Each flush has a single key to write, so this is mostly device latency */
+static void bench_journal_flush(struct bch_fs *c, struct bench_result *r, u64 nr)
+{
+	u64 start = ktime_get_ns();
+
+	for (u64 i = 0; i < nr; i++) {
+		int ret = bench_insert_key(c, get_random_u64());
+		if (ret)
+			die("btree insert error: %s", bch2_err_str(ret));
+
+		u64 op_start = ktime_get_ns();
+
+		ret = bch2_journal_flush(&c->journal);
+		if (ret)
+			die("journal flush error: %s", bch2_err_str(ret));
+		bench_lat_add(r, op_start);
+
+		r->nr++;
+	}
+
+	r->ns = ktime_get_ns() - start;
+}
+
+static void bench_results_to_text(struct printbuf *out, bench_results *results)
+{
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 16);
+	printbuf_tabstop_push(out, 12);
+	printbuf_tabstop_push(out, 14);
+	printbuf_tabstop_push(out, 14);
+	printbuf_tabstop_push(out, 12);
+	printbuf_tabstop_push(out, 12);
+	printbuf_tabstop_push(out, 12);
+
+	prt_printf(out, "Benchmark\tops\tops/sec\tthroughput\tp50\tp99\tmax");
+	prt_newline(out);
+
+	darray_for_each(*results, r) {
+		u64 us = max_t(u64, 1, div_u64(r->ns, NSEC_PER_USEC));
+
+		prt_str(out, r->name);
+		prt_tab(out);
+		prt_printf(out, "%llu", r->nr);
+		prt_tab_rjust(out);
+		prt_printf(out, "%llu", div64_u64(r->nr * USEC_PER_SEC, us));
+		prt_tab_rjust(out);
+		if (r->bytes) {
+			tools_prt_units_u64(out, div64_u64(r->bytes * USEC_PER_SEC, us));
+			prt_str(out, "/s");
+		}
+		prt_tab_rjust(out);
+		bch2_pr_time_units(out, bench_lat_pct(r, 50));
+		prt_tab_rjust(out);
+		bch2_pr_time_units(out, bench_lat_pct(r, 99));
+		prt_tab_rjust(out);
+		bch2_pr_time_units(out, bench_lat_pct(r, 100));
+		prt_tab_rjust(out);
+		prt_newline(out);
+	}
+}
+
+static void bench_results_json(bench_results *results)
+{
+	printf("{\n  \"version\": \"%s\",\n  \"results\": [", VERSION_STRING);
+
+	darray_for_each(*results, r)
+		printf("%s\n    {"
+		       "\"name\": \"%s\", "
+		       "\"ops\": %llu, "
+		       "\"bytes\": %llu, "
+		       "\"ns\": %llu, "
+		       "\"lat_p50_ns\": %llu, "
+		       "\"lat_p99_ns\": %llu, "
+		       "\"lat_max_ns\": %llu}",
+		       r == results->data ? "" : ",",
+		       r->name, r->nr, r->bytes, r->ns,
+		       bench_lat_pct(r, 50),
+		       bench_lat_pct(r, 99),
+		       bench_lat_pct(r, 100));
+
+	printf("\n  ]\n}\n");
+}
+
+static const char * const bench_names[] = {
+	"seq_write",
+	"rand_write",
+	"btree_insert",
+	"journal_flush",
+	NULL
+};
+
+static void bench_usage(void)
+{
+	puts("bcachefs bench - benchmark the userspace IO paths\n"
+	     "Usage: bcachefs bench [OPTION]... <image file or device>\n"
+	     "\n"
+	     "Formats the target, and runs microbenchmarks against it. If the image\n"
+	     "file doesn't exist, it's created.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -t, --tests=LIST             Benchmarks to run, comma separated (default all):\n"
+	     "                               seq_write, rand_write, btree_insert, journal_flush\n"
+	     "  -s, --size=SIZE              Size of image file to create (default 4G)\n"
+	     "  -d, --data=SIZE              Data to write, for write benchmarks (default 256M)\n"
+	     "  -n, --nr=NR                  Number of btree inserts (default 100000)\n"
+	     "                               and journal flushes (default 1000)\n"
+	     "  -j, --json                   JSON output\n"
+	     "  -f, --force                  Don't ask before formatting an existing filesystem\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Filesystem options, as for format (e.g. --compression) may also be given.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_bench(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "tests",		required_argument,	NULL, 't' },
+		{ "size",		required_argument,	NULL, 's' },
+		{ "data",		required_argument,	NULL, 'd' },
+		{ "nr",			required_argument,	NULL, 'n' },
+		{ "json",		no_argument,		NULL, 'j' },
+		{ "force",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	u64 image_size = 4ULL << 30, data = 256ULL << 20, nr = 0;
+	unsigned tests = ~0U;
+	bool json = false, force = false;
+	int opt;
+
+	struct bch_opt_strs fs_opt_strs =
+		bch2_cmdline_opts_get(&argc, argv, OPT_FORMAT);
+	struct bch_opts fs_opts = bch2_parse_opts(fs_opt_strs);
+
+	while ((opt = getopt_long(argc, argv, "t:s:d:n:jfh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 't':
+			tests = 0;
+			for (char *p = optarg, *name; (name = strsep(&p, ","));)
+				tests |= BIT(read_string_list_or_die(name, bench_names,
+								     "unknown benchmark"));
+			break;
+		case 's':
+			if (bch2_strtoull_h(optarg, &image_size))
+				die("invalid size %s", optarg);
+			break;
+		case 'd':
+			if (bch2_strtoull_h(optarg, &data) || !data)
+				die("invalid size %s", optarg);
+			break;
+		case 'n':
+			if (kstrtoull(optarg, 10, &nr) || !nr)
+				die("invalid number %s", optarg);
+			break;
+		case 'j':
+			json = true;
+			break;
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			bench_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			bench_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply an image file or device");
+	if (argc)
+		die("Too many arguments");
+
+	int fd = open(path, O_RDWR|O_CREAT|O_EXCL, 0600);
+	if (fd >= 0) {
+		if (ftruncate(fd, image_size))
+			die("error truncating %s: %m", path);
+		close(fd);
+		force = true;
+	} else if (errno != EEXIST) {
+		die("error creating %s: %m", path);
+	}
+
+	struct dev_opts dev = dev_opts_default();
+	dev.path = path;
+
+	int ret = open_for_format(&dev, force);
+	if (ret)
+		die("Error opening %s: %s", path, strerror(-ret));
+
+	free(bch2_format(fs_opt_strs, fs_opts, format_opts_default(), &dev, 1));
+	bch2_opt_strs_free(&fs_opt_strs);
+
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_fs *c = bch2_fs_open(&path, 1, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", path, bch2_err_str(PTR_ERR(c)));
+
+	get_random_bytes(bench_buf, sizeof(bench_buf));
+
+	bench_results results = {};
+
+	for (unsigned i = 0; bench_names[i]; i++) {
+		if (!(tests & BIT(i)))
+			continue;
+
+		struct bench_result r = { .name = bench_names[i] };
+
+		if (!json)
+			fprintf(stderr, "Running %s\n", r.name);
+
+		switch (i) {
+		case 0:
+			bench_seq_write(c, &r, data);
+			break;
+		case 1:
+			bench_rand_write(c, &r, data, max_t(unsigned, 4096, block_bytes(c)));
+			break;
+		case 2:
+			bench_btree_insert(c, &r, nr ?: 100000);
+			break;
+		case 3:
+			bench_journal_flush(c, &r, nr ?: 1000);
+			break;
+		}
+
+		sort(r.lat.data, r.lat.nr, sizeof(r.lat.data[0]), u64_cmp, NULL);
+
+		if (darray_push(&results, r))
+			die("memory allocation failure");
+	}
+
+	bch2_fs_stop(c);
+
+	if (json) {
+		bench_results_json(&results);
+	} else {
+		struct printbuf buf = PRINTBUF;
+
+		tools_printbuf_units(&buf, true);
+		bench_results_to_text(&buf, &results);
+		printf("%s", buf.buf);
+		printbuf_exit(&buf);
+	}
+
+	darray_for_each(results, r)
+		darray_exit(&r->lat);
+	darray_exit(&results);
+	return 0;
+}
@@ -0,0 +1,187 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/stat.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/disk_groups.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/super.h"
+
+struct cache_drop {
+	struct bch_devs_mask	devs;
+	u64			extents;
+	u64			sectors;
+};
+
+static void cache_drop_usage(void)
+{
+	puts("bcachefs cache drop - drop cached copies of data\n"
+	     "Usage: bcachefs cache drop [OPTION]... <devices>\n"
+	     "\n"
+	     "Drops every cached pointer on the promote target, freeing that space\n"
+	     "for new promotes. Only cached copies are dropped: data whose only copy\n"
+	     "is on the promote target is left as is.\n"
+	     "\n"
+	     "The kernel has no interface for this, so the filesystem must not be\n"
+	     "mounted; devices is a colon separated list.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -t, --target=target          Drop cached data on this target instead\n"
+	     "  -n, --dry-run                Only count what would be dropped\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static bool cache_drop_ptr(struct cache_drop *d, const struct bch_extent_ptr *ptr)
+{
+	return ptr->cached && test_bit(ptr->dev, d->devs.d);
+}
+
+static int cache_drop_key(struct btree_trans *trans, struct btree_iter *iter,
+			  struct bkey_s_c k, struct cache_drop *d, bool dry_run)
+{
+	if (!bkey_extent_is_direct_data(k.k))
+		return 0;
+
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	unsigned nr_drop = 0, nr_keep = 0;
+
+	bkey_for_each_ptr(ptrs, ptr)
+		if (cache_drop_ptr(d, ptr))
+			nr_drop++;
+		else
+			nr_keep++;
+
+	/* never drop the last pointer: */
+	if (!nr_drop || !nr_keep)
+		return 0;
+
+	d->extents++;
+	d->sectors += k.k->size * nr_drop;
+
+	if (dry_run)
+		return 0;
+
+	struct bkey_i *n = bch2_bkey_make_mut(trans, iter, &k, 0);
+	int ret = PTR_ERR_OR_ZERO(n);
+	if (ret)
+		return ret;
+
+	bch2_bkey_drop_ptrs(bkey_i_to_s(n), ptr, cache_drop_ptr(d, ptr));
+	return 0;
+}
+
+static int cache_drop_btree(struct bch_fs *c, enum btree_id btree,
+			    struct cache_drop *d, bool dry_run)
+{
+	return bch2_trans_run(c,
+		for_each_btree_key_commit(trans, iter, btree, POS_MIN,
+				BTREE_ITER_prefetch|BTREE_ITER_all_snapshots, k,
+				NULL, NULL, BCH_TRANS_COMMIT_no_enospc,
+			cache_drop_key(trans, &iter, k, d, dry_run)));
+}
+
+int cmd_cache_drop(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "target",		required_argument,	NULL, 't' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	struct cache_drop d = {};
+	const char *target_str = NULL;
+	bool dry_run = false;
+	struct stat st;
+	int opt, ret;
+
+	while ((opt = getopt_long(argc, argv, "t:nh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 't':
+			target_str = optarg;
+			break;
+		case 'n':
+			dry_run = true;
+			break;
+		case 'h':
+			cache_drop_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			cache_drop_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	if (!stat(argv[0], &st) && S_ISDIR(st.st_mode))
+		die("%s is a mountpoint: the kernel can't drop cached data on demand, "
+		    "so unmount it and give its devices", argv[0]);
+
+	darray_str devs = get_or_split_cmdline_devs(argc, argv);
+
+	darray_for_each(devs, i)
+		if (dev_mounted(*i))
+			die("%s is mounted: the kernel can't drop cached data on demand, "
+			    "so unmount it first", *i);
+
+	if (dry_run) {
+		opt_set(opts, nochanges,	true);
+		opt_set(opts, read_only,	true);
+	}
+
+	struct bch_fs *c = bch2_fs_open(devs.data, devs.nr, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", devs.data[0], bch2_err_str(PTR_ERR(c)));
+
+	u64 target = c->opts.promote_target;
+
+	if (target_str) {
+		ret = bch2_opt_parse(c, &bch2_opt_table[Opt_promote_target],
+				     target_str, &target, &buf);
+		if (ret < 0)
+			die("invalid target %s: %s", target_str, buf.buf);
+	}
+
+	if (!target)
+		die("no promote_target set; pass --target");
+
+	rcu_read_lock();
+	const struct bch_devs_mask *mask = bch2_target_to_mask(c, target);
+	if (mask)
+		d.devs = *mask;
+	rcu_read_unlock();
+
+	if (!mask)
+		die("target %s doesn't exist", target_str ?: "(promote_target)");
+
+	ret =   cache_drop_btree(c, BTREE_ID_extents, &d, dry_run) ?:
+		cache_drop_btree(c, BTREE_ID_reflink, &d, dry_run);
+	if (ret)
+		fprintf(stderr, "error dropping cached data: %s\n", bch2_err_str(ret));
+
+	printbuf_reset(&buf);
+	prt_printf(&buf, "%s cached data in %llu extents: ",
+		   dry_run ? "would drop" : "dropped", d.extents);
+	tools_prt_units_u64(&buf, d.sectors << 9);
+	printf("%s\n", buf.buf);
+
+	bch2_fs_stop(c);
+	printbuf_exit(&buf);
+	darray_for_each(devs, i)
+		free(*i);
+	darray_exit(&devs);
+	return ret ? EXIT_FAILURE : 0;
+}
@@ -0,0 +1,230 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_io.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/super.h"
+
+struct check_nodes {
+	bool			verbose;
+	u64			nodes;
+	u64			replicas;
+	u64			bad;
+	u64			unreadable;
+};
+
+static void check_nodes_usage(void)
+{
+	puts("bcachefs check-nodes - verify every replica of every btree node\n"
+	     "Usage: bcachefs check-nodes [OPTION]... <devices>\n"
+	     "\n"
+	     "Reads each replica of each btree node separately, and checks checksums,\n"
+	     "bset and key validity and key ordering. Read only.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -b, --btree=btree            Only check this btree\n"
+	     "  -v, --verbose                Print every node checked, not just bad ones\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+/*
+ * Like __bch2_btree_verify(): read one replica into c->verify_data, and
+ * run it through the normal read path's validation
+ */
+static bool check_node_replica(struct bch_fs *c, struct btree *b,
+			       const struct bch_extent_ptr *ptr,
+			       struct printbuf *err)
+{
+	struct btree *v = c->verify_data;
+	bool saw_error = false;
+
+	struct bch_dev *ca = bch2_dev_tryget_noerror(c, ptr->dev);
+	if (!ca || !ca->disk_sb.bdev) {
+		prt_printf(err, "device offline");
+		goto bad;
+	}
+
+	bkey_copy(&v->key, &b->key);
+	v->c.level	= b->c.level;
+	v->c.btree_id	= b->c.btree_id;
+	bch2_btree_keys_init(v);
+
+	ssize_t r = pread(ca->disk_sb.bdev->bd_fd, v->data,
+			  btree_buf_bytes(b), ptr->offset << 9);
+	if (r != btree_buf_bytes(b)) {
+		prt_printf(err, "read error: %s", r < 0 ? strerror(errno) : "short read");
+		goto bad;
+	}
+
+	v->written = 0;
+	if (bch2_btree_node_read_done(c, ca, v, false, &saw_error) || saw_error) {
+		prt_printf(err, "invalid (see errors above)");
+		goto bad;
+	}
+
+	if (v->written != btree_ptr_sectors_written(&b->key) &&
+	    btree_ptr_sectors_written(&b->key)) {
+		prt_printf(err, "written wrong: expected %u sectors, got %u",
+			   btree_ptr_sectors_written(&b->key), v->written);
+		goto bad;
+	}
+
+	bch2_dev_put(ca);
+	return true;
+bad:
+	bch2_dev_put(ca);
+	return false;
+}
+
+static void check_node(struct bch_fs *c, struct check_nodes *s, struct btree *b)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(bkey_i_to_s_c(&b->key));
+	struct printbuf buf = PRINTBUF;
+	unsigned nr_good = 0, nr = 0;
+
+	s->nodes++;
+
+	mutex_lock(&c->verify_lock);
+
+	bkey_for_each_ptr(ptrs, ptr) {
+		struct printbuf err = PRINTBUF;
+		bool ok = check_node_replica(c, b, ptr, &err);
+
+		s->replicas++;
+		nr++;
+		nr_good += ok;
+
+		if (!ok || s->verbose) {
+			struct bch_dev *ca = bch2_dev_tryget_noerror(c, ptr->dev);
+
+			printbuf_reset(&buf);
+			bch2_btree_pos_to_text(&buf, c, b);
+			printf("%s: dev %u (%s) sector %llu: %s\n",
+			       buf.buf, ptr->dev,
+			       ca && ca->name[0] ? ca->name : "missing",
+			       (u64) ptr->offset,
+			       ok ? "ok" : err.buf);
+			bch2_dev_put(ca);
+		}
+
+		if (!ok)
+			s->bad++;
+		printbuf_exit(&err);
+	}
+
+	mutex_unlock(&c->verify_lock);
+
+	if (nr && !nr_good) {
+		printbuf_reset(&buf);
+		bch2_btree_pos_to_text(&buf, c, b);
+		printf("%s: no good replicas\n", buf.buf);
+		s->unreadable++;
+	}
+
+	printbuf_exit(&buf);
+}
+
+static int check_nodes_btree(struct bch_fs *c, struct check_nodes *s, enum btree_id btree)
+{
+	struct btree *root = bch2_btree_id_root(c, btree)->b;
+	if (!root || IS_ERR(root))
+		return 0;
+
+	unsigned root_level = root->c.level;
+	int ret = 0;
+
+	for (unsigned level = 0; level <= root_level && !ret; level++) {
+		struct btree_trans *trans = bch2_trans_get(c);
+		struct btree_iter iter;
+		struct btree *b;
+
+		__for_each_btree_node(trans, iter, btree, POS_MIN, 0, level, 0, b, ret) {
+			if (b->c.level != level)
+				break;
+			check_node(c, s, b);
+		}
+		bch2_trans_iter_exit(trans, &iter);
+		bch2_trans_put(trans);
+
+		if (ret)
+			fprintf(stderr, "error walking %s btree at level %u: %s\n",
+				bch2_btree_id_str(btree), level, bch2_err_str(ret));
+	}
+
+	return ret;
+}
+
+int cmd_check_nodes(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "btree",		required_argument,	NULL, 'b' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct check_nodes s = {};
+	int btree = -1, opt, ret = 0;
+
+	opt_set(opts, read_only,	true);
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, very_degraded,	true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "b:vh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'b':
+			btree = read_string_list_or_die(optarg,
+						__bch2_btree_ids, "btree id");
+			break;
+		case 'v':
+			s.verbose = true;
+			break;
+		case 'h':
+			check_nodes_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			check_nodes_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	c->verify_data = __bch2_btree_node_mem_alloc(c);
+	if (!c->verify_data)
+		die("memory allocation failure");
+	list_del_init(&c->verify_data->list);
+
+	if (btree >= 0)
+		ret = check_nodes_btree(c, &s, btree);
+	else
+		for (unsigned i = 0; i < btree_id_nr_alive(c); i++)
+			ret = check_nodes_btree(c, &s, i) ?: ret;
+
+	printf("%llu nodes, %llu replicas checked: %llu bad replicas, %llu nodes with no good replica\n",
+	       s.nodes, s.replicas, s.bad, s.unreadable);
+
+	bch2_fs_stop(c);
+	return ret || s.bad ? EXIT_FAILURE : 0;
+}
@@ -0,0 +1,374 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/recovery_passes.h"
+#include "libbcachefs/sb-downgrade.h"
+#include "libbcachefs/super.h"
+#include "libbcachefs/super-io.h"
+
+static void compat_usage(void)
+{
+	puts("bcachefs compat - check a filesystem's version against the kernel and tools\n"
+	     "Usage: bcachefs compat [OPTION]... <device>\n"
+	     "\n"
+	     "Compares the on disk version and feature bits with what the running\n"
+	     "kernel and this version of bcachefs-tools support, and says which needs\n"
+	     "upgrading before the filesystem is upgraded (version_upgrade).\n"
+	     "\n"
+	     "Options:\n"
+	     "  -k, --kernel=version         Check against this kernel version (major.minor)\n"
+	     "                               instead of the running kernel's\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Exits 0 if the kernel and tools can both use the filesystem as is, 1 if not.\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static void version_col(struct printbuf *out, unsigned v)
+{
+	if (v)
+		bch2_version_to_text(out, v);
+	else
+		prt_str(out, "unknown");
+	prt_tab(out);
+}
+
+/*
+ * What mounting a filesystem at version @fs with an implementation that
+ * supports up to @impl does to it
+ */
+static bool version_check(struct printbuf *out, const char *who,
+			  unsigned fs, unsigned impl)
+{
+	if (!impl) {
+		prt_printf(out, "%s: version unknown (module not loaded, or too old to report it)\n", who);
+		return false;
+	}
+
+	if (fs < bcachefs_metadata_version_min ||
+	    BCH_VERSION_MAJOR(fs) > BCH_VERSION_MAJOR(impl)) {
+		prt_printf(out, "%s: can't use this filesystem, the %s needs upgrading\n", who, who);
+		return false;
+	}
+
+	if (fs > impl) {
+		prt_printf(out, "%s: filesystem is newer, it will be downgraded to ", who);
+		bch2_version_to_text(out, impl);
+		prt_printf(out, " when used read-write; upgrade the %s to avoid that\n", who);
+		return false;
+	}
+
+	unsigned compat = min(bch2_latest_compatible_version(fs), impl);
+
+	prt_printf(out, "%s: ok", who);
+	if (compat > fs) {
+		prt_str(out, "; version_upgrade=compatible will upgrade to ");
+		bch2_version_to_text(out, compat);
+	}
+	if (impl > compat) {
+		prt_str(out, "; version_upgrade=incompatible will upgrade to ");
+		bch2_version_to_text(out, impl);
+	}
+	prt_newline(out);
+	return true;
+}
+
+int cmd_compat(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "kernel",		required_argument,	NULL, 'k' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	unsigned kernel = kernel_metadata_version();
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "k:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'k':
+			kernel = parse_metadata_version(optarg);
+			break;
+		case 'h':
+			compat_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			compat_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *dev = arg_pop();
+	if (!dev)
+		die("Please supply a device");
+	if (argc)
+		die("too many arguments");
+
+	struct bch_opts opts = bch2_opts_empty();
+	opt_set(opts, noexcl,	true);
+	opt_set(opts, nochanges, true);
+
+	struct bch_sb_handle sb;
+	int ret = bch2_read_super(dev, &opts, &sb);
+	if (ret)
+		die("Error opening %s: %s", dev, bch2_err_str(ret));
+
+	unsigned version	= le16_to_cpu(sb.sb->version);
+	unsigned version_min	= le16_to_cpu(sb.sb->version_min);
+	unsigned tools		= bcachefs_metadata_version_current;
+	u64 features		= le64_to_cpu(sb.sb->features[0]);
+	u64 compat		= le64_to_cpu(sb.sb->compat[0]);
+	u64 unknown_features	= features & ~(~0ULL >> (64 - BCH_FEATURE_NR));
+	u64 unknown_compat	= compat & ~(~0ULL >> (64 - BCH_COMPAT_NR));
+	struct printbuf buf = PRINTBUF;
+
+	printbuf_tabstop_push(&buf, 24);
+	printbuf_tabstop_push(&buf, 40);
+
+	prt_printf(&buf, "Filesystem version:\t");
+	version_col(&buf, version);
+	prt_printf(&buf, "\nOldest metadata:\t");
+	version_col(&buf, version_min);
+	prt_printf(&buf, "\nUpgrade complete:\t");
+	version_col(&buf, BCH_SB_VERSION_UPGRADE_COMPLETE(sb.sb));
+	prt_printf(&buf, "\nTools version:\t");
+	version_col(&buf, tools);
+	prt_printf(&buf, "\nKernel version:\t");
+	version_col(&buf, kernel);
+	prt_printf(&buf, "\nFeatures:\t");
+	bch2_prt_bitflags(&buf, bch2_sb_features, features & ~unknown_features);
+	prt_printf(&buf, "\nCompat:\t");
+	bch2_prt_bitflags(&buf, bch2_sb_compat, compat & ~unknown_compat);
+	prt_str(&buf, "\n\n");
+
+	bool ok = true;
+
+	/*
+	 * Feature bits predate the versioning scheme: everything that
+	 * supports a 1.x version knows all of them, so only the tools can be
+	 * too old
+	 */
+	if (unknown_features || unknown_compat) {
+		prt_printf(&buf, "tools: unknown feature bits %llx, compat bits %llx; the tools need upgrading\n",
+			   unknown_features, unknown_compat);
+		ok = false;
+	}
+
+	ok &= version_check(&buf, "tools", version, tools);
+	ok &= version_check(&buf, "kernel", version, kernel);
+
+	if (kernel > tools &&
+	    BCH_VERSION_MAJOR(version) <= BCH_VERSION_MAJOR(kernel))
+		prt_printf(&buf, "\nThe kernel is newer than the tools: once the kernel upgrades the filesystem,\n"
+			   "fsck will use the kernel's implementation, and other offline commands\n"
+			   "will downgrade it. Upgrade the tools before upgrading the filesystem.\n");
+
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+	bch2_free_super(&sb);
+	return ok ? 0 : EXIT_FAILURE;
+}
+
+static void version_upgrade_usage(void)
+{
+	puts("bcachefs version-upgrade - upgrade or downgrade the on disk version, offline\n"
+	     "Usage: bcachefs version-upgrade [OPTION]... <devices>\n"
+	     "\n"
+	     "Options:\n"
+	     "  -t, --to=version             Version to upgrade or downgrade to (major.minor);\n"
+	     "                               default is the newest these tools support\n"
+	     "  -n, --dry-run                Only show the recovery passes that would run\n"
+	     "  -s, --schedule               Only update the superblock: the passes run on\n"
+	     "                               the next mount, by whatever kernel mounts it\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static void version_upgrade_passes_to_text(struct printbuf *out, u64 passes)
+{
+	u64 fsck = bch2_fsck_recovery_passes();
+	unsigned nr_fsck = hweight64(passes & fsck);
+	unsigned nr_other = hweight64(passes & ~fsck);
+
+	if (!passes) {
+		prt_str(out, "No recovery passes required\n");
+		return;
+	}
+
+	prt_str(out, "Recovery passes:\n");
+	for (unsigned i = 0; i < BCH_RECOVERY_PASS_NR; i++)
+		if (passes & BIT_ULL(i))
+			prt_printf(out, "  %s\n", bch2_recovery_passes[i]);
+
+	/*
+	 * We don't know how big the filesystem's metadata is without reading
+	 * it; the closest thing to a time estimate is how much of a full fsck
+	 * this is:
+	 */
+	prt_printf(out, "Estimated time: %u of the %u passes a full fsck runs",
+		   nr_fsck, (unsigned) hweight64(fsck));
+	if (nr_other)
+		prt_printf(out, ", plus %u others", nr_other);
+	prt_newline(out);
+}
+
+int cmd_version_upgrade(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "to",			required_argument,	NULL, 't' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "schedule",		no_argument,		NULL, 's' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	unsigned to = bcachefs_metadata_version_current;
+	bool dry_run = false, schedule = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "t:nsh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 't':
+			to = parse_metadata_version(optarg);
+			break;
+		case 'n':
+			dry_run = true;
+			break;
+		case 's':
+			schedule = true;
+			break;
+		case 'h':
+			version_upgrade_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			version_upgrade_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	for (unsigned i = 0; i < argc; i++)
+		if (dev_mounted(argv[i]))
+			die("%s is mounted: remount with -o version_upgrade= instead", argv[i]);
+
+	if (to > bcachefs_metadata_version_current) {
+		struct printbuf buf = PRINTBUF;
+
+		bch2_version_to_text(&buf, bcachefs_metadata_version_current);
+		die("these tools only support versions up to %s", buf.buf);
+	}
+
+	opt_set(opts, nostart, true);
+	if (dry_run)
+		opt_set(opts, nochanges, true);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	unsigned version	= c->sb.version;
+	unsigned old		= c->sb.version_upgrade_complete ?: version;
+	struct printbuf buf	= PRINTBUF;
+
+	if (to < version &&
+	    BCH_VERSION_MAJOR(to) != BCH_VERSION_MAJOR(version))
+		die("can't downgrade across a major version");
+
+	if (to < bcachefs_metadata_version_min)
+		die("version too old");
+
+	if (to == version && old == version) {
+		prt_str(&buf, "Already at ");
+		bch2_version_to_text(&buf, version);
+		printf("%s\n", buf.buf);
+		goto out;
+	}
+
+	mutex_lock(&c->sb_lock);
+	struct bch_sb_field_ext *ext = bch2_sb_field_get(c->disk_sb.sb, ext);
+	if (!ext)
+		die("superblock has no ext section");
+
+	u64 passes = le64_to_cpu(ext->recovery_passes_required[0]);
+
+	if (to >= version) {
+		prt_printf(&buf, "%s version upgrade from ",
+			   BCH_VERSION_MAJOR(old) != BCH_VERSION_MAJOR(to)
+			   ? "Incompatible" : "Compatible");
+		bch2_version_to_text(&buf, old);
+
+		bch2_sb_set_upgrade(c, old, to);
+		bch2_sb_upgrade(c, to);
+	} else {
+		prt_str(&buf, "Version downgrade from ");
+		bch2_version_to_text(&buf, version);
+
+		if (!bch2_sb_field_get(c->disk_sb.sb, downgrade))
+			fprintf(stderr, "warning: superblock has no downgrade information, "
+				"recovery passes may be missing\n");
+
+		/* as bch2_check_version_downgrade(), but to an arbitrary version: */
+		bch2_sb_set_downgrade(c, BCH_VERSION_MINOR(to), BCH_VERSION_MINOR(version));
+		c->disk_sb.sb->version = cpu_to_le16(to);
+		if (le16_to_cpu(c->disk_sb.sb->version_min) > to)
+			c->disk_sb.sb->version_min = cpu_to_le16(to);
+		if (BCH_SB_VERSION_UPGRADE_COMPLETE(c->disk_sb.sb) > to)
+			SET_BCH_SB_VERSION_UPGRADE_COMPLETE(c->disk_sb.sb, to);
+	}
+
+	passes = le64_to_cpu(ext->recovery_passes_required[0]) & ~passes;
+
+	prt_str(&buf, " to ");
+	bch2_version_to_text(&buf, to);
+	prt_newline(&buf);
+	version_upgrade_passes_to_text(&buf, bch2_recovery_passes_from_stable(passes));
+	printf("%s", buf.buf);
+	printbuf_reset(&buf);
+
+	if (!dry_run)
+		bch2_write_super(c);
+	mutex_unlock(&c->sb_lock);
+
+	if (dry_run || schedule) {
+		if (schedule) {
+			unsigned kernel = kernel_metadata_version();
+
+			if (kernel && kernel < to) {
+				bch2_version_to_text(&buf, kernel);
+				fprintf(stderr, "warning: the running kernel only supports up to %s, "
+					"and will downgrade instead\n", buf.buf);
+			}
+			printf("Scheduled; the recovery passes will run on the next mount\n");
+		}
+		goto out;
+	}
+
+	bch2_fs_stop(c);
+
+	/*
+	 * The superblock now has the new version and the passes it needs;
+	 * open it normally to run them, without any further implicit upgrade:
+	 */
+	opts = bch2_opts_empty();
+	opt_set(opts, version_upgrade, BCH_VERSION_UPGRADE_none);
+
+	c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+	printf("Done\n");
+out:
+	printbuf_exit(&buf);
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -1,8 +1,16 @@
+#include <ctype.h>
+#include <dirent.h>
+#include <fcntl.h>
 #include <getopt.h>
+#include <stdio.h>
+#include <unistd.h>
+
+#include <linux/sort.h>
 
 #include "cmds.h"
 #include "libbcachefs.h"
 #include "libbcachefs/super-io.h"
+#include "tools-util.h"
 
 static void reset_counters_usage(void)
 {
@@ -49,3 +57,205 @@ int cmd_reset_counters(int argc, char *argv[])
 	bch2_free_super(&sb);
 	return 0;
 }
+
+/*
+ * fs counters: the persistent counters, from sysfs
+ *
+ * sysfs prints them with prt_human_readable_u64(), so values past 1024 only
+ * have three significant digits - rates of busy counters are approximate
+ */
+
+struct counter {
+	char			*name;
+	double			since_mount;
+	double			since_creation;
+	double			delta;
+};
+
+typedef DARRAY(struct counter) counters;
+
+static double parse_human_readable(const char *s)
+{
+	char *end;
+	double v = strtod(s, &end);
+
+	while (*end == ' ')
+		end++;
+
+	const char *units = "kmgtpe";
+	const char *u = *end ? strchr(units, tolower(*end)) : NULL;
+	double base = strchr(end, 'i') ? 1024 : 1000;
+
+	if (u)
+		for (unsigned i = 0; i <= u - units; i++)
+			v *= base;
+	return v;
+}
+
+static int counter_cmp(const void *_l, const void *_r)
+{
+	const struct counter *l = _l, *r = _r;
+
+	return strcmp(l->name, r->name);
+}
+
+static int counter_delta_cmp(const void *_l, const void *_r)
+{
+	const struct counter *l = _l, *r = _r;
+
+	return (l->delta < r->delta) - (l->delta > r->delta) ?:
+		strcmp(l->name, r->name);
+}
+
+static counters counters_read(struct bchfs_handle fs, char **names, unsigned nr_names)
+{
+	counters ret = {};
+
+	int fd = openat(fs.sysfs_fd, "counters", O_RDONLY|O_DIRECTORY);
+	if (fd < 0)
+		die("error opening counters: %m");
+
+	DIR *dir = fdopendir(fd);
+	struct dirent *d;
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (d->d_name[0] == '.')
+			continue;
+
+		bool want = !nr_names;
+		for (unsigned i = 0; i < nr_names; i++)
+			want |= !strcmp(names[i], d->d_name);
+		if (!want)
+			continue;
+
+		struct counter c = { .name = strdup(d->d_name) };
+		char *text = read_file_str(fd, d->d_name), *p = text, *line;
+
+		while (p && (line = strsep(&p, "\n"))) {
+			char *v = strchr(line, ':');
+
+			if (!v)
+				continue;
+			*v++ = '\0';
+
+			if (!strcmp(strim(line), "since mount"))
+				c.since_mount = parse_human_readable(strim(v));
+			else if (!strcmp(strim(line), "since filesystem creation"))
+				c.since_creation = parse_human_readable(strim(v));
+		}
+		free(text);
+
+		if (darray_push(&ret, c))
+			die("memory allocation failure");
+	}
+
+	if (errno)
+		die("error reading counters: %m");
+	closedir(dir);
+
+	sort(ret.data, ret.nr, sizeof(ret.data[0]), counter_cmp, NULL);
+	return ret;
+}
+
+static void counters_exit(counters *c)
+{
+	darray_for_each(*c, i)
+		free(i->name);
+	darray_exit(c);
+}
+
+static void fs_counters_usage(void)
+{
+	puts("bcachefs fs counters - show the persistent counters of a mounted filesystem\n"
+	     "Usage: bcachefs fs counters [OPTION]... <mountpoint> [<counter>...]\n"
+	     "\n"
+	     "Options:\n"
+	     "  -d, --diff=seconds           Sample twice, this far apart, and show rates\n"
+	     "                               (busiest first)\n"
+	     "  -a, --all                    With --diff, include counters that didn't change\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Counters past 1024 are only shown by the kernel with three significant\n"
+	     "digits; use a longer interval for rates of busy counters.\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_fs_counters(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "diff",		required_argument,	NULL, 'd' },
+		{ "all",		no_argument,		NULL, 'a' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct printbuf buf = PRINTBUF;
+	unsigned diff = 0;
+	bool all = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "d:ah", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'd':
+			if (kstrtouint(optarg, 10, &diff) || !diff)
+				die("invalid interval %s", optarg);
+			break;
+		case 'a':
+			all = true;
+			break;
+		case 'h':
+			fs_counters_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			fs_counters_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	struct bchfs_handle fs = bcache_fs_open(fs_path);
+	counters c = counters_read(fs, argv, argc);
+
+	if (argc && c.nr != argc)
+		die("Unknown counter");
+
+	printbuf_tabstop_push(&buf, 40);
+	printbuf_tabstop_push(&buf, 16);
+	printbuf_tabstop_push(&buf, 16);
+
+	if (!diff) {
+		prt_printf(&buf, "counter\tsince mount\rsince creation\r\n");
+		darray_for_each(c, i)
+			prt_printf(&buf, "%s\t%.0f\r%.0f\r\n",
+				   i->name, i->since_mount, i->since_creation);
+	} else {
+		sleep(diff);
+
+		counters c2 = counters_read(fs, argv, argc);
+
+		darray_for_each(c2, i) {
+			struct counter *old = bsearch(i, c.data, c.nr, sizeof(*i), counter_cmp);
+
+			i->delta = old ? max(i->since_mount - old->since_mount, 0.0) : 0;
+		}
+
+		sort(c2.data, c2.nr, sizeof(c2.data[0]), counter_delta_cmp, NULL);
+
+		prt_printf(&buf, "counter\tchange\rper second\r\n");
+		darray_for_each(c2, i)
+			if (i->delta || all)
+				prt_printf(&buf, "%s\t%.0f\r%.1f\r\n",
+					   i->name, i->delta, i->delta / diff);
+
+		counters_exit(&c2);
+	}
+
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+	counters_exit(&c);
+	bcache_fs_close(fs);
+	return 0;
+}
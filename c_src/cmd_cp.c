@@ -0,0 +1,322 @@
+#include <dirent.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <libgen.h>
+#include <stdio.h>
+#include <sys/ioctl.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+#include <linux/fs.h>
+#include <linux/xxhash.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+enum cp_reflink {
+	CP_REFLINK_auto,
+	CP_REFLINK_always,
+	CP_REFLINK_never,
+};
+
+static const char * const cp_reflink_strs[] = {
+	"auto",
+	"always",
+	"never",
+	NULL
+};
+
+struct cp_opts {
+	enum cp_reflink		reflink;
+	bool			recursive;
+	bool			dedupe;
+	bool			verbose;
+
+	u64			reflinked;
+	u64			copied;
+	u64			deduped;
+	u64			dedupe_bytes;
+};
+
+static u64 file_hash(int fd, u64 size)
+{
+	struct xxh64_state state;
+	static char buf[1 << 20];
+
+	xxh64_reset(&state, 0);
+
+	for (u64 pos = 0; pos < size;) {
+		ssize_t r = pread(fd, buf, min_t(u64, sizeof(buf), size - pos), pos);
+		if (r < 0)
+			die("read error: %m");
+		if (!r)
+			break;
+
+		xxh64_update(&state, buf, r);
+		pos += r;
+	}
+
+	return xxh64_digest(&state);
+}
+
+static void copy_data(struct cp_opts *opts, int src_fd, int dst_fd, u64 size,
+		      const char *src, const char *dst)
+{
+	if (opts->reflink != CP_REFLINK_never) {
+		if (!ioctl(dst_fd, FICLONE, src_fd)) {
+			opts->reflinked++;
+			return;
+		}
+
+		if (opts->reflink == CP_REFLINK_always)
+			die("error reflinking %s to %s: %m", src, dst);
+
+		/*
+		 * Different filesystems, or reflink not supported:
+		 * copy_file_range() may still be able to share data, or at
+		 * least avoid the copy through userspace
+		 */
+		bool fallback = false;
+		for (u64 pos = 0; pos < size;) {
+			ssize_t r = copy_file_range(src_fd, NULL, dst_fd, NULL,
+						    size - pos, 0);
+			if (r < 0 && !pos &&
+			    (errno == EXDEV || errno == EINVAL ||
+			     errno == EOPNOTSUPP || errno == ENOSYS)) {
+				fallback = true;
+				break;
+			}
+			if (r < 0)
+				die("error copying %s to %s: %m", src, dst);
+			if (!r)
+				break;
+			pos += r;
+		}
+
+		if (!fallback) {
+			opts->copied++;
+			return;
+		}
+	}
+
+	static char buf[1 << 20];
+
+	for (u64 pos = 0; pos < size;) {
+		ssize_t r = pread(src_fd, buf, min_t(u64, sizeof(buf), size - pos), pos);
+		if (r < 0)
+			die("error reading %s: %m", src);
+		if (!r)
+			break;
+
+		xpwrite(dst_fd, buf, r, pos, dst);
+		pos += r;
+	}
+	opts->copied++;
+}
+
+static void copy_file(struct cp_opts *opts, const char *src, const char *dst,
+		      struct stat *src_st)
+{
+	int src_fd = xopen(src, O_RDONLY);
+	struct stat dst_st;
+
+	if (opts->dedupe &&
+	    !stat(dst, &dst_st) &&
+	    S_ISREG(dst_st.st_mode) &&
+	    dst_st.st_size == src_st->st_size &&
+	    !(dst_st.st_dev == src_st->st_dev && dst_st.st_ino == src_st->st_ino)) {
+		int dst_fd = xopen(dst, O_RDWR);
+
+		if (file_hash(src_fd, src_st->st_size) ==
+		    file_hash(dst_fd, dst_st.st_size)) {
+			/* the kernel compares the data, so a hash collision is harmless: */
+			s64 done = dedupe_range(src_fd, 0, dst_fd, 0, src_st->st_size);
+
+			if (done < 0) {
+				errno = -done;
+				die("%s: dedupe error: %m", dst);
+			}
+
+			if (done == src_st->st_size) {
+				if (opts->verbose)
+					printf("%s -> %s (deduplicated)\n", src, dst);
+				opts->deduped++;
+				opts->dedupe_bytes += done;
+				close(dst_fd);
+				close(src_fd);
+				return;
+			}
+		}
+		close(dst_fd);
+	}
+
+	int dst_fd = xopen(dst, O_WRONLY|O_CREAT|O_TRUNC, src_st->st_mode & 07777);
+
+	copy_data(opts, src_fd, dst_fd, src_st->st_size, src, dst);
+
+	if (opts->verbose)
+		printf("%s -> %s\n", src, dst);
+
+	close(dst_fd);
+	close(src_fd);
+}
+
+static void copy_symlink(const char *src, const char *dst)
+{
+	char target[PATH_MAX];
+	ssize_t len = readlink(src, target, sizeof(target) - 1);
+
+	if (len < 0)
+		die("error reading symlink %s: %m", src);
+	target[len] = '\0';
+
+	if (unlink(dst) && errno != ENOENT)
+		die("error removing %s: %m", dst);
+	if (symlink(target, dst))
+		die("error creating symlink %s: %m", dst);
+}
+
+static void copy_path(struct cp_opts *opts, const char *src, const char *dst)
+{
+	struct stat st;
+
+	if (lstat(src, &st))
+		die("error statting %s: %m", src);
+
+	if (S_ISREG(st.st_mode)) {
+		copy_file(opts, src, dst, &st);
+	} else if (S_ISLNK(st.st_mode)) {
+		copy_symlink(src, dst);
+	} else if (S_ISDIR(st.st_mode)) {
+		if (!opts->recursive) {
+			fprintf(stderr, "%s is a directory, skipping (use -r)\n", src);
+			return;
+		}
+
+		if (mkdir(dst, st.st_mode & 07777) && errno != EEXIST)
+			die("error creating %s: %m", dst);
+
+		DIR *dir = opendir(src);
+		struct dirent *d;
+
+		if (!dir)
+			die("error opening %s: %m", src);
+
+		while ((errno = 0), (d = readdir(dir))) {
+			if (!strcmp(d->d_name, ".") ||
+			    !strcmp(d->d_name, ".."))
+				continue;
+
+			char *s = mprintf("%s/%s", src, d->d_name);
+			char *t = mprintf("%s/%s", dst, d->d_name);
+
+			copy_path(opts, s, t);
+			free(t);
+			free(s);
+		}
+
+		if (errno)
+			die("error reading %s: %m", src);
+		closedir(dir);
+	} else {
+		fprintf(stderr, "%s: not a regular file, directory or symlink, skipping\n", src);
+	}
+}
+
+static void cp_usage(void)
+{
+	puts("bcachefs cp - copy files, sharing data with reflink when possible\n"
+	     "Usage: bcachefs cp [OPTION]... <source> <dest>\n"
+	     "   or: bcachefs cp [OPTION]... <source>... <directory>\n"
+	     "\n"
+	     "Options:\n"
+	     "      --reflink=(auto|always|never)\n"
+	     "                               auto (default): reflink, falling back to\n"
+	     "                               copy_file_range and then a normal copy;\n"
+	     "                               always: fail if the data can't be shared;\n"
+	     "                               never: always copy the data\n"
+	     "  -d, --dedupe                 If a destination file exists with the same\n"
+	     "                               contents, deduplicate it against the source\n"
+	     "                               (FIDEDUPERANGE) instead of overwriting it\n"
+	     "  -r, --recursive              Copy directories recursively\n"
+	     "  -v, --verbose                List each file copied, and print a summary\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_cp(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "reflink",		optional_argument,	NULL, 'R' },
+		{ "dedupe",		no_argument,		NULL, 'd' },
+		{ "recursive",		no_argument,		NULL, 'r' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct cp_opts opts = { .reflink = CP_REFLINK_auto };
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "drvh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'R':
+			opts.reflink = optarg
+				? read_string_list_or_die(optarg, cp_reflink_strs, "reflink mode")
+				: CP_REFLINK_always;
+			break;
+		case 'd':
+			opts.dedupe = true;
+			break;
+		case 'r':
+			opts.recursive = true;
+			break;
+		case 'v':
+			opts.verbose = true;
+			break;
+		case 'h':
+			cp_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			cp_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (argc < 2)
+		die("Please supply a source and destination");
+
+	char *dst = argv[--argc];
+	struct stat dst_st;
+	bool dst_is_dir = !stat(dst, &dst_st) && S_ISDIR(dst_st.st_mode);
+
+	if (argc > 1 && !dst_is_dir)
+		die("%s is not a directory", dst);
+
+	for (unsigned i = 0; i < argc; i++) {
+		if (dst_is_dir) {
+			char *src = strdup(argv[i]);
+			char *t = mprintf("%s/%s", dst, basename(src));
+
+			copy_path(&opts, argv[i], t);
+			free(t);
+			free(src);
+		} else {
+			copy_path(&opts, argv[i], dst);
+		}
+	}
+
+	if (opts.verbose || opts.dedupe) {
+		struct printbuf buf = PRINTBUF;
+
+		prt_printf(&buf, "%llu reflinked, %llu copied, %llu deduplicated (",
+			   opts.reflinked, opts.copied, opts.deduped);
+		prt_human_readable_u64(&buf, opts.dedupe_bytes);
+		prt_printf(&buf, ")\n");
+		printf("%s", buf.buf);
+		printbuf_exit(&buf);
+	}
+
+	return 0;
+}
@@ -1,14 +1,29 @@
 
 
+#include <getopt.h>
 #include <stdio.h>
 #include <sys/ioctl.h>
 
+#include <linux/sort.h>
+
 #include "libbcachefs/bcachefs_ioctl.h"
+#include "libbcachefs/alloc_foreground.h"
 #include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/checksum.h"
+#include "libbcachefs/compress.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/inode.h"
 #include "libbcachefs/move.h"
+#include "libbcachefs/replicas.h"
+#include "libbcachefs/super.h"
 
 #include "cmds.h"
+#include "data_job.h"
 #include "libbcachefs.h"
+#include "progress.h"
 
 int data_usage(void)
 {
@@ -17,6 +32,7 @@ int data_usage(void)
 	     "\n"
 	     "Commands:\n"
 	     "  rereplicate                     Rereplicate degraded data\n"
+	     "  rewrite                         Change the checksum or compression type of data\n"
 	     "  job                             Kick off low level data jobs\n"
 	     "\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
@@ -27,27 +43,195 @@ static void data_rereplicate_usage(void)
 {
 	puts("bcachefs data rereplicate\n"
 	     "Usage: bcachefs data rereplicate filesystem\n"
+	     "   or: bcachefs data rereplicate --what=types devices\n"
+	     "   or: bcachefs data rereplicate --buckets=report devices...\n"
 	     "\n"
 	     "Walks existing data in a filesystem, writing additional copies\n"
 	     "of any degraded data\n"
 	     "\n"
+	     "With --what, the filesystem must be unmounted, and devices are its devices\n"
+	     "separated by colons: the kernel's rereplicate does every kind of data,\n"
+	     "so the job is run here\n"
+	     "\n"
+	     "With --buckets, the filesystem must be unmounted: only extents with\n"
+	     "data in the buckets listed in the report (from device scan) are\n"
+	     "rewritten, replacing the copy in the bad bucket\n"
+	     "\n"
 	     "Options:\n"
+	     "  -b, --buckets=report        Only rewrite data in these buckets\n"
+	     "  -w, --what=types            Only rereplicate metadata and/or user data\n"
+	     "                              (comma separated; default both)\n"
+	     "      --no-progress           Don't report progress\n"
+	     "      --progress-json         Report progress as JSON lines on stderr\n"
 	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
 }
 
+struct dev_bucket {
+	u32			dev;
+	u64			bucket;
+};
+
+typedef DARRAY(struct dev_bucket) dev_buckets;
+
+static int dev_bucket_cmp(const void *_l, const void *_r)
+{
+	const struct dev_bucket *l = _l, *r = _r;
+
+	return cmp_int(l->dev, r->dev) ?: cmp_int(l->bucket, r->bucket);
+}
+
+/* The device scan report: one dev:bucket per line, then error counts */
+static dev_buckets read_bucket_report(const char *path)
+{
+	FILE *f = fopen(path, "r");
+	if (!f)
+		die("error opening %s: %m", path);
+
+	dev_buckets buckets = {};
+	char *line = NULL;
+	size_t n = 0;
+	unsigned lineno = 0;
+
+	while (getline(&line, &n, f) >= 0) {
+		struct dev_bucket b;
+		char *l = strim(line);
+
+		lineno++;
+		if (!*l || *l == '#')
+			continue;
+
+		if (sscanf(l, "%u:%llu", &b.dev, &b.bucket) != 2)
+			die("%s:%u: expected dev:bucket", path, lineno);
+
+		if (darray_push(&buckets, b))
+			die("memory allocation failure");
+	}
+
+	free(line);
+	fclose(f);
+
+	sort(buckets.data, buckets.nr, sizeof(buckets.data[0]), dev_bucket_cmp, NULL);
+	return buckets;
+}
+
+static bool bad_buckets_pred(struct bch_fs *c, void *arg,
+			     struct bkey_s_c k,
+			     struct bch_io_opts *io_opts,
+			     struct data_update_opts *data_opts)
+{
+	dev_buckets *buckets = arg;
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	unsigned i = 0;
+
+	data_opts->rewrite_ptrs		= 0;
+	data_opts->target		= 0;
+	data_opts->extra_replicas	= 0;
+	data_opts->btree_insert_flags	= 0;
+
+	rcu_read_lock();
+	bkey_for_each_ptr(ptrs, ptr) {
+		struct bch_dev *ca = bch2_dev_rcu(c, ptr->dev);
+
+		if (ca) {
+			struct dev_bucket b = {
+				.dev	= ptr->dev,
+				.bucket	= PTR_BUCKET_NR(ca, ptr),
+			};
+
+			if (bsearch(&b, buckets->data, buckets->nr,
+				    sizeof(b), dev_bucket_cmp))
+				data_opts->rewrite_ptrs |= 1U << i;
+		}
+		i++;
+	}
+	rcu_read_unlock();
+
+	return data_opts->rewrite_ptrs != 0;
+}
+
+static int rereplicate_buckets(const char *report, int argc, char *argv[])
+{
+	struct bch_opts opts = bch2_opts_empty();
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	dev_buckets buckets = read_bucket_report(report);
+	if (!buckets.nr) {
+		printf("No bad buckets in %s\n", report);
+		return 0;
+	}
+
+	opt_set(opts, degraded,		true);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	struct bch_move_stats stats;
+	bch2_move_stats_init(&stats, "rereplicate");
+
+	int ret = bch2_move_data(c, BBPOS_MIN, BBPOS_MAX, NULL, &stats,
+				 writepoint_hashed((unsigned long) current),
+				 false, bad_buckets_pred, &buckets) ?:
+		bch2_replicas_gc2(c);
+
+	printf("Rewrote %llu extents, %llu sectors\n",
+	       atomic64_read(&stats.keys_moved),
+	       atomic64_read(&stats.sectors_moved));
+	bch2_move_stats_exit(&stats, c);
+
+	if (ret)
+		fprintf(stderr, "error rewriting data: %s\n", bch2_err_str(ret));
+
+	darray_exit(&buckets);
+	bch2_fs_stop(c);
+	return ret ? 1 : 0;
+}
+
 int cmd_data_rereplicate(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "buckets",		required_argument,	NULL, 'b' },
+		{ "what",		required_argument,	NULL, 'w' },
+		{ "no-progress",	no_argument,		NULL, OPT_NO_PROGRESS },
+		{ "progress-json",	no_argument,		NULL, OPT_PROGRESS_JSON },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	const char *report = NULL;
+	unsigned skip = 0;
 	int opt;
 
-	while ((opt = getopt(argc, argv, "h")) != -1)
+	while ((opt = getopt_long(argc, argv, "b:w:h", longopts, NULL)) != -1)
 		switch (opt) {
+		case 'b':
+			report = optarg;
+			break;
+		case 'w':
+			skip = data_job_parse_what(optarg);
+			/* cached data is never rereplicated: */
+			skip &= ~DATA_JOB_SKIP_CACHED;
+			if (skip == (DATA_JOB_SKIP_METADATA|DATA_JOB_SKIP_USER))
+				die("nothing to rereplicate: cached data only has one copy");
+			break;
+		case OPT_NO_PROGRESS:
+		case OPT_PROGRESS_JSON:
+			progress_opt(opt);
+			break;
 		case 'h':
 			data_rereplicate_usage();
 		}
 	args_shift(optind);
 
+	if (report) {
+		if (skip)
+			die("--what isn't supported with --buckets");
+		return rereplicate_buckets(report, argc, argv);
+	}
+
 	char *fs_path = arg_pop();
 	if (!fs_path)
 		die("Please supply a filesystem");
@@ -55,6 +239,19 @@ int cmd_data_rereplicate(int argc, char *argv[])
 	if (argc)
 		die("too many arguments");
 
+	if (skip) {
+		if (fs_arg_is_mounted(fs_path))
+			die("--what only works on an unmounted filesystem");
+
+		struct bch_fs *c = fs_arg_open_offline(fs_path, bch2_opts_empty());
+		int ret = data_job_offline(c, (struct bch_ioctl_data) {
+			.op	= BCH_DATA_OP_rereplicate,
+		}, skip);
+
+		bch2_fs_stop(c);
+		return ret ? 1 : 0;
+	}
+
 	return bchu_data(bcache_fs_open(fs_path), (struct bch_ioctl_data) {
 		.op		= BCH_DATA_OP_rereplicate,
 		.start_btree	= 0,
@@ -64,6 +261,397 @@ int cmd_data_rereplicate(int argc, char *argv[])
 	});
 }
 
+static void data_rewrite_usage(void)
+{
+	puts("bcachefs data rewrite - change the checksum or compression type of existing data\n"
+	     "Usage: bcachefs data rewrite [OPTION]... <devices> <path>...\n"
+	     "   or: bcachefs data rewrite [OPTION]... --all <devices>\n"
+	     "\n"
+	     "Rewrites the extents of the given files, or of the whole filesystem, that\n"
+	     "aren't checksummed or compressed with the given types. Paths are relative\n"
+	     "to the root of the filesystem; devices is a colon separated list. Only\n"
+	     "works on unmounted filesystems.\n"
+	     "\n"
+	     "Options aren't changed: set data_checksum or compression with set-option\n"
+	     "or attr set as well, for new data to be written the same way. Without\n"
+	     "--compression, extents keep their compression type; compressed extents\n"
+	     "that need rechecksumming are decompressed, then recompressed.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -c, --checksum=type          none, crc32c, crc64 or xxhash (xxhash64)\n"
+	     "  -C, --compression=type[:level]\n"
+	     "                               none, lz4, gzip or zstd\n"
+	     "  -a, --all                    Rewrite every extent in the filesystem\n"
+	     "  -n, --dry-run                Only count the extents that would be rewritten\n"
+	     "      --no-progress            Don't report progress\n"
+	     "      --progress-json          Report progress as JSON lines on stderr\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+/*
+ * Compressed data can't be rechecksummed as is, and an extent that's already
+ * compressed the way it's being rewritten is written as is: those extents are
+ * written uncompressed, then recompressed in a second pass
+ */
+struct rewrite_recompress {
+	enum btree_id		btree;
+	struct bpos		start;
+	struct bpos		end;
+	u8			compression_opt;
+};
+
+struct data_rewrite {
+	bool			checksum;
+	u8			csum_opt;
+	enum bch_csum_type	csum_type;
+	bool			compression;
+	u8			compression_opt;
+
+	/* extents to rewrite, from the first pass: */
+	u64			extents;
+	u64			sectors;
+	struct progress		progress;
+
+	DARRAY(struct rewrite_recompress) recompress;
+	struct rewrite_recompress *recompress_cur;
+};
+
+/* The compression option that writes @type; there's none for incompressible */
+static u8 compression_type_to_opt(enum bch_compression_type type)
+{
+	if (type == BCH_COMPRESSION_TYPE_lz4_old)
+		return BCH_COMPRESSION_OPT_lz4;
+
+	for (unsigned i = 0; i < ARRAY_SIZE(__bch2_compression_opt_to_type); i++)
+		if (__bch2_compression_opt_to_type[i] == type)
+			return i;
+	return 0;
+}
+
+static bool rewrite_crc_wrong(struct data_rewrite *r, struct bch_extent_crc_unpacked crc)
+{
+	return (r->checksum &&
+		crc.csum_type != r->csum_type) ||
+	       (r->compression &&
+		crc.compression_type != BCH_COMPRESSION_TYPE_incompressible &&
+		crc.compression_type != bch2_compression_opt_to_type(r->compression_opt));
+}
+
+/* Cached pointers are left alone: they'll be dropped eventually */
+static unsigned rewrite_ptrs(struct data_rewrite *r, struct bkey_s_c k)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	unsigned i = 0, ret = 0;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		if (!p.ptr.cached && rewrite_crc_wrong(r, p.crc))
+			ret |= 1U << i;
+		i++;
+	}
+
+	return ret;
+}
+
+static bool rewrite_pred(struct bch_fs *c, void *arg,
+			 struct bkey_s_c k,
+			 struct bch_io_opts *io_opts,
+			 struct data_update_opts *data_opts)
+{
+	struct data_rewrite *r = arg;
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	unsigned i = 0;
+
+	data_opts->rewrite_ptrs = rewrite_ptrs(r, k);
+	if (!data_opts->rewrite_ptrs)
+		return false;
+
+	/*
+	 * The new extent is written with the file's io options - override them;
+	 * without --compression, the extent keeps its compression type:
+	 */
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry)
+		if (data_opts->rewrite_ptrs & (1U << i++))
+			break;
+
+	u8 compression_opt = r->compression
+		? r->compression_opt
+		: compression_type_to_opt(p.crc.compression_type);
+
+	if (r->checksum &&
+	    p.crc.csum_type != r->csum_type &&
+	    crc_is_compressed(p.crc) &&
+	    p.crc.compression_type == bch2_compression_opt_to_type(compression_opt)) {
+		struct rewrite_recompress e = {
+			.btree		= k.k->type == KEY_TYPE_reflink_v
+				? BTREE_ID_reflink : BTREE_ID_extents,
+			.start		= bkey_start_pos(k.k),
+			.end		= k.k->p,
+			.compression_opt = compression_opt,
+		};
+
+		if (darray_push(&r->recompress, e))
+			die("memory allocation failure");
+		compression_opt = 0;
+	}
+
+	if (r->checksum)
+		io_opts->data_checksum = r->csum_opt;
+	io_opts->compression = io_opts->background_compression = compression_opt;
+
+	progress_add(&r->progress, k.k->size << 9);
+	return true;
+}
+
+/* The second pass: extents written uncompressed by rewrite_pred() */
+static bool recompress_pred(struct bch_fs *c, void *arg,
+			    struct bkey_s_c k,
+			    struct bch_io_opts *io_opts,
+			    struct data_update_opts *data_opts)
+{
+	struct data_rewrite *r = arg;
+	struct rewrite_recompress *e = r->recompress_cur;
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	unsigned i = 0;
+
+	if (k.k->p.snapshot != e->start.snapshot)
+		return false;
+
+	data_opts->rewrite_ptrs = 0;
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		if (!p.ptr.cached &&
+		    p.crc.compression_type == BCH_COMPRESSION_TYPE_none)
+			data_opts->rewrite_ptrs |= 1U << i;
+		i++;
+	}
+
+	if (!data_opts->rewrite_ptrs)
+		return false;
+
+	if (r->checksum)
+		io_opts->data_checksum = r->csum_opt;
+	io_opts->compression = io_opts->background_compression = e->compression_opt;
+	return true;
+}
+
+static int rewrite_count(struct bch_fs *c, struct data_rewrite *r,
+			 struct bbpos start, struct bbpos end)
+{
+	int ret = 0;
+
+	for (enum btree_id id = start.btree; id <= end.btree && !ret; id++) {
+		if (!btree_type_has_ptrs(id) ||
+		    !bch2_btree_id_root(c, id)->b)
+			continue;
+
+		ret = bch2_trans_run(c,
+			for_each_btree_key_upto(trans, iter, id,
+					id == start.btree ? start.pos : POS_MIN,
+					id == end.btree   ? end.pos   : SPOS_MAX,
+					BTREE_ITER_all_snapshots|
+					BTREE_ITER_prefetch, k, ({
+				if (bkey_extent_is_direct_data(k.k) &&
+				    rewrite_ptrs(r, k)) {
+					r->extents++;
+					r->sectors += k.k->size;
+				}
+				0;
+			})));
+	}
+
+	return ret;
+}
+
+static int rewrite_range(struct bch_fs *c, struct data_rewrite *r,
+			 struct bbpos start, struct bbpos end)
+{
+	struct bch_move_stats stats;
+	bch2_move_stats_init(&stats, "rewrite");
+
+	int ret = bch2_move_data(c, start, end, NULL, &stats,
+				 writepoint_hashed((unsigned long) current),
+				 false, rewrite_pred, r);
+
+	bch2_move_stats_exit(&stats, c);
+	return ret;
+}
+
+static int rewrite_recompress(struct bch_fs *c, struct data_rewrite *r)
+{
+	struct bch_move_stats stats;
+	struct moving_context ctxt;
+	u64 sectors = 0;
+	int ret = 0;
+
+	darray_for_each(r->recompress, e)
+		sectors += e->end.offset - e->start.offset;
+
+	progress_stage(&r->progress, "recompress", sectors << 9, true);
+
+	bch2_move_stats_init(&stats, "rewrite");
+	bch2_moving_ctxt_init(&ctxt, c, NULL, &stats,
+			      writepoint_hashed((unsigned long) current), false);
+
+	darray_for_each(r->recompress, e) {
+		r->recompress_cur = e;
+		ret = __bch2_move_data(&ctxt, BBPOS(e->btree, e->start),
+				       BBPOS(e->btree, e->end), recompress_pred, r);
+		if (ret)
+			break;
+		progress_add(&r->progress, (e->end.offset - e->start.offset) << 9);
+	}
+
+	bch2_moving_ctxt_exit(&ctxt);
+	bch2_move_stats_exit(&stats, c);
+	return ret;
+}
+
+int cmd_data_rewrite(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "checksum",		required_argument,	NULL, 'c' },
+		{ "compression",	required_argument,	NULL, 'C' },
+		{ "all",		no_argument,		NULL, 'a' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "no-progress",	no_argument,		NULL, OPT_NO_PROGRESS },
+		{ "progress-json",	no_argument,		NULL, OPT_PROGRESS_JSON },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct data_rewrite r = {};
+	struct printbuf err = PRINTBUF;
+	bool all = false, dry_run = false;
+	u64 v;
+	int opt, ret = 0;
+
+	while ((opt = getopt_long(argc, argv, "c:C:anh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'c':
+			if (bch2_opt_parse(NULL, &bch2_opt_table[Opt_data_checksum],
+					   !strcmp(optarg, "xxhash64") ? "xxhash" : optarg,
+					   &v, &err) < 0)
+				die("invalid checksum type %s: %s", optarg, err.buf);
+			r.checksum	= true;
+			r.csum_opt	= v;
+			r.csum_type	= bch2_csum_opt_to_type(v, true);
+			break;
+		case 'C':
+			if (bch2_opt_parse(NULL, &bch2_opt_table[Opt_compression],
+					   optarg, &v, &err) < 0)
+				die("invalid compression type %s: %s", optarg, err.buf);
+			r.compression		= true;
+			r.compression_opt	= v;
+			break;
+		case 'a':
+			all = true;
+			break;
+		case 'n':
+			dry_run = true;
+			break;
+		case OPT_NO_PROGRESS:
+		case OPT_PROGRESS_JSON:
+			progress_opt(opt);
+			break;
+		case 'h':
+			data_rewrite_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			data_rewrite_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!r.checksum && !r.compression)
+		die("Please supply --checksum and/or --compression");
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	if (all != (argc == 1))
+		die(all
+		    ? "--all and paths can't both be given"
+		    : "Please supply path(s), or --all");
+
+	darray_str devs = get_or_split_cmdline_devs(1, argv);
+	args_shift(1);
+
+	darray_for_each(devs, i)
+		if (dev_mounted(*i))
+			die("%s is mounted; data rewrite only works on unmounted filesystems", *i);
+
+	if (dry_run) {
+		opt_set(opts, nochanges,	true);
+		opt_set(opts, read_only,	true);
+	}
+
+	struct bch_fs *c = bch2_fs_open(devs.data, devs.nr, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", devs.data[0], bch2_err_str(PTR_ERR(c)));
+
+	if (r.checksum && c->sb.encryption_type)
+		die("encrypted filesystems always use chacha20/poly1305; the checksum type can't be changed");
+
+	/* the extents of each file, in every snapshot, not counting reflinked data: */
+	DARRAY(struct bbpos) ranges = {};
+
+	if (all &&
+	    (darray_push(&ranges, BBPOS_MIN) ||
+	     darray_push(&ranges, BBPOS_MAX)))
+		die("memory allocation failure");
+
+	for (unsigned i = 0; i < argc; i++) {
+		struct bch_inode_unpacked bi;
+		subvol_inum inum;
+
+		ret = lookup_path(c, argv[i], &inum, &bi);
+		if (ret)
+			die("%s: %s", argv[i], bch2_err_str(ret));
+		if (!S_ISREG(bi.bi_mode))
+			die("%s: not a regular file", argv[i]);
+
+		if (darray_push(&ranges, BBPOS(BTREE_ID_extents, POS(inum.inum, 0))) ||
+		    darray_push(&ranges, BBPOS(BTREE_ID_extents, POS(inum.inum, U64_MAX))))
+			die("memory allocation failure");
+	}
+
+	for (unsigned i = 0; i < ranges.nr && !ret; i += 2)
+		ret = rewrite_count(c, &r, ranges.data[i], ranges.data[i + 1]);
+	if (ret)
+		die("error walking extents: %s", bch2_err_str(ret));
+
+	printf("%llu extents, %llu sectors to rewrite\n", r.extents, r.sectors);
+
+	if (!dry_run && r.extents) {
+		progress_init(&r.progress, "rewrite");
+		progress_stage(&r.progress, NULL, r.sectors << 9, true);
+
+		for (unsigned i = 0; i < ranges.nr && !ret; i += 2)
+			ret = rewrite_range(c, &r, ranges.data[i], ranges.data[i + 1]);
+		if (!ret && r.recompress.nr)
+			ret = rewrite_recompress(c, &r);
+		progress_finish(&r.progress);
+
+		if (ret)
+			fprintf(stderr, "error rewriting data: %s\n", bch2_err_str(ret));
+	}
+
+	darray_exit(&r.recompress);
+	darray_exit(&ranges);
+	printbuf_exit(&err);
+	bch2_fs_stop(c);
+	darray_for_each(devs, i)
+		free(*i);
+	darray_exit(&devs);
+	return ret ? 1 : 0;
+}
+
 static void data_job_usage(void)
 {
 	puts("bcachefs data job\n"
@@ -77,6 +665,8 @@ static void data_job_usage(void)
 	     "  -b btree                    btree to operate on\n"
 	     "  -s inode:offset       start position\n"
 	     "  -e inode:offset       end position\n"
+	     "      --no-progress           Don't report progress\n"
+	     "      --progress-json         Report progress as JSON lines on stderr\n"
 	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
@@ -84,6 +674,12 @@ static void data_job_usage(void)
 
 int cmd_data_job(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "no-progress",	no_argument,		NULL, OPT_NO_PROGRESS },
+		{ "progress-json",	no_argument,		NULL, OPT_PROGRESS_JSON },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
 	struct bch_ioctl_data op = {
 		.start_btree	= 0,
 		.start_pos	= POS_MIN,
@@ -92,7 +688,7 @@ int cmd_data_job(int argc, char *argv[])
 	};
 	int opt;
 
-	while ((opt = getopt(argc, argv, "s:e:h")) != -1)
+	while ((opt = getopt_long(argc, argv, "s:e:h", longopts, NULL)) != -1)
 		switch (opt) {
 		case 'b':
 			op.start_btree = read_string_list_or_die(optarg,
@@ -105,6 +701,10 @@ int cmd_data_job(int argc, char *argv[])
 			op.end_pos	= bpos_parse(optarg);
 		case 'e':
 			break;
+		case OPT_NO_PROGRESS:
+		case OPT_PROGRESS_JSON:
+			progress_opt(opt);
+			break;
 		case 'h':
 			data_job_usage();
 		}
@@ -0,0 +1,437 @@
+#include <dirent.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/stat.h>
+#include <sys/vfs.h>
+#include <unistd.h>
+
+#include <linux/fs.h>
+#include <linux/sort.h>
+#include <linux/xxhash.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+/*
+ * Offline deduplication: hash each file in fixed size chunks, then share
+ * chunks with identical hashes via FIDEDUPERANGE.
+ *
+ * Chunks that already point to the same physical data - reflinked copies, or
+ * the same file seen in multiple snapshots - are skipped: deduplicating them
+ * would reclaim nothing, and would only add work for the kernel.
+ */
+
+struct dedupe_chunk {
+	u64			hash;
+	u64			physical;	/* 0 if unknown */
+	u64			offset;
+	u32			file;
+};
+
+struct dedupe_op {
+	u32			src_file;
+	u32			dst_file;
+	u64			src_offset;
+	u64			dst_offset;
+	u64			len;
+};
+
+struct dedupe {
+	u64			chunk_size;
+	bool			dry_run;
+	bool			verbose;
+	fsid_t			fsid;
+
+	darray_str		files;
+	DARRAY(struct dedupe_chunk) chunks;
+	DARRAY(struct dedupe_op) ops;
+
+	u64			bytes_scanned;
+	u64			dup_chunks;
+	u64			shared_chunks;
+	u64			deduped;
+	u64			errors;
+};
+
+/* Physical address of @offset, if the whole chunk is in one plain extent: */
+static u64 chunk_physical(struct fiemap_extent *extents, unsigned nr,
+			  u64 offset, u64 len)
+{
+	for (unsigned i = 0; i < nr; i++) {
+		struct fiemap_extent *e = &extents[i];
+
+		if (offset < e->fe_logical ||
+		    offset + len > e->fe_logical + e->fe_length)
+			continue;
+
+		if (e->fe_flags & (FIEMAP_EXTENT_ENCODED|
+				   FIEMAP_EXTENT_DATA_INLINE|
+				   FIEMAP_EXTENT_UNWRITTEN|
+				   FIEMAP_EXTENT_DELALLOC))
+			return 0;
+
+		return e->fe_physical + offset - e->fe_logical;
+	}
+
+	return 0;
+}
+
+static void dedupe_scan_file(struct dedupe *d, const char *path, struct stat *st)
+{
+	u64 nr_chunks = st->st_size / d->chunk_size;
+
+	if (!nr_chunks)
+		return;
+
+	int fd = open(path, O_RDONLY);
+	if (fd < 0) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		d->errors++;
+		return;
+	}
+
+	DARRAY(struct fiemap_extent) extents = {};
+	struct fiemap_iter iter;
+	struct fiemap_extent e;
+
+	fiemap_for_each(fd, iter, e)
+		if (darray_push(&extents, e))
+			die("memory allocation failure");
+	fiemap_iter_exit(&iter);
+
+	u32 file = d->files.nr;
+	if (darray_push(&d->files, strdup(path)))
+		die("memory allocation failure");
+
+	void *buf = xmalloc(d->chunk_size);
+
+	for (u64 i = 0; i < nr_chunks; i++) {
+		u64 offset = i * d->chunk_size;
+		ssize_t r = pread(fd, buf, d->chunk_size, offset);
+
+		if (r != d->chunk_size) {
+			fprintf(stderr, "error reading %s: %m\n", path);
+			d->errors++;
+			break;
+		}
+
+		struct dedupe_chunk c = {
+			.hash		= xxh64(buf, d->chunk_size, 0),
+			.physical	= chunk_physical(extents.data, extents.nr,
+							 offset, d->chunk_size),
+			.offset		= offset,
+			.file		= file,
+		};
+
+		if (darray_push(&d->chunks, c))
+			die("memory allocation failure");
+		d->bytes_scanned += d->chunk_size;
+	}
+
+	free(buf);
+	darray_exit(&extents);
+	close(fd);
+}
+
+static void dedupe_scan(struct dedupe *d, const char *path)
+{
+	struct stat st;
+	struct statfs sfs;
+
+	if (lstat(path, &st)) {
+		fprintf(stderr, "error statting %s: %m\n", path);
+		d->errors++;
+		return;
+	}
+
+	if (S_ISREG(st.st_mode)) {
+		dedupe_scan_file(d, path, &st);
+		return;
+	}
+
+	if (!S_ISDIR(st.st_mode))
+		return;
+
+	/* Subvolumes are part of the same filesystem, other mounts aren't: */
+	if (statfs(path, &sfs) ||
+	    sfs.f_type != BCACHEFS_STATFS_MAGIC ||
+	    memcmp(&sfs.f_fsid, &d->fsid, sizeof(d->fsid)))
+		return;
+
+	DIR *dir = opendir(path);
+	struct dirent *de;
+
+	if (!dir) {
+		fprintf(stderr, "error opening %s: %m\n", path);
+		d->errors++;
+		return;
+	}
+
+	while ((errno = 0), (de = readdir(dir))) {
+		if (!strcmp(de->d_name, ".") ||
+		    !strcmp(de->d_name, ".."))
+			continue;
+
+		char *child = mprintf("%s/%s", path, de->d_name);
+		dedupe_scan(d, child);
+		free(child);
+	}
+
+	if (errno) {
+		fprintf(stderr, "error reading %s: %m\n", path);
+		d->errors++;
+	}
+	closedir(dir);
+}
+
+static int chunk_cmp(const void *_l, const void *_r)
+{
+	const struct dedupe_chunk *l = _l, *r = _r;
+
+	return  cmp_int(l->hash, r->hash) ?:
+		cmp_int(l->file, r->file) ?:
+		cmp_int(l->offset, r->offset);
+}
+
+static int op_cmp(const void *_l, const void *_r)
+{
+	const struct dedupe_op *l = _l, *r = _r;
+
+	return  cmp_int(l->dst_file, r->dst_file) ?:
+		cmp_int(l->dst_offset, r->dst_offset);
+}
+
+/*
+ * Each group of chunks with the same hash is deduplicated against its first
+ * member; then adjacent chunks are merged, so that runs of duplicate data
+ * become a single extent instead of one per chunk
+ */
+static void dedupe_plan(struct dedupe *d)
+{
+	sort(d->chunks.data, d->chunks.nr, sizeof(d->chunks.data[0]), chunk_cmp, NULL);
+
+	darray_for_each(d->chunks, c) {
+		struct dedupe_chunk *src = c;
+
+		while (src > d->chunks.data && src[-1].hash == c->hash)
+			--src;
+		if (src == c)
+			continue;
+
+		d->dup_chunks++;
+
+		if (c->physical && c->physical == src->physical) {
+			d->shared_chunks++;
+			continue;
+		}
+
+		struct dedupe_op op = {
+			.src_file	= src->file,
+			.dst_file	= c->file,
+			.src_offset	= src->offset,
+			.dst_offset	= c->offset,
+			.len		= d->chunk_size,
+		};
+
+		if (darray_push(&d->ops, op))
+			die("memory allocation failure");
+	}
+
+	sort(d->ops.data, d->ops.nr, sizeof(d->ops.data[0]), op_cmp, NULL);
+
+	unsigned nr = 0;
+	darray_for_each(d->ops, op) {
+		struct dedupe_op *prev = nr ? &d->ops.data[nr - 1] : NULL;
+
+		if (prev &&
+		    prev->src_file == op->src_file &&
+		    prev->dst_file == op->dst_file &&
+		    prev->src_offset + prev->len == op->src_offset &&
+		    prev->dst_offset + prev->len == op->dst_offset)
+			prev->len += op->len;
+		else
+			d->ops.data[nr++] = *op;
+	}
+	d->ops.nr = nr;
+}
+
+static void dedupe_run(struct dedupe *d)
+{
+	int src_fd = -1, dst_fd = -1;
+	u32 src_file = U32_MAX, dst_file = U32_MAX;
+
+	darray_for_each(d->ops, op) {
+		const char *src = d->files.data[op->src_file];
+		const char *dst = d->files.data[op->dst_file];
+
+		if (d->verbose)
+			printf("%s:%llu -> %s:%llu (%llu bytes)\n",
+			       src, op->src_offset, dst, op->dst_offset, op->len);
+
+		if (d->dry_run)
+			continue;
+
+		if (op->src_file != src_file) {
+			if (src_fd >= 0)
+				close(src_fd);
+			src_fd = xopen(src, O_RDONLY);
+			src_file = op->src_file;
+		}
+
+		if (op->dst_file != dst_file) {
+			if (dst_fd >= 0)
+				close(dst_fd);
+			dst_fd = xopen(dst, O_RDONLY);
+			dst_file = op->dst_file;
+		}
+
+		s64 ret = dedupe_range(src_fd, op->src_offset,
+				       dst_fd, op->dst_offset, op->len);
+		if (ret < 0) {
+			errno = -ret;
+			fprintf(stderr, "%s: dedupe error: %m\n", dst);
+			d->errors++;
+			continue;
+		}
+
+		d->deduped += ret;
+	}
+
+	if (src_fd >= 0)
+		close(src_fd);
+	if (dst_fd >= 0)
+		close(dst_fd);
+}
+
+static void dedupe_to_text(struct printbuf *out, struct dedupe *d)
+{
+	u64 reclaimable = 0;
+
+	darray_for_each(d->ops, op)
+		reclaimable += op->len;
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 24);
+	printbuf_tabstop_push(out, 16);
+
+	prt_printf(out, "Files scanned:\t%zu\r\n", d->files.nr);
+
+	prt_printf(out, "Data scanned:\t");
+	tools_prt_units_u64(out, d->bytes_scanned);
+	prt_printf(out, "\r\n");
+
+	prt_printf(out, "Duplicate chunks:\t%llu\r\n", d->dup_chunks);
+	prt_printf(out, "Already shared:\t%llu\r\n", d->shared_chunks);
+
+	prt_printf(out, "Reclaimable:\t");
+	tools_prt_units_u64(out, reclaimable);
+	prt_printf(out, "\r\n");
+
+	if (!d->dry_run) {
+		prt_printf(out, "Deduplicated:\t");
+		tools_prt_units_u64(out, d->deduped);
+		prt_printf(out, "\r\n");
+	}
+
+	if (d->errors)
+		prt_printf(out, "Errors:\t%llu\r\n", d->errors);
+}
+
+static void dedupe_usage(void)
+{
+	puts("bcachefs dedupe - deduplicate identical data\n"
+	     "Usage: bcachefs dedupe [OPTION]... <path>...\n"
+	     "\n"
+	     "Hashes files in fixed size chunks, and shares chunks with identical\n"
+	     "contents. Data that is already shared - reflinked, or in snapshots - is\n"
+	     "skipped. Doesn't cross into other filesystems.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -c, --chunk-size=size        Chunk size (default 128k); a multiple of the\n"
+	     "                               filesystem block size\n"
+	     "  -n, --dry-run                Only report how much space could be reclaimed\n"
+	     "  -v, --verbose                List each range deduplicated\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_dedupe(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "chunk-size",		required_argument,	NULL, 'c' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct dedupe d = { .chunk_size = 128 << 10 };
+	struct printbuf buf = PRINTBUF;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "c:nvh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'c':
+			if (bch2_strtoull_h(optarg, &d.chunk_size) || !d.chunk_size)
+				die("invalid chunk size %s", optarg);
+			break;
+		case 'n':
+			d.dry_run = true;
+			break;
+		case 'v':
+			d.verbose = true;
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'H':
+			dedupe_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			dedupe_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply one or more paths");
+
+	for (unsigned i = 0; i < argc; i++) {
+		struct statfs sfs;
+
+		if (statfs(argv[i], &sfs))
+			die("error statting %s: %m", argv[i]);
+		if (sfs.f_type != BCACHEFS_STATFS_MAGIC)
+			die("%s is not on a bcachefs filesystem", argv[i]);
+
+		if (!i)
+			d.fsid = sfs.f_fsid;
+		else if (memcmp(&sfs.f_fsid, &d.fsid, sizeof(d.fsid)))
+			die("%s is on a different filesystem from %s", argv[i], argv[0]);
+
+		if (d.chunk_size % sfs.f_bsize)
+			die("chunk size must be a multiple of the block size (%lu)",
+			    (unsigned long) sfs.f_bsize);
+	}
+
+	for (unsigned i = 0; i < argc; i++)
+		dedupe_scan(&d, argv[i]);
+
+	dedupe_plan(&d);
+	dedupe_run(&d);
+
+	dedupe_to_text(&buf, &d);
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+
+	darray_for_each(d.files, f)
+		free(*f);
+	darray_exit(&d.files);
+	darray_exit(&d.chunks);
+	darray_exit(&d.ops);
+	return d.errors != 0;
+}
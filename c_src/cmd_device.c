@@ -1,3 +1,4 @@
+#include <dirent.h>
 #include <errno.h>
 #include <fcntl.h>
 #include <getopt.h>
@@ -12,13 +13,26 @@
 #include <sys/types.h>
 #include <unistd.h>
 
+#include <blkid.h>
+#include <linux/falloc.h>
+#include <linux/fs.h>
+#include <linux/sort.h>
+#include <uuid/uuid.h>
+
 #include "libbcachefs/bcachefs.h"
 #include "libbcachefs/bcachefs_ioctl.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/checksum.h"
 #include "libbcachefs/errcode.h"
+#include "libbcachefs/extents.h"
 #include "libbcachefs/journal.h"
 #include "libbcachefs/sb-members.h"
+#include "libbcachefs/super.h"
 #include "libbcachefs/super-io.h"
 #include "cmds.h"
+#include "data_job.h"
 #include "libbcachefs.h"
 #include "libbcachefs/opts.h"
 #include "tools-util.h"
@@ -34,9 +48,14 @@ int device_usage(void)
             "  online                  re-add an existing member to a filesystem\n"
             "  offline                 take a device offline, without removing it\n"
             "  evacuate                migrate data off a specific device\n"
+            "  replace                 replace a device with a new one\n"
             "  set-state               mark a device as failed\n"
+            "  set-label               set a device's label\n"
             "  resize                  resize filesystem on a device\n"
             "  resize-journal          resize journal on a device\n"
+            "  locate                  find the disk a member device is on\n"
+            "  scan                    check a device for unreadable data\n"
+            "  trim                    discard free buckets\n"
             "\n"
             "Report bugs to <linux-bcachefs@vger.kernel.org>");
        return 0;
@@ -58,6 +77,49 @@ static void device_add_usage(void)
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
 
+static void dev_identity_to_text(struct printbuf *, const char *);
+
+/* Format @dev_opts->path and add it to a mounted filesystem */
+static void device_add(struct bchfs_handle fs, struct dev_opts *dev_opts, bool force)
+{
+	int ret = open_for_format(dev_opts, force);
+	if (ret)
+		die("Error opening %s: %s", dev_opts->path, strerror(-ret));
+
+	struct bch_opt_strs fs_opt_strs;
+	memset(&fs_opt_strs, 0, sizeof(fs_opt_strs));
+
+	struct bch_opts fs_opts = bch2_parse_opts(fs_opt_strs);
+
+	opt_set(fs_opts, block_size,
+		read_file_u64(fs.sysfs_fd, "options/block_size"));
+	opt_set(fs_opts, btree_node_size,
+		read_file_u64(fs.sysfs_fd, "options/btree_node_size"));
+
+	struct bch_sb *sb = bch2_format(fs_opt_strs,
+					fs_opts,
+					format_opts_default(),
+					dev_opts, 1);
+	free(sb);
+	bchu_disk_add(fs, dev_opts->path);
+
+	/* So there's a record of which disk became which member: */
+	struct stat dev_stat = xstat(dev_opts->path);
+	int idx = bchu_dev_path_to_idx(fs, dev_opts->path);
+	char *name = S_ISBLK(dev_stat.st_mode) ? dev_to_name(dev_stat.st_rdev) : NULL;
+
+	if (idx >= 0 && name) {
+		struct printbuf buf = PRINTBUF;
+
+		printbuf_tabstop_push(&buf, 16);
+		prt_printf(&buf, "Added device:\t%i\n", idx);
+		dev_identity_to_text(&buf, name);
+		printf("%s", buf.buf);
+		printbuf_exit(&buf);
+	}
+	free(name);
+}
+
 int cmd_device_add(int argc, char *argv[])
 {
 	static const struct option longopts[] = {
@@ -69,7 +131,6 @@ int cmd_device_add(int argc, char *argv[])
 		{ "help",		no_argument,		NULL, 'h' },
 		{ NULL }
 	};
-	struct format_opts format_opts	= format_opts_default();
 	struct dev_opts dev_opts	= dev_opts_default();
 	bool force = false;
 	int opt;
@@ -113,26 +174,7 @@ int cmd_device_add(int argc, char *argv[])
 
 	struct bchfs_handle fs = bcache_fs_open(fs_path);
 
-	int ret = open_for_format(&dev_opts, force);
-	if (ret)
-		die("Error opening %s: %s", dev_opts.path, strerror(-ret));
-
-	struct bch_opt_strs fs_opt_strs;
-	memset(&fs_opt_strs, 0, sizeof(fs_opt_strs));
-
-	struct bch_opts fs_opts = bch2_parse_opts(fs_opt_strs);
-
-	opt_set(fs_opts, block_size,
-		read_file_u64(fs.sysfs_fd, "options/block_size"));
-	opt_set(fs_opts, btree_node_size,
-		read_file_u64(fs.sysfs_fd, "options/btree_node_size"));
-
-	struct bch_sb *sb = bch2_format(fs_opt_strs,
-					fs_opts,
-					format_opts,
-					&dev_opts, 1);
-	free(sb);
-	bchu_disk_add(fs, dev_opts.path);
+	device_add(fs, &dev_opts, force);
 	return 0;
 }
 
@@ -144,7 +186,8 @@ static void device_remove_usage(void)
 	     "\n"
 	     "Options:\n"
 	     "  -f, --force		    Force removal, even if some data\n"
-	     "                              couldn't be migrated\n"
+	     "                              couldn't be migrated, or the other\n"
+	     "                              devices don't have room to rereplicate it\n"
 	     "  -F, --force-metadata	    Force removal, even if some metadata\n"
 	     "                              couldn't be migrated\n"
 	     "  -h, --help                  display this help and exit\n"
@@ -152,6 +195,165 @@ static void device_remove_usage(void)
 	exit(EXIT_SUCCESS);
 }
 
+/*
+ * Evacuate/remove preflight: will the data on @dev_idx fit on the remaining
+ * read-write devices, with each replica on a device that doesn't already have
+ * a copy?
+ *
+ * This is an estimate from the usage counters: space is free buckets less the
+ * copygc reserve, and targets are only reported on, since allocations fall
+ * back to any device when a target is full.
+ */
+struct preflight_label {
+	char			*label;
+	unsigned		nr_devs;
+	u64			free;
+};
+
+static u64 preflight_dev_free(struct bch_ioctl_dev_usage_v2 *u, unsigned gc_reserve)
+{
+	u64 free = u->d[BCH_DATA_free].buckets * u->bucket_size;
+	u64 reserve = div_u64(u->nr_buckets * u->bucket_size * gc_reserve, 100);
+
+	return free > reserve ? free - reserve : 0;
+}
+
+static bool evacuate_preflight(struct bchfs_handle fs, unsigned dev_idx,
+			       const char *verb)
+{
+	dev_names devs = bchu_fs_get_devices(fs);
+	struct bch_ioctl_fs_usage *fs_u = bchu_fs_usage(fs);
+	struct bch_sb *sb = bchu_read_super(fs, -1);
+	unsigned gc_reserve = BCH_SB_GC_RESERVE(sb);
+	DARRAY(struct preflight_label) labels = {};
+	u64 need[BCH_DATA_NR] = {}, need_total = 0, free_total = 0;
+	u64 dev_free[BCH_SB_MEMBERS_MAX] = {};
+	bool rw[BCH_SB_MEMBERS_MAX] = {};
+	const char *dev_label = NULL;
+	struct printbuf buf = PRINTBUF;
+	bool ok = true;
+
+	darray_for_each(devs, d) {
+		if (d->idx == dev_idx) {
+			dev_label = d->label;
+			continue;
+		}
+
+		struct bch_ioctl_dev_usage_v2 *u = bchu_dev_usage(fs, d->idx);
+
+		if (u->state == BCH_MEMBER_STATE_rw && d->idx < BCH_SB_MEMBERS_MAX) {
+			rw[d->idx]	= true;
+			dev_free[d->idx] = preflight_dev_free(u, gc_reserve);
+			free_total	+= dev_free[d->idx];
+
+			const char *label = d->label && *d->label ? d->label : "(no label)";
+			struct preflight_label *l = NULL;
+
+			darray_for_each(labels, i)
+				if (!strcmp(i->label, label))
+					l = i;
+			if (!l) {
+				if (darray_push(&labels, ((struct preflight_label) {
+						.label = strdup(label) })))
+					die("memory allocation failure");
+				l = &darray_last(labels);
+			}
+			l->nr_devs++;
+			l->free += dev_free[d->idx];
+		}
+		free(u);
+	}
+
+	struct bch_replicas_usage *r;
+	for (r = fs_u->replicas;
+	     r != (void *) fs_u->replicas + fs_u->replica_entries_bytes;
+	     r = replicas_usage_next(r)) {
+		unsigned type = r->r.data_type, nr_other = 0;
+		bool has_dev = false;
+
+		for (unsigned i = 0; i < r->r.nr_devs; i++)
+			has_dev |= r->r.devs[i] == dev_idx;
+
+		if (!has_dev || !r->sectors || type >= BCH_DATA_NR)
+			continue;
+
+		u64 share = div_u64(r->sectors, r->r.nr_devs);
+
+		need[type]	+= share;
+		need_total	+= share;
+
+		for (unsigned i = 0; i < BCH_SB_MEMBERS_MAX; i++)
+			if (rw[i]) {
+				bool in_entry = false;
+
+				for (unsigned j = 0; j < r->r.nr_devs; j++)
+					in_entry |= r->r.devs[j] == i;
+				nr_other += !in_entry;
+			}
+
+		if (!nr_other) {
+			prt_str(&buf, "Nowhere to put ");
+			tools_prt_units_u64(&buf, share << 9);
+			prt_str(&buf, " of ");
+			bch2_prt_data_type(&buf, type);
+			prt_str(&buf, " data: every other read-write device already has a copy\n");
+			ok = false;
+		}
+	}
+
+	prt_printf(&buf, "To %s device %u: ", verb, dev_idx);
+	tools_prt_units_u64(&buf, need_total << 9);
+	const char *sep = " (";
+	for (unsigned i = 0; i < BCH_DATA_NR; i++)
+		if (need[i]) {
+			prt_str(&buf, sep);
+			bch2_prt_data_type(&buf, i);
+			prt_char(&buf, ' ');
+			tools_prt_units_u64(&buf, need[i] << 9);
+			sep = ", ";
+		}
+	prt_str(&buf, need_total ? "), " : ", ");
+	tools_prt_units_u64(&buf, free_total << 9);
+	prt_str(&buf, " free on the other read-write devices\n");
+
+	printbuf_tabstop_push(&buf, 24);
+	printbuf_tabstop_push(&buf, 10);
+	printbuf_tabstop_push(&buf, 14);
+
+	darray_for_each(labels, l) {
+		prt_printf(&buf, "  %s", l->label);
+		prt_tab(&buf);
+		prt_printf(&buf, "%u dev%s", l->nr_devs, l->nr_devs == 1 ? "" : "s");
+		prt_tab_rjust(&buf);
+		tools_prt_units_u64(&buf, l->free << 9);
+		prt_tab_rjust(&buf);
+		prt_str(&buf, " free");
+		if (dev_label && !strcmp(dev_label, l->label) && l->free < need_total)
+			prt_str(&buf, " (less than the data leaving this label: the rest goes elsewhere)");
+		prt_newline(&buf);
+	}
+
+	if (need_total > free_total) {
+		prt_str(&buf, "Not enough free space: it would run out part way through\n");
+		ok = false;
+	}
+
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+
+	darray_for_each(labels, l)
+		free(l->label);
+	darray_exit(&labels);
+	darray_for_each(devs, d) {
+		free(d->dev);
+		free(d->label);
+	}
+	darray_exit(&devs);
+	free(sb);
+	free(fs_u);
+	return ok;
+}
+
 int cmd_device_remove(int argc, char *argv[])
 {
 	static const struct option longopts[] = {
@@ -203,6 +405,10 @@ int cmd_device_remove(int argc, char *argv[])
 		die("Filesystem path required when specifying device by id");
 	}
 
+	if (!(flags & BCH_FORCE_IF_DATA_LOST) &&
+	    !evacuate_preflight(fs, dev_idx, "restore the replicas on"))
+		die("Refusing to remove the device; use --force to remove it anyway");
+
 	bchu_disk_remove(fs, dev_idx, flags);
 	return 0;
 }
@@ -292,19 +498,70 @@ static void device_evacuate_usage(void)
 {
 	puts("bcachefs device evacuate - move data off of a given device\n"
 	     "Usage: bcachefs device evacuate [OPTION]... device\n"
+	     "   or: bcachefs device evacuate --what=types device|devid devices\n"
+	     "\n"
+	     "The kernel's evacuate moves every kind of data, so with --what the job is\n"
+	     "run here: the filesystem must be unmounted, and devices are its devices\n"
+	     "separated by colons. The check for space on the other devices is skipped.\n"
 	     "\n"
 	     "Options:\n"
+	     "  -w, --what=types            Only move these kinds of data: comma separated\n"
+	     "                              list of metadata, user and cached (default all)\n"
+	     "  -f, --force                 Start even if the other devices look too full\n"
 	     "  -h, --help                  Display this help and exit\n"
 	     "\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
 
+static int device_evacuate_offline(char *dev_str, char *fs_path, unsigned skip)
+{
+	if (fs_arg_is_mounted(fs_path))
+		die("--what only works on an unmounted filesystem");
+
+	struct bch_fs *c = fs_arg_open_offline(fs_path, bch2_opts_empty());
+	struct bch_dev *ca = fs_arg_dev_offline(c, fs_path, dev_str);
+	int ret;
+
+	if (ca->mi.state == BCH_MEMBER_STATE_rw) {
+		printf("Setting %s readonly\n", dev_str);
+		ret = bch2_dev_set_state(c, ca, BCH_MEMBER_STATE_ro, 0);
+		if (ret)
+			die("error setting %s readonly: %s", dev_str, bch2_err_str(ret));
+	}
+
+	if (skip & DATA_JOB_SKIP_CACHED)
+		printf("Skipping cached data: it will be dropped when %s is removed\n", dev_str);
+
+	ret = data_job_offline(c, (struct bch_ioctl_data) {
+		.op		= BCH_DATA_OP_migrate,
+		.migrate.dev	= ca->dev_idx,
+	}, skip);
+
+	bch2_dev_put(ca);
+	bch2_fs_stop(c);
+	return ret ? 1 : 0;
+}
+
 int cmd_device_evacuate(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "what",		required_argument,	NULL, 'w' },
+		{ "force",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	unsigned skip = 0;
+	bool force = false;
 	int opt;
 
-	while ((opt = getopt(argc, argv, "h")) != -1)
+	while ((opt = getopt_long(argc, argv, "w:fh", longopts, NULL)) != -1)
 		switch (opt) {
+		case 'w':
+			skip = data_job_parse_what(optarg);
+			break;
+		case 'f':
+			force = true;
+			break;
 		case 'h':
 			device_evacuate_usage();
 			exit(EXIT_SUCCESS);
@@ -315,12 +572,26 @@ int cmd_device_evacuate(int argc, char *argv[])
 	if (!dev_path)
 		die("Please supply a device");
 
+	if (skip) {
+		char *fs_path = arg_pop();
+		if (!fs_path)
+			die("With --what, please supply the filesystem's devices");
+
+		if (argc)
+			die("too many arguments");
+
+		return device_evacuate_offline(dev_path, fs_path, skip);
+	}
+
 	if (argc)
 		die("too many arguments");
 
 	int dev_idx;
 	struct bchfs_handle fs = bchu_fs_open_by_dev(dev_path, &dev_idx);
 
+	if (!evacuate_preflight(fs, dev_idx, "move the data off") && !force)
+		die("Refusing to start evacuate; use --force to start anyway");
+
 	struct bch_ioctl_dev_usage_v2 *u = bchu_dev_usage(fs, dev_idx);
 
 	if (u->state == BCH_MEMBER_STATE_rw) {
@@ -340,6 +611,206 @@ int cmd_device_evacuate(int argc, char *argv[])
 	});
 }
 
+static void device_replace_usage(void)
+{
+	puts("bcachefs device replace - replace a member device with a new one\n"
+	     "Usage: bcachefs device replace [OPTION]... <filesystem> <old device>|<devid> <new device>\n"
+	     "\n"
+	     "Adds the new device, sets the old device read-only, migrates its data\n"
+	     "off and removes it. If interrupted, run the same command again to\n"
+	     "resume: steps that are already done are skipped.\n"
+	     "\n"
+	     "If the old device is missing or failed, its data is rereplicated\n"
+	     "from the remaining copies instead of migrated.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -S, --fs_size=size          Size of filesystem on new device\n"
+	     "  -B, --bucket=size           Bucket size\n"
+	     "  -D, --discard               Enable discards\n"
+	     "  -l, --label=label           Disk label (default: label of old device)\n"
+	     "  -f, --force                 Use new device even if it appears to already be formatted\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+/* Member index of @path in @fs, or -1 if it's not (yet) a member */
+static int dev_member_idx(struct bchfs_handle fs, const char *path)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+	int idx = -1;
+
+	opt_set(opts, noexcl,	true);
+	opt_set(opts, nochanges, true);
+
+	if (bch2_read_super_silent(path, &opts, &sb))
+		return -1;
+
+	if (!memcmp(&sb.sb->user_uuid, &fs.uuid, sizeof(fs.uuid))) {
+		struct bch_sb *fs_sb = bchu_read_super(fs, -1);
+		unsigned i = sb.sb->dev_idx;
+
+		if (bch2_member_exists(fs_sb, i)) {
+			struct bch_member m = bch2_sb_member_get(fs_sb, i);
+
+			if (!memcmp(&m.uuid, &sb.sb->uuid, sizeof(m.uuid)))
+				idx = i;
+		}
+		free(fs_sb);
+	}
+
+	bch2_free_super(&sb);
+	return idx;
+}
+
+int cmd_device_replace(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "fs_size",		required_argument,	NULL, 'S' },
+		{ "bucket",		required_argument,	NULL, 'B' },
+		{ "discard",		no_argument,		NULL, 'D' },
+		{ "label",		required_argument,	NULL, 'l' },
+		{ "force",		no_argument,		NULL, 'f' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct dev_opts dev_opts	= dev_opts_default();
+	bool force = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "S:B:Dl:fh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 'S':
+			if (bch2_strtoull_h(optarg, &dev_opts.size))
+				die("invalid filesystem size");
+			break;
+		case 'B':
+			if (bch2_strtoull_h(optarg, &dev_opts.bucket_size))
+				die("bad bucket_size %s", optarg);
+			break;
+		case 'D':
+			dev_opts.discard = true;
+			break;
+		case 'l':
+			dev_opts.label = strdup(optarg);
+			break;
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			device_replace_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			device_replace_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	char *old_str = arg_pop();
+	if (!old_str)
+		die("Please supply the device to replace");
+
+	dev_opts.path = arg_pop();
+	if (!dev_opts.path)
+		die("Please supply the new device");
+
+	if (argc)
+		die("too many arguments");
+
+	struct bchfs_handle fs = bcache_fs_open(fs_path);
+	struct bch_sb *sb = bchu_read_super(fs, -1);
+	unsigned old_idx;
+
+	if (kstrtouint(old_str, 10, &old_idx)) {
+		int idx = dev_member_idx(fs, old_str);
+		if (idx < 0)
+			die("%s is not a member of %s (already replaced?)", old_str, fs_path);
+		old_idx = idx;
+	} else if (!bch2_member_exists(sb, old_idx)) {
+		die("no device %u in %s (already replaced?)", old_idx, fs_path);
+	}
+
+	struct bch_member old_m = bch2_sb_member_get(sb, old_idx);
+	free(sb);
+
+	/* The old device's name, or NULL if it's missing: */
+	char *old_dev = NULL;
+	dev_names devs = bchu_fs_get_devices(fs);
+
+	darray_for_each(devs, d) {
+		if (d->idx == old_idx) {
+			old_dev = d->dev;
+			d->dev = NULL;
+			if (!dev_opts.label && d->label)
+				dev_opts.label = strdup(d->label);
+		}
+		free(d->dev);
+		free(d->label);
+	}
+	darray_exit(&devs);
+
+	bool old_failed = !old_dev ||
+		BCH_MEMBER_STATE(&old_m) == BCH_MEMBER_STATE_failed;
+
+	printf("Replacing device %u (%s) with %s\n",
+	       old_idx, old_dev ?: "missing", dev_opts.path);
+
+	int new_idx = dev_member_idx(fs, dev_opts.path);
+	if (new_idx == old_idx)
+		die("%s is the device being replaced", dev_opts.path);
+
+	if (new_idx < 0) {
+		printf("Step 1/4: adding %s\n", dev_opts.path);
+		device_add(fs, &dev_opts, force);
+	} else {
+		printf("Step 1/4: %s already added as device %i\n", dev_opts.path, new_idx);
+	}
+
+	if (BCH_MEMBER_STATE(&old_m) == BCH_MEMBER_STATE_rw) {
+		printf("Step 2/4: setting device %u read-only\n", old_idx);
+		bchu_disk_set_state(fs, old_idx, BCH_MEMBER_STATE_ro, BCH_FORCE_IF_DEGRADED);
+	} else {
+		printf("Step 2/4: device %u already %s\n", old_idx,
+		       bch2_member_states[BCH_MEMBER_STATE(&old_m)]);
+	}
+
+	if (!old_failed) {
+		printf("Step 3/4: migrating data off device %u\n", old_idx);
+		bchu_data(fs, (struct bch_ioctl_data) {
+			.op		= BCH_DATA_OP_migrate,
+			.start_btree	= 0,
+			.start_pos	= POS_MIN,
+			.end_btree	= BTREE_ID_NR,
+			.end_pos	= POS_MAX,
+			.migrate.dev	= old_idx,
+		});
+	} else {
+		printf("Step 3/4: device %u is %s, rereplicating\n", old_idx,
+		       old_dev ? "failed" : "missing");
+		bchu_data(fs, (struct bch_ioctl_data) {
+			.op		= BCH_DATA_OP_rereplicate,
+			.start_btree	= 0,
+			.start_pos	= POS_MIN,
+			.end_btree	= BTREE_ID_NR,
+			.end_pos	= POS_MAX,
+		});
+	}
+
+	printf("Step 4/4: removing device %u\n", old_idx);
+	bchu_disk_remove(fs, old_idx, BCH_FORCE_IF_DEGRADED);
+
+	printf("Device %u replaced by %s\n", old_idx, dev_opts.path);
+	free(old_dev);
+	bcache_fs_close(fs);
+	return 0;
+}
+
 static void device_set_state_usage(void)
 {
 	puts("bcachefs device set-state\n"
@@ -645,3 +1116,958 @@ int cmd_device_resize_journal(int argc, char *argv[])
 	}
 	return 0;
 }
+
+/*
+ * Device identity: the superblock only knows member indices and UUIDs, so the
+ * mapping to a physical disk is looked up at runtime - from the udev database
+ * and sysfs - rather than recorded, where it would go stale when disks move
+ * between ports or enclosures
+ */
+
+/* sysfs attribute of a block device, or of the disk a partition is on */
+static char *block_dev_attr(const char *name, const char *attr)
+{
+	static const char * const dirs[] = { "", "/device", "/..", "/../device" };
+
+	for (unsigned i = 0; i < ARRAY_SIZE(dirs); i++) {
+		char *path = mprintf("/sys/class/block/%s%s/%s", name, dirs[i], attr);
+		char *ret = !access(path, R_OK) ? read_file_str(AT_FDCWD, path) : NULL;
+
+		free(path);
+		if (ret) {
+			char *v = strdup(strim(ret));
+
+			free(ret);
+			return v;
+		}
+	}
+
+	return NULL;
+}
+
+static char *udev_prop(const char *name, const char *key)
+{
+	char *devnum = block_dev_attr(name, "dev");
+	if (!devnum)
+		return NULL;
+
+	char *path = mprintf("/run/udev/data/b%s", devnum);
+	FILE *f = fopen(path, "r");
+	char *line = NULL, *ret = NULL;
+	size_t n = 0, keylen = strlen(key);
+
+	while (f && !ret && getline(&line, &n, f) != -1)
+		if (!strncmp(line, "E:", 2) &&
+		    !strncmp(line + 2, key, keylen) &&
+		    line[2 + keylen] == '=')
+			ret = strdup(strim(line + 3 + keylen));
+
+	if (f)
+		fclose(f);
+	free(line);
+	free(path);
+	free(devnum);
+	return ret;
+}
+
+static char *dev_serial(const char *name)
+{
+	return udev_prop(name, "ID_SERIAL_SHORT") ?:
+		block_dev_attr(name, "serial");
+}
+
+static char *dev_wwn(const char *name)
+{
+	return udev_prop(name, "ID_WWN_WITH_EXTENSION") ?:
+		udev_prop(name, "ID_WWN") ?:
+		block_dev_attr(name, "wwid");
+}
+
+/* The enclosure slot (from the kernel's SES driver) a disk is in, if any */
+static char *dev_enclosure_slot(const char *name)
+{
+	static const char * const dirs[] = { "device", "../device" };
+	char *ret = NULL;
+
+	for (unsigned i = 0; i < ARRAY_SIZE(dirs) && !ret; i++) {
+		char *path = mprintf("/sys/class/block/%s/%s", name, dirs[i]);
+		DIR *dir = opendir(path);
+		struct dirent *d;
+
+		while (dir && !ret && (d = readdir(dir)))
+			if (!strncmp(d->d_name, "enclosure_device:", 17)) {
+				char *link = mprintf("%s/%s", path, d->d_name);
+				ret = realpath(link, NULL);
+				free(link);
+			}
+
+		if (dir)
+			closedir(dir);
+		free(path);
+	}
+
+	return ret;
+}
+
+static void dev_identity_to_text(struct printbuf *out, const char *name)
+{
+	char *dev_path = mprintf("/dev/%s", name);
+	char *model	= block_dev_attr(name, "model");
+	char *serial	= dev_serial(name);
+	char *wwn	= dev_wwn(name);
+	char *slot	= dev_enclosure_slot(name);
+
+	prt_printf(out, "Path:\t%s\n",		dev_path);
+	prt_printf(out, "Model:\t%s\n",		model	?: "(unknown)");
+	prt_printf(out, "Serial:\t%s\n",	serial	?: "(unknown)");
+	prt_printf(out, "WWN:\t%s\n",		wwn	?: "(unknown)");
+
+	DIR *dir = opendir("/dev/disk/by-id");
+	struct dirent *d;
+
+	while (dir && (d = readdir(dir))) {
+		if (d->d_name[0] == '.')
+			continue;
+
+		char *link = mprintf("/dev/disk/by-id/%s", d->d_name);
+		char *target = realpath(link, NULL);
+
+		if (target && !strcmp(target, dev_path))
+			prt_printf(out, "By-id:\t%s\n", link);
+		free(target);
+		free(link);
+	}
+	if (dir)
+		closedir(dir);
+
+	if (slot)
+		prt_printf(out, "Enclosure slot:\t%s\n", slot);
+
+	free(slot);
+	free(wwn);
+	free(serial);
+	free(model);
+	free(dev_path);
+}
+
+static void device_locate_usage(void)
+{
+	puts("bcachefs device locate - find the disk a member device is on\n"
+	     "Usage: bcachefs device locate [OPTION]... <filesystem> <device>\n"
+	     "\n"
+	     "<filesystem> is a mountpoint, filesystem UUID, or any member device;\n"
+	     "<device> is a member index or member UUID.\n"
+	     "\n"
+	     "Prints the current block device path for the member, along with\n"
+	     "its model, serial number, WWN and enclosure slot.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -b, --blink                 Turn on the enclosure locate LED\n"
+	     "  -B, --no-blink              Turn off the enclosure locate LED\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+/* Find a member on an unmounted filesystem, by its member UUID */
+static char *locate_member_offline(struct bch_sb *sb, unsigned idx)
+{
+	struct bch_member m = bch2_sb_member_get(sb, idx);
+	char uuid_str[40];
+
+	uuid_unparse(m.uuid.b, uuid_str);
+
+	char *path = blkid_evaluate_tag("UUID_SUB", uuid_str, NULL);
+	if (!path)
+		return NULL;
+
+	/* blkid's cache may be stale, check the superblock it points to: */
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle dev_sb;
+
+	opt_set(opts, noexcl,	true);
+	opt_set(opts, nochanges, true);
+
+	bool ok = !bch2_read_super_silent(path, &opts, &dev_sb);
+	if (ok) {
+		ok = dev_sb.sb->dev_idx == idx &&
+			!memcmp(&dev_sb.sb->user_uuid, &sb->user_uuid, sizeof(sb->user_uuid));
+		bch2_free_super(&dev_sb);
+	}
+
+	if (!ok) {
+		free(path);
+		return NULL;
+	}
+
+	char *name = strdup(basename(path));
+	free(path);
+	return name;
+}
+
+int cmd_device_locate(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "blink",		no_argument,		NULL, 'b' },
+		{ "no-blink",		no_argument,		NULL, 'B' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt, blink = -1;
+
+	while ((opt = getopt_long(argc, argv, "bBh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'b':
+			blink = 1;
+			break;
+		case 'B':
+			blink = 0;
+			break;
+		case 'h':
+			device_locate_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			device_locate_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	char *dev_str = arg_pop();
+	if (!dev_str)
+		die("Please supply a device index or UUID");
+
+	if (argc)
+		die("too many arguments");
+
+	struct bch_sb_handle sb_handle = {};
+	struct bch_sb *sb;
+	struct stat fs_stat;
+	__uuid_t uuid;
+	bool mounted = !uuid_parse(fs_path, uuid.b) ||
+		(!stat(fs_path, &fs_stat) && S_ISDIR(fs_stat.st_mode));
+	struct bchfs_handle fs = {};
+
+	if (mounted) {
+		fs = bcache_fs_open(fs_path);
+		sb = bchu_read_super(fs, -1);
+	} else {
+		struct bch_opts opts = bch2_opts_empty();
+
+		opt_set(opts, noexcl,	true);
+		opt_set(opts, nochanges, true);
+
+		int ret = bch2_read_super(fs_path, &opts, &sb_handle);
+		if (ret)
+			die("Error opening %s: %s", fs_path, bch2_err_str(ret));
+		sb = sb_handle.sb;
+	}
+
+	unsigned idx;
+	if (kstrtouint(dev_str, 10, &idx)) {
+		if (uuid_parse(dev_str, uuid.b))
+			die("%s is not a device index or UUID", dev_str);
+
+		for (idx = 0; idx < sb->nr_devices; idx++) {
+			struct bch_member m = bch2_sb_member_get(sb, idx);
+
+			if (bch2_member_exists(sb, idx) &&
+			    !memcmp(&m.uuid, &uuid, sizeof(uuid)))
+				break;
+		}
+	}
+
+	if (idx >= sb->nr_devices || !bch2_member_exists(sb, idx))
+		die("No member device %s", dev_str);
+
+	struct bch_member m = bch2_sb_member_get(sb, idx);
+	char *name = NULL;
+
+	if (mounted) {
+		dev_names devs = bchu_fs_get_devices(fs);
+
+		darray_for_each(devs, d)
+			if (d->idx == idx && d->dev)
+				name = strdup(d->dev);
+		darray_for_each(devs, d) {
+			free(d->dev);
+			free(d->label);
+		}
+		darray_exit(&devs);
+	}
+
+	if (!name)
+		name = locate_member_offline(sb, idx);
+
+	struct printbuf buf = PRINTBUF;
+	char uuid_str[40];
+
+	uuid_unparse(m.uuid.b, uuid_str);
+
+	printbuf_tabstop_push(&buf, 16);
+	prt_printf(&buf, "Device:\t%u\n", idx);
+	prt_printf(&buf, "UUID:\t%s\n", uuid_str);
+	prt_printf(&buf, "State:\t%s\n", bch2_member_states[BCH_MEMBER_STATE(&m)]);
+
+	if (name)
+		dev_identity_to_text(&buf, name);
+	else
+		prt_printf(&buf, "Path:\t(not found)\n");
+
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+
+	int ret = name ? 0 : 1;
+
+	if (name && blink >= 0) {
+		char *slot = dev_enclosure_slot(name);
+		if (!slot)
+			die("%s is not in an enclosure with a locate LED", name);
+
+		char *locate = mprintf("%s/locate", slot);
+		write_file_str(AT_FDCWD, locate, blink ? "1" : "0");
+		printf("Locate LED %s\n", blink ? "on" : "off");
+		free(locate);
+		free(slot);
+	}
+
+	free(name);
+	if (mounted) {
+		free(sb);
+		bcache_fs_close(fs);
+	} else {
+		bch2_free_super(&sb_handle);
+	}
+	return ret;
+}
+
+static void device_scan_usage(void)
+{
+	puts("bcachefs device scan - check a device for unreadable data\n"
+	     "Usage: bcachefs device scan [OPTION]... <device> [<other devices>...]\n"
+	     "\n"
+	     "Reads all data on <device> and verifies its checksums, recording read and\n"
+	     "checksum errors per bucket. The filesystem must be unmounted; all of its\n"
+	     "devices are needed, but only the first one is scanned.\n"
+	     "\n"
+	     "The report can be passed to data rereplicate --buckets, to rewrite only the\n"
+	     "extents in bad buckets.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -o, --output=file            Write the report here instead of stdout\n"
+	     "  -v, --verbose                List each bad extent in the report\n"
+	     "  -j, --jobs=nr                Number of threads reading and checksumming\n"
+	     "                               (default: number of CPUs)\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+struct bad_bucket {
+	u64			bucket;
+	u32			read_errors;
+	u32			csum_errors;
+};
+
+struct device_scan {
+	struct bch_dev		*ca;
+	bool			verbose;
+	FILE			*out;
+	struct bch_fs		*c;
+	struct ordered_work	w;
+
+	u64			extents;
+	u64			sectors;
+	u64			read_errors;
+	u64			csum_errors;
+	DARRAY(struct bad_bucket) bad;
+};
+
+static void device_scan_bad(struct device_scan *s, struct bkey_s_c k,
+			    struct extent_ptr_decoded p, bool csum)
+{
+	u64 bucket = sector_to_bucket(s->ca, p.ptr.offset);
+	struct bad_bucket *b = NULL;
+
+	darray_for_each(s->bad, i)
+		if (i->bucket == bucket)
+			b = i;
+
+	if (!b) {
+		if (darray_push(&s->bad, ((struct bad_bucket) { .bucket = bucket })))
+			die("memory allocation failure");
+		b = &darray_last(s->bad);
+	}
+
+	if (csum) {
+		b->csum_errors++;
+		s->csum_errors++;
+	} else {
+		b->read_errors++;
+		s->read_errors++;
+	}
+
+	if (s->verbose) {
+		struct printbuf buf = PRINTBUF;
+
+		bch2_bpos_to_text(&buf, k.k->p);
+		fprintf(s->out, "# %s %s: %u:%llu %s error\n",
+			bch2_btree_id_str(k.k->type == KEY_TYPE_reflink_v
+					  ? BTREE_ID_reflink : BTREE_ID_extents),
+			buf.buf, p.ptr.dev, (u64) p.ptr.offset,
+			csum ? "checksum" : "read");
+		printbuf_exit(&buf);
+	}
+}
+
+/*
+ * Replicas are read and checksummed on worker threads; results are recorded
+ * from the main thread, in the order they were submitted
+ */
+enum device_scan_result {
+	DEVICE_SCAN_ok,
+	DEVICE_SCAN_read_error,
+	DEVICE_SCAN_csum_error,
+};
+
+struct device_scan_job {
+	struct bkey_i		*k;
+	struct extent_ptr_decoded p;
+	enum device_scan_result	result;
+};
+
+static void device_scan_job_work(void *item, void *priv)
+{
+	struct device_scan_job *j = item;
+	struct device_scan *s = priv;
+	struct bch_fs *c = s->c;
+	struct extent_ptr_decoded p = j->p;
+	size_t bytes = p.crc.compressed_size << 9;
+	void *buf = xmalloc(bytes);
+
+	j->result = DEVICE_SCAN_ok;
+
+	if (pread(s->ca->disk_sb.bdev->bd_fd, buf, bytes, p.ptr.offset << 9) != bytes) {
+		j->result = DEVICE_SCAN_read_error;
+		goto out;
+	}
+
+	if (bch2_csum_type_is_encryption(p.crc.csum_type) && !c->chacha20) {
+		/* can't verify without the key */
+		goto out;
+	}
+
+	if (p.crc.csum_type) {
+		struct bch_csum csum = bch2_checksum(c, p.crc.csum_type,
+					extent_nonce(j->k->k.version, p.crc),
+					buf, bytes);
+
+		if (bch2_crc_cmp(csum, p.crc.csum))
+			j->result = DEVICE_SCAN_csum_error;
+	}
+out:
+	free(buf);
+}
+
+static void device_scan_job_done(void *item, void *priv)
+{
+	struct device_scan_job *j = item;
+	struct device_scan *s = priv;
+
+	if (j->result != DEVICE_SCAN_ok)
+		device_scan_bad(s, bkey_i_to_s_c(j->k), j->p,
+				j->result == DEVICE_SCAN_csum_error);
+	free(j->k);
+}
+
+static int device_scan_extent(struct device_scan *s, struct bkey_s_c k)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		if (p.ptr.dev != s->ca->dev_idx || p.ptr.unwritten)
+			continue;
+
+		s->extents++;
+		s->sectors += p.crc.compressed_size;
+
+		struct device_scan_job *j = ordered_work_get(&s->w);
+
+		j->p = p;
+		j->k = xmalloc(bkey_bytes(k.k));
+		bkey_reassemble(j->k, k);
+
+		ordered_work_submit(&s->w);
+	}
+
+	return 0;
+}
+
+static int bad_bucket_cmp(const void *_l, const void *_r)
+{
+	const struct bad_bucket *l = _l, *r = _r;
+
+	return cmp_int(l->bucket, r->bucket);
+}
+
+int cmd_device_scan(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "output",		required_argument,	NULL, 'o' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "jobs",		required_argument,	NULL, 'j' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct device_scan s = { .out = stdout };
+	const char *output = NULL;
+	unsigned nr_jobs = nr_cpus();
+	int opt;
+
+	opt_set(opts, read_only,	true);
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "o:vj:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'o':
+			output = optarg;
+			break;
+		case 'v':
+			s.verbose = true;
+			break;
+		case 'j':
+			if (kstrtouint(optarg, 10, &nr_jobs) || !nr_jobs)
+				die("invalid number of jobs %s", optarg);
+			break;
+		case 'h':
+			device_scan_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			device_scan_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply a device");
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	for_each_online_member(c, ca)
+		if (!strcmp(ca->disk_sb.sb_name, argv[0]))
+			s.ca = ca;
+	if (!s.ca)
+		die("%s is not an online member", argv[0]);
+
+	if (output) {
+		s.out = fopen(output, "w");
+		if (!s.out)
+			die("error opening %s: %m", output);
+	}
+
+	char uuid_str[40];
+	uuid_unparse(c->sb.user_uuid.b, uuid_str);
+	fprintf(s.out, "# bcachefs device scan: filesystem %s device %u (%s)\n",
+		uuid_str, s.ca->dev_idx, argv[0]);
+
+	s.c = c;
+	ordered_work_init(&s.w, nr_jobs, sizeof(struct device_scan_job),
+			  device_scan_job_work, device_scan_job_done, &s);
+
+	struct btree_trans *trans = bch2_trans_get(c);
+	int ret = 0;
+
+	enum btree_id btrees[] = { BTREE_ID_extents, BTREE_ID_reflink };
+	for (unsigned i = 0; i < ARRAY_SIZE(btrees) && !ret; i++)
+		ret = for_each_btree_key(trans, iter, btrees[i], POS_MIN,
+					 BTREE_ITER_all_snapshots|BTREE_ITER_prefetch, k,
+			device_scan_extent(&s, k));
+	bch2_trans_put(trans);
+
+	ordered_work_exit(&s.w);
+
+	if (ret)
+		fprintf(stderr, "error walking extents: %s\n", bch2_err_str(ret));
+
+	sort(s.bad.data, s.bad.nr, sizeof(s.bad.data[0]), bad_bucket_cmp, NULL);
+
+	fprintf(s.out, "# %llu extents, %llu sectors read: %llu read errors, %llu checksum errors\n",
+		s.extents, s.sectors, s.read_errors, s.csum_errors);
+	fprintf(s.out, "# dev:bucket read_errors csum_errors\n");
+	darray_for_each(s.bad, b)
+		fprintf(s.out, "%u:%llu %u %u\n",
+			s.ca->dev_idx, b->bucket, b->read_errors, b->csum_errors);
+
+	if (output) {
+		fclose(s.out);
+		printf("%zu bad buckets, report written to %s\n", s.bad.nr, output);
+	}
+
+	ret = ret || s.bad.nr ? 1 : 0;
+
+	darray_exit(&s.bad);
+	bch2_fs_stop(c);
+	return ret;
+}
+
+static void device_trim_usage(void)
+{
+	puts("bcachefs device trim - discard free buckets\n"
+	     "Usage: bcachefs device trim [OPTION]... <mountpoint>\n"
+	     "   or: bcachefs device trim [OPTION]... <devices>\n"
+	     "\n"
+	     "On a mounted filesystem, issues discards now for buckets waiting to be\n"
+	     "discarded, temporarily enabling discard on devices that have it\n"
+	     "disabled. Buckets that were already free are only known to the\n"
+	     "allocator, so trimming those requires the filesystem to be unmounted:\n"
+	     "given its devices, every free bucket is discarded.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -d, --dev=index              Only trim this member device\n"
+	     "  -n, --dry-run                Only report how much could be trimmed\n"
+	     "  -t, --timeout=seconds        How long to wait for discards on a mounted\n"
+	     "                               filesystem (default 10)\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+struct trim_range {
+	u64			start;
+	u64			end;
+};
+
+struct trim_dev {
+	unsigned		idx;
+	char			*path;
+	u64			bucket_size;
+	u64			buckets;
+	DARRAY(struct trim_range) ranges;
+};
+
+typedef DARRAY(struct trim_dev) trim_devs;
+
+static void trim_range_add(struct trim_dev *d, u64 start, u64 end)
+{
+	struct trim_range *last = d->ranges.nr ? &darray_last(d->ranges) : NULL;
+
+	d->buckets += end - start;
+
+	if (last && last->end == start) {
+		last->end = end;
+		return;
+	}
+
+	if (darray_push(&d->ranges, ((struct trim_range) { start, end })))
+		die("memory allocation failure");
+}
+
+static int trim_dev_collect(struct bch_fs *c, struct bch_dev *ca, struct trim_dev *d)
+{
+	/* freespace btree offsets have generation bits in the top byte: */
+	const u64 mask = ~(~0ULL << 56);
+
+	return bch2_trans_run(c,
+		for_each_btree_key_upto(trans, iter, BTREE_ID_need_discard,
+				POS(ca->dev_idx, 0), POS(ca->dev_idx, U64_MAX), 0, k, ({
+			trim_range_add(d, k.k->p.offset, k.k->p.offset + 1);
+			0;
+		})) ?:
+		for_each_btree_key_upto(trans, iter, BTREE_ID_freespace,
+				POS(ca->dev_idx, 0), POS(ca->dev_idx, U64_MAX), 0, k, ({
+			trim_range_add(d, bkey_start_offset(k.k) & mask, k.k->p.offset & mask);
+			0;
+		})));
+}
+
+static int trim_dev_discard(struct trim_dev *d, u64 *trimmed)
+{
+	int fd = open(d->path, O_RDWR);
+	if (fd < 0)
+		return -errno;
+
+	struct stat st = xfstat(fd);
+	int ret = 0;
+
+	darray_for_each(d->ranges, r) {
+		u64 range[2] = {
+			r->start * d->bucket_size << 9,
+			(r->end - r->start) * d->bucket_size << 9,
+		};
+
+		ret = S_ISBLK(st.st_mode)
+			? ioctl(fd, BLKDISCARD, range)
+			: fallocate(fd, FALLOC_FL_PUNCH_HOLE|FALLOC_FL_KEEP_SIZE,
+				    range[0], range[1]);
+		if (ret) {
+			ret = -errno;
+			break;
+		}
+
+		*trimmed += range[1];
+	}
+
+	close(fd);
+	return ret;
+}
+
+static int device_trim_offline(int argc, char *argv[], int dev_idx, bool dry_run)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	trim_devs devs = {};
+	u64 total = 0;
+	int ret = 0;
+
+	darray_str dev_paths = get_or_split_cmdline_devs(argc, argv);
+
+	darray_for_each(dev_paths, i)
+		if (dev_mounted(*i))
+			die("%s is mounted: pass its mountpoint instead", *i);
+
+	opt_set(opts, read_only,	true);
+	opt_set(opts, nochanges,	true);
+
+	struct bch_fs *c = bch2_fs_open(dev_paths.data, dev_paths.nr, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", dev_paths.data[0], bch2_err_str(PTR_ERR(c)));
+
+	for_each_online_member(c, ca) {
+		if (dev_idx >= 0 && ca->dev_idx != dev_idx)
+			continue;
+
+		if (!ca->mi.freespace_initialized) {
+			fprintf(stderr, "device %u: freespace not initialized, skipping\n",
+				ca->dev_idx);
+			continue;
+		}
+
+		struct trim_dev d = {
+			.idx		= ca->dev_idx,
+			.path		= strdup(ca->disk_sb.sb_name),
+			.bucket_size	= ca->mi.bucket_size,
+		};
+
+		ret = trim_dev_collect(c, ca, &d);
+		if (ret) {
+			fprintf(stderr, "error walking free buckets on device %u: %s\n",
+				ca->dev_idx, bch2_err_str(ret));
+			free(d.path);
+			darray_exit(&d.ranges);
+			percpu_ref_put(&ca->io_ref);
+			break;
+		}
+
+		if (darray_push(&devs, d))
+			die("memory allocation failure");
+	}
+
+	/* Discard after shutting down, so nothing can allocate the buckets: */
+	bch2_fs_stop(c);
+
+	if (dev_idx >= 0 && !devs.nr && !ret)
+		die("device %i is not an online member", dev_idx);
+
+	darray_for_each(devs, d) {
+		u64 trimmed = d->buckets * d->bucket_size << 9;
+
+		if (!dry_run) {
+			trimmed = 0;
+			int ret2 = trim_dev_discard(d, &trimmed);
+			if (ret2) {
+				fprintf(stderr, "error discarding %s: %s\n",
+					d->path, strerror(-ret2));
+				ret = ret ?: ret2;
+			}
+		}
+
+		printbuf_reset(&buf);
+		prt_printf(&buf, "%s (%u): %s %llu buckets, ",
+			   d->path, d->idx, dry_run ? "would trim" : "trimmed", d->buckets);
+		tools_prt_units_u64(&buf, trimmed);
+		printf("%s\n", buf.buf);
+		total += trimmed;
+
+		free(d->path);
+		darray_exit(&d->ranges);
+	}
+
+	printbuf_reset(&buf);
+	prt_str(&buf, dry_run ? "total trimmable: " : "total trimmed: ");
+	tools_prt_units_u64(&buf, total);
+	printf("%s\n", buf.buf);
+
+	printbuf_exit(&buf);
+	darray_exit(&devs);
+	darray_for_each(dev_paths, i)
+		free(*i);
+	darray_exit(&dev_paths);
+	return ret ? EXIT_FAILURE : 0;
+}
+
+static u64 trim_need_discard(struct bchfs_handle fs, dev_names *devs, int dev_idx)
+{
+	u64 ret = 0;
+
+	darray_for_each(*devs, d) {
+		if (dev_idx >= 0 && d->idx != dev_idx)
+			continue;
+
+		struct bch_ioctl_dev_usage_v2 *u = bchu_dev_usage(fs, d->idx);
+		ret += u->d[BCH_DATA_need_discard].buckets * u->bucket_size;
+		free(u);
+	}
+
+	return ret;
+}
+
+static char *trim_discard_attr(unsigned idx)
+{
+	return mprintf("dev-%u/discard", idx);
+}
+
+static int device_trim_online(const char *path, int dev_idx, bool dry_run, unsigned timeout)
+{
+	struct bchfs_handle fs = bcache_fs_open(path);
+	dev_names devs = bchu_fs_get_devices(fs);
+	struct printbuf buf = PRINTBUF;
+	DARRAY(unsigned) enabled = {};
+	bool found = false;
+
+	darray_for_each(devs, d) {
+		if (dev_idx >= 0 && d->idx != dev_idx)
+			continue;
+		found = true;
+
+		struct bch_ioctl_dev_usage_v2 *u = bchu_dev_usage(fs, d->idx);
+		char *attr = trim_discard_attr(d->idx);
+		bool discard = read_file_u64(fs.sysfs_fd, attr);
+
+		printbuf_reset(&buf);
+		prt_printf(&buf, "%s (%u): discard %s, pending ",
+			   d->dev ?: d->label ?: "(unknown)", d->idx,
+			   discard ? "enabled" : "disabled");
+		tools_prt_units_u64(&buf, u->d[BCH_DATA_need_discard].buckets * u->bucket_size << 9);
+		prt_str(&buf, ", free ");
+		tools_prt_units_u64(&buf, u->d[BCH_DATA_free].buckets * u->bucket_size << 9);
+		printf("%s\n", buf.buf);
+
+		if (!dry_run && !discard) {
+			write_file_str(fs.sysfs_fd, attr, "1");
+			if (darray_push(&enabled, d->idx))
+				die("memory allocation failure");
+		}
+
+		free(attr);
+		free(u);
+	}
+
+	if (!found)
+		die("device %i is not a member of %s", dev_idx, path);
+
+	if (dry_run)
+		goto out;
+
+	/* buckets can't be discarded until the journal entry freeing them is written: */
+	if (syncfs(fs.ioctl_fd))
+		die("syncfs error: %m");
+
+	u64 before = trim_need_discard(fs, &devs, dev_idx), after;
+	u64 start = ktime_get_ns();
+
+	write_file_str(fs.sysfs_fd, "internal/trigger_discards", "1");
+
+	while (1) {
+		after = trim_need_discard(fs, &devs, dev_idx);
+		if (!after)
+			break;
+
+		if (ktime_get_ns() - start >= (u64) timeout * NSEC_PER_SEC) {
+			fprintf(stderr, "timed out waiting for discards\n");
+			break;
+		}
+		usleep(100 * 1000);
+	}
+
+	darray_for_each(enabled, i) {
+		char *attr = trim_discard_attr(*i);
+		write_file_str(fs.sysfs_fd, attr, "0");
+		free(attr);
+	}
+
+	printbuf_reset(&buf);
+	prt_str(&buf, "trimmed: ");
+	tools_prt_units_u64(&buf, (before - min(before, after)) << 9);
+	prt_newline(&buf);
+	prt_str(&buf, "free buckets can only be trimmed with the filesystem unmounted");
+	printf("%s\n", buf.buf);
+out:
+	darray_exit(&enabled);
+	printbuf_exit(&buf);
+	darray_for_each(devs, d) {
+		free(d->dev);
+		free(d->label);
+	}
+	darray_exit(&devs);
+	bcache_fs_close(fs);
+	return 0;
+}
+
+int cmd_device_trim(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "dev",		required_argument,	NULL, 'd' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "timeout",		required_argument,	NULL, 't' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	unsigned idx, timeout = 10;
+	int opt, dev_idx = -1;
+	bool dry_run = false;
+	struct stat st;
+
+	while ((opt = getopt_long(argc, argv, "d:nt:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'd':
+			if (kstrtouint(optarg, 10, &idx) || idx >= BCH_SB_MEMBERS_MAX)
+				die("invalid device index %s", optarg);
+			dev_idx = idx;
+			break;
+		case 'n':
+			dry_run = true;
+			break;
+		case 't':
+			if (kstrtouint(optarg, 10, &timeout))
+				die("invalid timeout %s", optarg);
+			break;
+		case 'h':
+			device_trim_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			device_trim_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply a mountpoint or device(s)");
+
+	if (!stat(argv[0], &st) && S_ISDIR(st.st_mode)) {
+		if (argc > 1)
+			die("too many arguments");
+		return device_trim_online(argv[0], dev_idx, dry_run, timeout);
+	}
+
+	return device_trim_offline(argc, argv, dev_idx, dry_run);
+}
@@ -0,0 +1,689 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/ioctl.h>
+#include <termios.h>
+#include <unistd.h>
+
+#include <linux/sort.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/subvolume.h"
+#include "libbcachefs/super.h"
+
+/*
+ * fs du: directory sizes of an unmounted filesystem, from one pass over each
+ * of the inodes, dirents and extents btrees instead of a stat() of every file.
+ *
+ * Files are linked to their directory by the inode backpointers (bi_dir,
+ * bi_dir_offset), so hardlinked files are counted once, under the link the
+ * backpointer refers to. Other subvolumes aren't descended into.
+ */
+
+#define DU_NONE		U32_MAX
+
+struct du_entry {
+	u64			inum;
+	u64			dir;
+	u64			dir_offset;
+	/* bytes on disk, or apparent size: */
+	u64			size;
+	/* size of this entry and everything below it: */
+	u64			total;
+	u32			parent;
+	/* children are kept sorted by total, largest first: */
+	u32			first_child;
+	u32			next_sibling;
+	bool			is_dir;
+	char			*name;
+};
+
+struct du {
+	DARRAY(struct du_entry)	e;
+	u32			snapshot;
+	u64			root_inum;
+	bool			apparent;
+};
+
+static int du_inum_cmp(const void *_l, const void *_r)
+{
+	const u64 *l = _l;
+	const struct du_entry *r = _r;
+
+	return cmp_int(*l, r->inum);
+}
+
+/* Entries are in inode number order, as the inodes btree is walked */
+static struct du_entry *du_find(struct du *du, u64 inum)
+{
+	return bsearch(&inum, du->e.data, du->e.nr, sizeof(du->e.data[0]), du_inum_cmp);
+}
+
+static struct du_entry *du_entry(struct du *du, u32 idx)
+{
+	return idx != DU_NONE ? du->e.data + idx : NULL;
+}
+
+#define for_each_du_child(_du, _e, _c)					\
+	for (struct du_entry *_c = du_entry(_du, (_e)->first_child);	\
+	     _c;							\
+	     _c = du_entry(_du, _c->next_sibling))
+
+static int du_walk_inodes(struct btree_trans *trans, struct du *du)
+{
+	return for_each_btree_key(trans, iter, BTREE_ID_inodes,
+				  SPOS(0, 0, du->snapshot),
+				  BTREE_ITER_prefetch, k, ({
+		struct bch_inode_unpacked inode;
+		int ret2 = 0;
+
+		if (bkey_is_inode(k.k) &&
+		    !(ret2 = bch2_inode_unpack(k, &inode))) {
+			struct du_entry e = {
+				.inum		= inode.bi_inum,
+				.dir		= inode.bi_dir,
+				.dir_offset	= inode.bi_dir_offset,
+				.size		= du->apparent ? inode.bi_size : 0,
+				.is_dir		= S_ISDIR(inode.bi_mode),
+			};
+
+			ret2 = darray_push(&du->e, e);
+		}
+		ret2;
+	}));
+}
+
+static u64 du_extent_disk_sectors(struct bkey_s_c k)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	u64 ret = 0;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry)
+		if (!p.ptr.cached)
+			ret += ptr_disk_sectors(k.k->size, p);
+	return ret;
+}
+
+static int du_walk_extents(struct btree_trans *trans, struct du *du)
+{
+	struct du_entry *cur = NULL;
+	u64 cur_inum = 0;
+
+	return for_each_btree_key(trans, iter, BTREE_ID_extents,
+				  SPOS(0, 0, du->snapshot),
+				  BTREE_ITER_prefetch, k, ({
+		if (k.k->p.inode != cur_inum) {
+			cur_inum = k.k->p.inode;
+			cur = du_find(du, cur_inum);
+		}
+
+		if (cur && bkey_extent_is_direct_data(k.k))
+			cur->size += du_extent_disk_sectors(k) << 9;
+		0;
+	}));
+}
+
+static int du_walk_dirents(struct btree_trans *trans, struct du *du)
+{
+	return for_each_btree_key(trans, iter, BTREE_ID_dirents,
+				  SPOS(0, 0, du->snapshot),
+				  BTREE_ITER_prefetch, k, ({
+		if (k.k->type == KEY_TYPE_dirent) {
+			struct bkey_s_c_dirent d = bkey_s_c_to_dirent(k);
+			struct du_entry *e = d.v->d_type != DT_SUBVOL
+				? du_find(du, le64_to_cpu(d.v->d_inum))
+				: NULL;
+
+			/* no backpointer: use the first link we find */
+			if (e && !e->dir && e->inum != du->root_inum) {
+				e->dir		= k.k->p.inode;
+				e->dir_offset	= k.k->p.offset;
+			}
+
+			if (e && !e->name &&
+			    e->dir == k.k->p.inode &&
+			    e->dir_offset == k.k->p.offset) {
+				struct qstr name = bch2_dirent_get_name(d);
+
+				e->name = strndup((const char *) name.name, name.len);
+			}
+		}
+		0;
+	}));
+}
+
+static int du_total_cmp(const void *_l, const void *_r, const void *priv)
+{
+	const struct du *du = priv;
+	const u32 *l = _l, *r = _r;
+
+	return cmp_int(du->e.data[*l].total, du->e.data[*r].total);
+}
+
+static void du_link(struct du *du)
+{
+	darray_for_each(du->e, e) {
+		struct du_entry *p = e->inum != du->root_inum && e->name
+			? du_find(du, e->dir)
+			: NULL;
+
+		e->parent	= p ? p - du->e.data : DU_NONE;
+		e->first_child	= DU_NONE;
+		e->next_sibling	= DU_NONE;
+	}
+
+	/* add each entry's size to it and all its ancestors (stopping on loops): */
+	darray_for_each(du->e, e) {
+		e->total += e->size;
+
+		unsigned depth = 0;
+		for (struct du_entry *p = du_entry(du, e->parent);
+		     p && depth < 4096;
+		     p = du_entry(du, p->parent), depth++)
+			p->total += e->size;
+	}
+
+	/* prepend in order of increasing size, so that children end up sorted: */
+	DARRAY(u32) order = {};
+
+	for (u32 i = 0; i < du->e.nr; i++)
+		if (darray_push(&order, i))
+			die("memory allocation failure");
+
+	sort_r(order.data, order.nr, sizeof(order.data[0]), du_total_cmp, NULL, du);
+
+	darray_for_each(order, i) {
+		struct du_entry *e = du->e.data + *i;
+		struct du_entry *p = du_entry(du, e->parent);
+
+		if (p) {
+			e->next_sibling	= p->first_child;
+			p->first_child	= *i;
+		}
+	}
+
+	darray_exit(&order);
+}
+
+static struct du_entry *du_lookup(struct du *du, const char *path)
+{
+	struct du_entry *e = du_find(du, du->root_inum);
+	char *p = strdup(path), *s = p, *n;
+
+	while (e && (n = strsep(&s, "/"))) {
+		if (!*n || !strcmp(n, "."))
+			continue;
+
+		struct du_entry *d = e;
+		e = NULL;
+
+		for_each_du_child(du, d, c)
+			if (!strcmp(c->name, n)) {
+				e = c;
+				break;
+			}
+	}
+
+	free(p);
+	return e;
+}
+
+static void du_exit(struct du *du)
+{
+	darray_for_each(du->e, e)
+		free(e->name);
+	darray_exit(&du->e);
+}
+
+static unsigned du_pct(u64 v, u64 total)
+{
+	return total ? div64_u64(v * 100, total) : 0;
+}
+
+/* The heatmap: a bar proportional to the share of the top level's total */
+static void du_bar(struct printbuf *out, u64 v, u64 total, unsigned width)
+{
+	unsigned n = total ? div64_u64(v * width, total) : 0;
+
+	prt_char(out, '[');
+	for (unsigned i = 0; i < width; i++)
+		prt_char(out, i < n ? '#' : ' ');
+	prt_char(out, ']');
+}
+
+static void du_size_to_text(struct printbuf *out, u64 v, u64 total)
+{
+	tools_prt_units_u64(out, v);
+	prt_tab_rjust(out);
+	prt_printf(out, "%u%%", du_pct(v, total));
+	prt_tab_rjust(out);
+	prt_char(out, ' ');
+	du_bar(out, v, total, 20);
+	prt_char(out, ' ');
+}
+
+static void du_truncate(struct printbuf *buf, unsigned pos)
+{
+	buf->pos = pos;
+	if (buf->size)
+		buf->buf[pos] = '\0';
+}
+
+/* du style: directories in post order, with their totals */
+static void du_plain_to_text(struct printbuf *out, struct du *du, struct du_entry *e,
+			     struct printbuf *path, unsigned depth, unsigned max_depth)
+{
+	unsigned pos = path->pos;
+
+	for_each_du_child(du, e, c)
+		if (c->is_dir && depth < max_depth) {
+			prt_printf(path, "/%s", c->name);
+			du_plain_to_text(out, du, c, path, depth + 1, max_depth);
+			du_truncate(path, pos);
+		}
+
+	tools_prt_units_u64(out, e->total);
+	prt_printf(out, "\t%s\n", path->pos ? path->buf : "/");
+}
+
+struct du_tree_opts {
+	unsigned		max_depth;
+	unsigned		nr;
+	u64			root_total;
+};
+
+static void du_tree_to_text(struct printbuf *out, struct du *du, struct du_entry *e,
+			    struct printbuf *prefix, unsigned depth,
+			    struct du_tree_opts *o)
+{
+	unsigned pos = prefix->pos, nr = 0, nr_more = 0;
+	u64 more = 0;
+
+	if (depth >= o->max_depth)
+		return;
+
+	for_each_du_child(du, e, c) {
+		if (nr == o->nr) {
+			nr_more++;
+			more += c->total;
+			continue;
+		}
+		nr++;
+
+		/* entries past --nr go on an "N more" line, last: */
+		bool last = c->next_sibling == DU_NONE;
+
+		du_size_to_text(out, c->total, o->root_total);
+		prt_printf(out, "%s%s%s%s\n", prefix->buf ?: "", last ? "`-- " : "|-- ",
+			   c->name, c->is_dir ? "/" : "");
+
+		if (c->is_dir) {
+			prt_str(prefix, last ? "    " : "|   ");
+			du_tree_to_text(out, du, c, prefix, depth + 1, o);
+			du_truncate(prefix, pos);
+		}
+	}
+
+	if (nr_more) {
+		du_size_to_text(out, more, o->root_total);
+		prt_printf(out, "%s`-- (%u more)\n", prefix->buf ?: "", nr_more);
+	}
+}
+
+static void du_tabstops(struct printbuf *out)
+{
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 10);
+	printbuf_tabstop_push(out, 5);
+}
+
+/* Interactive mode, like ncdu: */
+
+struct du_tui {
+	struct du		*du;
+	struct du_entry		*root;
+	const char		*root_path;
+	struct du_entry		*dir;
+	DARRAY(struct du_entry *) children;
+	unsigned		sel;
+	unsigned		top;
+	bool			human_readable;
+};
+
+static void du_tui_path(struct printbuf *out, struct du_tui *t, struct du_entry *e)
+{
+	if (e == t->root || !e) {
+		prt_str(out, t->root_path);
+		return;
+	}
+
+	du_tui_path(out, t, du_entry(t->du, e->parent));
+	if (out->pos && out->buf[out->pos - 1] != '/')
+		prt_char(out, '/');
+	prt_str(out, e->name);
+}
+
+static void du_tui_enter(struct du_tui *t, struct du_entry *dir, struct du_entry *sel)
+{
+	t->dir	= dir;
+	t->sel	= 0;
+	t->top	= 0;
+	t->children.nr = 0;
+
+	for_each_du_child(t->du, dir, c) {
+		if (c == sel)
+			t->sel = t->children.nr;
+		if (darray_push(&t->children, c))
+			die("memory allocation failure");
+	}
+}
+
+static void du_tui_draw(struct du_tui *t)
+{
+	struct printbuf buf = PRINTBUF;
+	struct winsize ws = { .ws_row = 24, .ws_col = 80 };
+
+	ioctl(STDOUT_FILENO, TIOCGWINSZ, &ws);
+
+	unsigned rows = max_t(int, ws.ws_row - 3, 1);
+
+	if (t->sel < t->top)
+		t->top = t->sel;
+	if (t->sel >= t->top + rows)
+		t->top = t->sel - rows + 1;
+
+	tools_printbuf_units(&buf, t->human_readable);
+	du_tabstops(&buf);
+
+	prt_str(&buf, "\033[H\033[2J");
+	du_tui_path(&buf, t, t->dir);
+	prt_str(&buf, ": ");
+	tools_prt_units_u64(&buf, t->dir->total);
+	prt_str(&buf, "\r\n  arrows/hjkl move, enter open, backspace up, q quit\r\n\r\n");
+
+	for (unsigned i = t->top; i < t->children.nr && i < t->top + rows; i++) {
+		struct du_entry *c = t->children.data[i];
+
+		if (i == t->sel)
+			prt_str(&buf, "\033[7m");
+		du_size_to_text(&buf, c->total, t->dir->total);
+		prt_printf(&buf, "%s%s", c->name, c->is_dir ? "/" : "");
+		if (i == t->sel)
+			prt_str(&buf, "\033[0m");
+		prt_str(&buf, "\r\n");
+	}
+
+	if (!t->children.nr)
+		prt_str(&buf, "  (empty)\r\n");
+
+	fputs(buf.buf, stdout);
+	fflush(stdout);
+	printbuf_exit(&buf);
+}
+
+enum du_key {
+	DU_KEY_NONE,
+	DU_KEY_UP,
+	DU_KEY_DOWN,
+	DU_KEY_OPEN,
+	DU_KEY_BACK,
+	DU_KEY_QUIT,
+};
+
+static enum du_key du_tui_key(void)
+{
+	char b[8] = {};
+	ssize_t n = read(STDIN_FILENO, b, sizeof(b) - 1);
+
+	if (n <= 0)
+		return DU_KEY_QUIT;
+
+	if (!strcmp(b, "\033[A") || !strcmp(b, "k"))
+		return DU_KEY_UP;
+	if (!strcmp(b, "\033[B") || !strcmp(b, "j"))
+		return DU_KEY_DOWN;
+	if (!strcmp(b, "\033[C") || !strcmp(b, "l") ||
+	    !strcmp(b, "\r") || !strcmp(b, "\n"))
+		return DU_KEY_OPEN;
+	if (!strcmp(b, "\033[D") || !strcmp(b, "h") ||
+	    !strcmp(b, "\x7f") || !strcmp(b, "\b"))
+		return DU_KEY_BACK;
+	if (!strcmp(b, "q") || !strcmp(b, "\033"))
+		return DU_KEY_QUIT;
+	return DU_KEY_NONE;
+}
+
+static void du_tui(struct du *du, struct du_entry *root, const char *root_path,
+		   bool human_readable)
+{
+	struct du_tui t = {
+		.du		= du,
+		.root		= root,
+		.root_path	= root_path,
+		.human_readable	= human_readable,
+	};
+	struct termios old, raw;
+
+	if (!isatty(STDIN_FILENO) || !isatty(STDOUT_FILENO))
+		die("--interactive needs a terminal");
+
+	if (tcgetattr(STDIN_FILENO, &old))
+		die("error getting terminal attrs: %m");
+
+	raw = old;
+	raw.c_lflag &= ~(ICANON|ECHO);
+	raw.c_cc[VMIN]	= 1;
+	raw.c_cc[VTIME]	= 0;
+	if (tcsetattr(STDIN_FILENO, TCSAFLUSH, &raw))
+		die("error setting terminal attrs: %m");
+
+	/* alternate screen, hide the cursor, no line wrapping: */
+	printf("\033[?1049h\033[?25l\033[?7l");
+
+	du_tui_enter(&t, root, NULL);
+
+	while (1) {
+		du_tui_draw(&t);
+
+		struct du_entry *sel = t.sel < t.children.nr
+			? t.children.data[t.sel]
+			: NULL;
+
+		switch (du_tui_key()) {
+		case DU_KEY_UP:
+			if (t.sel)
+				t.sel--;
+			break;
+		case DU_KEY_DOWN:
+			if (t.sel + 1 < t.children.nr)
+				t.sel++;
+			break;
+		case DU_KEY_OPEN:
+			if (sel && sel->is_dir)
+				du_tui_enter(&t, sel, NULL);
+			break;
+		case DU_KEY_BACK:
+			if (t.dir != t.root)
+				du_tui_enter(&t, du_entry(du, t.dir->parent), t.dir);
+			break;
+		case DU_KEY_QUIT:
+			goto out;
+		case DU_KEY_NONE:
+			break;
+		}
+	}
+out:
+	printf("\033[?7h\033[?25h\033[?1049l");
+	fflush(stdout);
+	tcsetattr(STDIN_FILENO, TCSAFLUSH, &old);
+	darray_exit(&t.children);
+}
+
+static void du_usage(void)
+{
+	puts("bcachefs fs du - show directory sizes\n"
+	     "Usage: bcachefs fs du [OPTION]... <devices>\n"
+	     "\n"
+	     "Walks the inodes, dirents and extents btrees once each, and shows the space\n"
+	     "used by each directory, after compression and counting each replica\n"
+	     "(or apparent sizes). Works on unmounted filesystems.\n"
+	     "Reflinked (shared) extents aren't counted, and hardlinked files are only\n"
+	     "counted in one directory.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -t, --tree                   Show a tree, with each entry's share of the total\n"
+	     "  -i, --interactive            Browse the tree interactively\n"
+	     "  -p, --path=PATH              Start from this directory (default /)\n"
+	     "  -s, --subvolume=ID           Subvolume to show (default: the root subvolume)\n"
+	     "  -d, --depth=N                Only show directories N levels down\n"
+	     "                               (default: all, or 3 with --tree)\n"
+	     "  -n, --nr=NR                  With --tree, entries per directory (default 10)\n"
+	     "      --apparent-size          Show file sizes instead of space used on disk\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -v, --verbose                Verbose mode\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_fs_du(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "tree",		no_argument,		NULL, 't' },
+		{ "interactive",	no_argument,		NULL, 'i' },
+		{ "path",		required_argument,	NULL, 'p' },
+		{ "subvolume",		required_argument,	NULL, 's' },
+		{ "depth",		required_argument,	NULL, 'd' },
+		{ "nr",			required_argument,	NULL, 'n' },
+		{ "apparent-size",	no_argument,		NULL, 'A' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	struct du du = {};
+	struct du_tree_opts tree_opts = { .nr = 10 };
+	const char *path = "/";
+	bool tree = false, interactive = false, have_depth = false;
+	unsigned subvol = BCACHEFS_ROOT_SUBVOL, max_depth = UINT_MAX;
+	int opt;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, read_only,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "tip:s:d:n:hv", longopts, NULL)) != -1)
+		switch (opt) {
+		case 't':
+			tree = true;
+			break;
+		case 'i':
+			interactive = true;
+			break;
+		case 'p':
+			path = optarg;
+			break;
+		case 's':
+			if (kstrtouint(optarg, 10, &subvol) || !subvol)
+				die("invalid subvolume %s", optarg);
+			break;
+		case 'd':
+			if (kstrtouint(optarg, 10, &max_depth))
+				die("invalid depth %s", optarg);
+			have_depth = true;
+			break;
+		case 'n':
+			if (kstrtouint(optarg, 10, &tree_opts.nr) || !tree_opts.nr)
+				die("invalid number of entries %s", optarg);
+			break;
+		case 'A':
+			du.apparent = true;
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'v':
+			opt_set(opts, verbose, true);
+			break;
+		case 'H':
+			du_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			du_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	struct bch_subvolume s;
+	int ret = bch2_trans_run(c,
+		lockrestart_do(trans, bch2_subvolume_get(trans, subvol, false, 0, &s)));
+	if (ret)
+		die("error looking up subvolume %u: %s", subvol, bch2_err_str(ret));
+
+	du.snapshot	= le32_to_cpu(s.snapshot);
+	du.root_inum	= le64_to_cpu(s.inode);
+
+	ret = bch2_trans_run(c,
+		du_walk_inodes(trans, &du) ?:
+		du_walk_dirents(trans, &du) ?:
+		(!du.apparent ? du_walk_extents(trans, &du) : 0));
+	if (ret)
+		die("error walking btrees: %s", bch2_err_str(ret));
+
+	du_link(&du);
+
+	struct du_entry *root = du_lookup(&du, path);
+	if (!root)
+		die("%s: not found", path);
+	if (!root->is_dir)
+		die("%s: not a directory", path);
+
+	if (interactive) {
+		du_tui(&du, root, path, buf.human_readable_units);
+	} else if (tree) {
+		struct printbuf prefix = PRINTBUF;
+
+		tree_opts.max_depth	= have_depth ? max_depth : 3;
+		tree_opts.root_total	= root->total;
+
+		du_tabstops(&buf);
+		du_size_to_text(&buf, root->total, root->total);
+		prt_printf(&buf, "%s\n", path);
+		du_tree_to_text(&buf, &du, root, &prefix, 0, &tree_opts);
+		printbuf_exit(&prefix);
+	} else {
+		struct printbuf p = PRINTBUF;
+
+		prt_str(&p, strcmp(path, "/") ? path : "");
+		du_plain_to_text(&buf, &du, root, &p, 0, max_depth);
+		printbuf_exit(&p);
+	}
+
+	printf("%s", buf.buf);
+
+	printbuf_exit(&buf);
+	du_exit(&du);
+	bch2_fs_stop(c);
+	return 0;
+}
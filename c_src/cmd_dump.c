@@ -1,11 +1,18 @@
 #include <fcntl.h>
 #include <getopt.h>
+#include <pthread.h>
+#include <spawn.h>
 #include <string.h>
 #include <sys/stat.h>
 #include <sys/types.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include <zstd.h>
 
 #include "cmds.h"
 #include "libbcachefs.h"
+#include "progress.h"
 #include "qcow2.h"
 
 #include "libbcachefs/bcachefs.h"
@@ -23,13 +30,186 @@ static void dump_usage(void)
 	     "Usage: bcachefs dump [OPTION]... <devices>\n"
 	     "\n"
 	     "Options:\n"
-	     "  -o output     Output qcow2 image(s)\n"
-	     "  -f, --force   Force; overwrite when needed\n"
-	     "  --nojournal   Don't dump entire journal, just dirty entries\n"
-	     "  -h, --help    Display this help and exit\n"
+	     "  -o output             Output qcow2 image(s): a filename, - for stdout,\n"
+	     "                        ssh://[user@]host/path or http(s)://url (PUT, with curl)\n"
+	     "  -z, --zstd[=level]    Compress the image(s) with zstd, as they're written\n"
+	     "  -f, --force           Force; overwrite when needed\n"
+	     "  --nojournal           Don't dump entire journal, just dirty entries\n"
+	     "  --no-progress         Don't show progress\n"
+	     "  --progress-json       Report progress as JSON lines on stderr\n"
+	     "  -h, --help            Display this help and exit\n"
+	     "\n"
+	     "The image(s) are written sequentially, so they can be streamed: with multiple\n"
+	     "devices, .<dev idx> is added before the .qcow2 (and .zst) suffix.\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
 
+/*
+ * Where an image is going: @fd is what the image is written to, which may be
+ * the write end of a pipe to a compression thread, and/or ssh/curl
+ */
+struct dump_out {
+	int			fd;
+	pid_t			pid;
+
+	bool			zstd;
+	pthread_t		zstd_thread;
+	int			zstd_in;
+	int			zstd_out;
+	int			zstd_level;
+};
+
+static void *dump_zstd_thread(void *arg)
+{
+	struct dump_out *o = arg;
+	size_t in_size = ZSTD_CStreamInSize(), out_size = ZSTD_CStreamOutSize();
+	void *in_buf = xmalloc(in_size), *out_buf = xmalloc(out_size);
+	ZSTD_CCtx *ctx = ZSTD_createCCtx();
+	ssize_t r;
+
+	if (!ctx)
+		die("error initializing zstd");
+	ZSTD_CCtx_setParameter(ctx, ZSTD_c_compressionLevel, o->zstd_level);
+	ZSTD_CCtx_setParameter(ctx, ZSTD_c_checksumFlag, 1);
+
+	do {
+		r = read(o->zstd_in, in_buf, in_size);
+		if (r < 0)
+			die("error reading image: %m");
+
+		ZSTD_EndDirective mode = r ? ZSTD_e_continue : ZSTD_e_end;
+		ZSTD_inBuffer in = { in_buf, r, 0 };
+		size_t remaining;
+
+		do {
+			ZSTD_outBuffer out = { out_buf, out_size, 0 };
+
+			remaining = ZSTD_compressStream2(ctx, &out, &in, mode);
+			if (ZSTD_isError(remaining))
+				die("zstd error: %s", ZSTD_getErrorName(remaining));
+
+			xwrite(o->zstd_out, out_buf, out.pos, "compressed image");
+		} while (mode == ZSTD_e_end ? remaining : in.pos < in.size);
+	} while (r);
+
+	ZSTD_freeCCtx(ctx);
+	free(out_buf);
+	free(in_buf);
+	return NULL;
+}
+
+/* Start @argv with its stdin a pipe; returns the write end */
+static int dump_spawn(char * const argv[], pid_t *pid)
+{
+	posix_spawn_file_actions_t actions;
+	int fds[2];
+
+	/* O_CLOEXEC, so that the child sees EOF when we close our end: */
+	if (pipe2(fds, O_CLOEXEC))
+		die("error creating pipe: %m");
+
+	posix_spawn_file_actions_init(&actions);
+	posix_spawn_file_actions_adddup2(&actions, fds[0], STDIN_FILENO);
+
+	int ret = posix_spawnp(pid, argv[0], &actions, NULL, argv, environ);
+	if (ret)
+		die("error running %s: %s", argv[0], strerror(ret));
+
+	posix_spawn_file_actions_destroy(&actions);
+	close(fds[0]);
+	return fds[1];
+}
+
+/* For the remote shell: */
+static char *shell_quote(const char *s)
+{
+	struct printbuf buf = PRINTBUF;
+
+	prt_char(&buf, '\'');
+	for (; *s; s++)
+		if (*s == '\'')
+			prt_str(&buf, "'\\''");
+		else
+			prt_char(&buf, *s);
+	prt_char(&buf, '\'');
+
+	return buf.buf;
+}
+
+static void dump_out_open(struct dump_out *o, int stdout_fd,
+			  const char *out, const char *suffix, bool force)
+{
+	char *path = mprintf("%s%s%s", out, suffix, o->zstd ? ".zst" : "");
+
+	if (!strcmp(out, "-")) {
+		o->fd = dup(stdout_fd);
+	} else if (!strncmp(path, "ssh://", 6)) {
+		char *host = strdup(path + 6);
+		char *remote_path = strchr(host, '/');
+
+		if (!remote_path || !remote_path[1])
+			die("%s: no remote path", out);
+
+		char *quoted = shell_quote(remote_path);
+		*remote_path = '\0';
+
+		/* noclobber, for O_EXCL: */
+		char *cmd = mprintf("%scat > %s", force ? "" : "set -C; ", quoted);
+		char *argv[] = { "ssh", "--", host, cmd, NULL };
+
+		o->fd = dump_spawn(argv, &o->pid);
+
+		free(cmd);
+		free(quoted);
+		free(host);
+	} else if (!strncmp(path, "http://", 7) ||
+		   !strncmp(path, "https://", 8)) {
+		char *argv[] = { "curl", "--silent", "--show-error", "--fail",
+			"--upload-file", "-", path, NULL };
+
+		o->fd = dump_spawn(argv, &o->pid);
+	} else {
+		o->fd = xopen(path, O_WRONLY|O_CREAT|O_TRUNC|(force ? 0 : O_EXCL), 0600);
+	}
+
+	if (o->zstd) {
+		int fds[2];
+
+		if (pipe2(fds, O_CLOEXEC))
+			die("error creating pipe: %m");
+
+		o->zstd_in	= fds[0];
+		o->zstd_out	= o->fd;
+		o->fd		= fds[1];
+
+		if (pthread_create(&o->zstd_thread, NULL, dump_zstd_thread, o))
+			die("error starting compression thread");
+	}
+
+	free(path);
+}
+
+static void dump_out_close(struct dump_out *o)
+{
+	close(o->fd);
+
+	if (o->zstd) {
+		pthread_join(o->zstd_thread, NULL);
+		close(o->zstd_in);
+		close(o->zstd_out);
+	}
+
+	if (o->pid) {
+		int status;
+
+		if (waitpid(o->pid, &status, 0) < 0)
+			die("waitpid error: %m");
+		if (!WIFEXITED(status) || WEXITSTATUS(status))
+			die("error sending image: upload command failed");
+		o->pid = 0;
+	}
+}
+
 static void dump_node(struct bch_fs *c, struct bch_dev *ca, struct bkey_s_c k, ranges *data)
 {
 	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
@@ -43,6 +223,7 @@ static void dump_one_device(struct bch_fs *c, struct bch_dev *ca, int fd,
 			    bool entire_journal)
 {
 	struct bch_sb *sb = ca->disk_sb.sb;
+	struct progress p;
 	ranges data = { 0 };
 	unsigned i;
 	int ret;
@@ -67,6 +248,9 @@ static void dump_one_device(struct bch_fs *c, struct bch_dev *ca, int fd,
 				  bucket_bytes(ca));
 		}
 
+	progress_init(&p, "dump");
+	progress_stage(&p, "scanning btree nodes", BTREE_ID_NR, false);
+
 	/* Btree: */
 	for (i = 0; i < BTREE_ID_NR; i++) {
 		struct btree_trans *trans = bch2_trans_get(c);
@@ -91,10 +275,14 @@ static void dump_one_device(struct bch_fs *c, struct bch_dev *ca, int fd,
 
 		bch2_trans_iter_exit(trans, &iter);
 		bch2_trans_put(trans);
+
+		progress_update(&p, i + 1);
 	}
 
 	qcow2_write_image(ca->disk_sb.bdev->bd_fd, fd, &data,
-			  max_t(unsigned, c->opts.btree_node_size / 8, block_bytes(c)));
+			  max_t(unsigned, c->opts.btree_node_size / 8, block_bytes(c)),
+			  &p);
+	progress_finish(&p);
 	darray_exit(&data);
 }
 
@@ -103,7 +291,10 @@ int cmd_dump(int argc, char *argv[])
 	static const struct option longopts[] = {
 		{ "force",		no_argument,		NULL, 'f' },
 		{ "nojournal",		no_argument,		NULL, 'j' },
+		{ "zstd",		optional_argument,	NULL, 'z' },
 		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "no-progress",	no_argument,		NULL, OPT_NO_PROGRESS },
+		{ "progress-json",	no_argument,		NULL, OPT_PROGRESS_JSON },
 		{ "help",		no_argument,		NULL, 'h' },
 		{ NULL }
 	};
@@ -111,7 +302,8 @@ int cmd_dump(int argc, char *argv[])
 	char *out = NULL;
 	unsigned nr_devices = 0;
 	bool force = false, entire_journal = true;
-	int fd, opt;
+	struct dump_out o = { .zstd_level = ZSTD_CLEVEL_DEFAULT };
+	int stdout_fd = -1, opt;
 
 	opt_set(opts, direct_io,	false);
 	opt_set(opts, read_only,	true);
@@ -122,7 +314,7 @@ int cmd_dump(int argc, char *argv[])
 	opt_set(opts, errors,		BCH_ON_ERROR_continue);
 	opt_set(opts, fix_errors,	FSCK_FIX_no);
 
-	while ((opt = getopt_long(argc, argv, "o:fvh",
+	while ((opt = getopt_long(argc, argv, "o:z::fvh",
 				  longopts, NULL)) != -1)
 		switch (opt) {
 		case 'o':
@@ -134,9 +326,21 @@ int cmd_dump(int argc, char *argv[])
 		case 'j':
 			entire_journal = false;
 			break;
+		case 'z':
+			o.zstd = true;
+			if (optarg &&
+			    (kstrtoint(optarg, 10, &o.zstd_level) ||
+			     o.zstd_level < ZSTD_minCLevel() ||
+			     o.zstd_level > ZSTD_maxCLevel()))
+				die("invalid zstd level %s", optarg);
+			break;
 		case 'v':
 			opt_set(opts, verbose, true);
 			break;
+		case OPT_NO_PROGRESS:
+		case OPT_PROGRESS_JSON:
+			progress_opt(opt);
+			break;
 		case 'h':
 			dump_usage();
 			exit(EXIT_SUCCESS);
@@ -149,6 +353,15 @@ int cmd_dump(int argc, char *argv[])
 	if (!argc)
 		die("Please supply device(s) to check");
 
+	if (!strcmp(out, "-")) {
+		if (isatty(STDOUT_FILENO))
+			die("Not writing an image to a terminal");
+
+		/* Log messages go to stdout, so move them out of the way: */
+		stdout_fd = dup(STDOUT_FILENO);
+		dup2(STDERR_FILENO, STDOUT_FILENO);
+	}
+
 	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
 	if (IS_ERR(c))
 		die("error opening devices: %s", bch2_err_str(PTR_ERR(c)));
@@ -160,22 +373,25 @@ int cmd_dump(int argc, char *argv[])
 
 	BUG_ON(!nr_devices);
 
-	for_each_online_member(c, ca) {
-		int flags = O_WRONLY|O_CREAT|O_TRUNC;
+	if (stdout_fd >= 0 && nr_devices > 1)
+		die("Can only write one device's image to stdout, and this filesystem has %u",
+		    nr_devices);
 
-		if (!force)
-			flags |= O_EXCL;
+	for_each_online_member(c, ca) {
+		char *suffix = nr_devices > 1
+			? mprintf(".%u.qcow2", ca->dev_idx)
+			: strdup(".qcow2");
 
-		char *path = nr_devices > 1
-			? mprintf("%s.%u.qcow2", out, ca->dev_idx)
-			: mprintf("%s.qcow2", out);
-		fd = xopen(path, flags, 0600);
-		free(path);
+		dump_out_open(&o, stdout_fd, out, suffix, force);
+		free(suffix);
 
-		dump_one_device(c, ca, fd, entire_journal);
-		close(fd);
+		dump_one_device(c, ca, o.fd, entire_journal);
+		dump_out_close(&o);
 	}
 
+	if (stdout_fd >= 0)
+		close(stdout_fd);
+
 	up_read(&c->gc_lock);
 
 	bch2_fs_stop(c);
@@ -0,0 +1,399 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/stat.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/alloc_foreground.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/ec.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/move.h"
+#include "libbcachefs/replicas.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/super.h"
+
+int ec_usage(void)
+{
+	puts("bcachefs ec - inspect and repair erasure coded stripes\n"
+	     "Usage: bcachefs ec <CMD> [OPTION]...\n"
+	     "\n"
+	     "Commands:\n"
+	     "  status                  show stripe counts and degraded stripes\n"
+	     "  repair                  reconstruct data in degraded stripes\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+	return 0;
+}
+
+typedef DARRAY(u64) stripe_idxs;
+
+struct ec_status {
+	bool		dev_bad[BCH_SB_MEMBERS_MAX];
+
+	u64		stripes;
+	u64		empty;
+	u64		degraded;
+	u64		unrecoverable;
+	/* sectors: */
+	u64		capacity;
+	u64		used;
+	u64		reconstruct;
+
+	/* indexed by nr data blocks, nr parity blocks */
+	u64		by_type[BCH_BKEY_PTRS_MAX + 1][BCH_BKEY_PTRS_MAX + 1];
+	u64		dev_blocks[BCH_SB_MEMBERS_MAX];
+
+	stripe_idxs	degraded_idx;
+};
+
+/* A device is bad if it's missing, offline, or marked failed: */
+static void ec_status_init(struct bch_fs *c, struct ec_status *s)
+{
+	memset(s, 0, sizeof(*s));
+
+	rcu_read_lock();
+	for (unsigned i = 0; i < BCH_SB_MEMBERS_MAX; i++) {
+		struct bch_dev *ca = bch2_dev_rcu(c, i);
+
+		s->dev_bad[i] = !ca || !bch2_dev_is_readable(ca);
+	}
+	rcu_read_unlock();
+}
+
+static void ec_status_add(struct ec_status *s, struct bkey_s_c k,
+			  struct printbuf *verbose, struct bch_fs *c)
+{
+	const struct bch_stripe *v = bkey_s_c_to_stripe(k).v;
+	unsigned nr_data = v->nr_blocks - v->nr_redundant;
+	unsigned sectors = le16_to_cpu(v->sectors);
+	unsigned nr_bad = 0;
+	bool empty = true;
+
+	s->stripes++;
+	s->by_type[nr_data][v->nr_redundant]++;
+	s->capacity += (u64) sectors * v->nr_blocks;
+
+	for (unsigned i = 0; i < v->nr_blocks; i++) {
+		unsigned dev = v->ptrs[i].dev;
+		bool bad = dev >= BCH_SB_MEMBERS_MAX || s->dev_bad[dev];
+
+		if (dev < BCH_SB_MEMBERS_MAX)
+			s->dev_blocks[dev]++;
+		nr_bad += bad;
+
+		if (i < nr_data) {
+			unsigned used = stripe_blockcount_get(v, i);
+
+			s->used += used;
+			if (bad)
+				s->reconstruct += used;
+			if (used)
+				empty = false;
+		}
+	}
+
+	s->empty += empty;
+
+	if (!nr_bad)
+		return;
+
+	if (nr_bad > v->nr_redundant)
+		s->unrecoverable++;
+	else
+		s->degraded++;
+
+	if (darray_push(&s->degraded_idx, k.k->p.offset))
+		die("memory allocation failure");
+
+	if (verbose) {
+		prt_printf(verbose, "%s: ", nr_bad > v->nr_redundant
+			   ? "unrecoverable" : "degraded");
+		bch2_bkey_val_to_text(verbose, c, k);
+		prt_newline(verbose);
+	}
+}
+
+static int ec_status_walk(struct bch_fs *c, struct ec_status *s,
+			  struct printbuf *verbose)
+{
+	struct btree_trans *trans = bch2_trans_get(c);
+
+	int ret = for_each_btree_key(trans, iter, BTREE_ID_stripes, POS_MIN,
+				     BTREE_ITER_prefetch, k, ({
+		if (k.k->type == KEY_TYPE_stripe)
+			ec_status_add(s, k, verbose, c);
+		0;
+	}));
+
+	bch2_trans_put(trans);
+	return ret;
+}
+
+static void ec_status_to_text(struct printbuf *out, struct bch_fs *c,
+			      struct ec_status *s)
+{
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 24);
+	printbuf_tabstop_push(out, 16);
+
+	prt_printf(out, "Stripes:\t%llu\r\n", s->stripes);
+	prt_printf(out, "Empty (pending delete):\t%llu\r\n", s->empty);
+	prt_printf(out, "Degraded:\t%llu\r\n", s->degraded);
+	prt_printf(out, "Unrecoverable:\t%llu\r\n", s->unrecoverable);
+
+	prt_printf(out, "Capacity:\t");
+	tools_prt_units_u64(out, s->capacity << 9);
+	prt_printf(out, "\r\n");
+
+	prt_printf(out, "Data:\t");
+	tools_prt_units_u64(out, s->used << 9);
+	prt_printf(out, "\r\n");
+
+	prt_printf(out, "Pending reconstruct:\t");
+	tools_prt_units_u64(out, s->reconstruct << 9);
+	prt_printf(out, "\r\n");
+
+	if (s->stripes) {
+		prt_printf(out, "\nStripes by layout (data+parity):\n");
+		for (unsigned d = 0; d <= BCH_BKEY_PTRS_MAX; d++)
+			for (unsigned p = 0; p <= BCH_BKEY_PTRS_MAX; p++)
+				if (s->by_type[d][p])
+					prt_printf(out, "%u+%u\t%llu\r\n",
+						   d, p, s->by_type[d][p]);
+
+		prt_printf(out, "\nStripe blocks by device:\n");
+		for (unsigned i = 0; i < BCH_SB_MEMBERS_MAX; i++) {
+			if (!s->dev_blocks[i])
+				continue;
+
+			struct bch_dev *ca = bch2_dev_tryget_noerror(c, i);
+			const char *name = ca && ca->name[0] ? ca->name : "(missing)";
+
+			prt_printf(out, "%u %s%s\t%llu\r\n", i, name,
+				   s->dev_bad[i] ? " (unavailable)" : "",
+				   s->dev_blocks[i]);
+			if (ca)
+				bch2_dev_put(ca);
+		}
+	}
+}
+
+static void ec_status_usage(void)
+{
+	puts("bcachefs ec status - show erasure coding status\n"
+	     "Usage: bcachefs ec status [OPTION]... <devices>\n"
+	     "\n"
+	     "Scans the stripes btree of an unmounted filesystem and reports stripe\n"
+	     "counts, degraded stripes (blocks on missing or failed devices), and how\n"
+	     "much data must be reconstructed from parity.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -v, --verbose                List degraded stripes\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "Exits with status 1 if any stripes are degraded.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_ec_status(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	struct printbuf verbose = PRINTBUF;
+	bool list = false;
+	int opt;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, read_only,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "hv", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'v':
+			list = true;
+			break;
+		case 'H':
+			ec_status_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			ec_status_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	struct ec_status s;
+	ec_status_init(c, &s);
+
+	int ret = ec_status_walk(c, &s, list ? &verbose : NULL);
+	if (ret)
+		die("error walking stripes: %s", bch2_err_str(ret));
+
+	ec_status_to_text(&buf, c, &s);
+	printf("%s", buf.buf);
+
+	if (verbose.pos)
+		printf("\n%s", verbose.buf);
+
+	ret = s.degraded || s.unrecoverable;
+
+	printbuf_exit(&verbose);
+	printbuf_exit(&buf);
+	darray_exit(&s.degraded_idx);
+	bch2_fs_stop(c);
+	return ret;
+}
+
+static int stripe_idx_cmp(const void *_l, const void *_r)
+{
+	const u64 *l = _l, *r = _r;
+
+	return cmp_int(*l, *r);
+}
+
+/* Rewrite every pointer into a degraded stripe, so the data lands in a new one */
+static bool ec_repair_pred(struct bch_fs *c, void *arg,
+			   struct bkey_s_c k,
+			   struct bch_io_opts *io_opts,
+			   struct data_update_opts *data_opts)
+{
+	struct ec_status *s = arg;
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	unsigned i = 0;
+
+	data_opts->rewrite_ptrs		= 0;
+	data_opts->target		= 0;
+	data_opts->extra_replicas	= 0;
+	data_opts->btree_insert_flags	= 0;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		u64 idx = p.ec.idx;
+
+		if (p.has_ec &&
+		    bsearch(&idx, s->degraded_idx.data, s->degraded_idx.nr,
+			    sizeof(idx), stripe_idx_cmp))
+			data_opts->rewrite_ptrs |= 1U << i;
+		i++;
+	}
+
+	return data_opts->rewrite_ptrs != 0;
+}
+
+static void ec_repair_usage(void)
+{
+	puts("bcachefs ec repair - reconstruct data in degraded stripes\n"
+	     "Usage: bcachefs ec repair [OPTION]... <mountpoint>|<devices>\n"
+	     "\n"
+	     "On an unmounted filesystem, rewrites every extent with data in a degraded\n"
+	     "stripe; the data is reconstructed from parity and written to new, healthy\n"
+	     "stripes. Old stripes are deleted once empty.\n"
+	     "\n"
+	     "On a mounted filesystem, where the kernel has no stripe specific job, this\n"
+	     "runs a rereplicate data job, which also rewrites degraded stripe data.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_ec_repair(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			ec_repair_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			ec_repair_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply a mountpoint or device(s)");
+
+	struct stat st;
+	if (argc == 1 && !stat(argv[0], &st) && S_ISDIR(st.st_mode)) {
+		struct bchfs_handle fs = bcache_fs_open(argv[0]);
+
+		return bchu_data(fs, (struct bch_ioctl_data) {
+			.op		= BCH_DATA_OP_rereplicate,
+			.start_btree	= 0,
+			.start_pos	= POS_MIN,
+			.end_btree	= BTREE_ID_NR,
+			.end_pos	= POS_MAX,
+		});
+	}
+
+	opt_set(opts, degraded,		true);
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	struct ec_status s;
+	ec_status_init(c, &s);
+
+	int ret = ec_status_walk(c, &s, NULL);
+	if (ret)
+		die("error walking stripes: %s", bch2_err_str(ret));
+
+	if (!s.degraded_idx.nr) {
+		printf("No degraded stripes\n");
+		goto out;
+	}
+
+	printf("Repairing %zu degraded stripes (%llu unrecoverable)\n",
+	       s.degraded_idx.nr, s.unrecoverable);
+
+	struct bch_move_stats stats;
+	bch2_move_stats_init(&stats, "ec_repair");
+
+	ret = bch2_move_data(c, BBPOS_MIN, BBPOS_MAX, NULL, &stats,
+			     writepoint_hashed((unsigned long) current),
+			     false, ec_repair_pred, &s) ?:
+		bch2_replicas_gc2(c);
+
+	printf("Rewrote %llu extents, %llu sectors\n",
+	       atomic64_read(&stats.keys_moved),
+	       atomic64_read(&stats.sectors_moved));
+	bch2_move_stats_exit(&stats, c);
+
+	if (ret)
+		fprintf(stderr, "error repairing stripes: %s\n", bch2_err_str(ret));
+out:
+	darray_exit(&s.degraded_idx);
+	bch2_fs_stop(c);
+	return ret ? 1 : 0;
+}
@@ -34,6 +34,8 @@
 x(0,	replicas,		required_argument)	\
 x(0,	encrypted,		no_argument)		\
 x(0,	no_passphrase,		no_argument)		\
+x(0,	kdf_memory,		required_argument)	\
+x(0,	kdf_parallelism,	required_argument)	\
 x('L',	fs_label,		required_argument)	\
 x('U',	uuid,			required_argument)	\
 x(0,	fs_size,		required_argument)	\
@@ -45,6 +47,7 @@ x(0,	data_allowed,		required_argument)	\
 x(0,	durability,		required_argument)	\
 x(0,	version,		required_argument)	\
 x(0,	no_initialize,		no_argument)		\
+x(0,	auto_tier,		no_argument)		\
 x('f',	force,			no_argument)		\
 x('q',	quiet,			no_argument)		\
 x('v',	verbose,		no_argument)		\
@@ -63,9 +66,12 @@ static void usage(void)
 	     "      --replicas=#            Sets both data and metadata replicas\n"
 	     "      --encrypted             Enable whole filesystem encryption (chacha20/poly1305)\n"
 	     "      --no_passphrase         Don't encrypt master encryption key\n"
+	     "      --kdf-memory=size       Memory scrypt uses for the passphrase, a power of two (default 16M)\n"
+	     "      --kdf-parallelism=#     scrypt's parallelism factor (p), a power of two (default 16)\n"
 	     "  -L, --fs_label=label\n"
 	     "  -U, --uuid=uuid\n"
 	     "      --superblock_size=size\n"
+	     "      --auto-tier             Label devices ssd or hdd, and set the targets to match\n"
 	     "\n"
 	     "Device specific options:");
 
@@ -99,6 +105,9 @@ enum {
 },
 static const struct option format_opts[] = {
 	OPTS
+	{ "auto-tier",	no_argument, NULL, O_auto_tier },
+	{ "kdf-memory",	required_argument, NULL, O_kdf_memory },
+	{ "kdf-parallelism", required_argument, NULL, O_kdf_parallelism },
 	{ NULL }
 };
 #undef x
@@ -113,6 +122,62 @@ u64 read_flag_list_or_die(char *opt, const char * const list[],
 	return v;
 }
 
+/*
+ * --auto-tier: label devices ssd or hdd by whether they're rotational, and send
+ * foreground writes and promotes to the ssds, and background writes to the hdds
+ */
+static void format_auto_tier(struct dev_opts *devs, size_t nr_devs,
+			     struct bch_opt_strs *fs_opt_strs,
+			     bool force, bool quiet)
+{
+	unsigned nr_ssd = 0, nr_hdd = 0;
+
+	if (fs_opt_strs->foreground_target ||
+	    fs_opt_strs->promote_target ||
+	    fs_opt_strs->background_target)
+		die("--auto-tier sets foreground_target, promote_target and background_target itself");
+
+	for (struct dev_opts *dev = devs; dev < devs + nr_devs; dev++) {
+		if (dev->label)
+			die("--auto-tier labels devices itself; got --label %s for %s",
+			    dev->label, dev->path);
+
+		int rotational = dev_rotational(dev->path);
+		if (rotational < 0)
+			die("Can't tell whether %s is rotational; use --label and set targets instead of --auto-tier",
+			    dev->path);
+
+		dev->label = rotational ? "hdd" : "ssd";
+		nr_hdd += rotational;
+		nr_ssd += !rotational;
+	}
+
+	if (!quiet || !force) {
+		for (struct dev_opts *dev = devs; dev < devs + nr_devs; dev++)
+			printf("%s: %s\n", dev->path, dev->label);
+
+		if (nr_ssd && nr_hdd)
+			printf("foreground_target:\tssd\n"
+			       "promote_target:\t\tssd\n"
+			       "background_target:\thdd\n");
+		else
+			printf("All devices are %s, not setting targets\n",
+			       nr_hdd ? "rotational" : "non-rotational");
+	}
+
+	if (nr_ssd && nr_hdd) {
+		fs_opt_strs->foreground_target	= strdup("ssd");
+		fs_opt_strs->promote_target	= strdup("ssd");
+		fs_opt_strs->background_target	= strdup("hdd");
+	}
+
+	if (!force) {
+		fputs("Format with this policy?", stdout);
+		if (!ask_yn())
+			exit(EXIT_FAILURE);
+	}
+}
+
 int cmd_format(int argc, char *argv[])
 {
 	DARRAY(struct dev_opts) devices = { 0 };
@@ -120,7 +185,7 @@ int cmd_format(int argc, char *argv[])
 	struct format_opts opts	= format_opts_default();
 	struct dev_opts dev_opts = dev_opts_default();
 	bool force = false, no_passphrase = false, quiet = false, initialize = true, verbose = false;
-	bool unconsumed_dev_option = false;
+	bool unconsumed_dev_option = false, auto_tier = false, kdf_opt = false;
 	unsigned v;
 	int opt;
 
@@ -151,6 +216,16 @@ int cmd_format(int argc, char *argv[])
 		case O_no_passphrase:
 			no_passphrase = true;
 			break;
+		case O_kdf_memory:
+			if (bch2_strtoull_h(optarg, &opts.kdf.memory))
+				die("invalid kdf memory %s", optarg);
+			kdf_opt = true;
+			break;
+		case O_kdf_parallelism:
+			if (kstrtouint(optarg, 10, &opts.kdf.parallelism) || !opts.kdf.parallelism)
+				die("invalid kdf parallelism %s", optarg);
+			kdf_opt = true;
+			break;
 		case O_fs_label:
 		case 'L':
 			opts.label = optarg;
@@ -208,6 +283,9 @@ int cmd_format(int argc, char *argv[])
 		case O_no_initialize:
 			initialize = false;
 			break;
+		case O_auto_tier:
+			auto_tier = true;
+			break;
 		case O_no_opt:
 			darray_push(&device_paths, optarg);
 			dev_opts.path = optarg;
@@ -240,6 +318,15 @@ int cmd_format(int argc, char *argv[])
 	if (!devices.nr)
 		die("Please supply a device");
 
+	if (auto_tier)
+		format_auto_tier(devices.data, devices.nr, &fs_opt_strs, force, quiet);
+
+	if (kdf_opt) {
+		if (!opts.encrypted || no_passphrase)
+			die("--kdf options need --encrypted, with a passphrase");
+		kdf_opts_check(&opts.kdf);
+	}
+
 	if (opts.encrypted && !no_passphrase) {
 		opts.passphrase = read_passphrase_twice("Enter passphrase: ");
 		initialize = false;
@@ -261,7 +348,7 @@ int cmd_format(int argc, char *argv[])
 	if (!quiet) {
 		struct printbuf buf = PRINTBUF;
 
-		buf.human_readable_units = true;
+		tools_printbuf_units(&buf, true);
 
 		bch2_sb_to_text(&buf, sb, false, 1 << BCH_SB_FIELD_members_v2);
 		printf("%s", buf.buf);
@@ -302,6 +389,306 @@ int cmd_format(int argc, char *argv[])
 	return 0;
 }
 
+/*
+ * --compare-backups: the fields of each superblock copy that matter for
+ * recovery, so that copies that disagree - with each other, or with the other
+ * members - stand out
+ */
+struct sb_copy {
+	u64			offset;
+	int			ret;
+	u64			seq;
+	u64			write_time;
+	bool			clean;
+	unsigned		version;
+	__uuid_t		uuid;
+	char			*members;
+};
+
+typedef DARRAY(struct sb_copy) sb_copies;
+
+static struct sb_copy sb_copy_read(const char *dev, u64 offset)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+	struct sb_copy c = { .offset = offset };
+
+	opt_set(opts, noexcl,	true);
+	opt_set(opts, nochanges, true);
+	opt_set(opts, sb,	offset);
+
+	c.ret = bch2_read_super(dev, &opts, &sb);
+	if (c.ret)
+		return c;
+
+	struct printbuf buf = PRINTBUF;
+
+	for (unsigned i = 0; i < sb.sb->nr_devices; i++) {
+		struct bch_member m = bch2_sb_member_get(sb.sb, i);
+
+		if (i)
+			prt_char(&buf, ',');
+		prt_str(&buf, bch2_member_exists(sb.sb, i)
+			? bch2_member_states[BCH_MEMBER_STATE(&m)]
+			: "-");
+	}
+
+	c.seq		= le64_to_cpu(sb.sb->seq);
+	c.write_time	= le64_to_cpu(sb.sb->write_time);
+	c.clean		= BCH_SB_CLEAN(sb.sb);
+	c.version	= le16_to_cpu(sb.sb->version);
+	c.uuid		= sb.sb->user_uuid;
+	c.members	= strdup(buf.buf ?: "");
+
+	printbuf_exit(&buf);
+	bch2_free_super(&sb);
+	return c;
+}
+
+static bool sb_copy_differs(struct sb_copy *c, struct sb_copy *ref)
+{
+	return c->seq != ref->seq ||
+		c->clean != ref->clean ||
+		c->version != ref->version ||
+		memcmp(&c->uuid, &ref->uuid, sizeof(c->uuid)) ||
+		strcmp(c->members, ref->members);
+}
+
+static void sb_copy_to_text(struct printbuf *out, struct sb_copy *c,
+			    struct sb_copy *ref)
+{
+	prt_printf(out, "%llu", c->offset);
+	prt_tab(out);
+
+	if (c->ret) {
+		prt_printf(out, "unreadable: %s", bch2_err_str(c->ret));
+		prt_newline(out);
+		return;
+	}
+
+#define mark(_differs)	prt_str(out, ref && (_differs) ? "*" : " ")
+	prt_printf(out, "%llu", c->seq);
+	mark(c->seq != ref->seq);
+	prt_tab_rjust(out);
+
+	prt_char(out, ' ');
+	bch2_prt_datetime(out, c->write_time);
+	prt_tab(out);
+
+	prt_str(out, c->clean ? "yes" : "no");
+	mark(c->clean != ref->clean);
+	prt_tab(out);
+
+	bch2_version_to_text(out, c->version);
+	mark(c->version != ref->version);
+	prt_tab(out);
+
+	prt_str(out, c->members);
+	mark(strcmp(c->members, ref->members));
+	prt_newline(out);
+#undef mark
+}
+
+static int show_super_compare_backups(int argc, char *argv[])
+{
+	struct printbuf buf = PRINTBUF;
+	struct sb_copy first = {};
+	const char *first_dev = NULL;
+	unsigned nr_bad = 0;
+
+	printbuf_tabstop_push(&buf, 12);
+	printbuf_tabstop_push(&buf, 14);
+	printbuf_tabstop_push(&buf, 24);
+	printbuf_tabstop_push(&buf, 8);
+	printbuf_tabstop_push(&buf, 36);
+
+	for (unsigned d = 0; d < argc; d++) {
+		char *dev = argv[d];
+		struct bch_opts opts = bch2_opts_empty();
+		struct bch_sb_handle sb;
+
+		opt_set(opts, noexcl,	true);
+		opt_set(opts, nochanges, true);
+
+		/* falls back to a backup if the primary is bad, for the layout: */
+		int ret = bch2_read_super(dev, &opts, &sb);
+		if (ret)
+			die("Error opening %s: %s", dev, bch2_err_str(ret));
+
+		sb_copies copies = {};
+		struct bch_sb_layout *layout = &sb.sb->layout;
+
+		for (unsigned i = 0; i < layout->nr_superblocks; i++)
+			if (darray_push(&copies,
+					sb_copy_read(dev, le64_to_cpu(layout->sb_offset[i]))))
+				die("memory allocation failure");
+		bch2_free_super(&sb);
+
+		/* compare against the newest copy that could be read: */
+		struct sb_copy *ref = NULL;
+		darray_for_each(copies, c)
+			if (!c->ret && (!ref || c->seq > ref->seq))
+				ref = c;
+
+		prt_printf(&buf, "%s:\n", dev);
+		printbuf_indent_add(&buf, 2);
+		prt_printf(&buf, "Offset\tSeq\t Written\tClean\tVersion\tMembers\n");
+
+		darray_for_each(copies, c) {
+			sb_copy_to_text(&buf, c, ref);
+			nr_bad += c->ret || sb_copy_differs(c, ref);
+		}
+		printbuf_indent_sub(&buf, 2);
+
+		if (ref && !first_dev) {
+			first = *ref;
+			first_dev = dev;
+		} else if (ref &&
+		    (memcmp(&ref->uuid, &first.uuid, sizeof(ref->uuid)) ||
+		     ref->seq != first.seq ||
+		     strcmp(ref->members, first.members))) {
+			prt_printf(&buf, "  doesn't match %s", first_dev);
+			if (memcmp(&ref->uuid, &first.uuid, sizeof(ref->uuid)))
+				prt_str(&buf, ": different filesystem");
+			else
+				prt_printf(&buf, ": seq %llu, members %s",
+					   first.seq, first.members);
+			prt_newline(&buf);
+			nr_bad++;
+		}
+
+		darray_for_each(copies, c)
+			if (c->members != first.members)
+				free(c->members);
+		darray_exit(&copies);
+	}
+
+	if (nr_bad)
+		prt_printf(&buf, "%u inconsistencies, marked with *\n", nr_bad);
+	else
+		prt_str(&buf, "All superblocks consistent\n");
+	printf("%s", buf.buf);
+
+	free(first.members);
+	printbuf_exit(&buf);
+	return nr_bad ? 1 : 0;
+}
+
+/*
+ * The first version each superblock section appeared in; 0 for sections (and
+ * all feature and compat bits) that predate 1.0, which every kernel that
+ * reports a version understands
+ */
+static const unsigned sb_field_versions[BCH_SB_FIELD_NR] = {
+	[BCH_SB_FIELD_members_v2]	= bcachefs_metadata_version_rebalance_work,
+	[BCH_SB_FIELD_errors]		= bcachefs_metadata_version_rebalance_work,
+	[BCH_SB_FIELD_ext]		= bcachefs_metadata_version_member_seq,
+	[BCH_SB_FIELD_downgrade]	= bcachefs_metadata_version_member_seq,
+};
+
+static void field_version_to_text(struct printbuf *out, const char *name,
+				  unsigned since, bool known, unsigned kernel,
+				  const char *kernel_unknown)
+{
+	prt_printf(out, "  %s", name);
+	prt_tab(out);
+
+	if (!known)
+		prt_str(out, "newer than tools");
+	else if (since)
+		bch2_version_to_text(out, since);
+	else
+		prt_str(out, "< 1.0");
+	prt_tab(out);
+
+	if (!kernel)
+		prt_str(out, "unknown");
+	else if (known && since <= kernel)
+		prt_str(out, "yes");
+	else if (!known && kernel > bcachefs_metadata_version_current)
+		prt_str(out, "maybe (kernel is newer than tools)");
+	else
+		prt_str(out, kernel_unknown);
+	prt_newline(out);
+}
+
+static void field_bits_to_text(struct printbuf *out, const char * const names[],
+			       unsigned nr, u64 bits, unsigned kernel,
+			       const char *kernel_unknown)
+{
+	for (unsigned i = 0; i < 64; i++)
+		if (bits & BIT_ULL(i)) {
+			char unknown[20];
+
+			snprintf(unknown, sizeof(unknown), "bit %u", i);
+			field_version_to_text(out, i < nr ? names[i] : unknown,
+					      0, i < nr, kernel, kernel_unknown);
+		}
+}
+
+/*
+ * For decoding mount failures: which version introduced each section and
+ * feature bit the superblock has, and whether the running kernel knows it
+ */
+static void show_super_field_versions(struct printbuf *out, struct bch_sb *sb)
+{
+	unsigned version = le16_to_cpu(sb->version);
+	unsigned kernel = kernel_metadata_version();
+
+	printbuf_tabstop_push(out, 36);
+	printbuf_tabstop_push(out, 20);
+
+	prt_str(out, "Version:");
+	prt_tab(out);
+	bch2_version_to_text(out, version);
+	prt_newline(out);
+
+	prt_str(out, "Kernel version:");
+	prt_tab(out);
+	if (kernel)
+		bch2_version_to_text(out, kernel);
+	else
+		prt_str(out, "unknown (module not loaded, or too old to report it)");
+	prt_newline(out);
+
+	if (kernel && BCH_VERSION_MAJOR(version) > BCH_VERSION_MAJOR(kernel))
+		prt_str(out, "The kernel can't mount this version: it must be upgraded\n");
+	else if (kernel && version > kernel)
+		prt_str(out, "The kernel will downgrade this version when mounted read-write\n");
+	prt_newline(out);
+
+	prt_str(out, "Sections:");
+	prt_tab(out);
+	prt_str(out, "since");
+	prt_tab(out);
+	prt_str(out, "kernel");
+	prt_newline(out);
+
+	vstruct_for_each(sb, f) {
+		unsigned type = le32_to_cpu(f->type);
+		char unknown[20];
+
+		snprintf(unknown, sizeof(unknown), "section %u", type);
+		field_version_to_text(out, type < BCH_SB_FIELD_NR ? bch2_sb_fields[type] : unknown,
+				      type < BCH_SB_FIELD_NR ? sb_field_versions[type] : 0,
+				      type < BCH_SB_FIELD_NR, kernel, "no: ignored");
+	}
+	prt_newline(out);
+
+	/* the kernel refuses to mount with unknown features, but ignores compat bits: */
+	prt_str(out, "Features:");
+	prt_newline(out);
+	field_bits_to_text(out, bch2_sb_features, BCH_FEATURE_NR,
+			   le64_to_cpu(sb->features[0]), kernel,
+			   "no: mount fails with \"incompatible features\"");
+	prt_newline(out);
+
+	prt_str(out, "Compat:");
+	prt_newline(out);
+	field_bits_to_text(out, bch2_sb_compat, BCH_COMPAT_NR,
+			   le64_to_cpu(sb->compat[0]), kernel, "no: ignored");
+}
+
 static void show_super_usage(void)
 {
 	puts("bcachefs show-super \n"
@@ -311,6 +698,10 @@ static void show_super_usage(void)
 	     "  -f, --fields=(fields)       list of sections to print\n"
 	     "      --field-only=fiel)      print superblock section only, no header\n"
 	     "  -l, --layout                print superblock layout\n"
+	     "      --compare-backups       compare the primary and backup superblocks\n"
+	     "                              on each device given\n"
+	     "      --field-versions        show the version that introduced each section\n"
+	     "                              and feature bit, and if the kernel knows it\n"
 	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
@@ -322,6 +713,8 @@ int cmd_show_super(int argc, char *argv[])
 		{ "fields",			1, NULL, 'f' },
 		{ "field-only",			1, NULL, 'F' },
 		{ "layout",			0, NULL, 'l' },
+		{ "compare-backups",		0, NULL, 'c' },
+		{ "field-versions",		0, NULL, 'V' },
 		{ "help",			0, NULL, 'h' },
 		{ NULL }
 	};
@@ -329,6 +722,8 @@ int cmd_show_super(int argc, char *argv[])
 	int field_only = -1;
 	bool print_layout = false;
 	bool print_default_fields = true;
+	bool compare_backups = false;
+	bool field_versions = false;
 	int opt;
 
 	while ((opt = getopt_long(argc, argv, "f:lh", longopts, NULL)) != -1)
@@ -348,12 +743,24 @@ int cmd_show_super(int argc, char *argv[])
 		case 'l':
 			print_layout = true;
 			break;
+		case 'c':
+			compare_backups = true;
+			break;
+		case 'V':
+			field_versions = true;
+			break;
 		case 'h':
 			show_super_usage();
 			break;
 		}
 	args_shift(optind);
 
+	if (compare_backups) {
+		if (!argc)
+			die("please supply a device");
+		return show_super_compare_backups(argc, argv);
+	}
+
 	char *dev = arg_pop();
 	if (!dev)
 		die("please supply a device");
@@ -379,9 +786,11 @@ int cmd_show_super(int argc, char *argv[])
 
 	struct printbuf buf = PRINTBUF;
 
-	buf.human_readable_units = true;
+	tools_printbuf_units(&buf, true);
 
-	if (field_only >= 0) {
+	if (field_versions) {
+		show_super_field_versions(&buf, sb.sb);
+	} else if (field_only >= 0) {
 		struct bch_sb_field *f = bch2_sb_field_get_id(sb.sb, field_only);
 
 		if (f)
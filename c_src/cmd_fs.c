@@ -1,3 +1,5 @@
+#include <dirent.h>
+#include <fcntl.h>
 #include <getopt.h>
 #include <stdio.h>
 #include <sys/ioctl.h>
@@ -11,6 +13,7 @@
 #include "libbcachefs/buckets.h"
 #include "libbcachefs/opts.h"
 #include "libbcachefs/super-io.h"
+#include "libbcachefs/time_stats.h"
 
 #include "cmds.h"
 #include "libbcachefs.h"
@@ -26,14 +29,14 @@ static void __dev_usage_type_to_text(struct printbuf *out,
 	prt_char(out, ':');
 	prt_tab(out);
 
-	prt_units_u64(out, sectors << 9);
+	tools_prt_units_u64(out, sectors << 9);
 	prt_tab_rjust(out);
 
 	prt_printf(out, "%llu", buckets);
 	prt_tab_rjust(out);
 
 	if (frag) {
-		prt_units_u64(out, frag << 9);
+		tools_prt_units_u64(out, frag << 9);
 		prt_tab_rjust(out);
 	}
 	prt_newline(out);
@@ -77,6 +80,11 @@ static void dev_usage_to_text(struct printbuf *out,
 	prt_str(out, bch2_member_states[u->state]);
 	prt_tab_rjust(out);
 
+	if (u->state != BCH_MEMBER_STATE_rw)
+		printbuf_color_last(out, strlen(bch2_member_states[u->state]),
+				    u->state == BCH_MEMBER_STATE_failed
+				    ? SEVERITY_ERROR : SEVERITY_WARNING);
+
 	prt_newline(out);
 
 	printbuf_indent_add(out, 2);
@@ -99,7 +107,7 @@ static void dev_usage_to_text(struct printbuf *out,
 	prt_str(out, "capacity:");
 	prt_tab(out);
 
-	prt_units_u64(out, (u->nr_buckets * u->bucket_size) << 9);
+	tools_prt_units_u64(out, (u->nr_buckets * u->bucket_size) << 9);
 	prt_tab_rjust(out);
 	prt_printf(out, "%llu", u->nr_buckets);
 	prt_tab_rjust(out);
@@ -170,7 +178,7 @@ static void replicas_usage_to_text(struct printbuf *out,
 	prt_printf(out, "%s ", devs);
 	prt_tab(out);
 
-	prt_units_u64(out, r->sectors << 9);
+	tools_prt_units_u64(out, r->sectors << 9);
 	prt_tab_rjust(out);
 	prt_newline(out);
 }
@@ -181,7 +189,305 @@ static void replicas_usage_to_text(struct printbuf *out,
 	     _r = replicas_usage_next(_r),				\
 	     BUG_ON((void *) _r > (void *) (_u)->replicas + (_u)->replica_entries_bytes))
 
-static void fs_usage_to_text(struct printbuf *out, const char *path)
+/*
+ * Usage summed over devices with the same label, at each level of the label
+ * hierarchy: devices labelled ssd.fast and ssd.slow are also counted in ssd
+ */
+struct label_usage {
+	char			*label;
+	unsigned		nr_devs;
+	u64			sectors[BCH_DATA_NR];
+	u64			capacity;
+};
+typedef DARRAY(struct label_usage) label_usages;
+
+/* Sort so that children come directly after their parent */
+static int label_cmp(const char *l, const char *r)
+{
+	for (; *l && *l == *r; l++, r++)
+		;
+
+	return (*l == '.' ? 1 : *l ? (unsigned char) *l + 1 : 0) -
+	       (*r == '.' ? 1 : *r ? (unsigned char) *r + 1 : 0);
+}
+
+static struct label_usage *label_usage_get(label_usages *labels,
+					   const char *label, size_t len)
+{
+	darray_for_each(*labels, l)
+		if (strlen(l->label) == len && !memcmp(l->label, label, len))
+			return l;
+
+	struct label_usage n = { .label = strndup(label, len) };
+	unsigned i = 0;
+
+	while (i < labels->nr && label_cmp(labels->data[i].label, n.label) < 0)
+		i++;
+
+	if (darray_insert_item(labels, i, n))
+		die("memory allocation failure");
+	return &labels->data[i];
+}
+
+static void label_usage_add(struct label_usage *l, struct bch_ioctl_dev_usage_v2 *u)
+{
+	l->nr_devs++;
+	l->capacity += u->nr_buckets * u->bucket_size;
+
+	for (unsigned i = 0; i < min_t(unsigned, u->nr_data_types, BCH_DATA_NR); i++)
+		l->sectors[i] += i == BCH_DATA_free || i == BCH_DATA_need_discard
+			? u->d[i].buckets * u->bucket_size
+			: u->d[i].sectors;
+}
+
+static const enum bch_data_type label_usage_types[] = {
+	BCH_DATA_journal,
+	BCH_DATA_btree,
+	BCH_DATA_user,
+	BCH_DATA_cached,
+	BCH_DATA_parity,
+	BCH_DATA_free,
+};
+
+static void label_usage_to_text(struct printbuf *out,
+				struct bchfs_handle fs,
+				dev_names *dev_names)
+{
+	label_usages labels = {};
+
+	darray_for_each(*dev_names, dev) {
+		if (!dev->label || !*dev->label)
+			continue;
+
+		struct bch_ioctl_dev_usage_v2 *u = bchu_dev_usage(fs, dev->idx);
+
+		for (const char *p = strchr(dev->label, '.'); p; p = strchr(p + 1, '.'))
+			label_usage_add(label_usage_get(&labels, dev->label,
+							p - dev->label), u);
+		label_usage_add(label_usage_get(&labels, dev->label,
+						strlen(dev->label)), u);
+		free(u);
+	}
+
+	if (!labels.nr)
+		return;
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 20);
+	for (unsigned i = 0; i <= ARRAY_SIZE(label_usage_types); i++)
+		printbuf_tabstop_push(out, 12);
+
+	prt_newline(out);
+	prt_str(out, "By label:");
+	prt_tab(out);
+	for (unsigned i = 0; i < ARRAY_SIZE(label_usage_types); i++) {
+		bch2_prt_data_type(out, label_usage_types[i]);
+		prt_tab_rjust(out);
+	}
+	prt_str(out, "capacity");
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	darray_for_each(labels, l) {
+		const char *name = l->label;
+		unsigned depth = 0;
+
+		for (const char *p = l->label; (p = strchr(p, '.')); p++) {
+			name = p + 1;
+			depth++;
+		}
+
+		prt_printf(out, "%*s%s (%u):", depth * 2, "", name, l->nr_devs);
+		prt_tab(out);
+
+		for (unsigned i = 0; i < ARRAY_SIZE(label_usage_types); i++) {
+			tools_prt_units_u64(out, l->sectors[label_usage_types[i]] << 9);
+			prt_tab_rjust(out);
+		}
+		tools_prt_units_u64(out, l->capacity << 9);
+		prt_tab_rjust(out);
+		prt_newline(out);
+	}
+
+	darray_for_each(labels, l)
+		free(l->label);
+	darray_exit(&labels);
+}
+
+/*
+ * fs usage --replicas: one row per replica set, i.e. per distinct set of
+ * devices a data type is stored on
+ */
+enum replicas_sort {
+	REPLICAS_SORT_size,
+	REPLICAS_SORT_devices,
+	REPLICAS_SORT_type,
+};
+
+static const char * const replicas_sort_strs[] = {
+	"size",
+	"devices",
+	"type",
+	NULL
+};
+
+struct fs_usage_opts {
+	bool			replicas;
+	enum replicas_sort	sort;
+	const char		*dev;
+};
+
+typedef DARRAY(const struct bch_replicas_usage *) replicas_rows;
+
+static int replicas_size_cmp(const void *_l, const void *_r)
+{
+	const struct bch_replicas_usage *l = *((const struct bch_replicas_usage **) _l);
+	const struct bch_replicas_usage *r = *((const struct bch_replicas_usage **) _r);
+
+	return cmp_int(r->sectors, l->sectors);
+}
+
+static int replicas_devs_cmp(const void *_l, const void *_r)
+{
+	const struct bch_replicas_usage *l = *((const struct bch_replicas_usage **) _l);
+	const struct bch_replicas_usage *r = *((const struct bch_replicas_usage **) _r);
+
+	for (unsigned i = 0; i < min(l->r.nr_devs, r->r.nr_devs); i++)
+		if (l->r.devs[i] != r->r.devs[i])
+			return cmp_int(l->r.devs[i], r->r.devs[i]);
+
+	return  cmp_int(l->r.nr_devs, r->r.nr_devs) ?:
+		cmp_int(l->r.data_type, r->r.data_type) ?:
+		replicas_size_cmp(_l, _r);
+}
+
+static int replicas_type_cmp(const void *_l, const void *_r)
+{
+	const struct bch_replicas_usage *l = *((const struct bch_replicas_usage **) _l);
+	const struct bch_replicas_usage *r = *((const struct bch_replicas_usage **) _r);
+
+	return  cmp_int(l->r.data_type, r->r.data_type) ?:
+		cmp_int((l->r.nr_required > 1), (r->r.nr_required > 1)) ?:
+		cmp_int(l->r.nr_devs, r->r.nr_devs) ?:
+		replicas_size_cmp(_l, _r);
+}
+
+static bool replicas_has_dev(const struct bch_replicas_usage *r, unsigned dev)
+{
+	for (unsigned i = 0; i < r->r.nr_devs; i++)
+		if (r->r.devs[i] == dev)
+			return true;
+	return false;
+}
+
+/* A device index, a device name (sda or /dev/sda) or a device label: */
+static unsigned replicas_dev_parse(dev_names *dev_names, const char *str)
+{
+	const char *name = strrchr(str, '/') ? strrchr(str, '/') + 1 : str;
+	unsigned idx;
+
+	if (!kstrtouint(str, 10, &idx)) {
+		if (!dev_idx_to_name(dev_names, idx))
+			die("no device %u in filesystem", idx);
+		return idx;
+	}
+
+	darray_for_each(*dev_names, dev)
+		if ((dev->dev && !strcmp(dev->dev, name)) ||
+		    (dev->label && !strcmp(dev->label, str)))
+			return dev->idx;
+
+	die("no device %s in filesystem", str);
+}
+
+static void replicas_table_to_text(struct printbuf *out,
+				   struct bch_ioctl_fs_usage *u,
+				   dev_names *dev_names,
+				   struct fs_usage_opts *opts)
+{
+	static int (*cmps[])(const void *, const void *) = {
+		[REPLICAS_SORT_size]	= replicas_size_cmp,
+		[REPLICAS_SORT_devices]	= replicas_devs_cmp,
+		[REPLICAS_SORT_type]	= replicas_type_cmp,
+	};
+	replicas_rows rows = {};
+	struct bch_replicas_usage *r;
+	u64 total = 0, shown = 0;
+	int dev = opts->dev ? replicas_dev_parse(dev_names, opts->dev) : -1;
+
+	for_each_usage_replica(u, r) {
+		if (!r->sectors)
+			continue;
+
+		total += r->sectors;
+
+		if (dev >= 0 && !replicas_has_dev(r, dev))
+			continue;
+
+		if (darray_push(&rows, r))
+			die("memory allocation failure");
+		shown += r->sectors;
+	}
+
+	sort(rows.data, rows.nr, sizeof(rows.data[0]), cmps[opts->sort], NULL);
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 16);
+	printbuf_tabstop_push(out, 8);
+	printbuf_tabstop_push(out, 10);
+	printbuf_tabstop_push(out, 6);
+	printbuf_tabstop_push(out, 12);
+	printbuf_tabstop_push(out, 14);
+	printbuf_tabstop_push(out, 8);
+
+	prt_printf(out, "Data type\tCopies\rRequired\rEC\rDurability\rSize\r%%\r  Devices\n");
+
+	darray_for_each(rows, i) {
+		const struct bch_replicas_usage *r = *i;
+		bool ec = r->r.nr_required > 1;
+		unsigned durability = 0;
+
+		for (unsigned j = 0; j < r->r.nr_devs; j++) {
+			struct dev_name *d = dev_idx_to_name(dev_names, r->r.devs[j]);
+
+			durability += d ? d->durability : 1;
+		}
+
+		bch2_prt_data_type(out, r->r.data_type);
+		prt_tab(out);
+		prt_printf(out, "%u\r%u\r%s\r%u\r", r->r.nr_devs, r->r.nr_required,
+			   ec ? "yes" : "no", durability);
+		tools_prt_units_u64(out, r->sectors << 9);
+		prt_tab_rjust(out);
+		prt_printf(out, "%llu.%llu\r",
+			   div64_u64(r->sectors * 100, total),
+			   div64_u64(r->sectors * 1000, total) % 10);
+
+		for (unsigned j = 0; j < r->r.nr_devs; j++) {
+			struct dev_name *d = dev_idx_to_name(dev_names, r->r.devs[j]);
+
+			prt_str(out, j ? " " : "  ");
+			if (d && d->dev)
+				prt_printf(out, "%s(%u)", d->dev, d->idx);
+			else
+				prt_printf(out, "%u", r->r.devs[j]);
+		}
+		prt_newline(out);
+	}
+
+	if (dev >= 0) {
+		prt_printf(out, "replica sets on device %u:\t", dev);
+		tools_prt_units_u64(out, shown << 9);
+		prt_printf(out, " of ");
+		tools_prt_units_u64(out, total << 9);
+		prt_newline(out);
+	}
+
+	darray_exit(&rows);
+}
+
+static void fs_usage_to_text(struct printbuf *out, const char *path,
+			     struct fs_usage_opts *opts)
 {
 	unsigned i;
 
@@ -201,24 +507,30 @@ static void fs_usage_to_text(struct printbuf *out, const char *path)
 
 	prt_str(out, "Size:");
 	prt_tab(out);
-	prt_units_u64(out, u->capacity << 9);
+	tools_prt_units_u64(out, u->capacity << 9);
 	prt_tab_rjust(out);
 	prt_newline(out);
 
 	prt_str(out, "Used:");
 	prt_tab(out);
-	prt_units_u64(out, u->used << 9);
+	tools_prt_units_u64(out, u->used << 9);
 	prt_tab_rjust(out);
 	prt_newline(out);
 
 	prt_str(out, "Online reserved:");
 	prt_tab(out);
-	prt_units_u64(out, u->online_reserved << 9);
+	tools_prt_units_u64(out, u->online_reserved << 9);
 	prt_tab_rjust(out);
 	prt_newline(out);
 
 	prt_newline(out);
 
+	if (opts->replicas) {
+		replicas_table_to_text(out, u, &dev_names, opts);
+		free(u);
+		goto out;
+	}
+
 	printbuf_tabstops_reset(out);
 
 	printbuf_tabstop_push(out, 16);
@@ -248,7 +560,7 @@ static void fs_usage_to_text(struct printbuf *out, const char *path)
 		prt_printf(out, "%u/%u ", 1, i);
 		prt_tab(out);
 		prt_str(out, "[] ");
-		prt_units_u64(out, u->persistent_reserved[i] << 9);
+		tools_prt_units_u64(out, u->persistent_reserved[i] << 9);
 		prt_tab_rjust(out);
 		prt_newline(out);
 	}
@@ -275,6 +587,8 @@ static void fs_usage_to_text(struct printbuf *out, const char *path)
 
 	free(u);
 
+	label_usage_to_text(out, fs, &dev_names);
+
 	sort(dev_names.data, dev_names.nr,
 	     sizeof(dev_names.data[0]), dev_by_label_cmp, NULL);
 
@@ -286,7 +600,7 @@ static void fs_usage_to_text(struct printbuf *out, const char *path)
 
 	darray_for_each(dev_names, dev)
 		dev_usage_to_text(out, fs, dev);
-
+out:
 	darray_for_each(dev_names, dev) {
 		free(dev->dev);
 		free(dev->label);
@@ -301,8 +615,19 @@ static void fs_usage_usage(void)
 	puts("bcachefs fs usage - display detailed filesystem usage\n"
 	     "Usage: bcachefs fs usage [OPTION]... <mountpoint>\n"
 	     "\n"
+	     "If devices have labels, usage by data type is also summed by label, at each\n"
+	     "level of the label hierarchy (e.g. ssd for ssd.fast and ssd.slow).\n"
+	     "\n"
 	     "Options:\n"
 	     "  -h, --human-readable              Human readable units\n"
+	     "  -r, --replicas                    Show a table of usage by replica set: the\n"
+	     "                                    devices, number of copies and erasure coding\n"
+	     "                                    of each, instead of the usage summary\n"
+	     "  -s, --sort=KEY                    Sort replica sets by size (default), devices\n"
+	     "                                    or type\n"
+	     "  -d, --device=DEV                  Only show replica sets that include DEV, a\n"
+	     "                                    device index, name or label; implies -r\n"
+	     "      --color=WHEN                  Color output: auto, always or never\n"
 	     "  -H, --help                        Display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
@@ -312,19 +637,40 @@ int cmd_fs_usage(int argc, char *argv[])
 	static const struct option longopts[] = {
 		{ "help",		no_argument,		NULL, 'H' },
 		{ "human-readable",     no_argument,            NULL, 'h' },
+		{ "replicas",		no_argument,		NULL, 'r' },
+		{ "sort",		required_argument,	NULL, 's' },
+		{ "device",		required_argument,	NULL, 'd' },
+		{ "color",		required_argument,	NULL, 'C' },
 		{ NULL }
 	};
+	struct fs_usage_opts opts = { .sort = REPLICAS_SORT_size };
 	bool human_readable = false;
 	struct printbuf buf = PRINTBUF;
 	char *fs;
-	int opt;
+	int opt, ret;
 
-	while ((opt = getopt_long(argc, argv, "h",
+	while ((opt = getopt_long(argc, argv, "hrs:d:",
 				  longopts, NULL)) != -1)
 		switch (opt) {
 		case 'h':
 			human_readable = true;
 			break;
+		case 'r':
+			opts.replicas = true;
+			break;
+		case 's':
+			ret = match_string(replicas_sort_strs, -1, optarg);
+			if (ret < 0)
+				die("invalid sort key %s", optarg);
+			opts.sort = ret;
+			break;
+		case 'd':
+			opts.dev = optarg;
+			opts.replicas = true;
+			break;
+		case 'C':
+			color_when_parse(optarg);
+			break;
 		case 'H':
 			fs_usage_usage();
 			exit(EXIT_SUCCESS);
@@ -336,14 +682,14 @@ int cmd_fs_usage(int argc, char *argv[])
 
 	if (!argc) {
 		printbuf_reset(&buf);
-		buf.human_readable_units = human_readable;
-		fs_usage_to_text(&buf, ".");
+		tools_printbuf_units(&buf, human_readable);
+		fs_usage_to_text(&buf, ".", &opts);
 		printf("%s", buf.buf);
 	} else {
 		while ((fs = arg_pop())) {
 			printbuf_reset(&buf);
-			buf.human_readable_units = human_readable;
-			fs_usage_to_text(&buf, fs);
+			tools_printbuf_units(&buf, human_readable);
+			fs_usage_to_text(&buf, fs, &opts);
 			printf("%s", buf.buf);
 		}
 	}
@@ -351,3 +697,882 @@ int cmd_fs_usage(int argc, char *argv[])
 	printbuf_exit(&buf);
 	return 0;
 }
+
+/*
+ * fs accounting: this on disk format version predates the accounting btree, so
+ * we report what the kernel gives us - usage by replica set from
+ * BCH_IOCTL_FS_USAGE, and compression stats from sysfs
+ */
+
+static void json_str(const char *s)
+{
+	putchar('"');
+	for (; *s; s++)
+		if (*s == '"' || *s == '\\')
+			printf("\\%c", *s);
+		else if ((unsigned char) *s < 0x20)
+			printf("\\u%04x", *s);
+		else
+			putchar(*s);
+	putchar('"');
+}
+
+static void replicas_devs_json(const struct bch_replicas_usage *r,
+			       dev_names *dev_names)
+{
+	putchar('[');
+	for (unsigned i = 0; i < r->r.nr_devs; i++) {
+		struct dev_name *dev = dev_idx_to_name(dev_names, r->r.devs[i]);
+
+		printf("%s{ \"idx\": %u, \"dev\": ", i ? ", " : "", r->r.devs[i]);
+		if (dev && dev->dev)
+			json_str(dev->dev);
+		else
+			printf("null");
+		putchar('}');
+	}
+	putchar(']');
+}
+
+static void fs_accounting_json(struct bchfs_handle fs,
+			       struct bch_ioctl_fs_usage *u,
+			       dev_names *dev_names,
+			       char *compression)
+{
+	struct bch_replicas_usage *r;
+	bool first = true;
+
+	printf("{\n  \"uuid\": \"");
+	for (unsigned i = 0; i < sizeof(fs.uuid.b); i++)
+		printf("%02x%s", fs.uuid.b[i],
+		       i == 3 || i == 5 || i == 7 || i == 9 ? "-" : "");
+	printf("\",\n");
+
+	printf("  \"capacity\": %llu,\n", u->capacity << 9);
+	printf("  \"used\": %llu,\n", u->used << 9);
+	printf("  \"online_reserved\": %llu,\n", u->online_reserved << 9);
+
+	printf("  \"persistent_reserved\": [");
+	for (unsigned i = 0; i < BCH_REPLICAS_MAX; i++)
+		printf("%s%llu", i ? ", " : "", u->persistent_reserved[i] << 9);
+	printf("],\n");
+
+	printf("  \"replicas\": [");
+	for_each_usage_replica(u, r) {
+		if (!r->sectors)
+			continue;
+
+		printf("%s\n    { \"data_type\": ", first ? "" : ",");
+		json_str(bch2_data_type_str(r->r.data_type));
+		printf(", \"nr_required\": %u, \"devs\": ", r->r.nr_required);
+		replicas_devs_json(r, dev_names);
+		printf(", \"bytes\": %llu }", r->sectors << 9);
+		first = false;
+	}
+	printf("\n  ]");
+
+	if (compression) {
+		/* sysfs gives us human readable units; pass them through as is */
+		char *line, *p = compression;
+
+		first = true;
+		printf(",\n  \"compression\": [");
+
+		strsep(&p, "\n"); /* header */
+		while ((line = strsep(&p, "\n"))) {
+			/* columns are padded with spaces; sizes are "1.23 MiB": */
+			char type[32], v[3][2][16], f[3][33];
+
+			if (sscanf(line, "%31s %15s %15s %15s %15s %15s %15s", type,
+				   v[0][0], v[0][1], v[1][0], v[1][1],
+				   v[2][0], v[2][1]) != 7)
+				continue;
+
+			for (unsigned i = 0; i < ARRAY_SIZE(f); i++)
+				sprintf(f[i], "%s %s", v[i][0], v[i][1]);
+
+			printf("%s\n    { \"type\": ", first ? "" : ",");
+			json_str(type);
+			printf(", \"compressed\": ");
+			json_str(f[0]);
+			printf(", \"uncompressed\": ");
+			json_str(f[1]);
+			printf(", \"average_extent_size\": ");
+			json_str(f[2]);
+			printf(" }");
+			first = false;
+		}
+		printf("\n  ]");
+	}
+
+	printf("\n}\n");
+}
+
+static void fs_accounting_to_text(struct printbuf *out,
+				  struct bch_ioctl_fs_usage *u,
+				  dev_names *dev_names,
+				  const char *compression)
+{
+	struct bch_replicas_usage *r;
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 20);
+	printbuf_tabstop_push(out, 16);
+
+	prt_str(out, "capacity:");
+	prt_tab(out);
+	tools_prt_units_u64(out, u->capacity << 9);
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	prt_str(out, "used:");
+	prt_tab(out);
+	tools_prt_units_u64(out, u->used << 9);
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	prt_str(out, "online reserved:");
+	prt_tab(out);
+	tools_prt_units_u64(out, u->online_reserved << 9);
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	for (unsigned i = 0; i < BCH_REPLICAS_MAX; i++) {
+		if (!u->persistent_reserved[i])
+			continue;
+
+		prt_printf(out, "reserved (%ux):", i + 1);
+		prt_tab(out);
+		tools_prt_units_u64(out, u->persistent_reserved[i] << 9);
+		prt_tab_rjust(out);
+		prt_newline(out);
+	}
+
+	prt_newline(out);
+	prt_str(out, "By replica set:");
+	prt_newline(out);
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 16);
+	printbuf_tabstop_push(out, 16);
+	printbuf_tabstop_push(out, 14);
+	printbuf_tabstop_push(out, 14);
+	printbuf_tabstop_push(out, 14);
+
+	prt_printf(out, "Data type\tRequired/total\tDurability\tDevices");
+	prt_newline(out);
+
+	for_each_usage_replica(u, r)
+		replicas_usage_to_text(out, r, dev_names);
+
+	if (compression) {
+		prt_newline(out);
+		prt_str(out, "Compression:");
+		prt_newline(out);
+		prt_str(out, compression);
+		prt_newline(out);
+	}
+}
+
+static void fs_accounting_usage(void)
+{
+	puts("bcachefs fs accounting - report filesystem accounting\n"
+	     "Usage: bcachefs fs accounting [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Options:\n"
+	     "  -c, --compression                 Include compression stats (walks the extents btree)\n"
+	     "  -j, --json                        JSON output; sizes are in bytes\n"
+	     "  -h, --human-readable              Human readable units\n"
+	     "  -H, --help                        Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_fs_accounting(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "compression",	no_argument,		NULL, 'c' },
+		{ "json",		no_argument,		NULL, 'j' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	bool compression = false, json = false;
+	struct printbuf buf = PRINTBUF;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "cjh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 'c':
+			compression = true;
+			break;
+		case 'j':
+			json = true;
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'H':
+			fs_accounting_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			fs_accounting_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	struct bchfs_handle fs = bcache_fs_open(arg_pop() ?: ".");
+	dev_names dev_names = bchu_fs_get_devices(fs);
+	struct bch_ioctl_fs_usage *u = bchu_fs_usage(fs);
+	char *compression_stats = compression
+		? read_file_str(fs.sysfs_fd, "compression_stats")
+		: NULL;
+
+	if (json) {
+		fs_accounting_json(fs, u, &dev_names, compression_stats);
+	} else {
+		prt_str(&buf, "Filesystem: ");
+		pr_uuid(&buf, fs.uuid.b);
+		prt_newline(&buf);
+
+		fs_accounting_to_text(&buf, u, &dev_names, compression_stats);
+		printf("%s", buf.buf);
+	}
+
+	darray_for_each(dev_names, dev) {
+		free(dev->dev);
+		free(dev->label);
+	}
+	darray_exit(&dev_names);
+	free(compression_stats);
+	free(u);
+	bcache_fs_close(fs);
+	printbuf_exit(&buf);
+	return 0;
+}
+
+struct resize_dev {
+	unsigned		idx;
+	char			*name;
+	unsigned		durability;
+	u64			bucket_size;
+	u64			first_bucket;
+	u64			nbuckets;
+	u64			new_nbuckets;
+};
+
+typedef DARRAY(struct resize_dev) resize_devs;
+
+/* "max", "80%" (of the block device), "+10G" or "10G"; returns sectors */
+static u64 resize_parse_size(const char *spec, u64 cur, u64 dev_size)
+{
+	size_t len = strlen(spec);
+	u64 v;
+
+	if (!strcmp(spec, "max"))
+		return dev_size;
+
+	if (len && spec[len - 1] == '%') {
+		char *pct_str = strndup(spec, len - 1);
+		unsigned pct;
+
+		if (kstrtouint(pct_str, 10, &pct) || !pct || pct > 100)
+			die("invalid percentage %s", spec);
+		free(pct_str);
+
+		return div_u64(dev_size * pct, 100);
+	}
+
+	if (spec[0] == '+') {
+		if (bch2_strtoull_h(spec + 1, &v))
+			die("invalid size %s", spec);
+		return cur + (v >> 9);
+	}
+
+	if (bch2_strtoull_h(spec, &v))
+		die("invalid size %s", spec);
+	return v >> 9;
+}
+
+/* by index, name (sda) or path */
+static struct resize_dev *resize_dev_find(resize_devs *devs, const char *dev_str)
+{
+	unsigned idx;
+
+	if (!kstrtouint(dev_str, 10, &idx)) {
+		darray_for_each(*devs, d)
+			if (d->idx == idx)
+				return d;
+		return NULL;
+	}
+
+	char *path = dev_str[0] == '/' ? realpath(dev_str, NULL) : NULL;
+	if (dev_str[0] == '/' && !path)
+		die("error resolving %s: %m", dev_str);
+
+	const char *name = path ? basename(path) : dev_str;
+	struct resize_dev *ret = NULL;
+
+	darray_for_each(*devs, d)
+		if (d->name && !strcmp(d->name, name))
+			ret = d;
+
+	free(path);
+	return ret;
+}
+
+static void resize_dev_set(struct resize_dev *d, const char *spec)
+{
+	char *dev_path = mprintf("/dev/%s", d->name);
+	int dev_fd = xopen(dev_path, O_RDONLY);
+	u64 dev_size = get_size(dev_fd) >> 9;
+	close(dev_fd);
+
+	u64 size = resize_parse_size(spec, d->nbuckets * d->bucket_size, dev_size);
+	if (size > dev_size)
+		die("%s: %s is larger than the device", dev_path, spec);
+
+	d->new_nbuckets = size / d->bucket_size;
+	if (d->new_nbuckets < d->nbuckets)
+		die("%s: shrinking not supported yet", dev_path);
+	free(dev_path);
+}
+
+/* Capacity, counting durability: what's available for data_replicas copies */
+static u64 resize_capacity(resize_devs *devs, bool new)
+{
+	u64 ret = 0;
+
+	darray_for_each(*devs, d)
+		ret += ((new ? d->new_nbuckets : d->nbuckets) - d->first_bucket) *
+			d->bucket_size * d->durability;
+	return ret;
+}
+
+static void fs_resize_to_text(struct printbuf *out, resize_devs *devs,
+			      unsigned replicas)
+{
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 16);
+	printbuf_tabstop_push(out, 14);
+	printbuf_tabstop_push(out, 14);
+
+	prt_str(out, "Device");
+	prt_tab(out);
+	prt_str(out, "Size");
+	prt_tab_rjust(out);
+	prt_str(out, "New size");
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	darray_for_each(*devs, d) {
+		prt_printf(out, "%s (%u)", d->name ?: "(offline)", d->idx);
+		prt_tab(out);
+		tools_prt_units_u64(out, d->nbuckets * d->bucket_size << 9);
+		prt_tab_rjust(out);
+		if (d->new_nbuckets != d->nbuckets)
+			tools_prt_units_u64(out, d->new_nbuckets * d->bucket_size << 9);
+		else
+			prt_str(out, "unchanged");
+		prt_tab_rjust(out);
+		prt_newline(out);
+	}
+
+	u64 before = resize_capacity(devs, false);
+	u64 after = resize_capacity(devs, true);
+
+	prt_newline(out);
+	prt_str(out, "Capacity:");
+	prt_tab(out);
+	tools_prt_units_u64(out, before << 9);
+	prt_tab_rjust(out);
+	tools_prt_units_u64(out, after << 9);
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	prt_printf(out, "Usable (%ux):", replicas);
+	prt_tab(out);
+	tools_prt_units_u64(out, div_u64(before, replicas) << 9);
+	prt_tab_rjust(out);
+	tools_prt_units_u64(out, div_u64(after, replicas) << 9);
+	prt_tab_rjust(out);
+	prt_newline(out);
+}
+
+static void fs_resize_usage(void)
+{
+	puts("bcachefs fs resize - resize the devices of a mounted filesystem\n"
+	     "Usage: bcachefs fs resize [OPTION]... <mountpoint> [<device>=<size>]...\n"
+	     "\n"
+	     "Devices may be given by index, name or path; size may be absolute (20G),\n"
+	     "relative (+10G), a percentage of the block device (80%), or max.\n"
+	     "With no devices, every device is resized to max.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -n, --dry-run                     Only show what would be done\n"
+	     "  -h, --human-readable              Human readable units\n"
+	     "  -H, --help                        Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_fs_resize(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct printbuf buf = PRINTBUF;
+	bool dry_run = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "nh",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 'n':
+			dry_run = true;
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'H':
+			fs_resize_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			fs_resize_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	struct bchfs_handle fs = bcache_fs_open(fs_path);
+	struct bch_sb *sb = bchu_read_super(fs, -1);
+	unsigned replicas = max_t(u64, 1, read_file_u64(fs.sysfs_fd, "options/data_replicas"));
+	dev_names dev_names = bchu_fs_get_devices(fs);
+	resize_devs devs = {};
+
+	darray_for_each(dev_names, n) {
+		if (n->idx >= sb->nr_devices)
+			die("error reading superblock: dev idx >= sb->nr_devices");
+
+		struct bch_member m = bch2_sb_member_get(sb, n->idx);
+		struct resize_dev d = {
+			.idx		= n->idx,
+			.name		= n->dev,
+			.durability	= n->durability,
+			.bucket_size	= le16_to_cpu(m.bucket_size),
+			.first_bucket	= le16_to_cpu(m.first_bucket),
+			.nbuckets	= le64_to_cpu(m.nbuckets),
+		};
+		d.new_nbuckets = d.nbuckets;
+
+		if (darray_push(&devs, d))
+			die("memory allocation failure");
+	}
+
+	if (!argc)
+		darray_for_each(devs, d)
+			if (d->name)
+				resize_dev_set(d, "max");
+
+	for (unsigned i = 0; i < argc; i++) {
+		char *spec = strrchr(argv[i], '=');
+		if (!spec)
+			die("expected <device>=<size>: %s", argv[i]);
+		*spec++ = '\0';
+
+		struct resize_dev *d = resize_dev_find(&devs, argv[i]);
+		if (!d)
+			die("%s is not a member of %s", argv[i], fs_path);
+		if (!d->name)
+			die("device %u is offline", d->idx);
+
+		resize_dev_set(d, spec);
+	}
+
+	fs_resize_to_text(&buf, &devs, replicas);
+	printf("%s", buf.buf);
+
+	if (!dry_run)
+		darray_for_each(devs, d)
+			if (d->new_nbuckets != d->nbuckets) {
+				printf("resizing %s to %llu buckets\n", d->name, d->new_nbuckets);
+				bchu_disk_resize(fs, d->idx, d->new_nbuckets);
+			}
+
+	darray_for_each(dev_names, dev) {
+		free(dev->dev);
+		free(dev->label);
+	}
+	darray_exit(&dev_names);
+	darray_exit(&devs);
+	free(sb);
+	bcache_fs_close(fs);
+	printbuf_exit(&buf);
+	return 0;
+}
+
+/*
+ * fs latency: the time_stats files in sysfs, as a table
+ *
+ * We get count, min/max/mean and the quantiles (at 1/16 steps) from
+ * bch2_time_stats_to_text(); the histogram is reconstructed from the
+ * quantiles, so it's approximate
+ */
+
+struct latency_stat {
+	char			*name;
+	u64			count;
+	u64			min;
+	u64			max;
+	u64			mean;
+	unsigned		nr_quantiles;
+	u64			quantiles[NR_QUANTILES];
+};
+
+typedef DARRAY(struct latency_stat) latency_stats;
+
+static const struct {
+	const char	*name;
+	u64		nsecs;
+} latency_units[] = {
+	{ "ns",		1			},
+	{ "us",		NSEC_PER_USEC		},
+	{ "ms",		NSEC_PER_MSEC		},
+	{ "s",		NSEC_PER_SEC		},
+	{ "m",		NSEC_PER_SEC * 60ULL	},
+	{ "h",		NSEC_PER_SEC * 3600ULL	},
+	{ "d",		NSEC_PER_SEC * 86400ULL	},
+};
+
+static u64 latency_unit(const char *name)
+{
+	for (unsigned i = 0; i < ARRAY_SIZE(latency_units); i++)
+		if (!strcmp(name, latency_units[i].name))
+			return latency_units[i].nsecs;
+	return 0;
+}
+
+static void latency_stat_parse(struct latency_stat *s, char *text)
+{
+	bool durations = false;
+	char *line, *p = text;
+
+	while ((line = strsep(&p, "\n"))) {
+		char name[32], unit[16];
+		u64 v;
+
+		line = strim(line);
+
+		if (!strcmp(line, "duration of events") ||
+		    !strcmp(line, "recent duration of events")) {
+			durations = true;
+			continue;
+		}
+		if (!strcmp(line, "time between events")) {
+			durations = false;
+			continue;
+		}
+
+		if (sscanf(line, "count: %llu", &v) == 1) {
+			s->count = v;
+			continue;
+		}
+
+		char *q;
+		if (sscanf(line, "quantiles (%15[^)]):", unit) == 1 &&
+		    (q = strchr(line, ':'))) {
+			u64 m = latency_unit(unit);
+			char *tok;
+
+			q++;
+			while ((tok = strsep(&q, " \t")) &&
+			       s->nr_quantiles < NR_QUANTILES)
+				if (*tok && !kstrtoull(tok, 10, &v))
+					s->quantiles[s->nr_quantiles++] = v * m;
+			continue;
+		}
+
+		if (durations &&
+		    sscanf(line, "%31s %llu %15s", name, &v, unit) == 3) {
+			v *= latency_unit(unit);
+
+			if (!strcmp(name, "min:"))
+				s->min = v;
+			else if (!strcmp(name, "max:"))
+				s->max = v;
+			else if (!strcmp(name, "mean:"))
+				s->mean = v;
+		}
+	}
+}
+
+static int latency_stat_cmp(const void *_l, const void *_r)
+{
+	const struct latency_stat *l = _l, *r = _r;
+
+	return strcmp(l->name, r->name);
+}
+
+static latency_stats latency_stats_read(struct bchfs_handle fs,
+					char **names, unsigned nr_names)
+{
+	latency_stats stats = {};
+
+	int fd = openat(fs.sysfs_fd, "time_stats", O_RDONLY|O_DIRECTORY);
+	if (fd < 0)
+		die("error opening time_stats: %m");
+
+	DIR *dir = fdopendir(fd);
+	struct dirent *d;
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (d->d_name[0] == '.')
+			continue;
+
+		bool want = !nr_names;
+		for (unsigned i = 0; i < nr_names; i++)
+			want |= !strcmp(names[i], d->d_name);
+		if (!want)
+			continue;
+
+		char *text = read_file_str(fd, d->d_name);
+		struct latency_stat s = { .name = strdup(d->d_name) };
+
+		if (text)
+			latency_stat_parse(&s, text);
+		free(text);
+
+		if (darray_push(&stats, s))
+			die("memory allocation failure");
+	}
+
+	if (errno)
+		die("error reading time_stats: %m");
+	closedir(dir);
+
+	sort(stats.data, stats.nr, sizeof(stats.data[0]), latency_stat_cmp, NULL);
+	return stats;
+}
+
+/*
+ * Fraction of events that took less than @ns: the quantiles are points on the
+ * CDF, at 1/16 steps between min and max; interpolate linearly between them
+ */
+static double latency_cdf(const struct latency_stat *s, u64 ns)
+{
+	u64 p[NR_QUANTILES + 2];
+	unsigned nr = 0;
+
+	p[nr] = s->min;
+	nr++;
+	for (unsigned i = 0; i < s->nr_quantiles; i++, nr++)
+		p[nr] = max(s->quantiles[i], p[nr - 1]);
+	p[nr] = max(s->max, p[nr - 1]);
+	nr++;
+
+	if (ns <= p[0])
+		return 0;
+	if (ns >= p[nr - 1])
+		return 1;
+
+	for (unsigned i = 0; i + 1 < nr; i++)
+		if (ns < p[i + 1]) {
+			double f = p[i + 1] > p[i]
+				? (double) (ns - p[i]) / (p[i + 1] - p[i])
+				: 0;
+			return (i + f) / (nr - 1);
+		}
+
+	return 1;
+}
+
+/* One character per power of two between min and max */
+static void latency_histogram_to_text(struct printbuf *out, const struct latency_stat *s)
+{
+	static const char * const bars[] = {
+		" ", "▁", "▂", "▃", "▄", "▅", "▆", "▇", "█",
+	};
+	double buckets[64];
+	double peak = 0;
+
+	if (!s->count || !s->nr_quantiles)
+		return;
+
+	unsigned lo = ilog2(max_t(u64, s->min, 1));
+	unsigned hi = min_t(unsigned, ilog2(max_t(u64, s->max, 1)) + 1, 63);
+
+	if (hi - lo > 32)
+		lo = hi - 32;
+
+	for (unsigned i = lo; i < hi; i++) {
+		buckets[i] = latency_cdf(s, 1ULL << (i + 1)) - latency_cdf(s, 1ULL << i);
+		peak = max(peak, buckets[i]);
+	}
+
+	for (unsigned i = lo; i < hi; i++)
+		prt_str(out, bars[peak > 0
+			? (unsigned) DIV_ROUND_UP((u64) (buckets[i] * 8 * 1000 / peak), 1000)
+			: 0]);
+}
+
+static u64 latency_quantile(const struct latency_stat *s, unsigned sixteenths)
+{
+	return sixteenths && sixteenths <= s->nr_quantiles
+		? s->quantiles[sixteenths - 1]
+		: 0;
+}
+
+static void latency_stats_to_text(struct printbuf *out, latency_stats *stats, bool all)
+{
+	static const struct {
+		const char	*name;
+		unsigned	sixteenths;
+	} cols[] = {
+		{ "p25",	4 },
+		{ "p50",	8 },
+		{ "p75",	12 },
+		{ "p94",	15 },
+	};
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 32);
+	printbuf_tabstop_push(out, 10);
+	for (unsigned i = 0; i < ARRAY_SIZE(cols) + 3; i++)
+		printbuf_tabstop_push(out, 10);
+	printbuf_tabstop_push(out, 2);
+
+	prt_printf(out, "stat\tcount\rmin\rmean\r");
+	for (unsigned i = 0; i < ARRAY_SIZE(cols); i++)
+		prt_printf(out, "%s\r", cols[i].name);
+	prt_printf(out, "max\r\thistogram (log2)\n");
+
+	darray_for_each(*stats, s) {
+		if (!s->count && !all)
+			continue;
+
+		prt_printf(out, "%s\t%llu\r", s->name, s->count);
+
+		if (!s->count) {
+			prt_newline(out);
+			continue;
+		}
+
+		bch2_pr_time_units(out, s->min);
+		prt_str(out, "\r");
+		bch2_pr_time_units(out, s->mean);
+		prt_str(out, "\r");
+		for (unsigned i = 0; i < ARRAY_SIZE(cols); i++) {
+			bch2_pr_time_units(out, latency_quantile(s, cols[i].sixteenths));
+			prt_str(out, "\r");
+		}
+		bch2_pr_time_units(out, s->max);
+		prt_str(out, "\r\t");
+		latency_histogram_to_text(out, s);
+		prt_newline(out);
+	}
+}
+
+static void latency_stats_json(latency_stats *stats, bool all)
+{
+	bool first = true;
+
+	printf("[");
+	darray_for_each(*stats, s) {
+		if (!s->count && !all)
+			continue;
+
+		printf("%s\n  { \"name\": ", first ? "" : ",");
+		json_str(s->name);
+		printf(", \"count\": %llu, \"min_ns\": %llu, \"mean_ns\": %llu, \"max_ns\": %llu, \"quantiles_ns\": [",
+		       s->count, s->min, s->mean, s->max);
+		for (unsigned i = 0; i < s->nr_quantiles; i++)
+			printf("%s%llu", i ? ", " : "", s->quantiles[i]);
+		printf("] }");
+		first = false;
+	}
+	printf("\n]\n");
+}
+
+static void fs_latency_usage(void)
+{
+	puts("bcachefs fs latency - show latency statistics of a mounted filesystem\n"
+	     "Usage: bcachefs fs latency [OPTION]... <mountpoint> [<stat>...]\n"
+	     "\n"
+	     "Shows the time_stats in sysfs (journal flushes, btree node splits, data\n"
+	     "reads and writes...) since mount: count, min, mean, quantiles, max, and a\n"
+	     "histogram with one column for each power of two nanoseconds. Quantiles\n"
+	     "and the histogram are approximate.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -a, --all                    Include stats with no events\n"
+	     "  -j, --json                   JSON output\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_fs_latency(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "all",		no_argument,		NULL, 'a' },
+		{ "json",		no_argument,		NULL, 'j' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	bool all = false, json = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "ajh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'a':
+			all = true;
+			break;
+		case 'j':
+			json = true;
+			break;
+		case 'h':
+			fs_latency_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			fs_latency_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	struct bchfs_handle fs = bcache_fs_open(fs_path);
+	latency_stats stats = latency_stats_read(fs, argv, argc);
+
+	for (unsigned i = 0; i < argc; i++) {
+		bool found = false;
+
+		darray_for_each(stats, s)
+			found |= !strcmp(s->name, argv[i]);
+		if (!found)
+			die("No time_stats %s", argv[i]);
+	}
+
+	if (json) {
+		latency_stats_json(&stats, all || argc);
+	} else {
+		struct printbuf buf = PRINTBUF;
+
+		latency_stats_to_text(&buf, &stats, all || argc);
+		printf("%s", buf.buf);
+		printbuf_exit(&buf);
+	}
+
+	darray_for_each(stats, s)
+		free(s->name);
+	darray_exit(&stats);
+	bcache_fs_close(fs);
+	return 0;
+}
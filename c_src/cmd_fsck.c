@@ -1,11 +1,22 @@
 
 #include <errno.h>
 #include <getopt.h>
+#include <pthread.h>
+#include <stdio.h>
+#include <sys/stat.h>
 #include <sys/uio.h>
+#include <time.h>
 #include <unistd.h>
+
+#include <linux/shrinker.h>
+#include <uuid/uuid.h>
+
 #include "cmds.h"
 #include "libbcachefs/error.h"
 #include "libbcachefs.h"
+#include "progress.h"
+#include "libbcachefs/recovery_passes.h"
+#include "libbcachefs/sb-errors.h"
 #include "libbcachefs/super.h"
 #include "libbcachefs/super-io.h"
 #include "tools-util.h"
@@ -22,8 +33,17 @@ static void fsck_usage(void)
 	     "  -f                      Force checking even if filesystem is marked clean\n"
 	     "  -r, --ratelimit_errors  Don't display more than 10 errors of a given type\n"
 	     "  -R, --reconstruct_alloc Reconstruct the alloc btree\n"
+	     "  -P, --passes=PASS,...   Only run these recovery passes, instead of a full\n"
+	     "                          fsck; --passes=list lists them\n"
 	     "  -k, --kernel            Use the in-kernel fsck implementation\n"
+	     "  -s, --state-file=FILE   With -n: save errors found to FILE, and report\n"
+	     "                          only errors that are new since the last run\n"
 	     "  -v                      Be verbose\n"
+	     "      --memory-limit=SIZE Shrink the btree node and key caches to stay under\n"
+	     "                          SIZE (e.g. 2G), and report peak memory used\n"
+	     "      --color=WHEN        Color output: auto, always or never\n"
+	     "      --no-progress       Don't report which pass is running\n"
+	     "      --progress-json     Report progress as JSON lines on stderr\n"
 	     "  -h, --help              Display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
@@ -104,6 +124,39 @@ static int fsck_online(const char *dev_path)
 	return splice_fd_to_stdinout(fsck_fd);
 }
 
+static void fsck_passes_list(void)
+{
+	u64 fsck_passes = bch2_fsck_recovery_passes();
+
+	for (unsigned i = 0; bch2_recovery_passes[i]; i++)
+		printf("%s%s\n", bch2_recovery_passes[i],
+		       fsck_passes & BIT_ULL(i) ? "" : " (not normally run by fsck)");
+}
+
+static u64 fsck_passes_parse(const char *arg)
+{
+	char *s = strdup(arg), *p = s, *name;
+	u64 ret = 0;
+
+	while ((name = strsep(&p, ","))) {
+		if (!*name)
+			continue;
+
+		int pass = match_string(bch2_recovery_passes, -1, name);
+		if (pass < 0) {
+			fprintf(stderr, "Unknown recovery pass %s; recovery passes are:\n", name);
+			fsck_passes_list();
+			exit(8);
+		}
+		ret |= BIT_ULL(pass);
+	}
+	free(s);
+
+	if (!ret)
+		die("--passes: no passes given");
+	return ret;
+}
+
 static void append_opt(struct printbuf *out, const char *opt)
 {
 	if (out->pos)
@@ -113,9 +166,7 @@ static void append_opt(struct printbuf *out, const char *opt)
 
 static bool should_use_kernel_fsck(darray_str devs)
 {
-	unsigned kernel_version = !access("/sys/module/bcachefs/parameters/version", R_OK)
-	    ? read_file_u64(AT_FDCWD, "/sys/module/bcachefs/parameters/version")
-	    : 0;
+	unsigned kernel_version = kernel_metadata_version();
 
 	if (!kernel_version)
 		return false;
@@ -158,18 +209,259 @@ static bool should_use_kernel_fsck(darray_str devs)
 	return ret;
 }
 
+/*
+ * With --state-file, a check-only run saves the errors it found, by type, and
+ * reports which are new since the previous run: errors that keep appearing
+ * point to ongoing corruption (bad RAM, cabling), not damage from the past
+ */
+struct fsck_state {
+	__uuid_t		uuid;
+	u64			time;
+	u64			nr[BCH_SB_ERR_MAX];
+};
+
+static int fsck_err_id(const char *name)
+{
+	for (unsigned i = 0; i < BCH_SB_ERR_MAX; i++)
+		if (bch2_sb_error_strs[i] && !strcmp(bch2_sb_error_strs[i], name))
+			return i;
+	return -1;
+}
+
+static bool fsck_state_read(const char *path, struct fsck_state *st)
+{
+	FILE *f = fopen(path, "r");
+	if (!f) {
+		if (errno == ENOENT)
+			return false;
+		die("error opening %s: %m", path);
+	}
+
+	char *line = NULL;
+	size_t n = 0;
+
+	while (getline(&line, &n, f) > 0) {
+		char name[128];
+		u64 v;
+
+		if (sscanf(line, "uuid %127s", name) == 1) {
+			if (uuid_parse(name, st->uuid.b))
+				die("%s: invalid uuid %s", path, name);
+		} else if (sscanf(line, "time %llu", &st->time) == 1) {
+		} else if (sscanf(line, "%127s %llu", name, &v) == 2) {
+			/* may be from a different version: */
+			int id = fsck_err_id(name);
+			if (id >= 0)
+				st->nr[id] = v;
+		}
+	}
+
+	free(line);
+	fclose(f);
+	return true;
+}
+
+static void fsck_state_write(const char *path, struct fsck_state *st)
+{
+	char *tmp = mprintf("%s.tmp", path);
+	FILE *f = fopen(tmp, "w");
+	char uuid_str[40];
+
+	if (!f)
+		die("error creating %s: %m", tmp);
+
+	uuid_unparse(st->uuid.b, uuid_str);
+	fprintf(f, "uuid %s\n", uuid_str);
+	fprintf(f, "time %llu\n", st->time);
+
+	for (unsigned i = 0; i < BCH_SB_ERR_MAX; i++)
+		if (st->nr[i])
+			fprintf(f, "%s %llu\n", bch2_sb_error_strs[i], st->nr[i]);
+
+	if (fclose(f))
+		die("error writing %s: %m", tmp);
+	if (rename(tmp, path))
+		die("error renaming %s: %m", tmp);
+	free(tmp);
+}
+
+/* Error counts from previous runs, as recorded in the superblock */
+static void fsck_sb_errors_read(const char *dev, u64 *nr)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	struct bch_sb_handle sb;
+
+	/* if it can't be read, fsck will say why: */
+	if (bch2_read_super(dev, &opts, &sb))
+		return;
+
+	struct bch_sb_field_errors *e = bch2_sb_field_get(sb.sb, errors);
+	for (unsigned i = 0; e && i < bch2_sb_field_nr_entries(e); i++) {
+		unsigned id = BCH_SB_ERROR_ENTRY_ID(&e->entries[i]);
+
+		if (id < BCH_SB_ERR_MAX)
+			nr[id] = BCH_SB_ERROR_ENTRY_NR(&e->entries[i]);
+	}
+
+	bch2_free_super(&sb);
+}
+
+/*
+ * Progress for the userspace fsck: which recovery pass we're on, polled while
+ * bch2_fs_start() runs them
+ */
+struct fsck_progress {
+	struct bch_fs		*c;
+	struct progress		p;
+	pthread_t		thread;
+	bool			stop;
+};
+
+static void *fsck_progress_thread(void *arg)
+{
+	struct fsck_progress *f = arg;
+	unsigned pass = BCH_RECOVERY_PASS_NR;
+
+	while (!READ_ONCE(f->stop)) {
+		unsigned cur = READ_ONCE(f->c->curr_recovery_pass);
+
+		if (cur != pass && cur < BCH_RECOVERY_PASS_NR) {
+			pass = cur;
+			progress_stage(&f->p, bch2_recovery_passes[pass],
+				       BCH_RECOVERY_PASS_NR, false);
+			progress_update(&f->p, pass);
+		}
+		usleep(100 * 1000);
+	}
+
+	return NULL;
+}
+
+static void fsck_progress_start(struct fsck_progress *f, struct bch_fs *c)
+{
+	f->c = c;
+	progress_init(&f->p, "fsck");
+
+	if (pthread_create(&f->thread, NULL, fsck_progress_thread, f))
+		die("error creating progress thread: %m");
+}
+
+static void fsck_progress_stop(struct fsck_progress *f)
+{
+	WRITE_ONCE(f->stop, true);
+	pthread_join(f->thread, NULL);
+
+	progress_update(&f->p, BCH_RECOVERY_PASS_NR);
+	progress_finish(&f->p);
+}
+
+static void fsck_state_report(struct printbuf *out, const char *path,
+			      struct fsck_state *prev, struct fsck_state *cur)
+{
+	time_t t = prev->time;
+	char time_str[64];
+	unsigned nr_new = 0, nr_gone = 0;
+
+	strftime(time_str, sizeof(time_str), "%F %T", localtime(&t));
+
+	if (!uuid_equal(&prev->uuid, &cur->uuid))
+		die("%s is from a different filesystem", path);
+
+	prt_printf(out, "Compared to the previous run at %s:\n", time_str);
+	printbuf_indent_add(out, 2);
+
+	for (unsigned i = 0; i < BCH_SB_ERR_MAX; i++)
+		if (cur->nr[i] > prev->nr[i]) {
+			if (!nr_new++) {
+				prt_printf(out, "New errors:\n");
+				printbuf_indent_add(out, 2);
+			}
+			prt_printf(out, "%s: %llu", bch2_sb_error_strs[i], cur->nr[i]);
+			if (prev->nr[i])
+				prt_printf(out, " (previously %llu)", prev->nr[i]);
+			prt_newline(out);
+		}
+	if (nr_new)
+		printbuf_indent_sub(out, 2);
+
+	for (unsigned i = 0; i < BCH_SB_ERR_MAX; i++)
+		if (prev->nr[i] && !cur->nr[i]) {
+			if (!nr_gone++) {
+				prt_printf(out, "No longer seen:\n");
+				printbuf_indent_add(out, 2);
+			}
+			prt_printf(out, "%s\n", bch2_sb_error_strs[i]);
+		}
+	if (nr_gone)
+		printbuf_indent_sub(out, 2);
+
+	if (!nr_new)
+		prt_printf(out, "No new errors\n");
+	printbuf_indent_sub(out, 2);
+}
+
+/*
+ * --memory-limit is enforced by the shrinkers, which free btree nodes and
+ * cached keys as resident memory gets close to it; locked and dirty nodes
+ * can't be freed, so it isn't a hard limit, and the peak is reported
+ */
+static void fsck_peak_memory_report(u64 limit)
+{
+	FILE *f = fopen("/proc/self/status", "r");
+	char *line = NULL;
+	size_t n = 0;
+	u64 peak = 0;
+
+	if (!f)
+		return;
+
+	while (getline(&line, &n, f) > 0)
+		if (sscanf(line, "VmHWM: %llu kB", &peak) == 1)
+			break;
+
+	free(line);
+	fclose(f);
+
+	if (!peak)
+		return;
+	peak <<= 10;
+
+	struct printbuf buf = PRINTBUF;
+	tools_printbuf_units(&buf, true);
+
+	prt_str(&buf, "Peak memory used: ");
+	tools_prt_units_u64(&buf, peak);
+	prt_str(&buf, " (limit ");
+	tools_prt_units_u64(&buf, limit);
+	prt_str(&buf, ")");
+	if (peak > limit)
+		prt_str(&buf, ": locked and dirty btree nodes can't be freed");
+
+	printf("%s\n", buf.buf);
+	printbuf_exit(&buf);
+}
+
 int cmd_fsck(int argc, char *argv[])
 {
 	static const struct option longopts[] = {
 		{ "ratelimit_errors",	no_argument,		NULL, 'r' },
 		{ "reconstruct_alloc",	no_argument,		NULL, 'R' },
 		{ "kernel",		no_argument,		NULL, 'k' },
+		{ "state-file",		required_argument,	NULL, 's' },
+		{ "passes",		required_argument,	NULL, 'P' },
+		{ "memory-limit",	required_argument,	NULL, 'M' },
 		{ "no-kernel",		no_argument,		NULL, 'K' },
+		{ "color",		required_argument,	NULL, 'C' },
+		{ "no-progress",	no_argument,		NULL, OPT_NO_PROGRESS },
+		{ "progress-json",	no_argument,		NULL, OPT_PROGRESS_JSON },
 		{ "help",		no_argument,		NULL, 'h' },
 		{ NULL }
 	};
 	int kernel = -1; /* unset */
 	int opt, ret = 0;
+	bool nochanges = false;
+	const char *state_path = NULL;
+	u64 passes = 0, memory_limit = 0;
 	struct printbuf opts_str = PRINTBUF;
 
 	if (getenv("BCACHEFS_KERNEL_ONLY"))
@@ -181,7 +473,7 @@ int cmd_fsck(int argc, char *argv[])
 	append_opt(&opts_str, "read_only");
 
 	while ((opt = getopt_long(argc, argv,
-				  "apynfo:rRkvh",
+				  "apynfo:rRP:ks:vh",
 				  longopts, NULL)) != -1)
 		switch (opt) {
 		case 'a': /* outdated alias for -p */
@@ -192,6 +484,7 @@ int cmd_fsck(int argc, char *argv[])
 		case 'n':
 			append_opt(&opts_str, "nochanges");
 			append_opt(&opts_str, "fix_errors=no");
+			nochanges = true;
 			break;
 		case 'f':
 			/* force check, even if filesystem marked clean: */
@@ -205,15 +498,36 @@ int cmd_fsck(int argc, char *argv[])
 		case 'R':
 			append_opt(&opts_str, "reconstruct_alloc");
 			break;
+		case 'P':
+			if (!strcmp(optarg, "list")) {
+				fsck_passes_list();
+				exit(0);
+			}
+			passes = fsck_passes_parse(optarg);
+			break;
+		case 'M':
+			if (bch2_strtoull_h(optarg, &memory_limit) || !memory_limit)
+				die("invalid memory limit %s", optarg);
+			break;
 		case 'k':
 			kernel = true;
 			break;
 		case 'K':
 			kernel = false;
 			break;
+		case 's':
+			state_path = optarg;
+			break;
 		case 'v':
 			append_opt(&opts_str, "verbose");
 			break;
+		case 'C':
+			color_when_parse(optarg);
+			break;
+		case OPT_NO_PROGRESS:
+		case OPT_PROGRESS_JSON:
+			progress_opt(opt);
+			break;
 		case 'h':
 			fsck_usage();
 			exit(16);
@@ -228,8 +542,56 @@ int cmd_fsck(int argc, char *argv[])
 	darray_str devs = get_or_split_cmdline_devs(argc, argv);
 
 	darray_for_each(devs, i)
-		if (dev_mounted(*i))
+		if (dev_mounted(*i)) {
+			if (state_path)
+				die("--state-file not supported on mounted filesystems");
+			if (memory_limit)
+				die("--memory-limit not supported on mounted filesystems");
 			return fsck_online(*i);
+		}
+
+	if (passes) {
+		if (kernel > 0)
+			die("--passes not supported with --kernel");
+		kernel = false;
+	}
+
+	if (memory_limit) {
+		if (kernel > 0)
+			die("--memory-limit not supported with --kernel");
+		kernel = false;
+
+		shrinker_memory_limit_set(memory_limit);
+	}
+
+	struct fsck_state prev = {}, cur = {};
+	bool have_prev = false;
+	u64 sb_errors[BCH_SB_ERR_MAX] = {};
+
+	if (state_path) {
+		/* Errors from a nochanges run aren't recorded in the superblock: */
+		if (!nochanges)
+			die("--state-file requires -n");
+		if (kernel > 0)
+			die("--state-file not supported with --kernel");
+
+		/* kernel fsck doesn't tell us which errors it found: */
+		kernel = false;
+
+		have_prev = fsck_state_read(state_path, &prev);
+		fsck_sb_errors_read(devs.data[0], sb_errors);
+	}
+
+	/* the kernel only opens block devices; image files are checked here */
+	darray_for_each(devs, i) {
+		struct stat st = xstat(*i);
+
+		if (!S_ISBLK(st.st_mode)) {
+			if (kernel > 0)
+				die("%s is an image file, not supported with --kernel", *i);
+			kernel = false;
+		}
+	}
 
 	int kernel_probed = kernel;
 	if (kernel_probed < 0)
@@ -263,20 +625,91 @@ userland_fsck:
 		if (ret)
 			return ret;
 
+		/*
+		 * Only the requested passes (and the ones that always run) - not
+		 * a full fsck, which is what the fsck option means to recovery:
+		 */
+		if (passes)
+			opt_set(opts, fsck,	false);
+
+		/* fsck's own messages go to stdout: don't draw a bar over them */
+		bool show_progress = progress_enabled() &&
+			!(progress_when == PROGRESS_AUTO &&
+			  isatty(STDOUT_FILENO) &&
+			  isatty(STDERR_FILENO));
+
+		if (passes || show_progress)
+			opt_set(opts, nostart,	true);
+
 		struct bch_fs *c = bch2_fs_open(devs.data, devs.nr, opts);
-		if (IS_ERR(c))
+
+		if ((passes || show_progress) && !IS_ERR(c)) {
+			struct fsck_progress fp = {};
+
+			if (passes) {
+				c->recovery_passes_explicit |= passes;
+				/* so that errors are reported and fixed per fix_errors: */
+				set_bit(BCH_FS_fsck_running, &c->flags);
+			}
+
+			if (show_progress)
+				fsck_progress_start(&fp, c);
+
+			int ret2 = bch2_fs_start(c);
+
+			if (show_progress)
+				fsck_progress_stop(&fp);
+
+			if (ret2) {
+				fprintf(stderr, "%s: %s\n",
+					passes
+					? "error running recovery passes"
+					: "error starting filesystem",
+					bch2_err_str(ret2));
+				bch2_fs_stop(c);
+				c = ERR_PTR(ret2);
+			}
+		}
+
+		if (IS_ERR(c)) {
+			if (memory_limit)
+				fsck_peak_memory_report(memory_limit);
 			exit(8);
+		}
 
 		if (test_bit(BCH_FS_errors_fixed, &c->flags)) {
-			fprintf(stderr, "%s: errors fixed\n", c->name);
+			fprintf_severity(stderr, SEVERITY_FIXED, "%s: errors fixed\n", c->name);
 			ret |= 1;
 		}
 		if (test_bit(BCH_FS_error, &c->flags)) {
-			fprintf(stderr, "%s: still has errors\n", c->name);
+			fprintf_severity(stderr, SEVERITY_ERROR, "%s: still has errors\n", c->name);
 			ret |= 4;
 		}
 
+		if (state_path) {
+			struct printbuf buf = PRINTBUF;
+
+			cur.uuid = c->sb.user_uuid;
+			cur.time = ktime_get_real_seconds();
+
+			mutex_lock(&c->fsck_error_counts_lock);
+			darray_for_each(c->fsck_error_counts, e)
+				if (e->id < BCH_SB_ERR_MAX)
+					cur.nr[e->id] = e->nr - min_t(u64, e->nr, sb_errors[e->id]);
+			mutex_unlock(&c->fsck_error_counts_lock);
+
+			if (have_prev) {
+				fsck_state_report(&buf, state_path, &prev, &cur);
+				printf("%s", buf.buf);
+			}
+			fsck_state_write(state_path, &cur);
+			printbuf_exit(&buf);
+		}
+
 		bch2_fs_stop(c);
+
+		if (memory_limit)
+			fsck_peak_memory_report(memory_limit);
 	}
 
 	printbuf_exit(&opts_str);
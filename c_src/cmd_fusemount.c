@@ -3,9 +3,15 @@
 #include <errno.h>
 #include <float.h>
 #include <getopt.h>
+#include <pthread.h>
+#include <stddef.h>
 #include <stdio.h>
 #include <sys/statvfs.h>
 
+#include <linux/falloc.h>
+#include <linux/fiemap.h>
+#include <linux/fs.h>
+
 #include <fuse_lowlevel.h>
 
 #include "cmds.h"
@@ -13,27 +19,47 @@
 #include "tools-util.h"
 
 #include "libbcachefs/bcachefs.h"
+#include "libbcachefs/acl.h"
 #include "libbcachefs/alloc_foreground.h"
 #include "libbcachefs/btree_iter.h"
 #include "libbcachefs/buckets.h"
 #include "libbcachefs/dirent.h"
 #include "libbcachefs/errcode.h"
 #include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
 #include "libbcachefs/fs-common.h"
 #include "libbcachefs/inode.h"
 #include "libbcachefs/io_read.h"
+#include "libbcachefs/io_misc.h"
 #include "libbcachefs/io_write.h"
 #include "libbcachefs/opts.h"
+#include "libbcachefs/reflink.h"
+#include "libbcachefs/str_hash.h"
+#include "libbcachefs/subvolume.h"
 #include "libbcachefs/super.h"
+#include "libbcachefs/xattr.h"
 
 /* mode_to_type(): */
 #include "libbcachefs/fs.h"
 
 #include <linux/dcache.h>
+#include <linux/posix_acl.h>
+#include <linux/posix_acl_xattr.h>
+#include <linux/rhashtable.h>
+#include <linux/xattr.h>
 
 /* XXX cut and pasted from fsck.c */
 #define QSTR(n) { { { .len = strlen(n) } }, .name = n }
 
+/* fusemount --direct-io: bypass the page cache, and disable writeback caching */
+static bool fuse_direct_io;
+
+/*
+ * fusemount --rescue: read only, and reads return whatever could be read
+ * before an error instead of failing outright
+ */
+static bool fuse_rescue;
+
 /* used by write_aligned function for waiting on bch2_write closure */
 struct write_aligned_op_t {
         struct closure cl;
@@ -43,21 +69,142 @@ struct write_aligned_op_t {
 };
 
 
-static inline subvol_inum map_root_ino(u64 ino)
+/*
+ * libfuse's worker threads aren't kthreads: the first time one calls into
+ * bcachefs, give it a task_struct and register it with urcu, and undo that
+ * when the thread exits
+ */
+static pthread_key_t fuse_thread_key;
+static pthread_once_t fuse_thread_key_once = PTHREAD_ONCE_INIT;
+
+static void fuse_thread_exit(void *p)
 {
-	return (subvol_inum) { 1, ino == 1 ? 4096 : ino };
+	tools_thread_exit();
 }
 
-static inline u64 unmap_root_ino(u64 ino)
+static void fuse_thread_key_init(void)
 {
-	return ino == 4096 ? 1 : ino;
+	if (pthread_key_create(&fuse_thread_key, fuse_thread_exit))
+		die("pthread_key_create err: %m");
 }
 
-static struct stat inode_to_stat(struct bch_fs *c,
+static struct bch_fs *fuse_req_fs(fuse_req_t req)
+{
+	if (unlikely(!current)) {
+		tools_thread_init();
+
+		pthread_once(&fuse_thread_key_once, fuse_thread_key_init);
+		pthread_setspecific(fuse_thread_key, current);
+	}
+
+	return fuse_req_userdata(req);
+}
+
+/*
+ * Subvolumes and snapshots:
+ *
+ * Inode numbers are only unique within a subvolume - a snapshot has the same
+ * inode numbers as the subvolume it was taken from. Inodes in the subvolume
+ * we're mounting use their inode number as their fuse inode number; inodes in
+ * other subvolumes, reached by looking up a subvolume dirent, are assigned
+ * fuse inode numbers with the top bit set, which bcachefs inode numbers never
+ * have.
+ */
+#define FUSE_INO_MAPPED		(1ULL << 63)
+
+struct fuse_ino_key {
+	u64			inum;
+	u32			subvol;
+	u32			pad;
+};
+
+struct fuse_ino_map {
+	struct rhash_head	hash;
+	struct fuse_ino_key	key;
+	u64			ino;
+};
+
+static const struct rhashtable_params fuse_ino_params = {
+	.head_offset		= offsetof(struct fuse_ino_map, hash),
+	.key_offset		= offsetof(struct fuse_ino_map, key),
+	.key_len		= sizeof(struct fuse_ino_key),
+};
+
+/* fusemount --subvol: the subvolume that appears as the root of the mount */
+static subvol_inum fuse_root = BCACHEFS_ROOT_SUBVOL_INUM;
+
+static pthread_mutex_t fuse_inos_lock = PTHREAD_MUTEX_INITIALIZER;
+static struct rhashtable fuse_inos_table;
+static DARRAY(struct fuse_ino_map *) fuse_inos;
+
+static subvol_inum map_root_ino(u64 ino)
+{
+	subvol_inum ret = { 0, 0 };
+
+	if (ino == FUSE_ROOT_ID)
+		return fuse_root;
+
+	if (!(ino & FUSE_INO_MAPPED))
+		return (subvol_inum) { fuse_root.subvol, ino };
+
+	u64 idx = ino & ~FUSE_INO_MAPPED;
+
+	pthread_mutex_lock(&fuse_inos_lock);
+	if (idx < fuse_inos.nr)
+		ret = (subvol_inum) {
+			.subvol	= fuse_inos.data[idx]->key.subvol,
+			.inum	= fuse_inos.data[idx]->key.inum,
+		};
+	pthread_mutex_unlock(&fuse_inos_lock);
+
+	return ret;
+}
+
+static u64 unmap_root_ino(subvol_inum inum)
+{
+	if (inum.subvol == fuse_root.subvol &&
+	    inum.inum == fuse_root.inum)
+		return FUSE_ROOT_ID;
+
+	if (inum.subvol == fuse_root.subvol)
+		return inum.inum;
+
+	struct fuse_ino_key key = {
+		.inum	= inum.inum,
+		.subvol	= inum.subvol,
+	};
+	struct fuse_ino_map *m;
+
+	pthread_mutex_lock(&fuse_inos_lock);
+	m = rhashtable_lookup_fast(&fuse_inos_table, &key, fuse_ino_params);
+	if (!m) {
+		m = xcalloc(1, sizeof(*m));
+		m->key	= key;
+		m->ino	= FUSE_INO_MAPPED | fuse_inos.nr;
+
+		if (darray_push(&fuse_inos, m) ||
+		    rhashtable_lookup_insert_fast(&fuse_inos_table, &m->hash,
+						  fuse_ino_params))
+			die("error allocating inode number");
+	}
+	pthread_mutex_unlock(&fuse_inos_lock);
+
+	return m->ino;
+}
+
+static void fuse_inos_exit(void)
+{
+	rhashtable_destroy(&fuse_inos_table);
+	darray_for_each(fuse_inos, i)
+		free(*i);
+	darray_exit(&fuse_inos);
+}
+
+static struct stat inode_to_stat(struct bch_fs *c, u32 subvol,
 				 struct bch_inode_unpacked *bi)
 {
 	return (struct stat) {
-		.st_ino		= unmap_root_ino(bi->bi_inum),
+		.st_ino		= unmap_root_ino((subvol_inum) { subvol, bi->bi_inum }),
 		.st_size	= bi->bi_size,
 		.st_mode	= bi->bi_mode,
 		.st_uid		= bi->bi_uid,
@@ -72,13 +219,13 @@ static struct stat inode_to_stat(struct bch_fs *c,
 	};
 }
 
-static struct fuse_entry_param inode_to_entry(struct bch_fs *c,
+static struct fuse_entry_param inode_to_entry(struct bch_fs *c, u32 subvol,
 					      struct bch_inode_unpacked *bi)
 {
 	return (struct fuse_entry_param) {
-		.ino		= unmap_root_ino(bi->bi_inum),
+		.ino		= unmap_root_ino((subvol_inum) { subvol, bi->bi_inum }),
 		.generation	= bi->bi_generation,
-		.attr		= inode_to_stat(c, bi),
+		.attr		= inode_to_stat(c, subvol, bi),
 		.attr_timeout	= DBL_MAX,
 		.entry_timeout	= DBL_MAX,
 	};
@@ -86,7 +233,10 @@ static struct fuse_entry_param inode_to_entry(struct bch_fs *c,
 
 static void bcachefs_fuse_init(void *arg, struct fuse_conn_info *conn)
 {
-	if (conn->capable & FUSE_CAP_WRITEBACK_CACHE) {
+	if (fuse_direct_io) {
+		fuse_log(FUSE_LOG_DEBUG, "fuse_init: direct io, not activating writeback\n");
+		conn->want &= ~FUSE_CAP_WRITEBACK_CACHE;
+	} else if (conn->capable & FUSE_CAP_WRITEBACK_CACHE) {
 		fuse_log(FUSE_LOG_DEBUG, "fuse_init: activating writeback\n");
 		conn->want |= FUSE_CAP_WRITEBACK_CACHE;
 	} else
@@ -106,7 +256,7 @@ static void bcachefs_fuse_lookup(fuse_req_t req, fuse_ino_t dir_ino,
 				 const char *name)
 {
 	subvol_inum dir = map_root_ino(dir_ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked bi;
 	struct qstr qstr = QSTR(name);
 	subvol_inum inum;
@@ -140,7 +290,7 @@ static void bcachefs_fuse_lookup(fuse_req_t req, fuse_ino_t dir_ino,
 	fuse_log(FUSE_LOG_DEBUG, "fuse_lookup ret(inum=%llu)\n",
 		 bi.bi_inum);
 
-	struct fuse_entry_param e = inode_to_entry(c, &bi);
+	struct fuse_entry_param e = inode_to_entry(c, inum.subvol, &bi);
 	fuse_reply_entry(req, &e);
 	return;
 err:
@@ -152,7 +302,7 @@ static void bcachefs_fuse_getattr(fuse_req_t req, fuse_ino_t ino,
 				  struct fuse_file_info *fi)
 {
 	subvol_inum inum = map_root_ino(ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked bi;
 	struct stat attr;
 
@@ -167,7 +317,7 @@ static void bcachefs_fuse_getattr(fuse_req_t req, fuse_ino_t ino,
 
 	fuse_log(FUSE_LOG_DEBUG, "fuse_getattr success\n");
 
-	attr = inode_to_stat(c, &bi);
+	attr = inode_to_stat(c, inum.subvol, &bi);
 	fuse_reply_attr(req, &attr, DBL_MAX);
 }
 
@@ -175,7 +325,7 @@ static void bcachefs_fuse_setattr(fuse_req_t req, fuse_ino_t ino,
 				  struct stat *attr, int to_set,
 				  struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked inode_u;
 	struct btree_trans *trans;
 	struct btree_iter iter;
@@ -224,7 +374,7 @@ err:
 	bch2_trans_put(trans);
 
 	if (!ret) {
-		*attr = inode_to_stat(c, &inode_u);
+		*attr = inode_to_stat(c, inum.subvol, &inode_u);
 		fuse_reply_attr(req, attr, DBL_MAX);
 	} else {
 		fuse_reply_err(req, -ret);
@@ -255,7 +405,7 @@ static void bcachefs_fuse_mknod(fuse_req_t req, fuse_ino_t dir_ino,
 				dev_t rdev)
 {
 	subvol_inum dir = map_root_ino(dir_ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked new_inode;
 	int ret;
 
@@ -266,7 +416,7 @@ static void bcachefs_fuse_mknod(fuse_req_t req, fuse_ino_t dir_ino,
 	if (ret)
 		goto err;
 
-	struct fuse_entry_param e = inode_to_entry(c, &new_inode);
+	struct fuse_entry_param e = inode_to_entry(c, dir.subvol, &new_inode);
 	fuse_reply_entry(req, &e);
 	return;
 err:
@@ -288,7 +438,7 @@ static void bcachefs_fuse_mkdir(fuse_req_t req, fuse_ino_t dir,
 static void bcachefs_fuse_unlink(fuse_req_t req, fuse_ino_t dir_ino,
 				 const char *name)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked dir_u, inode_u;
 	struct qstr qstr = QSTR(name);
 	subvol_inum dir = map_root_ino(dir_ino);
@@ -316,7 +466,7 @@ static void bcachefs_fuse_rename(fuse_req_t req,
 				 fuse_ino_t dst_dir_ino, const char *dstname,
 				 unsigned flags)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked dst_dir_u, src_dir_u;
 	struct bch_inode_unpacked src_inode_u, dst_inode_u;
 	struct qstr dst_name = QSTR(srcname);
@@ -344,7 +494,7 @@ static void bcachefs_fuse_rename(fuse_req_t req,
 static void bcachefs_fuse_link(fuse_req_t req, fuse_ino_t ino,
 			       fuse_ino_t newparent_ino, const char *newname)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked dir_u, inode_u;
 	struct qstr qstr = QSTR(newname);
 	subvol_inum newparent	= map_root_ino(newparent_ino);
@@ -359,7 +509,7 @@ static void bcachefs_fuse_link(fuse_req_t req, fuse_ino_t ino,
 					    inum, &inode_u, &qstr));
 
 	if (!ret) {
-		struct fuse_entry_param e = inode_to_entry(c, &inode_u);
+		struct fuse_entry_param e = inode_to_entry(c, inum.subvol, &inode_u);
 		fuse_reply_entry(req, &e);
 	} else {
 		fuse_reply_err(req, -ret);
@@ -369,8 +519,8 @@ static void bcachefs_fuse_link(fuse_req_t req, fuse_ino_t ino,
 static void bcachefs_fuse_open(fuse_req_t req, fuse_ino_t inum,
 			       struct fuse_file_info *fi)
 {
-	fi->direct_io		= false;
-	fi->keep_cache		= true;
+	fi->direct_io		= fuse_direct_io;
+	fi->keep_cache		= !fuse_direct_io;
 	fi->cache_readdir	= true;
 
 	fuse_reply_open(req, fi);
@@ -488,12 +638,38 @@ static int read_aligned(struct bch_fs *c, subvol_inum inum, size_t aligned_size,
 	return -blk_status_to_errno(rbio.bio.bi_status);
 }
 
+/*
+ * For --rescue: read block by block, stopping at the first block we can't
+ * read; returns the number of bytes read, or an error if the first block
+ * couldn't be read
+ */
+static ssize_t read_aligned_rescue(struct bch_fs *c, subvol_inum inum,
+				   size_t aligned_size, off_t aligned_offset,
+				   void *buf)
+{
+	size_t done;
+	int ret = 0;
+
+	for (done = 0; done < aligned_size; done += block_bytes(c)) {
+		ret = read_aligned(c, inum, block_bytes(c),
+				   aligned_offset + done, buf + done);
+		if (ret) {
+			fuse_log(FUSE_LOG_ERR, "rescue: skipping unreadable extent: inode %llu offset %llu len %u: %s\n",
+				 inum.inum, (u64) aligned_offset + done,
+				 block_bytes(c), bch2_err_str(ret));
+			break;
+		}
+	}
+
+	return done ?: ret;
+}
+
 static void bcachefs_fuse_read(fuse_req_t req, fuse_ino_t ino,
 			       size_t size, off_t offset,
 			       struct fuse_file_info *fi)
 {
 	subvol_inum inum = map_root_ino(ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 
 	fuse_log(FUSE_LOG_DEBUG, "bcachefs_fuse_read(%llu, %zd, %lld)\n",
 		 inum, size, offset);
@@ -523,6 +699,18 @@ static void bcachefs_fuse_read(fuse_req_t req, fuse_ino_t ino,
 
 	ret = read_aligned(c, inum, align.size, align.start, buf);
 
+	if (unlikely(ret) && fuse_rescue) {
+		ssize_t bytes = read_aligned_rescue(c, inum, align.size,
+						    align.start, buf);
+
+		if (bytes > (ssize_t) align.pad_start) {
+			size = min_t(size_t, size, bytes - align.pad_start);
+			ret = 0;
+		} else {
+			ret = -EIO;
+		}
+	}
+
 	if (likely(!ret))
 		fuse_reply_buf(req, buf + align.pad_start, size);
 	else
@@ -620,7 +808,7 @@ static void bcachefs_fuse_write(fuse_req_t req, fuse_ino_t ino,
 				struct fuse_file_info *fi)
 {
 	subvol_inum inum = map_root_ino(ino);
-	struct bch_fs *c	= fuse_req_userdata(req);
+	struct bch_fs *c	= fuse_req_fs(req);
 	struct bch_io_opts	io_opts;
 	size_t			aligned_written;
 	int			ret = 0;
@@ -707,7 +895,7 @@ static void bcachefs_fuse_symlink(fuse_req_t req, const char *link,
 				  fuse_ino_t dir_ino, const char *name)
 {
 	subvol_inum dir = map_root_ino(dir_ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked new_inode;
 	size_t link_len = strlen(link);
 	int ret;
@@ -752,7 +940,7 @@ static void bcachefs_fuse_symlink(fuse_req_t req, const char *link,
 
 	new_inode.bi_size = written;
 
-	struct fuse_entry_param e = inode_to_entry(c, &new_inode);
+	struct fuse_entry_param e = inode_to_entry(c, inum.subvol, &new_inode);
 	fuse_reply_entry(req, &e);
 	return;
 
@@ -763,7 +951,7 @@ err:
 static void bcachefs_fuse_readlink(fuse_req_t req, fuse_ino_t ino)
 {
 	subvol_inum inum = map_root_ino(ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	char *buf = NULL;
 
 	fuse_log(FUSE_LOG_DEBUG, "bcachefs_fuse_readlink(%llu)\n", inum.inum);
@@ -806,31 +994,32 @@ err:
 static void bcachefs_fuse_flush(fuse_req_t req, fuse_ino_t inum,
 				struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 }
 
 static void bcachefs_fuse_release(fuse_req_t req, fuse_ino_t inum,
 				  struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 }
 
 static void bcachefs_fuse_fsync(fuse_req_t req, fuse_ino_t inum, int datasync,
 				struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 }
 
 static void bcachefs_fuse_opendir(fuse_req_t req, fuse_ino_t inum,
 				  struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 }
 #endif
 
 struct fuse_dir_context {
 	struct dir_context	ctx;
 	fuse_req_t		req;
+	u32			subvol;
 	char			*buf;
 	size_t			bufsize;
 };
@@ -868,15 +1057,12 @@ static size_t fuse_add_direntry2(char *buf, size_t bufsize,
 	return entlen_padded;
 }
 
-static int fuse_filldir(struct dir_context *_ctx,
-			const char *name, int namelen,
-			loff_t pos, u64 ino, unsigned type)
+static int fuse_filldir_ino(struct fuse_dir_context *ctx,
+			    const char *name, int namelen,
+			    loff_t pos, fuse_ino_t ino, unsigned type)
 {
-	struct fuse_dir_context *ctx =
-		container_of(_ctx, struct fuse_dir_context, ctx);
-
 	struct stat statbuf = {
-		.st_ino		= unmap_root_ino(ino),
+		.st_ino		= ino,
 		.st_mode	= type << 12,
 	};
 
@@ -899,7 +1085,19 @@ static int fuse_filldir(struct dir_context *_ctx,
 	return 0;
 }
 
-static bool handle_dots(struct fuse_dir_context *ctx, fuse_ino_t dir)
+static int fuse_filldir(struct dir_context *_ctx,
+			const char *name, int namelen,
+			loff_t pos, u64 ino, unsigned type)
+{
+	struct fuse_dir_context *ctx =
+		container_of(_ctx, struct fuse_dir_context, ctx);
+
+	return fuse_filldir_ino(ctx, name, namelen, pos,
+				unmap_root_ino((subvol_inum) { ctx->subvol, ino }),
+				type);
+}
+
+static bool handle_dots(struct fuse_dir_context *ctx, u64 dir)
 {
 	if (ctx->ctx.pos == 0) {
 		if (fuse_filldir(&ctx->ctx, ".", 1, ctx->ctx.pos,
@@ -909,8 +1107,8 @@ static bool handle_dots(struct fuse_dir_context *ctx, fuse_ino_t dir)
 	}
 
 	if (ctx->ctx.pos == 1) {
-		if (fuse_filldir(&ctx->ctx, "..", 2, ctx->ctx.pos,
-				 /*TODO: parent*/ 1, DT_DIR) < 0)
+		if (fuse_filldir_ino(ctx, "..", 2, ctx->ctx.pos,
+				     /*TODO: parent*/ FUSE_ROOT_ID, DT_DIR) < 0)
 			return false;
 		ctx->ctx.pos = 2;
 	}
@@ -923,13 +1121,14 @@ static void bcachefs_fuse_readdir(fuse_req_t req, fuse_ino_t dir_ino,
 				  struct fuse_file_info *fi)
 {
 	subvol_inum dir = map_root_ino(dir_ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked bi;
 	char *buf = calloc(size, 1);
 	struct fuse_dir_context ctx = {
 		.ctx.actor	= fuse_filldir,
 		.ctx.pos	= off,
 		.req		= req,
+		.subvol		= dir.subvol,
 		.buf		= buf,
 		.bufsize	= size,
 	};
@@ -974,19 +1173,19 @@ static void bcachefs_fuse_readdirplus(fuse_req_t req, fuse_ino_t dir,
 static void bcachefs_fuse_releasedir(fuse_req_t req, fuse_ino_t inum,
 				     struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 }
 
 static void bcachefs_fuse_fsyncdir(fuse_req_t req, fuse_ino_t inum, int datasync,
 				   struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 }
 #endif
 
 static void bcachefs_fuse_statfs(fuse_req_t req, fuse_ino_t inum)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_fs_usage_short usage = bch2_fs_usage_read_short(c);
 	unsigned shift = c->block_bits;
 	struct statvfs statbuf = {
@@ -1003,40 +1202,364 @@ static void bcachefs_fuse_statfs(fuse_req_t req, fuse_ino_t inum)
 	fuse_reply_statfs(req, &statbuf);
 }
 
-#if 0
-static void bcachefs_fuse_setxattr(fuse_req_t req, fuse_ino_t inum,
+/*
+ * xattrs:
+ *
+ * ACLs are stored in bcachefs's own, more compact format (see acl.c), so they
+ * have to be converted to and from the representation the VFS passes us.
+ */
+
+static const struct fuse_xattr_type {
+	const char	*prefix;
+	unsigned	type;
+	/* name is the whole prefix; stored with an empty name */
+	bool		exact;
+} fuse_xattr_types[] = {
+	{ XATTR_USER_PREFIX,		KEY_TYPE_XATTR_INDEX_USER },
+	{ XATTR_TRUSTED_PREFIX,		KEY_TYPE_XATTR_INDEX_TRUSTED },
+	{ XATTR_SECURITY_PREFIX,	KEY_TYPE_XATTR_INDEX_SECURITY },
+	{ XATTR_NAME_POSIX_ACL_ACCESS,	KEY_TYPE_XATTR_INDEX_POSIX_ACL_ACCESS,	true },
+	{ XATTR_NAME_POSIX_ACL_DEFAULT,	KEY_TYPE_XATTR_INDEX_POSIX_ACL_DEFAULT,	true },
+};
+
+static const struct fuse_xattr_type *fuse_xattr_resolve_name(const char **name)
+{
+	for (const struct fuse_xattr_type *t = fuse_xattr_types;
+	     t < fuse_xattr_types + ARRAY_SIZE(fuse_xattr_types);
+	     t++) {
+		const char *n = strcmp_prefix((char *) *name, t->prefix);
+
+		if (n && !*n == t->exact) {
+			*name = n;
+			return t;
+		}
+	}
+
+	return NULL;
+}
+
+static const struct fuse_xattr_type *fuse_xattr_type_lookup(unsigned type)
+{
+	for (const struct fuse_xattr_type *t = fuse_xattr_types;
+	     t < fuse_xattr_types + ARRAY_SIZE(fuse_xattr_types);
+	     t++)
+		if (t->type == type)
+			return t;
+	return NULL;
+}
+
+static bool is_acl_type(unsigned type)
+{
+	return type == KEY_TYPE_XATTR_INDEX_POSIX_ACL_ACCESS ||
+		type == KEY_TYPE_XATTR_INDEX_POSIX_ACL_DEFAULT;
+}
+
+static bool acl_tag_is_short(unsigned tag)
+{
+	return tag == ACL_USER_OBJ ||
+		tag == ACL_GROUP_OBJ ||
+		tag == ACL_MASK ||
+		tag == ACL_OTHER;
+}
+
+/* Returns size of the converted ACL; only writes @out if it fits */
+static ssize_t acl_from_disk(const void *in, size_t in_size,
+			     void *out, size_t out_size)
+{
+	const void *p = in + sizeof(bch_acl_header), *end = in + in_size;
+	posix_acl_xattr_header *h = out;
+	size_t bytes = sizeof(*h);
+
+	if (in_size < sizeof(bch_acl_header) ||
+	    ((bch_acl_header *) in)->a_version != cpu_to_le32(BCH_ACL_VERSION))
+		return -EINVAL;
+
+	for (; p < end; bytes += sizeof(posix_acl_xattr_entry)) {
+		const bch_acl_entry *e = p;
+		unsigned tag = le16_to_cpu(e->e_tag);
+		size_t e_size = acl_tag_is_short(tag)
+			? sizeof(bch_acl_entry_short)
+			: sizeof(bch_acl_entry);
+
+		if (p + e_size > end)
+			return -EINVAL;
+
+		if (out && bytes + sizeof(posix_acl_xattr_entry) <= out_size) {
+			posix_acl_xattr_entry *o = out + bytes;
+
+			o->e_tag	= e->e_tag;
+			o->e_perm	= e->e_perm;
+			o->e_id		= acl_tag_is_short(tag)
+				? cpu_to_le32(ACL_UNDEFINED_ID)
+				: e->e_id;
+		}
+
+		p += e_size;
+	}
+
+	if (out && bytes <= out_size)
+		h->a_version = cpu_to_le32(POSIX_ACL_XATTR_VERSION);
+	return bytes;
+}
+
+static ssize_t acl_to_disk(const void *in, size_t in_size,
+			   void *out, size_t out_size)
+{
+	const posix_acl_xattr_header *h = in;
+	const posix_acl_xattr_entry *e = in + sizeof(*h),
+		*end = in + in_size;
+	bch_acl_header *o = out;
+	void *p = out + sizeof(*o);
+
+	if (in_size < sizeof(*h) ||
+	    (in_size - sizeof(*h)) % sizeof(*e) ||
+	    h->a_version != cpu_to_le32(POSIX_ACL_XATTR_VERSION))
+		return -EINVAL;
+
+	o->a_version = cpu_to_le32(BCH_ACL_VERSION);
+
+	for (; e < end; e++) {
+		unsigned tag = le16_to_cpu(e->e_tag);
+		bch_acl_entry *d = p;
+		size_t e_size = acl_tag_is_short(tag)
+			? sizeof(bch_acl_entry_short)
+			: sizeof(bch_acl_entry);
+
+		if (!acl_tag_is_short(tag) &&
+		    tag != ACL_USER &&
+		    tag != ACL_GROUP)
+			return -EINVAL;
+
+		if (p + e_size > out + out_size)
+			return -E2BIG;
+
+		d->e_tag	= e->e_tag;
+		d->e_perm	= e->e_perm;
+		if (!acl_tag_is_short(tag))
+			d->e_id	= e->e_id;
+		p += e_size;
+	}
+
+	return p - out;
+}
+
+/* Returns the size of the value; only copies it out if it fits in @buf */
+static int fuse_xattr_get_trans(struct btree_trans *trans, subvol_inum inum,
+				unsigned type, const char *name,
+				void *buf, size_t size)
+{
+	struct bch_inode_unpacked bi;
+	int ret = bch2_inode_find_by_inum_trans(trans, inum, &bi);
+	if (ret)
+		return ret;
+
+	struct bch_hash_info hash = bch2_hash_info_init(trans->c, &bi);
+	struct xattr_search_key search = X_SEARCH(type, name, strlen(name));
+	struct btree_iter iter;
+	struct bkey_s_c k = bch2_hash_lookup(trans, &iter, bch2_xattr_hash_desc,
+					     &hash, inum, &search, 0);
+	ret = bkey_err(k);
+	if (ret)
+		return ret;
+
+	struct bkey_s_c_xattr xattr = bkey_s_c_to_xattr(k);
+	ret = le16_to_cpu(xattr.v->x_val_len);
+	if (buf && ret <= size)
+		memcpy(buf, xattr_val(xattr.v), ret);
+	bch2_trans_iter_exit(trans, &iter);
+	return ret;
+}
+
+static int fuse_xattr_set_trans(struct btree_trans *trans, subvol_inum inum,
+				unsigned type, const char *name,
+				const void *value, size_t size, int flags)
+{
+	struct bch_inode_unpacked bi;
+	int ret = bch2_inode_find_by_inum_trans(trans, inum, &bi);
+	if (ret)
+		return ret;
+
+	struct bch_hash_info hash = bch2_hash_info_init(trans->c, &bi);
+
+	return bch2_xattr_set(trans, inum, &bi, &hash, name,
+			      value, size, type, flags);
+}
+
+static void bcachefs_fuse_setxattr(fuse_req_t req, fuse_ino_t ino,
 				   const char *name, const char *value,
 				   size_t size, int flags)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
+	subvol_inum inum = map_root_ino(ino);
+	char acl[U8_MAX * sizeof(u64)];
+	int ret;
+
+	fuse_log(FUSE_LOG_DEBUG, "fuse_setxattr(%llu, %s, %zu)\n",
+		 inum.inum, name, size);
+
+	const struct fuse_xattr_type *t = fuse_xattr_resolve_name(&name);
+	if (!t) {
+		ret = -EOPNOTSUPP;
+		goto err;
+	}
+
+	if (is_acl_type(t->type)) {
+		ssize_t acl_size = acl_to_disk(value, size, acl, sizeof(acl));
+		if (acl_size < 0) {
+			ret = acl_size;
+			goto err;
+		}
+
+		value	= acl;
+		size	= acl_size;
+	}
+
+	ret = bch2_trans_do(c, NULL, NULL, 0,
+		fuse_xattr_set_trans(trans, inum, t->type, name,
+				     value, size, flags));
+err:
+	fuse_reply_err(req, -bch2_err_class(ret));
 }
 
-static void bcachefs_fuse_getxattr(fuse_req_t req, fuse_ino_t inum,
+static void bcachefs_fuse_getxattr(fuse_req_t req, fuse_ino_t ino,
 				   const char *name, size_t size)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
+	subvol_inum inum = map_root_ino(ino);
+	char val[U8_MAX * sizeof(u64)];
+	int ret;
+
+	fuse_log(FUSE_LOG_DEBUG, "fuse_getxattr(%llu, %s, %zu)\n",
+		 inum.inum, name, size);
+
+	const struct fuse_xattr_type *t = fuse_xattr_resolve_name(&name);
+	if (!t) {
+		ret = -EOPNOTSUPP;
+		goto err;
+	}
+
+	ret = bch2_trans_run(c, lockrestart_do(trans,
+		fuse_xattr_get_trans(trans, inum, t->type, name,
+				     val, sizeof(val))));
+	if (bch2_err_matches(ret, ENOENT))
+		ret = -ENODATA;
+	if (ret < 0)
+		goto err;
+
+	const void *out = val;
+	size_t out_size = ret;
+	char *acl = NULL;
+
+	if (is_acl_type(t->type)) {
+		ssize_t acl_size = acl_from_disk(val, ret, NULL, 0);
+		if (acl_size < 0) {
+			ret = acl_size;
+			goto err;
+		}
+
+		acl = xmalloc(acl_size);
+		acl_from_disk(val, ret, acl, acl_size);
+		out		= acl;
+		out_size	= acl_size;
+	}
+
+	if (!size)
+		fuse_reply_xattr(req, out_size);
+	else if (out_size > size)
+		fuse_reply_err(req, ERANGE);
+	else
+		fuse_reply_buf(req, out, out_size);
+	free(acl);
+	return;
+err:
+	fuse_reply_err(req, -bch2_err_class(ret));
+}
+
+static int fuse_xattr_list_trans(struct btree_trans *trans, subvol_inum inum,
+				 struct printbuf *out)
+{
+	struct btree_iter iter;
+	struct bkey_s_c k;
+	u32 snapshot;
+	int ret;
+
+	printbuf_reset(out);
+
+	ret = bch2_subvolume_get_snapshot(trans, inum.subvol, &snapshot);
+	if (ret)
+		return ret;
+
+	for_each_btree_key_upto_norestart(trans, iter, BTREE_ID_xattrs,
+			   SPOS(inum.inum, 0, snapshot),
+			   POS(inum.inum, U64_MAX), 0, k, ret) {
+		if (k.k->type != KEY_TYPE_xattr)
+			continue;
+
+		struct bkey_s_c_xattr xattr = bkey_s_c_to_xattr(k);
+		const struct fuse_xattr_type *t =
+			fuse_xattr_type_lookup(xattr.v->x_type);
+		if (!t)
+			continue;
+
+		prt_str(out, t->prefix);
+		prt_bytes(out, xattr.v->x_name, xattr.v->x_name_len);
+		prt_char(out, '\0');
+	}
+	bch2_trans_iter_exit(trans, &iter);
 
-	fuse_reply_xattr(req, );
+	return ret ?: out->allocation_failure ? -ENOMEM : 0;
 }
 
-static void bcachefs_fuse_listxattr(fuse_req_t req, fuse_ino_t inum, size_t size)
+static void bcachefs_fuse_listxattr(fuse_req_t req, fuse_ino_t ino, size_t size)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
+	subvol_inum inum = map_root_ino(ino);
+	struct printbuf buf = PRINTBUF;
+
+	fuse_log(FUSE_LOG_DEBUG, "fuse_listxattr(%llu, %zu)\n", inum.inum, size);
+
+	int ret = bch2_trans_run(c, lockrestart_do(trans,
+			fuse_xattr_list_trans(trans, inum, &buf)));
+	if (ret)
+		fuse_reply_err(req, -bch2_err_class(ret));
+	else if (!size)
+		fuse_reply_xattr(req, buf.pos);
+	else if (buf.pos > size)
+		fuse_reply_err(req, ERANGE);
+	else
+		fuse_reply_buf(req, buf.buf, buf.pos);
+
+	printbuf_exit(&buf);
 }
 
-static void bcachefs_fuse_removexattr(fuse_req_t req, fuse_ino_t inum,
+static void bcachefs_fuse_removexattr(fuse_req_t req, fuse_ino_t ino,
 				      const char *name)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
+	subvol_inum inum = map_root_ino(ino);
+	int ret;
+
+	fuse_log(FUSE_LOG_DEBUG, "fuse_removexattr(%llu, %s)\n", inum.inum, name);
+
+	const struct fuse_xattr_type *t = fuse_xattr_resolve_name(&name);
+	if (!t) {
+		ret = -EOPNOTSUPP;
+		goto err;
+	}
+
+	ret = bch2_trans_do(c, NULL, NULL, 0,
+		fuse_xattr_set_trans(trans, inum, t->type, name,
+				     NULL, 0, XATTR_REPLACE));
+err:
+	fuse_reply_err(req, -bch2_err_class(ret));
 }
-#endif
 
 static void bcachefs_fuse_create(fuse_req_t req, fuse_ino_t dir_ino,
 				 const char *name, mode_t mode,
 				 struct fuse_file_info *fi)
 {
 	subvol_inum dir = map_root_ino(dir_ino);
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
 	struct bch_inode_unpacked new_inode;
 	int ret;
 
@@ -1047,7 +1570,7 @@ static void bcachefs_fuse_create(fuse_req_t req, fuse_ino_t dir_ino,
 	if (ret)
 		goto err;
 
-	struct fuse_entry_param e = inode_to_entry(c, &new_inode);
+	struct fuse_entry_param e = inode_to_entry(c, dir.subvol, &new_inode);
 	fuse_reply_create(req, &e, fi);
 	return;
 err:
@@ -1059,16 +1582,575 @@ static void bcachefs_fuse_write_buf(fuse_req_t req, fuse_ino_t inum,
 				    struct fuse_bufvec *bufv, off_t off,
 				    struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
+}
+#endif
+
+/* fallocate: */
+
+/* Set i_size, and update mtime/ctime */
+static int inode_set_size(struct bch_fs *c, subvol_inum inum, u64 new_size,
+			  bool only_grow)
+{
+	struct btree_trans *trans;
+	struct btree_iter iter;
+	struct bch_inode_unpacked inode_u;
+	int ret = 0;
+	u64 now;
+
+	trans = bch2_trans_get(c);
+retry:
+	bch2_trans_begin(trans);
+	now = bch2_current_time(c);
+
+	ret = bch2_inode_peek(trans, &iter, &inode_u, inum, BTREE_ITER_intent);
+	if (ret)
+		goto err;
+
+	if (!only_grow || new_size > inode_u.bi_size)
+		inode_u.bi_size = new_size;
+	inode_u.bi_mtime = now;
+	inode_u.bi_ctime = now;
+
+	ret =   bch2_inode_write(trans, &iter, &inode_u) ?:
+		bch2_trans_commit(trans, NULL, NULL,
+				  BCH_TRANS_COMMIT_no_enospc);
+	bch2_trans_iter_exit(trans, &iter);
+err:
+	if (bch2_err_matches(ret, BCH_ERR_transaction_restart))
+		goto retry;
+
+	bch2_trans_put(trans);
+	return ret;
+}
+
+/*
+ * Zero the part of a block that a punch or zero range only partially covers;
+ * @start and @end must be within the same block
+ */
+static int zero_partial_block(struct bch_fs *c, subvol_inum inum,
+			      u64 i_size, u64 start, u64 end)
+{
+	u64 block_start = round_down(start, block_bytes(c));
+	size_t written;
+	int ret;
+
+	if (start == end || block_start >= i_size)
+		return 0;
+
+	struct bch_io_opts io_opts;
+	ret = get_inode_io_opts(c, inum, &io_opts);
+	if (ret)
+		return ret;
+
+	void *buf = aligned_alloc(PAGE_SIZE, block_bytes(c));
+	BUG_ON(!buf);
+
+	ret = read_aligned(c, inum, block_bytes(c), block_start, buf);
+	if (ret)
+		goto out;
+
+	memset(buf + (start - block_start), 0, end - start);
+
+	ret = write_aligned(c, inum, io_opts, buf, block_bytes(c),
+			    block_start, 0, &written);
+out:
+	free(buf);
+	return ret;
+}
+
+static int fuse_fpunch(struct bch_fs *c, subvol_inum inum,
+		       u64 i_size, u64 offset, u64 len)
+{
+	u64 end		= offset + len;
+	u64 block_start	= round_up(offset, block_bytes(c));
+	u64 block_end	= round_down(end, block_bytes(c));
+	s64 i_sectors_delta = 0;
+	int ret = 0;
+
+	if (block_start > block_end) {
+		/* entirely within one block */
+		ret = zero_partial_block(c, inum, i_size, offset, end);
+	} else {
+		ret =   zero_partial_block(c, inum, i_size, offset, block_start) ?:
+			zero_partial_block(c, inum, i_size, block_end, end);
+
+		if (!ret && block_start < block_end)
+			ret = bch2_fpunch(c, inum,
+					  block_start >> 9, block_end >> 9,
+					  &i_sectors_delta);
+	}
+
+	return ret ?: inode_set_size(c, inum, i_size, false);
 }
 
-static void bcachefs_fuse_fallocate(fuse_req_t req, fuse_ino_t inum, int mode,
+/* Allocate (or with @zero, zero and allocate) sectors [start, end) */
+static int fuse_fallocate_range(struct bch_fs *c, subvol_inum inum,
+				bool zero, u64 start_sector, u64 end_sector)
+{
+	struct btree_trans *trans = bch2_trans_get(c);
+	struct btree_iter iter;
+	struct bpos end_pos = POS(inum.inum, end_sector);
+	struct bch_io_opts opts;
+	int ret = get_inode_io_opts(c, inum, &opts);
+	if (ret)
+		goto out;
+
+	bch2_trans_iter_init(trans, &iter, BTREE_ID_extents,
+			POS(inum.inum, start_sector),
+			BTREE_ITER_slots|BTREE_ITER_intent);
+
+	while (!ret && bkey_lt(iter.pos, end_pos)) {
+		s64 i_sectors_delta = 0;
+		struct bkey_s_c k;
+		u32 snapshot;
+
+		bch2_trans_begin(trans);
+
+		ret = bch2_subvolume_get_snapshot(trans, inum.subvol, &snapshot);
+		if (ret)
+			goto bkey_err;
+
+		bch2_btree_iter_set_snapshot(&iter, snapshot);
+
+		k = bch2_btree_iter_peek_slot(&iter);
+		if ((ret = bkey_err(k)))
+			goto bkey_err;
+
+		/* already reserved */
+		if (bkey_extent_is_reservation(k) &&
+		    bch2_bkey_nr_ptrs_fully_allocated(k) >= opts.data_replicas) {
+			bch2_btree_iter_advance(&iter);
+			continue;
+		}
+
+		if (bkey_extent_is_data(k.k) && !zero) {
+			bch2_btree_iter_advance(&iter);
+			continue;
+		}
+
+		u64 sectors = bpos_min(k.k->p, end_pos).offset - iter.pos.offset;
+
+		ret = bch2_extent_fallocate(trans, inum, &iter, sectors, opts,
+					    &i_sectors_delta, writepoint_hashed(0));
+bkey_err:
+		if (bch2_err_matches(ret, BCH_ERR_transaction_restart))
+			ret = 0;
+	}
+
+	if (bch2_err_matches(ret, ENOSPC) && zero) {
+		s64 i_sectors_delta = 0;
+
+		bch2_fpunch_at(trans, &iter, inum, end_sector, &i_sectors_delta);
+	}
+
+	bch2_trans_iter_exit(trans, &iter);
+out:
+	bch2_trans_put(trans);
+	return ret;
+}
+
+static int fuse_fallocate(struct bch_fs *c, subvol_inum inum, u64 i_size,
+			  int mode, u64 offset, u64 len)
+{
+	u64 end		= offset + len;
+	u64 block_start	= round_down(offset,	block_bytes(c));
+	u64 block_end	= round_up(end,		block_bytes(c));
+	bool zero	= mode & FALLOC_FL_ZERO_RANGE;
+	int ret;
+
+	if (zero) {
+		block_start	= round_up(offset,	block_bytes(c));
+		block_end	= round_down(end,	block_bytes(c));
+
+		if (block_start > block_end)
+			return zero_partial_block(c, inum, i_size, offset, end) ?:
+				inode_set_size(c, inum, i_size, false);
+
+		ret =   zero_partial_block(c, inum, i_size, offset, block_start) ?:
+			zero_partial_block(c, inum, i_size, block_end, end);
+		if (ret)
+			return ret;
+	}
+
+	ret = fuse_fallocate_range(c, inum, zero, block_start >> 9, block_end >> 9);
+
+	/* On -ENOSPC in ZERO_RANGE mode, we still want to do the inode update: */
+	if (ret && !(bch2_err_matches(ret, ENOSPC) && zero))
+		return ret;
+
+	int ret2 = inode_set_size(c, inum,
+				  mode & FALLOC_FL_KEEP_SIZE ? i_size : end,
+				  true);
+	return ret ?: ret2;
+}
+
+static int fuse_fcollapse_finsert(struct bch_fs *c, subvol_inum inum,
+				  u64 i_size, u64 offset, u64 len,
+				  bool insert)
+{
+	s64 i_sectors_delta = 0;
+
+	if ((offset | len) & (block_bytes(c) - 1))
+		return -EINVAL;
+
+	if (insert ? offset >= i_size : offset + len >= i_size)
+		return -EINVAL;
+
+	/* bch2_fcollapse_finsert() updates i_size: */
+	return bch2_fcollapse_finsert(c, inum, offset >> 9, len >> 9,
+				      insert, &i_sectors_delta);
+}
+
+static void bcachefs_fuse_fallocate(fuse_req_t req, fuse_ino_t ino, int mode,
 				    off_t offset, off_t length,
 				    struct fuse_file_info *fi)
 {
-	struct bch_fs *c = fuse_req_userdata(req);
+	struct bch_fs *c = fuse_req_fs(req);
+	subvol_inum inum = map_root_ino(ino);
+	struct bch_inode_unpacked bi;
+	int ret;
+
+	fuse_log(FUSE_LOG_DEBUG, "bcachefs_fuse_fallocate(%llu, %x, %lli, %lli)\n",
+		 inum.inum, mode, (s64) offset, (s64) length);
+
+	if (offset < 0 || length <= 0) {
+		ret = -EINVAL;
+		goto err;
+	}
+
+	ret = bch2_inode_find_by_inum(c, inum, &bi);
+	if (ret)
+		goto err;
+
+	if (!S_ISREG(bi.bi_mode)) {
+		ret = -ENODEV;
+		goto err;
+	}
+
+	if (!(mode & ~(FALLOC_FL_KEEP_SIZE|FALLOC_FL_ZERO_RANGE)))
+		ret = fuse_fallocate(c, inum, bi.bi_size, mode, offset, length);
+	else if (mode == (FALLOC_FL_PUNCH_HOLE|FALLOC_FL_KEEP_SIZE))
+		ret = fuse_fpunch(c, inum, bi.bi_size, offset, length);
+	else if (mode == FALLOC_FL_INSERT_RANGE)
+		ret = fuse_fcollapse_finsert(c, inum, bi.bi_size, offset, length, true);
+	else if (mode == FALLOC_FL_COLLAPSE_RANGE)
+		ret = fuse_fcollapse_finsert(c, inum, bi.bi_size, offset, length, false);
+	else
+		ret = -EOPNOTSUPP;
+err:
+	fuse_reply_err(req, -bch2_err_class(ret));
+}
+
+/* copy_file_range: */
+
+static void bcachefs_fuse_copy_file_range(fuse_req_t req,
+					  fuse_ino_t ino_in, off_t off_in,
+					  struct fuse_file_info *fi_in,
+					  fuse_ino_t ino_out, off_t off_out,
+					  struct fuse_file_info *fi_out,
+					  size_t len, int flags)
+{
+	struct bch_fs *c = fuse_req_fs(req);
+	subvol_inum src = map_root_ino(ino_in);
+	subvol_inum dst = map_root_ino(ino_out);
+	struct bch_inode_unpacked src_u, dst_u;
+	s64 i_sectors_delta = 0;
+	s64 ret;
+
+	fuse_log(FUSE_LOG_DEBUG, "bcachefs_fuse_copy_file_range(%llu, %lli, %llu, %lli, %zu)\n",
+		 src.inum, (s64) off_in, dst.inum, (s64) off_out, len);
+
+	if (flags) {
+		ret = -EINVAL;
+		goto err;
+	}
+
+	/*
+	 * Reflinking works in units of blocks; for unaligned copies, the kernel
+	 * falls back to copying via read and write:
+	 */
+	if ((off_in | off_out) & (block_bytes(c) - 1)) {
+		ret = -EOPNOTSUPP;
+		goto err;
+	}
+
+	ret = bch2_inode_find_by_inum(c, src, &src_u);
+	if (ret)
+		goto err;
+
+	if (off_in >= src_u.bi_size) {
+		fuse_reply_write(req, 0);
+		return;
+	}
+
+	len = min_t(u64, len, src_u.bi_size - off_in);
+
+	if (src.inum == dst.inum &&
+	    off_in < off_out + (s64) len &&
+	    off_out < off_in + (s64) len) {
+		ret = -EINVAL;
+		goto err;
+	}
+
+	ret = bch2_inode_find_by_inum(c, dst, &dst_u);
+	if (ret)
+		goto err;
+
+	/*
+	 * A partial block at the end can only be remapped whole if it's the end
+	 * of both files - otherwise it would overwrite dst past off_out + len.
+	 * If not, remap the whole blocks and return a short copy; the next call,
+	 * for just the tail, gets EOPNOTSUPP and the kernel copies it by read
+	 * and write:
+	 */
+	u64 aligned_len = off_in + len == src_u.bi_size &&
+		off_out + len >= dst_u.bi_size
+		? round_up((u64) len, block_bytes(c))
+		: round_down((u64) len, block_bytes(c));
+
+	if (!aligned_len) {
+		ret = -EOPNOTSUPP;
+		goto err;
+	}
+
+	ret = bch2_remap_range(c,
+			       dst, off_out >> 9,
+			       src, off_in >> 9,
+			       aligned_len >> 9,
+			       off_out + min((u64) len, aligned_len), &i_sectors_delta);
+	if (ret < 0)
+		goto err;
+
+	/* when rounded up, we remapped slightly more than requested */
+	ret = min((u64) ret << 9, (u64) len);
+
+	int ret2 = inode_update_times(c, dst);
+	if (ret2) {
+		ret = ret2;
+		goto err;
+	}
+
+	fuse_reply_write(req, ret);
+	return;
+err:
+	fuse_reply_err(req, -bch2_err_class(ret));
+}
+
+/* fiemap: */
+
+struct fuse_fiemap {
+	struct fiemap		*fm;
+	u32			max_extents;
+	struct fiemap_extent	*last;
+};
+
+/* Returns 1 when there's no more room */
+static int fuse_fiemap_add(struct fuse_fiemap *f, u64 logical, u64 phys,
+			   u64 len, u32 flags)
+{
+	if (f->fm->fm_mapped_extents == f->max_extents) {
+		if (!f->max_extents) {
+			/* just counting: */
+			f->fm->fm_mapped_extents++;
+			return 0;
+		}
+		return 1;
+	}
+
+	f->last = &f->fm->fm_extents[f->fm->fm_mapped_extents++];
+	*f->last = (struct fiemap_extent) {
+		.fe_logical	= logical,
+		.fe_physical	= phys,
+		.fe_length	= len,
+		.fe_flags	= flags,
+	};
+	return 0;
+}
+
+static int fuse_fiemap_fill_extent(struct bch_fs *c, struct fuse_fiemap *f,
+				   struct bkey_s_c k)
+{
+	u64 start = bkey_start_offset(k.k) << 9;
+	u64 len = k.k->size << 9;
+	int ret;
+
+	if (bkey_extent_is_direct_data(k.k)) {
+		struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+		const union bch_extent_entry *entry;
+		struct extent_ptr_decoded p;
+		unsigned flags = k.k->type == KEY_TYPE_reflink_v
+			? FIEMAP_EXTENT_SHARED : 0;
+
+		bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+			unsigned flags2 = flags;
+			u64 offset = p.ptr.offset;
+
+			if (p.ptr.unwritten)
+				flags2 |= FIEMAP_EXTENT_UNWRITTEN;
+
+			if (p.crc.compression_type)
+				flags2 |= FIEMAP_EXTENT_ENCODED;
+			else
+				offset += p.crc.offset;
+
+			if ((offset & (block_sectors(c) - 1)) ||
+			    (k.k->size & (block_sectors(c) - 1)))
+				flags2 |= FIEMAP_EXTENT_NOT_ALIGNED;
+
+			ret = fuse_fiemap_add(f, start, offset << 9, len, flags2);
+			if (ret)
+				return ret;
+		}
+
+		return 0;
+	} else if (bkey_extent_is_inline_data(k.k)) {
+		return fuse_fiemap_add(f, start, 0, len, FIEMAP_EXTENT_DATA_INLINE);
+	} else {
+		return fuse_fiemap_add(f, start, 0, len,
+				       FIEMAP_EXTENT_DELALLOC|FIEMAP_EXTENT_UNWRITTEN);
+	}
+}
+
+static int fuse_fiemap_trans(struct btree_trans *trans, subvol_inum inum,
+			     struct fuse_fiemap *f)
+{
+	struct bch_fs *c = trans->c;
+	struct fiemap *fm = f->fm;
+	struct btree_iter iter;
+	struct bkey_s_c k;
+	struct bkey_buf cur;
+	u32 snapshot;
+	int ret;
+
+	fm->fm_mapped_extents = 0;
+	f->last = NULL;
+
+	ret = bch2_subvolume_get_snapshot(trans, inum.subvol, &snapshot);
+	if (ret)
+		return ret;
+
+	u64 start = fm->fm_start >> 9;
+	u64 end = fm->fm_start + fm->fm_length < fm->fm_start
+		? U64_MAX
+		: (fm->fm_start + fm->fm_length) >> 9;
+
+	bch2_bkey_buf_init(&cur);
+
+	for_each_btree_key_upto_norestart(trans, iter, BTREE_ID_extents,
+				SPOS(inum.inum, start, snapshot),
+				POS(inum.inum, end), 0, k, ret) {
+		enum btree_id data_btree = BTREE_ID_extents;
+
+		if (!bkey_extent_is_data(k.k) &&
+		    k.k->type != KEY_TYPE_reservation)
+			continue;
+
+		unsigned offset_into_extent = iter.pos.offset - bkey_start_offset(k.k);
+		unsigned sectors = k.k->size - offset_into_extent;
+
+		bch2_bkey_buf_reassemble(&cur, c, k);
+
+		ret = bch2_read_indirect_extent(trans, &data_btree,
+					&offset_into_extent, &cur);
+		if (ret)
+			break;
+
+		k = bkey_i_to_s_c(cur.k);
+		sectors = min(sectors, k.k->size - offset_into_extent);
+
+		bch2_cut_front(POS(k.k->p.inode,
+				   bkey_start_offset(k.k) + offset_into_extent),
+			       cur.k);
+		bch2_key_resize(&cur.k->k, sectors);
+		cur.k->k.p = iter.pos;
+		cur.k->k.p.offset += cur.k->k.size;
+
+		ret = fuse_fiemap_fill_extent(c, f, bkey_i_to_s_c(cur.k));
+		if (ret)
+			break;
+	}
+	bch2_trans_iter_exit(trans, &iter);
+	bch2_bkey_buf_exit(&cur, c);
+
+	if (ret == 1) {
+		/* out of room, not an error */
+		ret = 0;
+	} else if (!ret && f->last) {
+		f->last->fe_flags |= FIEMAP_EXTENT_LAST;
+	}
+
+	return ret;
+}
+
+/*
+ * The kernel only passes through FS_IOC_FIEMAP for the fixed size header in
+ * restricted ioctl mode, so callers get as many extents as fit in out_bufsz -
+ * normally just a count, with fm_extent_count == 0.
+ */
+static void bcachefs_fuse_fiemap(fuse_req_t req, fuse_ino_t ino,
+				 const void *in_buf, size_t in_bufsz,
+				 size_t out_bufsz)
+{
+	struct bch_fs *c = fuse_req_fs(req);
+	subvol_inum inum = map_root_ino(ino);
+	int ret;
+
+	if (in_bufsz < sizeof(struct fiemap) ||
+	    out_bufsz < sizeof(struct fiemap)) {
+		fuse_reply_err(req, EINVAL);
+		return;
+	}
+
+	const struct fiemap *in = in_buf;
+	u32 max_extents = min_t(u64, in->fm_extent_count,
+				(out_bufsz - sizeof(struct fiemap)) /
+				sizeof(struct fiemap_extent));
+	size_t size = sizeof(struct fiemap) +
+		max_extents * sizeof(struct fiemap_extent);
+
+	struct fuse_fiemap f = {
+		.fm		= xcalloc(1, size),
+		.max_extents	= max_extents,
+	};
+	*f.fm = *in;
+	f.fm->fm_mapped_extents = 0;
+
+	if (in->fm_flags & ~FIEMAP_FLAG_SYNC) {
+		ret = -EBADR;
+		goto err;
+	}
+
+	ret = bch2_trans_run(c, lockrestart_do(trans,
+			fuse_fiemap_trans(trans, inum, &f)));
+	if (ret)
+		goto err;
+
+	fuse_reply_ioctl(req, 0, f.fm,
+			 sizeof(struct fiemap) +
+			 min(f.fm->fm_mapped_extents, max_extents) *
+			 sizeof(struct fiemap_extent));
+	free(f.fm);
+	return;
+err:
+	fuse_reply_err(req, -bch2_err_class(ret));
+	free(f.fm);
+}
+
+static void bcachefs_fuse_ioctl(fuse_req_t req, fuse_ino_t ino,
+				unsigned int cmd, void *arg,
+				struct fuse_file_info *fi, unsigned flags,
+				const void *in_buf, size_t in_bufsz,
+				size_t out_bufsz)
+{
+	switch (cmd) {
+	case FS_IOC_FIEMAP:
+		bcachefs_fuse_fiemap(req, ino, in_buf, in_bufsz, out_bufsz);
+		break;
+	default:
+		fuse_reply_err(req, ENOTTY);
+	}
 }
-#endif
 
 static const struct fuse_lowlevel_ops bcachefs_fuse_ops = {
 	.init		= bcachefs_fuse_init,
@@ -1096,10 +2178,10 @@ static const struct fuse_lowlevel_ops bcachefs_fuse_ops = {
 	//.releasedir	= bcachefs_fuse_releasedir,
 	//.fsyncdir	= bcachefs_fuse_fsyncdir,
 	.statfs		= bcachefs_fuse_statfs,
-	//.setxattr	= bcachefs_fuse_setxattr,
-	//.getxattr	= bcachefs_fuse_getxattr,
-	//.listxattr	= bcachefs_fuse_listxattr,
-	//.removexattr	= bcachefs_fuse_removexattr,
+	.setxattr	= bcachefs_fuse_setxattr,
+	.getxattr	= bcachefs_fuse_getxattr,
+	.listxattr	= bcachefs_fuse_listxattr,
+	.removexattr	= bcachefs_fuse_removexattr,
 	.create		= bcachefs_fuse_create,
 
 	/* posix locks: */
@@ -1108,7 +2190,9 @@ static const struct fuse_lowlevel_ops bcachefs_fuse_ops = {
 	.setlk		= bcachefs_fuse_setlk,
 #endif
 	//.write_buf	= bcachefs_fuse_write_buf,
-	//.fallocate	= bcachefs_fuse_fallocate,
+	.fallocate	= bcachefs_fuse_fallocate,
+	.copy_file_range = bcachefs_fuse_copy_file_range,
+	.ioctl		= bcachefs_fuse_ioctl,
 
 };
 
@@ -1120,6 +2204,10 @@ struct bf_context {
 	char            *devices_str;
 	char            **devices;
 	int             nr_devices;
+	unsigned        max_threads;
+	int             direct_io;
+	int             rescue;
+	char            *subvol;
 };
 
 static void bf_context_free(struct bf_context *ctx)
@@ -1130,9 +2218,16 @@ static void bf_context_free(struct bf_context *ctx)
 	for (i = 0; i < ctx->nr_devices; ++i)
 		free(ctx->devices[i]);
 	free(ctx->devices);
+	free(ctx->subvol);
 }
 
+#define BF_OPT(t, p, v) { t, offsetof(struct bf_context, p), v }
+
 static struct fuse_opt bf_opts[] = {
+	BF_OPT("--max-threads=%u",	max_threads,	0),
+	BF_OPT("--direct-io",		direct_io,	1),
+	BF_OPT("--rescue",		rescue,		1),
+	BF_OPT("--subvol=%s",		subvol,		0),
 	FUSE_OPT_END
 };
 
@@ -1190,11 +2285,56 @@ static void tokenize_devices(struct bf_context *ctx)
 	free(devices_str);
 }
 
+/* Look up a subvolume by path, from the root of the filesystem */
+static int fuse_lookup_subvol(struct bch_fs *c, const char *path,
+			      subvol_inum *ret_inum)
+{
+	char *p = strdup(path), *tmp = p, *name;
+	subvol_inum inum = BCACHEFS_ROOT_SUBVOL_INUM;
+	struct bch_inode_unpacked bi;
+	int ret = bch2_inode_find_by_inum(c, inum, &bi);
+
+	while (!ret && (name = strsep(&tmp, "/"))) {
+		if (!*name)
+			continue;
+
+		if (!S_ISDIR(bi.bi_mode)) {
+			ret = -ENOTDIR;
+			break;
+		}
+
+		struct bch_hash_info hash_info = bch2_hash_info_init(c, &bi);
+		struct qstr qstr = QSTR(name);
+
+		ret =   bch2_dirent_lookup(c, inum, &hash_info, &qstr, &inum) ?:
+			bch2_inode_find_by_inum(c, inum, &bi);
+	}
+	free(p);
+
+	if (ret)
+		return ret;
+
+	/* subvolume root inodes are the only ones with bi_subvol set: */
+	if (bi.bi_subvol != inum.subvol)
+		return -EINVAL;
+
+	*ret_inum = inum;
+	return 0;
+}
+
 static void usage(char *argv[])
 {
 	printf("Usage: %s fusemount [options] <dev>[:dev2:...] <mountpoint>\n",
 	       argv[0]);
-	printf("\n");
+	printf("\n"
+	       "bcachefs options:\n"
+	       "    --max-threads=N        Maximum number of worker threads (default 10)\n"
+	       "    --direct-io            Bypass the page cache, and don't use writeback caching\n"
+	       "    --rescue               Mount read only, ignoring metadata errors, and return\n"
+	       "                           partial reads for files with unreadable data\n"
+	       "    --subvol=PATH          Mount the subvolume or snapshot at PATH, relative to\n"
+	       "                           the root of the filesystem, instead of the root\n"
+	       "\n");
 }
 
 int cmd_fusemount(int argc, char *argv[])
@@ -1240,6 +2380,21 @@ int cmd_fusemount(int argc, char *argv[])
 		goto out;
 	}
 	tokenize_devices(&ctx);
+	fuse_direct_io = ctx.direct_io;
+	fuse_rescue = ctx.rescue;
+
+	if (fuse_rescue) {
+		opt_set(bch_opts, read_only,	true);
+		opt_set(bch_opts, nochanges,	true);
+		opt_set(bch_opts, norecovery,	true);
+		opt_set(bch_opts, degraded,	true);
+		opt_set(bch_opts, very_degraded, true);
+		opt_set(bch_opts, errors,	BCH_ON_ERROR_continue);
+		opt_set(bch_opts, fix_errors,	FSCK_FIX_no);
+
+		fuse_opt_add_arg(&args, "-o");
+		fuse_opt_add_arg(&args, "ro");
+	}
 
 	struct printbuf fsname = PRINTBUF;
 	prt_printf(&fsname, "fsname=");
@@ -1262,6 +2417,18 @@ int cmd_fusemount(int argc, char *argv[])
 		die("error opening %s: %s", ctx.devices_str,
 		    bch2_err_str(PTR_ERR(c)));
 
+	if (ctx.subvol) {
+		ret = fuse_lookup_subvol(c, ctx.subvol, &fuse_root);
+		if (ret)
+			die("error looking up subvolume %s: %s", ctx.subvol,
+			    ret == -EINVAL ? "not a subvolume" : bch2_err_str(ret));
+
+		printf("Mounting subvolume %u\n", fuse_root.subvol);
+	}
+
+	if (rhashtable_init(&fuse_inos_table, &fuse_ino_params))
+		die("rhashtable_init err");
+
 	/* Fuse */
 	struct fuse_session *se =
 		fuse_session_new(&args, &bcachefs_fuse_ops,
@@ -1285,12 +2452,27 @@ int cmd_fusemount(int argc, char *argv[])
 
 	fuse_daemonize(fuse_opts.foreground);
 
-	ret = fuse_session_loop(se);
+	if (fuse_opts.singlethread) {
+		ret = fuse_session_loop(se);
+	} else {
+		struct fuse_loop_config *loop_config = fuse_loop_cfg_create();
+		if (!loop_config)
+			die("fuse_loop_cfg_create err: %m");
+
+		fuse_loop_cfg_set_clone_fd(loop_config, fuse_opts.clone_fd);
+		fuse_loop_cfg_set_idle_threads(loop_config, fuse_opts.max_idle_threads);
+		fuse_loop_cfg_set_max_threads(loop_config,
+					      ctx.max_threads ?: fuse_opts.max_threads);
+
+		ret = fuse_session_loop_mt(se, loop_config);
+		fuse_loop_cfg_destroy(loop_config);
+	}
 
 	/* Cleanup */
 	fuse_session_unmount(se);
 	fuse_remove_signal_handlers(se);
 	fuse_session_destroy(se);
+	fuse_inos_exit();
 
 out:
 	free(fuse_opts.mountpoint);
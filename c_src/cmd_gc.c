@@ -0,0 +1,225 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs_ioctl.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/util.h"
+
+struct gc_dev_usage {
+	unsigned		idx;
+	char			*name;
+	u64			bucket_size;
+	u64			free;
+	u64			cached;
+	u64			need_gc_gens;
+	u64			need_discard;
+};
+
+typedef DARRAY(struct gc_dev_usage) gc_usage;
+
+static void fs_gc_usage(void)
+{
+	puts("bcachefs fs gc - run garbage collection on a mounted filesystem\n"
+	     "Usage: bcachefs fs gc [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Runs bucket generation garbage collection, then kicks the allocator to\n"
+	     "invalidate cached data and issue discards, and reports how many buckets\n"
+	     "were freed on each device. Space held by fragmented buckets is reclaimed\n"
+	     "by copygc, which runs on its own.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -g, --gens-only              Only run gc gens; don't invalidate or discard\n"
+	     "  -t, --timeout=seconds        How long to wait for invalidates and discards\n"
+	     "                               to finish (default 10)\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static u64 gc_now_ns(void)
+{
+	struct timespec ts;
+
+	clock_gettime(CLOCK_MONOTONIC, &ts);
+	return ts.tv_sec * NSEC_PER_SEC + ts.tv_nsec;
+}
+
+static void gc_trigger(struct bchfs_handle fs, const char *name)
+{
+	char *path = mprintf("internal/trigger_%s", name);
+	int fd = openat(fs.sysfs_fd, path, O_WRONLY);
+
+	if (fd < 0 || write(fd, "1", 1) != 1)
+		die("error triggering %s: %m", name);
+
+	close(fd);
+	free(path);
+}
+
+static gc_usage gc_usage_get(struct bchfs_handle fs, dev_names *devs)
+{
+	gc_usage ret = {};
+
+	darray_for_each(*devs, d) {
+		struct bch_ioctl_dev_usage_v2 *u = bchu_dev_usage(fs, d->idx);
+		struct gc_dev_usage g = {
+			.idx		= d->idx,
+			.name		= d->dev ?: d->label,
+			.bucket_size	= u->bucket_size,
+			.free		= u->d[BCH_DATA_free].buckets,
+			.cached		= u->d[BCH_DATA_cached].buckets,
+			.need_gc_gens	= u->d[BCH_DATA_need_gc_gens].buckets,
+			.need_discard	= u->d[BCH_DATA_need_discard].buckets,
+		};
+
+		if (darray_push(&ret, g))
+			die("memory allocation failure");
+		free(u);
+	}
+
+	return ret;
+}
+
+/* Invalidates and discards are done asynchronously: */
+static bool gc_usage_settled(gc_usage *prev, gc_usage *cur)
+{
+	for (unsigned i = 0; i < cur->nr; i++)
+		if (cur->data[i].free != prev->data[i].free ||
+		    cur->data[i].need_discard)
+			return false;
+	return true;
+}
+
+static void gc_usage_to_text(struct printbuf *out, gc_usage *before, gc_usage *after)
+{
+	s64 reclaimed = 0;
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 20);
+	for (unsigned i = 0; i < 5; i++)
+		printbuf_tabstop_push(out, 14);
+
+	prt_printf(out, "device\tfree before\rfree after\rreclaimed\rcached\rneed discard\r\n");
+
+	for (unsigned i = 0; i < after->nr; i++) {
+		struct gc_dev_usage *b = &before->data[i], *a = &after->data[i];
+		s64 r = (s64) a->free - (s64) b->free;
+
+		prt_printf(out, "%s (%u)\t", a->name ?: "(unknown)", a->idx);
+		prt_printf(out, "%llu\r%llu\r%lli\r%llu\r%llu\r\n",
+			   b->free, a->free, r, a->cached, a->need_discard);
+		reclaimed += r * (s64) a->bucket_size;
+	}
+
+	prt_str(out, "reclaimed: ");
+	tools_prt_units_s64(out, reclaimed << 9);
+	prt_newline(out);
+}
+
+int cmd_fs_gc(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "gens-only",		no_argument,		NULL, 'g' },
+		{ "timeout",		required_argument,	NULL, 't' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct printbuf buf = PRINTBUF;
+	bool gens_only = false;
+	unsigned timeout = 10;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "gt:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'g':
+			gens_only = true;
+			break;
+		case 't':
+			if (kstrtouint(optarg, 10, &timeout))
+				die("invalid timeout %s", optarg);
+			break;
+		case 'h':
+			fs_gc_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			fs_gc_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	struct bchfs_handle fs = bcache_fs_open(fs_path);
+	dev_names devs = bchu_fs_get_devices(fs);
+	gc_usage before = gc_usage_get(fs, &devs), after;
+
+	u64 start = gc_now_ns();
+
+	/* synchronous: */
+	gc_trigger(fs, "gc");
+
+	u64 gc_done = gc_now_ns();
+
+	prt_str(&buf, "gc gens: ");
+	bch2_pr_time_units(&buf, gc_done - start);
+	prt_newline(&buf);
+
+	if (!gens_only) {
+		gc_trigger(fs, "invalidates");
+		gc_trigger(fs, "discards");
+
+		gc_usage prev = gc_usage_get(fs, &devs);
+		u64 settled = gc_now_ns();
+
+		while (1) {
+			usleep(100 * 1000);
+
+			after = gc_usage_get(fs, &devs);
+			u64 now = gc_now_ns();
+
+			if (!gc_usage_settled(&prev, &after))
+				settled = now;
+
+			darray_exit(&prev);
+			prev = after;
+
+			if (now - settled >= NSEC_PER_SEC)
+				break;
+
+			if (now - gc_done >= (u64) timeout * NSEC_PER_SEC) {
+				prt_str(&buf, "timed out waiting for invalidates and discards\n");
+				break;
+			}
+		}
+
+		prt_str(&buf, "invalidates and discards: ");
+		bch2_pr_time_units(&buf, settled - gc_done);
+		prt_newline(&buf);
+	} else {
+		after = gc_usage_get(fs, &devs);
+	}
+
+	prt_newline(&buf);
+	gc_usage_to_text(&buf, &before, &after);
+	printf("%s", buf.buf);
+
+	printbuf_exit(&buf);
+	darray_exit(&before);
+	darray_exit(&after);
+	darray_for_each(devs, d) {
+		free(d->dev);
+		free(d->label);
+	}
+	darray_exit(&devs);
+	bcache_fs_close(fs);
+	return 0;
+}
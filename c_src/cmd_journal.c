@@ -0,0 +1,346 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/alloc_background.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/journal.h"
+#include "libbcachefs/journal_reclaim.h"
+#include "libbcachefs/journal_sb.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/super.h"
+#include "libbcachefs/super-io.h"
+
+int journal_usage(void)
+{
+	puts("bcachefs journal - manage the journal\n"
+	     "Usage: bcachefs journal <CMD> [OPTION]...\n"
+	     "\n"
+	     "Commands:\n"
+	     "  resize                  grow the journal on a device\n"
+	     "  move                    move the journal from one device to another (unmounted)\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+	return 0;
+}
+
+static u64 journal_size_parse(const char *size_str)
+{
+	u64 size;
+
+	if (bch2_strtoull_h(size_str, &size))
+		die("invalid size %s", size_str);
+	return size;
+}
+
+/*
+ * The kernel doesn't shrink the journal - bch2_set_nr_journal_buckets() does
+ * nothing if asked to - so check here, rather than silently succeed:
+ */
+static bool journal_resize_check(const char *dev, u64 nbuckets, u64 cur,
+				 u64 dev_nbuckets, unsigned bucket_bytes)
+{
+	struct printbuf buf = PRINTBUF;
+
+	if (nbuckets < BCH_JOURNAL_BUCKETS_MIN) {
+		tools_prt_units_u64(&buf, (u64) BCH_JOURNAL_BUCKETS_MIN * bucket_bytes);
+		die("the journal must be at least %u buckets (%s) on a device",
+		    BCH_JOURNAL_BUCKETS_MIN, buf.buf);
+	}
+
+	if (nbuckets > U32_MAX || nbuckets >= dev_nbuckets)
+		die("%llu buckets is too big for %s", nbuckets, dev);
+
+	tools_prt_units_u64(&buf, cur * bucket_bytes);
+
+	if (nbuckets < cur)
+		die("the journal on %s is %llu buckets (%s), and shrinking it isn't supported",
+		    dev, cur, buf.buf);
+
+	if (nbuckets == cur) {
+		printf("the journal on %s is already %llu buckets (%s)\n", dev, cur, buf.buf);
+		printbuf_exit(&buf);
+		return false;
+	}
+
+	printbuf_reset(&buf);
+	tools_prt_units_u64(&buf, nbuckets * bucket_bytes);
+	printf("resizing journal on %s from %llu to %llu buckets (%s)\n",
+	       dev, cur, nbuckets, buf.buf);
+	printbuf_exit(&buf);
+	return true;
+}
+
+/* journal_v2 is per device: @sb must be that device's superblock */
+static u64 sb_journal_nr_buckets(struct bch_sb *sb)
+{
+	struct bch_sb_field_journal_v2 *j = bch2_sb_field_get(sb, journal_v2);
+	u64 nr = 0;
+
+	if (!j)
+		return bch2_nr_journal_buckets(bch2_sb_field_get(sb, journal));
+
+	for (unsigned i = 0; i < bch2_sb_field_journal_v2_nr_entries(j); i++)
+		nr += le64_to_cpu(j->d[i].nr);
+	return nr;
+}
+
+/* journal resize */
+
+static void journal_resize_usage(void)
+{
+	puts("bcachefs journal resize - grow the journal on a device\n"
+	     "Usage: bcachefs journal resize [OPTION]... <filesystem> <device|devid> <size>\n"
+	     "\n"
+	     "filesystem is a mountpoint, or the devices of an unmounted filesystem\n"
+	     "separated by colons. size is in bytes, with optional units (e.g. 1G), and\n"
+	     "is rounded down to a whole number of buckets; it must be at least 8 buckets.\n"
+	     "The journal can't be shrunk.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_journal_resize(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			journal_resize_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			journal_resize_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	char *dev_str = arg_pop();
+	if (!dev_str)
+		die("Please supply a device");
+
+	char *size_str = arg_pop();
+	if (!size_str)
+		die("Please supply a journal size");
+
+	if (argc)
+		die("Too many arguments");
+
+	u64 size = journal_size_parse(size_str);
+
+	if (fs_arg_is_mounted(fs_path)) {
+		struct bchfs_handle fs = bcache_fs_open(fs_path);
+		char *end;
+		int dev_idx = strtoul(dev_str, &end, 10);
+
+		if (!*dev_str || *end) {
+			dev_idx = bchu_dev_path_to_idx(fs, dev_str);
+			if (dev_idx < 0)
+				die("%s does not seem to be a member of %s", dev_str, fs_path);
+		}
+
+		struct bch_sb *sb = bchu_read_super(fs, -1);
+		if (dev_idx >= sb->nr_devices || !bch2_member_exists(sb, dev_idx))
+			die("no device %u in %s", dev_idx, fs_path);
+
+		struct bch_member m = bch2_sb_member_get(sb, dev_idx);
+		unsigned bucket_bytes = le16_to_cpu(m.bucket_size) << 9;
+		free(sb);
+
+		sb = bchu_read_super(fs, dev_idx);
+		u64 cur = sb_journal_nr_buckets(sb);
+		free(sb);
+
+		u64 nbuckets = size / bucket_bytes;
+		if (journal_resize_check(dev_str, nbuckets, cur,
+					 le64_to_cpu(m.nbuckets), bucket_bytes))
+			bchu_disk_resize_journal(fs, dev_idx, nbuckets);
+
+		bcache_fs_close(fs);
+		return 0;
+	}
+
+	struct bch_fs *c = fs_arg_open_offline(fs_path, bch2_opts_empty());
+	struct bch_dev *ca = fs_arg_dev_offline(c, fs_path, dev_str);
+	unsigned bucket_bytes = ca->mi.bucket_size << 9;
+	u64 nbuckets = size / bucket_bytes;
+	int ret = 0;
+
+	if (journal_resize_check(dev_str, nbuckets, ca->journal.nr,
+				 ca->mi.nbuckets, bucket_bytes)) {
+		ret = bch2_set_nr_journal_buckets(c, ca, nbuckets);
+		if (ret)
+			fprintf(stderr, "error resizing journal: %s\n", bch2_err_str(ret));
+	}
+
+	bch2_dev_put(ca);
+	bch2_fs_stop(c);
+	return ret ? EXIT_FAILURE : 0;
+}
+
+/* journal move */
+
+static void journal_move_usage(void)
+{
+	puts("bcachefs journal move - move the journal from one device to another\n"
+	     "Usage: bcachefs journal move [OPTION]... <devices> <from> <to> [size]\n"
+	     "\n"
+	     "Stops journal writes to from, and makes sure to has a journal of at least\n"
+	     "size (default: the size of from's), on an unmounted filesystem. devices\n"
+	     "are its devices separated by colons; from and to are members, by path or\n"
+	     "index. The journal can't be shrunk, so from's journal buckets stay\n"
+	     "allocated, but aren't written to.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_journal_move(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	int opt, ret;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			journal_move_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			journal_move_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	char *from_str = arg_pop();
+	char *to_str = arg_pop();
+	if (!from_str || !to_str)
+		die("Please supply the devices to move the journal from and to");
+
+	char *size_str = arg_pop();
+
+	if (argc)
+		die("Too many arguments");
+
+	/* a running kernel only recomputes rw_devs from data_allowed on going rw: */
+	if (fs_arg_is_mounted(fs_path))
+		die("the journal can only be moved on an unmounted filesystem");
+
+	opt_set(opts, nostart, true);
+
+	struct bch_fs *c = fs_arg_open_offline(fs_path, opts);
+	struct bch_dev *from = fs_arg_dev_offline(c, fs_path, from_str);
+	struct bch_dev *to = fs_arg_dev_offline(c, fs_path, to_str);
+
+	if (from == to)
+		die("%s and %s are the same device", from_str, to_str);
+
+	if (!(from->mi.data_allowed & BIT(BCH_DATA_journal)))
+		die("%s doesn't have the journal", from_str);
+
+	if (!(from->mi.data_allowed & ~BIT(BCH_DATA_journal)))
+		die("%s would be left with no data types allowed", from_str);
+
+	if (to->mi.state != BCH_MEMBER_STATE_rw)
+		die("%s is %s, not rw", to_str, bch2_member_states[to->mi.state]);
+
+	unsigned nr_journal_devs = 0;
+	for_each_member_device(c, ca)
+		nr_journal_devs += ca != from &&
+			ca->mi.state == BCH_MEMBER_STATE_rw &&
+			(ca == to || (ca->mi.data_allowed & BIT(BCH_DATA_journal)));
+
+	if (nr_journal_devs < c->opts.metadata_replicas_required)
+		die("that would leave %u devices for the journal, and metadata_replicas_required is %u",
+		    nr_journal_devs, c->opts.metadata_replicas_required);
+
+	unsigned bucket_bytes = to->mi.bucket_size << 9;
+	u64 size = size_str
+		? journal_size_parse(size_str)
+		: (u64) from->journal.nr * (from->mi.bucket_size << 9);
+	u64 nbuckets = max_t(u64, size / bucket_bytes, BCH_JOURNAL_BUCKETS_MIN);
+
+	if (nbuckets > U32_MAX || nbuckets >= to->mi.nbuckets)
+		die("%llu buckets is too big for %s", nbuckets, to_str);
+
+	ret = bch2_fs_start(c);
+	if (ret)
+		die("error starting filesystem: %s", bch2_err_str(ret));
+
+	/*
+	 * Grow @to's journal first, so that it's never the journal device
+	 * without journal buckets:
+	 */
+	if (nbuckets > to->journal.nr) {
+		tools_prt_units_u64(&buf, nbuckets * bucket_bytes);
+		printf("resizing journal on %s from %u to %llu buckets (%s)\n",
+		       to_str, to->journal.nr, nbuckets, buf.buf);
+
+		ret = bch2_set_nr_journal_buckets(c, to, nbuckets);
+		if (ret)
+			die("error resizing journal: %s", bch2_err_str(ret));
+	}
+
+	mutex_lock(&c->sb_lock);
+	struct bch_member *m = bch2_members_v2_get_mut(c->disk_sb.sb, from->dev_idx);
+	SET_BCH_MEMBER_DATA_ALLOWED(m, BCH_MEMBER_DATA_ALLOWED(m) & ~BIT(BCH_DATA_journal));
+
+	m = bch2_members_v2_get_mut(c->disk_sb.sb, to->dev_idx);
+	SET_BCH_MEMBER_DATA_ALLOWED(m, BCH_MEMBER_DATA_ALLOWED(m) | BIT(BCH_DATA_journal));
+
+	ret = bch2_write_super(c);
+	mutex_unlock(&c->sb_lock);
+	if (ret)
+		die("error writing superblock: %s", bch2_err_str(ret));
+
+	/* and recompute rw_devs from the new data_allowed, as going rw does: */
+	down_write(&c->state_lock);
+	bch2_dev_allocator_remove(c, from);
+	bch2_dev_allocator_add(c, from);
+	bch2_dev_allocator_add(c, to);
+	bch2_recalc_capacity(c);
+	up_write(&c->state_lock);
+
+	/* Flush everything in journal entries on @from, so they're no longer needed: */
+	ret = bch2_journal_flush_device_pins(&c->journal, from->dev_idx) ?:
+		bch2_journal_meta(&c->journal);
+	if (ret)
+		die("error flushing journal: %s", bch2_err_str(ret));
+
+	printbuf_reset(&buf);
+	tools_prt_units_u64(&buf, (u64) from->journal.nr * (from->mi.bucket_size << 9));
+	printf("journal moved from %s to %s; the %u buckets (%s) it had on %s stay allocated\n",
+	       from_str, to_str, from->journal.nr, buf.buf, from_str);
+
+	printbuf_exit(&buf);
+	bch2_dev_put(to);
+	bch2_dev_put(from);
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -3,6 +3,8 @@
 #include <unistd.h>
 #include <uuid/uuid.h>
 
+#include <blkid.h>
+
 #include "cmds.h"
 #include "libbcachefs/checksum.h"
 #include "crypto.h"
@@ -13,26 +15,205 @@ static void unlock_usage(void)
 {
 	puts("bcachefs unlock - unlock an encrypted filesystem so it can be mounted\n"
 	     "Usage: bcachefs unlock [OPTION] device\n"
+	     "       bcachefs unlock [OPTION] -b map\n"
 	     "\n"
 	     "Options:\n"
 	     "  -c                     Check if a device is encrypted\n"
 	     "  -k (session|user|user_session)\n"
 	     "                         Keyring to add to (default: user)\n"
 	     "  -f                     Passphrase file to read from (disables passphrase prompt)\n"
+	     "  -b map                 Unlock every filesystem listed in map, one per line:\n"
+	     "                           <device|UUID> [prompt|keyring|file:<passphrase file>]\n"
 	     "  -h                     Display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
 
+/*
+ * unlock -b: filesystems from a map file, each with where its passphrase comes
+ * from. Filesystems to be prompted for share one prompt, and are only asked for
+ * individually if that passphrase doesn't work for them
+ */
+struct unlock_entry {
+	char			*spec;
+	char			*source;	/* NULL: prompt */
+	struct bch_sb_handle	sb;
+};
+
+typedef DARRAY(struct unlock_entry) unlock_entries;
+
+static char *unlock_spec_to_dev(const char *spec)
+{
+	const char *uuid = strcmp_prefix((char *) spec, "UUID=") ?: spec;
+	__uuid_t u;
+
+	if (uuid_parse(uuid, u.b))
+		return strdup(spec);
+
+	return blkid_evaluate_tag("UUID", uuid, NULL);
+}
+
+static unlock_entries unlock_map_read(const char *path)
+{
+	unlock_entries entries = {};
+	FILE *f = fopen(path, "r");
+	char *line = NULL;
+	size_t n = 0;
+	unsigned lineno = 0;
+
+	if (!f)
+		die("error opening %s: %m", path);
+
+	while (getline(&line, &n, f) >= 0) {
+		char *p = line, *spec, *source;
+
+		lineno++;
+		strim(p);
+		if (!*p || *p == '#')
+			continue;
+
+		spec	= strsep(&p, " \t");
+		source	= p ? strim(p) : NULL;
+		if (source && (!*source || !strcmp(source, "prompt")))
+			source = NULL;
+
+		if (source &&
+		    strcmp(source, "keyring") &&
+		    !strcmp_prefix(source, "file:"))
+			die("%s:%u: unknown passphrase source %s", path, lineno, source);
+
+		darray_push(&entries, ((struct unlock_entry) {
+			.spec	= strdup(spec),
+			.source	= source ? strdup(source) : NULL,
+		}));
+	}
+
+	free(line);
+	fclose(f);
+	return entries;
+}
+
+static bool unlock_try(struct unlock_entry *e, const char *keyring,
+		       const char *passphrase, bool last)
+{
+	int ret = bch2_try_add_key(e->sb.sb, "user", keyring, passphrase);
+
+	if (!ret)
+		printf("%s: unlocked\n", e->spec);
+	else if (last)
+		printf("%s: %s\n", e->spec,
+		       ret == -EKEYREJECTED ? "wrong passphrase" : strerror(-ret));
+	return !ret;
+}
+
+static int cmd_unlock_batch(const char *map, const char *keyring)
+{
+	unlock_entries entries = unlock_map_read(map);
+	DARRAY(struct unlock_entry *) prompt = {};
+	unsigned nr_failed = 0;
+
+	if (!entries.nr)
+		die("%s: no filesystems listed", map);
+
+	darray_for_each(entries, e) {
+		char *dev = unlock_spec_to_dev(e->spec);
+		if (!dev) {
+			printf("%s: not found\n", e->spec);
+			nr_failed++;
+			continue;
+		}
+
+		struct bch_opts opts = bch2_opts_empty();
+		opt_set(opts, noexcl, true);
+		opt_set(opts, nochanges, true);
+
+		int ret = bch2_read_super(dev, &opts, &e->sb);
+		free(dev);
+		if (ret) {
+			printf("%s: error reading superblock: %s\n", e->spec, bch2_err_str(ret));
+			e->sb.sb = NULL;
+			nr_failed++;
+			continue;
+		}
+
+		if (!bch2_sb_is_encrypted(e->sb.sb)) {
+			printf("%s: not encrypted\n", e->spec);
+		} else if (bch2_key_present(e->sb.sb, "user", keyring)) {
+			printf("%s: already unlocked\n", e->spec);
+		} else if (!e->source) {
+			darray_push(&prompt, e);
+		} else if (!strcmp(e->source, "keyring")) {
+			printf("%s: key not in keyring\n", e->spec);
+			nr_failed++;
+		} else {
+			const char *path = strcmp_prefix(e->source, "file:");
+			if (access(path, R_OK)) {
+				printf("%s: error reading %s: %m\n", e->spec, path);
+				nr_failed++;
+				continue;
+			}
+
+			char *passphrase = read_file_str(AT_FDCWD, path) ?: strdup("");
+
+			nr_failed += !unlock_try(e, keyring, passphrase, true);
+
+			memzero_explicit(passphrase, strlen(passphrase));
+			free(passphrase);
+		}
+	}
+
+	if (prompt.nr) {
+		char *passphrase = read_passphrase("Enter passphrase: ");
+		bool retry = isatty(STDIN_FILENO);
+		DARRAY(struct unlock_entry *) failed = {};
+
+		darray_for_each(prompt, e)
+			if (!unlock_try(*e, keyring, passphrase, !retry))
+				darray_push(&failed, *e);
+
+		memzero_explicit(passphrase, strlen(passphrase));
+		free(passphrase);
+
+		darray_for_each(failed, e) {
+			if (!retry) {
+				nr_failed++;
+				continue;
+			}
+
+			char *prompt_str = mprintf("Enter passphrase for %s: ", (*e)->spec);
+			passphrase = read_passphrase(prompt_str);
+			free(prompt_str);
+
+			nr_failed += !unlock_try(*e, keyring, passphrase, true);
+
+			memzero_explicit(passphrase, strlen(passphrase));
+			free(passphrase);
+		}
+
+		darray_exit(&failed);
+	}
+
+	darray_for_each(entries, e) {
+		if (e->sb.sb)
+			bch2_free_super(&e->sb);
+		free(e->spec);
+		free(e->source);
+	}
+	darray_exit(&prompt);
+	darray_exit(&entries);
+
+	return nr_failed ? 1 : 0;
+}
+
 int cmd_unlock(int argc, char *argv[])
 {
 	const char *keyring = "user";
 	bool check = false;
-	const char *passphrase_file_path = NULL;
+	const char *passphrase_file_path = NULL, *map = NULL;
 	char *passphrase = NULL;
 
 	int opt;
 
-	while ((opt = getopt(argc, argv, "cf:k:h")) != -1)
+	while ((opt = getopt(argc, argv, "cf:k:b:h")) != -1)
 		switch (opt) {
 		case 'c':
 			check = true;
@@ -43,12 +224,21 @@ int cmd_unlock(int argc, char *argv[])
 		case 'f':
 			passphrase_file_path = strdup(optarg);
 			break;
+		case 'b':
+			map = strdup(optarg);
+			break;
 		case 'h':
 			unlock_usage();
 			exit(EXIT_SUCCESS);
 		}
 	args_shift(optind);
 
+	if (map) {
+		if (argc || check || passphrase_file_path)
+			die("-b takes the filesystems, and their passphrase files, from the map");
+		return cmd_unlock_batch(map, keyring);
+	}
+
 	char *dev = arg_pop();
 	if (!dev)
 		die("Please supply a device");
@@ -85,6 +275,88 @@ int cmd_unlock(int argc, char *argv[])
 	return 0;
 }
 
+static void lock_usage(void)
+{
+	puts("bcachefs lock - remove an encrypted filesystem's key from the keyring\n"
+	     "Usage: bcachefs lock [OPTION] <device|UUID>\n"
+	     "\n"
+	     "The filesystem must not be mounted: run this after unmounting it.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -k (session|user|user_session)\n"
+	     "                         Keyring to remove from (default: user)\n"
+	     "  -h                     Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_lock(int argc, char *argv[])
+{
+	const char *keyring = "user";
+	int opt;
+
+	while ((opt = getopt(argc, argv, "k:h")) != -1)
+		switch (opt) {
+		case 'k':
+			keyring = strdup(optarg);
+			break;
+		case 'h':
+			lock_usage();
+			exit(EXIT_SUCCESS);
+		}
+	args_shift(optind);
+
+	char *spec = arg_pop();
+	if (!spec)
+		die("Please supply a device or filesystem UUID");
+
+	if (argc)
+		die("Too many arguments");
+
+	__uuid_t uuid;
+	const char *uuid_str = strcmp_prefix(spec, "UUID=") ?: spec;
+
+	if (uuid_parse(uuid_str, uuid.b)) {
+		struct bch_opts opts = bch2_opts_empty();
+		struct bch_sb_handle sb;
+
+		opt_set(opts, noexcl, true);
+		opt_set(opts, nochanges, true);
+
+		int ret = bch2_read_super(spec, &opts, &sb);
+		if (ret)
+			die("Error opening %s: %s", spec, bch2_err_str(ret));
+
+		if (!bch2_sb_is_encrypted(sb.sb))
+			die("%s is not encrypted", spec);
+
+		uuid = sb.sb->user_uuid;
+		bch2_free_super(&sb);
+	}
+
+	char uuid_unparsed[40];
+	uuid_unparse_lower(uuid.b, uuid_unparsed);
+
+	/*
+	 * Checked by UUID, not by mountpoint, so that a filesystem still being
+	 * started or stopped counts too:
+	 */
+	char *sysfs = mprintf("/sys/fs/bcachefs/%s", uuid_unparsed);
+	bool running = !access(sysfs, F_OK);
+	free(sysfs);
+
+	if (running)
+		die("%s is still mounted; unmount it first", uuid_unparsed);
+
+	int ret = bch2_remove_key(uuid, keyring);
+	if (ret == -ENOKEY)
+		die("no key for %s in the %s keyring", uuid_unparsed, keyring);
+	if (ret)
+		die("error removing key for %s: %s", uuid_unparsed, strerror(-ret));
+
+	printf("%s: locked\n", uuid_unparsed);
+	return 0;
+}
+
 int cmd_set_passphrase(int argc, char *argv[])
 {
 	struct bch_opts opts = bch2_opts_empty();
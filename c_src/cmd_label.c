@@ -0,0 +1,614 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/stat.h>
+
+#include <linux/sort.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/disk_groups.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/super.h"
+#include "libbcachefs/super-io.h"
+
+int label_usage(void)
+{
+	puts("bcachefs label - manage the device label tree\n"
+	     "Usage: bcachefs label <CMD> [OPTION]...\n"
+	     "\n"
+	     "Commands:\n"
+	     "  list                    show labels, their devices and the targets using them\n"
+	     "  rename                  rename or move a label, and everything under it\n"
+	     "\n"
+	     "Device labels are set with bcachefs device set-label.\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+	return 0;
+}
+
+/*
+ * The label tree, as paths: a device with label ssd.fast is in targets ssd and
+ * ssd.fast. Targets refer to labels by index, not by path, so renaming a label
+ * in place changes nothing, but moving one, or a device, to a different parent
+ * changes which devices the targets above it include - changes are checked on
+ * copies of this before they're made
+ */
+static const enum bch_opt_id label_target_opts[] = {
+	Opt_metadata_target,
+	Opt_foreground_target,
+	Opt_background_target,
+	Opt_promote_target,
+};
+
+struct label_tree {
+	u64		devs;				/* members that exist */
+	char		*dev[BCH_SB_MEMBERS_MAX];	/* label, or NULL */
+	struct {
+		int	dev;				/* -1: not a device target */
+		char	*group;				/* NULL: not a label target */
+	}		targets[ARRAY_SIZE(label_target_opts)];
+};
+
+static char *sb_group_path(struct bch_sb *sb, unsigned group)
+{
+	struct printbuf buf = PRINTBUF;
+
+	bch2_disk_path_to_text_sb(&buf, sb, group);
+	return buf.buf;
+}
+
+static struct label_tree label_tree_get(struct bch_sb *sb)
+{
+	struct label_tree t = {};
+
+	for (unsigned i = 0; i < sb->nr_devices; i++) {
+		struct bch_member m = bch2_sb_member_get(sb, i);
+
+		if (!bch2_member_alive(&m))
+			continue;
+
+		t.devs |= BIT_ULL(i);
+		if (BCH_MEMBER_GROUP(&m))
+			t.dev[i] = sb_group_path(sb, BCH_MEMBER_GROUP(&m) - 1);
+	}
+
+	for (unsigned i = 0; i < ARRAY_SIZE(label_target_opts); i++) {
+		struct target tgt = target_decode(bch2_opt_from_sb(sb, label_target_opts[i]));
+
+		t.targets[i].dev = tgt.type == TARGET_DEV ? tgt.dev : -1;
+		t.targets[i].group = tgt.type == TARGET_GROUP
+			? sb_group_path(sb, tgt.group)
+			: NULL;
+	}
+
+	return t;
+}
+
+static struct label_tree label_tree_copy(struct label_tree *src)
+{
+	struct label_tree t = *src;
+
+	for (unsigned i = 0; i < ARRAY_SIZE(t.dev); i++)
+		if (t.dev[i])
+			t.dev[i] = strdup(t.dev[i]);
+	for (unsigned i = 0; i < ARRAY_SIZE(t.targets); i++)
+		if (t.targets[i].group)
+			t.targets[i].group = strdup(t.targets[i].group);
+	return t;
+}
+
+static void label_tree_exit(struct label_tree *t)
+{
+	for (unsigned i = 0; i < ARRAY_SIZE(t->dev); i++)
+		free(t->dev[i]);
+	for (unsigned i = 0; i < ARRAY_SIZE(t->targets); i++)
+		free(t->targets[i].group);
+}
+
+/* @path is @label, or under it */
+static bool label_contains(const char *label, const char *path)
+{
+	size_t len = strlen(label);
+
+	return path &&
+		!strncmp(label, path, len) &&
+		(!path[len] || path[len] == '.');
+}
+
+static u64 label_devs(struct label_tree *t, const char *label)
+{
+	u64 devs = 0;
+
+	for (unsigned i = 0; i < ARRAY_SIZE(t->dev); i++)
+		if (label_contains(label, t->dev[i]))
+			devs |= BIT_ULL(i);
+	return devs;
+}
+
+static u64 label_tree_target_devs(struct label_tree *t, unsigned i)
+{
+	if (t->targets[i].dev >= 0)
+		return BIT_ULL(t->targets[i].dev) & t->devs;
+	if (t->targets[i].group)
+		return label_devs(t, t->targets[i].group);
+	return 0;
+}
+
+/* @old, and everything under it, are moved to @new */
+static void label_tree_rename(struct label_tree *t, const char *old, const char *new)
+{
+	size_t len = strlen(old);
+
+	for (unsigned i = 0; i < ARRAY_SIZE(t->dev); i++)
+		if (label_contains(old, t->dev[i])) {
+			char *p = mprintf("%s%s", new, t->dev[i] + len);
+			free(t->dev[i]);
+			t->dev[i] = p;
+		}
+
+	for (unsigned i = 0; i < ARRAY_SIZE(t->targets); i++)
+		if (label_contains(old, t->targets[i].group)) {
+			char *p = mprintf("%s%s", new, t->targets[i].group + len);
+			free(t->targets[i].group);
+			t->targets[i].group = p;
+		}
+}
+
+static void devs_to_text(struct printbuf *out, u64 devs)
+{
+	if (!devs) {
+		prt_str(out, "no devices");
+		return;
+	}
+
+	prt_str(out, "devices");
+	for (unsigned i = 0; i < 64; i++)
+		if (devs & BIT_ULL(i))
+			prt_printf(out, " %u", i);
+}
+
+/*
+ * Print the targets whose devices change from @old to @new; returns false if a
+ * target would be left with no devices
+ */
+static bool label_tree_check(struct label_tree *old, struct label_tree *new)
+{
+	struct printbuf buf = PRINTBUF;
+	bool ok = true;
+
+	for (unsigned i = 0; i < ARRAY_SIZE(label_target_opts); i++) {
+		u64 old_devs = label_tree_target_devs(old, i);
+		u64 new_devs = label_tree_target_devs(new, i);
+
+		if (old_devs == new_devs)
+			continue;
+
+		printbuf_reset(&buf);
+		prt_printf(&buf, "%s", bch2_opt_table[label_target_opts[i]].attr.name);
+		if (old->targets[i].group)
+			prt_printf(&buf, " (%s)", old->targets[i].group);
+		prt_str(&buf, ": ");
+		devs_to_text(&buf, old_devs);
+		prt_str(&buf, " -> ");
+		devs_to_text(&buf, new_devs);
+		printf("%s\n", buf.buf);
+
+		if (old_devs && !new_devs)
+			ok = false;
+	}
+
+	printbuf_exit(&buf);
+	return ok;
+}
+
+static void label_tree_check_or_die(struct label_tree *old, struct label_tree *new,
+				    bool force)
+{
+	if (!label_tree_check(old, new) && !force)
+		die("a target would be left with no devices; change the target first, or use --force");
+}
+
+/* Labels are only changed in the superblock: nothing else is read or written */
+static struct bch_fs *label_fs_open_offline(char *fs)
+{
+	struct bch_opts opts = bch2_opts_empty();
+
+	opt_set(opts, nostart, true);
+	return fs_arg_open_offline(fs, opts);
+}
+
+static void label_check_path(const char *label)
+{
+	const char *p = label;
+
+	do {
+		const char *next = strchrnul(p, '.');
+
+		if (next == p || next - p > BCH_SB_LABEL_SIZE)
+			die("invalid label %s: each part must be 1 to %u characters",
+			    label, BCH_SB_LABEL_SIZE);
+		p = *next ? next + 1 : next;
+	} while (*p);
+
+	if (label[strlen(label) - 1] == '.')
+		die("invalid label %s", label);
+}
+
+/* device set-label */
+
+static void device_set_label_usage(void)
+{
+	puts("bcachefs device set-label - set a device's label\n"
+	     "Usage: bcachefs device set-label [OPTION]... <filesystem> <device|devid> <label>\n"
+	     "\n"
+	     "filesystem is a mountpoint, or the devices of an unmounted filesystem\n"
+	     "separated by colons. Labels are paths: ssd.fast.nvme0 is under ssd.fast,\n"
+	     "which is under ssd. A label of none removes the device's label, which can\n"
+	     "only be done while unmounted.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -f, --force                 Even if a target would be left with no devices\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_device_set_label(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "force",			0, NULL, 'f' },
+		{ "help",			0, NULL, 'h' },
+		{ NULL }
+	};
+	bool force = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "fh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			device_set_label_usage();
+			exit(EXIT_SUCCESS);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	char *dev_str = arg_pop();
+	if (!dev_str)
+		die("Please supply a device");
+
+	char *label = arg_pop();
+	if (!label)
+		die("Please supply a label");
+
+	if (argc)
+		die("Too many arguments");
+
+	bool remove = !strcmp(label, "none");
+	if (!remove)
+		label_check_path(label);
+
+	char *end;
+	int dev_idx = strtoul(dev_str, &end, 10);
+	bool by_id = *dev_str && !*end;
+
+	if (fs_arg_is_mounted(fs_path)) {
+		if (remove)
+			die("a device's label can only be removed while the filesystem is unmounted");
+
+		struct bchfs_handle fs = bcache_fs_open(fs_path);
+
+		if (!by_id) {
+			dev_idx = bchu_dev_path_to_idx(fs, dev_str);
+			if (dev_idx < 0)
+				die("%s does not seem to be a member of %s", dev_str, fs_path);
+		}
+
+		struct bch_sb *sb = bchu_read_super(fs, -1);
+		struct label_tree old = label_tree_get(sb);
+
+		if (dev_idx >= sb->nr_devices || !(old.devs & BIT_ULL(dev_idx)))
+			die("no device %u in %s", dev_idx, fs_path);
+
+		struct label_tree new = label_tree_copy(&old);
+		free(new.dev[dev_idx]);
+		new.dev[dev_idx] = strdup(label);
+
+		label_tree_check_or_die(&old, &new, force);
+
+		char *attr = mprintf("dev-%u/label", dev_idx);
+		write_file_str(fs.sysfs_fd, attr, label);
+		free(attr);
+
+		label_tree_exit(&new);
+		label_tree_exit(&old);
+		free(sb);
+		bcache_fs_close(fs);
+		return 0;
+	}
+
+	struct bch_fs *c = label_fs_open_offline(fs_path);
+	struct bch_dev *ca = fs_arg_dev_offline(c, fs_path, dev_str);
+
+	mutex_lock(&c->sb_lock);
+	struct label_tree old = label_tree_get(c->disk_sb.sb);
+	struct label_tree new = label_tree_copy(&old);
+	free(new.dev[ca->dev_idx]);
+	new.dev[ca->dev_idx] = remove ? NULL : strdup(label);
+
+	label_tree_check_or_die(&old, &new, force);
+
+	int ret;
+	if (remove) {
+		struct bch_member *m = bch2_members_v2_get_mut(c->disk_sb.sb, ca->dev_idx);
+		SET_BCH_MEMBER_GROUP(m, 0);
+		ret = bch2_sb_disk_groups_to_cpu(c);
+	} else {
+		ret = __bch2_dev_group_set(c, ca, label);
+	}
+
+	ret = ret ?: bch2_write_super(c);
+	mutex_unlock(&c->sb_lock);
+	bch2_dev_put(ca);
+
+	if (ret)
+		die("error setting label: %s", bch2_err_str(ret));
+
+	label_tree_exit(&new);
+	label_tree_exit(&old);
+	bch2_fs_stop(c);
+	return 0;
+}
+
+/* label list */
+
+static void label_list_usage(void)
+{
+	puts("bcachefs label list - show the device label tree\n"
+	     "Usage: bcachefs label list [OPTION]... <mountpoint|device>\n"
+	     "\n"
+	     "Each label is shown with the devices labelled with it - not the ones\n"
+	     "under it - and the filesystem targets that name it.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static int path_cmp(const void *l, const void *r)
+{
+	return strcmp(*((char **) l), *((char **) r));
+}
+
+int cmd_label_list(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",			0, NULL, 'h' },
+		{ NULL }
+	};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			label_list_usage();
+			exit(EXIT_SUCCESS);
+		}
+	args_shift(optind);
+
+	char *path = arg_pop();
+	if (!path)
+		die("Please supply a mountpoint or device");
+
+	if (argc)
+		die("Too many arguments");
+
+	struct bch_sb_handle sb_handle = {};
+	struct bch_sb *sb;
+
+	if (fs_arg_is_mounted(path)) {
+		struct bchfs_handle fs = bcache_fs_open(path);
+		sb = bchu_read_super(fs, -1);
+		bcache_fs_close(fs);
+	} else {
+		struct bch_opts opts = bch2_opts_empty();
+
+		opt_set(opts, noexcl, true);
+		opt_set(opts, nochanges, true);
+
+		int ret = bch2_read_super(path, &opts, &sb_handle);
+		if (ret)
+			die("Error opening %s: %s", path, bch2_err_str(ret));
+		sb = sb_handle.sb;
+	}
+
+	struct bch_sb_field_disk_groups *groups = bch2_sb_field_get(sb, disk_groups);
+	struct label_tree t = label_tree_get(sb);
+	DARRAY(char *) paths = {};
+
+	for (unsigned i = 0; groups && i < disk_groups_nr(groups); i++)
+		if (!BCH_GROUP_DELETED(&groups->entries[i]))
+			darray_push(&paths, sb_group_path(sb, i));
+
+	sort(paths.data, paths.nr, sizeof(paths.data[0]), path_cmp, NULL);
+
+	if (!paths.nr)
+		printf("No labels\n");
+
+	struct printbuf buf = PRINTBUF;
+	printbuf_tabstop_push(&buf, 32);
+	printbuf_tabstop_push(&buf, 24);
+
+	darray_for_each(paths, p) {
+		const char *leaf = strrchr(*p, '.');
+		unsigned depth = 0;
+
+		for (const char *c = *p; *c; c++)
+			depth += *c == '.';
+
+		for (unsigned i = 0; i < depth; i++)
+			prt_str(&buf, "  ");
+		prt_str(&buf, leaf ? leaf + 1 : *p);
+		prt_tab(&buf);
+
+		u64 devs = 0;
+		for (unsigned i = 0; i < ARRAY_SIZE(t.dev); i++)
+			if (t.dev[i] && !strcmp(t.dev[i], *p))
+				devs |= BIT_ULL(i);
+		if (devs)
+			devs_to_text(&buf, devs);
+		prt_tab(&buf);
+
+		for (unsigned i = 0; i < ARRAY_SIZE(label_target_opts); i++)
+			if (t.targets[i].group && !strcmp(t.targets[i].group, *p))
+				prt_printf(&buf, "%s ", bch2_opt_table[label_target_opts[i]].attr.name);
+		prt_newline(&buf);
+	}
+
+	for (unsigned i = 0; i < ARRAY_SIZE(label_target_opts); i++)
+		if (t.targets[i].dev >= 0)
+			prt_printf(&buf, "%s: device %i\n",
+				   bch2_opt_table[label_target_opts[i]].attr.name,
+				   t.targets[i].dev);
+
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+
+	darray_for_each(paths, p)
+		free(*p);
+	darray_exit(&paths);
+	label_tree_exit(&t);
+
+	if (sb_handle.sb)
+		bch2_free_super(&sb_handle);
+	else
+		free(sb);
+	return 0;
+}
+
+/* label rename */
+
+static void label_rename_usage(void)
+{
+	puts("bcachefs label rename - rename or move a label\n"
+	     "Usage: bcachefs label rename [OPTION]... <devices> <old> <new>\n"
+	     "\n"
+	     "devices are the devices of an unmounted filesystem, separated by colons.\n"
+	     "Labels under old move with it: renaming ssd to flash makes ssd.fast\n"
+	     "flash.fast. Targets naming old, or a label under it, follow it.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -f, --force                 Even if a target would be left with no devices\n"
+	     "  -h, --help                  Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_label_rename(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "force",			0, NULL, 'f' },
+		{ "help",			0, NULL, 'h' },
+		{ NULL }
+	};
+	bool force = false;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "fh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			label_rename_usage();
+			exit(EXIT_SUCCESS);
+		}
+	args_shift(optind);
+
+	char *fs_path = arg_pop();
+	if (!fs_path)
+		die("Please supply a filesystem");
+
+	char *old_label = arg_pop();
+	char *new_label = arg_pop();
+	if (!old_label || !new_label)
+		die("Please supply the label to rename, and its new name");
+
+	if (argc)
+		die("Too many arguments");
+
+	if (fs_arg_is_mounted(fs_path))
+		die("labels can only be renamed while the filesystem is unmounted");
+
+	label_check_path(new_label);
+
+	if (label_contains(old_label, new_label))
+		die("can't move %s under itself", old_label);
+
+	struct bch_fs *c = label_fs_open_offline(fs_path);
+
+	mutex_lock(&c->sb_lock);
+
+	int group = bch2_disk_path_find(&c->disk_sb, old_label);
+	if (group < 0)
+		die("no label %s", old_label);
+
+	if (bch2_disk_path_find(&c->disk_sb, new_label) >= 0)
+		die("label %s already exists", new_label);
+
+	struct label_tree old = label_tree_get(c->disk_sb.sb);
+	struct label_tree new = label_tree_copy(&old);
+	label_tree_rename(&new, old_label, new_label);
+
+	label_tree_check_or_die(&old, &new, force);
+
+	char *leaf = strrchr(new_label, '.');
+	unsigned parent = 0;
+
+	if (leaf) {
+		char *parent_path = strndup(new_label, leaf - new_label);
+		int v = bch2_disk_path_find_or_create(&c->disk_sb, parent_path);
+		if (v < 0)
+			die("error creating label %s: %s", parent_path, bch2_err_str(v));
+
+		free(parent_path);
+		parent = v + 1;
+		leaf++;
+	} else {
+		leaf = new_label;
+	}
+
+	/* find_or_create may have resized the section: */
+	struct bch_sb_field_disk_groups *groups =
+		bch2_sb_field_get(c->disk_sb.sb, disk_groups);
+	struct bch_disk_group *g = &groups->entries[group];
+
+	memset(g->label, 0, sizeof(g->label));
+	memcpy(g->label, leaf, strlen(leaf));
+	SET_BCH_GROUP_PARENT(g, parent);
+
+	int ret = bch2_sb_disk_groups_to_cpu(c) ?:
+		bch2_write_super(c);
+	mutex_unlock(&c->sb_lock);
+
+	if (ret)
+		die("error renaming label: %s", bch2_err_str(ret));
+
+	printf("%s -> %s\n", old_label, new_label);
+
+	label_tree_exit(&new);
+	label_tree_exit(&old);
+	bch2_fs_stop(c);
+	return 0;
+}
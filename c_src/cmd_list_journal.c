@@ -8,7 +8,10 @@
 #include "libbcachefs.h"
 #include "tools-util.h"
 
+#include "linux/sort.h"
+
 #include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_cache.h"
 #include "libbcachefs/btree_iter.h"
 #include "libbcachefs/errcode.h"
 #include "libbcachefs/error.h"
@@ -16,240 +19,277 @@
 #include "libbcachefs/journal_seq_blacklist.h"
 #include "libbcachefs/super.h"
 
-static const char *NORMAL	= "\x1B[0m";
-static const char *RED		= "\x1B[31m";
-
-static void list_journal_usage(void)
-{
-	puts("bcachefs list_journal - print contents of journal\n"
-	     "Usage: bcachefs list_journal [OPTION]... <devices>\n"
-	     "\n"
-	     "Options:\n"
-	     "  -a                                Read entire journal, not just dirty entries\n"
-	     "  -n, --nr-entries=nr               Number of journal entries to print, starting from the most recent\n"
-	     "  -t, --transaction-filter=bbpos    Filter transactions not updating <bbpos>\n"
-	     "                                    Or entries not matching the range <bbpos-bbpos>\n"
-	     "  -k, --key-filter=btree            Filter keys not updating btree\n"
-	     "  -v, --verbose                     Verbose mode\n"
-	     "  -h, --help                        Display this help and exit\n"
-	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
-}
-
-static void star_start_of_lines(char *buf)
-{
-	char *p = buf;
-
-	if (*p == ' ')
-		*p = '*';
-
-	while ((p = strstr(p, "\n ")))
-		p[1] = '*';
-}
-
 static inline bool entry_is_transaction_start(struct jset_entry *entry)
 {
 	return entry->type == BCH_JSET_ENTRY_log && !entry->level;
 }
 
-typedef DARRAY(struct bbpos_range) d_bbpos_range;
-typedef DARRAY(enum btree_id) d_btree_id;
+/* journal-stats: */
 
-static bool bkey_matches_filter(d_bbpos_range filter, struct jset_entry *entry, struct bkey_i *k)
-{
-	darray_for_each(filter, i) {
-		struct bbpos k_start	= BBPOS(entry->btree_id, bkey_start_pos(&k->k));
-		struct bbpos k_end	= BBPOS(entry->btree_id, k->k.p);
+struct journal_fn_stats {
+	char		*fn;
+	u64		nr;
+	u64		bytes;
+	u64		keys;
+};
 
-		if (bbpos_cmp(k_start, i->end) < 0 &&
-		    bbpos_cmp(k_end, i->start) > 0)
-			return true;
-	}
-	return false;
-}
+struct journal_btree_stats {
+	u64		keys;
+	u64		bytes;
+	u64		overwrites;
+	u64		overwrite_bytes;
+};
 
-static bool entry_matches_transaction_filter(struct jset_entry *entry,
-					     d_bbpos_range filter)
-{
-	if (entry->type == BCH_JSET_ENTRY_btree_root ||
-	    entry->type == BCH_JSET_ENTRY_btree_keys ||
-	    entry->type == BCH_JSET_ENTRY_overwrite)
-		jset_entry_for_each_key(entry, k)
-			if (bkey_matches_filter(filter, entry, k))
-				return true;
-	return false;
-}
+typedef DARRAY(struct journal_fn_stats) d_journal_fn_stats;
 
-static bool should_print_transaction(struct jset_entry *entry, struct jset_entry *end,
-				     d_bbpos_range filter)
+static struct journal_fn_stats *journal_fn_stats_get(d_journal_fn_stats *fns,
+						     struct jset_entry *entry)
 {
-	if (!filter.nr)
-		return true;
-
-	for (entry = vstruct_next(entry);
-	     entry != end && !entry_is_transaction_start(entry);
-	     entry = vstruct_next(entry))
-		if (entry_matches_transaction_filter(entry, filter))
-			return true;
-
-	return false;
+	struct jset_entry_log *l = container_of(entry, struct jset_entry_log, entry);
+	unsigned len = strnlen((char *) l->d,
+			       vstruct_bytes(entry) - offsetof(struct jset_entry_log, d));
+
+	darray_for_each(*fns, i)
+		if (strlen(i->fn) == len && !memcmp(i->fn, l->d, len))
+			return i;
+
+	struct journal_fn_stats n = { .fn = strndup((char *) l->d, len) };
+	if (darray_push(fns, n))
+		die("out of memory");
+	return &darray_last(*fns);
 }
 
-static bool should_print_entry(struct jset_entry *entry, d_btree_id filter)
+static int journal_fn_stats_cmp(const void *_l, const void *_r)
 {
-	if (!filter.nr)
-		return true;
-
-	if (entry->type != BCH_JSET_ENTRY_btree_root &&
-	    entry->type != BCH_JSET_ENTRY_btree_keys &&
-	    entry->type != BCH_JSET_ENTRY_overwrite)
-		return true;
-
-	jset_entry_for_each_key(entry, k)
-		darray_for_each(filter, id)
-			if (entry->btree_id == *id)
-				return true;
+	const struct journal_fn_stats *l = _l, *r = _r;
 
-	return false;
+	return cmp_int(r->bytes, l->bytes);
 }
 
-static void journal_entries_print(struct bch_fs *c, unsigned nr_entries,
-				  d_bbpos_range transaction_filter,
-				  d_btree_id key_filter)
+static void journal_stats_print(struct bch_fs *c, bool human_readable)
 {
+	struct journal_btree_stats btrees[BTREE_ID_NR] = {};
+	d_journal_fn_stats fns = {};
+	struct journal_fn_stats *fn = NULL;
 	struct journal_replay *p, **_p;
 	struct genradix_iter iter;
 	struct printbuf buf = PRINTBUF;
+	u64 nr = 0, nr_flush = 0, bytes = 0, nr_trans = 0;
+	u64 seq_min = U64_MAX, seq_max = 0;
+	u64 time_min = U64_MAX, time_max = 0;
+
+	tools_printbuf_units(&buf, human_readable);
 
 	genradix_for_each(&c->journal_entries, iter, _p) {
 		p = *_p;
-		if (!p)
-			continue;
-
-		if (le64_to_cpu(p->j.seq) + nr_entries < atomic64_read(&c->journal.seq))
+		if (!p || p->ignore_blacklisted)
 			continue;
 
-		bool blacklisted = p->ignore_blacklisted ||
-			bch2_journal_seq_is_blacklisted(c,
-					le64_to_cpu(p->j.seq), false);
+		u64 seq = le64_to_cpu(p->j.seq);
 
-		if (!transaction_filter.nr) {
-			if (blacklisted)
-				printf("blacklisted ");
+		nr++;
+		nr_flush += !JSET_NO_FLUSH(&p->j);
+		bytes += vstruct_bytes(&p->j);
+		seq_min = min(seq_min, seq);
+		seq_max = max(seq_max, seq);
+		fn = NULL;
 
-			printf("journal entry     %llu\n", le64_to_cpu(p->j.seq));
+		vstruct_for_each(&p->j, entry) {
+			unsigned entry_bytes = vstruct_bytes(entry);
 
-			printbuf_reset(&buf);
+			switch (entry->type) {
+			case BCH_JSET_ENTRY_log:
+				if (entry_is_transaction_start(entry)) {
+					fn = journal_fn_stats_get(&fns, entry);
+					fn->nr++;
+					nr_trans++;
+				}
+				break;
+			case BCH_JSET_ENTRY_datetime: {
+				struct jset_entry_datetime *d =
+					container_of(entry, struct jset_entry_datetime, entry);
+				u64 t = le64_to_cpu(d->seconds);
+
+				time_min = min(time_min, t);
+				time_max = max(time_max, t);
+				break;
+			}
+			case BCH_JSET_ENTRY_btree_keys:
+			case BCH_JSET_ENTRY_overwrite:
+				if (entry->btree_id >= BTREE_ID_NR)
+					break;
+
+				jset_entry_for_each_key(entry, k) {
+					if (entry->type == BCH_JSET_ENTRY_btree_keys)
+						btrees[entry->btree_id].keys++;
+					else
+						btrees[entry->btree_id].overwrites++;
+					if (fn && entry->type == BCH_JSET_ENTRY_btree_keys)
+						fn->keys++;
+				}
 
-			prt_printf(&buf,
-				   "  version         %u\n"
-				   "  last seq        %llu\n"
-				   "  flush           %u\n"
-				   "  written at      ",
-				   le32_to_cpu(p->j.version),
-				   le64_to_cpu(p->j.last_seq),
-				   !JSET_NO_FLUSH(&p->j));
-			bch2_journal_ptrs_to_text(&buf, c, p);
+				if (entry->type == BCH_JSET_ENTRY_btree_keys)
+					btrees[entry->btree_id].bytes += entry_bytes;
+				else
+					btrees[entry->btree_id].overwrite_bytes += entry_bytes;
+				break;
+			}
 
-			if (blacklisted)
-				star_start_of_lines(buf.buf);
-			printf("%s\n", buf.buf);
-			printbuf_reset(&buf);
+			if (fn)
+				fn->bytes += entry_bytes;
 		}
+	}
 
-		struct jset_entry *entry = p->j.start;
-		struct jset_entry *end = vstruct_last(&p->j);
-		while (entry != end) {
-
-			/*
-			 * log entries denote the start of a new transaction
-			 * commit:
-			 */
-			if (entry_is_transaction_start(entry)) {
-				if (!should_print_transaction(entry, end, transaction_filter)) {
-					do {
-						entry = vstruct_next(entry);
-					} while (entry != end && !entry_is_transaction_start(entry));
-
-					continue;
-				}
+	if (!nr) {
+		printf("No journal entries\n");
+		goto out;
+	}
 
-				prt_newline(&buf);
-			}
+	printbuf_tabstops_reset(&buf);
+	printbuf_tabstop_push(&buf, 24);
+	printbuf_tabstop_push(&buf, 16);
+
+	prt_printf(&buf, "Journal entries:\t%llu\r\n", nr);
+	prt_printf(&buf, "Sequence numbers:\t%llu-%llu\r\n", seq_min, seq_max);
+	prt_printf(&buf, "Total size:\t");
+	tools_prt_units_u64(&buf, bytes);
+	prt_printf(&buf, "\r\n");
+	prt_printf(&buf, "Average entry size:\t");
+	tools_prt_units_u64(&buf, div64_u64(bytes, nr));
+	prt_printf(&buf, "\r\n");
+	prt_printf(&buf, "Flush entries:\t%llu\r\n", nr_flush);
+	prt_printf(&buf, "Noflush entries:\t%llu\r\n", nr - nr_flush);
+
+	if (time_max > time_min) {
+		u64 secs = time_max - time_min;
+
+		prt_printf(&buf, "Time span:\t");
+		bch2_pr_time_units(&buf, secs * NSEC_PER_SEC);
+		prt_printf(&buf, "\r\n");
+		prt_printf(&buf, "Flushes per minute:\t%llu\r\n",
+			   div64_u64(nr_flush * 60, secs));
+	}
+
+	prt_printf(&buf, "Transactions:\t%llu\r\n", nr_trans);
+	if (nr_trans) {
+		u64 trans_bytes = 0;
 
-			if (!should_print_entry(entry, key_filter))
-				goto next;
+		darray_for_each(fns, i)
+			trans_bytes += i->bytes;
 
-			bool highlight = entry_matches_transaction_filter(entry, transaction_filter);
-			if (highlight)
-				fputs(RED, stdout);
+		prt_printf(&buf, "Average transaction:\t");
+		tools_prt_units_u64(&buf, div64_u64(trans_bytes, nr_trans));
+		prt_printf(&buf, "\r\n");
+	}
+	prt_newline(&buf);
 
-			printbuf_indent_add(&buf, 4);
-			bch2_journal_entry_to_text(&buf, c, entry);
+	printbuf_tabstops_reset(&buf);
+	printbuf_tabstop_push(&buf, 24);
+	printbuf_tabstop_push(&buf, 12);
+	printbuf_tabstop_push(&buf, 12);
+	printbuf_tabstop_push(&buf, 12);
+	printbuf_tabstop_push(&buf, 12);
 
-			if (blacklisted)
-				star_start_of_lines(buf.buf);
-			printf("%s\n", buf.buf);
-			printbuf_reset(&buf);
+	prt_printf(&buf, "Btree\tkeys\rbytes\roverwrites\rbytes\r\n");
 
-			if (highlight)
-				fputs(NORMAL, stdout);
-next:
-			entry = vstruct_next(entry);
-		}
+	for (unsigned i = 0; i < BTREE_ID_NR; i++) {
+		struct journal_btree_stats *b = &btrees[i];
+
+		if (!b->keys && !b->overwrites)
+			continue;
+
+		prt_printf(&buf, "%s\t%llu\r", bch2_btree_id_str(i), b->keys);
+		tools_prt_units_u64(&buf, b->bytes);
+		prt_printf(&buf, "\r%llu\r", b->overwrites);
+		tools_prt_units_u64(&buf, b->overwrite_bytes);
+		prt_printf(&buf, "\r\n");
+	}
+	prt_newline(&buf);
+
+	sort(fns.data, fns.nr, sizeof(fns.data[0]), journal_fn_stats_cmp, NULL);
+
+	printbuf_tabstops_reset(&buf);
+	printbuf_tabstop_push(&buf, 40);
+	printbuf_tabstop_push(&buf, 12);
+	printbuf_tabstop_push(&buf, 12);
+	printbuf_tabstop_push(&buf, 12);
+	printbuf_tabstop_push(&buf, 12);
+
+	prt_printf(&buf, "Transaction\tcount\rkeys\rbytes\ravg bytes\r\n");
+
+	darray_for_each(fns, i) {
+		prt_printf(&buf, "%s\t%llu\r%llu\r", i->fn, i->nr, i->keys);
+		tools_prt_units_u64(&buf, i->bytes);
+		prt_printf(&buf, "\r");
+		tools_prt_units_u64(&buf, div64_u64(i->bytes, i->nr));
+		prt_printf(&buf, "\r\n");
 	}
 
+	printf("%s", buf.buf);
+out:
+	darray_for_each(fns, i)
+		free(i->fn);
+	darray_exit(&fns);
 	printbuf_exit(&buf);
 }
 
-int cmd_list_journal(int argc, char *argv[])
+static void journal_stats_usage(void)
+{
+	puts("bcachefs journal-stats - print statistics about the journal\n"
+	     "Usage: bcachefs journal-stats [OPTION]... <devices>\n"
+	     "\n"
+	     "Reports entry sizes, flush frequency, and keys and bytes journalled per\n"
+	     "btree and per transaction, for tuning journal size and understanding\n"
+	     "write amplification.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -a                                Read entire journal, not just dirty entries\n"
+	     "  -h, --human-readable              Human readable units\n"
+	     "  -v, --verbose                     Verbose mode\n"
+	     "  -H, --help                        Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_journal_stats(int argc, char *argv[])
 {
 	static const struct option longopts[] = {
-		{ "nr-entries",		required_argument,	NULL, 'n' },
-		{ "transaction-filter",	required_argument,	NULL, 't' },
-		{ "key-filter",		required_argument,	NULL, 'k' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
 		{ "verbose",		no_argument,		NULL, 'v' },
-		{ "help",		no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
 		{ NULL }
 	};
 	struct bch_opts opts = bch2_opts_empty();
-	u32 nr_entries = U32_MAX;
-	d_bbpos_range	transaction_filter = { 0 };
-	d_btree_id	key_filter = { 0 };
+	bool human_readable = false;
 	int opt;
 
 	opt_set(opts, nochanges,	true);
 	opt_set(opts, norecovery,	true);
 	opt_set(opts, read_only,	true);
 	opt_set(opts, degraded,		true);
-	opt_set(opts, very_degraded,		true);
+	opt_set(opts, very_degraded,	true);
 	opt_set(opts, errors,		BCH_ON_ERROR_continue);
 	opt_set(opts, fix_errors,	FSCK_FIX_yes);
 	opt_set(opts, retain_recovery_info ,true);
 	opt_set(opts, read_journal_only,true);
 
-	while ((opt = getopt_long(argc, argv, "an:t:k:vh",
+	while ((opt = getopt_long(argc, argv, "ahvH",
 				  longopts, NULL)) != -1)
 		switch (opt) {
 		case 'a':
 			opt_set(opts, read_entire_journal, true);
 			break;
-		case 'n':
-			if (kstrtouint(optarg, 10, &nr_entries))
-				die("error parsing nr_entries");
-			opt_set(opts, read_entire_journal, true);
-			break;
-		case 't':
-			darray_push(&transaction_filter, bbpos_range_parse(optarg));
-			break;
-		case 'k':
-			darray_push(&key_filter, read_string_list_or_die(optarg, __bch2_btree_ids, "btree id"));
+		case 'h':
+			human_readable = true;
 			break;
 		case 'v':
 			opt_set(opts, verbose, true);
 			break;
-		case 'h':
-			list_journal_usage();
+		case 'H':
+			journal_stats_usage();
 			exit(EXIT_SUCCESS);
+		default:
+			journal_stats_usage();
+			exit(EXIT_FAILURE);
 		}
 	args_shift(optind);
 
@@ -262,7 +302,7 @@ int cmd_list_journal(int argc, char *argv[])
 	if (IS_ERR(c))
 		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
 
-	journal_entries_print(c, nr_entries, transaction_filter, key_filter);
+	journal_stats_print(c, human_readable);
 	bch2_fs_stop(c);
 	return 0;
 }
@@ -9,6 +9,7 @@
 #include <sys/sysmacros.h>
 #include <sys/types.h>
 #include <sys/vfs.h>
+#include <time.h>
 #include <unistd.h>
 
 #include <linux/fiemap.h>
@@ -20,6 +21,7 @@
 #include "cmds.h"
 #include "crypto.h"
 #include "libbcachefs.h"
+#include "progress.h"
 
 #include <linux/dcache.h>
 #include <linux/generic-radix-tree.h>
@@ -34,6 +36,7 @@
 #include "libbcachefs/fs-common.h"
 #include "libbcachefs/inode.h"
 #include "libbcachefs/io_write.h"
+#include "libbcachefs/journal.h"
 #include "libbcachefs/replicas.h"
 #include "libbcachefs/str_hash.h"
 #include "libbcachefs/super.h"
@@ -170,6 +173,30 @@ static struct bch_inode_unpacked create_file(struct bch_fs *c,
 	return new_inode;
 }
 
+/* When resuming, files may already exist in the new filesystem */
+static bool lookup_file(struct bch_fs *c,
+			struct bch_inode_unpacked *parent,
+			const char *name,
+			struct bch_inode_unpacked *inode)
+{
+	struct qstr qstr = QSTR(name);
+	struct bch_hash_info hash = bch2_hash_info_init(c, parent);
+	subvol_inum inum;
+
+	int ret = bch2_dirent_lookup(c, (subvol_inum) { 1, parent->bi_inum },
+				     &hash, &qstr, &inum);
+	if (bch2_err_matches(ret, ENOENT))
+		return false;
+	if (ret)
+		die("error looking up %s: %s", name, bch2_err_str(ret));
+
+	ret = bch2_inode_find_by_inum(c, inum, inode);
+	if (ret)
+		die("error looking up %s: %s", name, bch2_err_str(ret));
+
+	return true;
+}
+
 #define for_each_xattr_handler(handlers, handler)		\
 	if (handlers)						\
 		for ((handler) = *(handlers)++;			\
@@ -363,9 +390,30 @@ static void copy_link(struct bch_fs *c, struct bch_inode_unpacked *dst,
 	write_data(c, dst, 0, buf, round_up(ret, block_bytes(c)));
 }
 
+/*
+ * Extents we can't reference in place, and have to copy instead: those fiemap
+ * can't give us a usable device offset for, those shared with other files
+ * (reflinked, on xfs) since each bcachefs extent needs its own space, and
+ * anything below 1 MB, so it doesn't conflict with bcachefs's potentially
+ * larger superblock
+ */
+static bool migrate_extent_must_copy(struct fiemap_extent *e)
+{
+	return (e->fe_flags & (FIEMAP_EXTENT_UNKNOWN|
+			       FIEMAP_EXTENT_ENCODED|
+			       FIEMAP_EXTENT_NOT_ALIGNED|
+			       FIEMAP_EXTENT_DATA_INLINE|
+			       FIEMAP_EXTENT_SHARED)) ||
+		e->fe_physical < 1 << 20;
+}
+
+/*
+ * If @copied, the file was already copied before a migration was interrupted:
+ * we only need to note which extents it shares with the old filesystem
+ */
 static void copy_file(struct bch_fs *c, struct bch_inode_unpacked *dst,
 		      int src_fd, u64 src_size,
-		      char *src_path, ranges *extents)
+		      char *src_path, ranges *extents, bool copied)
 {
 	struct fiemap_iter iter;
 	struct fiemap_extent e;
@@ -380,30 +428,28 @@ static void copy_file(struct bch_fs *c, struct bch_inode_unpacked *dst,
 	fiemap_for_each(src_fd, iter, e) {
 		u64 src_max = roundup(src_size, block_bytes(c));
 
+		/* preallocated past EOF: */
+		if (e.fe_logical >= src_max)
+			continue;
+
 		e.fe_length = min(e.fe_length, src_max - e.fe_logical);
 
 		if ((e.fe_logical	& (block_bytes(c) - 1)) ||
 		    (e.fe_length	& (block_bytes(c) - 1)))
 			die("Unaligned extent in %s - can't handle", src_path);
 
-		if (e.fe_flags & (FIEMAP_EXTENT_UNKNOWN|
-				  FIEMAP_EXTENT_ENCODED|
-				  FIEMAP_EXTENT_NOT_ALIGNED|
-				  FIEMAP_EXTENT_DATA_INLINE)) {
-			copy_data(c, dst, src_fd, e.fe_logical,
-				  min(src_size - e.fe_logical,
-				      e.fe_length));
-			continue;
-		}
-
 		/*
-		 * if the data is below 1 MB, copy it so it doesn't conflict
-		 * with bcachefs's potentially larger superblock:
+		 * Preallocated but never written: reads as zeroes, but the
+		 * blocks may contain anyone's stale data - leave a hole:
 		 */
-		if (e.fe_physical < 1 << 20) {
-			copy_data(c, dst, src_fd, e.fe_logical,
-				  min(src_size - e.fe_logical,
-				      e.fe_length));
+		if (e.fe_flags & FIEMAP_EXTENT_UNWRITTEN)
+			continue;
+
+		if (migrate_extent_must_copy(&e)) {
+			if (!copied)
+				copy_data(c, dst, src_fd, e.fe_logical,
+					  min(src_size - e.fe_logical,
+					      e.fe_length));
 			continue;
 		}
 
@@ -411,19 +457,86 @@ static void copy_file(struct bch_fs *c, struct bch_inode_unpacked *dst,
 			die("Unaligned extent in %s - can't handle", src_path);
 
 		range_add(extents, e.fe_physical, e.fe_length);
-		link_data(c, dst, e.fe_logical, e.fe_physical, e.fe_length);
+		if (!copied)
+			link_data(c, dst, e.fe_logical, e.fe_physical, e.fe_length);
 	}
 	fiemap_iter_exit(&iter);
 }
 
+/*
+ * Progress is saved to bcachefs.migrate in the root of the filesystem being
+ * migrated, so that an interrupted migration can be resumed: the cursor is the
+ * number of files and directories, in readdir order, that have been copied
+ */
+#define MIGRATE_STATE_FILE	"bcachefs.migrate"
+#define MIGRATE_CHECKPOINT_SECS	10
+
+struct migrate_state {
+	u64			sb_offset;
+	u64			cursor;
+};
+
+static void migrate_state_write(int fd, struct migrate_state st)
+{
+	char buf[128];
+	int len = snprintf(buf, sizeof(buf),
+			   "sb_offset=%20llu\ncursor=%20llu\n",
+			   st.sb_offset, st.cursor);
+
+	/* fixed width, so it can be rewritten in place: */
+	xpwrite(fd, buf, len, 0, "migrate state");
+	if (fdatasync(fd))
+		die("error syncing migrate state: %m");
+}
+
+static struct migrate_state migrate_state_read(int fd)
+{
+	struct migrate_state st;
+	char buf[128] = {};
+
+	if (pread(fd, buf, sizeof(buf) - 1, 0) < 0 ||
+	    sscanf(buf, "sb_offset=%llu cursor=%llu",
+		   &st.sb_offset, &st.cursor) != 2)
+		die("error reading " MIGRATE_STATE_FILE);
+
+	return st;
+}
+
 struct copy_fs_state {
 	u64			bcachefs_inum;
 	dev_t			dev;
 
 	GENRADIX(u64)		hardlinks;
 	ranges			extents;
+
+	bool			resume;
+	int			state_fd;
+	u64			state_inum;
+	struct migrate_state	state;
+
+	u64			nr_done;
+	struct progress		progress;
+	time_t			last_checkpoint;
 };
 
+static void copy_fs_progress(struct copy_fs_state *s, struct bch_fs *c)
+{
+	progress_update(&s->progress, s->nr_done);
+
+	time_t now = time(NULL);
+	if (now - s->last_checkpoint >= MIGRATE_CHECKPOINT_SECS &&
+	    s->nr_done > s->state.cursor) {
+		/* Everything up to the cursor must be on disk first: */
+		int ret = bch2_journal_flush(&c->journal);
+		if (ret)
+			die("error flushing journal: %s", bch2_err_str(ret));
+
+		s->state.cursor = s->nr_done;
+		migrate_state_write(s->state_fd, s->state);
+		s->last_checkpoint = now;
+	}
+}
+
 static void copy_dir(struct copy_fs_state *s,
 		     struct bch_fs *c,
 		     struct bch_inode_unpacked *dst,
@@ -445,32 +558,49 @@ static void copy_dir(struct copy_fs_state *s,
 		if (!strcmp(d->d_name, ".") ||
 		    !strcmp(d->d_name, "..") ||
 		    !strcmp(d->d_name, "lost+found") ||
-		    stat.st_ino == s->bcachefs_inum)
+		    stat.st_ino == s->bcachefs_inum ||
+		    stat.st_ino == s->state_inum)
 			continue;
 
+		copy_fs_progress(s, c);
+
 		char *child_path = mprintf("%s/%s", src_path, d->d_name);
 
 		if (stat.st_dev != s->dev)
 			die("%s does not have correct st_dev!", child_path);
 
+		/*
+		 * Entries before the cursor were completely copied; entries
+		 * after it may have been partially copied, and are redone:
+		 */
+		bool done = s->nr_done++ < s->state.cursor;
+		bool exists = s->resume &&
+			lookup_file(c, dst, d->d_name, &inode);
+
 		u64 *dst_inum = S_ISREG(stat.st_mode)
 			? genradix_ptr_alloc(&s->hardlinks, stat.st_ino, GFP_KERNEL)
 			: NULL;
 
 		if (dst_inum && *dst_inum) {
-			create_link(c, dst, d->d_name, *dst_inum, S_IFREG);
+			if (!exists)
+				create_link(c, dst, d->d_name, *dst_inum, S_IFREG);
 			goto next;
 		}
 
-		inode = create_file(c, dst, d->d_name,
-				    stat.st_uid, stat.st_gid,
-				    stat.st_mode, stat.st_rdev);
+		if (!exists)
+			inode = create_file(c, dst, d->d_name,
+					    stat.st_uid, stat.st_gid,
+					    stat.st_mode, stat.st_rdev);
+		else if (!done)
+			inode.bi_sectors = 0;
 
 		if (dst_inum)
 			*dst_inum = inode.bi_inum;
 
-		copy_times(c, &inode, &stat);
-		copy_xattrs(c, &inode, d->d_name);
+		if (!done) {
+			copy_times(c, &inode, &stat);
+			copy_xattrs(c, &inode, d->d_name);
+		}
 
 		/* copy xattrs */
 
@@ -485,13 +615,14 @@ static void copy_dir(struct copy_fs_state *s,
 
 			fd = xopen(d->d_name, O_RDONLY|O_NOATIME);
 			copy_file(c, &inode, fd, stat.st_size,
-				  child_path, &s->extents);
+				  child_path, &s->extents, done);
 			close(fd);
 			break;
 		case DT_LNK:
 			inode.bi_size = stat.st_size;
 
-			copy_link(c, &inode, d->d_name);
+			if (!done)
+				copy_link(c, &inode, d->d_name);
 			break;
 		case DT_FIFO:
 		case DT_CHR:
@@ -516,9 +647,11 @@ next:
 
 static ranges reserve_new_fs_space(const char *file_path, unsigned block_size,
 				   u64 size, u64 *bcachefs_inum, dev_t dev,
-				   bool force)
+				   bool force, bool resume)
 {
-	int fd = force
+	int fd = resume
+		? open(file_path, O_RDWR)
+		: force
 		? open(file_path, O_RDWR|O_CREAT, 0600)
 		: open(file_path, O_RDWR|O_CREAT|O_EXCL, 0600);
 	if (fd < 0)
@@ -563,15 +696,18 @@ static ranges reserve_new_fs_space(const char *file_path, unsigned block_size,
 
 static void reserve_old_fs_space(struct bch_fs *c,
 				 struct bch_inode_unpacked *root_inode,
-				 ranges *extents)
+				 ranges *extents, bool resume)
 {
 	struct bch_dev *ca = c->devs[0];
 	struct bch_inode_unpacked dst;
 	struct hole_iter iter;
 	struct range i;
 
-	dst = create_file(c, root_inode, "old_migrated_filesystem",
-			  0, 0, S_IFREG|0400, 0);
+	if (resume && lookup_file(c, root_inode, "old_migrated_filesystem", &dst))
+		dst.bi_sectors = 0;
+	else
+		dst = create_file(c, root_inode, "old_migrated_filesystem",
+				  0, 0, S_IFREG|0400, 0);
 	dst.bi_size = bucket_to_sector(ca, ca->mi.nbuckets) << 9;
 
 	ranges_sort_merge(extents);
@@ -583,7 +719,8 @@ static void reserve_old_fs_space(struct bch_fs *c,
 }
 
 static void copy_fs(struct bch_fs *c, int src_fd, const char *src_path,
-		    u64 bcachefs_inum, ranges *extents)
+		    u64 bcachefs_inum, ranges *extents,
+		    int state_fd, struct migrate_state state, bool resume)
 {
 	syncfs(src_fd);
 
@@ -600,16 +737,34 @@ static void copy_fs(struct bch_fs *c, int src_fd, const char *src_path,
 	copy_times(c, &root_inode, &stat);
 	copy_xattrs(c, &root_inode, ".");
 
+	struct statfs statfs;
+	if (fstatfs(src_fd, &statfs))
+		die("statfs error: %m");
+
 	struct copy_fs_state s = {
 		.bcachefs_inum	= bcachefs_inum,
 		.dev		= stat.st_dev,
 		.extents	= *extents,
+		.resume		= resume,
+		.state_fd	= state_fd,
+		.state_inum	= xfstat(state_fd).st_ino,
+		.state		= state,
+		.last_checkpoint = time(NULL),
 	};
 
+	/* inodes in use on the source - an estimate, hardlinks are visited once per link */
+	progress_init(&s.progress, "migrate");
+	progress_stage(&s.progress, "copying files",
+		       statfs.f_files - statfs.f_ffree, false);
+
 	/* now, copy: */
 	copy_dir(&s, c, &root_inode, src_fd, src_path);
 
-	reserve_old_fs_space(c, &root_inode, &s.extents);
+	s.progress.total = s.nr_done;
+	progress_update(&s.progress, s.nr_done);
+	progress_finish(&s.progress);
+
+	reserve_old_fs_space(c, &root_inode, &s.extents, resume);
 
 	update_inode(c, &root_inode);
 
@@ -617,6 +772,209 @@ static void copy_fs(struct bch_fs *c, int src_fd, const char *src_path,
 	genradix_free(&s.hardlinks);
 }
 
+/*
+ * Migrating in place only works if fiemap gives us offsets on the block device
+ * the filesystem lives on; ext2/3/4 and xfs are known to work
+ */
+static void migrate_check_fs_type(int fd, const char *fs_path)
+{
+	struct statfs statfs;
+
+	if (fstatfs(fd, &statfs))
+		die("statfs error: %m");
+
+	switch (statfs.f_type) {
+	case EXT4_SUPER_MAGIC:
+	case XFS_SUPER_MAGIC:
+		break;
+	case BTRFS_SUPER_MAGIC:
+		die("%s is btrfs: can't migrate in place, fiemap on btrfs reports "
+		    "btrfs logical addresses, not device offsets", fs_path);
+	case BCACHEFS_STATFS_MAGIC:
+		die("%s is already bcachefs", fs_path);
+	default:
+		fprintf(stderr, "Warning: %s has unknown filesystem type 0x%lx; "
+			"only ext2/3/4 and xfs have been tested\n",
+			fs_path, (unsigned long) statfs.f_type);
+	}
+}
+
+/*
+ * Before touching anything, walk the filesystem to be migrated: check that
+ * every file can be converted, and that the data we have to copy, plus the new
+ * filesystem's metadata, will fit in the space reserved for bcachefs
+ */
+struct migrate_preflight {
+	dev_t			dev;
+	u64			bcachefs_inum;
+	unsigned		block_size;
+
+	u64			files;
+	u64			dirs;
+	u64			extents;
+	u64			link_bytes;
+	u64			copy_bytes;
+};
+
+static void migrate_preflight_file(struct migrate_preflight *p, int fd,
+				   u64 size, const char *path)
+{
+	struct fsxattr fsx;
+
+	if (!ioctl(fd, FS_IOC_FSGETXATTR, &fsx) &&
+	    (fsx.fsx_xflags & FS_XFLAG_REALTIME))
+		die("%s is on the xfs realtime device - can't migrate", path);
+
+	struct fiemap_iter iter;
+	struct fiemap_extent e;
+	u64 src_max = round_up(size, p->block_size);
+
+	fiemap_for_each(fd, iter, e) {
+		if (e.fe_logical >= src_max)
+			continue;
+
+		e.fe_length = min(e.fe_length, src_max - e.fe_logical);
+
+		if ((e.fe_logical	& (p->block_size - 1)) ||
+		    (e.fe_length	& (p->block_size - 1)))
+			die("Unaligned extent in %s - can't handle", path);
+
+		if (e.fe_flags & FIEMAP_EXTENT_UNWRITTEN)
+			continue;
+
+		p->extents++;
+
+		if (migrate_extent_must_copy(&e)) {
+			p->copy_bytes += e.fe_length;
+			continue;
+		}
+
+		if (e.fe_physical & (p->block_size - 1))
+			die("Unaligned extent in %s - can't handle", path);
+
+		p->link_bytes += e.fe_length;
+	}
+	fiemap_iter_exit(&iter);
+}
+
+static void migrate_preflight_dir(struct migrate_preflight *p,
+				  int dir_fd, const char *dir_path)
+{
+	DIR *dir = fdopendir(dir_fd);
+	struct dirent *d;
+
+	p->dirs++;
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (!strcmp(d->d_name, ".") ||
+		    !strcmp(d->d_name, "..") ||
+		    !strcmp(d->d_name, "lost+found"))
+			continue;
+
+		struct stat stat =
+			xfstatat(dir_fd, d->d_name, AT_SYMLINK_NOFOLLOW);
+
+		if (stat.st_ino == p->bcachefs_inum)
+			continue;
+
+		char *child_path = mprintf("%s/%s", dir_path, d->d_name);
+
+		if (stat.st_dev != p->dev)
+			die("%s is on a different filesystem - can't migrate", child_path);
+
+		if (S_ISDIR(stat.st_mode) || S_ISREG(stat.st_mode)) {
+			int fd = openat(dir_fd, d->d_name, O_RDONLY|O_NOATIME);
+			if (fd < 0)
+				die("error opening %s: %m", child_path);
+
+			if (S_ISDIR(stat.st_mode)) {
+				migrate_preflight_dir(p, fd, child_path);
+			} else {
+				migrate_preflight_file(p, fd, stat.st_size, child_path);
+				close(fd);
+			}
+		}
+
+		if (S_ISLNK(stat.st_mode))
+			p->copy_bytes += round_up(stat.st_size, p->block_size);
+
+		if (!S_ISDIR(stat.st_mode))
+			p->files++;
+		free(child_path);
+	}
+
+	if (errno)
+		die("readdir error: %m");
+	closedir(dir);
+}
+
+static void migrate_preflight(int fs_fd, const char *fs_path, dev_t dev,
+			      u64 bcachefs_inum, unsigned block_size,
+			      u64 reserve)
+{
+	struct migrate_preflight p = {
+		.dev		= dev,
+		.bcachefs_inum	= bcachefs_inum,
+		.block_size	= block_size,
+	};
+
+	/* delayed allocations have no physical location yet: */
+	syncfs(fs_fd);
+
+	/* not dup(): that would share the directory offset with @fs_fd */
+	migrate_preflight_dir(&p, xopen(fs_path, O_RDONLY|O_NOATIME), fs_path);
+
+	/*
+	 * Rough, on the generous side: inodes and dirents for everything, an
+	 * extent key per extent, and slack for btree nodes not being full and
+	 * the journal
+	 */
+	u64 metadata = 4 * ((p.files + p.dirs) * 256 +
+			    (p.files + p.dirs) * 64 +
+			    p.extents * 64);
+	u64 need = p.copy_bytes + metadata;
+
+	struct printbuf buf = PRINTBUF;
+	tools_printbuf_units(&buf, true);
+
+	printbuf_tabstop_push(&buf, 24);
+	printbuf_tabstop_push(&buf, 12);
+
+	prt_printf(&buf, "Files:\t%llu\r\n", p.files);
+	prt_printf(&buf, "Directories:\t%llu\r\n", p.dirs);
+	prt_printf(&buf, "Extents:\t%llu\r\n", p.extents);
+	prt_printf(&buf, "Data referenced in place:\t");
+	tools_prt_units_u64(&buf, p.link_bytes);
+	prt_printf(&buf, "\r\nData to be copied:\t");
+	tools_prt_units_u64(&buf, p.copy_bytes);
+	prt_printf(&buf, "\r\nMetadata (estimated):\t");
+	tools_prt_units_u64(&buf, metadata);
+	prt_printf(&buf, "\r\nSpace reserved:\t");
+	tools_prt_units_u64(&buf, reserve);
+	prt_printf(&buf, "\r\n");
+	printf("%s", buf.buf);
+
+	if (need > reserve) {
+		printbuf_reset(&buf);
+		tools_prt_units_u64(&buf, need);
+		die("Not enough space: need %s for copied data and metadata, "
+		    "more than the space reserved for bcachefs", buf.buf);
+	}
+
+	struct statfs statfs;
+	if (fstatfs(fs_fd, &statfs))
+		die("statfs error: %m");
+
+	u64 avail = (u64) statfs.f_bavail * statfs.f_bsize;
+	if (avail < reserve) {
+		printbuf_reset(&buf);
+		tools_prt_units_u64(&buf, avail);
+		die("Not enough free space on %s: %s available", fs_path, buf.buf);
+	}
+
+	printbuf_exit(&buf);
+}
+
 static void find_superblock_space(ranges extents,
 				  struct format_opts opts,
 				  struct dev_opts *dev)
@@ -642,12 +1000,17 @@ static void migrate_usage(void)
 {
 	puts("bcachefs migrate - migrate an existing filesystem to bcachefs\n"
 	     "Usage: bcachefs migrate [OPTION]...\n"
+	     "       bcachefs migrate rollback [OPTION]...\n"
 	     "\n"
 	     "Options:\n"
 	     "  -f fs                  Root of filesystem to migrate(s)\n"
 	     "      --encrypted        Enable whole filesystem encryption (chacha20/poly1305)\n"
 	     "      --no_passphrase    Don't encrypt master encryption key\n"
 	     "  -F                     Force, even if metadata file already exists\n"
+	     "  -r, --resume           Resume an interrupted migration\n"
+     "  -n, --dry-run          Only check whether the filesystem can be migrated\n"
+	     "      --no-progress      Don't show progress while copying\n"
+	     "      --progress-json    Report progress as JSON lines on stderr\n"
 	     "  -h                     Display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
@@ -655,6 +1018,10 @@ static void migrate_usage(void)
 static const struct option migrate_opts[] = {
 	{ "encrypted",		no_argument, NULL, 'e' },
 	{ "no_passphrase",	no_argument, NULL, 'p' },
+	{ "resume",		no_argument, NULL, 'r' },
+	{ "dry-run",		no_argument, NULL, 'n' },
+	{ "no-progress",	no_argument, NULL, OPT_NO_PROGRESS },
+	{ "progress-json",	no_argument, NULL, OPT_PROGRESS_JSON },
 	{ NULL }
 };
 
@@ -662,7 +1029,7 @@ static int migrate_fs(const char		*fs_path,
 		      struct bch_opt_strs	fs_opt_strs,
 		      struct bch_opts		fs_opts,
 		      struct format_opts	format_opts,
-		      bool force)
+		      bool force, bool resume, bool dry_run)
 {
 	if (!path_is_fs_root(fs_path))
 		die("%s is not a filesystem root", fs_path);
@@ -673,6 +1040,8 @@ static int migrate_fs(const char		*fs_path,
 	if (!S_ISDIR(stat.st_mode))
 		die("%s is not a directory", fs_path);
 
+	migrate_check_fs_type(fs_fd, fs_path);
+
 	struct dev_opts dev = dev_opts_default();
 
 	dev.path = dev_t_to_path(stat.st_dev);
@@ -686,32 +1055,72 @@ static int migrate_fs(const char		*fs_path,
 	opt_set(fs_opts, block_size, get_blocksize(dev.bdev->bd_fd));
 
 	char *file_path = mprintf("%s/bcachefs", fs_path);
-	printf("Creating new filesystem on %s in space reserved at %s\n",
-	       dev.path, file_path);
+	char *state_path = mprintf("%s/" MIGRATE_STATE_FILE, fs_path);
+	struct migrate_state state = {};
+	int state_fd;
+
+	if (!resume) {
+		/* with -F, the metadata file may already exist: */
+		struct stat old_file;
+		u64 old_inum = !lstat(file_path, &old_file) ? old_file.st_ino : 0;
+
+		migrate_preflight(fs_fd, fs_path, stat.st_dev, old_inum,
+				  fs_opts.block_size,
+				  get_size(dev.bdev->bd_fd) / 5);
+	}
 
-	dev.size	= get_size(dev.bdev->bd_fd);
-	dev.bucket_size = bch2_pick_bucket_size(fs_opts, &dev);
-	dev.nbuckets	= dev.size / dev.bucket_size;
+	if (dry_run) {
+		printf("%s can be migrated\n", fs_path);
+		return 0;
+	}
 
-	bch2_check_bucket_size(fs_opts, &dev);
+	if (resume) {
+		state_fd = open(state_path, O_RDWR);
+		if (state_fd < 0)
+			die("Error opening %s: %m; no migration to resume?", state_path);
+
+		state = migrate_state_read(state_fd);
+		printf("Resuming migration of %s to %s, from %llu files copied\n",
+		       fs_path, dev.path, state.cursor);
+	} else {
+		printf("Creating new filesystem on %s in space reserved at %s\n",
+		       dev.path, file_path);
+	}
 
 	u64 bcachefs_inum;
 	ranges extents = reserve_new_fs_space(file_path,
 				fs_opts.block_size >> 9,
 				get_size(dev.bdev->bd_fd) / 5,
-				&bcachefs_inum, stat.st_dev, force);
+				&bcachefs_inum, stat.st_dev, force, resume);
+
+	if (!resume) {
+		dev.size	= get_size(dev.bdev->bd_fd);
+		dev.bucket_size = bch2_pick_bucket_size(fs_opts, &dev);
+		dev.nbuckets	= dev.size / dev.bucket_size;
 
-	find_superblock_space(extents, format_opts, &dev);
+		bch2_check_bucket_size(fs_opts, &dev);
 
-	struct bch_sb *sb = bch2_format(fs_opt_strs,
-					fs_opts, format_opts, &dev, 1);
-	u64 sb_offset = le64_to_cpu(sb->layout.sb_offset[0]);
+		find_superblock_space(extents, format_opts, &dev);
 
-	if (format_opts.passphrase)
-		bch2_add_key(sb, "user", "user", format_opts.passphrase);
+		struct bch_sb *sb = bch2_format(fs_opt_strs,
+						fs_opts, format_opts, &dev, 1);
+		state.sb_offset = le64_to_cpu(sb->layout.sb_offset[0]);
 
-	free(sb);
+		if (format_opts.passphrase)
+			bch2_add_key(sb, "user", "user", format_opts.passphrase);
 
+		free(sb);
+
+		state_fd = force
+			? open(state_path, O_RDWR|O_CREAT|O_TRUNC, 0600)
+			: open(state_path, O_RDWR|O_CREAT|O_EXCL, 0600);
+		if (state_fd < 0)
+			die("Error creating %s: %m", state_path);
+
+		migrate_state_write(state_fd, state);
+	}
+
+	u64 sb_offset = state.sb_offset;
 	struct bch_opts opts = bch2_opts_empty();
 	struct bch_fs *c = NULL;
 	char *path[1] = { dev.path };
@@ -739,10 +1148,16 @@ static int migrate_fs(const char		*fs_path,
 	if (ret)
 		die("Error starting new filesystem: %s", bch2_err_str(ret));
 
-	copy_fs(c, fs_fd, fs_path, bcachefs_inum, &extents);
+	copy_fs(c, fs_fd, fs_path, bcachefs_inum, &extents,
+		state_fd, state, resume);
 
 	bch2_fs_stop(c);
 
+	/* The copy is complete, there's nothing left to resume: */
+	close(state_fd);
+	if (unlink(state_path))
+		die("Error removing %s: %m", state_path);
+
 	printf("Migrate complete, running fsck:\n");
 	opt_set(opts, nostart,	false);
 	opt_set(opts, nochanges, true);
@@ -760,7 +1175,7 @@ static int migrate_fs(const char		*fs_path,
 	       "\n"
 	       "After verifying that the new filesystem is correct, to create a\n"
 	       "superblock at the default offset and finish the migration run\n"
-	       "  bcachefs migrate-superblock -d %s -o %llu\n"
+	       "  bcachefs migrate-superblock -d %s -o %llu -b <backup file>\n"
 	       "\n"
 	       "The new filesystem will have a file at /old_migrated_filesystem\n"
 	       "referencing all disk space that might be used by the existing\n"
@@ -771,18 +1186,23 @@ static int migrate_fs(const char		*fs_path,
 	return 0;
 }
 
+static int cmd_migrate_rollback(int argc, char *argv[]);
+
 int cmd_migrate(int argc, char *argv[])
 {
 	struct format_opts format_opts = format_opts_default();
 	char *fs_path = NULL;
-	bool no_passphrase = false, force = false;
+	bool no_passphrase = false, force = false, resume = false, dry_run = false;
 	int opt;
 
+	if (argc > 1 && !strcmp(argv[1], "rollback"))
+		return cmd_migrate_rollback(argc - 1, argv + 1);
+
 	struct bch_opt_strs fs_opt_strs =
 		bch2_cmdline_opts_get(&argc, argv, OPT_FORMAT);
 	struct bch_opts fs_opts = bch2_parse_opts(fs_opt_strs);
 
-	while ((opt = getopt_long(argc, argv, "f:Fh",
+	while ((opt = getopt_long(argc, argv, "f:Fnrh",
 				  migrate_opts, NULL)) != -1)
 		switch (opt) {
 		case 'f':
@@ -797,6 +1217,16 @@ int cmd_migrate(int argc, char *argv[])
 		case 'F':
 			force = true;
 			break;
+		case 'n':
+			dry_run = true;
+			break;
+		case 'r':
+			resume = true;
+			break;
+		case OPT_NO_PROGRESS:
+		case OPT_PROGRESS_JSON:
+			progress_opt(opt);
+			break;
 		case 'h':
 			migrate_usage();
 			exit(EXIT_SUCCESS);
@@ -805,17 +1235,59 @@ int cmd_migrate(int argc, char *argv[])
 	if (!fs_path)
 		die("Please specify a filesystem to migrate");
 
-	if (format_opts.encrypted && !no_passphrase)
+	if (format_opts.encrypted && !no_passphrase && !resume && !dry_run)
 		format_opts.passphrase = read_passphrase_twice("Enter passphrase: ");
 
 	int ret = migrate_fs(fs_path,
 			     fs_opt_strs,
 			     fs_opts,
-			     format_opts, force);
+			     format_opts, force, resume, dry_run);
 	bch2_opt_strs_free(&fs_opt_strs);
 	return ret;
 }
 
+/*
+ * migrate-superblock overwrites the start of the device, where the old
+ * filesystem keeps its own superblock; the original contents are saved to a
+ * backup file so that migrate rollback can put them back
+ */
+#define MIGRATE_BACKUP_MAGIC	"bcachefs migrate"
+
+struct migrate_backup {
+	char			magic[16];
+	__uuid_t		uuid;
+	__le64			sb_offset;
+	__le64			len;
+};
+
+static void migrate_backup_write(const char *path, int dev_fd,
+				 struct bch_sb *sb, u64 sb_offset)
+{
+	u64 len = (BCH_SB_SECTOR + (1ULL << sb->layout.sb_max_size_bits)) << 9;
+	struct migrate_backup b = {
+		.uuid		= sb->user_uuid,
+		.sb_offset	= cpu_to_le64(sb_offset),
+		.len		= cpu_to_le64(len),
+	};
+	void *buf = xmalloc(len);
+
+	memcpy(b.magic, MIGRATE_BACKUP_MAGIC, sizeof(b.magic));
+	xpread(dev_fd, buf, len, 0);
+
+	int fd = open(path, O_WRONLY|O_CREAT|O_EXCL, 0600);
+	if (fd < 0)
+		die("Error creating %s: %m", path);
+
+	xpwrite(fd, &b, sizeof(b), 0, "migrate backup");
+	xpwrite(fd, buf, len, sizeof(b), "migrate backup");
+	if (fsync(fd))
+		die("Error syncing %s: %m", path);
+	close(fd);
+	free(buf);
+
+	printf("Saved the first %llu bytes of the device to %s\n", len, path);
+}
+
 static void migrate_superblock_usage(void)
 {
 	puts("bcachefs migrate-superblock - create default superblock after migrating\n"
@@ -824,21 +1296,25 @@ static void migrate_superblock_usage(void)
 	     "Options:\n"
 	     "  -d device     Device to create superblock for\n"
 	     "  -o offset     Offset of existing superblock\n"
+	     "  -b file       Save the old filesystem's superblock to file, for migrate rollback\n"
 	     "  -h            Display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 }
 
 int cmd_migrate_superblock(int argc, char *argv[])
 {
-	char *dev = NULL;
+	char *dev = NULL, *backup = NULL;
 	u64 offset = 0;
 	int opt, ret;
 
-	while ((opt = getopt(argc, argv, "d:o:h")) != -1)
+	while ((opt = getopt(argc, argv, "d:o:b:h")) != -1)
 		switch (opt) {
 			case 'd':
 				dev = optarg;
 				break;
+			case 'b':
+				backup = optarg;
+				break;
 			case 'o':
 				ret = kstrtou64(optarg, 10, &offset);
 				if (ret)
@@ -873,8 +1349,157 @@ int cmd_migrate_superblock(int argc, char *argv[])
 
 	sb->layout.sb_offset[0] = cpu_to_le64(BCH_SB_SECTOR);
 
+	if (backup)
+		migrate_backup_write(backup, fd, sb, offset);
+	else
+		fprintf(stderr, "No backup file (-b), migrate rollback won't be possible\n");
+
 	bch2_super_write(fd, sb);
 	close(fd);
 
 	return 0;
 }
+
+/*
+ * Rolling back is only safe if the new filesystem hasn't reused space the old
+ * filesystem was using: that space is referenced by /old_migrated_filesystem,
+ * so check that it still exists and none of its pointers are stale
+ */
+static void migrate_rollback_check(char *dev, u64 sb_offset)
+{
+	struct bch_opts opts = bch2_opts_empty();
+	char *path[1] = { dev };
+
+	opt_set(opts, sb,		sb_offset);
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, read_only,	true);
+
+	struct bch_fs *c = bch2_fs_open(path, 1, opts);
+	if (IS_ERR(c))
+		die("Error opening new filesystem: %s", bch2_err_str(PTR_ERR(c)));
+
+	struct bch_dev *ca = c->devs[0];
+	struct bch_inode_unpacked root, old;
+
+	int ret = bch2_inode_find_by_inum(c, (subvol_inum) { 1, BCACHEFS_ROOT_INO }, &root);
+	if (ret)
+		die("error looking up root directory: %s", bch2_err_str(ret));
+
+	if (!lookup_file(c, &root, "old_migrated_filesystem", &old))
+		die("/old_migrated_filesystem has been deleted, can't roll back");
+
+	if (old.bi_size != bucket_to_sector(ca, ca->mi.nbuckets) << 9)
+		die("/old_migrated_filesystem has been truncated, can't roll back");
+
+	u64 stale = 0;
+	struct btree_trans *trans = bch2_trans_get(c);
+
+	ret = for_each_btree_key_upto(trans, iter, BTREE_ID_extents,
+				      POS(old.bi_inum, 0), POS(old.bi_inum, U64_MAX),
+				      0, k, ({
+		struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+
+		bkey_for_each_ptr(ptrs, ptr)
+			stale += ptr->dev != 0 || dev_ptr_stale(ca, ptr);
+		0;
+	}));
+	bch2_trans_put(trans);
+
+	if (ret)
+		die("error walking /old_migrated_filesystem: %s", bch2_err_str(ret));
+	if (stale)
+		die("space used by the old filesystem has been reused (%llu stale extents), can't roll back",
+		    stale);
+
+	bch2_fs_stop(c);
+}
+
+static void migrate_rollback_usage(void)
+{
+	puts("bcachefs migrate rollback - restore the filesystem that was migrated from\n"
+	     "Usage: bcachefs migrate rollback [OPTION]...\n"
+	     "\n"
+	     "Puts back the old filesystem's superblock saved by migrate-superblock -b.\n"
+	     "Changes made in the new filesystem are lost, and data in files that were\n"
+	     "deleted or rewritten there may have been overwritten.\n"
+	     "\n"
+	     "Before migrate-superblock has been run, the old filesystem is intact:\n"
+	     "delete <fs>/bcachefs to discard the migration.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -d device     Device that was migrated\n"
+	     "  -b file       Backup file written by migrate-superblock\n"
+	     "  -f            Skip checking that the old filesystem's space is intact\n"
+	     "  -h            Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static int cmd_migrate_rollback(int argc, char *argv[])
+{
+	char *dev = NULL, *backup = NULL;
+	bool force = false;
+	int opt;
+
+	while ((opt = getopt(argc, argv, "d:b:fh")) != -1)
+		switch (opt) {
+		case 'd':
+			dev = optarg;
+			break;
+		case 'b':
+			backup = optarg;
+			break;
+		case 'f':
+			force = true;
+			break;
+		case 'h':
+			migrate_rollback_usage();
+			exit(EXIT_SUCCESS);
+		}
+
+	if (!dev)
+		die("Please specify a device");
+
+	int fd = xopen(dev, O_RDWR);
+	struct bch_sb sb;
+
+	xpread(fd, &sb, sizeof(sb), BCH_SB_SECTOR << 9);
+	if (memcmp(&sb.magic, &BCACHE_MAGIC, sizeof(sb.magic)) &&
+	    memcmp(&sb.magic, &BCHFS_MAGIC, sizeof(sb.magic))) {
+		printf("%s has no bcachefs superblock at the default offset: migrate-superblock\n"
+		       "hasn't been run, and the old filesystem is intact. To discard the\n"
+		       "migration, mount it and delete the bcachefs file in its root.\n", dev);
+		return 0;
+	}
+
+	if (!backup)
+		die("Please specify the backup file written by migrate-superblock");
+
+	int backup_fd = xopen(backup, O_RDONLY);
+	struct migrate_backup b;
+
+	xpread(backup_fd, &b, sizeof(b), 0);
+	if (memcmp(b.magic, MIGRATE_BACKUP_MAGIC, sizeof(b.magic)))
+		die("%s is not a migrate backup file", backup);
+	if (memcmp(&b.uuid, &sb.user_uuid, sizeof(b.uuid)))
+		die("%s is a backup of a different filesystem", backup);
+
+	u64 len = le64_to_cpu(b.len);
+	void *buf = xmalloc(len);
+	xpread(backup_fd, buf, len, sizeof(b));
+	close(backup_fd);
+
+	if (!force)
+		migrate_rollback_check(dev, le64_to_cpu(b.sb_offset));
+
+	xpwrite(fd, buf, len, 0, "old superblock");
+	if (fsync(fd))
+		die("Error syncing %s: %m", dev);
+	close(fd);
+	free(buf);
+
+	printf("Restored the old filesystem's superblock on %s\n"
+	       "The bcachefs filesystem can still be mounted with -o sb=%llu until the\n"
+	       "old filesystem is written to\n",
+	       dev, le64_to_cpu(b.sb_offset));
+	return 0;
+}
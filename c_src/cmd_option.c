@@ -26,42 +26,207 @@
 
 static void set_option_usage(void)
 {
-	puts("bcachefs set-option \n"
-	     "Usage: bcachefs set-option [OPTION].. device\n"
+	puts("bcachefs set-option - set filesystem options\n"
+	     "Usage: bcachefs set-option [OPTION]... <devices|mountpoint> [name=value]...\n"
+	     "\n"
+	     "Options are written to the superblock when the filesystem is offline, and\n"
+	     "set via sysfs (which also updates the superblock) when it's mounted. They\n"
+	     "may be given either as --name=value or as name=value.\n"
 	     "\n"
 	     "Options:\n");
 	bch2_opts_usage(OPT_MOUNT);
-	puts("  -h, --help                  display this help and exit\n"
+	puts("      --mount-options=opts    Default mount options, used by mount.bcachefs\n"
+	     "                              on this machine when none are given; empty\n"
+	     "                              to remove\n"
+	     "  -h, --help                  display this help and exit\n"
 	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
 	exit(EXIT_SUCCESS);
 }
 
+/*
+ * Options that take effect for new writes, but don't touch what's already on
+ * disk:
+ */
+static const char *set_option_existing_data(unsigned id, bool online)
+{
+	switch (id) {
+	case Opt_data_replicas:
+	case Opt_metadata_replicas:
+		return "existing data isn't rereplicated: run bcachefs data rereplicate";
+	case Opt_data_checksum:
+	case Opt_metadata_checksum:
+	case Opt_erasure_code:
+		return "only applies to new writes";
+	case Opt_str_hash:
+		return "only applies to new directories";
+	case Opt_compression:
+	case Opt_background_compression:
+	case Opt_foreground_target:
+	case Opt_background_target:
+	case Opt_promote_target:
+		/* the kernel kicks off a rebalance scan when these are changed: */
+		return !online
+			? "existing data is only moved or recompressed when this is changed while mounted"
+			: NULL;
+	default:
+		return NULL;
+	}
+}
+
+static void set_option_report(unsigned id, const char *val, bool online)
+{
+	const char *msg = set_option_existing_data(id, online);
+
+	printf("%s=%s", bch2_opt_table[id].attr.name, val);
+	if (!online)
+		printf(" (takes effect on next mount)");
+	if (msg)
+		printf(": %s", msg);
+	printf("\n");
+}
+
+/* Options may also be given as name=value, after the devices: */
+static void set_option_parse_positional(int *argc, char *argv[],
+					struct bch_opt_strs *strs,
+					const char **mount_opts)
+{
+	unsigned i, nr = 0;
+
+	for (i = 0; i < *argc; i++) {
+		char *eq = argv[i] + strspn(argv[i], "abcdefghijklmnopqrstuvwxyz_");
+
+		if (*eq != '=' || eq == argv[i]) {
+			argv[nr++] = argv[i];
+			continue;
+		}
+
+		char *name = strndup(argv[i], eq - argv[i]);
+
+		if (!strcmp(name, "mount_options")) {
+			*mount_opts = eq + 1;
+			free(name);
+			continue;
+		}
+
+		int id = bch2_opt_lookup(name);
+
+		if (id < 0)
+			die("Unknown option %s", name);
+		if (!(bch2_opt_table[id].flags & OPT_MOUNT))
+			die("%s can only be set at format time", name);
+
+		free(strs->by_id[id]);
+		strs->by_id[id] = strdup(eq + 1);
+		free(name);
+	}
+
+	*argc = nr;
+}
+
+static int set_option_online(struct bchfs_handle fs, struct bch_opt_strs *strs)
+{
+	int ret = 0;
+
+	for (unsigned i = 0; i < bch2_opts_nr; i++) {
+		if (!strs->by_id[i])
+			continue;
+
+		const char *name = bch2_opt_table[i].attr.name;
+
+		if (!(bch2_opt_table[i].flags & OPT_RUNTIME)) {
+			fprintf(stderr, "%s can't be changed while mounted: set it offline, "
+				"or remount with -o %s=%s\n", name, name, strs->by_id[i]);
+			ret = EXIT_FAILURE;
+			continue;
+		}
+
+		char *path = mprintf("options/%s", name);
+		int fd = openat(fs.sysfs_fd, path, O_WRONLY);
+		ssize_t len = strlen(strs->by_id[i]);
+
+		if (fd < 0 || write(fd, strs->by_id[i], len) != len) {
+			fprintf(stderr, "error setting %s: %m\n", name);
+			ret = EXIT_FAILURE;
+		} else {
+			set_option_report(i, strs->by_id[i], true);
+		}
+
+		if (fd >= 0)
+			close(fd);
+		free(path);
+	}
+
+	return ret;
+}
+
+static void set_mount_options(__uuid_t uuid, const char *mount_opts)
+{
+	bch_mount_opts_set(uuid, mount_opts);
+
+	if (*mount_opts)
+		printf("mount_options=%s (used when mounting without -o)\n", mount_opts);
+	else
+		printf("mount_options removed\n");
+}
+
 int cmd_set_option(int argc, char *argv[])
 {
+	static const struct option longopts[] = {
+		{ "mount-options",	required_argument,	NULL, 'o' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
 	struct bch_opt_strs new_opt_strs = bch2_cmdline_opts_get(&argc, argv, OPT_MOUNT);
-	struct bch_opts new_opts = bch2_parse_opts(new_opt_strs);
 	struct bch_opts open_opts = bch2_opts_empty();
+	const char *mount_opts = NULL;
+	struct stat st;
 	unsigned i;
 	int opt, ret = 0;
 
 	opt_set(open_opts, nostart, true);
 
-	while ((opt = getopt(argc, argv, "h")) != -1)
+	while ((opt = getopt_long(argc, argv, "h", longopts, NULL)) != -1)
 		switch (opt) {
+		case 'o':
+			mount_opts = optarg;
+			break;
 		case 'h':
 			set_option_usage();
 			break;
 		}
 	args_shift(optind);
 
+	set_option_parse_positional(&argc, argv, &new_opt_strs, &mount_opts);
+
 	if (!argc) {
 		fprintf(stderr, "Please supply device(s)\n");
 		exit(EXIT_FAILURE);
 	}
 
+	/* validates, and dies on invalid values: */
+	struct bch_opts new_opts = bch2_parse_opts(new_opt_strs);
+
+	if (argc == 1 && !stat(argv[0], &st) && S_ISDIR(st.st_mode)) {
+		struct bchfs_handle fs = bcache_fs_open(argv[0]);
+
+		ret = set_option_online(fs, &new_opt_strs);
+		if (!ret && mount_opts)
+			set_mount_options(fs.uuid, mount_opts);
+		bcache_fs_close(fs);
+		goto out;
+	}
+
 	for (i = 0; i < argc; i++)
-		if (dev_mounted(argv[i]))
-			goto online;
+		if (dev_mounted(argv[i])) {
+			int dev_idx;
+			struct bchfs_handle fs = bchu_fs_open_by_dev(argv[i], &dev_idx);
+
+			ret = set_option_online(fs, &new_opt_strs);
+			if (!ret && mount_opts)
+				set_mount_options(fs.uuid, mount_opts);
+			bcache_fs_close(fs);
+			goto out;
+		}
 
 	struct bch_fs *c = bch2_fs_open(argv, argc, open_opts);
 	if (IS_ERR(c)) {
@@ -77,31 +242,21 @@ int cmd_set_option(int argc, char *argv[])
 
 		ret = bch2_opt_check_may_set(c, i, v);
 		if (ret < 0) {
-			fprintf(stderr, "error setting %s: %i\n",
-				bch2_opt_table[i].attr.name, ret);
+			fprintf(stderr, "error setting %s: %s\n",
+				bch2_opt_table[i].attr.name, bch2_err_str(ret));
 			break;
 		}
 
 		bch2_opt_set_sb(c, bch2_opt_table + i, v);
 		bch2_opt_set_by_id(&c->opts, i, v);
+		set_option_report(i, new_opt_strs.by_id[i], false);
 	}
 
+	if (!ret && mount_opts)
+		set_mount_options(c->sb.user_uuid, mount_opts);
+
 	bch2_fs_stop(c);
+out:
+	bch2_opt_strs_free(&new_opt_strs);
 	return ret;
-online:
-	{
-		unsigned dev_idx;
-		struct bchfs_handle fs = bchu_fs_open_by_dev(argv[i], &dev_idx);
-
-		for (i = 0; i < bch2_opts_nr; i++) {
-			if (!new_opt_strs.by_id[i])
-				continue;
-
-			char *path = mprintf("options/%s", bch2_opt_table[i].attr.name);
-
-			write_file_str(fs.sysfs_fd, path, new_opt_strs.by_id[i]);
-			free(path);
-		}
-	}
-	return 0;
 }
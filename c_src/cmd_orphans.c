@@ -0,0 +1,244 @@
+#include <getopt.h>
+#include <stdio.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/super.h"
+
+/*
+ * An inode is flagged unlinked when its last link goes away, and put in the
+ * deleted_inodes btree, to be deleted once it's no longer open. If that
+ * doesn't happen - a crash, or an interrupted deletion - it stays, using
+ * space, until fsck or an unclean mount gets to it:
+ */
+struct orphan {
+	u64			inum;
+	u32			snapshot;
+	u64			size;
+	u64			sectors;
+	bool			queued;		/* in deleted_inodes */
+};
+
+typedef DARRAY(struct orphan) orphans;
+
+static void fs_orphans_usage(void)
+{
+	puts("bcachefs fs orphans - list unlinked inodes that haven't been deleted\n"
+	     "Usage: bcachefs fs orphans [OPTION]... <devices>\n"
+	     "\n"
+	     "Inodes with no links left are normally deleted when they're closed; after a\n"
+	     "crash they stay behind, still using space, until fsck or an unclean mount\n"
+	     "deletes them. Lists them, with their size, space allocated, and whether\n"
+	     "they're queued for deletion (in the deleted_inodes btree). Only works on\n"
+	     "unmounted filesystems.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -r, --reap                   Delete them\n"
+	     "  -y, --yes                    Don't ask before deleting\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -v, --verbose                Verbose mode\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static int orphans_find(struct btree_trans *trans, orphans *o)
+{
+	darray_exit(o);
+
+	return for_each_btree_key(trans, iter, BTREE_ID_inodes, POS_MIN,
+				  BTREE_ITER_all_snapshots|
+				  BTREE_ITER_prefetch, k, ({
+		struct bch_inode_unpacked u;
+		int ret2 = 0;
+
+		if (bkey_is_inode(k.k) &&
+		    !bch2_inode_unpack(k, &u) &&
+		    (u.bi_flags & BCH_INODE_unlinked)) {
+			struct orphan n = {
+				.inum		= u.bi_inum,
+				.snapshot	= k.k->p.snapshot,
+				.size		= u.bi_size,
+				.sectors	= u.bi_sectors,
+			};
+			struct btree_iter d_iter;
+			struct bkey_s_c d = bch2_bkey_get_iter(trans, &d_iter,
+						BTREE_ID_deleted_inodes, k.k->p, 0);
+
+			ret2 = bkey_err(d);
+			n.queued = !ret2 && d.k->type == KEY_TYPE_set;
+			bch2_trans_iter_exit(trans, &d_iter);
+
+			ret2 = ret2 ?: darray_push(o, n);
+		}
+		ret2;
+	}));
+}
+
+static void orphans_to_text(struct printbuf *out, orphans *o)
+{
+	u64 sectors = 0;
+	unsigned nr_unqueued = 0;
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 20);
+	printbuf_tabstop_push(out, 12);
+	printbuf_tabstop_push(out, 12);
+
+	prt_str(out, "inode:snapshot");
+	prt_tab_rjust(out);
+	prt_str(out, "size");
+	prt_tab_rjust(out);
+	prt_str(out, "allocated");
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	darray_for_each(*o, i) {
+		prt_printf(out, "%llu:%u", i->inum, i->snapshot);
+		prt_tab_rjust(out);
+		tools_prt_units_u64(out, i->size);
+		prt_tab_rjust(out);
+		tools_prt_units_u64(out, i->sectors << 9);
+		prt_tab_rjust(out);
+		if (!i->queued)
+			prt_str(out, " (not queued for deletion)");
+		prt_newline(out);
+
+		sectors += i->sectors;
+		nr_unqueued += !i->queued;
+	}
+
+	prt_printf(out, "%zu unlinked inodes, ", o->nr);
+	tools_prt_units_u64(out, sectors << 9);
+	prt_str(out, " allocated");
+	prt_newline(out);
+
+	if (nr_unqueued)
+		prt_printf(out, "%u not queued for deletion: fsck will find these, or --reap\n",
+			   nr_unqueued);
+}
+
+static int orphans_reap(struct bch_fs *c, orphans *o)
+{
+	struct btree_trans *trans = bch2_trans_get(c);
+	int ret = 0;
+
+	/* bch2_delete_dead_inodes() only looks at deleted_inodes: */
+	darray_for_each(*o, i)
+		if (!i->queued) {
+			ret = commit_do(trans, NULL, NULL, BCH_TRANS_COMMIT_no_enospc,
+					bch2_btree_bit_mod(trans, BTREE_ID_deleted_inodes,
+							   SPOS(0, i->inum, i->snapshot), true));
+			if (ret)
+				goto err;
+		}
+
+	ret = bch2_delete_dead_inodes(c);
+err:
+	bch2_trans_put(trans);
+	return ret;
+}
+
+int cmd_fs_orphans(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "reap",		no_argument,		NULL, 'r' },
+		{ "yes",		no_argument,		NULL, 'y' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	orphans o = {};
+	bool reap = false, yes = false;
+	int opt, ret;
+
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "ryhvH", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'r':
+			reap = true;
+			break;
+		case 'y':
+			yes = true;
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'v':
+			opt_set(opts, verbose, true);
+			break;
+		case 'H':
+			fs_orphans_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			fs_orphans_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	for (unsigned i = 0; i < argc; i++)
+		if (dev_mounted(argv[i]))
+			die("%s is mounted; fs orphans only works on unmounted filesystems", argv[i]);
+
+	if (!reap) {
+		opt_set(opts, nochanges,	true);
+		opt_set(opts, read_only,	true);
+	}
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	struct btree_trans *trans = bch2_trans_get(c);
+	ret = orphans_find(trans, &o);
+	bch2_trans_put(trans);
+	if (ret)
+		die("error walking inodes: %s", bch2_err_str(ret));
+
+	orphans_to_text(&buf, &o);
+	printf("%s", buf.buf);
+
+	if (reap && o.nr) {
+		size_t nr = o.nr;
+
+		if (!yes) {
+			printf("Delete these %zu inodes and their data? ", nr);
+			if (!ask_yn())
+				goto out;
+		}
+
+		ret = orphans_reap(c, &o);
+		if (ret)
+			die("error deleting unlinked inodes: %s", bch2_err_str(ret));
+
+		trans = bch2_trans_get(c);
+		ret = orphans_find(trans, &o);
+		bch2_trans_put(trans);
+		if (ret)
+			die("error walking inodes: %s", bch2_err_str(ret));
+
+		printf("%zu deleted", nr - o.nr);
+		if (o.nr)
+			printf(", %zu left (still referenced from a snapshot, or need fsck)", o.nr);
+		printf("\n");
+	}
+out:
+	printbuf_exit(&buf);
+	darray_exit(&o);
+	bch2_fs_stop(c);
+	return 0;
+}
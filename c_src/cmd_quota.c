@@ -0,0 +1,682 @@
+#include <dirent.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <grp.h>
+#include <pwd.h>
+#include <stdio.h>
+#include <sys/ioctl.h>
+#include <sys/stat.h>
+#include <sys/syscall.h>
+#include <unistd.h>
+
+#include <linux/fs.h>
+#include <linux/quota.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+/*
+ * Quotas are managed through the standard quotactl() interface, which the
+ * kernel implements on top of the quotas btree; glibc's wrapper uses the old
+ * struct dqblk, so call it directly with the kernel's if_dqblk:
+ */
+static int bch_quotactl(int cmd, int type, const char *dev, unsigned id, void *addr)
+{
+	return syscall(SYS_quotactl, QCMD(cmd, type), dev, id, addr);
+}
+
+int quota_usage(void)
+{
+	puts("bcachefs quota - manage user, group and project quotas\n"
+	     "Usage: bcachefs quota <CMD> [OPTION]...\n"
+	     "\n"
+	     "Commands:\n"
+	     "  show                    show quota usage and limits\n"
+	     "  set                     set quota limits\n"
+	     "  project                 set the project ID of files and directories, recursively\n"
+	     "  rescan                  recount usage and compare against the quotas btree\n"
+	     "\n"
+	     "Quotas must be enabled with the usrquota, grpquota or prjquota options.\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+	return 0;
+}
+
+static const char * const quota_type_strs[] = {
+	[USRQUOTA]	= "user",
+	[GRPQUOTA]	= "group",
+	[PRJQUOTA]	= "project",
+};
+
+/* quotactl() wants a block device; any member of the filesystem will do */
+static char *quota_dev(const char *mnt)
+{
+	struct bchfs_handle fs = bcache_fs_open(mnt);
+	dev_names devs = bchu_fs_get_devices(fs);
+	char *ret = NULL;
+
+	darray_for_each(devs, d) {
+		if (d->dev && !ret)
+			ret = mprintf("/dev/%s", d->dev);
+		free(d->dev);
+		free(d->label);
+	}
+	darray_exit(&devs);
+	bcache_fs_close(fs);
+
+	if (!ret)
+		die("%s: no online devices", mnt);
+	return ret;
+}
+
+static void quota_err(const char *mnt, int type)
+{
+	if (errno == ESRCH)
+		die("%s quotas not enabled on %s (mount with %squota)",
+		    quota_type_strs[type], mnt,
+		    type == USRQUOTA ? "usr" : type == GRPQUOTA ? "grp" : "prj");
+	die("quotactl error on %s: %m", mnt);
+}
+
+static unsigned quota_parse_id(int type, const char *s)
+{
+	unsigned id;
+
+	if (!kstrtouint(s, 10, &id))
+		return id;
+
+	if (type == USRQUOTA) {
+		struct passwd *pw = getpwnam(s);
+		if (pw)
+			return pw->pw_uid;
+	} else if (type == GRPQUOTA) {
+		struct group *gr = getgrnam(s);
+		if (gr)
+			return gr->gr_gid;
+	}
+
+	die("invalid %s %s", quota_type_strs[type], s);
+}
+
+static void quota_id_to_text(struct printbuf *out, int type, unsigned id)
+{
+	struct passwd *pw = type == USRQUOTA ? getpwuid(id) : NULL;
+	struct group *gr = type == GRPQUOTA ? getgrgid(id) : NULL;
+
+	if (pw)
+		prt_str(out, pw->pw_name);
+	else if (gr)
+		prt_str(out, gr->gr_name);
+	else
+		prt_printf(out, "%u", id);
+}
+
+static void quota_limit_to_text(struct printbuf *out, u64 v, bool bytes)
+{
+	if (!v)
+		prt_str(out, "-");
+	else if (bytes)
+		tools_prt_units_u64(out, v);
+	else
+		prt_u64(out, v);
+}
+
+static void quota_header_to_text(struct printbuf *out, int type)
+{
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 16);
+	for (unsigned i = 0; i < 6; i++)
+		printbuf_tabstop_push(out, 12);
+
+	prt_printf(out, "%s\tused\rsoft\rhard\rfiles\rsoft\rhard\r\n",
+		   quota_type_strs[type]);
+}
+
+static void quota_to_text(struct printbuf *out, int type, unsigned id,
+			  struct if_dqblk *q)
+{
+	quota_id_to_text(out, type, id);
+	prt_tab(out);
+	tools_prt_units_u64(out, q->dqb_curspace);
+	prt_tab_rjust(out);
+	quota_limit_to_text(out, q->dqb_bsoftlimit * QIF_DQBLKSIZE, true);
+	prt_tab_rjust(out);
+	quota_limit_to_text(out, q->dqb_bhardlimit * QIF_DQBLKSIZE, true);
+	prt_tab_rjust(out);
+	prt_u64(out, q->dqb_curinodes);
+	prt_tab_rjust(out);
+	quota_limit_to_text(out, q->dqb_isoftlimit, false);
+	prt_tab_rjust(out);
+	quota_limit_to_text(out, q->dqb_ihardlimit, false);
+	prt_tab_rjust(out);
+	prt_newline(out);
+}
+
+static int quota_parse_type(int opt)
+{
+	switch (opt) {
+	case 'u':
+		return USRQUOTA;
+	case 'g':
+		return GRPQUOTA;
+	default:
+		return PRJQUOTA;
+	}
+}
+
+static void quota_show_usage(void)
+{
+	puts("bcachefs quota show - show quota usage and limits\n"
+	     "Usage: bcachefs quota show [OPTION]... <mountpoint> [<id>...]\n"
+	     "\n"
+	     "With no ids, shows every id with usage or limits set.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -u, --user                   User quotas (default)\n"
+	     "  -g, --group                  Group quotas\n"
+	     "  -p, --project                Project quotas\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_quota_show(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "user",		no_argument,		NULL, 'u' },
+		{ "group",		no_argument,		NULL, 'g' },
+		{ "project",		no_argument,		NULL, 'p' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct printbuf buf = PRINTBUF;
+	int opt, type = USRQUOTA;
+
+	while ((opt = getopt_long(argc, argv, "ugphH", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'u':
+		case 'g':
+		case 'p':
+			type = quota_parse_type(opt);
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'H':
+			quota_show_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			quota_show_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *mnt = arg_pop();
+	if (!mnt)
+		die("Please supply a mountpoint");
+
+	char *dev = quota_dev(mnt);
+
+	quota_header_to_text(&buf, type);
+
+	if (argc) {
+		for (unsigned i = 0; i < argc; i++) {
+			unsigned id = quota_parse_id(type, argv[i]);
+			struct if_dqblk q;
+
+			if (bch_quotactl(Q_GETQUOTA, type, dev, id, &q))
+				quota_err(mnt, type);
+			quota_to_text(&buf, type, id, &q);
+		}
+	} else {
+		struct if_nextdqblk q;
+		unsigned id = 0;
+
+		while (!bch_quotactl(Q_GETNEXTQUOTA, type, dev, id, &q)) {
+			quota_to_text(&buf, type, q.dqb_id, (struct if_dqblk *) &q);
+
+			if (q.dqb_id == U32_MAX)
+				break;
+			id = q.dqb_id + 1;
+		}
+
+		if (errno != ENOENT)
+			quota_err(mnt, type);
+	}
+
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+	free(dev);
+	return 0;
+}
+
+static void quota_set_usage(void)
+{
+	puts("bcachefs quota set - set quota limits\n"
+	     "Usage: bcachefs quota set [OPTION]... <mountpoint> <id>...\n"
+	     "\n"
+	     "Limits not specified are left unchanged; a limit of 0 means no limit.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -u, --user                   User quotas (default)\n"
+	     "  -g, --group                  Group quotas\n"
+	     "  -p, --project                Project quotas\n"
+	     "  -b, --bsoft=size             Soft limit on space used\n"
+	     "  -B, --bhard=size             Hard limit on space used\n"
+	     "  -i, --isoft=nr               Soft limit on number of inodes\n"
+	     "  -I, --ihard=nr               Hard limit on number of inodes\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_quota_set(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "user",		no_argument,		NULL, 'u' },
+		{ "group",		no_argument,		NULL, 'g' },
+		{ "project",		no_argument,		NULL, 'p' },
+		{ "bsoft",		required_argument,	NULL, 'b' },
+		{ "bhard",		required_argument,	NULL, 'B' },
+		{ "isoft",		required_argument,	NULL, 'i' },
+		{ "ihard",		required_argument,	NULL, 'I' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	u64 bsoft = 0, bhard = 0, isoft = 0, ihard = 0;
+	bool set_bsoft = false, set_bhard = false, set_isoft = false, set_ihard = false;
+	int opt, type = USRQUOTA;
+
+	while ((opt = getopt_long(argc, argv, "ugpb:B:i:I:H", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'u':
+		case 'g':
+		case 'p':
+			type = quota_parse_type(opt);
+			break;
+		case 'b':
+			if (bch2_strtoull_h(optarg, &bsoft))
+				die("invalid size %s", optarg);
+			set_bsoft = true;
+			break;
+		case 'B':
+			if (bch2_strtoull_h(optarg, &bhard))
+				die("invalid size %s", optarg);
+			set_bhard = true;
+			break;
+		case 'i':
+			if (kstrtoull(optarg, 10, &isoft))
+				die("invalid number of inodes %s", optarg);
+			set_isoft = true;
+			break;
+		case 'I':
+			if (kstrtoull(optarg, 10, &ihard))
+				die("invalid number of inodes %s", optarg);
+			set_ihard = true;
+			break;
+		case 'H':
+			quota_set_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			quota_set_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *mnt = arg_pop();
+	if (!mnt)
+		die("Please supply a mountpoint");
+
+	if (!argc)
+		die("Please supply at least one %s", quota_type_strs[type]);
+
+	if (!set_bsoft && !set_bhard && !set_isoft && !set_ihard)
+		die("No limits specified");
+
+	char *dev = quota_dev(mnt);
+
+	for (unsigned i = 0; i < argc; i++) {
+		unsigned id = quota_parse_id(type, argv[i]);
+		struct if_dqblk q;
+
+		if (bch_quotactl(Q_GETQUOTA, type, dev, id, &q))
+			quota_err(mnt, type);
+
+		if (set_bsoft)
+			q.dqb_bsoftlimit = DIV_ROUND_UP(bsoft, QIF_DQBLKSIZE);
+		if (set_bhard)
+			q.dqb_bhardlimit = DIV_ROUND_UP(bhard, QIF_DQBLKSIZE);
+		if (set_isoft)
+			q.dqb_isoftlimit = isoft;
+		if (set_ihard)
+			q.dqb_ihardlimit = ihard;
+		q.dqb_valid = QIF_LIMITS;
+
+		if (bch_quotactl(Q_SETQUOTA, type, dev, id, &q))
+			quota_err(mnt, type);
+	}
+
+	free(dev);
+	return 0;
+}
+
+/* Directories get PROJINHERIT, so new files inherit the project ID: */
+static void quota_project_set(int fd, const char *path, unsigned projid, bool is_dir)
+{
+	struct fsxattr fa;
+
+	if (ioctl(fd, FS_IOC_FSGETXATTR, &fa))
+		die("%s: error getting attributes: %m", path);
+
+	fa.fsx_projid = projid;
+	if (is_dir)
+		fa.fsx_xflags |= FS_XFLAG_PROJINHERIT;
+
+	if (ioctl(fd, FS_IOC_FSSETXATTR, &fa))
+		die("%s: error setting project ID: %m", path);
+}
+
+static void quota_project_recurse(int dirfd, const char *path, unsigned projid,
+				  dev_t dev, u64 *nr)
+{
+	DIR *dir = fdopendir(dirfd);
+	struct dirent *d;
+
+	if (!dir)
+		die("%s: fdopendir error: %m", path);
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (!strcmp(d->d_name, ".") ||
+		    !strcmp(d->d_name, ".."))
+			continue;
+
+		struct stat st = xfstatat(dirfd, d->d_name, AT_SYMLINK_NOFOLLOW);
+
+		/* fsxattr can only be set through an open fd: */
+		if (!S_ISDIR(st.st_mode) && !S_ISREG(st.st_mode))
+			continue;
+		if (st.st_dev != dev)
+			continue;
+
+		char *child = mprintf("%s/%s", path, d->d_name);
+		int fd = openat(dirfd, d->d_name, O_RDONLY|O_NOFOLLOW);
+		if (fd < 0)
+			die("error opening %s: %m", child);
+
+		quota_project_set(fd, child, projid, S_ISDIR(st.st_mode));
+		(*nr)++;
+
+		if (S_ISDIR(st.st_mode))
+			quota_project_recurse(fd, child, projid, dev, nr);
+		else
+			close(fd);
+		free(child);
+	}
+
+	if (errno)
+		die("%s: readdir error: %m", path);
+	closedir(dir);
+}
+
+static void quota_project_usage(void)
+{
+	puts("bcachefs quota project - set project IDs\n"
+	     "Usage: bcachefs quota project [OPTION]... <id> <path>...\n"
+	     "\n"
+	     "Sets the project ID of each path and everything below it. Directories\n"
+	     "are marked so that new files inherit the project ID.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_quota_project(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "H", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'H':
+			quota_project_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			quota_project_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *id_str = arg_pop();
+	unsigned projid;
+
+	if (!id_str)
+		die("Please supply a project ID");
+	if (kstrtouint(id_str, 10, &projid))
+		die("invalid project ID %s", id_str);
+
+	if (!argc)
+		die("Please supply at least one path");
+
+	for (unsigned i = 0; i < argc; i++) {
+		struct stat st = xstat(argv[i]);
+		int fd = xopen(argv[i], O_RDONLY);
+		u64 nr = 1;
+
+		quota_project_set(fd, argv[i], projid, S_ISDIR(st.st_mode));
+
+		if (S_ISDIR(st.st_mode))
+			quota_project_recurse(fd, argv[i], projid, st.st_dev, &nr);
+		else
+			close(fd);
+
+		printf("%s: set project %u on %llu files\n", argv[i], projid, nr);
+	}
+
+	return 0;
+}
+
+struct quota_count {
+	unsigned	id;
+	u64		space;
+	u64		inodes;
+};
+
+struct quota_rescan {
+	int		type;
+	dev_t		dev;
+	DARRAY(struct quota_count) counts;
+	/* inodes with more than one link, so they're only counted once: */
+	DARRAY(ino_t)	seen;
+};
+
+static void quota_rescan_account(struct quota_rescan *r, unsigned id, struct stat *st)
+{
+	if (st->st_nlink > 1 && !S_ISDIR(st->st_mode)) {
+		darray_for_each(r->seen, i)
+			if (*i == st->st_ino)
+				return;
+		if (darray_push(&r->seen, st->st_ino))
+			die("memory allocation failure");
+	}
+
+	darray_for_each(r->counts, c)
+		if (c->id == id) {
+			c->space += st->st_blocks << 9;
+			c->inodes++;
+			return;
+		}
+
+	struct quota_count c = { id, st->st_blocks << 9, 1 };
+	if (darray_push(&r->counts, c))
+		die("memory allocation failure");
+}
+
+static unsigned quota_rescan_id(struct quota_rescan *r, int dirfd,
+				const char *name, struct stat *st)
+{
+	if (r->type == USRQUOTA)
+		return st->st_uid;
+	if (r->type == GRPQUOTA)
+		return st->st_gid;
+
+	/* project ID is only readable through an open fd: */
+	if (!S_ISDIR(st->st_mode) && !S_ISREG(st->st_mode))
+		return 0;
+
+	int fd = openat(dirfd, name, O_RDONLY|O_NOFOLLOW|O_NONBLOCK);
+	struct fsxattr fa = {};
+
+	if (fd < 0 || ioctl(fd, FS_IOC_FSGETXATTR, &fa))
+		die("%s: error getting project ID: %m", name);
+	close(fd);
+	return fa.fsx_projid;
+}
+
+static void quota_rescan_recurse(struct quota_rescan *r, int dirfd)
+{
+	DIR *dir = fdopendir(dirfd);
+	struct dirent *d;
+
+	if (!dir)
+		die("fdopendir error: %m");
+
+	while ((errno = 0), (d = readdir(dir))) {
+		if (!strcmp(d->d_name, ".") ||
+		    !strcmp(d->d_name, ".."))
+			continue;
+
+		struct stat st = xfstatat(dirfd, d->d_name, AT_SYMLINK_NOFOLLOW);
+		if (st.st_dev != r->dev)
+			continue;
+
+		quota_rescan_account(r, quota_rescan_id(r, dirfd, d->d_name, &st), &st);
+
+		if (S_ISDIR(st.st_mode))
+			quota_rescan_recurse(r, xopenat(dirfd, d->d_name, O_RDONLY));
+	}
+
+	if (errno)
+		die("readdir error: %m");
+	closedir(dir);
+}
+
+static void quota_rescan_usage(void)
+{
+	puts("bcachefs quota rescan - recount quota usage\n"
+	     "Usage: bcachefs quota rescan [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Walks the filesystem, totals space and inodes used per id, and compares\n"
+	     "against the usage in the quotas btree. The kernel recalculates quota\n"
+	     "usage when the filesystem is mounted, so mismatches found here are fixed\n"
+	     "by remounting. Snapshots and other subvolumes not reachable from the\n"
+	     "mountpoint aren't counted.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -u, --user                   User quotas (default)\n"
+	     "  -g, --group                  Group quotas\n"
+	     "  -p, --project                Project quotas\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "\n"
+	     "Exits with status 1 if usage doesn't match.\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_quota_rescan(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "user",		no_argument,		NULL, 'u' },
+		{ "group",		no_argument,		NULL, 'g' },
+		{ "project",		no_argument,		NULL, 'p' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct printbuf buf = PRINTBUF;
+	struct quota_rescan r = { .type = USRQUOTA };
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "ugphH", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'u':
+		case 'g':
+		case 'p':
+			r.type = quota_parse_type(opt);
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'H':
+			quota_rescan_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			quota_rescan_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	char *mnt = arg_pop();
+	if (!mnt)
+		die("Please supply a mountpoint");
+
+	if (argc)
+		die("too many arguments");
+
+	char *dev = quota_dev(mnt);
+	struct stat st = xstat(mnt);
+	r.dev = st.st_dev;
+
+	int fd = xopen(mnt, O_RDONLY);
+	quota_rescan_account(&r, quota_rescan_id(&r, AT_FDCWD, mnt, &st), &st);
+	quota_rescan_recurse(&r, fd);
+
+	unsigned mismatches = 0;
+
+	darray_for_each(r.counts, c) {
+		struct if_dqblk q;
+
+		if (bch_quotactl(Q_GETQUOTA, r.type, dev, c->id, &q))
+			quota_err(mnt, r.type);
+
+		if (q.dqb_curspace == c->space &&
+		    q.dqb_curinodes == c->inodes)
+			continue;
+
+		if (!mismatches++) {
+			printbuf_tabstops_reset(&buf);
+			printbuf_tabstop_push(&buf, 16);
+			for (unsigned i = 0; i < 4; i++)
+				printbuf_tabstop_push(&buf, 12);
+			prt_printf(&buf, "%s\tcounted\rquota\rfiles\rquota\r\n",
+				   quota_type_strs[r.type]);
+		}
+
+		quota_id_to_text(&buf, r.type, c->id);
+		prt_tab(&buf);
+		tools_prt_units_u64(&buf, c->space);
+		prt_tab_rjust(&buf);
+		tools_prt_units_u64(&buf, q.dqb_curspace);
+		prt_tab_rjust(&buf);
+		prt_printf(&buf, "%llu\r%llu\r\n", c->inodes, (u64) q.dqb_curinodes);
+	}
+
+	if (mismatches)
+		printf("%s%u %s quota(s) don't match, remount to recalculate\n",
+		       buf.buf, mismatches, quota_type_strs[r.type]);
+	else
+		printf("%zu %s quota(s) match\n", r.counts.nr, quota_type_strs[r.type]);
+
+	printbuf_exit(&buf);
+	darray_exit(&r.seen);
+	darray_exit(&r.counts);
+	free(dev);
+	return mismatches != 0;
+}
@@ -0,0 +1,543 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <search.h>
+#include <stdio.h>
+#include <sys/stat.h>
+#include <sys/sysmacros.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/io_read.h"
+#include "libbcachefs/str_hash.h"
+#include "libbcachefs/subvolume.h"
+#include "libbcachefs/super.h"
+
+#define SALVAGE_BUF_SIZE	(1U << 20)
+
+/* Inodes already salvaged, so that we don't copy them twice or loop */
+struct salvage_ino {
+	u32			subvol;
+	u64			inum;
+	bool			dir;
+	/* where it went, for hardlinks to it: */
+	char			*path;
+};
+
+struct salvage_subvol {
+	u32			subvol;
+	u32			snapshot;
+};
+
+struct salvage_orphan {
+	subvol_inum		inum;
+	bool			dir;
+};
+
+typedef DARRAY(struct salvage_orphan) salvage_orphan_list;
+
+struct salvage {
+	struct bch_fs		*c;
+	bool			quiet;
+	void			*visited;	/* tsearch() tree */
+	void			*buf;
+
+	u64			nr_ok;
+	u64			nr_partial;
+	u64			nr_failed;
+	u64			bytes_lost;
+};
+
+static void salvage_usage(void)
+{
+	puts("bcachefs salvage - copy out as much as possible of a damaged filesystem\n"
+	     "Usage: bcachefs salvage [OPTION]... <devices> <target dir>\n"
+	     "\n"
+	     "Recreates the directory tree in the target directory, from dirents where\n"
+	     "they can be read, copying file data block by block and zero filling what\n"
+	     "can't be read. Inodes not reachable from the root go in lost+found/, named\n"
+	     "by inode number. The filesystem is opened read only, and isn't modified.\n"
+	     "Prints one line per file: ok, partial (with the bytes lost), or failed.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -q, --quiet                  Only print files that weren't fully salvaged\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static int salvage_ino_cmp(const void *_l, const void *_r)
+{
+	const struct salvage_ino *l = _l, *r = _r;
+
+	return cmp_int(l->subvol, r->subvol) ?: cmp_int(l->inum, r->inum);
+}
+
+static struct salvage_ino *salvage_visited(struct salvage *s, subvol_inum inum)
+{
+	struct salvage_ino search = { .subvol = inum.subvol, .inum = inum.inum };
+	struct salvage_ino **p = tfind(&search, &s->visited, salvage_ino_cmp);
+
+	return p ? *p : NULL;
+}
+
+static void salvage_visit(struct salvage *s, subvol_inum inum,
+			  struct bch_inode_unpacked *bi, const char *path)
+{
+	struct salvage_ino *i = xmalloc(sizeof(*i));
+
+	i->subvol	= inum.subvol;
+	i->inum		= inum.inum;
+	i->dir		= S_ISDIR(bi->bi_mode);
+	i->path		= !i->dir && bch2_inode_nlink_get(bi) > 1 ? strdup(path) : NULL;
+
+	if (!tsearch(i, &s->visited, salvage_ino_cmp))
+		die("memory allocation failure");
+}
+
+static void salvage_ino_free(void *p)
+{
+	struct salvage_ino *i = p;
+
+	free(i->path);
+	free(i);
+}
+
+static void salvage_read_endio(struct bio *bio)
+{
+	closure_put(bio->bi_private);
+}
+
+/* Read through the normal read path: checksummed, decompressed, decrypted */
+static int salvage_read(struct bch_fs *c, subvol_inum inum,
+			struct bch_inode_unpacked *bi,
+			void *buf, size_t size, u64 offset)
+{
+	struct bch_io_opts io_opts;
+	struct bch_read_bio rbio;
+	struct bio_vec bv;
+	struct closure cl;
+
+	bch2_inode_opts_get(&io_opts, c, bi);
+
+	bio_init(&rbio.bio, NULL, &bv, 1, 0);
+	rbio.bio.bi_iter.bi_size	= size;
+	bv.bv_page			= buf;
+	bv.bv_len			= size;
+	bv.bv_offset			= 0;
+
+	bio_set_op_attrs(&rbio.bio, REQ_OP_READ, REQ_SYNC);
+	rbio.bio.bi_iter.bi_sector	= offset >> 9;
+
+	closure_init_stack(&cl);
+	closure_get(&cl);
+	rbio.bio.bi_end_io		= salvage_read_endio;
+	rbio.bio.bi_private		= &cl;
+
+	bch2_read(c, rbio_init(&rbio.bio, io_opts), inum);
+	closure_sync(&cl);
+
+	return -blk_status_to_errno(rbio.bio.bi_status);
+}
+
+static void salvage_log(const char *path, const char *fmt, ...)
+	__attribute__ ((format (printf, 2, 3)));
+
+static void salvage_log(const char *path, const char *fmt, ...)
+{
+	va_list args;
+
+	printf("%s: ", path);
+	va_start(args, fmt);
+	vprintf(fmt, args);
+	va_end(args);
+	putchar('\n');
+}
+
+static void salvage_failed(struct salvage *s, const char *path, const char *what, int ret)
+{
+	salvage_log(path, "failed: %s: %s", what, bch2_err_str(ret));
+	s->nr_failed++;
+}
+
+static void salvage_file(struct salvage *s, subvol_inum inum,
+			 struct bch_inode_unpacked *bi, const char *dst)
+{
+	struct bch_fs *c = s->c;
+	unsigned block_size = block_bytes(c);
+	u64 size = bi->bi_size, lost = 0;
+
+	int fd = open(dst, O_WRONLY|O_CREAT|O_EXCL, 0600);
+	if (fd < 0) {
+		salvage_failed(s, dst, "error creating", -errno);
+		return;
+	}
+
+	for (u64 offset = 0; offset < size; offset += SALVAGE_BUF_SIZE) {
+		size_t len = min_t(u64, SALVAGE_BUF_SIZE,
+				   round_up(size - offset, block_size));
+
+		/* on error, retry block by block, to get everything we can: */
+		if (salvage_read(c, inum, bi, s->buf, len, offset))
+			for (size_t b = 0; b < len; b += block_size)
+				if (salvage_read(c, inum, bi, s->buf + b, block_size, offset + b)) {
+					memset(s->buf + b, 0, block_size);
+					lost += min_t(u64, block_size, size - offset - b);
+				}
+
+		/* keep holes, and what couldn't be read, sparse: */
+		if (!bch2_is_zero(s->buf, len) &&
+		    pwrite(fd, s->buf, len, offset) != len) {
+			salvage_failed(s, dst, "error writing", -errno);
+			goto out;
+		}
+	}
+
+	if (ftruncate(fd, size)) {
+		salvage_failed(s, dst, "error writing", -errno);
+		goto out;
+	}
+
+	if (lost) {
+		salvage_log(dst, "partial: %llu of %llu bytes unreadable", lost, size);
+		s->nr_partial++;
+		s->bytes_lost += lost;
+	} else {
+		if (!s->quiet)
+			salvage_log(dst, "ok");
+		s->nr_ok++;
+	}
+out:
+	close(fd);
+}
+
+static void salvage_symlink(struct salvage *s, subvol_inum inum,
+			    struct bch_inode_unpacked *bi, const char *dst)
+{
+	size_t len = round_up(bi->bi_size, block_bytes(s->c));
+
+	if (!bi->bi_size || len > SALVAGE_BUF_SIZE) {
+		salvage_failed(s, dst, "bad symlink size", -EINVAL);
+		return;
+	}
+
+	int ret = salvage_read(s->c, inum, bi, s->buf, len, 0);
+	if (ret) {
+		salvage_failed(s, dst, "error reading symlink", ret);
+		return;
+	}
+
+	((char *) s->buf)[bi->bi_size] = '\0';
+
+	if (symlink(s->buf, dst)) {
+		salvage_failed(s, dst, "error creating", -errno);
+		return;
+	}
+
+	if (!s->quiet)
+		salvage_log(dst, "ok");
+	s->nr_ok++;
+}
+
+/* Best effort - we may not be running as root: */
+static void salvage_attrs(struct salvage *s, struct bch_inode_unpacked *bi, const char *dst)
+{
+	struct timespec64 atime = bch2_time_to_timespec(s->c, bi->bi_atime);
+	struct timespec64 mtime = bch2_time_to_timespec(s->c, bi->bi_mtime);
+	struct timespec times[2] = {
+		{ .tv_sec = atime.tv_sec, .tv_nsec = atime.tv_nsec },
+		{ .tv_sec = mtime.tv_sec, .tv_nsec = mtime.tv_nsec },
+	};
+
+	if (lchown(dst, bi->bi_uid, bi->bi_gid)) {}
+	if (!S_ISLNK(bi->bi_mode) &&
+	    chmod(dst, bi->bi_mode & 07777)) {}
+	if (utimensat(AT_FDCWD, dst, times, AT_SYMLINK_NOFOLLOW)) {}
+}
+
+struct salvage_dir_ctx {
+	struct dir_context	ctx;
+	darray_str		names;
+};
+
+static int salvage_filldir(struct dir_context *_ctx, const char *name, int namelen,
+			   loff_t pos, u64 ino, unsigned type)
+{
+	struct salvage_dir_ctx *ctx = container_of(_ctx, struct salvage_dir_ctx, ctx);
+
+	if ((namelen == 1 && name[0] == '.') ||
+	    (namelen == 2 && !memcmp(name, "..", 2)))
+		return 0;
+
+	return darray_push(&ctx->names, strndup(name, namelen));
+}
+
+static void salvage_inode(struct salvage *, subvol_inum,
+			  struct bch_inode_unpacked *, const char *);
+
+static void salvage_dir(struct salvage *s, subvol_inum dir,
+			struct bch_inode_unpacked *bi, const char *dst)
+{
+	struct bch_fs *c = s->c;
+	struct salvage_dir_ctx ctx = {
+		.ctx.actor	= salvage_filldir,
+		.ctx.pos	= 2,
+	};
+
+	if (mkdir(dst, 0700) && errno != EEXIST) {
+		salvage_failed(s, dst, "error creating", -errno);
+		return;
+	}
+
+	/* whatever was listed before an error is still salvaged: */
+	int readdir_ret = bch2_readdir(c, dir, &ctx.ctx);
+	if (readdir_ret)
+		salvage_failed(s, dst, "error listing directory", readdir_ret);
+
+	struct bch_hash_info hash_info = bch2_hash_info_init(c, bi);
+
+	darray_for_each(ctx.names, name) {
+		char *path = mprintf("%s/%s", dst, *name);
+		struct qstr qstr = QSTR(*name);
+		struct bch_inode_unpacked child_bi;
+		subvol_inum child;
+
+		int ret = bch2_dirent_lookup(c, dir, &hash_info, &qstr, &child) ?:
+			bch2_inode_find_by_inum(c, child, &child_bi);
+		if (ret)
+			salvage_failed(s, path, "error looking up", ret);
+		else
+			salvage_inode(s, child, &child_bi, path);
+
+		free(path);
+		free(*name);
+	}
+	darray_exit(&ctx.names);
+
+	if (!readdir_ret) {
+		if (!s->quiet)
+			salvage_log(dst, "ok");
+		s->nr_ok++;
+	}
+}
+
+static void salvage_inode(struct salvage *s, subvol_inum inum,
+			  struct bch_inode_unpacked *bi, const char *dst)
+{
+	struct salvage_ino *prev = salvage_visited(s, inum);
+
+	if (prev) {
+		if (prev->dir) {
+			salvage_failed(s, dst, "directory loop", -ELOOP);
+		} else if (!prev->path) {
+			salvage_failed(s, dst, "already salvaged, with a link count of 1", -EEXIST);
+		} else if (link(prev->path, dst)) {
+			salvage_failed(s, dst, "error creating hardlink", -errno);
+		} else {
+			if (!s->quiet)
+				salvage_log(dst, "ok (hardlink to %s)", prev->path);
+			s->nr_ok++;
+		}
+		return;
+	}
+
+	salvage_visit(s, inum, bi, dst);
+
+	switch (bi->bi_mode & S_IFMT) {
+	case S_IFDIR:
+		salvage_dir(s, inum, bi, dst);
+		break;
+	case S_IFREG:
+		salvage_file(s, inum, bi, dst);
+		break;
+	case S_IFLNK:
+		salvage_symlink(s, inum, bi, dst);
+		break;
+	default:
+		if (mknod(dst, bi->bi_mode & S_IFMT, bi->bi_dev)) {
+			salvage_failed(s, dst, "error creating", -errno);
+			return;
+		}
+		if (!s->quiet)
+			salvage_log(dst, "ok");
+		s->nr_ok++;
+	}
+
+	salvage_attrs(s, bi, dst);
+}
+
+/* Inodes that weren't reached from the root, and the subvolume to read them in */
+static int salvage_find_orphans(struct salvage *s, salvage_orphan_list *orphans)
+{
+	DARRAY(struct salvage_subvol) subvols = {};
+	struct btree_trans *trans = bch2_trans_get(s->c);
+
+	int ret = for_each_btree_key(trans, iter, BTREE_ID_subvolumes, POS_MIN, 0, k, ({
+		int ret2 = 0;
+
+		if (k.k->type == KEY_TYPE_subvolume) {
+			struct bkey_s_c_subvolume subvol = bkey_s_c_to_subvolume(k);
+			struct salvage_subvol n = {
+				.subvol		= k.k->p.offset,
+				.snapshot	= le32_to_cpu(subvol.v->snapshot),
+			};
+
+			ret2 = darray_push(&subvols, n);
+		}
+		ret2;
+	})) ?:
+	for_each_btree_key(trans, iter, BTREE_ID_inodes, POS_MIN,
+			   BTREE_ITER_all_snapshots, k, ({
+		struct bch_inode_unpacked bi;
+		int ret2 = 0;
+
+		if (bkey_is_inode(k.k) &&
+		    !bch2_inode_unpack(k, &bi))
+			darray_for_each(subvols, i)
+				if (i->snapshot == k.k->p.snapshot) {
+					struct salvage_orphan o = {
+						.inum	= { i->subvol, k.k->p.offset },
+						.dir	= S_ISDIR(bi.bi_mode),
+					};
+
+					if (!salvage_visited(s, o.inum))
+						ret2 = darray_push(orphans, o);
+					break;
+				}
+		ret2;
+	}));
+
+	bch2_trans_put(trans);
+	darray_exit(&subvols);
+	return ret;
+}
+
+static void salvage_orphans(struct salvage *s, const char *dst)
+{
+	salvage_orphan_list orphans = {};
+	char *lostfound = mprintf("%s/lost+found", dst);
+
+	int ret = salvage_find_orphans(s, &orphans);
+	if (ret)
+		salvage_failed(s, lostfound, "error walking inodes", ret);
+
+	if (!orphans.nr)
+		goto out;
+
+	if (mkdir(lostfound, 0700) && errno != EEXIST) {
+		salvage_failed(s, lostfound, "error creating", -errno);
+		goto out;
+	}
+
+	/* directories first, so that their contents go in them: */
+	for (int dirs = 1; dirs >= 0; --dirs)
+		darray_for_each(orphans, o) {
+			struct bch_inode_unpacked bi;
+
+			if (o->dir != dirs || salvage_visited(s, o->inum))
+				continue;
+
+			char *path = o->inum.subvol == BCACHEFS_ROOT_SUBVOL
+				? mprintf("%s/#%llu", lostfound, o->inum.inum)
+				: mprintf("%s/#%u.%llu", lostfound, o->inum.subvol, o->inum.inum);
+
+			ret = bch2_inode_find_by_inum(s->c, o->inum, &bi);
+			if (ret)
+				salvage_failed(s, path, "error reading inode", ret);
+			else
+				salvage_inode(s, o->inum, &bi, path);
+			free(path);
+		}
+out:
+	darray_exit(&orphans);
+	free(lostfound);
+}
+
+int cmd_salvage(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "quiet",		no_argument,		NULL, 'q' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct salvage s = {};
+	int opt;
+
+	opt_set(opts, read_only,	true);
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, very_degraded,	true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+	opt_set(opts, fix_errors,	FSCK_FIX_no);
+
+	while ((opt = getopt_long(argc, argv, "qh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'q':
+			s.quiet = true;
+			break;
+		case 'h':
+			salvage_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			salvage_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (argc != 2) {
+		salvage_usage();
+		die("Please supply device(s) and a target directory");
+	}
+
+	darray_str devs = get_or_split_cmdline_devs(1, argv);
+	const char *dst = argv[1];
+
+	if (mkdir(dst, 0700) && errno != EEXIST)
+		die("error creating %s: %m", dst);
+
+	s.c = bch2_fs_open(devs.data, devs.nr, opts);
+	if (IS_ERR(s.c)) {
+		/* the journal may be what's damaged: */
+		fprintf(stderr, "error opening %s: %s, retrying without journal replay\n",
+			devs.data[0], bch2_err_str(PTR_ERR(s.c)));
+
+		opt_set(opts, norecovery, true);
+		s.c = bch2_fs_open(devs.data, devs.nr, opts);
+	}
+	if (IS_ERR(s.c))
+		die("error opening %s: %s", devs.data[0], bch2_err_str(PTR_ERR(s.c)));
+
+	s.buf = aligned_alloc(PAGE_SIZE, SALVAGE_BUF_SIZE);
+	if (!s.buf)
+		die("memory allocation failure");
+
+	struct bch_inode_unpacked root;
+	int ret = bch2_inode_find_by_inum(s.c, BCACHEFS_ROOT_SUBVOL_INUM, &root);
+	if (ret)
+		salvage_failed(&s, dst, "error reading root directory", ret);
+	else
+		salvage_inode(&s, BCACHEFS_ROOT_SUBVOL_INUM, &root, dst);
+
+	salvage_orphans(&s, dst);
+
+	printf("%llu salvaged, %llu partially (%llu bytes lost), %llu failed\n",
+	       s.nr_ok, s.nr_partial, s.bytes_lost, s.nr_failed);
+
+	bch2_fs_stop(s.c);
+	tdestroy(s.visited, salvage_ino_free);
+	free(s.buf);
+	darray_for_each(devs, i)
+		free(*i);
+	darray_exit(&devs);
+
+	return s.nr_partial || s.nr_failed ? EXIT_FAILURE : 0;
+}
@@ -0,0 +1,332 @@
+#include <fcntl.h>
+#include <getopt.h>
+#include <stdio.h>
+#include <unistd.h>
+
+#include "libbcachefs/bcachefs_ioctl.h"
+#include "libbcachefs/opts.h"
+#include "libbcachefs/recovery_passes.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/super-io.h"
+
+#include "cmds.h"
+#include "libbcachefs.h"
+
+/* internal/flags is a comma separated list of BCH_FS_FLAGS() that are set */
+static bool fs_flag_set(const char *flags, const char *flag)
+{
+	size_t len = strlen(flag);
+
+	for (const char *p = flags; p && (p = strstr(p, flag)); p += len)
+		if ((p == flags || p[-1] == ',') &&
+		    (!p[len] || p[len] == ','))
+			return true;
+	return false;
+}
+
+static char *read_sysfs_opt(int dirfd, const char *path)
+{
+	return !faccessat(dirfd, path, R_OK, 0)
+		? read_file_str(dirfd, path)
+		: NULL;
+}
+
+static void status_field(struct printbuf *out, const char *name)
+{
+	prt_str(out, name);
+	prt_char(out, ':');
+	prt_tab(out);
+}
+
+static void prt_color(struct printbuf *out, enum severity sev,
+		      const char *fmt, ...)
+	__attribute__ ((format (printf, 3, 4)));
+
+/* Must be the last field on a line, see printbuf_color_last() */
+static void prt_color(struct printbuf *out, enum severity sev,
+		      const char *fmt, ...)
+{
+	unsigned start = out->pos;
+	va_list args;
+
+	va_start(args, fmt);
+	prt_vprintf(out, fmt, args);
+	va_end(args);
+
+	printbuf_color_last(out, out->pos - start, sev);
+}
+
+static struct dev_name *dev_idx_to_name(dev_names *dev_names, unsigned idx)
+{
+	darray_for_each(*dev_names, dev)
+		if (dev->idx == idx)
+			return dev;
+	return NULL;
+}
+
+static unsigned devs_to_text(struct printbuf *out, struct bch_sb *sb,
+			     dev_names *dev_names)
+{
+	unsigned degraded = 0;
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 24);
+	printbuf_tabstop_push(out, 16);
+	printbuf_tabstop_push(out, 8);
+	printbuf_tabstop_push(out, 8);
+	printbuf_tabstop_push(out, 10);
+	printbuf_tabstop_push(out, 12);
+
+	prt_str(out, "Device");
+	prt_tab(out);
+	prt_str(out, "Label");
+	prt_tab(out);
+	for (unsigned i = 0; i < BCH_MEMBER_ERROR_NR; i++) {
+		prt_str(out, bch2_member_error_strs[i]);
+		prt_tab_rjust(out);
+	}
+	prt_str(out, "State");
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	for (unsigned i = 0; i < sb->nr_devices; i++) {
+		if (!bch2_member_exists(sb, i))
+			continue;
+
+		struct bch_member m = bch2_sb_member_get(sb, i);
+		struct dev_name *d = dev_idx_to_name(dev_names, i);
+		unsigned state = BCH_MEMBER_STATE(&m);
+		bool missing = !d || !d->dev;
+
+		if (missing || state != BCH_MEMBER_STATE_rw)
+			degraded++;
+
+		if (!missing)
+			prt_printf(out, "%s (%u)", d->dev, i);
+		else
+			prt_printf(out, "(missing) (%u)", i);
+		prt_tab(out);
+
+		prt_str(out, d && d->label ? d->label : "(no label)");
+		prt_tab(out);
+
+		for (unsigned j = 0; j < BCH_MEMBER_ERROR_NR; j++) {
+			prt_u64(out, le64_to_cpu(m.errors[j]));
+			prt_tab_rjust(out);
+		}
+
+		const char *state_str = missing ? "missing" : bch2_member_states[state];
+		prt_str(out, state_str);
+		prt_tab_rjust(out);
+
+		if (missing || state != BCH_MEMBER_STATE_rw)
+			printbuf_color_last(out, strlen(state_str),
+					    missing || state == BCH_MEMBER_STATE_failed
+					    ? SEVERITY_ERROR : SEVERITY_WARNING);
+		prt_newline(out);
+	}
+
+	return degraded;
+}
+
+static void sb_errors_summary(struct bch_sb *sb, u64 *nr, u64 *last)
+{
+	struct bch_sb_field_errors *e = bch2_sb_field_get(sb, errors);
+
+	*nr = *last = 0;
+
+	for (unsigned i = 0; i < bch2_sb_field_nr_entries(e); i++) {
+		*nr += BCH_SB_ERROR_ENTRY_NR(&e->entries[i]);
+		*last = max(*last, le64_to_cpu(e->entries[i].last_error_time));
+	}
+}
+
+static void fs_status_to_text(struct printbuf *out, const char *path)
+{
+	struct bchfs_handle fs = bcache_fs_open(path);
+	struct bch_sb *sb = bchu_read_super(fs, -1);
+	dev_names dev_names = bchu_fs_get_devices(fs);
+	struct bch_ioctl_fs_usage *u = bchu_fs_usage(fs);
+	char *flags = read_sysfs_opt(fs.sysfs_fd, "internal/flags");
+	char *rebalance = read_sysfs_opt(fs.sysfs_fd, "internal/rebalance_status");
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 16);
+
+	status_field(out, "Filesystem");
+	pr_uuid(out, fs.uuid.b);
+	if (sb->label[0])
+		prt_printf(out, " (%.*s)", (int) sizeof(sb->label), sb->label);
+	prt_newline(out);
+
+	status_field(out, "Version");
+	bch2_version_to_text(out, le16_to_cpu(sb->version));
+	prt_printf(out, " (bcachefs-tools %s)", VERSION_STRING);
+	prt_newline(out);
+
+	status_field(out, "State");
+	if (!flags)
+		prt_str(out, "unknown");
+	else if (fs_flag_set(flags, "emergency_ro"))
+		prt_color(out, SEVERITY_ERROR, "emergency read-only");
+	else if (fs_flag_set(flags, "rw"))
+		prt_str(out, "read-write");
+	else
+		prt_str(out, "read-only");
+	prt_newline(out);
+
+	status_field(out, "Errors");
+	if (flags && fs_flag_set(flags, "errors_not_fixed"))
+		prt_color(out, SEVERITY_ERROR, "errors not fixed, run fsck");
+	else if ((flags && (fs_flag_set(flags, "error") ||
+			    fs_flag_set(flags, "topology_error"))) ||
+		 BCH_SB_HAS_ERRORS(sb) ||
+		 BCH_SB_HAS_TOPOLOGY_ERRORS(sb))
+		prt_color(out, SEVERITY_ERROR, "filesystem has errors, run fsck");
+	else if (flags && fs_flag_set(flags, "errors_fixed"))
+		prt_color(out, SEVERITY_FIXED, "errors fixed since mount");
+	else
+		prt_str(out, "none");
+	prt_newline(out);
+
+	status_field(out, "Size");
+	tools_prt_units_u64(out, u->capacity << 9);
+	prt_newline(out);
+
+	status_field(out, "Used");
+	tools_prt_units_u64(out, u->used << 9);
+	if (u->capacity) {
+		unsigned pct = div64_u64(u->used * 100, u->capacity);
+
+		prt_color(out, pct >= 98 ? SEVERITY_ERROR :
+			  pct >= 90 ? SEVERITY_WARNING : SEVERITY_INFO,
+			  " (%u%%)", pct);
+	}
+	prt_newline(out);
+
+	status_field(out, "Rebalance");
+	if (rebalance) {
+		/* First line is the state; the rest is detail we don't need */
+		char *nl = strchr(rebalance, '\n');
+		if (nl)
+			*nl = '\0';
+		prt_str(out, strim(rebalance));
+	} else {
+		prt_str(out, "unknown");
+	}
+	prt_newline(out);
+
+	u64 nr_errors, last_error;
+	sb_errors_summary(sb, &nr_errors, &last_error);
+
+	status_field(out, "Last error");
+	if (last_error) {
+		bch2_prt_datetime(out, last_error);
+		prt_printf(out, " (%llu errors recorded)", nr_errors);
+	} else {
+		prt_str(out, "none recorded");
+	}
+	prt_newline(out);
+
+	/*
+	 * The superblock doesn't record when fsck last ran, only whether
+	 * there's repair that still needs to be done:
+	 */
+	struct bch_sb_field_ext *ext = bch2_sb_field_get(sb, ext);
+	u64 passes = ext
+		? bch2_recovery_passes_from_stable(le64_to_cpu(ext->recovery_passes_required[0]))
+		: 0;
+
+	status_field(out, "Fsck");
+	if (flags && fs_flag_set(flags, "fsck_running")) {
+		prt_str(out, "running");
+	} else if (passes) {
+		struct printbuf p = PRINTBUF;
+
+		prt_bitflags(&p, bch2_recovery_passes, passes);
+		prt_color(out, SEVERITY_WARNING, "required: %s", p.buf);
+		printbuf_exit(&p);
+	} else {
+		prt_str(out, "not required");
+	}
+	prt_newline(out);
+
+	prt_newline(out);
+	unsigned degraded = devs_to_text(out, sb, &dev_names);
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 16);
+	prt_newline(out);
+	status_field(out, "Devices");
+	prt_printf(out, "%u", sb->nr_devices);
+	if (degraded)
+		prt_color(out, SEVERITY_WARNING, ", %u degraded or missing", degraded);
+	prt_newline(out);
+
+	darray_for_each(dev_names, dev) {
+		free(dev->dev);
+		free(dev->label);
+	}
+	darray_exit(&dev_names);
+	free(rebalance);
+	free(flags);
+	free(u);
+	free(sb);
+	bcache_fs_close(fs);
+}
+
+static void status_usage(void)
+{
+	puts("bcachefs status - summarize the health of a mounted filesystem\n"
+	     "Usage: bcachefs status [OPTION]... <mountpoint>\n"
+	     "\n"
+	     "Shows version, read-write state, errors, space used, rebalance state,\n"
+	     "whether fsck is required, and devices with their IO error counts.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -h, --human-readable              Human readable units\n"
+	     "      --color=WHEN                  Color output: auto, always or never\n"
+	     "  -H, --help                        Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_status(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "help",		no_argument,		NULL, 'H' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "color",		required_argument,	NULL, 'C' },
+		{ NULL }
+	};
+	bool human_readable = false;
+	struct printbuf buf = PRINTBUF;
+	char *fs;
+	int opt;
+
+	while ((opt = getopt_long(argc, argv, "h",
+				  longopts, NULL)) != -1)
+		switch (opt) {
+		case 'h':
+			human_readable = true;
+			break;
+		case 'C':
+			color_when_parse(optarg);
+			break;
+		case 'H':
+			status_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			status_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	fs = arg_pop() ?: ".";
+
+	tools_printbuf_units(&buf, human_readable);
+	fs_status_to_text(&buf, fs);
+	printf("%s", buf.buf);
+
+	printbuf_exit(&buf);
+	return 0;
+}
@@ -0,0 +1,211 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <uuid/uuid.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/recovery_passes.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/super.h"
+
+/*
+ * The kernel only drops alloc info with the reconstruct_alloc option, which
+ * isn't persistent - so it can't be left for the next mount; instead, it's
+ * done here, with the filesystem offline. The passes that do it are marked
+ * required in the superblock before any are run, so if it's interrupted they
+ * run again at the next mount.
+ */
+static const enum bch_recovery_pass strip_alloc_passes[] = {
+	BCH_RECOVERY_PASS_check_allocations,
+	BCH_RECOVERY_PASS_check_alloc_info,
+	BCH_RECOVERY_PASS_check_lrus,
+	BCH_RECOVERY_PASS_check_extents_to_backpointers,
+	BCH_RECOVERY_PASS_check_alloc_to_lru_refs,
+};
+
+static void strip_alloc_usage(void)
+{
+	puts("bcachefs strip-alloc - drop and rebuild allocation information\n"
+	     "Usage: bcachefs strip-alloc [OPTION]... <devices>\n"
+	     "\n"
+	     "Discards the alloc, bucket_gens, freespace, need_discard and backpointers\n"
+	     "btrees, and rebuilds them - and checks the LRU btree against them - from\n"
+	     "the extents and the rest of the metadata. For allocator corruption that\n"
+	     "fsck can't repair: nothing else is checked. Only works on unmounted\n"
+	     "filesystems.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -n, --dry-run                Only show what would be rebuilt\n"
+	     "  -y, --yes                    Don't ask for confirmation\n"
+	     "  -v, --verbose                Verbose mode\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static void strip_alloc_summary(struct printbuf *out, struct bch_fs *c)
+{
+	char uuid_str[40];
+	u64 nbuckets = 0;
+
+	uuid_unparse(c->sb.user_uuid.b, uuid_str);
+	prt_printf(out, "Filesystem %s:\n", uuid_str);
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 20);
+	printbuf_tabstop_push(out, 16);
+	printbuf_tabstop_push(out, 12);
+
+	prt_str(out, "device");
+	prt_tab(out);
+	prt_str(out, "buckets");
+	prt_tab_rjust(out);
+	prt_str(out, "capacity");
+	prt_tab_rjust(out);
+	prt_newline(out);
+
+	for_each_member_device(c, ca) {
+		prt_str(out, ca->name);
+		prt_tab(out);
+		prt_u64(out, ca->mi.nbuckets);
+		prt_tab_rjust(out);
+		tools_prt_units_u64(out, ca->mi.nbuckets * ca->mi.bucket_size << 9);
+		prt_tab_rjust(out);
+		prt_newline(out);
+
+		nbuckets += ca->mi.nbuckets;
+	}
+
+	prt_newline(out);
+	prt_str(out, "Dropped and rebuilt:   ");
+	bool first = true;
+	for (unsigned i = 0; i < BTREE_ID_NR; i++)
+		if (btree_id_is_alloc(i)) {
+			prt_printf(out, "%s%s", first ? "" : ", ", bch2_btree_id_str(i));
+			first = false;
+		}
+	prt_newline(out);
+
+	prt_printf(out, "Checked and repaired:  %s\n", bch2_btree_id_str(BTREE_ID_lru));
+
+	prt_str(out, "Recovery passes:       ");
+	for (unsigned i = 0; i < ARRAY_SIZE(strip_alloc_passes); i++)
+		prt_printf(out, "%s%s", i ? ", " : "",
+			   bch2_recovery_passes[strip_alloc_passes[i]]);
+	prt_newline(out);
+
+	prt_printf(out, "\nAlloc info for %llu buckets is rebuilt by reading every extent and btree\n"
+		   "node; if interrupted, the rebuild is redone at the next mount.\n", nbuckets);
+}
+
+static bool strip_alloc_confirm(void)
+{
+	char *buf = NULL;
+	size_t buflen = 0;
+	bool ret;
+
+	if (!isatty(STDIN_FILENO))
+		die("Not a terminal; use --yes to rebuild without confirmation");
+
+	fputs("Type \"yes\" to drop and rebuild alloc info: ", stdout);
+	fflush(stdout);
+
+	if (getline(&buf, &buflen, stdin) < 0)
+		die("error reading from standard input");
+
+	ret = !strcmp(strim(buf), "yes");
+	free(buf);
+	return ret;
+}
+
+int cmd_strip_alloc(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "yes",		no_argument,		NULL, 'y' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	bool dry_run = false, yes = false;
+	int opt, ret;
+
+	opt_set(opts, nostart,		true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, reconstruct_alloc, true);
+	opt_set(opts, fix_errors,	FSCK_FIX_yes);
+
+	while ((opt = getopt_long(argc, argv, "nyvh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'n':
+			dry_run = true;
+			break;
+		case 'y':
+			yes = true;
+			break;
+		case 'v':
+			opt_set(opts, verbose, true);
+			break;
+		case 'h':
+			strip_alloc_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			strip_alloc_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	for (unsigned i = 0; i < argc; i++)
+		if (dev_mounted(argv[i]))
+			die("%s is mounted; strip-alloc only works on unmounted filesystems", argv[i]);
+
+	if (dry_run) {
+		opt_set(opts, nochanges,	true);
+		opt_set(opts, read_only,	true);
+	}
+
+	/* nostart: nothing is read or written but the superblock, until bch2_fs_start() */
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	strip_alloc_summary(&buf, c);
+	printf("%s", buf.buf);
+	printbuf_exit(&buf);
+
+	if (dry_run || (!yes && !strip_alloc_confirm())) {
+		bch2_fs_stop(c);
+		return 0;
+	}
+
+	/* so that errors are reported and fixed per fix_errors, as in fsck: */
+	set_bit(BCH_FS_fsck_running, &c->flags);
+
+	ret = bch2_fs_start(c);
+	if (ret) {
+		fprintf(stderr, "error rebuilding alloc info: %s; fsck -R will retry it\n",
+			bch2_err_str(ret));
+		bch2_fs_stop(c);
+		return 1;
+	}
+
+	ret = test_bit(BCH_FS_error, &c->flags);
+	if (ret)
+		fprintf(stderr, "%s: still has errors; run fsck\n", c->name);
+	else
+		printf("Alloc info rebuilt\n");
+
+	bch2_fs_stop(c);
+	return ret;
+}
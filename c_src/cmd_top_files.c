@@ -0,0 +1,301 @@
+#include <getopt.h>
+#include <stdio.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/super.h"
+
+struct top_file {
+	u64			inum;
+	u32			snapshot;
+	u64			disk_sectors;
+	u64			sectors;
+};
+
+typedef DARRAY(struct top_file) top_files;
+
+/* @files is kept sorted by disk usage, largest first, at most @nr entries */
+static void top_files_add(top_files *files, unsigned nr, struct top_file f)
+{
+	if (!f.disk_sectors)
+		return;
+
+	if (files->nr == nr &&
+	    files->data[nr - 1].disk_sectors >= f.disk_sectors)
+		return;
+
+	if (files->nr == nr)
+		files->nr--;
+
+	unsigned i = files->nr;
+	while (i && files->data[i - 1].disk_sectors < f.disk_sectors)
+		--i;
+
+	if (darray_insert_item(files, i, f))
+		die("memory allocation failure");
+}
+
+/*
+ * Walk the inode backpointers (bi_dir, bi_dir_offset) up to the root; stops
+ * at subvolume roots, since their parent is in a different snapshot
+ */
+static int inum_to_path(struct btree_trans *trans, u64 inum, u32 snapshot,
+			struct printbuf *out)
+{
+	char *names[64];
+	unsigned nr = 0;
+	int ret = 0;
+
+	printbuf_reset(out);
+
+	while (nr < ARRAY_SIZE(names)) {
+		struct bch_inode_unpacked inode;
+		struct btree_iter iter;
+		struct bkey_s_c k;
+
+		k = bch2_bkey_get_iter(trans, &iter, BTREE_ID_inodes,
+				       SPOS(0, inum, snapshot), 0);
+		ret = bkey_err(k) ?:
+			(bkey_is_inode(k.k)
+			 ? bch2_inode_unpack(k, &inode)
+			 : -BCH_ERR_ENOENT_inode);
+		bch2_trans_iter_exit(trans, &iter);
+		if (ret)
+			break;
+
+		if (!inode.bi_dir || inode.bi_subvol)
+			break;
+
+		k = bch2_bkey_get_iter(trans, &iter, BTREE_ID_dirents,
+				       SPOS(inode.bi_dir, inode.bi_dir_offset, snapshot), 0);
+		ret = bkey_err(k) ?:
+			(k.k->type == KEY_TYPE_dirent ? 0 : -BCH_ERR_ENOENT_dirent_doesnt_match_inode);
+		if (!ret) {
+			struct qstr name = bch2_dirent_get_name(bkey_s_c_to_dirent(k));
+
+			names[nr++] = strndup((const char *) name.name, name.len);
+		}
+		bch2_trans_iter_exit(trans, &iter);
+		if (ret)
+			break;
+
+		inum = inode.bi_dir;
+	}
+
+	if (!ret) {
+		if (nr == ARRAY_SIZE(names))
+			prt_str(out, "...");
+
+		for (unsigned i = nr; i; --i)
+			prt_printf(out, "/%s", names[i - 1]);
+		if (!nr)
+			prt_char(out, '/');
+	}
+
+	for (unsigned i = 0; i < nr; i++)
+		free(names[i]);
+	return ret;
+}
+
+static void top_files_to_text(struct printbuf *out, struct bch_fs *c,
+			      top_files *files)
+{
+	struct btree_trans *trans = bch2_trans_get(c);
+	struct printbuf path = PRINTBUF;
+
+	printbuf_tabstops_reset(out);
+	printbuf_tabstop_push(out, 12);
+	printbuf_tabstop_push(out, 12);
+	printbuf_tabstop_push(out, 20);
+
+	prt_str(out, "on disk");
+	prt_tab_rjust(out);
+	prt_str(out, "size");
+	prt_tab_rjust(out);
+	prt_str(out, "inode:snapshot");
+	prt_tab_rjust(out);
+	prt_str(out, " path");
+	prt_newline(out);
+
+	darray_for_each(*files, f) {
+		tools_prt_units_u64(out, f->disk_sectors << 9);
+		prt_tab_rjust(out);
+		tools_prt_units_u64(out, f->sectors << 9);
+		prt_tab_rjust(out);
+		prt_printf(out, "%llu:%u", f->inum, f->snapshot);
+		prt_tab_rjust(out);
+
+		int ret = lockrestart_do(trans,
+				inum_to_path(trans, f->inum, f->snapshot, &path));
+		if (!ret)
+			prt_printf(out, " %s", path.buf);
+		else
+			prt_printf(out, " (path not found: %s)", bch2_err_str(ret));
+		prt_newline(out);
+	}
+
+	printbuf_exit(&path);
+	bch2_trans_put(trans);
+}
+
+static void add_extent(struct top_file *f, struct bkey_s_c k, int dev)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	bool on_dev = dev < 0;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		if (p.ptr.cached)
+			continue;
+		if (dev >= 0 && p.ptr.dev != dev)
+			continue;
+
+		f->disk_sectors += ptr_disk_sectors(k.k->size, p);
+		on_dev = true;
+	}
+
+	if (on_dev)
+		f->sectors += k.k->size;
+}
+
+static int top_files_walk(struct btree_trans *trans, top_files *files,
+			  unsigned nr, int dev)
+{
+	struct top_file cur = {};
+
+	int ret = for_each_btree_key(trans, iter, BTREE_ID_extents, POS_MIN,
+				     BTREE_ITER_all_snapshots|
+				     BTREE_ITER_prefetch, k, ({
+		if (k.k->p.inode != cur.inum ||
+		    k.k->p.snapshot != cur.snapshot) {
+			top_files_add(files, nr, cur);
+			cur = (struct top_file) {
+				.inum		= k.k->p.inode,
+				.snapshot	= k.k->p.snapshot,
+			};
+		}
+
+		if (bkey_extent_is_direct_data(k.k))
+			add_extent(&cur, k, dev);
+		else if (bkey_extent_is_inline_data(k.k) && dev < 0)
+			cur.sectors += k.k->size;
+		0;
+	}));
+
+	top_files_add(files, nr, cur);
+	return ret;
+}
+
+static void top_files_usage(void)
+{
+	puts("bcachefs fs top-files - list the files using the most space\n"
+	     "Usage: bcachefs fs top-files [OPTION]... <devices>\n"
+	     "\n"
+	     "Scans the extents btree, and lists files by space used on disk, after\n"
+	     "compression and counting each replica. Works on unmounted filesystems.\n"
+	     "Reflinked (shared) extents live in the reflink btree and aren't counted.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -n, --nr=NR                  Number of files to list (default 20)\n"
+	     "  -d, --device=DEV             Only count data on this device (index or path)\n"
+	     "  -h, --human-readable         Human readable units\n"
+	     "  -v, --verbose                Verbose mode\n"
+	     "  -H, --help                   Display this help and exit\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+int cmd_fs_top_files(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "nr",			required_argument,	NULL, 'n' },
+		{ "device",		required_argument,	NULL, 'd' },
+		{ "human-readable",	no_argument,		NULL, 'h' },
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "help",		no_argument,		NULL, 'H' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	top_files files = {};
+	unsigned nr = 20;
+	char *dev_str = NULL;
+	int opt, dev = -1;
+
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, read_only,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "n:d:hv", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'n':
+			if (kstrtouint(optarg, 10, &nr) || !nr)
+				die("invalid number of files %s", optarg);
+			break;
+		case 'd':
+			dev_str = optarg;
+			break;
+		case 'h':
+			tools_printbuf_units(&buf, true);
+			break;
+		case 'v':
+			opt_set(opts, verbose, true);
+			break;
+		case 'H':
+			top_files_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			top_files_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	struct bch_fs *c = bch2_fs_open(argv, argc, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", argv[0], bch2_err_str(PTR_ERR(c)));
+
+	if (dev_str) {
+		unsigned idx;
+
+		if (!kstrtouint(dev_str, 10, &idx)) {
+			if (!bch2_dev_exists(c, idx))
+				die("no device %u", idx);
+			dev = idx;
+		} else {
+			for_each_member_device(c, ca)
+				if (ca->disk_sb.sb_name &&
+				    !strcmp(ca->disk_sb.sb_name, dev_str))
+					dev = ca->dev_idx;
+			if (dev < 0)
+				die("device %s not found", dev_str);
+		}
+	}
+
+	struct btree_trans *trans = bch2_trans_get(c);
+	int ret = top_files_walk(trans, &files, nr, dev);
+	bch2_trans_put(trans);
+
+	if (ret)
+		die("error walking extents: %s", bch2_err_str(ret));
+
+	top_files_to_text(&buf, c, &files);
+	printf("%s", buf.buf);
+
+	printbuf_exit(&buf);
+	darray_exit(&files);
+	bch2_fs_stop(c);
+	return 0;
+}
@@ -0,0 +1,548 @@
+#include <fnmatch.h>
+#include <getopt.h>
+#include <search.h>
+#include <stdio.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/alloc_background.h"
+#include "libbcachefs/alloc_foreground.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/journal_io.h"
+#include "libbcachefs/journal_seq_blacklist.h"
+#include "libbcachefs/super.h"
+
+/*
+ * Deleted keys are found in the journal: with journal_transaction_names (the
+ * default), every update also journals the key it overwrote, so for anything
+ * deleted recently enough that its journal bucket hasn't been reused, we have
+ * the last version of the key before it was deleted
+ */
+struct undelete_key {
+	enum btree_id		btree;
+	struct bpos		pos;
+	u64			seq;
+	bool			deleted;
+	struct bkey_i		*k;		/* last live version */
+};
+
+typedef DARRAY(struct undelete_key *) undelete_keys;
+
+struct undelete {
+	void			*keys;		/* tsearch() tree, by btree:pos */
+	undelete_keys		deleted;	/* inodes, dirents and xattrs */
+	undelete_keys		extents;	/* overwritten extents, in journal order */
+
+	u64			inum;
+	const char		*name;
+
+	undelete_keys		inodes;		/* selected */
+	undelete_keys		dirents;	/* selected */
+};
+
+static void undelete_usage(void)
+{
+	puts("bcachefs undelete - restore recently deleted files, from the journal\n"
+	     "Usage: bcachefs undelete [OPTION]... <devices>\n"
+	     "\n"
+	     "Searches the journal for inodes and dirents that were deleted, and that\n"
+	     "haven't since been reused. With no options, lists them; with --inode or\n"
+	     "--name, restores the matching files as far as possible: inodes, dirents,\n"
+	     "xattrs, and extents whose data hasn't been overwritten.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -i, --inode=N                Restore inode N, and dirents pointing to it\n"
+	     "  -N, --name=PATTERN           Restore dirents matching PATTERN (a shell\n"
+	     "                               glob), and the inodes they point to\n"
+	     "  -n, --dry-run                Show what would be restored, but don't\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Run fsck afterwards, to fix link counts and directory sizes.\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+static int undelete_key_cmp(const void *_l, const void *_r)
+{
+	const struct undelete_key *l = _l, *r = _r;
+
+	return cmp_int(l->btree, r->btree) ?: bpos_cmp(l->pos, r->pos);
+}
+
+static bool key_is_live(const struct bkey_i *k)
+{
+	return !bkey_whiteout(&k->k) && k->k.type != KEY_TYPE_hash_whiteout;
+}
+
+static void undelete_journal_key(struct undelete *u, u64 seq, bool overwrite,
+				 enum btree_id btree, struct bkey_i *k)
+{
+	if (btree == BTREE_ID_extents) {
+		/* extents are deleted by ranges, so just keep what was overwritten: */
+		if (overwrite && key_is_live(k) && bkey_extent_is_direct_data(&k->k)) {
+			struct undelete_key *e = xcalloc(1, sizeof(*e));
+
+			e->btree	= btree;
+			e->pos		= k->k.p;
+			e->seq		= seq;
+			e->k		= xmalloc(bkey_bytes(&k->k));
+			bkey_copy(e->k, k);
+
+			if (darray_push(&u->extents, e))
+				die("memory allocation failure");
+		}
+		return;
+	}
+
+	if (btree != BTREE_ID_inodes &&
+	    btree != BTREE_ID_dirents &&
+	    btree != BTREE_ID_xattrs)
+		return;
+
+	struct undelete_key search = { .btree = btree, .pos = k->k.p };
+	struct undelete_key **p = tfind(&search, &u->keys, undelete_key_cmp);
+	struct undelete_key *i = p ? *p : NULL;
+
+	if (!i) {
+		i = xcalloc(1, sizeof(*i));
+		i->btree	= btree;
+		i->pos		= k->k.p;
+
+		if (!tsearch(i, &u->keys, undelete_key_cmp))
+			die("memory allocation failure");
+	}
+
+	if (key_is_live(k)) {
+		free(i->k);
+		i->k = xmalloc(bkey_bytes(&k->k));
+		bkey_copy(i->k, k);
+		i->seq = seq;
+	}
+
+	if (!overwrite)
+		i->deleted = !key_is_live(k);
+}
+
+static void undelete_scan_journal(struct bch_fs *c, struct undelete *u)
+{
+	struct journal_replay *p, **_p;
+	struct genradix_iter iter;
+
+	genradix_for_each(&c->journal_entries, iter, _p) {
+		p = *_p;
+		if (!p || p->ignore_blacklisted)
+			continue;
+
+		u64 seq = le64_to_cpu(p->j.seq);
+
+		if (bch2_journal_seq_is_blacklisted(c, seq, false))
+			continue;
+
+		vstruct_for_each(&p->j, entry)
+			if ((entry->type == BCH_JSET_ENTRY_btree_keys ||
+			     entry->type == BCH_JSET_ENTRY_overwrite) &&
+			    !entry->level &&
+			    entry->btree_id < BTREE_ID_NR)
+				jset_entry_for_each_key(entry, k)
+					undelete_journal_key(u, seq,
+							     entry->type == BCH_JSET_ENTRY_overwrite,
+							     entry->btree_id, k);
+	}
+}
+
+static void undelete_collect(const void *node, VISIT which, void *data)
+{
+	struct undelete_key *i = *(struct undelete_key **) node;
+	struct undelete *u = data;
+
+	if ((which == postorder || which == leaf) &&
+	    i->deleted && i->k &&
+	    darray_push(&u->deleted, i))
+		die("memory allocation failure");
+}
+
+static void undelete_key_to_text(struct printbuf *out, struct bch_fs *c,
+				 struct undelete_key *i)
+{
+	prt_printf(out, "seq %llu %s ", i->seq, bch2_btree_id_str(i->btree));
+	bch2_bkey_val_to_text(out, c, bkey_i_to_s_c(i->k));
+}
+
+static bool dirent_matches(struct undelete *u, struct undelete_key *i)
+{
+	struct bkey_s_c_dirent d = bkey_i_to_s_c_dirent(i->k);
+
+	if (u->name) {
+		struct qstr name = bch2_dirent_get_name(d);
+		char *n = strndup(name.name, name.len);
+		bool ret = !fnmatch(u->name, n, 0);
+
+		free(n);
+		return ret;
+	}
+
+	return d.v->d_type != DT_SUBVOL &&
+		le64_to_cpu(d.v->d_inum) == u->inum;
+}
+
+/* Pick the inodes and dirents to restore: */
+static void undelete_select(struct undelete *u)
+{
+	darray_for_each(u->deleted, i)
+		if ((*i)->btree == BTREE_ID_dirents &&
+		    (*i)->k->k.type == KEY_TYPE_dirent &&
+		    dirent_matches(u, *i) &&
+		    darray_push(&u->dirents, *i))
+			die("memory allocation failure");
+
+	darray_for_each(u->deleted, i) {
+		if ((*i)->btree != BTREE_ID_inodes ||
+		    !bkey_is_inode(&(*i)->k->k))
+			continue;
+
+		bool match = !u->name && (*i)->pos.offset == u->inum;
+
+		darray_for_each(u->dirents, d) {
+			struct bkey_s_c_dirent dirent = bkey_i_to_s_c_dirent((*d)->k);
+
+			match |= dirent.v->d_type != DT_SUBVOL &&
+				le64_to_cpu(dirent.v->d_inum) == (*i)->pos.offset &&
+				(*d)->pos.snapshot == (*i)->pos.snapshot;
+		}
+
+		if (match && darray_push(&u->inodes, *i))
+			die("memory allocation failure");
+	}
+}
+
+/*
+ * Deleting an extent doesn't touch its data: that's only gone once the bucket
+ * is reused, or discarded. Checking the pointer's gen isn't enough, since a
+ * bucket's gen is bumped as soon as it empties - but a bucket one gen on that's
+ * still empty, and not open for writes, still has the data, unless it's been
+ * discarded. Pointers to such buckets are moved to the new gen, so they're
+ * live again when the extent is restored.
+ */
+static int extent_ptrs_check(struct btree_trans *trans, struct bkey_i *k)
+{
+	struct bch_fs *c = trans->c;
+	struct bkey_ptrs ptrs = bch2_bkey_ptrs(bkey_i_to_s(k));
+	int ret = 0;
+
+	bkey_for_each_ptr(ptrs, ptr) {
+		/* stale cached pointers are ignored, and dropped later: */
+		if (ptr->cached)
+			continue;
+
+		struct bch_dev *ca = bch2_dev_tryget(c, ptr->dev);
+		if (!ca)
+			return -ESTALE;
+
+		struct btree_iter iter;
+		struct bkey_s_c a_k = bch2_bkey_get_iter(trans, &iter, BTREE_ID_alloc,
+							 PTR_BUCKET_POS(ca, ptr),
+							 BTREE_ITER_cached);
+		ret = bkey_err(a_k);
+		if (!ret) {
+			struct bch_alloc_v4 a_convert;
+			const struct bch_alloc_v4 *a = bch2_alloc_to_v4(a_k, &a_convert);
+			int gen = gen_cmp(a->gen, ptr->gen);
+			bool empty = data_type_is_empty(a->data_type);
+
+			if (gen < 0 || gen > 1 ||
+			    (gen == 1 && !empty) ||
+			    (empty && bch2_bucket_is_open_safe(c, ptr->dev, PTR_BUCKET_NR(ca, ptr))) ||
+			    (empty && ca->mi.discard && a->data_type != BCH_DATA_need_discard))
+				ret = -ESTALE;
+			else
+				ptr->gen = a->gen;
+
+			bch2_trans_iter_exit(trans, &iter);
+		}
+
+		bch2_dev_put(ca);
+		if (ret)
+			break;
+	}
+
+	return ret;
+}
+
+/* Insert @k if nothing has since been written at its position */
+static int undelete_insert(struct btree_trans *trans, enum btree_id btree, struct bkey_i *k)
+{
+	struct btree_iter iter;
+	struct bkey_s_c old;
+	int ret;
+
+	if (btree == BTREE_ID_extents) {
+		bch2_trans_iter_init(trans, &iter, btree,
+				     SPOS(k->k.p.inode, bkey_start_offset(&k->k), k->k.p.snapshot), 0);
+		old = bch2_btree_iter_peek_upto(&iter, k->k.p);
+	} else {
+		old = bch2_bkey_get_iter(trans, &iter, btree, k->k.p, 0);
+	}
+
+	ret = bkey_err(old);
+	if (ret)
+		goto err;
+
+	if (old.k && !bkey_whiteout(old.k) && old.k->type != KEY_TYPE_hash_whiteout) {
+		ret = -EEXIST;
+		goto err;
+	}
+
+	struct bkey_i *n = bch2_trans_kmalloc(trans, bkey_bytes(&k->k));
+	ret = PTR_ERR_OR_ZERO(n);
+	if (ret)
+		goto err;
+
+	bkey_copy(n, k);
+
+	/* the bucket may have been reused since the extent was selected: */
+	if (btree == BTREE_ID_extents) {
+		ret = extent_ptrs_check(trans, n);
+		if (ret)
+			goto err;
+	}
+
+	ret = bch2_btree_insert_trans(trans, btree, n, 0);
+err:
+	bch2_trans_iter_exit(trans, &iter);
+	return ret;
+}
+
+/* A deleted inode is flagged unlinked, with no links; give it one back */
+static void undelete_fix_inode(struct bch_fs *c, struct undelete_key *i)
+{
+	struct bch_inode_unpacked inode;
+
+	if (bch2_inode_unpack(bkey_i_to_s_c(i->k), &inode))
+		return;
+
+	inode.bi_flags &= ~BCH_INODE_unlinked;
+	if (!bch2_inode_nlink_get(&inode))
+		bch2_inode_nlink_set(&inode, 1);
+
+	struct bkey_inode_buf *p = xmalloc(sizeof(*p));
+	bch2_inode_pack(p, &inode);
+	p->inode.k.p.snapshot = i->pos.snapshot;
+
+	free(i->k);
+	i->k = &p->inode.k_i;
+}
+
+static void undelete_restore_one(struct btree_trans *trans, struct undelete_key *i,
+				 unsigned *nr, unsigned *nr_failed)
+{
+	struct printbuf buf = PRINTBUF;
+
+	int ret = commit_do(trans, NULL, NULL, BCH_TRANS_COMMIT_no_enospc,
+			    undelete_insert(trans, i->btree, i->k));
+
+	undelete_key_to_text(&buf, trans->c, i);
+	if (ret) {
+		printf("not restored (%s): %s\n",
+		       ret == -EEXIST ? "position since reused" :
+		       ret == -ESTALE ? "data overwritten" : bch2_err_str(ret),
+		       buf.buf);
+		(*nr_failed)++;
+	} else {
+		printf("restored: %s\n", buf.buf);
+		(*nr)++;
+	}
+	printbuf_exit(&buf);
+}
+
+static void undelete_restore(struct bch_fs *c, struct undelete *u, bool dry_run)
+{
+	struct btree_trans *trans = bch2_trans_get(c);
+	struct printbuf buf = PRINTBUF;
+	unsigned nr = 0, nr_failed = 0;
+	undelete_keys todo = {};
+
+	darray_for_each(u->inodes, i) {
+		undelete_fix_inode(c, *i);
+		if (darray_push(&todo, *i))
+			die("memory allocation failure");
+
+		/* newest first, so that older versions of the same range are skipped: */
+		darray_for_each_reverse(u->extents, e)
+			if ((*e)->pos.inode == (*i)->pos.offset &&
+			    (*e)->pos.snapshot == (*i)->pos.snapshot) {
+				if (lockrestart_do(trans, extent_ptrs_check(trans, (*e)->k))) {
+					printbuf_reset(&buf);
+					undelete_key_to_text(&buf, c, *e);
+					printf("not restored (data overwritten): %s\n", buf.buf);
+					nr_failed++;
+				} else if (darray_push(&todo, *e)) {
+					die("memory allocation failure");
+				}
+			}
+
+		darray_for_each(u->deleted, x)
+			if ((*x)->btree == BTREE_ID_xattrs &&
+			    (*x)->pos.inode == (*i)->pos.offset &&
+			    (*x)->pos.snapshot == (*i)->pos.snapshot &&
+			    darray_push(&todo, *x))
+				die("memory allocation failure");
+	}
+
+	/* dirents last, once what they point to is back: */
+	darray_for_each(u->dirents, d)
+		if (darray_push(&todo, *d))
+			die("memory allocation failure");
+
+	darray_for_each(todo, i) {
+		if (!dry_run) {
+			undelete_restore_one(trans, *i, &nr, &nr_failed);
+		} else {
+			printbuf_reset(&buf);
+			undelete_key_to_text(&buf, c, *i);
+			printf("would restore: %s\n", buf.buf);
+		}
+	}
+
+	if (!dry_run) {
+		printf("%u keys restored, %u not restored\n", nr, nr_failed);
+		if (nr)
+			printf("Now run fsck, to fix link counts and directory sizes\n");
+	}
+
+	bch2_trans_put(trans);
+	darray_exit(&todo);
+	printbuf_exit(&buf);
+}
+
+static void undelete_key_free(void *p)
+{
+	struct undelete_key *i = p;
+
+	free(i->k);
+	free(i);
+}
+
+int cmd_undelete(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "inode",		required_argument,	NULL, 'i' },
+		{ "name",		required_argument,	NULL, 'N' },
+		{ "dry-run",		no_argument,		NULL, 'n' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct printbuf buf = PRINTBUF;
+	struct undelete u = {};
+	bool dry_run = false, inode_set = false;
+	int opt;
+
+	opt_set(opts, nochanges,		true);
+	opt_set(opts, norecovery,		true);
+	opt_set(opts, read_only,		true);
+	opt_set(opts, degraded,			true);
+	opt_set(opts, very_degraded,		true);
+	opt_set(opts, errors,			BCH_ON_ERROR_continue);
+	opt_set(opts, retain_recovery_info,	true);
+	opt_set(opts, read_journal_only,	true);
+	opt_set(opts, read_entire_journal,	true);
+
+	while ((opt = getopt_long(argc, argv, "i:N:nh", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'i':
+			if (kstrtoull(optarg, 10, &u.inum))
+				die("invalid inode number %s", optarg);
+			inode_set = true;
+			break;
+		case 'N':
+			u.name = optarg;
+			break;
+		case 'n':
+			dry_run = true;
+			break;
+		case 'h':
+			undelete_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			undelete_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (!argc)
+		die("Please supply device(s)");
+
+	if (inode_set && u.name)
+		die("--inode and --name are mutually exclusive");
+
+	darray_str devs = get_or_split_cmdline_devs(argc, argv);
+
+	darray_for_each(devs, i)
+		if (dev_mounted(*i))
+			die("%s is mounted; undelete only works on unmounted filesystems", *i);
+
+	struct bch_fs *c = bch2_fs_open(devs.data, devs.nr, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", devs.data[0], bch2_err_str(PTR_ERR(c)));
+
+	undelete_scan_journal(c, &u);
+	twalk_r(u.keys, undelete_collect, &u);
+
+	if (!inode_set && !u.name) {
+		darray_for_each(u.deleted, i)
+			if ((*i)->btree != BTREE_ID_xattrs) {
+				printbuf_reset(&buf);
+				undelete_key_to_text(&buf, c, *i);
+				printf("%s\n", buf.buf);
+			}
+		printf("%zu deleted inodes, dirents and xattrs found in the journal\n",
+		       u.deleted.nr);
+		bch2_fs_stop(c);
+		goto out;
+	}
+
+	undelete_select(&u);
+	bch2_fs_stop(c);
+
+	if (!u.inodes.nr && !u.dirents.nr) {
+		printf("No matching deleted inodes or dirents found in the journal\n");
+		goto out;
+	}
+
+	/* Now open it for real, to restore: */
+	opts = bch2_opts_empty();
+	if (dry_run) {
+		opt_set(opts, nochanges,	true);
+		opt_set(opts, read_only,	true);
+	}
+
+	c = bch2_fs_open(devs.data, devs.nr, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", devs.data[0], bch2_err_str(PTR_ERR(c)));
+
+	undelete_restore(c, &u, dry_run);
+	bch2_fs_stop(c);
+out:
+	darray_exit(&u.inodes);
+	darray_exit(&u.dirents);
+	darray_exit(&u.deleted);
+	darray_for_each(u.extents, e)
+		undelete_key_free(*e);
+	darray_exit(&u.extents);
+	tdestroy(u.keys, undelete_key_free);
+	darray_for_each(devs, i)
+		free(*i);
+	darray_exit(&devs);
+	printbuf_exit(&buf);
+	return 0;
+}
@@ -0,0 +1,327 @@
+#include <getopt.h>
+#include <stdio.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+#include "cmds.h"
+#include "libbcachefs.h"
+#include "tools-util.h"
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/bkey_buf.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/buckets.h"
+#include "libbcachefs/checksum.h"
+#include "libbcachefs/dirent.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/io_read.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/subvolume.h"
+#include "libbcachefs/super.h"
+
+struct verify {
+	bool			verbose;
+	u64			extents;
+	u64			replicas;
+	u64			bad;
+	u64			unchecked;
+	u64			unreadable;
+};
+
+/*
+ * Replicas are read and checksummed on worker threads, an extent at a time;
+ * results are reported in order, from the main thread
+ */
+struct verify_job {
+	const char		*path;
+	u64			offset;
+	struct bkey_i		*k;
+	struct printbuf		out;
+	struct verify		s;
+};
+
+struct verify_run {
+	struct bch_fs		*c;
+	struct verify		*s;
+	struct ordered_work	w;
+};
+
+static void verify_usage(void)
+{
+	puts("bcachefs verify - check the checksums of every replica of a file's data\n"
+	     "Usage: bcachefs verify [OPTION]... <devices> <path>...\n"
+	     "\n"
+	     "Reads each replica of each extent of the given files separately, and\n"
+	     "verifies its checksum. Paths are relative to the root of the filesystem;\n"
+	     "devices is a colon separated list. Read only.\n"
+	     "\n"
+	     "Options:\n"
+	     "  -v, --verbose                Print every replica checked, not just bad ones\n"
+	     "  -j, --jobs=nr                Number of threads reading and checksumming\n"
+	     "                               (default: number of CPUs)\n"
+	     "  -h, --help                   Display this help and exit\n"
+	     "\n"
+	     "Report bugs to <linux-bcachefs@vger.kernel.org>");
+}
+
+/* Returns true if the replica's checksum is good */
+static bool verify_replica(struct bch_fs *c, struct bkey_s_c k,
+			   struct extent_ptr_decoded p, struct printbuf *err)
+{
+	size_t bytes = p.crc.compressed_size << 9;
+	void *data = NULL;
+	bool ok = false;
+
+	struct bch_dev *ca = bch2_dev_tryget_noerror(c, p.ptr.dev);
+	if (!ca || !ca->disk_sb.bdev) {
+		prt_printf(err, "device offline");
+		goto out;
+	}
+
+	if (p.ptr.cached && dev_ptr_stale(ca, &p.ptr)) {
+		prt_printf(err, "stale cached pointer");
+		goto out;
+	}
+
+	data = xmalloc(bytes);
+
+	ssize_t r = pread(ca->disk_sb.bdev->bd_fd, data, bytes, p.ptr.offset << 9);
+	if (r != bytes) {
+		prt_printf(err, "read error: %s", r < 0 ? strerror(errno) : "short read");
+		goto out;
+	}
+
+	struct nonce nonce = extent_nonce(k.k->version, p.crc);
+	struct bch_csum csum = bch2_checksum(c, p.crc.csum_type, nonce, data, bytes);
+	if (bch2_crc_cmp(csum, p.crc.csum)) {
+		bch2_csum_err_msg(err, p.crc.csum_type, p.crc.csum, csum);
+		goto out;
+	}
+
+	ok = true;
+out:
+	free(data);
+	bch2_dev_put(ca);
+	return ok;
+}
+
+static void verify_extent(struct bch_fs *c, struct verify *s, struct printbuf *out,
+			  const char *path, u64 offset, struct bkey_s_c k)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	unsigned nr_good = 0, nr_checked = 0;
+
+	s->extents++;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		struct printbuf err = PRINTBUF;
+		bool ok = true, checked = p.crc.csum_type != BCH_CSUM_none;
+
+		s->replicas++;
+
+		if (checked) {
+			ok = verify_replica(c, k, p, &err);
+			nr_checked++;
+			nr_good += ok;
+		} else {
+			s->unchecked++;
+		}
+
+		if (!ok || s->verbose) {
+			struct bch_dev *ca = bch2_dev_tryget_noerror(c, p.ptr.dev);
+
+			prt_printf(out, "%s: offset %llu: dev %u (%s) sector %llu%s: %s\n",
+			       path, offset << 9, p.ptr.dev,
+			       ca && ca->name[0] ? ca->name : "missing",
+			       (u64) p.ptr.offset,
+			       p.ptr.cached ? " (cached)" : "",
+			       !checked ? "no checksum" : ok ? "ok" : err.buf);
+			bch2_dev_put(ca);
+		}
+
+		if (!ok)
+			s->bad++;
+		printbuf_exit(&err);
+	}
+
+	if (nr_checked && !nr_good) {
+		prt_printf(out, "%s: offset %llu: no good replicas\n", path, offset << 9);
+		s->unreadable++;
+	}
+}
+
+static void verify_job_work(void *item, void *priv)
+{
+	struct verify_job *j = item;
+	struct verify_run *r = priv;
+
+	j->s.verbose = r->s->verbose;
+	verify_extent(r->c, &j->s, &j->out, j->path, j->offset, bkey_i_to_s_c(j->k));
+}
+
+static void verify_job_done(void *item, void *priv)
+{
+	struct verify_job *j = item;
+	struct verify_run *r = priv;
+
+	if (j->out.pos)
+		fputs(j->out.buf, stdout);
+
+	r->s->extents		+= j->s.extents;
+	r->s->replicas		+= j->s.replicas;
+	r->s->bad		+= j->s.bad;
+	r->s->unchecked		+= j->s.unchecked;
+	r->s->unreadable	+= j->s.unreadable;
+
+	printbuf_exit(&j->out);
+	free(j->k);
+}
+
+static void verify_submit(struct verify_run *r, const char *path, u64 offset,
+			  struct bkey_i *k)
+{
+	struct verify_job *j = ordered_work_get(&r->w);
+
+	memset(j, 0, sizeof(*j));
+	j->path		= path;
+	j->offset	= offset;
+	j->out		= PRINTBUF;
+	j->k		= xmalloc(bkey_bytes(&k->k));
+	bkey_copy(j->k, k);
+
+	ordered_work_submit(&r->w);
+}
+
+static int verify_file(struct verify_run *r, const char *path)
+{
+	struct bch_fs *c = r->c;
+	struct btree_trans *trans = bch2_trans_get(c);
+	struct bch_inode_unpacked bi;
+	struct bkey_buf cur;
+	subvol_inum inum;
+	u32 snapshot;
+	int ret;
+
+	ret = lookup_path(c, path, &inum, &bi);
+	if (ret) {
+		fprintf(stderr, "%s: %s\n", path, bch2_err_str(ret));
+		goto out;
+	}
+
+	if (!S_ISREG(bi.bi_mode)) {
+		fprintf(stderr, "%s: not a regular file\n", path);
+		ret = -EINVAL;
+		goto out;
+	}
+
+	ret = lockrestart_do(trans,
+		bch2_subvolume_get_snapshot(trans, inum.subvol, &snapshot));
+	if (ret)
+		goto out;
+
+	bch2_bkey_buf_init(&cur);
+
+	ret = for_each_btree_key_upto(trans, iter, BTREE_ID_extents,
+				SPOS(inum.inum, 0, snapshot),
+				POS(inum.inum, U64_MAX), 0, k, ({
+		enum btree_id data_btree = BTREE_ID_extents;
+		unsigned offset_into_extent = 0;
+		u64 offset = bkey_start_offset(k.k);
+		int ret2 = 0;
+
+		if (bkey_extent_is_data(k.k)) {
+			/* reflinked data: check the indirect extent */
+			bch2_bkey_buf_reassemble(&cur, c, k);
+
+			ret2 = bch2_read_indirect_extent(trans, &data_btree,
+						&offset_into_extent, &cur);
+			if (!ret2 && bkey_extent_is_direct_data(&cur.k->k))
+				verify_submit(r, path, offset, cur.k);
+		}
+		ret2;
+	}));
+
+	bch2_bkey_buf_exit(&cur, c);
+
+	/* report this file's extents before any errors for the next: */
+	ordered_work_flush(&r->w);
+
+	if (ret)
+		fprintf(stderr, "%s: error walking extents: %s\n", path, bch2_err_str(ret));
+out:
+	bch2_trans_put(trans);
+	return ret;
+}
+
+int cmd_verify(int argc, char *argv[])
+{
+	static const struct option longopts[] = {
+		{ "verbose",		no_argument,		NULL, 'v' },
+		{ "jobs",		required_argument,	NULL, 'j' },
+		{ "help",		no_argument,		NULL, 'h' },
+		{ NULL }
+	};
+	struct bch_opts opts = bch2_opts_empty();
+	struct verify s = {};
+	unsigned nr_jobs = nr_cpus();
+	int opt, ret = 0;
+
+	opt_set(opts, read_only,	true);
+	opt_set(opts, nochanges,	true);
+	opt_set(opts, norecovery,	true);
+	opt_set(opts, degraded,		true);
+	opt_set(opts, very_degraded,	true);
+	opt_set(opts, errors,		BCH_ON_ERROR_continue);
+
+	while ((opt = getopt_long(argc, argv, "vj:h", longopts, NULL)) != -1)
+		switch (opt) {
+		case 'v':
+			s.verbose = true;
+			break;
+		case 'j':
+			if (kstrtouint(optarg, 10, &nr_jobs) || !nr_jobs)
+				die("invalid number of jobs %s", optarg);
+			break;
+		case 'h':
+			verify_usage();
+			exit(EXIT_SUCCESS);
+		default:
+			verify_usage();
+			exit(EXIT_FAILURE);
+		}
+	args_shift(optind);
+
+	if (argc < 2) {
+		verify_usage();
+		die("Please supply device(s) and file(s)");
+	}
+
+	darray_str devs = get_or_split_cmdline_devs(1, argv);
+	args_shift(1);
+
+	struct bch_fs *c = bch2_fs_open(devs.data, devs.nr, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", devs.data[0], bch2_err_str(PTR_ERR(c)));
+
+	struct verify_run r = { .c = c, .s = &s };
+	ordered_work_init(&r.w, nr_jobs, sizeof(struct verify_job),
+			  verify_job_work, verify_job_done, &r);
+
+	for (unsigned i = 0; i < argc; i++)
+		ret = verify_file(&r, argv[i]) ?: ret;
+
+	ordered_work_exit(&r.w);
+
+	printf("%llu extents, %llu replicas checked: %llu bad replicas, %llu extents with no good replica, %llu replicas without checksums\n",
+	       s.extents, s.replicas, s.bad, s.unreadable, s.unchecked);
+
+	bch2_fs_stop(c);
+	darray_for_each(devs, i)
+		free(*i);
+	darray_exit(&devs);
+	return ret || s.bad ? EXIT_FAILURE : 0;
+}
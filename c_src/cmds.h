@@ -12,9 +12,21 @@
 int cmd_format(int argc, char *argv[]);
 int cmd_show_super(int argc, char *argv[]);
 int cmd_reset_counters(int argc, char *argv[]);
+int cmd_compat(int argc, char *argv[]);
+int cmd_version_upgrade(int argc, char *argv[]);
 int cmd_set_option(int argc, char *argv[]);
 
 int cmd_fs_usage(int argc, char *argv[]);
+int cmd_fs_accounting(int argc, char *argv[]);
+int cmd_fs_audit_options(int argc, char *argv[]);
+int cmd_fs_top_files(int argc, char *argv[]);
+int cmd_fs_du(int argc, char *argv[]);
+int cmd_fs_orphans(int argc, char *argv[]);
+int cmd_fs_resize(int argc, char *argv[]);
+int cmd_fs_latency(int argc, char *argv[]);
+int cmd_fs_counters(int argc, char *argv[]);
+int cmd_fs_gc(int argc, char *argv[]);
+int cmd_status(int argc, char *argv[]);
 
 int device_usage(void);
 int cmd_device_add(int argc, char *argv[]);
@@ -22,30 +34,76 @@ int cmd_device_remove(int argc, char *argv[]);
 int cmd_device_online(int argc, char *argv[]);
 int cmd_device_offline(int argc, char *argv[]);
 int cmd_device_evacuate(int argc, char *argv[]);
+int cmd_device_replace(int argc, char *argv[]);
 int cmd_device_set_state(int argc, char *argv[]);
 int cmd_device_resize(int argc, char *argv[]);
 int cmd_device_resize_journal(int argc, char *argv[]);
+int cmd_device_locate(int argc, char *argv[]);
+int cmd_device_scan(int argc, char *argv[]);
+int cmd_device_trim(int argc, char *argv[]);
 
 int data_usage(void);
 int cmd_data_rereplicate(int argc, char *argv[]);
+int cmd_data_rewrite(int argc, char *argv[]);
 int cmd_data_job(int argc, char *argv[]);
 
+int ec_usage(void);
+int cmd_ec_status(int argc, char *argv[]);
+int cmd_ec_repair(int argc, char *argv[]);
+
+int quota_usage(void);
+int cmd_quota_show(int argc, char *argv[]);
+int cmd_quota_set(int argc, char *argv[]);
+int cmd_quota_project(int argc, char *argv[]);
+int cmd_quota_rescan(int argc, char *argv[]);
+
+int label_usage(void);
+int cmd_device_set_label(int argc, char *argv[]);
+int cmd_label_list(int argc, char *argv[]);
+int cmd_label_rename(int argc, char *argv[]);
+
 int cmd_unlock(int argc, char *argv[]);
+int cmd_lock(int argc, char *argv[]);
 int cmd_set_passphrase(int argc, char *argv[]);
 int cmd_remove_passphrase(int argc, char *argv[]);
 
 int cmd_fsck(int argc, char *argv[]);
+int cmd_salvage(int argc, char *argv[]);
+int cmd_strip_alloc(int argc, char *argv[]);
+int cmd_undelete(int argc, char *argv[]);
 
 int cmd_dump(int argc, char *argv[]);
-int cmd_list_journal(int argc, char *argv[]);
+int cmd_journal_stats(int argc, char *argv[]);
+
+int journal_usage(void);
+int cmd_journal_resize(int argc, char *argv[]);
+int cmd_journal_move(int argc, char *argv[]);
 int cmd_kill_btree_node(int argc, char *argv[]);
+int cmd_check_nodes(int argc, char *argv[]);
+int cmd_verify(int argc, char *argv[]);
 
 int cmd_migrate(int argc, char *argv[]);
 int cmd_migrate_superblock(int argc, char *argv[]);
 
 int cmd_version(int argc, char *argv[]);
+int cmd_bench(int argc, char *argv[]);
 
 int cmd_setattr(int argc, char *argv[]);
+int cmd_getattr(int argc, char *argv[]);
+int cmd_cp(int argc, char *argv[]);
+int cmd_dedupe(int argc, char *argv[]);
+
+int attr_usage(void);
+int cmd_attr_get(int argc, char *argv[]);
+int cmd_attr_set(int argc, char *argv[]);
+
+int nocow_usage(void);
+int cmd_nocow_set(int argc, char *argv[]);
+int cmd_nocow_status(int argc, char *argv[]);
+
+int cache_usage(void);
+int cmd_cache_drop(int argc, char *argv[]);
+int cmd_cache_pin(int argc, char *argv[]);
 
 int subvolume_usage(void);
 int cmd_subvolume_create(int argc, char *argv[]);
@@ -58,6 +116,13 @@ void bcachefs_usage(void);
 int device_cmds(int argc, char *argv[]);
 int fs_cmds(int argc, char *argv[]);
 int data_cmds(int argc, char *argv[]);
+int ec_cmds(int argc, char *argv[]);
+int label_cmds(int argc, char *argv[]);
+int quota_cmds(int argc, char *argv[]);
+int attr_cmds(int argc, char *argv[]);
+int nocow_cmds(int argc, char *argv[]);
+int cache_cmds(int argc, char *argv[]);
+int journal_cmds(int argc, char *argv[]);
 int subvolume_cmds(int argc, char *argv[]);
 
 #endif /* _CMDS_H */
@@ -75,6 +75,29 @@ char *read_passphrase_twice(const char *prompt)
 	return pass;
 }
 
+#define SCRYPT_N_DEFAULT	16384
+#define SCRYPT_R		8
+#define SCRYPT_P_DEFAULT	16
+
+/*
+ * Fill in the defaults, and check the cost parameters are ones scrypt can use:
+ * the memory (128 * r * N bytes) and the parallelism factor (p) are
+ * stored as base 2 logs, so both must be powers of two
+ */
+void kdf_opts_check(struct kdf_opts *kdf)
+{
+	if (!kdf->memory)
+		kdf->memory = 128ULL * SCRYPT_R * SCRYPT_N_DEFAULT;
+	if (!kdf->parallelism)
+		kdf->parallelism = SCRYPT_P_DEFAULT;
+
+	if (kdf->memory < 128ULL * SCRYPT_R * 2 ||
+	    !is_power_of_2(kdf->memory))
+		die("kdf memory must be a power of two, at least %u", 128 * SCRYPT_R * 2);
+	if (!is_power_of_2(kdf->parallelism))
+		die("kdf parallelism must be a power of two");
+}
+
 struct bch_key derive_passphrase(struct bch_sb_field_crypt *crypt,
 				 const char *passphrase)
 {
@@ -109,9 +132,9 @@ bool bch2_sb_is_encrypted(struct bch_sb *sb)
 		bch2_key_is_encrypted(&crypt->key);
 }
 
-void bch2_passphrase_check(struct bch_sb *sb, const char *passphrase,
-			   struct bch_key *passphrase_key,
-			   struct bch_encrypted_key *sb_key)
+static int passphrase_check(struct bch_sb *sb, const char *passphrase,
+			    struct bch_key *passphrase_key,
+			    struct bch_encrypted_key *sb_key)
 {
 	struct bch_sb_field_crypt *crypt = bch2_sb_field_get(sb, crypt);
 	if (!crypt)
@@ -129,62 +152,134 @@ void bch2_passphrase_check(struct bch_sb *sb, const char *passphrase,
 				    sb_key, sizeof(*sb_key)))
 		die("error encrypting key");
 
-	if (bch2_key_is_encrypted(sb_key))
-		die("incorrect passphrase");
+	return bch2_key_is_encrypted(sb_key) ? -EKEYREJECTED : 0;
 }
 
-void bch2_add_key(struct bch_sb *sb,
-		  const char *type,
-		  const char *keyring_str,
-		  const char *passphrase)
+void bch2_passphrase_check(struct bch_sb *sb, const char *passphrase,
+			   struct bch_key *passphrase_key,
+			   struct bch_encrypted_key *sb_key)
 {
-	struct bch_key passphrase_key;
-	struct bch_encrypted_key sb_key;
-	int keyring;
+	if (passphrase_check(sb, passphrase, passphrase_key, sb_key))
+		die("incorrect passphrase");
+}
 
+static int keyring_lookup(const char *keyring_str)
+{
 	if (!strcmp(keyring_str, "session"))
-		keyring = KEY_SPEC_SESSION_KEYRING;
+		return KEY_SPEC_SESSION_KEYRING;
 	else if (!strcmp(keyring_str, "user"))
-		keyring = KEY_SPEC_USER_KEYRING;
+		return KEY_SPEC_USER_KEYRING;
 	else if (!strcmp(keyring_str, "user_session"))
-		keyring = KEY_SPEC_USER_SESSION_KEYRING;
+		return KEY_SPEC_USER_SESSION_KEYRING;
 	else
 		die("unknown keyring %s", keyring_str);
+}
 
-	bch2_passphrase_check(sb, passphrase,
-			      &passphrase_key,
-			      &sb_key);
-
+static char *sb_key_description(struct bch_sb *sb)
+{
 	char uuid[40];
 	uuid_unparse_lower(sb->user_uuid.b, uuid);
 
-	char *description = mprintf("bcachefs:%s", uuid);
+	return mprintf("bcachefs:%s", uuid);
+}
+
+/* Whether the key for @sb is already in @keyring_str, i.e. it's unlocked */
+bool bch2_key_present(struct bch_sb *sb, const char *type, const char *keyring_str)
+{
+	int keyring = keyring_lookup(keyring_str);
+	char *description = sb_key_description(sb);
+
+	bool ret = keyctl_search(keyring, type, description, 0) >= 0;
 
-	if (add_key(type,
-		    description,
-		    &passphrase_key, sizeof(passphrase_key),
-		    keyring) < 0)
-		die("add_key error: %m");
+	free(description);
+	return ret;
+}
+
+/*
+ * Revoke and unlink the key for @uuid from @keyring_str: revoking makes it
+ * unusable from every keyring it's in, unlinking means it's gone now rather
+ * than at the next garbage collection
+ */
+int bch2_remove_key(__uuid_t uuid, const char *keyring_str)
+{
+	int keyring = keyring_lookup(keyring_str);
+	char uuid_str[40];
+	uuid_unparse_lower(uuid.b, uuid_str);
 
-	memzero_explicit(description, strlen(description));
+	char *description = mprintf("bcachefs:%s", uuid_str);
+	long key_id = keyctl_search(keyring, "user", description, 0);
 	free(description);
+
+	if (key_id < 0)
+		return -errno;
+
+	if (keyctl_revoke(key_id) < 0 ||
+	    keyctl_unlink(key_id, keyring) < 0)
+		return -errno;
+	return 0;
+}
+
+/*
+ * bch2_add_key(), but returning errors, -EKEYREJECTED for the wrong passphrase,
+ * instead of exiting
+ */
+int bch2_try_add_key(struct bch_sb *sb,
+		     const char *type,
+		     const char *keyring_str,
+		     const char *passphrase)
+{
+	struct bch_key passphrase_key;
+	struct bch_encrypted_key sb_key;
+	int keyring = keyring_lookup(keyring_str);
+	int ret;
+
+	ret = passphrase_check(sb, passphrase, &passphrase_key, &sb_key);
+	if (!ret) {
+		char *description = sb_key_description(sb);
+
+		if (add_key(type,
+			    description,
+			    &passphrase_key, sizeof(passphrase_key),
+			    keyring) < 0)
+			ret = -errno;
+
+		memzero_explicit(description, strlen(description));
+		free(description);
+	}
+
 	memzero_explicit(&passphrase_key, sizeof(passphrase_key));
 	memzero_explicit(&sb_key, sizeof(sb_key));
+	return ret;
+}
+
+void bch2_add_key(struct bch_sb *sb,
+		  const char *type,
+		  const char *keyring_str,
+		  const char *passphrase)
+{
+	int ret = bch2_try_add_key(sb, type, keyring_str, passphrase);
+
+	if (ret == -EKEYREJECTED)
+		die("incorrect passphrase");
+	if (ret)
+		die("add_key error: %s", strerror(-ret));
 }
 
 void bch_sb_crypt_init(struct bch_sb *sb,
 		       struct bch_sb_field_crypt *crypt,
-		       const char *passphrase)
+		       const char *passphrase,
+		       struct kdf_opts kdf)
 {
 	crypt->key.magic = BCH_KEY_MAGIC;
 	get_random_bytes(&crypt->key.key, sizeof(crypt->key.key));
 
 	if (passphrase) {
+		kdf_opts_check(&kdf);
 
 		SET_BCH_CRYPT_KDF_TYPE(crypt, BCH_KDF_SCRYPT);
-		SET_BCH_KDF_SCRYPT_N(crypt, ilog2(16384));
-		SET_BCH_KDF_SCRYPT_R(crypt, ilog2(8));
-		SET_BCH_KDF_SCRYPT_P(crypt, ilog2(16));
+		SET_BCH_KDF_SCRYPT_N(crypt, ilog2(kdf.memory / (128 * SCRYPT_R)));
+		SET_BCH_KDF_SCRYPT_R(crypt, ilog2(SCRYPT_R));
+		SET_BCH_KDF_SCRYPT_P(crypt, ilog2(kdf.parallelism));
 
 		struct bch_key passphrase_key = derive_passphrase(crypt, passphrase);
 
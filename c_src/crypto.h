@@ -8,6 +8,17 @@ struct bch_sb_field_crypt;
 struct bch_key;
 struct bch_encrypted_key;
 
+/*
+ * scrypt cost for the passphrase key derivation, for format: zeroes mean the
+ * default
+ */
+struct kdf_opts {
+	u64		memory;		/* bytes */
+	unsigned	parallelism;	/* scrypt's p */
+};
+
+void kdf_opts_check(struct kdf_opts *);
+
 char *read_passphrase(const char *);
 char *read_passphrase_twice(const char *);
 
@@ -15,8 +26,11 @@ struct bch_key derive_passphrase(struct bch_sb_field_crypt *, const char *);
 bool bch2_sb_is_encrypted(struct bch_sb *);
 void bch2_passphrase_check(struct bch_sb *, const char *,
 			   struct bch_key *, struct bch_encrypted_key *);
+bool bch2_key_present(struct bch_sb *, const char *, const char *);
+int bch2_remove_key(__uuid_t, const char *);
+int bch2_try_add_key(struct bch_sb *, const char *, const char *, const char *);
 void bch2_add_key(struct bch_sb *, const char *, const char *, const char *);
 void bch_sb_crypt_init(struct bch_sb *sb, struct bch_sb_field_crypt *,
-		       const char *);
+		       const char *, struct kdf_opts);
 
 #endif /* _CRYPTO_H */
@@ -0,0 +1,168 @@
+#include <stdio.h>
+
+#include "libbcachefs/bcachefs.h"
+#include "libbcachefs/alloc_foreground.h"
+#include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update_interior.h"
+#include "libbcachefs/errcode.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/journal_reclaim.h"
+#include "libbcachefs/move.h"
+#include "libbcachefs/replicas.h"
+
+#include "data_job.h"
+#include "tools-util.h"
+
+unsigned data_job_parse_what(char *what)
+{
+	static const char * const types[] = { "metadata", "user", "cached", NULL };
+	u64 v = bch2_read_flag_list(what, types);
+
+	if (v == (u64) -1 || !v)
+		die("Bad data type %s (expected metadata, user and/or cached)", what);
+
+	return DATA_JOB_SKIP_ALL & ~v;
+}
+
+struct data_job {
+	struct bch_ioctl_data	op;
+	unsigned		skip;
+};
+
+/* As the kernel's rereplicate_pred() and migrate_pred(), plus --what: */
+static bool data_job_pred(struct bch_fs *c, void *arg,
+			  struct bkey_s_c k,
+			  struct bch_io_opts *io_opts,
+			  struct data_update_opts *data_opts)
+{
+	struct data_job *j = arg;
+
+	data_opts->rewrite_ptrs		= 0;
+	data_opts->target		= 0;
+	data_opts->extra_replicas	= 0;
+	data_opts->btree_insert_flags	= 0;
+
+	if (j->op.op == BCH_DATA_OP_rereplicate) {
+		unsigned nr_good = bch2_bkey_durability(c, k);
+		unsigned replicas = bkey_is_btree_ptr(k.k)
+			? c->opts.metadata_replicas
+			: io_opts->data_replicas;
+
+		if (!nr_good || nr_good >= replicas)
+			return false;
+
+		data_opts->extra_replicas = replicas - nr_good;
+		return true;
+	}
+
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	unsigned i = 0;
+
+	bkey_for_each_ptr(ptrs, ptr) {
+		unsigned skip = bkey_is_btree_ptr(k.k)	? 0
+			: ptr->cached			? DATA_JOB_SKIP_CACHED
+			:				  DATA_JOB_SKIP_USER;
+
+		if (ptr->dev == j->op.migrate.dev && !(j->skip & skip))
+			data_opts->rewrite_ptrs |= 1U << i;
+		i++;
+	}
+
+	return data_opts->rewrite_ptrs != 0;
+}
+
+/* bch2_move_btree() isn't exported: rewrite the btree nodes data_job_pred() picks */
+static int data_job_move_btree(struct bch_fs *c, struct data_job *j,
+			       struct bch_move_stats *stats)
+{
+	struct bch_io_opts io_opts = bch2_opts_to_inode_opts(c->opts);
+	struct data_update_opts data_opts;
+	struct btree_trans *trans = bch2_trans_get(c);
+	struct btree_iter iter;
+	struct btree *b;
+	int ret = 0;
+
+	stats->data_type = BCH_DATA_btree;
+
+	for (unsigned btree = 0; btree < btree_id_nr_alive(c); btree++) {
+		stats->pos = BBPOS(btree, POS_MIN);
+
+		if (!bch2_btree_id_root(c, btree)->b)
+			continue;
+
+		bch2_trans_node_iter_init(trans, &iter, btree, POS_MIN, 0, 0,
+					  BTREE_ITER_prefetch);
+retry:
+		ret = 0;
+		while (bch2_trans_begin(trans),
+		       (b = bch2_btree_iter_peek_node(&iter)) &&
+		       !(ret = PTR_ERR_OR_ZERO(b))) {
+			stats->pos = BBPOS(iter.btree_id, iter.pos);
+
+			if (data_job_pred(c, j, bkey_i_to_s_c(&b->key), &io_opts, &data_opts)) {
+				ret = bch2_btree_node_rewrite(trans, &iter, b, 0);
+				if (bch2_err_matches(ret, BCH_ERR_transaction_restart))
+					continue;
+				if (ret)
+					break;
+				atomic64_inc(&stats->keys_moved);
+				atomic64_add(btree_sectors(c), &stats->sectors_moved);
+			}
+
+			bch2_btree_iter_next_node(&iter);
+		}
+		if (bch2_err_matches(ret, BCH_ERR_transaction_restart))
+			goto retry;
+
+		bch2_trans_iter_exit(trans, &iter);
+		if (ret)
+			break;
+	}
+
+	bch2_trans_put(trans);
+	bch2_btree_interior_updates_flush(c);
+	return ret;
+}
+
+/*
+ * A rereplicate or migrate job, as bch2_data_job() runs them, skipping the
+ * kinds of data in @skip
+ */
+int data_job_offline(struct bch_fs *c, struct bch_ioctl_data op, unsigned skip)
+{
+	struct data_job j = { .op = op, .skip = skip };
+	struct bch_move_stats stats;
+	int ret;
+
+	bch2_move_stats_init(&stats, bch2_data_ops_strs[op.op]);
+
+	ret = bch2_journal_flush_device_pins(&c->journal,
+			op.op == BCH_DATA_OP_migrate ? op.migrate.dev : -1);
+
+	if (!(skip & DATA_JOB_SKIP_METADATA))
+		ret = data_job_move_btree(c, &j, &stats) ?: ret;
+
+	/* cached data is never rereplicated: */
+	bool move_data = op.op == BCH_DATA_OP_rereplicate
+		? !(skip & DATA_JOB_SKIP_USER)
+		: (skip & (DATA_JOB_SKIP_USER|DATA_JOB_SKIP_CACHED)) !=
+		  (DATA_JOB_SKIP_USER|DATA_JOB_SKIP_CACHED);
+
+	if (move_data)
+		ret = bch2_move_data(c, BBPOS_MIN, BBPOS_MAX, NULL, &stats,
+				     writepoint_hashed((unsigned long) current),
+				     true, data_job_pred, &j) ?: ret;
+
+	ret = bch2_replicas_gc2(c) ?: ret;
+
+	printf("Moved %llu keys, %llu sectors\n",
+	       atomic64_read(&stats.keys_moved),
+	       atomic64_read(&stats.sectors_moved));
+	bch2_move_stats_exit(&stats, c);
+
+	if (ret)
+		fprintf(stderr, "error running %s: %s\n",
+			bch2_data_ops_strs[op.op], bch2_err_str(ret));
+	return ret;
+}
@@ -0,0 +1,23 @@
+#ifndef _DATA_JOB_H
+#define _DATA_JOB_H
+
+#include "libbcachefs/bcachefs_ioctl.h"
+
+/*
+ * --what, for evacuate and rereplicate: the kinds of data to skip. The kernel's
+ * data jobs always move everything, so a job that skips anything runs here, on
+ * an unmounted filesystem
+ */
+#define DATA_JOB_SKIP_METADATA		(1U << 0)
+#define DATA_JOB_SKIP_USER		(1U << 1)
+#define DATA_JOB_SKIP_CACHED		(1U << 2)
+#define DATA_JOB_SKIP_ALL		(DATA_JOB_SKIP_METADATA|	\
+					 DATA_JOB_SKIP_USER|		\
+					 DATA_JOB_SKIP_CACHED)
+
+unsigned data_job_parse_what(char *);
+
+struct bch_fs;
+int data_job_offline(struct bch_fs *, struct bch_ioctl_data, unsigned);
+
+#endif /* _DATA_JOB_H */
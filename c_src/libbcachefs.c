@@ -17,13 +17,20 @@
 #include <uuid/uuid.h>
 
 #include "libbcachefs.h"
+#include "progress.h"
 #include "crypto.h"
 #include "libbcachefs/bcachefs_format.h"
+#include "libbcachefs/bkey_buf.h"
 #include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_locking.h"
 #include "libbcachefs/buckets.h"
 #include "libbcachefs/checksum.h"
+#include "libbcachefs/dirent.h"
 #include "libbcachefs/disk_groups.h"
+#include "libbcachefs/inode.h"
 #include "libbcachefs/journal_seq_blacklist.h"
+#include "libbcachefs/move.h"
 #include "libbcachefs/opts.h"
 #include "libbcachefs/replicas.h"
 #include "libbcachefs/super-io.h"
@@ -290,7 +297,7 @@ struct bch_sb *bch2_format(struct bch_opt_strs	fs_opt_strs,
 		struct bch_sb_field_crypt *crypt =
 			bch2_sb_field_resize(&sb, crypt, sizeof(*crypt) / sizeof(u64));
 
-		bch_sb_crypt_init(sb.sb, crypt, opts.passphrase);
+		bch_sb_crypt_init(sb.sb, crypt, opts.passphrase, opts.kdf);
 		SET_BCH_SB_ENCRYPTION_TYPE(sb.sb, 1);
 	}
 
@@ -396,6 +403,95 @@ struct bch_sb *__bch2_super_read(int fd, u64 sector)
 /* ioctl interface: */
 
 /* Global control device: */
+unsigned parse_metadata_version(const char *s)
+{
+	unsigned major, minor;
+
+	if (sscanf(s, "%u.%u", &major, &minor) != 2 ||
+	    minor >= 1U << 10)
+		die("invalid version %s (should be major.minor)", s);
+	return BCH_VERSION(major, minor);
+}
+
+static const char *mount_opts_path(void)
+{
+	return getenv("BCACHEFS_MOUNT_OPTIONS_FILE") ?: BCH_MOUNT_OPTS_FILE;
+}
+
+/* If @line is @uuid's entry, the options in it: */
+static char *mount_opts_line_match(char *line, const char *uuid)
+{
+	size_t len = strlen(uuid);
+
+	return !strncasecmp(line, uuid, len) && isspace(line[len])
+		? strim(line + len)
+		: NULL;
+}
+
+char *bch_mount_opts_get(__uuid_t uuid)
+{
+	FILE *f = fopen(mount_opts_path(), "r");
+	char uuid_str[40], *line = NULL, *ret = NULL;
+	size_t n = 0;
+
+	if (!f)
+		return NULL;
+
+	uuid_unparse_lower(uuid.b, uuid_str);
+
+	while (!ret && getline(&line, &n, f) > 0) {
+		char *opts = mount_opts_line_match(line, uuid_str);
+
+		if (opts && *opts)
+			ret = strdup(opts);
+	}
+
+	free(line);
+	fclose(f);
+	return ret;
+}
+
+/* An empty or NULL @opts removes the entry */
+void bch_mount_opts_set(__uuid_t uuid, const char *opts)
+{
+	const char *path = mount_opts_path();
+	char *tmp = mprintf("%s.tmp", path);
+	char *dir = strdup(path);
+	char uuid_str[40], *line = NULL;
+	size_t n = 0;
+
+	if (mkdir(dirname(dir), 0755) && errno != EEXIST)
+		die("error creating %s: %m", dir);
+	free(dir);
+
+	FILE *in = fopen(path, "r");
+	if (!in && errno != ENOENT)
+		die("error opening %s: %m", path);
+
+	FILE *out = fopen(tmp, "w");
+	if (!out)
+		die("error creating %s: %m", tmp);
+
+	uuid_unparse_lower(uuid.b, uuid_str);
+
+	while (in && getline(&line, &n, in) > 0)
+		if (!mount_opts_line_match(line, uuid_str))
+			fputs(line, out);
+
+	if (opts && *opts)
+		fprintf(out, "%s %s\n", uuid_str, opts);
+
+	if (fclose(out))
+		die("error writing %s: %m", tmp);
+	if (rename(tmp, path))
+		die("error renaming %s: %m", tmp);
+
+	if (in)
+		fclose(in);
+	free(line);
+	free(tmp);
+}
+
 int bcachectl_open(void)
 {
 	return xopen("/dev/bcachefs-ctl", O_RDWR);
@@ -514,8 +610,12 @@ int bchu_data(struct bchfs_handle fs, struct bch_ioctl_data cmd)
 {
 	int progress_fd = xioctl(fs.ioctl_fd, BCH_IOCTL_DATA, &cmd);
 
+	struct progress p;
+	progress_init(&p, bch2_data_ops_strs[cmd.op]);
+
 	while (1) {
 		struct bch_ioctl_data_event e;
+		char stage[64];
 
 		if (read(progress_fd, &e, sizeof(e)) != sizeof(e))
 			die("error reading from progress fd %m");
@@ -526,27 +626,29 @@ int bchu_data(struct bchfs_handle fs, struct bch_ioctl_data cmd)
 		if (e.p.data_type == U8_MAX)
 			break;
 
-		printf("\33[2K\r");
-
-		printf("%llu%% complete: current position %s",
-		       e.p.sectors_total
-		       ? e.p.sectors_done * 100 / e.p.sectors_total
-		       : 0,
-		       bch2_data_type_str(e.p.data_type));
-
 		switch (e.p.data_type) {
 		case BCH_DATA_btree:
 		case BCH_DATA_user:
-			printf(" %s:%llu:%llu",
-			       bch2_btree_id_str(e.p.btree_id),
-			       e.p.pos.inode,
-			       e.p.pos.offset);
+			snprintf(stage, sizeof(stage), "%s %s:%llu:%llu",
+				 bch2_data_type_str(e.p.data_type),
+				 bch2_btree_id_str(e.p.btree_id),
+				 e.p.pos.inode,
+				 e.p.pos.offset);
+			break;
+		default:
+			snprintf(stage, sizeof(stage), "%s",
+				 bch2_data_type_str(e.p.data_type));
 		}
 
-		fflush(stdout);
+		/* the position changes every event; the total is the same: */
+		strscpy(p.stage, stage, sizeof(p.stage));
+		p.total = e.p.sectors_total << 9;
+		p.bytes = true;
+		progress_update(&p, e.p.sectors_done << 9);
 		sleep(1);
 	}
-	printf("\nDone\n");
+	progress_finish(&p);
+	printf("Done\n");
 
 	close(progress_fd);
 	return 0;
@@ -648,6 +750,12 @@ struct bch_opts bch2_parse_opts(struct bch_opt_strs strs)
 	return opts;
 }
 
+/* bch2_opts_default is static, so not visible to Rust: */
+u64 bch2_opt_default_by_id(enum bch_opt_id id)
+{
+	return bch2_opt_get_by_id(&bch2_opts_default, id);
+}
+
 #define newline(c)		\
 	do {			\
 		printf("\n");	\
@@ -713,7 +821,8 @@ void bch2_opts_usage(unsigned opt_types)
 
 dev_names bchu_fs_get_devices(struct bchfs_handle fs)
 {
-	DIR *dir = fdopendir(fs.sysfs_fd);
+	/* a new fd: closedir() mustn't close fs.sysfs_fd */
+	DIR *dir = fdopendir(xopenat(fs.sysfs_fd, ".", O_RDONLY|O_DIRECTORY));
 	struct dirent *d;
 	dev_names devs;
 
@@ -752,3 +861,98 @@ dev_names bchu_fs_get_devices(struct bchfs_handle fs)
 
 	return devs;
 }
+
+/* Look up a file by path, from the root of the filesystem */
+int lookup_path(struct bch_fs *c, const char *path,
+		subvol_inum *ret_inum, struct bch_inode_unpacked *bi)
+{
+	char *p = strdup(path), *tmp = p, *name;
+	subvol_inum inum = BCACHEFS_ROOT_SUBVOL_INUM;
+	int ret = bch2_inode_find_by_inum(c, inum, bi);
+
+	while (!ret && (name = strsep(&tmp, "/"))) {
+		if (!*name)
+			continue;
+
+		if (!S_ISDIR(bi->bi_mode)) {
+			ret = -ENOTDIR;
+			break;
+		}
+
+		struct bch_hash_info hash_info = bch2_hash_info_init(c, bi);
+		struct qstr qstr = QSTR(name);
+
+		ret =   bch2_dirent_lookup(c, inum, &hash_info, &qstr, &inum) ?:
+			bch2_inode_find_by_inum(c, inum, bi);
+	}
+	free(p);
+
+	if (!ret)
+		*ret_inum = inum;
+	return ret;
+}
+
+/*
+ * The iterator's own prefetching (BTREE_ITER_prefetch) only reads a couple of
+ * nodes ahead once the filesystem has started; walking a whole btree on a
+ * high latency device wants many more reads in flight. Siblings are found in
+ * the parent node, so this doesn't read ahead past the end of the parent.
+ */
+void bch2_btree_iter_readahead(struct btree_iter *iter, struct btree_readahead *ra)
+{
+	struct btree_trans *trans = iter->trans;
+	struct bch_fs *c = trans->c;
+
+	if (!iter->path)
+		return;
+
+	struct btree_path *path = btree_iter_path(trans, iter);
+	unsigned level = path->level;
+
+	if (level + 1 >= BTREE_MAX_DEPTH)
+		return;
+
+	struct btree *b = path->l[level].b;
+	struct btree_path_level *l = &path->l[level + 1];
+
+	if (IS_ERR_OR_NULL(b) || b == ra->b ||
+	    IS_ERR_OR_NULL(l->b))
+		return;
+	ra->b = b;
+
+	bool was_locked = btree_node_locked(path, level + 1);
+	if (!was_locked && !bch2_btree_node_relock(trans, path, level + 1))
+		return;
+
+	struct btree_node_iter node_iter = l->iter;
+	struct bkey_packed *k;
+	struct bkey_buf tmp;
+	unsigned nr = ra->nr;
+	int ret = 0;
+
+	bch2_bkey_buf_init(&tmp);
+
+	while (nr-- && !ret) {
+		bch2_btree_node_iter_advance(&node_iter, l->b);
+		k = bch2_btree_node_iter_peek(&node_iter, l->b);
+		if (!k)
+			break;
+
+		bch2_bkey_buf_unpack(&tmp, c, l->b, k);
+		if (bpos_le(tmp.k->k.p, ra->prefetched_to))
+			continue;
+
+		ret = bch2_btree_node_prefetch(trans, path, tmp.k, path->btree_id, level);
+		if (!ret)
+			ra->prefetched_to = tmp.k->k.p;
+	}
+
+	if (!was_locked && btree_node_locked(path, level + 1))
+		btree_node_unlock(trans, path, level + 1);
+
+	bch2_bkey_buf_exit(&tmp, c);
+
+	/* readahead is best effort; the caller is about to peek again: */
+	if (bch2_err_matches(ret, BCH_ERR_transaction_restart))
+		bch2_trans_begin(trans);
+}
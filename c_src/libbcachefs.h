@@ -8,6 +8,7 @@
 #include "libbcachefs/bcachefs_ioctl.h"
 #include "libbcachefs/opts.h"
 #include "libbcachefs/vstructs.h"
+#include "crypto.h"
 #include "tools-util.h"
 
 /* option parsing */
@@ -28,6 +29,7 @@ struct {
 void bch2_opt_strs_free(struct bch_opt_strs *);
 struct bch_opt_strs bch2_cmdline_opts_get(int *, char *[], unsigned);
 struct bch_opts bch2_parse_opts(struct bch_opt_strs);
+u64 bch2_opt_default_by_id(enum bch_opt_id);
 void bch2_opts_usage(unsigned);
 
 struct format_opts {
@@ -37,16 +39,24 @@ struct format_opts {
 	unsigned	superblock_size;
 	bool		encrypted;
 	char		*passphrase;
+	struct kdf_opts	kdf;
 };
 
-static inline struct format_opts format_opts_default()
+/* Newest metadata version the running kernel supports, or 0 if unknown */
+static inline unsigned kernel_metadata_version(void)
 {
-	unsigned version = !access(   "/sys/module/bcachefs/parameters/version", R_OK)
+	return !access(   "/sys/module/bcachefs/parameters/version", R_OK)
 	    ? read_file_u64(AT_FDCWD, "/sys/module/bcachefs/parameters/version")
-	    : bcachefs_metadata_version_current;
+	    : 0;
+}
+
+unsigned parse_metadata_version(const char *);
 
+static inline struct format_opts format_opts_default()
+{
 	return (struct format_opts) {
-		.version		= version,
+		.version		= kernel_metadata_version() ?:
+					  bcachefs_metadata_version_current,
 		.superblock_size	= SUPERBLOCK_SIZE_DEFAULT,
 	};
 }
@@ -84,6 +94,17 @@ struct bch_sb *bch2_format(struct bch_opt_strs,
 			   struct format_opts, struct dev_opts *, size_t);
 
 void bch2_super_write(int, struct bch_sb *);
+
+/*
+ * Default mount options, for mount.bcachefs to use when none are given: the
+ * superblock has no field for them, so they're kept in a file, one
+ * "<uuid> <options>" line per filesystem; BCACHEFS_MOUNT_OPTIONS_FILE
+ * overrides the path
+ */
+#define BCH_MOUNT_OPTS_FILE	"/etc/bcachefs/mount_options"
+
+char *bch_mount_opts_get(__uuid_t);
+void bch_mount_opts_set(__uuid_t, const char *);
 struct bch_sb *__bch2_super_read(int, u64);
 
 /* ioctl interface: */
@@ -243,7 +264,7 @@ static inline void bchu_disk_resize_journal(struct bchfs_handle fs,
 					    unsigned idx,
 					    u64 nbuckets)
 {
-	struct bch_ioctl_disk_resize i = {
+	struct bch_ioctl_disk_resize_journal i = {
 		.flags	= BCH_BY_INDEX,
 		.dev	= idx,
 		.nbuckets = nbuckets,
@@ -265,4 +286,22 @@ typedef DARRAY(struct dev_name) dev_names;
 
 dev_names bchu_fs_get_devices(struct bchfs_handle);
 
+/*
+ * Readahead for offline btree walks: when the iterator moves to a new node,
+ * issue reads for the next @nr nodes at the same level
+ */
+struct btree_readahead {
+	unsigned	nr;
+	/* node the iterator was in, last time: */
+	struct btree	*b;
+	/* end of the last node read ahead: */
+	struct bpos	prefetched_to;
+};
+
+void bch2_btree_iter_readahead(struct btree_iter *, struct btree_readahead *);
+
+struct bch_inode_unpacked;
+int lookup_path(struct bch_fs *, const char *, subvol_inum *,
+		struct bch_inode_unpacked *);
+
 #endif /* _LIBBCACHE_H */
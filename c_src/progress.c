@@ -0,0 +1,181 @@
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <linux/jiffies.h>
+
+#include "progress.h"
+#include "tools-util.h"
+
+#define PROGRESS_BAR_WIDTH	30
+
+enum progress_when progress_when;
+
+void progress_opt(int opt)
+{
+	progress_when = opt == OPT_PROGRESS_JSON
+		? PROGRESS_JSON
+		: PROGRESS_NEVER;
+}
+
+bool progress_enabled(void)
+{
+	return progress_when != PROGRESS_NEVER;
+}
+
+static unsigned progress_pct(struct progress *p)
+{
+	return p->total
+		? min_t(u64, 100, div64_u64(p->done * 100, p->total))
+		: 0;
+}
+
+static void prt_progress_amount(struct printbuf *out, struct progress *p, u64 v)
+{
+	if (p->bytes)
+		prt_human_readable_u64(out, v);
+	else
+		prt_printf(out, "%llu", v);
+}
+
+static void prt_duration_secs(struct printbuf *out, u64 s)
+{
+	if (s >= 3600)
+		prt_printf(out, "%llu:%02llu:%02llu", s / 3600, s / 60 % 60, s % 60);
+	else
+		prt_printf(out, "%llu:%02llu", s / 60, s % 60);
+}
+
+static void prt_json_str(struct printbuf *out, const char *s)
+{
+	prt_char(out, '"');
+	for (; *s; s++) {
+		if ((unsigned char) *s < ' ') {
+			prt_printf(out, "\\u%04x", *s);
+			continue;
+		}
+		if (*s == '"' || *s == '\\')
+			prt_char(out, '\\');
+		prt_char(out, *s);
+	}
+	prt_char(out, '"');
+}
+
+static void progress_bar(struct progress *p, struct printbuf *out)
+{
+	prt_printf(out, "\r\033[2K%s", p->op);
+	if (*p->stage)
+		prt_printf(out, ": %s", p->stage);
+	prt_char(out, ' ');
+
+	if (p->total) {
+		unsigned pct = progress_pct(p);
+		unsigned filled = pct * PROGRESS_BAR_WIDTH / 100;
+
+		prt_char(out, '[');
+		for (unsigned i = 0; i < PROGRESS_BAR_WIDTH; i++)
+			prt_char(out, i < filled ? '#' : '-');
+		prt_printf(out, "] %3u%% ", pct);
+
+		prt_progress_amount(out, p, p->done);
+		prt_char(out, '/');
+		prt_progress_amount(out, p, p->total);
+	} else {
+		prt_progress_amount(out, p, p->done);
+	}
+
+	/* a rate and ETA only make sense for bytes: */
+	u64 ms = div_u64(ktime_get_ns() - p->stage_start, NSEC_PER_MSEC);
+	u64 rate = p->bytes && ms ? div64_u64(p->done * 1000, ms) : 0;
+
+	if (rate) {
+		prt_str(out, " (");
+		prt_progress_amount(out, p, rate);
+		prt_str(out, "/s");
+		if (p->total > p->done) {
+			prt_str(out, ", ETA ");
+			prt_duration_secs(out, div64_u64(p->total - p->done, rate));
+		}
+		prt_char(out, ')');
+	}
+}
+
+static void progress_json(struct progress *p, struct printbuf *out, bool finished)
+{
+	prt_str(out, "{\"op\":");
+	prt_json_str(out, p->op);
+	prt_str(out, ",\"stage\":");
+	prt_json_str(out, p->stage);
+	prt_printf(out, ",\"units\":\"%s\",\"done\":%llu,\"total\":",
+		   p->bytes ? "bytes" : "items", p->done);
+	if (p->total)
+		prt_printf(out, "%llu", p->total);
+	else
+		prt_str(out, "null");
+	prt_printf(out, ",\"elapsed_ms\":%llu,\"finished\":%s}\n",
+		   div_u64(ktime_get_ns() - p->start, NSEC_PER_MSEC),
+		   finished ? "true" : "false");
+}
+
+static void progress_draw(struct progress *p, bool force, bool finished)
+{
+	struct printbuf buf = PRINTBUF;
+	u64 now = ktime_get_ns();
+
+	switch (progress_when) {
+	case PROGRESS_NEVER:
+		return;
+	case PROGRESS_JSON:
+		if (!force && now - p->last < NSEC_PER_SEC)
+			return;
+		progress_json(p, &buf, finished);
+		break;
+	case PROGRESS_AUTO:
+		if (!isatty(STDERR_FILENO))
+			return;
+		if (!force && now - p->last < NSEC_PER_SEC / 10)
+			return;
+		progress_bar(p, &buf);
+		if (finished)
+			prt_newline(&buf);
+		break;
+	}
+
+	p->last = now;
+	fputs(buf.buf ?: "", stderr);
+	fflush(stderr);
+	printbuf_exit(&buf);
+}
+
+void progress_init(struct progress *p, const char *op)
+{
+	memset(p, 0, sizeof(*p));
+	p->op		= op;
+	p->start	= ktime_get_ns();
+	p->stage_start	= p->start;
+}
+
+/*
+ * Start a new stage, of @total bytes or items (0 if not known); it's shown on
+ * the next update
+ */
+void progress_stage(struct progress *p, const char *stage, u64 total, bool bytes)
+{
+	strscpy(p->stage, stage ?: "", sizeof(p->stage));
+	p->done		= 0;
+	p->total	= total;
+	p->bytes	= bytes;
+	p->stage_start	= ktime_get_ns();
+	p->last		= 0;
+}
+
+void progress_update(struct progress *p, u64 done)
+{
+	p->done = done;
+	progress_draw(p, false, false);
+}
+
+void progress_finish(struct progress *p)
+{
+	progress_draw(p, true, true);
+}
@@ -0,0 +1,49 @@
+#ifndef _PROGRESS_H
+#define _PROGRESS_H
+
+#include <stdbool.h>
+#include <linux/types.h>
+
+/*
+ * Progress reporting for long running offline operations (fsck, dump, migrate,
+ * data jobs), on stderr: a bar if it's a terminal and nothing if not, or with
+ * --progress-json, a JSON object per line, once a second
+ */
+enum progress_when {
+	PROGRESS_AUTO,
+	PROGRESS_NEVER,
+	PROGRESS_JSON,
+};
+
+extern enum progress_when progress_when;
+
+/* getopt values for --no-progress and --progress-json, which have no short options: */
+#define OPT_NO_PROGRESS		0x100
+#define OPT_PROGRESS_JSON	0x101
+
+void progress_opt(int);
+bool progress_enabled(void);
+
+struct progress {
+	const char	*op;
+	char		stage[64];
+	bool		bytes;		/* done and total are bytes, not a count */
+	u64		done;
+	u64		total;		/* 0 if not known */
+
+	u64		start;
+	u64		stage_start;
+	u64		last;
+};
+
+void progress_init(struct progress *, const char *);
+void progress_stage(struct progress *, const char *, u64, bool);
+void progress_update(struct progress *, u64);
+void progress_finish(struct progress *);
+
+static inline void progress_add(struct progress *p, u64 n)
+{
+	progress_update(p, p->done + n);
+}
+
+#endif /* _PROGRESS_H */
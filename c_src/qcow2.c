@@ -3,6 +3,7 @@
 #include <sys/types.h>
 #include <unistd.h>
 
+#include "progress.h"
 #include "qcow2.h"
 #include "tools-util.h"
 
@@ -31,104 +32,90 @@ struct qcow2_hdr {
 	u64			snapshots_offset;
 };
 
-struct qcow2_image {
-	int			fd;
-	u32			block_size;
-	u64			*l1_table;
-	u64			l1_offset;
-	u32			l1_index;
-	u64			*l2_table;
-	u64			offset;
-};
-
-static void flush_l2(struct qcow2_image *img)
-{
-	if (img->l1_index != -1) {
-		img->l1_table[img->l1_index] =
-			cpu_to_be64(img->offset|QCOW_OFLAG_COPIED);
-		xpwrite(img->fd, img->l2_table, img->block_size, img->offset,
-			"qcow2 l2 table");
-		img->offset += img->block_size;
-
-		memset(img->l2_table, 0, img->block_size);
-		img->l1_index = -1;
-	}
-}
-
-static void add_l2(struct qcow2_image *img, u64 src_blk, u64 dst_offset)
-{
-	unsigned l2_size = img->block_size / sizeof(u64);
-	u64 l1_index = src_blk / l2_size;
-	u64 l2_index = src_blk & (l2_size - 1);
-
-	if (img->l1_index != l1_index) {
-		flush_l2(img);
-		img->l1_index = l1_index;
-	}
-
-	img->l2_table[l2_index] = cpu_to_be64(dst_offset|QCOW_OFLAG_COPIED);
-}
+/*
+ * The image is laid out as header, L1 table, L2 tables, then data, which can
+ * all be computed up front from the list of ranges - so that it can be written
+ * out sequentially, to a pipe
+ */
+#define for_each_block(_data, _block_size, _src)				\
+	darray_for_each(*(_data), _r)						\
+		for (_src = _r->start; _src < _r->end; _src += (_block_size))
 
 void qcow2_write_image(int infd, int outfd, ranges *data,
-		       unsigned block_size)
+		       unsigned block_size, struct progress *p)
 {
 	u64 image_size = get_size(infd);
 	unsigned l2_size = block_size / sizeof(u64);
 	unsigned l1_size = DIV_ROUND_UP(image_size, (u64) block_size * l2_size);
+	size_t l1_bytes = round_up(l1_size * sizeof(u64), block_size);
+	u64 *l1_table = xcalloc(l1_bytes, 1);
+	u64 *l2_table = xcalloc(l2_size, sizeof(u64));
+	char *buf = xcalloc(block_size, 1);
+	u64 l1_offset = block_size;
+	u64 l2_offset = l1_offset + l1_bytes;
+	u64 l1_index = U64_MAX, nr_l2 = 0, src_offset, dst_offset, data_bytes = 0;
 	struct qcow2_hdr hdr = { 0 };
-	struct qcow2_image img = {
-		.fd		= outfd,
-		.block_size	= block_size,
-		.l2_table	= xcalloc(l2_size, sizeof(u64)),
-		.l1_table	= xcalloc(l1_size, sizeof(u64)),
-		.l1_index	= -1,
-		.offset		= round_up(sizeof(hdr), block_size),
-	};
-	char *buf = xmalloc(block_size);
-	u64 src_offset, dst_offset;
 
 	assert(is_power_of_2(block_size));
 
 	ranges_roundup(data, block_size);
 	ranges_sort_merge(data);
 
-	/* Write data: */
 	darray_for_each(*data, r)
-		for (src_offset = r->start;
-		     src_offset < r->end;
-		     src_offset += block_size) {
-			dst_offset = img.offset;
-			img.offset += img.block_size;
-
-			xpread(infd, buf, block_size, src_offset);
-			xpwrite(outfd, buf, block_size, dst_offset,
-				"qcow2 data");
-
-			add_l2(&img, src_offset / block_size, dst_offset);
+		data_bytes += r->end - r->start;
+	progress_stage(p, "writing image", data_bytes, true);
+
+	/* L1 table: */
+	for_each_block(data, block_size, src_offset)
+		if (src_offset / block_size / l2_size != l1_index) {
+			l1_index = src_offset / block_size / l2_size;
+			l1_table[l1_index] =
+				cpu_to_be64((l2_offset + nr_l2++ * block_size)|QCOW_OFLAG_COPIED);
 		}
 
-	flush_l2(&img);
-
-	/* Write L1 table: */
-	dst_offset		= img.offset;
-	img.offset		+= round_up(l1_size * sizeof(u64), block_size);
-	xpwrite(img.fd, img.l1_table, l1_size * sizeof(u64), dst_offset,
-		"qcow2 l1 table");
-
 	/* Write header: */
 	hdr.magic		= cpu_to_be32(QCOW_MAGIC);
 	hdr.version		= cpu_to_be32(QCOW_VERSION);
 	hdr.block_bits		= cpu_to_be32(ilog2(block_size));
 	hdr.size		= cpu_to_be64(image_size);
 	hdr.l1_size		= cpu_to_be32(l1_size);
-	hdr.l1_table_offset	= cpu_to_be64(dst_offset);
+	hdr.l1_table_offset	= cpu_to_be64(l1_offset);
 
-	memset(buf, 0, block_size);
 	memcpy(buf, &hdr, sizeof(hdr));
-	xpwrite(img.fd, buf, block_size, 0,
-		"qcow2 header");
+	xwrite(outfd, buf, block_size, "qcow2 header");
+
+	xwrite(outfd, l1_table, l1_bytes, "qcow2 l1 table");
+
+	/* Write L2 tables: */
+	dst_offset	= l2_offset + nr_l2 * block_size;
+	l1_index	= U64_MAX;
+
+	for_each_block(data, block_size, src_offset) {
+		u64 src_blk = src_offset / block_size;
+
+		if (src_blk / l2_size != l1_index) {
+			if (l1_index != U64_MAX) {
+				xwrite(outfd, l2_table, block_size, "qcow2 l2 table");
+				memset(l2_table, 0, block_size);
+			}
+			l1_index = src_blk / l2_size;
+		}
+
+		l2_table[src_blk & (l2_size - 1)] = cpu_to_be64(dst_offset|QCOW_OFLAG_COPIED);
+		dst_offset += block_size;
+	}
+
+	if (l1_index != U64_MAX)
+		xwrite(outfd, l2_table, block_size, "qcow2 l2 table");
+
+	/* Write data: */
+	for_each_block(data, block_size, src_offset) {
+		xpread(infd, buf, block_size, src_offset);
+		xwrite(outfd, buf, block_size, "qcow2 data");
+		progress_add(p, block_size);
+	}
 
-	free(img.l2_table);
-	free(img.l1_table);
+	free(l2_table);
+	free(l1_table);
 	free(buf);
 }
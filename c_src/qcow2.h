@@ -4,6 +4,8 @@
 #include <linux/types.h>
 #include "tools-util.h"
 
-void qcow2_write_image(int, int, ranges *, unsigned);
+struct progress;
+
+void qcow2_write_image(int, int, ranges *, unsigned, struct progress *);
 
 #endif /* _QCOW2_H */
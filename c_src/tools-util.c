@@ -19,8 +19,12 @@
 
 #include "libbcachefs.h"
 #include "libbcachefs/bcachefs_ioctl.h"
+#include "linux/rcupdate.h"
+#include "linux/sched.h"
 #include "linux/sort.h"
 #include "tools-util.h"
+#include "libbcachefs/sb-members.h"
+#include "libbcachefs/super.h"
 #include "libbcachefs/util.h"
 
 void die(const char *fmt, ...)
@@ -73,6 +77,19 @@ void xpwrite(int fd, const void *buf, size_t count, off_t offset, const char *ms
 		die("error writing %s (ret %zi err %m)", msg, r);
 }
 
+/* Sequential writes, which may be to a pipe: */
+void xwrite(int fd, const void *buf, size_t count, const char *msg)
+{
+	while (count) {
+		ssize_t r = write(fd, buf, count);
+
+		if (r < 0)
+			die("error writing %s: %m", msg);
+		buf	+= r;
+		count	-= r;
+	}
+}
+
 struct stat xfstatat(int dirfd, const char *path, int flags)
 {
 	struct stat stat;
@@ -323,6 +340,50 @@ struct fiemap_extent fiemap_iter_next(struct fiemap_iter *iter)
 	return e;
 }
 
+/* FIDEDUPERANGE may do less than asked for; most filesystems cap it at 16M: */
+#define DEDUPE_CHUNK	(16ULL << 20)
+
+/*
+ * Share @len bytes at @dst_off in @dst_fd with @src_off in @src_fd, if the
+ * data is identical - the kernel does the comparison. Returns bytes
+ * deduplicated, stopping early where the data differs, or -errno.
+ */
+s64 dedupe_range(int src_fd, u64 src_off, int dst_fd, u64 dst_off, u64 len)
+{
+	struct file_dedupe_range *r =
+		xcalloc(1, sizeof(*r) + sizeof(struct file_dedupe_range_info));
+	u64 done = 0;
+	s64 ret = 0;
+
+	r->dest_count = 1;
+
+	while (done < len) {
+		r->src_offset		= src_off + done;
+		r->src_length		= min(len - done, DEDUPE_CHUNK);
+		r->info[0].dest_fd	= dst_fd;
+		r->info[0].dest_offset	= dst_off + done;
+
+		if (ioctl(src_fd, FIDEDUPERANGE, r)) {
+			ret = -errno;
+			break;
+		}
+
+		if (r->info[0].status < 0) {
+			ret = r->info[0].status;
+			break;
+		}
+
+		if (r->info[0].status == FILE_DEDUPE_RANGE_DIFFERS ||
+		    !r->info[0].bytes_deduped)
+			break;
+
+		done += r->info[0].bytes_deduped;
+	}
+
+	free(r);
+	return ret ?: done;
+}
+
 char *strcmp_prefix(char *a, const char *a_prefix)
 {
 	while (*a_prefix && *a == *a_prefix) {
@@ -622,6 +683,35 @@ got_model:
 	}
 }
 
+/*
+ * Whether a block device is rotational, according to sysfs: -1 if it's not a
+ * block device, or sysfs doesn't say
+ */
+int dev_rotational(const char *path)
+{
+	struct stat statbuf;
+	int ret = -1;
+
+	if (!path || stat(path, &statbuf) || !S_ISBLK(statbuf.st_mode))
+		return -1;
+
+	char *sysfs_path = dev_to_sysfs_path(statbuf.st_rdev);
+	char *rot_path = mprintf("%s/queue/rotational", sysfs_path);
+
+	/* partition? the queue belongs to the parent */
+	if (access(rot_path, R_OK)) {
+		free(rot_path);
+		rot_path = mprintf("%s/../queue/rotational", sysfs_path);
+	}
+
+	if (!access(rot_path, R_OK))
+		ret = read_file_u64(AT_FDCWD, rot_path) != 0;
+
+	free(rot_path);
+	free(sysfs_path);
+	return ret;
+}
+
 static int kstrtoull_symbolic(const char *s, unsigned int base, unsigned long long *res)
 {
 	if (!strcmp(s, "U64_MAX")) {
@@ -721,3 +811,368 @@ darray_str get_or_split_cmdline_devs(int argc, char *argv[])
 
 	return ret;
 }
+
+bool same_file(const char *l, const char *r)
+{
+	struct stat l_st, r_st;
+
+	return !stat(l, &l_st) && !stat(r, &r_st) &&
+		l_st.st_dev == r_st.st_dev &&
+		l_st.st_ino == r_st.st_ino;
+}
+
+/*
+ * A filesystem argument: a mountpoint, or for an unmounted filesystem, its
+ * devices separated by colons
+ */
+bool fs_arg_is_mounted(const char *fs)
+{
+	struct stat st;
+
+	return !stat(fs, &st) && S_ISDIR(st.st_mode);
+}
+
+struct bch_fs *fs_arg_open_offline(char *fs, struct bch_opts opts)
+{
+	darray_str devs = get_or_split_cmdline_devs(1, &fs);
+
+	darray_for_each(devs, i)
+		if (dev_mounted(*i))
+			die("%s is mounted; give the mountpoint instead", *i);
+
+	struct bch_fs *c = bch2_fs_open(devs.data, devs.nr, opts);
+	if (IS_ERR(c))
+		die("error opening %s: %s", fs, bch2_err_str(PTR_ERR(c)));
+
+	bch2_darray_str_exit(&devs);
+	return c;
+}
+
+/*
+ * A member of a filesystem opened with fs_arg_open_offline(), by index or path;
+ * returns with a ref held
+ */
+struct bch_dev *fs_arg_dev_offline(struct bch_fs *c, const char *fs,
+				   const char *dev_str)
+{
+	char *end;
+	unsigned dev_idx = strtoul(dev_str, &end, 10);
+	bool by_id = *dev_str && !*end;
+
+	for_each_member_device(c, ca)
+		if (by_id
+		    ? ca->dev_idx == dev_idx
+		    : ca->disk_sb.sb_name && same_file(ca->disk_sb.sb_name, dev_str)) {
+			if (!ca->disk_sb.bdev)
+				die("%s is not online", dev_str);
+			return ca;
+		}
+
+	die("%s does not seem to be a member of %s", dev_str, fs);
+}
+
+enum color_when color_when = COLOR_AUTO;
+
+void color_when_parse(const char *arg)
+{
+	static const char * const color_when_strs[] = {
+		"auto", "always", "never", NULL
+	};
+
+	color_when = read_string_list_or_die(arg, color_when_strs, "--color");
+}
+
+bool color_enabled(FILE *f)
+{
+	switch (color_when) {
+	case COLOR_ALWAYS:
+		return true;
+	case COLOR_NEVER:
+		return false;
+	default:
+		return isatty(fileno(f)) && !getenv("NO_COLOR");
+	}
+}
+
+const char *severity_color(enum severity sev)
+{
+	switch (sev) {
+	case SEVERITY_ERROR:
+		return "\033[1;31m";
+	case SEVERITY_WARNING:
+		return "\033[1;33m";
+	case SEVERITY_FIXED:
+		return "\033[32m";
+	default:
+		return "";
+	}
+}
+
+void fprintf_severity(FILE *f, enum severity sev, const char *fmt, ...)
+{
+	bool color = color_enabled(f);
+	va_list args;
+
+	if (color)
+		fputs(severity_color(sev), f);
+
+	va_start(args, fmt);
+	vfprintf(f, fmt, args);
+	va_end(args);
+
+	if (color)
+		fputs(COLOR_RESET, f);
+}
+
+/*
+ * Color the last @len bytes of a printbuf that will be printed to stdout:
+ * this must be the last field on the line, since the escape codes would throw
+ * off tabstops for any that came after
+ */
+void printbuf_color_last(struct printbuf *out, unsigned len, enum severity sev)
+{
+	const char *start = severity_color(sev);
+	unsigned start_len = strlen(start);
+
+	if (!color_enabled(stdout) || !start_len || len > out->pos)
+		return;
+
+	if (bch2_printbuf_make_room(out, start_len + strlen(COLOR_RESET)))
+		return;
+
+	char *p = out->buf + out->pos - len;
+	memmove(p + start_len, p, len);
+	memcpy(p, start, start_len);
+	out->pos += start_len;
+
+	prt_str(out, COLOR_RESET);
+}
+
+unsigned nr_cpus(void)
+{
+	long n = sysconf(_SC_NPROCESSORS_ONLN);
+
+	return n > 0 ? n : 1;
+}
+
+/*
+ * Threads not created with kthread_create() - libfuse's, or Rust's - have no
+ * task_struct and aren't registered with urcu: they need this before calling
+ * into bcachefs, and tools_thread_exit() before they exit
+ */
+void tools_thread_init(void)
+{
+	struct task_struct *p = xcalloc(1, sizeof(*p));
+
+	p->state = TASK_RUNNING;
+	atomic_set(&p->usage, 1);
+	init_completion(&p->exited);
+
+	current = p;
+	rcu_register_thread();
+}
+
+void tools_thread_exit(void)
+{
+	struct task_struct *p = current;
+
+	rcu_unregister_thread();
+	current = NULL;
+	/* not a kthread, nothing to join: */
+	free(p);
+}
+
+static void *ordered_work_item(struct ordered_work *w, u64 idx)
+{
+	return w->items + (idx % w->depth) * w->item_size;
+}
+
+static void *ordered_work_thread(void *arg)
+{
+	struct ordered_work *w = arg;
+
+	pthread_mutex_lock(&w->lock);
+	while (1) {
+		while (w->next == w->tail && !w->stop)
+			pthread_cond_wait(&w->wait, &w->lock);
+		if (w->next == w->tail)
+			break;
+
+		u64 idx = w->next++;
+		pthread_mutex_unlock(&w->lock);
+
+		w->work(ordered_work_item(w, idx), w->priv);
+
+		pthread_mutex_lock(&w->lock);
+		w->items_done[idx % w->depth] = true;
+		pthread_cond_broadcast(&w->wait);
+	}
+	pthread_mutex_unlock(&w->lock);
+	return NULL;
+}
+
+void ordered_work_init(struct ordered_work *w, unsigned nr_threads, size_t item_size,
+		       ordered_work_fn work, ordered_work_fn done, void *priv)
+{
+	memset(w, 0, sizeof(*w));
+	w->work		= work;
+	w->done		= done;
+	w->priv		= priv;
+	w->nr_threads	= max(nr_threads, 1U);
+	w->depth	= w->nr_threads * 4;
+	w->item_size	= item_size;
+	w->items	= xcalloc(w->depth, item_size);
+	w->items_done	= xcalloc(w->depth, sizeof(bool));
+	w->threads	= xcalloc(w->nr_threads, sizeof(pthread_t));
+
+	pthread_mutex_init(&w->lock, NULL);
+	pthread_cond_init(&w->wait, NULL);
+
+	for (unsigned i = 0; i < w->nr_threads; i++)
+		if (pthread_create(&w->threads[i], NULL, ordered_work_thread, w))
+			die("error starting worker thread");
+}
+
+/* Report the oldest item: called with the lock held */
+static void ordered_work_report(struct ordered_work *w)
+{
+	unsigned slot = w->head % w->depth;
+
+	while (!w->items_done[slot])
+		pthread_cond_wait(&w->wait, &w->lock);
+
+	/* workers don't touch an item once it's done: */
+	pthread_mutex_unlock(&w->lock);
+	w->done(ordered_work_item(w, w->head), w->priv);
+	pthread_mutex_lock(&w->lock);
+
+	w->items_done[slot] = false;
+	w->head++;
+}
+
+/* The next free item, to be filled in and then submitted */
+void *ordered_work_get(struct ordered_work *w)
+{
+	pthread_mutex_lock(&w->lock);
+	if (w->tail - w->head == w->depth)
+		ordered_work_report(w);
+	pthread_mutex_unlock(&w->lock);
+
+	return ordered_work_item(w, w->tail);
+}
+
+void ordered_work_submit(struct ordered_work *w)
+{
+	pthread_mutex_lock(&w->lock);
+	w->tail++;
+	pthread_cond_broadcast(&w->wait);
+	pthread_mutex_unlock(&w->lock);
+}
+
+/* Wait for everything submitted, and report it */
+void ordered_work_flush(struct ordered_work *w)
+{
+	pthread_mutex_lock(&w->lock);
+	while (w->head != w->tail)
+		ordered_work_report(w);
+	pthread_mutex_unlock(&w->lock);
+}
+
+void ordered_work_exit(struct ordered_work *w)
+{
+	ordered_work_flush(w);
+
+	pthread_mutex_lock(&w->lock);
+	w->stop = true;
+	pthread_cond_broadcast(&w->wait);
+	pthread_mutex_unlock(&w->lock);
+
+	for (unsigned i = 0; i < w->nr_threads; i++)
+		pthread_join(w->threads[i], NULL);
+
+	pthread_cond_destroy(&w->wait);
+	pthread_mutex_destroy(&w->lock);
+	free(w->threads);
+	free(w->items_done);
+	free(w->items);
+}
+
+enum tools_units tools_units;
+
+static const char * const tools_units_strs[] = {
+	[TOOLS_UNITS_bytes]	= "B",
+	[TOOLS_UNITS_KiB]	= "KiB",
+	[TOOLS_UNITS_MiB]	= "MiB",
+	[TOOLS_UNITS_GiB]	= "GiB",
+	[TOOLS_UNITS_TiB]	= "TiB",
+	[TOOLS_UNITS_human]	= "human",
+	[TOOLS_UNITS_si]	= "si",
+};
+
+int tools_units_parse(const char *str)
+{
+	for (unsigned i = TOOLS_UNITS_bytes; i < ARRAY_SIZE(tools_units_strs); i++)
+		if (!strcasecmp(str, tools_units_strs[i])) {
+			tools_units = i;
+			return 0;
+		}
+
+	return -EINVAL;
+}
+
+/*
+ * Set the units sizes are printed in: @human_readable as the command asked,
+ * unless overridden with --units
+ */
+void tools_printbuf_units(struct printbuf *out, bool human_readable)
+{
+	switch (tools_units) {
+	case TOOLS_UNITS_default:
+	case TOOLS_UNITS_KiB ... TOOLS_UNITS_TiB:
+		/* done by tools_prt_units_u64() */
+		out->human_readable_units = human_readable;
+		break;
+	case TOOLS_UNITS_bytes:
+		out->human_readable_units = false;
+		break;
+	case TOOLS_UNITS_human:
+		out->human_readable_units = true;
+		out->si_units = PRINTBUF_UNITS_2;
+		break;
+	case TOOLS_UNITS_si:
+		out->human_readable_units = true;
+		out->si_units = PRINTBUF_UNITS_10;
+		break;
+	}
+}
+
+/*
+ * prt_units_u64(), or with --units=KiB..TiB, in that unit to two decimal
+ * places - which printbufs can't do themselves
+ */
+void tools_prt_units_u64(struct printbuf *out, u64 v)
+{
+	if (tools_units < TOOLS_UNITS_KiB ||
+	    tools_units > TOOLS_UNITS_TiB) {
+		prt_units_u64(out, v);
+		return;
+	}
+
+	unsigned shift = 10 * (tools_units - TOOLS_UNITS_bytes);
+	u64 whole = v >> shift;
+	u64 frac = ((v & ((1ULL << shift) - 1)) * 100 + (1ULL << (shift - 1))) >> shift;
+
+	if (frac == 100) {
+		whole++;
+		frac = 0;
+	}
+
+	prt_printf(out, "%llu.%02llu %s", whole, frac, tools_units_strs[tools_units]);
+}
+
+void tools_prt_units_s64(struct printbuf *out, s64 v)
+{
+	if (v < 0)
+		prt_char(out, '-');
+	tools_prt_units_u64(out, abs(v));
+}
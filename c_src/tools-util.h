@@ -3,6 +3,7 @@
 
 #include <errno.h>
 #include <mntent.h>
+#include <pthread.h>
 #include <stdbool.h>
 #include <stdio.h>
 #include <stdlib.h>
@@ -30,6 +31,7 @@ char *mprintf(const char *, ...)
 	__attribute__ ((format (printf, 1, 2)));
 void xpread(int, void *, size_t, off_t);
 void xpwrite(int, const void *, size_t, off_t, const char *);
+void xwrite(int, const void *, size_t, const char *);
 struct stat xfstatat(int, const char *, int);
 struct stat xfstat(int);
 struct stat xstat(const char *);
@@ -172,6 +174,8 @@ struct fiemap_extent fiemap_iter_next(struct fiemap_iter *);
 	for (fiemap_iter_init(&iter, fd);				\
 	     (extent = fiemap_iter_next(&iter)).fe_length;)
 
+s64 dedupe_range(int, u64, int, u64, u64);
+
 char *strcmp_prefix(char *, const char *);
 
 u32 crc32c(u32, const void *, size_t);
@@ -181,6 +185,7 @@ char *dev_to_path(dev_t);
 struct mntent *dev_to_mount(char *);
 int dev_mounted(char *);
 char *fd_to_dev_model(int);
+int dev_rotational(const char *);
 
 #define args_shift(_nr)							\
 do {									\
@@ -209,4 +214,100 @@ struct bbpos_range bbpos_range_parse(char *);
 
 darray_str get_or_split_cmdline_devs(int argc, char *argv[]);
 
+bool same_file(const char *, const char *);
+
+struct bch_dev;
+bool fs_arg_is_mounted(const char *);
+struct bch_fs *fs_arg_open_offline(char *, struct bch_opts);
+struct bch_dev *fs_arg_dev_offline(struct bch_fs *, const char *, const char *);
+
+/* Output coloring, for --color=auto|always|never: */
+
+enum color_when {
+	COLOR_AUTO,
+	COLOR_ALWAYS,
+	COLOR_NEVER,
+};
+
+enum severity {
+	SEVERITY_ERROR,
+	SEVERITY_WARNING,
+	SEVERITY_FIXED,
+	SEVERITY_INFO,
+};
+
+extern enum color_when color_when;
+
+void color_when_parse(const char *);
+bool color_enabled(FILE *);
+const char *severity_color(enum severity);
+
+#define COLOR_RESET	"\033[0m"
+
+void fprintf_severity(FILE *, enum severity, const char *, ...)
+	__attribute__ ((format (printf, 3, 4)));
+void printbuf_color_last(struct printbuf *, unsigned, enum severity);
+
+/*
+ * A pool of threads for work done per item (e.g. reading and checksumming an
+ * extent), where the results have to be reported in order: @work is called on
+ * the worker threads, then @done on the submitting thread, in the order items
+ * were submitted. Up to four items per thread are in flight; getting a new
+ * item reports the oldest first if they're all in use.
+ */
+typedef void (*ordered_work_fn)(void *item, void *priv);
+
+struct ordered_work {
+	ordered_work_fn		work;
+	ordered_work_fn		done;
+	void			*priv;
+
+	pthread_mutex_t		lock;
+	pthread_cond_t		wait;
+	void			*items;
+	size_t			item_size;
+	bool			*items_done;
+	unsigned		depth;
+	/* oldest item, to be reported next: */
+	u64			head;
+	/* next item for a worker: */
+	u64			next;
+	/* next free slot: */
+	u64			tail;
+	bool			stop;
+
+	pthread_t		*threads;
+	unsigned		nr_threads;
+};
+
+void ordered_work_init(struct ordered_work *, unsigned, size_t,
+		       ordered_work_fn, ordered_work_fn, void *);
+void *ordered_work_get(struct ordered_work *);
+void ordered_work_submit(struct ordered_work *);
+void ordered_work_flush(struct ordered_work *);
+void ordered_work_exit(struct ordered_work *);
+
+unsigned nr_cpus(void);
+
+void tools_thread_init(void);
+void tools_thread_exit(void);
+
+/* bcachefs --units: overrides the units commands print sizes in */
+enum tools_units {
+	TOOLS_UNITS_default,
+	TOOLS_UNITS_bytes,
+	TOOLS_UNITS_KiB,
+	TOOLS_UNITS_MiB,
+	TOOLS_UNITS_GiB,
+	TOOLS_UNITS_TiB,
+	TOOLS_UNITS_human,
+	TOOLS_UNITS_si,
+};
+
+extern enum tools_units tools_units;
+int tools_units_parse(const char *);
+void tools_printbuf_units(struct printbuf *, bool);
+void tools_prt_units_u64(struct printbuf *, u64);
+void tools_prt_units_s64(struct printbuf *, s64);
+
 #endif /* _TOOLS_UTIL_H */
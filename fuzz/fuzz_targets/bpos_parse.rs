@@ -0,0 +1,26 @@
+//! bpos parser, as used for `list --start/--end`: anything it accepts must
+//! print back to something that parses to the same position
+
+#![no_main]
+
+use bch_bindgen::{bpos_to_string, c::bpos};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let Ok(p) = s.parse::<bpos>() else {
+        return;
+    };
+
+    let printed = bpos_to_string(p);
+    let reparsed: bpos = printed.parse().expect("printed bpos doesn't parse");
+
+    assert_eq!(
+        (p.inode, p.offset, p.snapshot),
+        (reparsed.inode, reparsed.offset, reparsed.snapshot),
+        "{s:?} printed as {printed:?}"
+    );
+});
@@ -0,0 +1,22 @@
+//! Mount option splitting: flags must never leak through as filesystem
+//! options, and splitting must be idempotent
+
+#![no_main]
+
+use bcachefs::mount::parse_mount_options;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let (opts, _flags) = parse_mount_options(s);
+
+    if let Some(opts) = opts {
+        let (opts2, flags2) = parse_mount_options(&opts);
+
+        assert_eq!(opts2.as_deref(), Some(opts.as_str()));
+        assert_eq!(flags2, 0, "{opts:?} contains mount flags");
+    }
+});
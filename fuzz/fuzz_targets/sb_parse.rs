@@ -0,0 +1,56 @@
+//! Superblock parser: must never panic or read out of bounds, whatever the
+//! input; exercises every accessor so offsets past the header are checked too
+
+#![no_main]
+
+use bch_bindgen::sb_parse::{SbLayout, Superblock};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(layout) = SbLayout::parse(data) {
+        let _ = layout.sb_offsets().count();
+    }
+
+    let Ok(sb) = Superblock::parse(data) else {
+        return;
+    };
+
+    assert!(sb.bytes() <= data.len());
+
+    let _ = (
+        sb.version(),
+        sb.version_min(),
+        sb.label(),
+        sb.seq(),
+        sb.dev_idx(),
+    );
+    let _ = sb.layout().sb_offsets().count();
+
+    for f in sb.fields().flatten() {
+        assert!(f.data().len() <= f.bytes().len());
+    }
+
+    if let Ok(Some(members)) = sb.members() {
+        for m in members {
+            let _ = (m.uuid(), m.nbuckets(), m.durability(), m.errors(), m.seq());
+        }
+    }
+
+    if let Ok(Some(crypt)) = sb.crypt() {
+        let _ = (crypt.scrypt_params(), crypt.encrypted_key());
+    }
+
+    if let Some(replicas) = sb.replicas() {
+        for r in replicas {
+            let _ = (r.data_type(), r.nr_required(), r.devs());
+        }
+    }
+
+    if let Ok(Some(clean)) = sb.clean_section() {
+        let _ = (clean.journal_seq(), clean.entries());
+    }
+
+    if let Some(counters) = sb.counters() {
+        let _ = counters.count();
+    }
+});
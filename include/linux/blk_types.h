@@ -62,6 +62,7 @@ struct block_device {
 	struct gendisk *	bd_disk;
 	struct gendisk		__bd_disk;
 	int			bd_fd;
+	struct bdev_overlay	*bd_overlay;
 };
 
 #define bdev_kobj(_bdev) (&((_bdev)->kobj))
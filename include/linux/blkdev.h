@@ -78,6 +78,9 @@ struct file *bdev_file_open_by_path(const char *, blk_mode_t, void *,
 				    const struct blk_holder_ops *);
 int lookup_bdev(const char *path, dev_t *);
 
+void blkdev_overlay_enable(const char *);
+int blkdev_fault_inject_add(const char *);
+
 struct super_block {
 	void			*s_fs_info;
 };
@@ -32,4 +32,7 @@ int shrinker_register(struct shrinker *);
 
 void run_shrinkers(gfp_t gfp_mask, bool);
 
+/* Shrink caches to keep resident memory under @bytes; 0 for no limit */
+void shrinker_memory_limit_set(u64);
+
 #endif /* __TOOLS_LINUX_SHRINKER_H */
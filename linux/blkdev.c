@@ -2,6 +2,8 @@
 #include <alloca.h>
 #include <errno.h>
 #include <fcntl.h>
+#include <pthread.h>
+#include <sys/mman.h>
 #include <sys/ioctl.h>
 #include <sys/stat.h>
 #include <sys/types.h>
@@ -33,6 +35,284 @@ static struct fops *fops;
 static io_context_t aio_ctx;
 static atomic_t running_requests;
 
+/*
+ * Copy on write overlays, for bcachefs --image: devices are opened read only,
+ * and writes go to a sparse file (or a memfd) the size of the device. Which
+ * parts of the device have been written is tracked by which parts of the
+ * overlay are allocated (SEEK_DATA), so an overlay file can be reused by
+ * later runs.
+ */
+struct bdev_overlay {
+	int			fd;
+	unsigned		block_size;
+	void			*buf;
+	pthread_mutex_t		lock;
+};
+
+static bool overlay_enabled;
+static char *overlay_dir;
+
+static struct bdev_overlay *overlay_open(const char *path, int base_fd)
+{
+	struct bdev_overlay *o = xmalloc(sizeof(*o));
+	u64 size = get_size(base_fd);
+
+	if (overlay_dir) {
+		char *name = strdup(path + strspn(path, "/"));
+		for (char *p = name; *p; p++)
+			if (*p == '/')
+				*p = '_';
+
+		char *overlay = mprintf("%s/%s.overlay", overlay_dir, name);
+		o->fd = open(overlay, O_RDWR|O_CREAT, 0600);
+		if (o->fd < 0)
+			die("error opening overlay %s: %m", overlay);
+		free(overlay);
+		free(name);
+	} else {
+		o->fd = memfd_create("bcachefs-overlay", 0);
+		if (o->fd < 0)
+			die("error creating in memory overlay: %m");
+	}
+
+	if (xfstat(o->fd).st_size != size &&
+	    ftruncate(o->fd, size))
+		die("error resizing overlay for %s: %m", path);
+
+	o->block_size = max_t(unsigned, xfstat(o->fd).st_blksize, 4096);
+	if (posix_memalign(&o->buf, o->block_size, o->block_size))
+		die("memory allocation failure");
+	pthread_mutex_init(&o->lock, NULL);
+	return o;
+}
+
+static void overlay_close(struct bdev_overlay *o)
+{
+	close(o->fd);
+	free(o->buf);
+	free(o);
+}
+
+static size_t iov_bytes(struct iovec *iov, unsigned nr)
+{
+	size_t ret = 0;
+
+	for (unsigned i = 0; i < nr; i++)
+		ret += iov[i].iov_len;
+	return ret;
+}
+
+/* Read @len bytes at @offset from @fd into the iovec, starting @skip bytes in */
+static int iov_pread(int fd, struct iovec *iov, unsigned nr,
+		     size_t skip, size_t len, off_t offset)
+{
+	for (unsigned i = 0; i < nr && len; i++) {
+		if (skip >= iov[i].iov_len) {
+			skip -= iov[i].iov_len;
+			continue;
+		}
+
+		size_t b = min(iov[i].iov_len - skip, len);
+		if (pread(fd, iov[i].iov_base + skip, b, offset) != b)
+			return -EIO;
+
+		offset	+= b;
+		len	-= b;
+		skip	= 0;
+	}
+
+	return 0;
+}
+
+static ssize_t overlay_preadv(struct block_device *bdev, struct iovec *iov,
+			      unsigned nr, off_t offset)
+{
+	struct bdev_overlay *o = bdev->bd_overlay;
+	size_t len = iov_bytes(iov, nr);
+	off_t end = offset + len, pos = offset;
+
+	ssize_t ret = preadv(bdev->bd_fd, iov, nr, offset);
+	if (ret != len)
+		return ret;
+
+	pthread_mutex_lock(&o->lock);
+	while (pos < end) {
+		off_t data = lseek(o->fd, pos, SEEK_DATA);
+		if (data < 0 || data >= end)
+			break;
+
+		off_t hole = min(lseek(o->fd, data, SEEK_HOLE), end);
+
+		if (iov_pread(o->fd, iov, nr, data - offset, hole - data, data)) {
+			ret = -EIO;
+			break;
+		}
+		pos = hole;
+	}
+	pthread_mutex_unlock(&o->lock);
+
+	return ret;
+}
+
+/*
+ * A write that doesn't cover a whole overlay block: fill in the rest from the
+ * device first, or reads of the rest would see zeroes
+ */
+static int overlay_copy_up(struct block_device *bdev, off_t block)
+{
+	struct bdev_overlay *o = bdev->bd_overlay;
+
+	if (lseek(o->fd, block, SEEK_DATA) == block)
+		return 0;
+
+	ssize_t r = pread(bdev->bd_fd, o->buf, o->block_size, block);
+	if (r < 0)
+		return -errno;
+	memset(o->buf + r, 0, o->block_size - r);
+
+	return pwrite(o->fd, o->buf, o->block_size, block) == o->block_size ? 0 : -EIO;
+}
+
+static ssize_t overlay_pwritev(struct block_device *bdev, struct iovec *iov,
+			       unsigned nr, off_t offset)
+{
+	struct bdev_overlay *o = bdev->bd_overlay;
+	size_t len = iov_bytes(iov, nr);
+	off_t end = offset + len;
+	ssize_t ret = 0;
+
+	pthread_mutex_lock(&o->lock);
+	if (offset & (o->block_size - 1))
+		ret = overlay_copy_up(bdev, round_down(offset, o->block_size));
+	if (!ret && (end & (o->block_size - 1)))
+		ret = overlay_copy_up(bdev, round_down(end - 1, o->block_size));
+	if (!ret)
+		ret = pwritev(o->fd, iov, nr, offset);
+	pthread_mutex_unlock(&o->lock);
+
+	return ret;
+}
+
+/*
+ * Fault injection, for bcachefs --fault-inject: IO to a range of a device fails,
+ * is delayed, or both, so that error paths can be tested deterministically
+ */
+struct bdev_fault {
+	char			*dev;
+	u64			start;
+	u64			end;
+	bool			reads;
+	bool			writes;
+	bool			error;
+	unsigned		delay_ms;
+};
+
+static DARRAY(struct bdev_fault) bdev_faults;
+
+/*
+ * @spec is [device:]offset[+len][,eio][,delay=ms][,reads][,writes]: offset and
+ * len are in bytes, with optional units; len defaults to the rest of the device.
+ * Faults apply to reads unless writes is given, and fail with -EIO unless only
+ * a delay is given.
+ */
+int blkdev_fault_inject_add(const char *spec)
+{
+	struct bdev_fault f = { .end = U64_MAX };
+	char *s = strdup(spec), *opts = s, *range = strsep(&opts, ","), *p, *opt;
+	int ret = -EINVAL;
+
+	p = strrchr(range, ':');
+	if (p) {
+		*p = '\0';
+		f.dev = strdup(range);
+		range = p + 1;
+	}
+
+	p = strchr(range, '+');
+	if (p) {
+		u64 len;
+
+		*p++ = '\0';
+		if (bch2_strtou64_h(p, &len) || !len)
+			goto err;
+		f.end = len;
+	}
+
+	if (bch2_strtou64_h(range, &f.start))
+		goto err;
+
+	if (f.end != U64_MAX)
+		f.end += f.start;
+
+	while ((opt = strsep(&opts, ","))) {
+		if (!strcmp(opt, "eio"))
+			f.error = true;
+		else if (!strcmp(opt, "reads"))
+			f.reads = true;
+		else if (!strcmp(opt, "writes"))
+			f.writes = true;
+		else if (!strncmp(opt, "delay=", 6)) {
+			if (kstrtouint(opt + 6, 10, &f.delay_ms))
+				goto err;
+		} else
+			goto err;
+	}
+
+	if (!f.reads && !f.writes)
+		f.reads = true;
+	if (!f.delay_ms)
+		f.error = true;
+
+	if (darray_push(&bdev_faults, f))
+		die("memory allocation failure");
+	f.dev = NULL;
+	ret = 0;
+err:
+	free(f.dev);
+	free(s);
+	return ret;
+}
+
+static bool bdev_fault_dev_matches(struct bdev_fault *f, struct block_device *bdev)
+{
+	if (!f->dev || !strcmp(f->dev, bdev->name))
+		return true;
+
+	const char *name = strrchr(bdev->name, '/');
+	return name && !strcmp(f->dev, name + 1);
+}
+
+/* Returns true if the bio was failed, and has been completed */
+static bool bio_fault_inject(struct bio *bio)
+{
+	bool write = bio_op(bio) == REQ_OP_WRITE;
+	u64 start = bio->bi_iter.bi_sector << 9;
+	u64 end = start + bio->bi_iter.bi_size;
+	unsigned delay_ms = 0;
+	bool error = false;
+
+	if (bio_op(bio) != REQ_OP_READ && !write)
+		return false;
+
+	darray_for_each(bdev_faults, f)
+		if ((write ? f->writes : f->reads) &&
+		    start < f->end && end > f->start &&
+		    bdev_fault_dev_matches(f, bio->bi_bdev)) {
+			error |= f->error;
+			delay_ms = max(delay_ms, f->delay_ms);
+		}
+
+	if (delay_ms)
+		usleep(delay_ms * 1000);
+
+	if (!error)
+		return false;
+
+	bio->bi_status = BLK_STS_IOERR;
+	bio_endio(bio);
+	return true;
+}
+
 void generic_make_request(struct bio *bio)
 {
 	struct iovec *iov;
@@ -41,6 +321,9 @@ void generic_make_request(struct bio *bio)
 	ssize_t ret;
 	unsigned i;
 
+	if (bdev_faults.nr && bio_fault_inject(bio))
+		return;
+
 	if (bio->bi_opf & REQ_PREFLUSH) {
 		ret = fdatasync(bio->bi_bdev->bd_fd);
 		if (ret) {
@@ -166,6 +449,9 @@ void bdev_fput(struct file *file)
 {
 	struct block_device *bdev = file_bdev(file);
 
+	if (bdev->bd_overlay)
+		overlay_close(bdev->bd_overlay);
+
 	fdatasync(bdev->bd_fd);
 	close(bdev->bd_fd);
 	free(bdev);
@@ -190,7 +476,23 @@ struct file *bdev_file_open_by_path(const char *path, blk_mode_t mode,
 	if (mode & BLK_OPEN_EXCL)
 		flags |= O_EXCL;
 
+	/* writes go to the overlay; the device itself is never written to */
+	if (overlay_enabled)
+		flags = (flags & ~(O_RDWR|O_WRONLY))|O_RDONLY;
+
 	fd = open(path, flags);
+
+	/*
+	 * Image files may be on a filesystem without O_DIRECT support (tmpfs,
+	 * some FUSE filesystems): fall back to buffered IO for those
+	 */
+	if (fd < 0 && errno == EINVAL && (flags & O_DIRECT)) {
+		struct stat st;
+
+		if (!stat(path, &st) && S_ISREG(st.st_mode))
+			fd = open(path, flags & ~O_DIRECT);
+	}
+
 	if (fd < 0)
 		return ERR_PTR(-errno);
 
@@ -208,6 +510,9 @@ struct file *bdev_file_open_by_path(const char *path, blk_mode_t mode,
 	bdev->queue.backing_dev_info = bdev->bd_disk->bdi;
 	bdev->bd_inode		= &bdev->__bd_inode;
 
+	if (overlay_enabled)
+		bdev->bd_overlay = overlay_open(path, fd);
+
 	struct file *file = calloc(sizeof(*file), 1);
 	file->f_inode = bdev->bd_inode;
 
@@ -252,16 +557,20 @@ static void sync_cleanup(void)
 static void sync_read(struct bio *bio, struct iovec * iov, unsigned i)
 {
 
-	ssize_t ret = preadv(bio->bi_bdev->bd_fd, iov, i,
-			     bio->bi_iter.bi_sector << 9);
+	ssize_t ret = bio->bi_bdev->bd_overlay
+		? overlay_preadv(bio->bi_bdev, iov, i, bio->bi_iter.bi_sector << 9)
+		: preadv(bio->bi_bdev->bd_fd, iov, i,
+			 bio->bi_iter.bi_sector << 9);
 	sync_check(bio, ret);
 }
 
 static void sync_write(struct bio *bio, struct iovec * iov, unsigned i)
 {
-	ssize_t ret = pwritev2(bio->bi_bdev->bd_fd, iov, i,
-			       bio->bi_iter.bi_sector << 9,
-			       bio->bi_opf & REQ_FUA ? RWF_SYNC : 0);
+	ssize_t ret = bio->bi_bdev->bd_overlay
+		? overlay_pwritev(bio->bi_bdev, iov, i, bio->bi_iter.bi_sector << 9)
+		: pwritev2(bio->bi_bdev->bd_fd, iov, i,
+			   bio->bi_iter.bi_sector << 9,
+			   bio->bi_opf & REQ_FUA ? RWF_SYNC : 0);
 	sync_check(bio, ret);
 }
 
@@ -415,6 +724,25 @@ struct fops fops_list[] = {
 	}
 };
 
+/*
+ * Must be called before any devices are opened; @dir is where to keep overlay
+ * files, or NULL to keep them in memory
+ */
+void blkdev_overlay_enable(const char *dir)
+{
+	struct fops *sync_fops = fops_list + ARRAY_SIZE(fops_list) - 2;
+
+	overlay_enabled = true;
+	overlay_dir = dir ? strdup(dir) : NULL;
+
+	/* overlay IO is only implemented for the synchronous path: */
+	if (fops != sync_fops) {
+		fops->cleanup();
+		fops = sync_fops;
+		fops->init();
+	}
+}
+
 __attribute__((constructor(102)))
 static void blkdev_init(void)
 {
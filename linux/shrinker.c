@@ -12,6 +12,26 @@
 
 static LIST_HEAD(shrinker_list);
 static DEFINE_MUTEX(shrinker_lock);
+static u64 memory_limit;
+
+void shrinker_memory_limit_set(u64 bytes)
+{
+	memory_limit = bytes;
+}
+
+static u64 rss_bytes(void)
+{
+	FILE *f = fopen("/proc/self/statm", "r");
+	unsigned long size, resident = 0;
+
+	if (f) {
+		if (fscanf(f, "%lu %lu", &size, &resident) != 2)
+			resident = 0;
+		fclose(f);
+	}
+
+	return (u64) resident * sysconf(_SC_PAGESIZE);
+}
 
 void shrinker_free(struct shrinker *s)
 {
@@ -76,6 +96,11 @@ void run_shrinkers(gfp_t gfp_mask, bool allocation_failed)
 	/* Aim for 6% of physical RAM free without anything in swap */
 	want_shrink = (info.totalram >> 4) - info.freeram
 			+ info.totalswap - info.freeswap;
+
+	/* And for 6% under the memory limit, if there is one: */
+	if (memory_limit)
+		want_shrink = max_t(s64, want_shrink,
+				    rss_bytes() - memory_limit + (memory_limit >> 4));
 	if (want_shrink <= 0)
 		return;
 
@@ -98,7 +123,14 @@ static int shrinker_thread(void *arg)
 		int v;
 
 		clock_gettime(CLOCK_MONOTONIC, &to);
-		to.tv_sec += 1;
+		/* With a memory limit, check more often: */
+		if (memory_limit) {
+			to.tv_nsec += NSEC_PER_SEC / 10;
+			to.tv_sec  += to.tv_nsec / NSEC_PER_SEC;
+			to.tv_nsec %= NSEC_PER_SEC;
+		} else {
+			to.tv_sec += 1;
+		}
 		__set_current_state(TASK_INTERRUPTIBLE);
 		errno = 0;
 		while ((v = READ_ONCE(current->state)) != TASK_RUNNING &&
@@ -1,5 +1,4 @@
 mod commands;
-mod key;
 mod wrappers;
 
 use std::ffi::{c_char, CString};
@@ -7,22 +6,17 @@ use std::ffi::{c_char, CString};
 use bch_bindgen::c;
 use commands::logger::SimpleLogger;
 
-#[derive(Debug)]
-pub struct ErrnoError(pub errno::Errno);
-impl std::fmt::Display for ErrnoError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        self.0.fmt(f)
-    }
-}
-
-impl std::error::Error for ErrnoError {}
-
 fn handle_c_command(mut argv: Vec<String>, symlink_cmd: Option<&str>) -> i32 {
     let cmd = match symlink_cmd {
         Some(s) => s.to_string(),
         None => argv.remove(1),
     };
 
+    if let Err(e) = commands::targets::check_c_command(&cmd, &mut argv[1..]) {
+        eprintln!("{e}");
+        return 1;
+    }
+
     let argc: i32 = argv.len().try_into().unwrap();
 
     let argv: Vec<_> = argv.into_iter().map(|s| CString::new(s).unwrap()).collect();
@@ -39,25 +33,45 @@ fn handle_c_command(mut argv: Vec<String>, symlink_cmd: Option<&str>) -> i32 {
                 c::bcachefs_usage();
                 0
             }
+            "attr" => c::attr_cmds(argc, argv),
+            "bench" => c::cmd_bench(argc, argv),
+            "cache" => c::cache_cmds(argc, argv),
+            "check-nodes" => c::cmd_check_nodes(argc, argv),
+            "compat" => c::cmd_compat(argc, argv),
+            "cp" => c::cmd_cp(argc, argv),
             "data" => c::data_cmds(argc, argv),
+            "dedupe" => c::cmd_dedupe(argc, argv),
             "device" => c::device_cmds(argc, argv),
             "dump" => c::cmd_dump(argc, argv),
+            "ec" => c::ec_cmds(argc, argv),
             "format" => c::cmd_format(argc, argv),
             "fs" => c::fs_cmds(argc, argv),
             "fsck" => c::cmd_fsck(argc, argv),
-            "list_journal" => c::cmd_list_journal(argc, argv),
+            "getattr" => c::cmd_getattr(argc, argv),
+            "journal" => c::journal_cmds(argc, argv),
+            "journal-stats" => c::cmd_journal_stats(argc, argv),
             "kill_btree_node" => c::cmd_kill_btree_node(argc, argv),
+            "label" => c::label_cmds(argc, argv),
+            "lock" => c::cmd_lock(argc, argv),
             "migrate" => c::cmd_migrate(argc, argv),
             "migrate-superblock" => c::cmd_migrate_superblock(argc, argv),
             "mkfs" => c::cmd_format(argc, argv),
+            "nocow" => c::nocow_cmds(argc, argv),
+            "quota" => c::quota_cmds(argc, argv),
             "remove-passphrase" => c::cmd_remove_passphrase(argc, argv),
             "reset-counters" => c::cmd_reset_counters(argc, argv),
+            "salvage" => c::cmd_salvage(argc, argv),
             "set-option" => c::cmd_set_option(argc, argv),
             "set-passphrase" => c::cmd_set_passphrase(argc, argv),
             "setattr" => c::cmd_setattr(argc, argv),
             "show-super" => c::cmd_show_super(argc, argv),
+            "status" => c::cmd_status(argc, argv),
+            "strip-alloc" => c::cmd_strip_alloc(argc, argv),
+            "undelete" => c::cmd_undelete(argc, argv),
             "unlock" => c::cmd_unlock(argc, argv),
+            "verify" => c::cmd_verify(argc, argv),
             "version" => c::cmd_version(argc, argv),
+            "version-upgrade" => c::cmd_version_upgrade(argc, argv),
 
             #[cfg(fuse)]
             "fusemount" => c::cmd_fusemount(argc, argv),
@@ -71,8 +85,70 @@ fn handle_c_command(mut argv: Vec<String>, symlink_cmd: Option<&str>) -> i32 {
     }
 }
 
+/// Global options, between "bcachefs" and the command: --image[=dir], for
+/// running commands against a copy on write overlay of their devices,
+/// --units=UNITS, for the units sizes are printed in, and
+/// --fault-inject=SPEC, for testing error paths by failing or delaying IO to
+/// part of a device
+///
+/// Returns true if --image was given.
+fn global_opts(args: &mut Vec<String>) -> bool {
+    let mut image = false;
+
+    while let Some(arg) = args.get(1).cloned() {
+        if let Some(spec) = arg.strip_prefix("--fault-inject") {
+            let spec = match spec.strip_prefix('=') {
+                Some(spec) => spec.to_string(),
+                None if spec.is_empty() && args.len() > 2 => args.remove(2),
+                None => break,
+            };
+
+            let spec = CString::new(spec).unwrap();
+            if unsafe { c::blkdev_fault_inject_add(spec.as_ptr()) } != 0 {
+                eprintln!(
+                    "invalid fault injection {spec:?}: must be \
+                     [device:]offset[+len][,eio][,delay=ms][,reads][,writes]"
+                );
+                std::process::exit(1);
+            }
+            args.remove(1);
+            continue;
+        }
+
+        if let Some(units) = arg.strip_prefix("--units") {
+            let units = match units.strip_prefix('=') {
+                Some(units) => units.to_string(),
+                None if units.is_empty() && args.len() > 2 => args.remove(2),
+                None => break,
+            };
+
+            let units = CString::new(units).unwrap();
+            if unsafe { c::tools_units_parse(units.as_ptr()) } != 0 {
+                eprintln!(
+                    "invalid units {units:?}: must be one of B, KiB, MiB, GiB, TiB, human, si"
+                );
+                std::process::exit(1);
+            }
+            args.remove(1);
+            continue;
+        }
+
+        let dir = match arg.strip_prefix("--image") {
+            Some("") => None,
+            Some(dir) if dir.starts_with('=') => Some(CString::new(&dir[1..]).unwrap()),
+            _ => break,
+        };
+
+        unsafe { c::blkdev_overlay_enable(dir.as_ref().map_or(std::ptr::null(), |d| d.as_ptr())) };
+        args.remove(1);
+        image = true;
+    }
+
+    image
+}
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
 
     let symlink_cmd: Option<&str> = if args[0].contains("mkfs") {
         Some("mkfs")
@@ -86,6 +162,8 @@ fn main() {
         None
     };
 
+    let image = symlink_cmd.is_none() && global_opts(&mut args);
+
     if symlink_cmd.is_none() && args.len() < 2 {
         println!("missing command");
         unsafe { c::bcachefs_usage() };
@@ -102,15 +180,36 @@ fn main() {
         None => args[1].as_str(),
     };
 
+    // Commands run against an --image overlay don't change the filesystem:
+    if !image {
+        let cmdline: Vec<String> = match symlink_cmd {
+            Some(s) => std::iter::once(s.to_owned())
+                .chain(args[1..].iter().cloned())
+                .collect(),
+            None => args[1..].to_vec(),
+        };
+        commands::history::start(&cmdline);
+    }
+
     let ret = match cmd {
         "completions" => commands::completions(args[1..].to_vec()),
+        "exporter" => commands::exporter(args[1..].to_vec()),
+        "history" => commands::history(args[1..].to_vec()),
+        "initramfs" => commands::initramfs(args[1..].to_vec()),
         "list" => commands::list(args[1..].to_vec()),
+        "list_journal" => commands::list_journal(args[1..].to_vec()),
         "mount" => commands::mount(args, symlink_cmd),
+        "options" => commands::options(args[1..].to_vec()),
+        "probe" => commands::probe(args[1..].to_vec()),
         "subvolume" => commands::subvolume(args[1..].to_vec()),
+        "test" => commands::test(args[1..].to_vec()),
         _ => handle_c_command(args, symlink_cmd),
     };
 
+    commands::history::finish(ret);
+
+    // Pass on commands' exit codes, but not negative error codes from C:
     if ret != 0 {
-        std::process::exit(1);
+        std::process::exit(if (1..=255).contains(&ret) { ret } else { 1 });
     }
 }
@@ -1,19 +1,346 @@
-use clap::{Command, CommandFactory, Parser};
+use std::{collections::BTreeSet, io};
+
+use bcachefs::device;
+use clap::{Arg, Command, CommandFactory, Parser, ValueEnum, ValueHint};
 use clap_complete::{generate, Generator, Shell};
-use std::io;
+
+/// A command implemented in C, which clap doesn't know about: we only complete
+/// their names, and paths for their arguments
+struct CCommand {
+    name:        &'static str,
+    about:       &'static str,
+    subcommands: &'static [CCommand],
+}
+
+const fn cmd(name: &'static str, about: &'static str) -> CCommand {
+    CCommand {
+        name,
+        about,
+        subcommands: &[],
+    }
+}
+
+const fn group(
+    name: &'static str,
+    about: &'static str,
+    subcommands: &'static [CCommand],
+) -> CCommand {
+    CCommand {
+        name,
+        about,
+        subcommands,
+    }
+}
+
+const C_COMMANDS: &[CCommand] = &[
+    cmd("format", "Format a new filesystem"),
+    cmd("show-super", "Dump superblock information to stdout"),
+    cmd("set-option", "Set a filesystem option"),
+    cmd(
+        "reset-counters",
+        "Reset all counters on an unmounted device",
+    ),
+    cmd(
+        "compat",
+        "Check the filesystem version against the kernel and tools",
+    ),
+    cmd(
+        "version-upgrade",
+        "Upgrade or downgrade the on disk version, offline",
+    ),
+    cmd("fsck", "Check an existing filesystem for errors"),
+    cmd(
+        "salvage",
+        "Copy out as much as possible of a damaged filesystem",
+    ),
+    cmd(
+        "strip-alloc",
+        "Drop and rebuild allocation information, offline",
+    ),
+    cmd(
+        "undelete",
+        "Restore recently deleted files, from the journal",
+    ),
+    cmd(
+        "history",
+        "Show the destructive commands run on a filesystem",
+    ),
+    group(
+        "fs",
+        "Manage a running filesystem",
+        &[
+            cmd("usage", "Show disk usage"),
+            cmd(
+                "accounting",
+                "Show usage by replica set and compression type",
+            ),
+            cmd(
+                "audit-options",
+                "Find files not matching their directory's options",
+            ),
+            cmd("top-files", "List the files using the most space"),
+            cmd("du", "Show directory sizes, as a list, tree or browser"),
+            cmd("orphans", "List and delete unlinked inodes"),
+            cmd("resize", "Resize the devices of a mounted filesystem"),
+            cmd("latency", "Show latency statistics"),
+            cmd("counters", "Show event counters, or their rates"),
+            cmd("gc", "Run garbage collection, and report buckets freed"),
+        ],
+    ),
+    cmd("status", "Summarize filesystem health"),
+    group(
+        "device",
+        "Manage devices within a running filesystem",
+        &[
+            cmd("add", "Add a new device to an existing filesystem"),
+            cmd("remove", "Remove a device from an existing filesystem"),
+            cmd("online", "Re-add an existing member to a filesystem"),
+            cmd("offline", "Take a device offline, without removing it"),
+            cmd("evacuate", "Migrate data off of a specific device"),
+            cmd("replace", "Replace a device with a new one"),
+            cmd("set-state", "Mark a device as failed"),
+            cmd("set-label", "Set a device's label"),
+            cmd("resize", "Resize filesystem on a device"),
+            cmd("resize-journal", "Resize journal on a device"),
+            cmd("locate", "Find the disk a member device is on"),
+            cmd("scan", "Check a device for unreadable data"),
+            cmd("trim", "Discard free buckets"),
+        ],
+    ),
+    group(
+        "label",
+        "Manage the device label tree",
+        &[
+            cmd("list", "Show device labels, and the targets using them"),
+            cmd("rename", "Rename or move a device label"),
+        ],
+    ),
+    group(
+        "journal",
+        "Manage the journal",
+        &[
+            cmd("resize", "Grow the journal on a device"),
+            cmd("move", "Move the journal to another device"),
+        ],
+    ),
+    group(
+        "data",
+        "Manage filesystem data",
+        &[
+            cmd("rereplicate", "Rereplicate degraded data"),
+            cmd("rewrite", "Change the checksum or compression type of data"),
+            cmd("job", "Kick off low level data jobs"),
+        ],
+    ),
+    group(
+        "ec",
+        "Inspect and repair erasure coded stripes",
+        &[
+            cmd("status", "Show erasure coding status and degraded stripes"),
+            cmd("repair", "Reconstruct data in degraded stripes"),
+        ],
+    ),
+    group(
+        "quota",
+        "Manage user, group and project quotas",
+        &[
+            cmd("show", "Show quota usage and limits"),
+            cmd("set", "Set quota limits"),
+            cmd("project", "Set project IDs, recursively"),
+            cmd("rescan", "Recount quota usage and check it matches"),
+        ],
+    ),
+    cmd(
+        "unlock",
+        "Unlock an encrypted filesystem prior to running/mounting",
+    ),
+    cmd(
+        "lock",
+        "Remove an unmounted filesystem's key from the keyring",
+    ),
+    cmd(
+        "set-passphrase",
+        "Change passphrase on an existing (unmounted) filesystem",
+    ),
+    cmd(
+        "remove-passphrase",
+        "Remove passphrase on an existing (unmounted) filesystem",
+    ),
+    cmd(
+        "migrate",
+        "Migrate an existing filesystem to bcachefs, in place",
+    ),
+    cmd(
+        "migrate-superblock",
+        "Add default superblock, after bcachefs migrate",
+    ),
+    cmd("setattr", "Set various per file attributes"),
+    cmd("getattr", "Show per file attributes"),
+    group(
+        "attr",
+        "Get and set per file options",
+        &[
+            cmd("get", "Show options set on files, as name=value"),
+            cmd("set", "Set or remove options on files, after checking them"),
+        ],
+    ),
+    group(
+        "nocow",
+        "Manage and check nocow mode",
+        &[
+            cmd("set", "Set or clear nocow mode on files"),
+            cmd("status", "Check if nocow data can be written in place"),
+        ],
+    ),
+    group(
+        "cache",
+        "Manage cached data on tiered filesystems",
+        &[
+            cmd("drop", "Drop cached copies of data from the promote target"),
+            cmd("pin", "Keep files' data on the promote target"),
+        ],
+    ),
+    cmd("cp", "Copy files, sharing data with reflink"),
+    cmd("dedupe", "Deduplicate identical data"),
+    cmd("dump", "Dump filesystem metadata to a qcow2 image"),
+    cmd("list_journal", "List contents of journal"),
+    cmd("journal-stats", "Print statistics about the journal"),
+    cmd("kill_btree_node", "Make btree nodes unreadable"),
+    cmd("check-nodes", "Verify every replica of every btree node"),
+    cmd(
+        "verify",
+        "Verify the checksums of every replica of a file's data",
+    ),
+    cmd("fusemount", "Mount a filesystem via FUSE"),
+    cmd("bench", "Benchmark the userspace IO paths"),
+    cmd(
+        "version",
+        "Display the version of the invoked bcachefs tool",
+    ),
+];
 
 /// Generate shell completions
 #[derive(Parser, Debug)]
 pub struct Cli {
-    shell: Shell,
+    #[arg(required_unless_present = "list")]
+    shell: Option<Shell>,
+
+    /// Print candidates for dynamic completion, one per line; used by the
+    /// generated scripts
+    #[arg(long, hide = true, value_enum, conflicts_with = "shell")]
+    list: Option<Candidates>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Candidates {
+    /// bcachefs member devices
+    Devices,
+    /// bcachefs filesystem UUIDs
+    Uuids,
+}
+
+impl CCommand {
+    fn command(&self) -> Command {
+        let cmd = Command::new(self.name).about(self.about);
+
+        if self.subcommands.is_empty() {
+            cmd.arg(
+                Arg::new("args")
+                    .num_args(0..)
+                    .trailing_var_arg(true)
+                    .allow_hyphen_values(true)
+                    .value_hint(ValueHint::AnyPath),
+            )
+        } else {
+            cmd.subcommands(self.subcommands.iter().map(CCommand::command))
+        }
+    }
+}
+
+fn command() -> Command {
+    super::Cli::command().subcommands(C_COMMANDS.iter().map(CCommand::command))
+}
+
+/// Device arguments of `mount`, `unlock` and `lock` also complete to member devices
+/// and `UUID=<uuid>`, by calling back into `bcachefs completions --list`
+fn dynamic_completions(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => {
+            r#"
+_bcachefs_dynamic() {
+    local line="${COMP_LINE:0:COMP_POINT}"
+    local cur="${line##* }"
+    local words=( $line )
+    local prev="${words[${#words[@]}-1]}"
+    [[ -n "$cur" ]] && prev="${words[${#words[@]}-2]}"
+
+    if [[ "${words[1]}" =~ ^(mount|unlock|lock)$ && "$cur" != -* && "$prev" != -* ]]; then
+        local candidates="$(bcachefs completions --list devices) $(bcachefs completions --list uuids | sed 's/^/UUID=/')"
+        COMPREPLY=( $(compgen -W "$candidates" -- "$cur") )
+        # bash splits words on '=':
+        [[ "$cur" == *=* ]] && COMPREPLY=( "${COMPREPLY[@]#*=}" )
+        [[ ${#COMPREPLY[@]} -gt 0 ]] && return 0
+    fi
+
+    _bcachefs "$@"
+}
+
+complete -F _bcachefs_dynamic -o nosort -o bashdefault -o default bcachefs
+"#
+        }
+        Shell::Zsh => {
+            r#"
+_bcachefs_dynamic() {
+    if (( CURRENT > 2 )) && [[ $words[2] == (mount|unlock|lock) && $words[CURRENT] != -* && $words[CURRENT-1] != -* ]]; then
+        local -a candidates
+        candidates=( ${(f)"$(bcachefs completions --list devices)"} ${(f)"$(bcachefs completions --list uuids | sed 's/^/UUID=/')"} )
+        compadd -a candidates && return 0
+    fi
+
+    _bcachefs "$@"
+}
+
+compdef _bcachefs_dynamic bcachefs
+"#
+        }
+        Shell::Fish => {
+            r#"
+complete -c bcachefs -n "__fish_seen_subcommand_from mount unlock lock" -a "(bcachefs completions --list devices; bcachefs completions --list uuids | string replace -r '^' 'UUID=')"
+"#
+        }
+        _ => "",
+    }
 }
 
 fn print_completions<G: Generator>(gen: G, cmd: &mut Command) {
     generate(gen, cmd, cmd.get_name().to_string(), &mut io::stdout());
 }
 
+fn print_candidates(candidates: Candidates) {
+    // Completion shouldn't print errors: without udev, there's nothing to offer
+    let udev_info = device::udev_bcachefs_info().unwrap_or_default();
+
+    let is_dev = |k: &String| k.starts_with('/');
+    let matches: BTreeSet<_> = match candidates {
+        Candidates::Devices => udev_info.keys().filter(|k| is_dev(k)).collect(),
+        Candidates::Uuids => udev_info.keys().filter(|k| !is_dev(k)).collect(),
+    };
+
+    for m in matches {
+        println!("{}", m);
+    }
+}
+
 pub fn completions(argv: Vec<String>) -> i32 {
     let cli = Cli::parse_from(argv);
-    print_completions(cli.shell, &mut super::Cli::command());
+
+    if let Some(candidates) = cli.list {
+        print_candidates(candidates);
+        return 0;
+    }
+
+    let shell = cli.shell.unwrap();
+    print_completions(shell, &mut command());
+    print!("{}", dynamic_completions(shell));
     0
 }
@@ -0,0 +1,626 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    fmt::{self, Write as _},
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use log::{error, info};
+
+use super::logger::LogOpts;
+
+const SYSFS_BCACHEFS: &str = "/sys/fs/bcachefs";
+
+/// Only the request line is read, and it's never more than this
+const MAX_REQUEST_LINE: u64 = 8192;
+
+/// Serve metrics for mounted filesystems in Prometheus text format
+///
+/// Everything is read from sysfs on each scrape: usage, device state and IO
+/// errors, IO and latency stats, time_stats and persistent counters.
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Address to listen on, as [host]:port; ":port" listens on all
+    /// interfaces
+    #[arg(short, long, default_value = "127.0.0.1:9952")]
+    listen: String,
+
+    /// Print metrics to stdout once and exit, e.g. for node_exporter's
+    /// textfile collector
+    #[arg(long)]
+    once: bool,
+
+    #[command(flatten)]
+    log: LogOpts,
+}
+
+struct Family {
+    kind:    &'static str,
+    help:    &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+/// Metric families, in the order they're output
+#[derive(Default)]
+struct Metrics(BTreeMap<String, Family>);
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+impl Metrics {
+    fn add(
+        &mut self,
+        name: &str,
+        kind: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        v: f64,
+    ) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        self.0
+            .entry(format!("bcachefs_{}", name))
+            .or_insert(Family {
+                kind,
+                help,
+                samples: Vec::new(),
+            })
+            .samples
+            .push((labels, v));
+    }
+
+    fn gauge(&mut self, name: &str, help: &'static str, labels: &[(&str, &str)], v: f64) {
+        self.add(name, "gauge", help, labels, v)
+    }
+
+    fn counter(&mut self, name: &str, help: &'static str, labels: &[(&str, &str)], v: f64) {
+        self.add(name, "counter", help, labels, v)
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, family) in &self.0 {
+            writeln!(f, "# HELP {} {}", name, family.help)?;
+            writeln!(f, "# TYPE {} {}", name, family.kind)?;
+            for (labels, v) in &family.samples {
+                writeln!(f, "{}{{{}}} {}", name, labels, v)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr)).ok()
+}
+
+fn read_attr_u64(dir: &Path, attr: &str) -> Option<u64> {
+    read_attr(dir, attr)?.trim().parse().ok()
+}
+
+/// Parse a number as printed by `prt_human_readable_u64()`: `123`, `1.23k`,
+/// `4.56 MiB`
+fn parse_human_readable(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().ok()?;
+    let unit = unit.trim();
+
+    let base: f64 = if unit.contains('i') { 1024.0 } else { 1000.0 };
+    let exp = match unit.chars().next().map(|c| c.to_ascii_lowercase()) {
+        None | Some('b') => 0,
+        Some('k') => 1,
+        Some('m') => 2,
+        Some('g') => 3,
+        Some('t') => 4,
+        Some('p') => 5,
+        Some('e') => 6,
+        _ => return None,
+    };
+
+    Some(num * base.powi(exp))
+}
+
+/// Convert a duration as printed by `bch2_pr_time_units()` to seconds
+fn parse_time(num: &str, unit: &str) -> Option<f64> {
+    let num: f64 = num.parse().ok()?;
+    let ns: f64 = match unit {
+        "ns" => 1.0,
+        "us" => 1e3,
+        "ms" => 1e6,
+        "s" => 1e9,
+        "m" => 60e9,
+        "h" => 3600e9,
+        "d" => 86400e9,
+        "w" => 7.0 * 86400e9,
+        "y" => 365.25 * 86400e9,
+        _ => return None,
+    };
+
+    Some(num * ns / 1e9)
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct TimeStats {
+    count:     u64,
+    /// Durations of events, since mount: (min, max, mean, total) in seconds
+    durations: Vec<(&'static str, f64)>,
+}
+
+/// Parse the output of `bch2_time_stats_to_text()`: we only want the count,
+/// and the "since mount" durations
+fn parse_time_stats(s: &str) -> Option<TimeStats> {
+    let mut stats = TimeStats::default();
+
+    for line in s.lines().map(str::trim) {
+        if line.starts_with("time between events") {
+            break;
+        }
+
+        let mut tokens = line.split_whitespace();
+        let Some(name) = tokens.next() else { continue };
+
+        if name == "count:" {
+            stats.count = tokens.next()?.parse().ok()?;
+            continue;
+        }
+
+        let name = match name {
+            "min:" => "min",
+            "max:" => "max",
+            "mean:" => "mean",
+            "total:" => "total",
+            _ => continue,
+        };
+
+        if let (Some(num), Some(unit)) = (tokens.next(), tokens.next()) {
+            stats.durations.push((name, parse_time(num, unit)?));
+        }
+    }
+
+    Some(stats)
+}
+
+fn add_time_stats(
+    m: &mut Metrics,
+    name: &str,
+    help_count: &'static str,
+    help_duration: &'static str,
+    labels: &[(&str, &str)],
+    stats: &TimeStats,
+) {
+    m.counter(
+        &format!("{}_total", name),
+        help_count,
+        labels,
+        stats.count as f64,
+    );
+
+    for (stat, v) in &stats.durations {
+        let mut labels = labels.to_vec();
+        labels.push(("stat", stat));
+        m.gauge(&format!("{}_seconds", name), help_duration, &labels, *v);
+    }
+}
+
+/// Block devices (as st_rdev) of each bcachefs mount, to find mountpoints
+/// for filesystems in sysfs
+fn mounts() -> HashMap<u64, String> {
+    let Ok(mounts) = fs::read_to_string("/proc/self/mounts") else {
+        return HashMap::new();
+    };
+
+    let unescape = |s: &str| {
+        s.replace("\\040", " ")
+            .replace("\\011", "\t")
+            .replace("\\012", "\n")
+            .replace("\\134", "\\")
+    };
+
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let f: Vec<_> = line.split(' ').collect();
+            if f.len() < 3 || f[2] != "bcachefs" {
+                return None;
+            }
+
+            let dev = unescape(f[0]);
+            let dev = dev.split(':').next()?;
+            let rdev = fs::metadata(dev).ok()?.rdev();
+            Some((rdev, unescape(f[1])))
+        })
+        .collect()
+}
+
+fn dev_rdev(dev_dir: &Path) -> Option<u64> {
+    let s = read_attr(&dev_dir.join("block"), "dev")?;
+    let (major, minor) = s.trim().split_once(':')?;
+    Some(libc::makedev(major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn add_fs_usage(m: &mut Metrics, uuid: &str, mountpoint: &str) {
+    let Ok(path) = CString::new(mountpoint) else {
+        return;
+    };
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut st) } != 0 {
+        return;
+    }
+
+    let labels = [("uuid", uuid), ("mountpoint", mountpoint)];
+    let frsize = st.f_frsize as f64;
+
+    m.gauge(
+        "fs_size_bytes",
+        "Filesystem size",
+        &labels,
+        st.f_blocks as f64 * frsize,
+    );
+    m.gauge(
+        "fs_free_bytes",
+        "Free space",
+        &labels,
+        st.f_bfree as f64 * frsize,
+    );
+    m.gauge(
+        "fs_avail_bytes",
+        "Free space available to unprivileged users",
+        &labels,
+        st.f_bavail as f64 * frsize,
+    );
+    m.gauge("fs_inodes", "Inodes in use", &labels, st.f_files as f64);
+}
+
+fn add_dev(m: &mut Metrics, uuid: &str, dev_dir: &Path, idx: &str) {
+    let label = read_attr(dev_dir, "label").unwrap_or_default();
+    let state = read_attr(dev_dir, "state").unwrap_or_default();
+    let block = fs::read_link(dev_dir.join("block"))
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_default();
+    let labels = [("uuid", uuid), ("device", idx)];
+
+    m.gauge(
+        "device_info",
+        "Member device; value is always 1",
+        &[
+            ("uuid", uuid),
+            ("device", idx),
+            ("label", label.trim()),
+            ("state", state.trim()),
+            ("block", &block),
+        ],
+        1.0,
+    );
+
+    if let (Some(nbuckets), Some(bucket_size)) = (
+        read_attr_u64(dev_dir, "nbuckets"),
+        read_attr_u64(dev_dir, "bucket_size"),
+    ) {
+        m.gauge(
+            "device_capacity_bytes",
+            "Device capacity",
+            &labels,
+            (nbuckets * bucket_size) as f64,
+        );
+    }
+
+    // The first section is errors since filesystem creation, the second since
+    // the last reset:
+    if let Some(errors) = read_attr(dev_dir, "io_errors") {
+        for line in errors.lines().skip(1).take_while(|l| l.starts_with(' ')) {
+            if let Some((ty, nr)) = line.split_once(':') {
+                let Ok(nr) = nr.trim().parse::<u64>() else {
+                    continue;
+                };
+                m.counter(
+                    "device_io_errors_total",
+                    "IO errors since filesystem creation",
+                    &[("uuid", uuid), ("device", idx), ("type", ty.trim())],
+                    nr as f64,
+                );
+            }
+        }
+    }
+
+    if let Some(io_done) = read_attr(dev_dir, "io_done") {
+        let mut rw = "";
+        for line in io_done.lines() {
+            match line.split_once(':') {
+                Some((r, "")) => rw = r.trim(),
+                Some((ty, bytes)) => {
+                    let Ok(bytes) = bytes.trim().parse::<u64>() else {
+                        continue;
+                    };
+                    m.counter(
+                        "device_io_done_bytes_total",
+                        "Bytes of IO done since mount, by data type",
+                        &[
+                            ("uuid", uuid),
+                            ("device", idx),
+                            ("rw", rw),
+                            ("data_type", ty.trim()),
+                        ],
+                        bytes as f64,
+                    );
+                }
+                None => {}
+            }
+        }
+    }
+
+    for rw in ["read", "write"] {
+        let labels = [("uuid", uuid), ("device", idx), ("rw", rw)];
+
+        if let Some(ns) = read_attr_u64(dev_dir, &format!("io_latency_{}", rw)) {
+            m.gauge(
+                "device_io_latency_seconds",
+                "Moving average of IO latency",
+                &labels,
+                ns as f64 / 1e9,
+            );
+        }
+
+        if let Some(stats) = read_attr(dev_dir, &format!("io_latency_stats_{}", rw))
+            .and_then(|s| parse_time_stats(&s))
+        {
+            add_time_stats(
+                m,
+                "device_io",
+                "IOs completed since mount",
+                "IO latency since mount",
+                &labels,
+                &stats,
+            );
+        }
+    }
+}
+
+fn add_fs(m: &mut Metrics, fs_dir: &Path, uuid: &str, mounts: &HashMap<u64, String>) {
+    let labels = [("uuid", uuid)];
+    let mut mountpoint = None;
+
+    if let Some(flags) = read_attr(fs_dir, "internal/flags") {
+        for flag in flags.trim().split(',').filter(|f| !f.is_empty()) {
+            m.gauge(
+                "fs_flag",
+                "Filesystem state flags that are set",
+                &[("uuid", uuid), ("flag", flag)],
+                1.0,
+            );
+        }
+    }
+
+    if let Some(status) = read_attr(fs_dir, "internal/rebalance_status") {
+        let state = status.lines().next().unwrap_or_default().trim();
+        m.gauge(
+            "rebalance_state",
+            "Current state of the rebalance thread",
+            &[("uuid", uuid), ("state", state)],
+            1.0,
+        );
+    }
+
+    for (attr, name, help) in [
+        (
+            "internal/rebalance_enabled",
+            "rebalance_enabled",
+            "Rebalance is enabled",
+        ),
+        (
+            "internal/copy_gc_enabled",
+            "copygc_enabled",
+            "Copygc is enabled",
+        ),
+    ] {
+        if let Some(v) = read_attr_u64(fs_dir, attr) {
+            m.gauge(name, help, &labels, v as f64);
+        }
+    }
+
+    if let Ok(counters) = fs::read_dir(fs_dir.join("counters")) {
+        for c in counters.flatten() {
+            let name = c.file_name().to_string_lossy().into_owned();
+            let Some(v) = fs::read_to_string(c.path()).ok().and_then(|s| {
+                s.lines()
+                    .find_map(|l| l.strip_prefix("since filesystem creation:"))
+                    .and_then(parse_human_readable)
+            }) else {
+                continue;
+            };
+
+            m.counter(
+                "counter_total",
+                "Persistent counters, since filesystem creation",
+                &[("uuid", uuid), ("counter", &name)],
+                v,
+            );
+        }
+    }
+
+    if let Ok(time_stats) = fs::read_dir(fs_dir.join("time_stats")) {
+        for t in time_stats.flatten() {
+            let name = t.file_name().to_string_lossy().into_owned();
+            let Some(stats) = fs::read_to_string(t.path())
+                .ok()
+                .and_then(|s| parse_time_stats(&s))
+            else {
+                continue;
+            };
+
+            add_time_stats(
+                m,
+                "time_stats",
+                "Events since mount",
+                "Duration of events since mount",
+                &[("uuid", uuid), ("event", &name)],
+                &stats,
+            );
+        }
+    }
+
+    if let Ok(devs) = fs::read_dir(fs_dir) {
+        for d in devs.flatten() {
+            let name = d.file_name().to_string_lossy().into_owned();
+            let Some(idx) = name.strip_prefix("dev-") else {
+                continue;
+            };
+
+            let dev_dir = d.path();
+            if mountpoint.is_none() {
+                mountpoint = dev_rdev(&dev_dir).and_then(|rdev| mounts.get(&rdev));
+            }
+
+            add_dev(m, uuid, &dev_dir, idx);
+        }
+    }
+
+    if let Some(mountpoint) = mountpoint {
+        add_fs_usage(m, uuid, mountpoint);
+    }
+}
+
+fn collect() -> Metrics {
+    let mut m = Metrics::default();
+    let mounts = mounts();
+
+    if let Ok(dir) = fs::read_dir(SYSFS_BCACHEFS) {
+        for fs in dir.flatten() {
+            let uuid = fs.file_name().to_string_lossy().into_owned();
+            if uuid::Uuid::parse_str(&uuid).is_ok() {
+                add_fs(&mut m, &fs.path(), &uuid, &mounts);
+            }
+        }
+    }
+
+    m
+}
+
+fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = String::new();
+    BufReader::new(&stream)
+        .take(MAX_REQUEST_LINE)
+        .read_line(&mut request)?;
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", collect().to_string()),
+        "/" => (
+            "200 OK",
+            "bcachefs exporter: metrics are at /metrics\n".to_string(),
+        ),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    let mut response = String::new();
+    let _ = write!(
+        response,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    response.push_str(&body);
+
+    stream.write_all(response.as_bytes())
+}
+
+fn cmd_exporter_inner(opt: Cli) -> Result<()> {
+    opt.log.init()?;
+
+    if opt.once {
+        print!("{}", collect());
+        return Ok(());
+    }
+
+    // Prometheus convention: ":port" means all interfaces
+    let addr = match opt.listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => opt.listen.clone(),
+    };
+
+    let listener = TcpListener::bind(&addr).with_context(|| format!("listening on {}", addr))?;
+    info!("listening on {}", addr);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_connection(stream) {
+                    info!("error handling request: {}", e);
+                }
+            }
+            Err(e) => error!("error accepting connection: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn exporter(argv: Vec<String>) -> i32 {
+    let opt = Cli::parse_from(argv);
+
+    if let Err(e) = cmd_exporter_inner(opt) {
+        error!("Fatal error: {}", e);
+        ::bcachefs::error::exit_code(&e)
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_readable() {
+        assert_eq!(parse_human_readable("123"), Some(123.0));
+        assert_eq!(parse_human_readable(" 1.50 KiB\n"), Some(1536.0));
+        assert_eq!(parse_human_readable("2k"), Some(2000.0));
+        assert_eq!(parse_human_readable("lots"), None);
+    }
+
+    #[test]
+    fn time_stats() {
+        let s = "count:          10
+                             since mount        recent
+recent duration of events
+  min:                        2 us
+  max:                      300 ms
+  total:                      1 s
+  mean:                      50 ms            45 ms
+  stddev:                    10 ms            12 ms
+time between events
+  min:                        1 s
+";
+        assert_eq!(
+            parse_time_stats(s),
+            Some(TimeStats {
+                count:     10,
+                durations: vec![("min", 2e-6), ("max", 0.3), ("total", 1.0), ("mean", 0.05)],
+            })
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! A per filesystem log of the destructive commands run against it
+//!
+//! Commands that can destroy or rewrite data (format, fsck, migrate, device
+//! remove...) append a record to `/var/lib/bcachefs/history/<uuid>`, one line
+//! per command:
+//!
+//! ```text
+//! <unix time>\t<uid>\t<exit status>\t<command line>
+//! ```
+//!
+//! The exit status is `?` if the command exited directly (usually on an error)
+//! rather than returning it. The command line is shell quoted. The directory
+//! can be changed with `BCACHEFS_HISTORY_DIR`; setting it to the empty string
+//! disables recording.
+
+use std::{
+    collections::BTreeSet,
+    env,
+    ffi::CStr,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use bcachefs::device;
+use bch_bindgen::c;
+use clap::Parser;
+use log::{debug, error};
+use uuid::Uuid;
+
+use super::logger::LogOpts;
+use crate::wrappers::handle::BcachefsHandle;
+
+const BCACHEFS_STATFS_MAGIC: u64 = 0xca451a4e;
+
+fn history_dir() -> Option<PathBuf> {
+    match env::var_os("BCACHEFS_HISTORY_DIR") {
+        Some(d) if d.is_empty() => None,
+        Some(d) => Some(d.into()),
+        None => Some("/var/lib/bcachefs/history".into()),
+    }
+}
+
+/// fsck's getopt string, from cmd_fsck.c
+const FSCK_SHORTOPTS: &str = "apynfo:rRP:ks:vh";
+/// fsck's long options that take an argument, which may be the next word
+const FSCK_LONGOPTS_WITH_ARG: &[&str] = &["state-file", "passes", "memory-limit", "color"];
+
+/// Whether fsck was run with `-n`, or `-o nochanges`, parsing the arguments the
+/// way getopt does
+fn fsck_nochanges(args: &[String]) -> bool {
+    let is_nochanges = |opts: &str| {
+        opts.split(',')
+            .any(|o| o == "nochanges" || o == "nochanges=1")
+    };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            if FSCK_LONGOPTS_WITH_ARG.contains(&long) {
+                args.next();
+            }
+            continue;
+        }
+
+        let Some(cluster) = arg.strip_prefix('-') else {
+            continue;
+        };
+
+        for (i, c) in cluster.char_indices() {
+            let Some(j) = FSCK_SHORTOPTS.find(c).filter(|_| c != ':') else {
+                continue;
+            };
+
+            if c == 'n' {
+                return true;
+            }
+
+            if FSCK_SHORTOPTS[j + 1..].starts_with(':') {
+                let optarg = match &cluster[i + 1..] {
+                    "" => args.next().map(String::as_str).unwrap_or_default(),
+                    rest => rest,
+                };
+
+                if c == 'o' && is_nochanges(optarg) {
+                    return true;
+                }
+                break;
+            }
+        }
+    }
+
+    false
+}
+
+/// Commands that get recorded; fsck only if it can make changes
+fn is_destructive(cmd: &[String]) -> bool {
+    let Some(name) = cmd.first() else {
+        return false;
+    };
+    let args = &cmd[1..];
+
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        return false;
+    }
+
+    match name.as_str() {
+        "format" | "mkfs" | "migrate" | "migrate-superblock" => true,
+        "strip-alloc" => !args.iter().any(|a| a == "-n" || a == "--dry-run"),
+        "fsck" => !fsck_nochanges(args),
+        "device" => matches!(
+            args.first().map(String::as_str),
+            Some("remove" | "replace" | "set-state" | "evacuate")
+        ),
+        _ => false,
+    }
+}
+
+fn is_bcachefs_mount(path: &Path) -> bool {
+    let path = bch_bindgen::path_to_cstr(path);
+    let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+
+    unsafe {
+        libc::statfs(path.as_ptr(), &mut st) == 0 && st.f_type as u64 == BCACHEFS_STATFS_MAGIC
+    }
+}
+
+/// The filesystem a mountpoint, member device or UUID refers to
+fn fs_uuid(arg: &str) -> Option<Uuid> {
+    if let Ok(uuid) = Uuid::parse_str(arg) {
+        return Some(uuid);
+    }
+
+    let path = Path::new(arg);
+    if path.is_dir() {
+        if !is_bcachefs_mount(path) {
+            return None;
+        }
+        return Some(unsafe { BcachefsHandle::open(path) }.uuid());
+    }
+
+    if !path.exists() {
+        return None;
+    }
+
+    let mut sb = device::read_super_silent(path).ok()?;
+    let uuid = sb.sb().uuid();
+    unsafe { c::bch2_free_super(&mut sb) };
+    Some(uuid)
+}
+
+/// Every filesystem the command's arguments refer to
+fn fs_uuids(cmd: &[String]) -> BTreeSet<Uuid> {
+    cmd[1..]
+        .iter()
+        .filter(|a| !a.starts_with('-'))
+        .flat_map(|a| a.split(':'))
+        .filter_map(fs_uuid)
+        .collect()
+}
+
+fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''").replace('\n', r"\n"))
+    }
+}
+
+struct Pending {
+    cmd:   Vec<String>,
+    uuids: BTreeSet<Uuid>,
+}
+
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+fn write_record(p: Pending, status: Option<i32>) {
+    let Some(dir) = history_dir() else {
+        return;
+    };
+
+    // format creates a new filesystem, remove takes a device out of one:
+    // record against both what the arguments referred to before and after
+    let mut uuids = p.uuids;
+    uuids.extend(fs_uuids(&p.cmd));
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let status = status.map_or("?".to_owned(), |s| s.to_string());
+    let cmdline = std::iter::once("bcachefs")
+        .chain(p.cmd.iter().map(String::as_str))
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let uid = unsafe { libc::getuid() };
+    let record = format!("{now}\t{uid}\t{status}\t{cmdline}\n");
+
+    for uuid in uuids {
+        let ret = fs::create_dir_all(&dir).and_then(|_| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(uuid.to_string()))?
+                .write_all(record.as_bytes())
+        });
+
+        // never fail the command because the history can't be written:
+        if let Err(e) = ret {
+            debug!("error writing history for {uuid}: {e}");
+        }
+    }
+}
+
+extern "C" fn record_at_exit() {
+    if let Some(p) = PENDING.lock().ok().and_then(|mut p| p.take()) {
+        write_record(p, None);
+    }
+}
+
+/// Called before running a command: `cmd` is the command name and its
+/// arguments
+///
+/// C commands exit directly on errors, so the record is also written at exit
+/// if [`finish`] isn't reached.
+pub fn start(cmd: &[String]) {
+    if history_dir().is_none() || !is_destructive(cmd) {
+        return;
+    }
+
+    *PENDING.lock().unwrap() = Some(Pending {
+        cmd:   cmd.to_vec(),
+        uuids: fs_uuids(cmd),
+    });
+
+    unsafe { libc::atexit(record_at_exit) };
+}
+
+/// Called with the command's exit status, once it's returned
+pub fn finish(status: i32) {
+    if let Some(p) = PENDING.lock().unwrap().take() {
+        write_record(p, Some(status));
+    }
+}
+
+/// Show the destructive commands that have been run against a filesystem
+///
+/// Format, fsck (without -n), migrate, and device remove, replace, set-state
+/// and evacuate are recorded, with when they were run, by whom, and how they
+/// exited. The history is kept on this machine, in /var/lib/bcachefs/history.
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Mountpoint, member device or UUID of the filesystem
+    #[arg(value_hint = clap::ValueHint::AnyPath)]
+    filesystem: String,
+
+    #[command(flatten)]
+    log: LogOpts,
+}
+
+fn time_str(secs: i64) -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buf = [0u8; 64];
+
+    unsafe {
+        libc::localtime_r(&secs, &mut tm);
+        libc::strftime(
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            b"%F %T\0".as_ptr().cast(),
+            &tm,
+        );
+    }
+
+    CStr::from_bytes_until_nul(&buf)
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn cmd_history_inner(opt: &Cli) -> Result<()> {
+    opt.log.init()?;
+
+    let uuid = fs_uuid(&opt.filesystem)
+        .ok_or_else(|| anyhow!("{}: not a bcachefs filesystem", opt.filesystem))?;
+    let dir = history_dir().ok_or_else(|| anyhow!("history is disabled"))?;
+    let path = dir.join(uuid.to_string());
+
+    let history = match fs::read(&path) {
+        Ok(h) => h,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No history for {uuid}");
+            return Ok(());
+        }
+        Err(e) => return Err(anyhow!("error reading {}: {e}", path.display())),
+    };
+
+    for line in history.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        let line = String::from_utf8_lossy(line);
+        let fields: Vec<_> = line.splitn(4, '\t').collect();
+
+        match fields[..] {
+            [time, uid, status, cmdline] => {
+                let time = time.parse().map_or(time.to_owned(), time_str);
+                println!("{time}  uid {uid:<5}  exit {status:<3}  {cmdline}");
+            }
+            _ => println!("{line}"),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn history(argv: Vec<String>) -> i32 {
+    let opt = Cli::parse_from(argv);
+
+    if let Err(e) = cmd_history_inner(&opt) {
+        error!("{e}");
+        ::bcachefs::error::exit_code(&e)
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nochanges(args: &[&str]) -> bool {
+        fsck_nochanges(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn fsck_args() {
+        assert!(nochanges(&["-n", "/dev/sda"]));
+        assert!(nochanges(&["-fn", "/dev/sda"]));
+        assert!(nochanges(&["-o", "verbose,nochanges", "/dev/sda"]));
+        assert!(nochanges(&["-onochanges", "/dev/sda"]));
+
+        assert!(!nochanges(&["-y", "/dev/sda"]));
+        assert!(!nochanges(&["-s", "run-n", "/dev/sda"]));
+        assert!(!nochanges(&["-srun-n", "/dev/sda"]));
+        assert!(!nochanges(&["--state-file", "-n", "/dev/sda"]));
+        assert!(!nochanges(&["--", "-n"]));
+        assert!(!nochanges(&["--no-kernel", "/dev/nvme0n1"]));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, ensure, Result};
+use bcachefs::{device, mount::fstab_unescape};
+use clap::{CommandFactory, Parser};
+use log::{debug, error};
+
+use super::logger::LogOpts;
+
+/// What's needed to mount a filesystem from an initramfs
+///
+/// With --list-deps, prints one dependency per line, as `<kind> <value>`:
+///
+///   binary <path>       programs to copy in
+///   module <name>       kernel modules to load, with " builtin" appended if
+///                       the running kernel has them built in
+///   udev-rule <path>    udev rules to copy in
+///   device <path>       the member devices found
+///   unlock yes|no       whether the filesystem is encrypted, and so needs a
+///                       passphrase (`bcachefs unlock`) at boot
+///
+/// so that dracut and mkinitcpio hooks can be generated from it.
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// List the dependencies
+    #[arg(long)]
+    list_deps: bool,
+
+    /// Device, UUID=\<UUID\>, colon separated list of devices, or mountpoint
+    fs: String,
+
+    #[command(flatten)]
+    log: LogOpts,
+}
+
+/// The source of a mounted bcachefs filesystem, as in /proc/mounts
+fn mount_source(dir: &Path) -> Result<String> {
+    let dir = fs::canonicalize(dir)?;
+
+    fs::read_to_string("/proc/self/mounts")?
+        .lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .filter(|f| f.len() >= 3 && f[2] == "bcachefs")
+        .find(|f| Path::new(&fstab_unescape(f[1])) == dir)
+        .map(|f| fstab_unescape(f[0]))
+        .ok_or_else(|| anyhow!("{} is not a bcachefs mountpoint", dir.display()))
+}
+
+/// Modules built into the running kernel, from modules.builtin
+fn builtin_modules() -> BTreeSet<String> {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let path = format!("/lib/modules/{}/modules.builtin", release.trim());
+
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| Path::new(l).file_stem())
+        .map(|m| m.to_string_lossy().replace('-', "_"))
+        .collect()
+}
+
+fn link_name(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_link(path)
+        .ok()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+}
+
+/// The kernel modules needed to see a block device: the drivers of each
+/// device up the sysfs hierarchy, and for stacked devices (dm, md) the
+/// stacking driver and the modules for each underlying device
+fn block_dev_modules(sysfs: &Path, modules: &mut BTreeSet<String>) {
+    let Ok(sysfs) = fs::canonicalize(sysfs) else {
+        return;
+    };
+
+    for dir in sysfs.ancestors() {
+        if let Some(m) = link_name(dir.join("driver/module")) {
+            modules.insert(m);
+        }
+    }
+
+    if let Ok(uuid) = fs::read_to_string(sysfs.join("dm/uuid")) {
+        modules.insert("dm_mod".into());
+        if uuid.starts_with("CRYPT-") {
+            modules.insert("dm_crypt".into());
+        }
+    }
+
+    if let Ok(level) = fs::read_to_string(sysfs.join("md/level")) {
+        modules.insert("md_mod".into());
+        match level.trim() {
+            "raid4" | "raid5" | "raid6" => modules.insert("raid456".into()),
+            l => modules.insert(l.into()),
+        };
+    }
+
+    // A partition's slaves are its parent's:
+    let slaves =
+        fs::read_dir(sysfs.join("slaves")).or_else(|_| fs::read_dir(sysfs.join("../slaves")));
+
+    for slave in slaves.into_iter().flatten().flatten() {
+        block_dev_modules(&slave.path(), modules);
+    }
+}
+
+fn dev_sysfs(dev: &Path) -> Result<PathBuf> {
+    let rdev = fs::metadata(dev)?.rdev();
+
+    Ok(PathBuf::from(format!(
+        "/sys/dev/block/{}:{}",
+        libc::major(rdev),
+        libc::minor(rdev)
+    )))
+}
+
+fn udev_rules() -> Vec<PathBuf> {
+    [
+        "/etc/udev/rules.d",
+        "/usr/lib/udev/rules.d",
+        "/lib/udev/rules.d",
+    ]
+    .iter()
+    .map(|d| Path::new(d).join("64-bcachefs.rules"))
+    .filter(|p| p.exists())
+    .take(1)
+    .collect()
+}
+
+fn cmd_initramfs_inner(opt: &Cli) -> Result<()> {
+    opt.log.init()?;
+
+    if !opt.list_deps {
+        Cli::command().print_help()?;
+        return Ok(());
+    }
+
+    let spec = if Path::new(&opt.fs).is_dir() {
+        mount_source(Path::new(&opt.fs))?
+    } else {
+        opt.fs.clone()
+    };
+
+    let udev_info = device::udev_bcachefs_info()?;
+    let (devs, sbs) = device::find_devices(&udev_info, &spec)?;
+    ensure!(!sbs.is_empty(), "no devices found for {}", spec);
+
+    let encrypted = device::sb_is_encrypted(&sbs[0]);
+    let devs: Vec<_> = devs.split(':').map(PathBuf::from).collect();
+
+    let exe = env::current_exe()?;
+    println!("binary {}", exe.display());
+
+    let mount_helper = exe.with_file_name("mount.bcachefs");
+    if mount_helper.exists() {
+        println!("binary {}", mount_helper.display());
+    }
+
+    let mut modules = BTreeSet::from(["bcachefs".to_string()]);
+    if encrypted {
+        modules.insert("chacha20".into());
+        modules.insert("poly1305".into());
+    }
+    for dev in &devs {
+        match dev_sysfs(dev) {
+            Ok(sysfs) => block_dev_modules(&sysfs, &mut modules),
+            Err(e) => debug!("{}: {}", dev.display(), e),
+        }
+    }
+
+    let builtin = builtin_modules();
+    for m in &modules {
+        let m = m.replace('-', "_");
+
+        if builtin.contains(&m) {
+            println!("module {} builtin", m);
+        } else {
+            println!("module {}", m);
+        }
+    }
+
+    for r in udev_rules() {
+        println!("udev-rule {}", r.display());
+    }
+
+    for dev in &devs {
+        println!("device {}", dev.display());
+    }
+
+    println!("unlock {}", if encrypted { "yes" } else { "no" });
+
+    Ok(())
+}
+
+pub fn initramfs(argv: Vec<String>) -> i32 {
+    let opt = Cli::parse_from(argv);
+
+    if let Err(e) = cmd_initramfs_inner(&opt) {
+        error!("{}", e);
+        return ::bcachefs::error::exit_code(&e);
+    }
+
+    0
+}
@@ -1,3 +1,7 @@
+use std::collections::BTreeMap;
+
+use ::bcachefs::device;
+use ::bcachefs::output::{self, ColorWhen, Table};
 use bch_bindgen::bcachefs;
 use bch_bindgen::bkey::BkeySC;
 use bch_bindgen::btree::BtreeIter;
@@ -5,19 +9,23 @@ use bch_bindgen::btree::BtreeIterFlags;
 use bch_bindgen::btree::BtreeNodeIter;
 use bch_bindgen::btree::BtreeTrans;
 use bch_bindgen::fs::Fs;
-use bch_bindgen::opt_set;
+use bch_bindgen::opts::Opts;
+use bch_bindgen::{bkey_type_name, c, printbuf_to_string};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::Parser;
 use log::error;
-use std::io::{stdout, IsTerminal};
+
+use super::logger::LogOpts;
 
 fn list_keys(fs: &Fs, opt: &Cli) -> anyhow::Result<()> {
     let trans = BtreeTrans::new(fs);
     let mut iter = BtreeIter::new(
         &trans,
-        opt.btree,
+        opt.btree(),
         opt.start,
         BtreeIterFlags::ALL_SNAPSHOTS | BtreeIterFlags::PREFETCH,
-    );
+    )
+    .readahead(opt.readahead);
 
     while let Some(k) = iter.peek_and_restart()? {
         if k.k.p > opt.end {
@@ -32,22 +40,153 @@ fn list_keys(fs: &Fs, opt: &Cli) -> anyhow::Result<()> {
         }
 
         println!("{}", k.to_text(fs));
+        let v: *const bcachefs::bch_val = k.v;
+
+        if opt.offsets {
+            match iter.leaf() {
+                Some(b) => println!(
+                    "  node {} journal_seq {}",
+                    node_ptrs(b),
+                    b.bset_journal_seq(v)
+                        .map_or_else(|| "(journal)".to_string(), |seq| seq.to_string())
+                ),
+                None => println!("  node (none)"),
+            }
+        }
+
+        iter.advance();
+    }
+
+    Ok(())
+}
+
+/// Number of keys and their total size, for --stats-only
+#[derive(Clone, Copy, Default)]
+struct KeyStats {
+    nr:    u64,
+    bytes: u64,
+}
+
+impl KeyStats {
+    fn add(&mut self, other: KeyStats) {
+        self.nr += other.nr;
+        self.bytes += other.bytes;
+    }
+
+    fn row(&self, name: String) -> [String; 3] {
+        let bytes = printbuf_to_string(|buf| unsafe {
+            c::tools_printbuf_units(buf, true);
+            c::tools_prt_units_u64(buf, self.bytes)
+        });
+
+        [name, self.nr.to_string(), bytes]
+    }
+}
+
+/// Keys in one btree by (snapshot, key type); snapshot is 0 unless
+/// --by-snapshot
+fn btree_census(
+    fs: &Fs,
+    opt: &Cli,
+    btree: bcachefs::btree_id,
+) -> anyhow::Result<BTreeMap<(u32, u8), KeyStats>> {
+    let trans = BtreeTrans::new(fs);
+    let mut iter = BtreeIter::new(
+        &trans,
+        btree,
+        opt.start,
+        BtreeIterFlags::ALL_SNAPSHOTS | BtreeIterFlags::PREFETCH,
+    )
+    .readahead(opt.readahead);
+    let mut census = BTreeMap::new();
+
+    while let Some(k) = iter.peek_and_restart()? {
+        if k.k.p > opt.end {
+            break;
+        }
+
+        if opt.bkey_type.map_or(true, |ty| k.k.type_ == ty as u8) {
+            let snapshot = if opt.by_snapshot { k.k.p.snapshot } else { 0 };
+
+            census
+                .entry((snapshot, k.k.type_))
+                .or_default()
+                .add(KeyStats {
+                    nr:    1,
+                    bytes: k.k.u64s as u64 * 8,
+                });
+        }
+
         iter.advance();
     }
 
+    Ok(census)
+}
+
+/// Key counts and sizes per btree and key type, instead of the keys: the
+/// shape of a filesystem's metadata, without its contents
+fn list_stats(fs: &Fs, opt: &Cli) -> anyhow::Result<()> {
+    let btrees: Vec<bcachefs::btree_id> = match opt.btree {
+        Some(btree) => vec![btree],
+        None => bch_bindgen::btree_id_names()
+            .map(|n| n.parse().unwrap())
+            .collect(),
+    };
+    let mut table = Table::new().header(["btree", "keys", "bytes"]);
+    let mut total = KeyStats::default();
+
+    for btree in btrees {
+        let census = btree_census(fs, opt, btree)?;
+        if census.is_empty() {
+            continue;
+        }
+
+        let mut btree_total = KeyStats::default();
+        census.values().for_each(|s| btree_total.add(*s));
+        table.row(btree_total.row(btree.to_string()));
+        total.add(btree_total);
+
+        let mut snapshot = None;
+        for (&(snap, ty), stats) in &census {
+            if opt.by_snapshot && snapshot != Some(snap) {
+                let mut snap_total = KeyStats::default();
+                census
+                    .range((snap, 0)..=(snap, u8::MAX))
+                    .for_each(|(_, s)| snap_total.add(*s));
+                table.row(snap_total.row(format!("  snapshot {snap}")));
+                snapshot = Some(snap);
+            }
+
+            let indent = if opt.by_snapshot { "    " } else { "  " };
+            table.row(stats.row(format!("{indent}{}", bkey_type_name(ty))));
+        }
+    }
+
+    table.row(total.row("total".to_owned()));
+    print!("{table}");
     Ok(())
 }
 
+/// Where a btree node is on disk, as a list of dev:sector, one per replica
+fn node_ptrs(b: &bcachefs::btree) -> String {
+    b.ptrs()
+        .iter()
+        .map(|(dev, sector)| format!("{}:{}", dev, sector))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 fn list_btree_formats(fs: &Fs, opt: &Cli) -> anyhow::Result<()> {
     let trans = BtreeTrans::new(fs);
     let mut iter = BtreeNodeIter::new(
         &trans,
-        opt.btree,
+        opt.btree(),
         opt.start,
         0,
         opt.level,
         BtreeIterFlags::PREFETCH,
-    );
+    )
+    .readahead(opt.readahead);
 
     while let Some(b) = iter.peek_and_restart()? {
         if b.key.k.p > opt.end {
@@ -65,12 +204,13 @@ fn list_btree_nodes(fs: &Fs, opt: &Cli) -> anyhow::Result<()> {
     let trans = BtreeTrans::new(fs);
     let mut iter = BtreeNodeIter::new(
         &trans,
-        opt.btree,
+        opt.btree(),
         opt.start,
         0,
         opt.level,
         BtreeIterFlags::PREFETCH,
-    );
+    )
+    .readahead(opt.readahead);
 
     while let Some(b) = iter.peek_and_restart()? {
         if b.key.k.p > opt.end {
@@ -78,6 +218,9 @@ fn list_btree_nodes(fs: &Fs, opt: &Cli) -> anyhow::Result<()> {
         }
 
         println!("{}", BkeySC::from(&b.key).to_text(fs));
+        if opt.offsets {
+            println!("  node {}", node_ptrs(b));
+        }
         iter.advance();
     }
 
@@ -88,12 +231,13 @@ fn list_nodes_ondisk(fs: &Fs, opt: &Cli) -> anyhow::Result<()> {
     let trans = BtreeTrans::new(fs);
     let mut iter = BtreeNodeIter::new(
         &trans,
-        opt.btree,
+        opt.btree(),
         opt.start,
         0,
         opt.level,
         BtreeIterFlags::PREFETCH,
-    );
+    )
+    .readahead(opt.readahead);
 
     while let Some(b) = iter.peek_and_restart()? {
         if b.key.k.p > opt.end {
@@ -115,12 +259,19 @@ enum Mode {
     NodesOndisk,
 }
 
+/// Parse btree names, listing them as the possible values so that they're
+/// completed by the shell
+fn btree_parser() -> impl TypedValueParser<Value = bcachefs::btree_id> {
+    PossibleValuesParser::new(bch_bindgen::btree_id_names()).map(|s| s.parse().unwrap())
+}
+
 /// List filesystem metadata in textual form
 #[derive(Parser, Debug)]
 pub struct Cli {
-    /// Btree to list from
-    #[arg(short, long, default_value_t=bcachefs::btree_id::BTREE_ID_extents)]
-    btree: bcachefs::btree_id,
+    /// Btree to list from (default: extents, or every btree with
+    /// --stats-only)
+    #[arg(short, long, value_parser = btree_parser())]
+    btree: Option<bcachefs::btree_id>,
 
     /// Bkey type to list
     #[arg(short = 'k', long)]
@@ -130,7 +281,8 @@ pub struct Cli {
     #[arg(short, long, default_value_t = 0)]
     level: u32,
 
-    /// Start position to list from
+    /// Start position to list from, as inode:offset[:snapshot] or
+    /// "inode=N offset=N snapshot=N"
     #[arg(short, long, default_value = "POS_MIN")]
     start: bcachefs::bpos,
 
@@ -141,50 +293,77 @@ pub struct Cli {
     #[arg(short, long, default_value = "keys")]
     mode: Mode,
 
+    /// Don't print keys: print the number of keys and their size, for each
+    /// btree and key type
+    #[arg(long, conflicts_with = "offsets")]
+    stats_only: bool,
+
+    /// With --stats-only, break the numbers down by snapshot too
+    #[arg(long, requires = "stats_only")]
+    by_snapshot: bool,
+
+    /// Print where each key's btree node is on disk (dev:sector, for each
+    /// replica), and the journal sequence number of the bset containing it
+    #[arg(short, long)]
+    offsets: bool,
+
+    /// Number of btree nodes to read ahead of the one being listed, to keep
+    /// reads in flight on high latency devices (0 to disable)
+    #[arg(long, default_value_t = 32)]
+    readahead: u32,
+
     /// Check (fsck) the filesystem first
     #[arg(short, long)]
     fsck: bool,
 
-    /// Force color on/off. Default: autodetect tty
-    #[arg(short, long, action = clap::ArgAction::Set, default_value_t=stdout().is_terminal())]
-    colorize: bool,
+    /// Color output
+    #[arg(long, value_enum, default_value_t)]
+    color: ColorWhen,
 
-    /// Verbose mode
-    #[arg(short, long)]
-    verbose: bool,
+    /// Force color on/off; superseded by --color
+    #[arg(short, long, action = clap::ArgAction::Set, hide = true)]
+    colorize: Option<bool>,
 
-    #[arg(required(true))]
+    #[command(flatten)]
+    log: LogOpts,
+
+    #[arg(required(true), value_hint = clap::ValueHint::FilePath)]
     devices: Vec<std::path::PathBuf>,
 }
 
+impl Cli {
+    fn btree(&self) -> bcachefs::btree_id {
+        self.btree.unwrap_or(bcachefs::btree_id::BTREE_ID_extents)
+    }
+}
+
 fn cmd_list_inner(opt: &Cli) -> anyhow::Result<()> {
-    let mut fs_opts = bcachefs::bch_opts::default();
-
-    opt_set!(fs_opts, nochanges, 1);
-    opt_set!(fs_opts, read_only, 1);
-    opt_set!(fs_opts, norecovery, 1);
-    opt_set!(fs_opts, degraded, 1);
-    opt_set!(fs_opts, very_degraded, 1);
-    opt_set!(
-        fs_opts,
-        errors,
-        bcachefs::bch_error_actions::BCH_ON_ERROR_continue as u8
-    );
+    opt.log.init()?;
+
+    let mut fs_opts = Opts::new()
+        .nochanges(true)
+        .read_only(true)
+        .norecovery(true)
+        .degraded(true)
+        .very_degraded(true)
+        .errors(bcachefs::bch_error_actions::BCH_ON_ERROR_continue as u8);
 
     if opt.fsck {
-        opt_set!(
-            fs_opts,
-            fix_errors,
-            bcachefs::fsck_err_opts::FSCK_FIX_yes as u8
-        );
-        opt_set!(fs_opts, norecovery, 0);
+        fs_opts = fs_opts
+            .fix_errors(bcachefs::fsck_err_opts::FSCK_FIX_yes as u8)
+            .norecovery(false);
     }
 
-    if opt.verbose {
-        opt_set!(fs_opts, verbose, 1);
+    if opt.log.verbose > 0 {
+        fs_opts = fs_opts.verbose(true);
     }
 
-    let fs = Fs::open(&opt.devices, fs_opts)?;
+    device::check_members(&opt.devices)?;
+    let fs = Fs::open(&opt.devices, fs_opts.build())?;
+
+    if opt.stats_only {
+        return list_stats(&fs, opt);
+    }
 
     match opt.mode {
         Mode::Keys => list_keys(&fs, opt),
@@ -196,10 +375,10 @@ fn cmd_list_inner(opt: &Cli) -> anyhow::Result<()> {
 
 pub fn list(argv: Vec<String>) -> i32 {
     let opt = Cli::parse_from(argv);
-    colored::control::set_override(opt.colorize);
+    output::set_color(opt.color, opt.colorize);
     if let Err(e) = cmd_list_inner(&opt) {
         error!("Fatal error: {}", e);
-        1
+        ::bcachefs::error::exit_code(&e)
     } else {
         0
     }
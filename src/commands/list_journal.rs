@@ -0,0 +1,265 @@
+use std::{cmp::Ordering, io::Write};
+
+use ::bcachefs::{
+    device,
+    output::{self, ColorWhen},
+};
+use anyhow::{anyhow, Result};
+use bch_bindgen::{
+    bcachefs, c,
+    fs::Fs,
+    journal::{JournalEntry, JsetEntry},
+    opts::Opts,
+    printbuf_to_string,
+};
+use clap::Parser;
+use colored::Colorize;
+use log::error;
+
+use super::logger::LogOpts;
+
+/// A btree and a position in it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Bbpos {
+    btree: c::btree_id,
+    pos:   c::bpos,
+}
+
+impl PartialOrd for Bbpos {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Bbpos {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.btree as u32, self.pos).cmp(&(other.btree as u32, other.pos))
+    }
+}
+
+fn parse_bbpos(s: &str) -> Result<Bbpos> {
+    let (btree, pos) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid bbpos {s}"))?;
+
+    Ok(Bbpos {
+        btree: btree.parse()?,
+        pos:   pos.parse()?,
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BbposRange {
+    start: Bbpos,
+    end:   Bbpos,
+}
+
+/// `btree:pos`, or `btree:pos-btree:pos` for a range
+fn parse_bbpos_range(s: &str) -> Result<BbposRange> {
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (parse_bbpos(start)?, parse_bbpos(end)?),
+        None => {
+            let p = parse_bbpos(s)?;
+            (p, p)
+        }
+    };
+
+    Ok(BbposRange { start, end })
+}
+
+/// Print the contents of the journal
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Read entire journal, not just dirty entries
+    #[arg(short = 'a')]
+    all: bool,
+
+    /// Number of journal entries to print, starting from the most recent
+    #[arg(short, long)]
+    nr_entries: Option<u32>,
+
+    /// Filter transactions not updating <bbpos>, or entries not matching the
+    /// range <bbpos-bbpos>
+    #[arg(short, long, value_parser = parse_bbpos_range)]
+    transaction_filter: Vec<BbposRange>,
+
+    /// Filter keys not updating btree
+    #[arg(short, long)]
+    key_filter: Vec<bcachefs::btree_id>,
+
+    /// Color output
+    #[arg(long, value_enum, default_value_t)]
+    color: ColorWhen,
+
+    #[command(flatten)]
+    log: LogOpts,
+
+    #[arg(required(true), value_hint = clap::ValueHint::FilePath)]
+    devices: Vec<std::path::PathBuf>,
+}
+
+fn key_matches_filter(filter: &[BbposRange], entry: &JsetEntry) -> bool {
+    let btree = entry.btree_id();
+
+    entry.keys().any(|k| {
+        let k_start = Bbpos {
+            btree,
+            pos: c::bpos {
+                offset: k.k.p.offset.saturating_sub(k.k.size as u64),
+                ..k.k.p
+            },
+        };
+        let k_end = Bbpos { btree, pos: k.k.p };
+
+        filter.iter().any(|r| k_start < r.end && k_end > r.start)
+    })
+}
+
+fn should_print_entry(filter: &[bcachefs::btree_id], entry: &JsetEntry) -> bool {
+    filter.is_empty()
+        || !entry.has_keys()
+        || (entry.keys().next().is_some() && filter.contains(&entry.btree_id()))
+}
+
+/// Mark lines from blacklisted entries, which will be ignored by replay
+fn star_start_of_lines(s: &str) -> String {
+    s.split('\n')
+        .map(|l| match l.strip_prefix(' ') {
+            Some(rest) => format!("*{rest}"),
+            None => l.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn print_journal_entry(
+    out: &mut impl Write,
+    fs: &Fs,
+    j: &JournalEntry,
+    opt: &Cli,
+) -> std::io::Result<()> {
+    let blacklisted = j.blacklisted();
+    let star = |s: String| {
+        if blacklisted {
+            star_start_of_lines(&s)
+        } else {
+            s
+        }
+    };
+
+    if opt.transaction_filter.is_empty() {
+        if blacklisted {
+            write!(out, "blacklisted ")?;
+        }
+        writeln!(out, "journal entry     {}", j.seq())?;
+
+        let header = format!(
+            "  version         {}\n  last seq        {}\n  flush           {}\n  written at      {}",
+            j.version(),
+            j.last_seq(),
+            j.is_flush() as u32,
+            j.ptrs_to_text()
+        );
+        writeln!(out, "{}", star(header))?;
+    }
+
+    let entries: Vec<_> = j.entries().collect();
+    let mut i = 0;
+
+    while i < entries.len() {
+        let entry = &entries[i];
+        let mut text = String::new();
+
+        // log entries denote the start of a new transaction commit:
+        if entry.is_transaction_start() {
+            let trans_end = entries[i + 1..]
+                .iter()
+                .position(|e| e.is_transaction_start())
+                .map_or(entries.len(), |n| i + 1 + n);
+
+            if !opt.transaction_filter.is_empty()
+                && !entries[i + 1..trans_end]
+                    .iter()
+                    .any(|e| key_matches_filter(&opt.transaction_filter, e))
+            {
+                i = trans_end;
+                continue;
+            }
+
+            text.push('\n');
+        }
+
+        if should_print_entry(&opt.key_filter, entry) {
+            text += &printbuf_to_string(|buf| unsafe {
+                c::bch2_printbuf_indent_add(buf, 4);
+                c::bch2_journal_entry_to_text(
+                    buf,
+                    fs.raw,
+                    entry.raw as *const _ as *mut c::jset_entry,
+                );
+            });
+            let text = star(text);
+
+            if key_matches_filter(&opt.transaction_filter, entry) {
+                writeln!(out, "{}", text.as_str().red())?;
+            } else {
+                writeln!(out, "{text}")?;
+            }
+        }
+
+        i += 1;
+    }
+
+    Ok(())
+}
+
+fn cmd_list_journal_inner(opt: &Cli) -> Result<()> {
+    opt.log.init()?;
+
+    let mut fs_opts = Opts::new()
+        .nochanges(true)
+        .norecovery(true)
+        .read_only(true)
+        .degraded(true)
+        .very_degraded(true)
+        .errors(bcachefs::bch_error_actions::BCH_ON_ERROR_continue as u8)
+        .fix_errors(bcachefs::fsck_err_opts::FSCK_FIX_yes as u8)
+        .retain_recovery_info(true)
+        .read_journal_only(true);
+
+    if opt.all || opt.nr_entries.is_some() {
+        fs_opts = fs_opts.read_entire_journal(true);
+    }
+
+    if opt.log.verbose > 0 {
+        fs_opts = fs_opts.verbose(true);
+    }
+
+    device::check_members(&opt.devices)?;
+    let fs = Fs::open(&opt.devices, fs_opts.build())?;
+
+    let max_seq = fs.journal_entries().map(|j| j.seq()).max().unwrap_or(0);
+    let min_seq = opt
+        .nr_entries
+        .map_or(0, |nr| (max_seq + 1).saturating_sub(nr as u64));
+
+    let mut out = std::io::stdout().lock();
+
+    for j in fs.journal_entries().filter(|j| j.seq() >= min_seq) {
+        print_journal_entry(&mut out, &fs, &j, opt)?;
+    }
+
+    Ok(())
+}
+
+pub fn list_journal(argv: Vec<String>) -> i32 {
+    let opt = Cli::parse_from(argv);
+    output::set_color(opt.color, None);
+
+    if let Err(e) = cmd_list_journal_inner(&opt) {
+        error!("Fatal error: {}", e);
+        ::bcachefs::error::exit_code(&e)
+    } else {
+        0
+    }
+}
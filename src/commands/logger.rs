@@ -1,5 +1,23 @@
+//! Logging for the Rust commands
+//!
+//! Diagnostics go through the `log` macros, to stderr, so that they stay out
+//! of the way of a command's actual output. Every command takes the same
+//! [`LogOpts`]: `-q`/`-v` to pick the level, and `--log-file` to also write
+//! the log (uncolored, with timestamps) to a file.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
 use colored::Colorize;
-use log::{Level, Metadata, Record};
+use log::{Level, LevelFilter, Metadata, Record};
+
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
 
 pub struct SimpleLogger;
 
@@ -16,13 +34,83 @@ impl log::Log for SimpleLogger {
             Level::Debug => "DEBUG".bright_blue(),
             Level::Trace => "TRACE".into(),
         };
-        println!(
+        let module = record.module_path().unwrap_or_default();
+
+        eprintln!(
             "{} - {}: {}",
             debug_prefix,
-            record.module_path().unwrap_or_default().bright_black(),
+            module.bright_black(),
             record.args()
         );
+
+        if let Some(f) = LOG_FILE.lock().unwrap().as_mut() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+
+            // Nowhere left to report a failure to write the log
+            let _ = writeln!(
+                f,
+                "{}.{:06} {} - {}: {}",
+                now.as_secs(),
+                now.subsec_micros(),
+                record.level(),
+                module,
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(f) = LOG_FILE.lock().unwrap().as_mut() {
+            let _ = f.flush();
+        }
     }
+}
+
+/// Verbosity and log file options, common to all commands
+#[derive(clap::Args, Debug, Default)]
+pub struct LogOpts {
+    /// Only print errors; twice to print nothing
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
 
-    fn flush(&self) {}
+    /// Verbose mode; repeat for more detail
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Append log messages to this file, as well as printing them
+    #[arg(long, global = true, value_hint = clap::ValueHint::FilePath)]
+    pub log_file: Option<PathBuf>,
+}
+
+impl LogOpts {
+    pub fn level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (0, 0) => LevelFilter::Warn,
+            (0, 1) => LevelFilter::Info,
+            (0, 2) => LevelFilter::Debug,
+            (0, _) => LevelFilter::Trace,
+            (1, _) => LevelFilter::Error,
+            (_, _) => LevelFilter::Off,
+        }
+    }
+
+    /// Set the log level and open the log file; the logger itself is
+    /// installed by main(), before any arguments are parsed
+    pub fn init(&self) -> Result<()> {
+        log::set_max_level(self.level());
+
+        if let Some(path) = &self.log_file {
+            let f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("opening log file {}", path.display()))?;
+
+            *LOG_FILE.lock().unwrap() = Some(f);
+        }
+
+        Ok(())
+    }
 }
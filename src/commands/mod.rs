@@ -1,15 +1,30 @@
 use clap::Subcommand;
 
 pub mod completions;
+pub mod exporter;
+pub mod history;
+pub mod initramfs;
 pub mod list;
+pub mod list_journal;
 pub mod logger;
 pub mod mount;
+pub mod options;
+pub mod probe;
 pub mod subvolume;
+pub mod targets;
+pub mod test;
 
 pub use completions::completions;
+pub use exporter::exporter;
+pub use history::history;
+pub use initramfs::initramfs;
 pub use list::list;
+pub use list_journal::list_journal;
 pub use mount::mount;
+pub use options::options;
+pub use probe::probe;
 pub use subvolume::subvolume;
+pub use test::test;
 
 #[derive(clap::Parser, Debug)]
 #[command(name = "bcachefs")]
@@ -21,33 +36,17 @@ pub struct Cli {
 #[derive(Subcommand, Debug)]
 enum Subcommands {
     List(list::Cli),
+    #[command(name = "list_journal")]
+    ListJournal(list_journal::Cli),
     Mount(mount::Cli),
+    Options(options::Cli),
+    Probe(probe::Cli),
     Completions(completions::Cli),
+    Exporter(exporter::Cli),
+    History(history::Cli),
+    Initramfs(initramfs::Cli),
     #[command(visible_aliases = ["subvol"])]
     Subvolume(subvolume::Cli),
-}
-
-// FIXME: Can be removed after bumping MSRV >= 1.77 in favor of `c""` literals
-#[macro_export]
-macro_rules! c_str {
-    ($lit:expr) => {
-        ::std::ffi::CStr::from_bytes_with_nul(concat!($lit, "\0").as_bytes())
-            .unwrap()
-            .as_ptr()
-    };
-}
-
-#[cfg(test)]
-mod tests {
-    use std::ffi::CStr;
-
-    #[test]
-    fn check_cstr_macro() {
-        let literal = c_str!("hello");
-
-        assert_eq!(
-            literal,
-            CStr::from_bytes_with_nul(b"hello\0").unwrap().as_ptr()
-        );
-    }
+    #[command(hide = true)]
+    Test(test::Cli),
 }
@@ -1,215 +1,24 @@
 use std::{
-    collections::HashMap,
-    env,
+    collections::BTreeSet,
     ffi::CString,
     fs,
-    io::{stdout, IsTerminal},
     path::{Path, PathBuf},
-    ptr, str,
 };
 
-use anyhow::{ensure, Result};
-use bch_bindgen::{bcachefs, bcachefs::bch_sb_handle, opt_set, path_to_cstr};
+use anyhow::{anyhow, bail, ensure, Result};
+use bcachefs::{
+    device,
+    key::{KeyHandle, Passphrase, UnlockPolicy},
+    mount::{self as mnt, FstabEntry},
+    output::{self, ColorWhen},
+    target::Targets,
+};
+use bch_bindgen::{c, opts::Opts};
 use clap::Parser;
-use log::{debug, error, info, LevelFilter};
+use log::{error, info};
 use uuid::Uuid;
 
-use crate::key::{KeyHandle, Passphrase, UnlockPolicy};
-
-fn mount_inner(
-    src: String,
-    target: impl AsRef<std::path::Path>,
-    fstype: &str,
-    mountflags: libc::c_ulong,
-    data: Option<String>,
-) -> anyhow::Result<()> {
-    // bind the CStrings to keep them alive
-    let src = CString::new(src)?;
-    let target = path_to_cstr(target);
-    let data = data.map(CString::new).transpose()?;
-    let fstype = CString::new(fstype)?;
-
-    // convert to pointers for ffi
-    let src = src.as_ptr();
-    let target = target.as_ptr();
-    let data = data.map_or(ptr::null(), |data| data.as_ptr().cast());
-    let fstype = fstype.as_ptr();
-
-    let ret = {
-        info!("mounting filesystem");
-        // REQUIRES: CAP_SYS_ADMIN
-        unsafe { libc::mount(src, target, fstype, mountflags, data) }
-    };
-    match ret {
-        0 => Ok(()),
-        _ => Err(crate::ErrnoError(errno::errno()).into()),
-    }
-}
-
-/// Parse a comma-separated mount options and split out mountflags and filesystem
-/// specific options.
-fn parse_mount_options(options: impl AsRef<str>) -> (Option<String>, libc::c_ulong) {
-    use either::Either::{Left, Right};
-
-    debug!("parsing mount options: {}", options.as_ref());
-    let (opts, flags) = options
-        .as_ref()
-        .split(',')
-        .map(|o| match o {
-            "dirsync" => Left(libc::MS_DIRSYNC),
-            "lazytime" => Left(1 << 25), // MS_LAZYTIME
-            "mand" => Left(libc::MS_MANDLOCK),
-            "noatime" => Left(libc::MS_NOATIME),
-            "nodev" => Left(libc::MS_NODEV),
-            "nodiratime" => Left(libc::MS_NODIRATIME),
-            "noexec" => Left(libc::MS_NOEXEC),
-            "nosuid" => Left(libc::MS_NOSUID),
-            "relatime" => Left(libc::MS_RELATIME),
-            "remount" => Left(libc::MS_REMOUNT),
-            "ro" => Left(libc::MS_RDONLY),
-            "rw" | "" => Left(0),
-            "strictatime" => Left(libc::MS_STRICTATIME),
-            "sync" => Left(libc::MS_SYNCHRONOUS),
-            o => Right(o),
-        })
-        .fold((Vec::new(), 0), |(mut opts, flags), next| match next {
-            Left(f) => (opts, flags | f),
-            Right(o) => {
-                opts.push(o);
-                (opts, flags)
-            }
-        });
-
-    (
-        if opts.is_empty() {
-            None
-        } else {
-            Some(opts.join(","))
-        },
-        flags,
-    )
-}
-
-fn read_super_silent(path: impl AsRef<Path>) -> anyhow::Result<bch_sb_handle> {
-    let mut opts = bcachefs::bch_opts::default();
-    opt_set!(opts, noexcl, 1);
-
-    bch_bindgen::sb_io::read_super_silent(path.as_ref(), opts)
-}
-
-fn device_property_map(dev: &udev::Device) -> HashMap<String, String> {
-    let rc: HashMap<_, _> = dev
-        .properties()
-        .map(|i| {
-            (
-                String::from(i.name().to_string_lossy()),
-                String::from(i.value().to_string_lossy()),
-            )
-        })
-        .collect();
-    rc
-}
-
-fn udev_bcachefs_info() -> anyhow::Result<HashMap<String, Vec<String>>> {
-    let mut info = HashMap::new();
-
-    if env::var("BCACHEFS_BLOCK_SCAN").is_ok() {
-        debug!("Checking all block devices for bcachefs super block!");
-        return Ok(info);
-    }
-
-    let mut udev = udev::Enumerator::new()?;
-
-    debug!("Walking udev db!");
-
-    udev.match_subsystem("block")?;
-    udev.match_property("ID_FS_TYPE", "bcachefs")?;
-
-    for m in udev
-        .scan_devices()?
-        .filter(udev::Device::is_initialized)
-        .map(|dev| device_property_map(&dev))
-        .filter(|m| m.contains_key("ID_FS_UUID") && m.contains_key("DEVNAME"))
-    {
-        let fs_uuid = m["ID_FS_UUID"].clone();
-        let dev_node = m["DEVNAME"].clone();
-        info.insert(dev_node.clone(), vec![fs_uuid.clone()]);
-        info.entry(fs_uuid).or_insert(vec![]).push(dev_node.clone());
-    }
-
-    Ok(info)
-}
-
-fn get_super_blocks(uuid: Uuid, devices: &[String]) -> Vec<(PathBuf, bch_sb_handle)> {
-    devices
-        .iter()
-        .filter_map(|dev| {
-            read_super_silent(PathBuf::from(dev))
-                .ok()
-                .map(|sb| (PathBuf::from(dev), sb))
-        })
-        .filter(|(_, sb)| sb.sb().uuid() == uuid)
-        .collect::<Vec<_>>()
-}
-
-fn get_all_block_devnodes() -> anyhow::Result<Vec<String>> {
-    let mut udev = udev::Enumerator::new()?;
-    udev.match_subsystem("block")?;
-
-    let devices = udev
-        .scan_devices()?
-        .filter_map(|dev| {
-            if dev.is_initialized() {
-                dev.devnode().map(|dn| dn.to_string_lossy().into_owned())
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-    Ok(devices)
-}
-
-fn get_devices_by_uuid(
-    udev_bcachefs: &HashMap<String, Vec<String>>,
-    uuid: Uuid,
-) -> anyhow::Result<Vec<(PathBuf, bch_sb_handle)>> {
-    let devices = {
-        if !udev_bcachefs.is_empty() {
-            let uuid_string = uuid.hyphenated().to_string();
-            if let Some(devices) = udev_bcachefs.get(&uuid_string) {
-                devices.clone()
-            } else {
-                Vec::new()
-            }
-        } else {
-            get_all_block_devnodes()?
-        }
-    };
-
-    Ok(get_super_blocks(uuid, &devices))
-}
-
-#[allow(clippy::type_complexity)]
-fn get_uuid_for_dev_node(
-    udev_bcachefs: &HashMap<String, Vec<String>>,
-    device: impl AsRef<Path>,
-) -> Result<(Option<Uuid>, Option<(PathBuf, bch_sb_handle)>)> {
-    let canonical = fs::canonicalize(device)?;
-
-    if !udev_bcachefs.is_empty() {
-        let dev_node_str = canonical.into_os_string().into_string().unwrap();
-
-        if udev_bcachefs.contains_key(&dev_node_str) && udev_bcachefs[&dev_node_str].len() == 1 {
-            let uuid_str = udev_bcachefs[&dev_node_str][0].clone();
-            return Ok((Some(Uuid::parse_str(&uuid_str)?), None));
-        }
-    } else {
-        return read_super_silent(&canonical).map_or(Ok((None, None)), |sb| {
-            Ok((Some(sb.sb().uuid()), Some((canonical, sb))))
-        });
-    }
-    Ok((None, None))
-}
+use super::logger::LogOpts;
 
 /// Mount a bcachefs filesystem by its UUID.
 #[derive(Parser, Debug)]
@@ -239,7 +48,8 @@ pub struct Cli {
     unlock_policy: UnlockPolicy,
 
     /// Device, or UUID=\<UUID\>
-    dev: String,
+    #[arg(required_unless_present_any = ["fstab_check", "all"])]
+    dev: Option<String>,
 
     /// Where the filesystem should be mounted. If not set, then the filesystem
     /// won't actually be mounted. But all steps preceeding mounting the
@@ -250,95 +60,174 @@ pub struct Cli {
     #[arg(short, default_value = "")]
     options: String,
 
-    /// Force color on/off. Autodetect tty is used to define default:
-    #[arg(short, long, action = clap::ArgAction::Set, default_value_t=stdout().is_terminal())]
-    colorize: bool,
+    /// Color output
+    #[arg(long, value_enum, default_value_t)]
+    color: ColorWhen,
 
-    /// Verbose mode
-    #[arg(short, long, action = clap::ArgAction::Count)]
-    verbose: u8,
-}
+    /// Force color on/off; superseded by --color
+    #[arg(short, long, action = clap::ArgAction::Set, hide = true)]
+    colorize: Option<bool>,
+
+    /// Don't mount anything: check the bcachefs entries in fstab (default
+    /// /etc/fstab) - that their devices are present and their options valid
+    #[arg(
+        long,
+        value_name = "FSTAB",
+        num_args = 0..=1,
+        default_missing_value = "/etc/fstab",
+        conflicts_with = "dev"
+    )]
+    fstab_check: Option<PathBuf>,
 
-fn devs_str_sbs_from_uuid(
-    udev_info: &HashMap<String, Vec<String>>,
-    uuid: &str,
-) -> anyhow::Result<(String, Vec<bch_sb_handle>)> {
-    debug!("enumerating devices with UUID {}", uuid);
+    /// Mount every bcachefs entry in /etc/fstab that isn't noauto or already
+    /// mounted, continuing past failures; -o options are added to each
+    /// entry's
+    #[arg(short, long, conflicts_with_all = ["dev", "fstab_check"])]
+    all: bool,
 
-    let devs_sbs = Uuid::parse_str(uuid).map(|uuid| get_devices_by_uuid(udev_info, uuid))??;
+    /// With --all: instead of fstab, mount every complete filesystem found on
+    /// the system, each at DIR/<label or UUID>
+    #[arg(long, value_name = "DIR", requires = "all")]
+    into: Option<PathBuf>,
 
-    let devs_str = devs_sbs
-        .iter()
-        .map(|(dev, _)| dev.to_str().unwrap())
-        .collect::<Vec<_>>()
-        .join(":");
+    /// Passed by mount(8) to mount helpers; ignored
+    #[arg(short = 'n', hide = true)]
+    _no_mtab: bool,
 
-    let sbs: Vec<bch_sb_handle> = devs_sbs.iter().map(|(_, sb)| *sb).collect();
+    /// Passed by mount(8) to mount helpers; ignored
+    #[arg(short = 's', hide = true)]
+    _sloppy: bool,
 
-    Ok((devs_str, sbs))
+    /// Passed by mount(8) to mount helpers; ignored
+    #[arg(short = 't', hide = true)]
+    _fstype: Option<String>,
+
+    #[command(flatten)]
+    log: LogOpts,
 }
 
-fn devs_str_sbs_from_device(
-    udev_info: &HashMap<String, Vec<String>>,
-    device: impl AsRef<Path>,
-) -> anyhow::Result<(String, Vec<bch_sb_handle>)> {
-    let (uuid, sb_info) = get_uuid_for_dev_node(udev_info, device)?;
-
-    match (uuid, sb_info) {
-        (Some(uuid), Some((path, sb))) => {
-            // If we have a super block, it implies we aren't using udev db.  If we only need
-            // 1 device to mount, we'll simply return it as we're done, else we'll use the uuid
-            // to walk through all the block devices.
-            debug!(
-                "number of devices in this FS = {}",
-                sb.sb().number_of_devices()
-            );
-            if sb.sb().number_of_devices() == 1 {
-                let dev = path.into_os_string().into_string().unwrap();
-                Ok((dev, vec![sb]))
-            } else {
-                devs_str_sbs_from_uuid(udev_info, &uuid.to_string())
+/// Check a filesystem specific mount option: the kernel ignores options it
+/// doesn't know, so typos would otherwise go unnoticed
+fn check_fs_option(o: &str) -> Result<()> {
+    let name = o.split('=').next().unwrap_or_default();
+    let lookup = |n: &str| {
+        let n = CString::new(n).unwrap_or_default();
+        unsafe { c::bch2_opt_lookup(n.as_ptr()) }
+    };
+
+    if lookup(name) < 0 && !name.strip_prefix("no").is_some_and(|n| lookup(n) >= 0) {
+        return Err(anyhow!("unknown option {}", name));
+    }
+
+    Opts::new()
+        .parse(o)
+        .map(|_| ())
+        .map_err(|e| anyhow!("{}: {}", o, e))
+}
+
+/// Returns (errors, warnings) for one fstab entry
+fn fstab_check_entry(udev_info: &device::UdevInfo, e: &FstabEntry) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    match device::find_devices(udev_info, &e.spec) {
+        Ok((_, sbs)) => {
+            let nr = sbs[0].sb().number_of_devices() as usize;
+
+            if sbs.len() < nr {
+                let msg = format!("only {} of {} member devices found", sbs.len(), nr);
+
+                if e.has_option("degraded") || e.has_option("very_degraded") {
+                    warnings.push(msg);
+                } else {
+                    errors.push(msg + " (and not mounted degraded)");
+                }
+            }
+
+            if device::sb_is_encrypted(&sbs[0]) {
+                warnings.push("encrypted: a passphrase will be needed at boot".into());
             }
         }
-        (Some(uuid), None) => devs_str_sbs_from_uuid(udev_info, &uuid.to_string()),
-        _ => Ok((String::new(), Vec::new())),
+        Err(err) => errors.push(format!("error finding devices: {}", err)),
+    }
+
+    if !std::path::Path::new(&e.file).is_dir() {
+        errors.push(format!("mountpoint {} is not a directory", e.file));
+    }
+
+    let (data, _) = mnt::parse_mount_options(&e.mntops);
+    for o in data.iter().flat_map(|d| d.split(',')) {
+        if let Err(err) = check_fs_option(o) {
+            errors.push(err.to_string());
+        }
     }
+
+    (errors, warnings)
 }
 
-fn cmd_mount_inner(opt: Cli) -> Result<()> {
-    // Grab the udev information once
-    let udev_info = udev_bcachefs_info()?;
+fn fstab_check(path: &Path) -> Result<()> {
+    let entries = mnt::read_fstab(path)?;
+    let udev_info = device::udev_bcachefs_info()?;
+    let mut nr_bad = 0;
 
-    let (devices, sbs) = if let Some(uuid) = opt.dev.strip_prefix("UUID=") {
-        devs_str_sbs_from_uuid(&udev_info, uuid)?
-    } else if let Some(uuid) = opt.dev.strip_prefix("OLD_BLKID_UUID=") {
-        devs_str_sbs_from_uuid(&udev_info, uuid)?
-    } else {
-        // If the device string contains ":" we will assume the user knows the entire list.
-        // If they supply a single device it could be either the FS only has 1 device or it's
-        // only 1 of a number of devices which are part of the FS. This appears to be the case
-        // when we get called during fstab mount processing and the fstab specifies a UUID.
-        if opt.dev.contains(':') {
-            let sbs = opt
-                .dev
-                .split(':')
-                .map(read_super_silent)
-                .collect::<Result<Vec<_>>>()?;
-
-            (opt.dev, sbs)
-        } else {
-            devs_str_sbs_from_device(&udev_info, Path::new(&opt.dev))?
+    if entries.is_empty() {
+        println!("no bcachefs entries in {}", path.display());
+    }
+
+    for e in &entries {
+        let (errors, warnings) = fstab_check_entry(&udev_info, e);
+
+        println!(
+            "{} {}: {}",
+            e.spec,
+            e.file,
+            if errors.is_empty() { "ok" } else { "error" }
+        );
+        for i in &errors {
+            println!("  error: {}", i);
         }
-    };
+        for i in &warnings {
+            println!("  warning: {}", i);
+        }
+
+        nr_bad += usize::from(!errors.is_empty());
+    }
+
+    ensure!(
+        nr_bad == 0,
+        "{} of {} entries have errors",
+        nr_bad,
+        entries.len()
+    );
+    Ok(())
+}
+
+/// Unlock if necessary, and mount one filesystem
+fn mount_one(
+    opt: &Cli,
+    udev_info: &device::UdevInfo,
+    dev: &str,
+    mountpoint: Option<&Path>,
+    options: &str,
+) -> Result<()> {
+    let (devices, sbs) = device::find_devices(udev_info, dev)?;
 
     ensure!(!sbs.is_empty(), "No device(s) to mount specified");
 
+    if let Some(image) = devices.split(':').find(|d| device::is_image_file(d)) {
+        bail!(
+            "{image} is an image file; the kernel can only mount block devices \
+             (attach it with losetup, or use bcachefs fusemount)"
+        );
+    }
+
     let first_sb = sbs[0];
     let uuid = first_sb.sb().uuid();
 
-    if unsafe { bcachefs::bch2_sb_is_encrypted(first_sb.sb) } {
+    if device::sb_is_encrypted(&first_sb) {
         let _key_handle: KeyHandle = KeyHandle::new_from_search(&uuid).or_else(|_| {
             opt.passphrase_file
+                .as_ref()
                 .and_then(|path| match Passphrase::new_from_file(&first_sb, path) {
                     Ok(p) => Some(KeyHandle::new(&first_sb, &p)),
                     Err(e) => {
@@ -353,26 +242,192 @@ fn cmd_mount_inner(opt: Cli) -> Result<()> {
         })?;
     }
 
-    if let Some(mountpoint) = opt.mountpoint {
+    let options = &mnt::options_or_default(options, &sbs[0]);
+
+    // The kernel only says EINVAL for a bad target:
+    let options = &match Targets::from_sb(&first_sb) {
+        Ok(mut targets) => {
+            devices.split(':').for_each(|d| targets.add_device(d));
+            targets.check_opts(options)?
+        }
+        Err(_) => options.to_owned(),
+    };
+
+    if let Some(mountpoint) = mountpoint {
         info!(
             "mounting with params: device: {}, target: {}, options: {}",
             devices,
             mountpoint.to_string_lossy(),
-            &opt.options
+            options
         );
 
-        let (data, mountflags) = parse_mount_options(&opt.options);
-        mount_inner(devices, mountpoint, "bcachefs", mountflags, data)
+        let perms = mnt::MountpointPerms::from_options(options)?;
+        let (data, mountflags) = mnt::parse_mount_options(options);
+        mnt::mount(devices, mountpoint, "bcachefs", mountflags, data)
+            .map_err(|e| mnt::check_kernel_version(&first_sb).err().unwrap_or(e))?;
+        perms.apply(mountpoint)
     } else {
         info!(
             "would mount with params: device: {}, options: {}",
-            devices, &opt.options
+            devices, options
         );
 
         Ok(())
     }
 }
 
+/// Something for --all to mount
+struct MountTarget {
+    spec:    String,
+    target:  PathBuf,
+    options: String,
+    uuid:    Option<Uuid>,
+}
+
+/// Every filesystem on the system with all its member devices present: the
+/// device sets known to udev, or found by probing every block device
+fn complete_filesystems(udev_info: &device::UdevInfo) -> Result<Vec<(Uuid, String)>> {
+    let uuids: BTreeSet<Uuid> = if !udev_info.is_empty() {
+        udev_info
+            .keys()
+            .filter_map(|k| Uuid::parse_str(k).ok())
+            .collect()
+    } else {
+        device::read_supers(&device::get_all_block_devnodes()?, device::probe_timeout())
+            .into_iter()
+            .filter_map(|(_, sb)| sb.ok())
+            .map(|sb| sb.sb().uuid())
+            .collect()
+    };
+
+    let mut ret = Vec::new();
+    for uuid in uuids {
+        let devs = device::get_devices_by_uuid(udev_info, uuid)?;
+        let Some((_, sb)) = devs.first() else {
+            continue;
+        };
+
+        if devs.len() < sb.sb().number_of_devices() as usize {
+            info!(
+                "{}: only {} of {} devices found, skipping",
+                uuid,
+                devs.len(),
+                sb.sb().number_of_devices()
+            );
+            continue;
+        }
+
+        let label = String::from_utf8_lossy(&sb.sb().label)
+            .trim_end_matches('\0')
+            .replace('/', "_");
+        ret.push((uuid, label));
+    }
+
+    Ok(ret)
+}
+
+fn mount_all(opt: &Cli, udev_info: &device::UdevInfo) -> Result<()> {
+    let targets: Vec<MountTarget> = if let Some(dir) = &opt.into {
+        complete_filesystems(udev_info)?
+            .into_iter()
+            .map(|(uuid, label)| MountTarget {
+                spec:    format!("UUID={}", uuid),
+                target:  dir.join(if label.is_empty() {
+                    uuid.to_string()
+                } else {
+                    label
+                }),
+                options: opt.options.clone(),
+                uuid:    Some(uuid),
+            })
+            .collect()
+    } else {
+        mnt::read_fstab("/etc/fstab")?
+            .into_iter()
+            .filter(|e| !e.has_option("noauto"))
+            .map(|e| MountTarget {
+                spec:    e.spec,
+                target:  PathBuf::from(e.file),
+                options: [e.mntops, opt.options.clone()]
+                    .into_iter()
+                    .filter(|o| !o.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                uuid:    None,
+            })
+            .collect()
+    };
+
+    let mounted: BTreeSet<PathBuf> = fs::read_to_string("/proc/self/mounts")?
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(|t| PathBuf::from(mnt::fstab_unescape(t)))
+        .collect();
+
+    let (mut nr_mounted, mut nr_skipped, mut nr_failed) = (0, 0, 0);
+
+    for t in &targets {
+        let already = mounted.contains(&t.target)
+            || t.uuid
+                .is_some_and(|u| Path::new(&format!("/sys/fs/bcachefs/{}", u)).exists());
+
+        if already {
+            println!("{} on {}: already mounted", t.spec, t.target.display());
+            nr_skipped += 1;
+            continue;
+        }
+
+        let ret = if opt.into.is_some() {
+            fs::create_dir_all(&t.target).map_err(anyhow::Error::from)
+        } else {
+            Ok(())
+        }
+        .and_then(|_| mount_one(opt, udev_info, &t.spec, Some(&t.target), &t.options));
+
+        match ret {
+            Ok(()) => {
+                println!("{} on {}: mounted", t.spec, t.target.display());
+                nr_mounted += 1;
+            }
+            Err(e) => {
+                println!("{} on {}: {}", t.spec, t.target.display(), e);
+                nr_failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} mounted, {} already mounted, {} failed",
+        nr_mounted, nr_skipped, nr_failed
+    );
+    ensure!(nr_failed == 0, "{} filesystems failed to mount", nr_failed);
+    Ok(())
+}
+
+fn cmd_mount_inner(opt: Cli) -> Result<()> {
+    opt.log.init()?;
+
+    if let Some(path) = &opt.fstab_check {
+        return fstab_check(path);
+    }
+
+    // Grab the udev information once
+    let udev_info = device::udev_bcachefs_info()?;
+
+    if opt.all {
+        return mount_all(&opt, &udev_info);
+    }
+
+    let dev = opt.dev.clone().unwrap_or_default();
+    mount_one(
+        &opt,
+        &udev_info,
+        &dev,
+        opt.mountpoint.as_deref(),
+        &opt.options,
+    )
+}
+
 pub fn mount(mut argv: Vec<String>, symlink_cmd: Option<&str>) -> i32 {
     // If the bcachefs tool is being called as "bcachefs mount dev ..." (as opposed to via a
     // symlink like "/usr/sbin/mount.bcachefs dev ...", then we need to pop the 0th argument
@@ -383,17 +438,10 @@ pub fn mount(mut argv: Vec<String>, symlink_cmd: Option<&str>) -> i32 {
 
     let opt = Cli::parse_from(argv);
 
-    // @TODO : more granular log levels via mount option
-    log::set_max_level(match opt.verbose {
-        0 => LevelFilter::Warn,
-        1 => LevelFilter::Trace,
-        2_u8..=u8::MAX => todo!(),
-    });
-
-    colored::control::set_override(opt.colorize);
+    output::set_color(opt.color, opt.colorize);
     if let Err(e) = cmd_mount_inner(opt) {
         error!("Fatal error: {}", e);
-        1
+        ::bcachefs::error::exit_code(&e)
     } else {
         info!("Successfully mounted");
         0
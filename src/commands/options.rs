@@ -0,0 +1,230 @@
+use std::{
+    ffi::CStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use bcachefs::device;
+use bch_bindgen::{
+    c,
+    opts::{opt_id, opt_table},
+    printbuf_to_string,
+};
+use clap::Parser;
+use log::error;
+
+use super::logger::LogOpts;
+use crate::wrappers::handle::BcachefsHandle;
+
+/// List filesystem, device and inode options: their types, allowed values and
+/// defaults, and when they can be set
+///
+/// Generated from the options table in libbcachefs, so it's always in sync with
+/// what format, mount and set-option accept. Given a device or mountpoint, also
+/// shows each option's current value.
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Show current values for this filesystem: a member device if it's not
+    /// mounted, or a mountpoint
+    #[arg(short, long, value_hint = clap::ValueHint::AnyPath)]
+    fs: Option<PathBuf>,
+
+    /// Only show these options
+    names: Vec<String>,
+
+    #[command(flatten)]
+    log: LogOpts,
+}
+
+fn has_flag(opt: &c::bch_option, flag: c::opt_flags) -> bool {
+    opt.flags.0 & flag.0 != 0
+}
+
+fn cstr(s: *const std::os::raw::c_char) -> Option<String> {
+    (!s.is_null()).then(|| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+}
+
+fn opt_name(opt: &c::bch_option) -> String {
+    cstr(opt.attr.name).unwrap_or_default()
+}
+
+fn opt_choices(opt: &c::bch_option) -> Vec<String> {
+    let mut ret = Vec::new();
+
+    if !opt.choices.is_null() {
+        for i in 0.. {
+            match cstr(unsafe { *opt.choices.add(i) }) {
+                Some(s) => ret.push(s),
+                None => break,
+            }
+        }
+    }
+    ret
+}
+
+fn opt_type(opt: &c::bch_option) -> String {
+    use c::opt_type::*;
+
+    match opt.type_ {
+        BCH_OPT_BOOL => "boolean".to_owned(),
+        BCH_OPT_UINT if has_flag(opt, c::opt_flags::OPT_HUMAN_READABLE) => {
+            format!("size, {} to {}", opt.min, opt.max)
+        }
+        BCH_OPT_UINT if opt.max == u64::MAX => format!("integer, at least {}", opt.min),
+        BCH_OPT_UINT => format!("integer, {} to {}", opt.min, opt.max),
+        BCH_OPT_STR => format!("one of {}", opt_choices(opt).join(", ")),
+        BCH_OPT_FN => cstr(opt.hint).unwrap_or_else(|| "string".to_owned()),
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// Where an option can be set
+fn opt_scope(opt: &c::bch_option) -> String {
+    let scope: Vec<_> = [
+        (c::opt_flags::OPT_FS, "filesystem"),
+        (c::opt_flags::OPT_DEVICE, "device"),
+        (c::opt_flags::OPT_INODE, "inode"),
+    ]
+    .into_iter()
+    .filter(|(f, _)| has_flag(opt, *f))
+    .map(|(_, s)| s)
+    .collect();
+
+    let when: Vec<_> = [
+        (c::opt_flags::OPT_FORMAT, "format"),
+        (c::opt_flags::OPT_MOUNT, "mount"),
+        (c::opt_flags::OPT_RUNTIME, "runtime"),
+    ]
+    .into_iter()
+    .filter(|(f, _)| has_flag(opt, *f))
+    .map(|(_, s)| s)
+    .collect();
+
+    if when.is_empty() {
+        scope.join(", ")
+    } else {
+        format!("{}; set at {}", scope.join(", "), when.join(", "))
+    }
+}
+
+fn opt_val_to_string(opt: &c::bch_option, sb: *mut c::bch_sb, v: u64) -> String {
+    if opt.type_ == c::opt_type::BCH_OPT_BOOL {
+        return if v != 0 { "yes" } else { "no" }.to_owned();
+    }
+
+    printbuf_to_string(|buf| unsafe {
+        c::bch2_opt_to_text(buf, std::ptr::null_mut(), sb, opt, v, 0)
+    })
+}
+
+/// Current option values, from sysfs for a mounted filesystem or from the
+/// superblock for an unmounted one
+enum Current {
+    Mounted(PathBuf),
+    Offline(c::bch_sb_handle, c::bch_opts),
+}
+
+impl Current {
+    fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            let fs = unsafe { BcachefsHandle::open(path) };
+            let sysfs = PathBuf::from(format!("/sys/fs/bcachefs/{}/options", fs.uuid()));
+
+            if !sysfs.is_dir() {
+                bail!("{}: no options in sysfs", path.display());
+            }
+            return Ok(Current::Mounted(sysfs));
+        }
+
+        let sb = device::read_super_silent(path)?;
+        let mut opts = c::bch_opts::default();
+        unsafe { c::bch2_opts_from_sb(&mut opts, sb.sb) };
+        Ok(Current::Offline(sb, opts))
+    }
+
+    fn get(&self, idx: usize, opt: &c::bch_option) -> String {
+        match self {
+            Current::Mounted(sysfs) => match fs::read_to_string(sysfs.join(opt_name(opt))) {
+                // choices are shown as "none [lz4] gzip zstd":
+                Ok(v) => match (v.find('['), v.find(']')) {
+                    (Some(start), Some(end)) if start < end => v[start + 1..end].to_owned(),
+                    _ => v.trim().to_owned(),
+                },
+                Err(_) => "-".to_owned(),
+            },
+            Current::Offline(sb, opts) => {
+                if unsafe { c::bch2_opt_defined_by_id(opts, opt_id(idx)) } {
+                    let v = unsafe { c::bch2_opt_get_by_id(opts, opt_id(idx)) };
+                    opt_val_to_string(opt, sb.sb, v)
+                } else if !has_flag(opt, c::opt_flags::OPT_FS) {
+                    "(per device)".to_owned()
+                } else {
+                    "(not stored in the superblock)".to_owned()
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        if let Current::Offline(sb, _) = self {
+            unsafe { c::bch2_free_super(sb) };
+        }
+    }
+}
+
+fn cmd_options_inner(opt: &Cli) -> Result<()> {
+    opt.log.init()?;
+
+    let table = opt_table();
+
+    for name in &opt.names {
+        if !table.iter().any(|o| opt_name(o) == *name) {
+            bail!("unknown option {name}");
+        }
+    }
+
+    let current = opt.fs.as_deref().map(Current::open).transpose()?;
+
+    for (idx, o) in table.iter().enumerate() {
+        let name = opt_name(o);
+
+        if !opt.names.is_empty() && !opt.names.contains(&name) {
+            continue;
+        }
+
+        println!("{name}");
+        if let Some(help) = cstr(o.help) {
+            for line in help.lines() {
+                println!("  {line}");
+            }
+        }
+        println!("  type:     {}", opt_type(o));
+        println!("  scope:    {}", opt_scope(o));
+
+        let default = unsafe { c::bch2_opt_default_by_id(opt_id(idx)) };
+        println!(
+            "  default:  {}",
+            opt_val_to_string(o, std::ptr::null_mut(), default)
+        );
+
+        if let Some(current) = &current {
+            println!("  current:  {}", current.get(idx, o));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn options(argv: Vec<String>) -> i32 {
+    let opt = Cli::parse_from(argv);
+
+    if let Err(e) = cmd_options_inner(&opt) {
+        error!("{e}");
+        ::bcachefs::error::exit_code(&e)
+    } else {
+        0
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use bcachefs::device;
+use bch_bindgen::sb_parse::{SbParseError, Superblock, BCH_SB_SECTOR};
+use clap::Parser;
+use log::error;
+use uuid::Uuid;
+
+use super::logger::LogOpts;
+
+/// Identify a bcachefs member device, reading only its superblock
+///
+/// With --udev, prints KEY=value pairs for udev rules, e.g.
+///
+///   IMPORT{program}="/usr/sbin/bcachefs probe --udev $devnode"
+///
+/// With --udisks, also the hints udisks2 (and so file managers) use, so that a
+/// multi device filesystem shows up once: only its lowest numbered member is
+/// shown, and the others get UDISKS_IGNORE=1
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Print udev properties (ID_FS_*, BCACHEFS_*)
+    #[arg(long)]
+    udev: bool,
+
+    /// Print udev properties, with the hints for udisks2 (UDISKS_*)
+    #[arg(long, conflicts_with = "udev")]
+    udisks: bool,
+
+    #[arg(value_hint = clap::ValueHint::FilePath)]
+    device: PathBuf,
+
+    #[command(flatten)]
+    log: LogOpts,
+}
+
+/// Read the primary superblock: the header first, then the rest if its fields
+/// don't fit
+fn read_sb(dev: &Path) -> Result<Vec<u8>> {
+    let mut f = File::open(dev)?;
+    let mut buf = vec![0; 4096];
+
+    loop {
+        f.seek(SeekFrom::Start(BCH_SB_SECTOR << 9))?;
+        f.read_exact(&mut buf)?;
+
+        match Superblock::parse(&buf) {
+            Err(SbParseError::TooShort { need, .. }) if need > buf.len() => {
+                buf.resize((need + 4095) & !4095, 0);
+            }
+            Err(e) => return Err(e.into()),
+            Ok(_) => return Ok(buf),
+        }
+    }
+}
+
+fn version_str(v: u16) -> String {
+    format!("{}.{}", v >> 10, v & 1023)
+}
+
+/// Label with whitespace and unsafe characters replaced, like blkid's
+/// ID_FS_LABEL
+fn label_safe(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_whitespace() || c.is_control() || c == '"' || c == '\\' {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Encoding for ID_FS_LABEL_ENC, like blkid_encode_string()
+fn label_enc(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+                c.to_string()
+            } else {
+                format!("\\x{:02x}", c as u8)
+            }
+        })
+        .collect()
+}
+
+/// How many members of the filesystem udev knows about, including this one;
+/// None if udev isn't available
+fn devices_found(dev: &Path, uuid: &Uuid) -> Option<usize> {
+    let udev_info = device::udev_bcachefs_info().ok()?;
+    if udev_info.is_empty() {
+        return None;
+    }
+
+    let mut found: BTreeSet<PathBuf> = udev_info
+        .get(&uuid.hyphenated().to_string())
+        .into_iter()
+        .flatten()
+        .filter_map(|d| fs::canonicalize(d).ok())
+        .collect();
+
+    // When run from a udev rule, this device isn't in the database yet:
+    if let Ok(dev) = fs::canonicalize(dev) {
+        found.insert(dev);
+    }
+
+    Some(found.len())
+}
+
+fn cmd_probe_inner(opt: &Cli) -> Result<()> {
+    opt.log.init()?;
+
+    let buf = read_sb(&opt.device)?;
+    let sb = Superblock::parse(&buf)?;
+
+    let uuid = Uuid::from_bytes(sb.user_uuid());
+    let label = String::from_utf8_lossy(sb.label()).into_owned();
+    let members: Vec<_> = sb
+        .members()?
+        .ok_or_else(|| anyhow!("superblock has no members section"))?
+        .filter(|m| m.exists())
+        .collect();
+    let dev_uuid = members
+        .iter()
+        .find(|m| m.idx() == sb.dev_idx() as usize)
+        .map(|m| Uuid::from_bytes(m.uuid()));
+    let nr_devices = members.len();
+    let found = devices_found(&opt.device, &uuid);
+    // the member a multi device filesystem is shown as, and mounted from:
+    let primary = members.iter().map(|m| m.idx()).min() == Some(sb.dev_idx() as usize);
+
+    if opt.udev || opt.udisks {
+        println!("ID_FS_TYPE=bcachefs");
+        println!("ID_FS_USAGE=filesystem");
+        println!("ID_FS_VERSION={}", version_str(sb.version()));
+        println!("ID_FS_UUID={}", uuid);
+        println!("ID_FS_UUID_ENC={}", uuid);
+        if let Some(dev_uuid) = dev_uuid {
+            println!("ID_FS_UUID_SUB={}", dev_uuid);
+            println!("ID_FS_UUID_SUB_ENC={}", dev_uuid);
+        }
+        if !label.is_empty() {
+            println!("ID_FS_LABEL={}", label_safe(&label));
+            println!("ID_FS_LABEL_ENC={}", label_enc(&label));
+        }
+        println!("BCACHEFS_DEV_IDX={}", sb.dev_idx());
+        println!("BCACHEFS_NR_DEVICES={}", nr_devices);
+        if let Some(found) = found {
+            println!("BCACHEFS_DEVICES_FOUND={}", found);
+            println!("BCACHEFS_COMPLETE={}", (found >= nr_devices) as u8);
+        }
+
+        if opt.udisks {
+            println!(
+                "BCACHEFS_ROLE={}",
+                if primary { "primary" } else { "member" }
+            );
+            if !primary {
+                println!("UDISKS_IGNORE=1");
+            }
+            if !label.is_empty() {
+                println!("UDISKS_NAME={}", label_safe(&label));
+            }
+        }
+    } else {
+        println!("{}: bcachefs", opt.device.display());
+        println!("  UUID:      {}", uuid);
+        println!("  Label:     {}", label);
+        print!("  Member:    {}", sb.dev_idx());
+        match dev_uuid {
+            Some(dev_uuid) => println!(" ({})", dev_uuid),
+            None => println!(),
+        }
+        println!(
+            "  Devices:   {}{}",
+            nr_devices,
+            if nr_devices > 1 && primary {
+                " (this is the primary member)"
+            } else {
+                ""
+            }
+        );
+        println!("  Version:   {}", version_str(sb.version()));
+        match found {
+            Some(found) => println!(
+                "  Complete:  {} ({}/{} devices found)",
+                if found >= nr_devices { "yes" } else { "no" },
+                found,
+                nr_devices
+            ),
+            None => println!("  Complete:  unknown (udev not available)"),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn probe(argv: Vec<String>) -> i32 {
+    let opt = Cli::parse_from(argv);
+
+    if let Err(e) = cmd_probe_inner(&opt) {
+        // udev treats any output as properties; the log goes to stderr only
+        error!("{}: {}", opt.device.display(), e);
+        return ::bcachefs::error::exit_code(&e);
+    }
+
+    0
+}
@@ -1,14 +1,33 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
-use bch_bindgen::c::BCH_SUBVOL_SNAPSHOT_RO;
-use clap::{Parser, Subcommand};
+use ::bcachefs::device;
+use anyhow::{anyhow, Context, Result};
+use bch_bindgen::{
+    bcachefs,
+    btree::{BtreeIter, BtreeIterFlags, BtreeTrans},
+    c,
+    c::BCH_SUBVOL_SNAPSHOT_RO,
+    fs::Fs,
+    opts::Opts,
+    POS_MIN,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::error;
 
+use super::logger::LogOpts;
 use crate::wrappers::handle::BcachefsHandle;
 
 #[derive(Parser, Debug)]
 pub struct Cli {
     #[command(subcommand)]
     subcommands: Subcommands,
+
+    #[command(flatten)]
+    log: LogOpts,
 }
 
 /// Subvolumes-related commands
@@ -34,26 +53,330 @@ enum Subcommands {
         source:    Option<PathBuf>,
         dest:      PathBuf,
     },
+
+    /// Print the snapshot tree: every snapshot node, its parent and children,
+    /// depth, and the subvolume pointing to it
+    ///
+    /// A mounted filesystem is read through debugfs, which must be mounted.
+    Tree {
+        #[arg(short, long, value_enum, default_value_t)]
+        format: TreeFormat,
+
+        /// Mountpoint, or the devices of an unmounted filesystem
+        #[arg(required = true)]
+        filesystem: Vec<PathBuf>,
+    },
 }
 
-pub fn subvolume(argv: Vec<String>) -> i32 {
-    let cli = Cli::parse_from(argv);
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum TreeFormat {
+    #[default]
+    Ascii,
+    /// Graphviz, e.g. for `| dot -Tsvg`
+    Dot,
+}
+
+/// A node in the snapshots btree
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct SnapshotNode {
+    id:      u32,
+    parent:  u32,
+    subvol:  u32,
+    tree:    u32,
+    depth:   u32,
+    deleted: bool,
+}
+
+/// A key in the subvolumes btree
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct SubvolInfo {
+    id:              u32,
+    snapshot:        u32,
+    inode:           u64,
+    /// The subvolume this one is a snapshot of; 0 if it isn't one
+    creation_parent: u32,
+}
+
+#[derive(Default)]
+struct SnapshotTree {
+    nodes:   BTreeMap<u32, SnapshotNode>,
+    subvols: BTreeMap<u32, SubvolInfo>,
+}
+
+/// Split a key as printed by bch2_bkey_val_to_text() into its type, the offset
+/// of its position, and the tokens of its value
+fn parse_key_line(line: &str) -> Option<(&str, u64, Vec<&str>)> {
+    let (key, val) = line.split_once(": ")?;
+    let key: Vec<_> = key.split_whitespace().collect();
+    let i = key.iter().position(|t| *t == "type")?;
+    let offset = key.get(i + 2)?.split(':').nth(1)?.parse().ok()?;
+
+    Some((key.get(i + 1)?, offset, val.split_whitespace().collect()))
+}
+
+/// The `n`th number after `name` in a key's value, e.g. `children 0 0`
+fn val_field(val: &[&str], name: &str, n: usize) -> Option<u64> {
+    let i = val.iter().position(|t| *t == name)?;
+    val.get(i + 1 + n)?.parse().ok()
+}
+
+impl SnapshotTree {
+    fn parse_snapshot(id: u64, val: &[&str]) -> Option<SnapshotNode> {
+        Some(SnapshotNode {
+            id:      id as u32,
+            parent:  val_field(val, "parent", 0)? as u32,
+            subvol:  val_field(val, "subvol", 0)? as u32,
+            tree:    val_field(val, "tree", 0)? as u32,
+            depth:   val_field(val, "depth", 0).unwrap_or(0) as u32,
+            deleted: val_field(val, "deleted", 0)? != 0,
+        })
+    }
+
+    fn parse_subvol(id: u64, val: &[&str]) -> Option<SubvolInfo> {
+        Some(SubvolInfo {
+            id:              id as u32,
+            snapshot:        val_field(val, "id", 0)? as u32,
+            inode:           val_field(val, "root", 0)?,
+            creation_parent: val_field(val, "creation_parent", 0).unwrap_or(0) as u32,
+        })
+    }
+
+    /// From the snapshots and subvolumes btrees, as printed in debugfs
+    fn parse(snapshots: &str, subvolumes: &str) -> Self {
+        let mut tree = Self::default();
+
+        for (ty, id, val) in snapshots.lines().filter_map(parse_key_line) {
+            if let Some(n) = (ty == "snapshot")
+                .then(|| Self::parse_snapshot(id, &val))
+                .flatten()
+            {
+                tree.nodes.insert(n.id, n);
+            }
+        }
+
+        for (ty, id, val) in subvolumes.lines().filter_map(parse_key_line) {
+            if let Some(s) = (ty == "subvolume")
+                .then(|| Self::parse_subvol(id, &val))
+                .flatten()
+            {
+                tree.subvols.insert(s.id, s);
+            }
+        }
+
+        tree
+    }
+
+    fn read_mounted(path: &Path) -> Result<Self> {
+        let uuid = unsafe { BcachefsHandle::open(path) }.uuid();
+        let debugfs = PathBuf::from(format!("/sys/kernel/debug/bcachefs/{uuid}/btrees"));
+        let read = |btree: &str| {
+            let path = debugfs.join(btree).join("keys");
+            fs::read_to_string(&path)
+                .with_context(|| format!("reading {} (is debugfs mounted?)", path.display()))
+        };
+
+        Ok(Self::parse(&read("snapshots")?, &read("subvolumes")?))
+    }
+
+    fn read_offline(devices: &[PathBuf]) -> Result<Self> {
+        let opts = Opts::new()
+            .nochanges(true)
+            .read_only(true)
+            .norecovery(true)
+            .degraded(true)
+            .very_degraded(true)
+            .errors(bcachefs::bch_error_actions::BCH_ON_ERROR_continue as u8);
+
+        device::check_members(devices)?;
+        let fs = Fs::open(devices, opts.build())?;
+        let trans = BtreeTrans::new(&fs);
+        let mut tree = Self::default();
+
+        /* older versions have shorter values: */
+        let val_bytes =
+            |k: &c::bkey| (k.u64s as usize * 8).saturating_sub(std::mem::size_of::<c::bkey>());
+
+        let mut iter = BtreeIter::new(
+            &trans,
+            bcachefs::btree_id::BTREE_ID_snapshots,
+            POS_MIN,
+            BtreeIterFlags::PREFETCH,
+        );
+        while let Some(k) = iter.peek_and_restart()? {
+            if k.k.type_ == c::bch_bkey_type::KEY_TYPE_snapshot as u8 {
+                let v = unsafe { &*(k.v as *const c::bch_val).cast::<c::bch_snapshot>() };
+                let n = SnapshotNode {
+                    id:      k.k.p.offset as u32,
+                    parent:  v.parent,
+                    subvol:  v.subvol,
+                    tree:    v.tree,
+                    depth:   if val_bytes(k.k) > 24 { v.depth } else { 0 },
+                    deleted: v.flags & 1 != 0,
+                };
+                tree.nodes.insert(n.id, n);
+            }
+            iter.advance();
+        }
+        drop(iter);
+
+        let mut iter = BtreeIter::new(
+            &trans,
+            bcachefs::btree_id::BTREE_ID_subvolumes,
+            POS_MIN,
+            BtreeIterFlags::PREFETCH,
+        );
+        while let Some(k) = iter.peek_and_restart()? {
+            if k.k.type_ == c::bch_bkey_type::KEY_TYPE_subvolume as u8 {
+                let v = unsafe { &*(k.v as *const c::bch_val).cast::<c::bch_subvolume>() };
+                let s = SubvolInfo {
+                    id:              k.k.p.offset as u32,
+                    snapshot:        v.snapshot,
+                    inode:           v.inode,
+                    creation_parent: if val_bytes(k.k) > 16 {
+                        v.creation_parent
+                    } else {
+                        0
+                    },
+                };
+                tree.subvols.insert(s.id, s);
+            }
+            iter.advance();
+        }
+
+        Ok(tree)
+    }
+
+    /// Children of each node, newest (lowest ID) last
+    fn children(&self) -> BTreeMap<u32, Vec<u32>> {
+        let mut children: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+        for n in self.nodes.values().rev() {
+            children.entry(n.parent).or_default().push(n.id);
+        }
+        children
+    }
+
+    /// Nodes without a parent, including nodes whose parent is missing
+    fn roots(&self) -> Vec<u32> {
+        self.nodes
+            .values()
+            .rev()
+            .filter(|n| n.parent == 0 || !self.nodes.contains_key(&n.parent))
+            .map(|n| n.id)
+            .collect()
+    }
+
+    fn label(&self, id: u32, sep: &str) -> String {
+        let n = &self.nodes[&id];
+        let mut label = vec![format!("{id}"), format!("depth {}", n.depth)];
+
+        if n.subvol != 0 {
+            label.push(format!("subvol {}", n.subvol));
+
+            if let Some(s) = self.subvols.get(&n.subvol) {
+                label.push(format!("root inode {}", s.inode));
+                if s.creation_parent != 0 {
+                    label.push(format!("snapshot of subvol {}", s.creation_parent));
+                }
+            }
+        }
+        if n.deleted {
+            label.push("deleted".to_owned());
+        }
+        if n.parent != 0 && !self.nodes.contains_key(&n.parent) {
+            label.push(format!("parent {} missing", n.parent));
+        }
+
+        label.join(sep)
+    }
+
+    fn to_ascii(&self) -> String {
+        let children = self.children();
+        let mut lines = Vec::new();
+
+        for root in self.roots() {
+            lines.push(format!("snapshot tree {}", self.nodes[&root].tree));
+
+            /* not recursive: snapshot trees can be very deep */
+            let mut stack = vec![(root, String::new(), None)];
+            while let Some((id, prefix, last)) = stack.pop() {
+                let (branch, child_prefix) = match last {
+                    None => ("", prefix.clone()),
+                    Some(true) => ("└─ ", format!("{prefix}   ")),
+                    Some(false) => ("├─ ", format!("{prefix}│  ")),
+                };
+                lines.push(format!("{prefix}{branch}{}", self.label(id, " ")));
+
+                let kids = children.get(&id).map_or(&[][..], |k| k.as_slice());
+                for (i, &child) in kids.iter().enumerate().rev() {
+                    stack.push((child, child_prefix.clone(), Some(i + 1 == kids.len())));
+                }
+            }
+        }
+
+        lines.iter().map(|l| format!("{l}\n")).collect()
+    }
+
+    fn to_dot(&self) -> String {
+        let mut out = String::from("digraph snapshots {\n\tnode [shape=box];\n");
+
+        for n in self.nodes.values().rev() {
+            let style = match (n.deleted, n.subvol != 0) {
+                (true, _) => " style=dashed",
+                (false, true) => " style=bold",
+                (false, false) => "",
+            };
+            out += &format!(
+                "\t\"{}\" [label=\"{}\"{style}];\n",
+                n.id,
+                self.label(n.id, "\\n")
+            );
+            if self.nodes.contains_key(&n.parent) {
+                out += &format!("\t\"{}\" -> \"{}\";\n", n.parent, n.id);
+            }
+        }
+
+        out + "}\n"
+    }
+}
+
+fn cmd_tree(format: TreeFormat, filesystem: &[PathBuf]) -> Result<()> {
+    let tree = match filesystem {
+        [path] if path.is_dir() => SnapshotTree::read_mounted(path)?,
+        devices => SnapshotTree::read_offline(devices)?,
+    };
+
+    if tree.nodes.is_empty() {
+        return Err(anyhow!("no snapshot nodes found"));
+    }
+
+    match format {
+        TreeFormat::Ascii => print!("{}", tree.to_ascii()),
+        TreeFormat::Dot => print!("{}", tree.to_dot()),
+    }
+    Ok(())
+}
+
+fn cmd_subvolume_inner(cli: Cli) -> Result<()> {
+    cli.log.init()?;
 
     match cli.subcommands {
         Subcommands::Create { targets } => {
             for target in targets {
                 if let Some(dirname) = target.parent() {
                     let fs = unsafe { BcachefsHandle::open(dirname) };
-                    fs.create_subvolume(target)
-                        .expect("Failed to create the subvolume");
+                    fs.create_subvolume(&target).with_context(|| {
+                        format!("Failed to create the subvolume {}", target.display())
+                    })?;
                 }
             }
         }
         Subcommands::Delete { target } => {
             if let Some(dirname) = target.parent() {
                 let fs = unsafe { BcachefsHandle::open(dirname) };
-                fs.delete_subvolume(target)
-                    .expect("Failed to delete the subvolume");
+                fs.delete_subvolume(&target).with_context(|| {
+                    format!("Failed to delete the subvolume {}", target.display())
+                })?;
             }
         }
         Subcommands::Snapshot {
@@ -76,13 +399,77 @@ pub fn subvolume(argv: Vec<String>) -> i32 {
                     } else {
                         0x0
                     },
-                    source,
-                    dest,
+                    source.as_ref(),
+                    &dest,
                 )
-                .expect("Failed to snapshot the subvolume");
+                .with_context(|| {
+                    format!("Failed to snapshot the subvolume to {}", dest.display())
+                })?;
             }
         }
+        Subcommands::Tree { format, filesystem } => cmd_tree(format, &filesystem)?,
+    }
+
+    Ok(())
+}
+
+pub fn subvolume(argv: Vec<String>) -> i32 {
+    let cli = Cli::parse_from(argv);
+
+    if let Err(e) = cmd_subvolume_inner(cli) {
+        error!("{:#}", e);
+        ::bcachefs::error::exit_code(&e)
+    } else {
+        0
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNAPSHOTS: &str = "\
+u64s 11 type snapshot 0:4294967293:0 len 0 ver 0: is_subvol 1 deleted 0 parent 4294967295 children          0          0 subvol 2 tree 1 depth 1 skiplist 4294967295 0 0
+u64s 11 type snapshot 0:4294967294:0 len 0 ver 0: is_subvol 1 deleted 0 parent 4294967295 children          0          0 subvol 1 tree 1 depth 1 skiplist 4294967295 0 0
+u64s 11 type snapshot 0:4294967295:0 len 0 ver 0: is_subvol 0 deleted 0 parent          0 children 4294967294 4294967293 subvol 0 tree 1 depth 0 skiplist 0 0 0
+";
+    const SUBVOLUMES: &str = "\
+u64s 9 type subvolume 0:1:0 len 0 ver 0: root 4096 snapshot id 4294967294 creation_parent 0 fs_parent 0
+u64s 9 type subvolume 0:2:0 len 0 ver 0: root 4096 snapshot id 4294967293 creation_parent 1 fs_parent 1
+";
 
-    0
+    #[test]
+    fn parse_debugfs() {
+        let tree = SnapshotTree::parse(SNAPSHOTS, SUBVOLUMES);
+
+        assert_eq!(tree.nodes.len(), 3);
+        assert_eq!(
+            tree.nodes[&4294967293],
+            SnapshotNode {
+                id:      4294967293,
+                parent:  4294967295,
+                subvol:  2,
+                tree:    1,
+                depth:   1,
+                deleted: false,
+            }
+        );
+        assert_eq!(tree.subvols[&2].creation_parent, 1);
+        assert_eq!(tree.roots(), vec![4294967295]);
+    }
+
+    #[test]
+    fn ascii_tree() {
+        let tree = SnapshotTree::parse(SNAPSHOTS, SUBVOLUMES);
+
+        assert_eq!(
+            tree.to_ascii(),
+            "\
+snapshot tree 1
+4294967295 depth 0
+├─ 4294967294 depth 1 subvol 1 root inode 4096
+└─ 4294967293 depth 1 subvol 2 root inode 4096 snapshot of subvol 1
+"
+        );
+    }
 }
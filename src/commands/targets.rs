@@ -0,0 +1,197 @@
+//! Checking the target options given to C commands, before running them
+//!
+//! format and set-option would otherwise only find out a target is invalid
+//! deep in libbcachefs; the errors from [`Targets`] say what's wrong, and what
+//! the devices and labels are. Globs are replaced in argv with the label they
+//! match.
+
+use std::path::Path;
+
+use ::bcachefs::{
+    device,
+    target::{Targets, TARGET_OPTS},
+};
+use anyhow::Result;
+use bch_bindgen::c;
+
+use crate::wrappers::handle::BcachefsHandle;
+
+/// Where an option's value is in argv: `(index, offset into the arg)`, for
+/// `--name=value`, `--name value`, `-xvalue` or `-x value`
+fn opt_value(args: &[String], i: usize, long: &str, short: Option<char>) -> Option<(usize, usize)> {
+    let arg = &args[i];
+
+    if let Some(rest) = arg.strip_prefix(long) {
+        if rest.starts_with('=') {
+            return Some((i, long.len() + 1));
+        }
+        if rest.is_empty() && i + 1 < args.len() {
+            return Some((i + 1, 0));
+        }
+    }
+
+    if let Some(short) = short.map(|c| format!("-{c}")) {
+        if *arg == short && i + 1 < args.len() {
+            return Some((i + 1, 0));
+        }
+        if arg.len() > short.len() && arg.starts_with(&short) {
+            return Some((i, short.len()));
+        }
+    }
+
+    None
+}
+
+/// A target option found in argv
+struct TargetArg {
+    name: &'static str,
+    idx:  usize,
+    off:  usize,
+}
+
+impl TargetArg {
+    fn check(&self, targets: &Targets, args: &mut [String]) -> Result<()> {
+        let spec = &args[self.idx][self.off..];
+        let target = targets.parse_opt(self.name, spec)?.to_string();
+
+        if target != spec {
+            args[self.idx] = format!("{}{target}", &args[self.idx][..self.off]);
+        }
+        Ok(())
+    }
+}
+
+/// `--foreground_target=ssd`, `--foreground_target ssd`
+fn target_arg(args: &[String], i: usize) -> Option<TargetArg> {
+    TARGET_OPTS.iter().copied().find_map(|name| {
+        opt_value(args, i, &format!("--{name}"), None).map(|(idx, off)| TargetArg {
+            name,
+            idx,
+            off,
+        })
+    })
+}
+
+/// format: the labels are the ones being given with --label
+fn check_format(args: &mut [String]) -> Result<()> {
+    let mut targets = Targets::new();
+    let mut target_args = Vec::new();
+    let mut i = 0;
+
+    /* --auto-tier picks the labels itself, and conflicts with targets: */
+    if args.iter().any(|a| a == "--auto-tier") {
+        return Ok(());
+    }
+
+    while i < args.len() {
+        if let Some(t) = target_arg(args, i) {
+            i = t.idx + 1;
+            target_args.push(t);
+        } else if let Some((idx, off)) = opt_value(args, i, "--label", Some('l')) {
+            targets
+                .add_label(&args[idx][off..])
+                .map_err(|e| anyhow::anyhow!("invalid --label: {e}"))?;
+            i = idx + 1;
+        } else {
+            if !args[i].starts_with('-') {
+                targets.add_device(&args[i]);
+            }
+            i += 1;
+        }
+    }
+
+    for t in &target_args {
+        t.check(&targets, args)?;
+    }
+    Ok(())
+}
+
+/// The devices and labels of the filesystem set-option is changing, from
+/// sysfs if it's mounted or the superblock if not
+fn fs_targets(devs: &[&String]) -> Option<Targets> {
+    let first = Path::new(devs.first()?);
+
+    if first.is_dir() {
+        let uuid = unsafe { BcachefsHandle::open(first) }.uuid();
+        return Targets::from_sysfs(uuid).ok();
+    }
+
+    let mut sb = device::read_super_silent(first).ok()?;
+    let targets = Targets::from_sb(&sb).ok();
+    unsafe { c::bch2_free_super(&mut sb) };
+
+    let mut targets = targets?;
+    for dev in devs {
+        targets.add_device(dev.as_str());
+    }
+    Some(targets)
+}
+
+/// set-option: options are --name=value, or name=value after the devices;
+/// default mount options may contain targets too
+fn check_set_option(args: &mut [String]) -> Result<()> {
+    let mut target_args = Vec::new();
+    let mut mount_opts = None;
+    let mut devs = Vec::new();
+    let mut i = 0;
+
+    while i < args.len() {
+        if let Some(t) = target_arg(args, i) {
+            i = t.idx + 1;
+            target_args.push(t);
+        } else if let Some((idx, off)) = opt_value(args, i, "--mount-options", Some('o')) {
+            mount_opts = Some((idx, off));
+            i = idx + 1;
+        } else if let Some((name, _)) = args[i]
+            .split_once('=')
+            .filter(|_| !args[i].starts_with('-'))
+        {
+            if let Some(name) = TARGET_OPTS.iter().copied().find(|n| *n == name) {
+                target_args.push(TargetArg {
+                    name,
+                    idx: i,
+                    off: name.len() + 1,
+                });
+            } else if name == "mount_options" {
+                mount_opts = Some((i, name.len() + 1));
+            }
+            i += 1;
+        } else {
+            /* values of --name value options aren't devices: */
+            if !args[i].starts_with('-') && Path::new(&args[i]).exists() {
+                devs.push(i);
+            }
+            i += 1;
+        }
+    }
+
+    if target_args.is_empty() && mount_opts.is_none() {
+        return Ok(());
+    }
+
+    /* if we can't read the filesystem, set-option will say why: */
+    let dev_args: Vec<_> = devs.iter().map(|&i| &args[i]).collect();
+    let Some(targets) = fs_targets(&dev_args) else {
+        return Ok(());
+    };
+
+    for t in &target_args {
+        t.check(&targets, args)?;
+    }
+
+    if let Some((idx, off)) = mount_opts {
+        let opts = targets.check_opts(&args[idx][off..])?;
+        args[idx] = format!("{}{opts}", &args[idx][..off]);
+    }
+    Ok(())
+}
+
+/// Check the target options in the arguments to C command `cmd`; `args`
+/// doesn't include the program or command name
+pub fn check_c_command(cmd: &str, args: &mut [String]) -> Result<()> {
+    match cmd {
+        "format" | "mkfs" => check_format(args),
+        "set-option" => check_set_option(args),
+        _ => Ok(()),
+    }
+}
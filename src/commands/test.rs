@@ -0,0 +1,565 @@
+//! `bcachefs test`: end to end tests of the tools, driven by scenario scripts
+//!
+//! A scenario formats sparse image files in a scratch directory and runs a
+//! script against them, one statement per line (`#` starts a comment):
+//!
+//! ```text
+//! images N [SIZE]         create N images (by default one, of 1G)
+//! requires root           skip the scenario unless run as root
+//! format ARGS...          bcachefs format ARGS... on every image
+//! fsck ARGS...            bcachefs fsck ARGS... on every image
+//! bcachefs ARGS...        run any bcachefs command
+//! ! STATEMENT             expect STATEMENT to fail
+//! expect-output TEXT      the last command's output contains TEXT
+//! mount [OPTS]            attach the images to loop devices and mount them
+//! umount                  unmount, and detach the loop devices
+//! subvolume PATH          create a subvolume
+//! snapshot SRC DST        snapshot subvolume SRC to DST
+//! write PATH SIZE         write SIZE bytes of known data to PATH
+//! verify                  check every file written has the data written
+//! corrupt IMG OFF LEN     overwrite LEN bytes at OFF in image IMG
+//! ```
+//!
+//! Paths are relative to the mountpoint. Arguments may refer to `$IMG0`,
+//! `$IMG1`..., `$IMGS` (every image, as separate arguments), `$DEVS` (every
+//! image or loop device, colon separated), `$MNT` and `$DIR` (the scratch
+//! directory).
+//!
+//! Once a scenario's script has run, any mount is torn down, and unless the
+//! images were corrupted since they were formatted, `fsck -n` must find them
+//! clean.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use log::{error, info};
+
+use super::logger::LogOpts;
+
+/// Scenarios built into the binary, run when no scripts are given
+const SCENARIOS: &[(&str, &str)] = &[
+    (
+        "format-fsck",
+        "format
+         fsck -n
+         bcachefs show-super $IMG0
+         expect-output Version:",
+    ),
+    (
+        "multi-device",
+        "images 2 1G
+         format --replicas=2
+         fsck -n
+         bcachefs list -b inodes $IMGS",
+    ),
+    (
+        "io-error",
+        "format
+         ! bcachefs --fault-inject=0 fsck -n $IMG0",
+    ),
+    (
+        "backup-superblock",
+        "format
+         corrupt 0 4096 512
+         bcachefs show-super $IMG0
+         expect-output Version:",
+    ),
+    (
+        "mount-write-snapshot",
+        "requires root
+         format
+         mount
+         subvolume sub
+         write sub/a 1M
+         snapshot sub snap
+         write sub/b 64K
+         verify
+         umount
+         fsck -n
+         mount
+         verify
+         umount",
+    ),
+];
+
+/// Run end to end tests of the tools against scratch images
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Scenario scripts to run; by default, the built in scenarios
+    #[arg(value_hint = clap::ValueHint::FilePath)]
+    scripts: Vec<PathBuf>,
+
+    /// Only run the built in scenarios with these names
+    #[arg(short, long)]
+    scenario: Vec<String>,
+
+    /// List the built in scenarios
+    #[arg(long)]
+    list: bool,
+
+    /// Keep each scenario's scratch directory
+    #[arg(short, long)]
+    keep: bool,
+
+    #[command(flatten)]
+    log: LogOpts,
+}
+
+enum Outcome {
+    Passed,
+    Skipped(&'static str),
+}
+
+/// `1G`, `64K`, `512`...
+fn parse_size(s: &str) -> Result<u64> {
+    let (n, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+
+    Ok(n.parse::<u64>()
+        .with_context(|| format!("invalid size {s}"))?
+        << shift)
+}
+
+/// The data `write` writes and `verify` expects: a xorshift stream, seeded by
+/// the path so that files don't all have the same contents
+fn file_data(path: &str, size: u64) -> Vec<u8> {
+    let mut x = path.bytes().fold(0x9e3779b97f4a7c15u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+
+    (0..size)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+struct Runner {
+    exe:         PathBuf,
+    dir:         PathBuf,
+    mnt:         PathBuf,
+    images:      Vec<PathBuf>,
+    loops:       Vec<String>,
+    mounted:     bool,
+    formatted:   bool,
+    corrupted:   bool,
+    /// Files written: the path their data was generated from, and its size
+    written:     BTreeMap<String, (String, u64)>,
+    last_output: String,
+}
+
+impl Runner {
+    fn new(dir: PathBuf) -> Result<Self> {
+        let mut r = Runner {
+            exe: std::env::current_exe()?,
+            mnt: dir.join("mnt"),
+            dir,
+            images: Vec::new(),
+            loops: Vec::new(),
+            mounted: false,
+            formatted: false,
+            corrupted: false,
+            written: BTreeMap::new(),
+            last_output: String::new(),
+        };
+
+        r.create_images(1, 1 << 30)?;
+        Ok(r)
+    }
+
+    fn create_images(&mut self, nr: usize, size: u64) -> Result<()> {
+        for img in self.images.drain(..) {
+            fs::remove_file(img)?;
+        }
+
+        for i in 0..nr {
+            let img = self.dir.join(format!("img{i}"));
+            File::create(&img)?.set_len(size)?;
+            self.images.push(img);
+        }
+
+        self.formatted = false;
+        Ok(())
+    }
+
+    fn devs(&self) -> Vec<String> {
+        if self.mounted {
+            self.loops.clone()
+        } else {
+            self.images
+                .iter()
+                .map(|i| i.to_string_lossy().into_owned())
+                .collect()
+        }
+    }
+
+    /// Substitute `$IMG0`, `$IMGS`... in a statement's arguments
+    fn expand(&self, args: &[&str]) -> Vec<String> {
+        let mut ret = Vec::new();
+
+        for arg in args {
+            if *arg == "$IMGS" {
+                ret.extend(self.devs());
+                continue;
+            }
+
+            let mut arg = arg
+                .replace("$DEVS", &self.devs().join(":"))
+                .replace("$MNT", &self.mnt.to_string_lossy())
+                .replace("$DIR", &self.dir.to_string_lossy());
+
+            // highest first, so that $IMG1 doesn't match $IMG10:
+            for (i, img) in self.devs().iter().enumerate().rev() {
+                arg = arg.replace(&format!("$IMG{i}"), img);
+            }
+
+            ret.push(arg);
+        }
+
+        ret
+    }
+
+    fn run(&mut self, cmd: &str, args: &[String]) -> Result<()> {
+        info!("{cmd} {}", args.join(" "));
+
+        let out = Command::new(cmd)
+            .args(args)
+            .output()
+            .with_context(|| format!("error running {cmd}"))?;
+
+        self.last_output = String::from_utf8_lossy(&out.stdout).into_owned()
+            + &String::from_utf8_lossy(&out.stderr);
+
+        if !out.status.success() {
+            bail!(
+                "{cmd} {} failed: {}\n{}",
+                args.join(" "),
+                out.status,
+                self.last_output
+            );
+        }
+        Ok(())
+    }
+
+    fn bcachefs(&mut self, args: &[String]) -> Result<()> {
+        let exe = self.exe.to_string_lossy().into_owned();
+        self.run(&exe, args)
+    }
+
+    /// `bcachefs <cmd> ARGS... <every image>`
+    fn on_images(&mut self, cmd: &str, args: &[&str]) -> Result<()> {
+        let args: Vec<String> = std::iter::once(cmd.to_owned())
+            .chain(self.expand(args))
+            .chain(self.devs())
+            .collect();
+        self.bcachefs(&args)
+    }
+
+    fn mount(&mut self, opts: Option<&str>) -> Result<()> {
+        if self.mounted {
+            bail!("already mounted");
+        }
+
+        for img in self.images.clone() {
+            self.run(
+                "losetup",
+                &[
+                    "--find".into(),
+                    "--show".into(),
+                    img.to_string_lossy().into(),
+                ],
+            )?;
+            self.loops.push(self.last_output.trim().to_owned());
+        }
+
+        fs::create_dir_all(&self.mnt)?;
+
+        let mut args = vec!["mount".to_owned()];
+        if let Some(opts) = opts {
+            args.extend(["-o".to_owned(), opts.to_owned()]);
+        }
+        args.push(self.loops.join(":"));
+        args.push(self.mnt.to_string_lossy().into_owned());
+
+        self.mounted = true;
+        self.bcachefs(&args)
+    }
+
+    fn umount(&mut self) -> Result<()> {
+        if self.mounted {
+            self.run("umount", &[self.mnt.to_string_lossy().into_owned()])?;
+            self.mounted = false;
+        }
+
+        for dev in std::mem::take(&mut self.loops) {
+            self.run("losetup", &["-d".into(), dev])?;
+        }
+        Ok(())
+    }
+
+    fn mnt_path(&self, path: &str) -> Result<PathBuf> {
+        if !self.mounted {
+            bail!("not mounted");
+        }
+        Ok(self.mnt.join(path))
+    }
+
+    fn write(&mut self, path: &str, size: u64) -> Result<()> {
+        let p = self.mnt_path(path)?;
+
+        if let Some(dir) = p.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&p, file_data(path, size))
+            .with_context(|| format!("error writing {}", p.display()))?;
+
+        self.written
+            .insert(path.to_owned(), (path.to_owned(), size));
+        Ok(())
+    }
+
+    /// A snapshot has the files its source had when it was taken
+    fn snapshot(&mut self, src: &str, dst: &str) -> Result<()> {
+        let args = vec![
+            "subvolume".to_owned(),
+            "snapshot".to_owned(),
+            self.mnt_path(src)?.to_string_lossy().into_owned(),
+            self.mnt_path(dst)?.to_string_lossy().into_owned(),
+        ];
+        self.bcachefs(&args)?;
+
+        let prefix = format!("{}/", src.trim_end_matches('/'));
+        let copied: Vec<_> = self
+            .written
+            .iter()
+            .filter_map(|(path, data)| {
+                path.strip_prefix(&prefix).map(|rest| {
+                    (
+                        format!("{}/{rest}", dst.trim_end_matches('/')),
+                        data.clone(),
+                    )
+                })
+            })
+            .collect();
+
+        self.written.extend(copied);
+        Ok(())
+    }
+
+    fn verify(&self) -> Result<()> {
+        for (path, (seed, size)) in &self.written {
+            let p = self.mnt_path(path)?;
+            let data = fs::read(&p).with_context(|| format!("error reading {}", p.display()))?;
+
+            if data != file_data(seed, *size) {
+                bail!("{path}: contents don't match what was written");
+            }
+        }
+        Ok(())
+    }
+
+    fn corrupt(&mut self, img: usize, offset: u64, len: u64) -> Result<()> {
+        if self.mounted {
+            bail!("corrupt while mounted");
+        }
+
+        let img = self
+            .images
+            .get(img)
+            .ok_or_else(|| anyhow!("no image {img}"))?;
+        let mut f = OpenOptions::new().read(true).write(true).open(img)?;
+        let mut buf = vec![0u8; len as usize];
+
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut buf)?;
+        for b in &mut buf {
+            *b = !*b;
+        }
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(&buf)?;
+
+        self.corrupted = true;
+        Ok(())
+    }
+
+    fn statement(&mut self, line: &str) -> Result<Option<Outcome>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words[..] {
+            ["!", ref rest @ ..] => match self.statement(&rest.join(" ")) {
+                Ok(_) => bail!("{} succeeded, but was expected to fail", rest.join(" ")),
+                Err(e) => info!("failed as expected: {e}"),
+            },
+            ["requires", "root"] => {
+                if unsafe { libc::geteuid() } != 0 {
+                    return Ok(Some(Outcome::Skipped("needs root")));
+                }
+            }
+            ["images", nr] => self.create_images(nr.parse()?, 1 << 30)?,
+            ["images", nr, size] => self.create_images(nr.parse()?, parse_size(size)?)?,
+            ["format", ref args @ ..] => {
+                self.on_images("format", args)?;
+                self.formatted = true;
+                self.corrupted = false;
+                self.written.clear();
+            }
+            ["fsck", ref args @ ..] => self.on_images("fsck", args)?,
+            ["bcachefs", ref args @ ..] => {
+                let args = self.expand(args);
+                self.bcachefs(&args)?;
+            }
+            ["expect-output", ..] => {
+                let text = line["expect-output".len()..].trim();
+                if !self.last_output.contains(text) {
+                    bail!("output doesn't contain {text:?}:\n{}", self.last_output);
+                }
+            }
+            ["mount"] => self.mount(None)?,
+            ["mount", opts] => self.mount(Some(opts))?,
+            ["umount"] => self.umount()?,
+            ["subvolume", path] => {
+                let args = vec![
+                    "subvolume".to_owned(),
+                    "create".to_owned(),
+                    self.mnt_path(path)?.to_string_lossy().into_owned(),
+                ];
+                self.bcachefs(&args)?;
+            }
+            ["snapshot", src, dst] => self.snapshot(src, dst)?,
+            ["write", path, size] => self.write(path, parse_size(size)?)?,
+            ["verify"] => self.verify()?,
+            ["corrupt", img, offset, len] => {
+                self.corrupt(img.parse()?, parse_size(offset)?, parse_size(len)?)?
+            }
+            _ => bail!("invalid statement"),
+        }
+
+        Ok(None)
+    }
+
+    fn script(&mut self, script: &str) -> Result<Outcome> {
+        for (nr, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match self.statement(line) {
+                Ok(Some(outcome)) => return Ok(outcome),
+                Ok(None) => {}
+                Err(e) => return Err(e.context(format!("line {}: {line}", nr + 1))),
+            }
+        }
+
+        self.umount()?;
+
+        if self.formatted && !self.corrupted {
+            self.on_images("fsck", &["-n"])
+                .context("final fsck found errors")?;
+        }
+
+        Ok(Outcome::Passed)
+    }
+}
+
+fn run_scenario(name: &str, script: &str, keep: bool) -> Result<Outcome> {
+    let dir = std::env::temp_dir().join(format!("bcachefs-test-{}-{name}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let mut runner = Runner::new(dir.clone())?;
+    let ret = runner.script(script);
+
+    // don't leave devices mounted if the script failed partway:
+    let _ = runner.umount();
+
+    if keep {
+        println!("{name}: kept {}", dir.display());
+    } else {
+        fs::remove_dir_all(&dir)?;
+    }
+
+    ret
+}
+
+fn cmd_test_inner(opt: &Cli) -> Result<bool> {
+    opt.log.init()?;
+
+    if opt.list {
+        for (name, _) in SCENARIOS {
+            println!("{name}");
+        }
+        return Ok(true);
+    }
+
+    let mut scenarios: Vec<(String, String)> = Vec::new();
+
+    for path in &opt.scripts {
+        let script = fs::read_to_string(path)
+            .with_context(|| format!("error reading {}", path.display()))?;
+        let name = Path::new(path)
+            .file_stem()
+            .map_or("script".into(), |s| s.to_string_lossy().into_owned());
+        scenarios.push((name, script));
+    }
+
+    if opt.scripts.is_empty() {
+        for s in &opt.scenario {
+            if !SCENARIOS.iter().any(|(name, _)| name == s) {
+                bail!("no scenario {s}; see --list");
+            }
+        }
+
+        scenarios.extend(
+            SCENARIOS
+                .iter()
+                .filter(|(name, _)| {
+                    opt.scenario.is_empty() || opt.scenario.iter().any(|s| s == name)
+                })
+                .map(|(name, script)| (name.to_string(), script.to_string())),
+        );
+    }
+
+    let mut failed = 0;
+
+    for (name, script) in &scenarios {
+        match run_scenario(name, script, opt.keep) {
+            Ok(Outcome::Passed) => println!("{name}: passed"),
+            Ok(Outcome::Skipped(why)) => println!("{name}: skipped ({why})"),
+            Err(e) => {
+                println!("{name}: FAILED");
+                error!("{name}: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} scenarios, {failed} failed", scenarios.len());
+    Ok(failed == 0)
+}
+
+pub fn test(argv: Vec<String>) -> i32 {
+    let opt = Cli::parse_from(argv);
+
+    match cmd_test_inner(&opt) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            error!("{e}");
+            ::bcachefs::error::exit_code(&e)
+        }
+    }
+}
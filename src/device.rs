@@ -0,0 +1,409 @@
+//! Finding the member devices of a filesystem
+//!
+//! Devices are found via the udev database when it's available; otherwise
+//! (or when `BCACHEFS_BLOCK_SCAN` is set) every block device is probed for a
+//! bcachefs superblock.
+//!
+//! Regular files are accepted anywhere a device is, as filesystem images:
+//! they're never in the udev database, so their superblocks are always read
+//! directly, and the other members of a multi device image are looked for
+//! alongside it.
+//!
+//! Superblocks are read in parallel, with a timeout per device, so that one
+//! hung device can't block finding the others. The timeout defaults to 10
+//! seconds, and can be set with `BCACHEFS_PROBE_TIMEOUT` (in seconds).
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use bch_bindgen::{
+    bcachefs,
+    bcachefs::bch_sb_handle,
+    c,
+    errcode::{bch_errcode, BchError},
+    opts::Opts,
+};
+use log::{debug, warn};
+use uuid::Uuid;
+
+use crate::Error;
+
+/// Map from both device node to filesystem UUID, and filesystem UUID to device
+/// nodes, from the udev database
+pub type UdevInfo = HashMap<String, Vec<String>>;
+
+/// Read a superblock without printing errors, and without opening the device
+/// exclusively
+///
+/// Fails with [`Error::NotBcachefs`] if the device has no bcachefs superblock.
+pub fn read_super_silent(path: impl AsRef<Path>) -> Result<bch_sb_handle> {
+    let opts = Opts::new().noexcl(true).build();
+
+    bch_bindgen::sb_io::read_super_silent(path.as_ref(), opts).map_err(|e: BchError| {
+        if e.matches(bch_errcode::BCH_ERR_invalid_sb_magic) {
+            Error::NotBcachefs(path.as_ref().to_path_buf()).into()
+        } else {
+            e.into()
+        }
+    })
+}
+
+/// True if `path` is a regular file, i.e. a filesystem image rather than a
+/// block device
+pub fn is_image_file(path: impl AsRef<Path>) -> bool {
+    fs::metadata(path).map_or(false, |m| m.file_type().is_file())
+}
+
+/// Check that `paths` are block devices or image files with bcachefs
+/// superblocks, for commands that open the filesystem directly
+///
+/// Errors from opening the filesystem don't say which device was the problem;
+/// this does. Fails with [`Error::NotBcachefs`] for a device or image without a
+/// superblock.
+pub fn check_members<P: AsRef<Path>>(paths: &[P]) -> Result<()> {
+    for path in paths {
+        let path = path.as_ref();
+        let ft = fs::metadata(path)
+            .map_err(|e| anyhow!("{}: {e}", path.display()))?
+            .file_type();
+
+        if ft.is_dir() {
+            return Err(anyhow!("{}: is a directory", path.display()));
+        }
+        if !ft.is_block_device() && !ft.is_file() {
+            return Err(anyhow!(
+                "{}: not a block device or image file",
+                path.display()
+            ));
+        }
+
+        let mut sb = read_super_silent(path)?;
+        unsafe { bcachefs::bch2_free_super(&mut sb) };
+    }
+    Ok(())
+}
+
+/// Other image files in the same directory as `image`, belonging to the same
+/// filesystem
+fn image_members(image: &Path, uuid: Uuid) -> Vec<(PathBuf, bch_sb_handle)> {
+    let dir = image.parent().unwrap_or(Path::new("."));
+    let files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|d| {
+            d.filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| is_image_file(p))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    debug!("looking for members of {uuid} in {}", dir.display());
+    get_super_blocks(uuid, &files)
+}
+
+/// How long to wait for each device in [`read_supers`]
+pub fn probe_timeout() -> Duration {
+    env::var("BCACHEFS_PROBE_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map_or(Duration::from_secs(10), Duration::from_secs)
+}
+
+/// A superblock handle being passed back from a probe thread; nothing else
+/// refers to its buffers
+struct SendSb(bch_sb_handle);
+
+unsafe impl Send for SendSb {}
+
+/// Most threads [`read_supers`] reads superblocks on
+const PROBE_THREADS: usize = 8;
+
+/// Read the superblocks of `devices` in parallel, giving up on devices that
+/// haven't responded within `timeout`
+///
+/// Devices are read by a pool of up to [`PROBE_THREADS`] threads. Threads stuck
+/// on a device that times out are abandoned, since a read stuck in the kernel
+/// can't be interrupted; if one completes later its superblock is freed rather
+/// than returned, and the other threads carry on with the remaining devices.
+pub fn read_supers<P: AsRef<Path>>(
+    devices: &[P],
+    timeout: Duration,
+) -> Vec<(PathBuf, Result<bch_sb_handle>)> {
+    let (tx, rx) = mpsc::channel();
+    let queue: Vec<PathBuf> = devices.iter().map(|d| d.as_ref().to_path_buf()).collect();
+    let queue = Arc::new(Mutex::new(queue.into_iter().enumerate()));
+
+    for _ in 0..devices.len().min(PROBE_THREADS) {
+        let queue = Arc::clone(&queue);
+        let tx = tx.clone();
+
+        thread::spawn(move || {
+            // bch2_read_super() needs a task_struct and RCU, as a kthread has:
+            unsafe { c::tools_thread_init() };
+
+            loop {
+                let Some((i, dev)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let ret = read_super_silent(&dev).map(SendSb);
+
+                if let Err(mpsc::SendError((_, Ok(mut sb)))) = tx.send((i, ret)) {
+                    unsafe { bcachefs::bch2_free_super(&mut sb.0) };
+                }
+            }
+
+            unsafe { c::tools_thread_exit() };
+        });
+    }
+    drop(tx);
+
+    let deadline = Instant::now() + timeout;
+    let mut results: Vec<Option<Result<SendSb>>> = devices.iter().map(|_| None).collect();
+
+    while results.iter().any(Option::is_none) {
+        let wait = deadline.saturating_duration_since(Instant::now());
+
+        match rx.recv_timeout(wait) {
+            Ok((i, ret)) => results[i] = Some(ret),
+            Err(_) => break,
+        }
+    }
+
+    devices
+        .iter()
+        .zip(results)
+        .map(|(dev, ret)| {
+            let dev = dev.as_ref().to_path_buf();
+            let ret = match ret {
+                Some(ret) => ret.map(|sb| sb.0),
+                None => {
+                    warn!("{}: no response after {:?}", dev.display(), timeout);
+                    Err(anyhow!("{}: timed out reading superblock", dev.display()))
+                }
+            };
+            (dev, ret)
+        })
+        .collect()
+}
+
+pub fn sb_is_encrypted(sb: &bch_sb_handle) -> bool {
+    unsafe { bcachefs::bch2_sb_is_encrypted(sb.sb) }
+}
+
+fn device_property_map(dev: &udev::Device) -> HashMap<String, String> {
+    let rc: HashMap<_, _> = dev
+        .properties()
+        .map(|i| {
+            (
+                String::from(i.name().to_string_lossy()),
+                String::from(i.value().to_string_lossy()),
+            )
+        })
+        .collect();
+    rc
+}
+
+/// Query udev for bcachefs devices; returns an empty map if udev shouldn't be
+/// used, in which case all block devices will be scanned
+pub fn udev_bcachefs_info() -> Result<UdevInfo> {
+    let mut info = HashMap::new();
+
+    if env::var("BCACHEFS_BLOCK_SCAN").is_ok() {
+        debug!("Checking all block devices for bcachefs super block!");
+        return Ok(info);
+    }
+
+    let mut udev = udev::Enumerator::new()?;
+
+    debug!("Walking udev db!");
+
+    udev.match_subsystem("block")?;
+    udev.match_property("ID_FS_TYPE", "bcachefs")?;
+
+    for m in udev
+        .scan_devices()?
+        .filter(udev::Device::is_initialized)
+        .map(|dev| device_property_map(&dev))
+        .filter(|m| m.contains_key("ID_FS_UUID") && m.contains_key("DEVNAME"))
+    {
+        let fs_uuid = m["ID_FS_UUID"].clone();
+        let dev_node = m["DEVNAME"].clone();
+        info.insert(dev_node.clone(), vec![fs_uuid.clone()]);
+        info.entry(fs_uuid).or_insert(vec![]).push(dev_node.clone());
+    }
+
+    Ok(info)
+}
+
+fn get_super_blocks<P: AsRef<Path>>(uuid: Uuid, devices: &[P]) -> Vec<(PathBuf, bch_sb_handle)> {
+    read_supers(devices, probe_timeout())
+        .into_iter()
+        .filter_map(|(dev, sb)| sb.ok().map(|sb| (dev, sb)))
+        .filter(|(_, sb)| sb.sb().uuid() == uuid)
+        .collect::<Vec<_>>()
+}
+
+pub fn get_all_block_devnodes() -> Result<Vec<String>> {
+    let mut udev = udev::Enumerator::new()?;
+    udev.match_subsystem("block")?;
+
+    let devices = udev
+        .scan_devices()?
+        .filter_map(|dev| {
+            if dev.is_initialized() {
+                dev.devnode().map(|dn| dn.to_string_lossy().into_owned())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(devices)
+}
+
+/// Find all devices belonging to the filesystem with the given UUID
+pub fn get_devices_by_uuid(
+    udev_bcachefs: &UdevInfo,
+    uuid: Uuid,
+) -> Result<Vec<(PathBuf, bch_sb_handle)>> {
+    let devices = {
+        if !udev_bcachefs.is_empty() {
+            let uuid_string = uuid.hyphenated().to_string();
+            if let Some(devices) = udev_bcachefs.get(&uuid_string) {
+                devices.clone()
+            } else {
+                Vec::new()
+            }
+        } else {
+            get_all_block_devnodes()?
+        }
+    };
+
+    Ok(get_super_blocks(uuid, &devices))
+}
+
+#[allow(clippy::type_complexity)]
+fn get_uuid_for_dev_node(
+    udev_bcachefs: &UdevInfo,
+    device: impl AsRef<Path>,
+) -> Result<(Option<Uuid>, Option<(PathBuf, bch_sb_handle)>)> {
+    let canonical = fs::canonicalize(device)?;
+
+    if !udev_bcachefs.is_empty() && !is_image_file(&canonical) {
+        let dev_node_str = canonical.into_os_string().into_string().unwrap();
+
+        if udev_bcachefs.contains_key(&dev_node_str) && udev_bcachefs[&dev_node_str].len() == 1 {
+            let uuid_str = udev_bcachefs[&dev_node_str][0].clone();
+            return Ok((Some(Uuid::parse_str(&uuid_str)?), None));
+        }
+    } else {
+        let (canonical, sb) = read_supers(&[canonical], probe_timeout()).remove(0);
+
+        return sb.map_or(Ok((None, None)), |sb| {
+            Ok((Some(sb.sb().uuid()), Some((canonical, sb))))
+        });
+    }
+    Ok((None, None))
+}
+
+/// Find the devices of the filesystem with the given UUID; returns them as a
+/// colon separated string, as the kernel expects for mount, along with their
+/// superblocks
+pub fn devs_str_sbs_from_uuid(
+    udev_info: &UdevInfo,
+    uuid: &str,
+) -> Result<(String, Vec<bch_sb_handle>)> {
+    debug!("enumerating devices with UUID {}", uuid);
+
+    let devs_sbs = Uuid::parse_str(uuid).map(|uuid| get_devices_by_uuid(udev_info, uuid))??;
+
+    let devs_str = devs_sbs
+        .iter()
+        .map(|(dev, _)| dev.to_str().unwrap())
+        .collect::<Vec<_>>()
+        .join(":");
+
+    let sbs: Vec<bch_sb_handle> = devs_sbs.iter().map(|(_, sb)| *sb).collect();
+
+    Ok((devs_str, sbs))
+}
+
+/// Like [`devs_str_sbs_from_uuid`], starting from one member device
+pub fn devs_str_sbs_from_device(
+    udev_info: &UdevInfo,
+    device: impl AsRef<Path>,
+) -> Result<(String, Vec<bch_sb_handle>)> {
+    let (uuid, sb_info) = get_uuid_for_dev_node(udev_info, device)?;
+
+    match (uuid, sb_info) {
+        (Some(uuid), Some((path, sb))) => {
+            // If we have a super block, it implies we aren't using udev db, or this is an
+            // image file.  If we only need 1 device to mount, we'll simply return it as we're
+            // done, else we'll use the uuid to walk through all the block devices - or the
+            // other images in the same directory.
+            debug!(
+                "number of devices in this FS = {}",
+                sb.sb().number_of_devices()
+            );
+            if sb.sb().number_of_devices() == 1 {
+                let dev = path.into_os_string().into_string().unwrap();
+                Ok((dev, vec![sb]))
+            } else if is_image_file(&path) {
+                let devs_sbs = image_members(&path, uuid);
+                let devs_str = devs_sbs
+                    .iter()
+                    .map(|(dev, _)| dev.to_str().unwrap())
+                    .collect::<Vec<_>>()
+                    .join(":");
+
+                Ok((devs_str, devs_sbs.into_iter().map(|(_, sb)| sb).collect()))
+            } else {
+                devs_str_sbs_from_uuid(udev_info, &uuid.to_string())
+            }
+        }
+        (Some(uuid), None) => devs_str_sbs_from_uuid(udev_info, &uuid.to_string()),
+        _ => Ok((String::new(), Vec::new())),
+    }
+}
+
+/// Resolve a device specification, as passed to mount: `UUID=<uuid>`, a colon
+/// separated list of devices, or a single device (which may be one member of a
+/// multi device filesystem)
+///
+/// Fails with [`Error::DeviceNotFound`] if no devices were found.
+pub fn find_devices(udev_info: &UdevInfo, dev: &str) -> Result<(String, Vec<bch_sb_handle>)> {
+    let (devs, sbs) = find_devices_inner(udev_info, dev)?;
+
+    if sbs.is_empty() {
+        return Err(Error::DeviceNotFound(dev.to_owned()).into());
+    }
+    Ok((devs, sbs))
+}
+
+fn find_devices_inner(udev_info: &UdevInfo, dev: &str) -> Result<(String, Vec<bch_sb_handle>)> {
+    if let Some(uuid) = dev.strip_prefix("UUID=") {
+        devs_str_sbs_from_uuid(udev_info, uuid)
+    } else if let Some(uuid) = dev.strip_prefix("OLD_BLKID_UUID=") {
+        devs_str_sbs_from_uuid(udev_info, uuid)
+    } else if dev.contains(':') {
+        // If the device string contains ":" we will assume the user knows the entire list.
+        // If they supply a single device it could be either the FS only has 1 device or it's
+        // only 1 of a number of devices which are part of the FS. This appears to be the case
+        // when we get called during fstab mount processing and the fstab specifies a UUID.
+        let devs: Vec<_> = dev.split(':').collect();
+        let sbs = read_supers(&devs, probe_timeout())
+            .into_iter()
+            .map(|(_, sb)| sb)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((dev.to_string(), sbs))
+    } else {
+        devs_str_sbs_from_device(udev_info, Path::new(dev))
+    }
+}
@@ -0,0 +1,128 @@
+//! Errors that callers may want to react to
+//!
+//! Library functions return [`anyhow::Result`], as the commands do, but errors
+//! a caller can do something about - no devices, a locked filesystem, a kernel
+//! that's too old - are an [`Error`] underneath, which can be recovered with
+//! [`Error::find`]. Commands exit with [`exit_code`], so scripts can tell them
+//! apart too:
+//!
+//! | Exit code | Error                          |
+//! |-----------|--------------------------------|
+//! | 1         | anything else                  |
+//! | 2         | invalid arguments (from clap)  |
+//! | 64        | [`Error::DeviceNotFound`]      |
+//! | 65        | [`Error::NotBcachefs`]         |
+//! | 66        | [`Error::KeyMissing`]          |
+//! | 67        | [`Error::WrongPassphrase`]     |
+//! | 68        | [`Error::NeedsFsck`]           |
+//! | 69        | [`Error::KernelTooOld`]        |
+//!
+//! These don't overlap with fsck's exit codes, which are a bitmask of 1, 4 and
+//! 8.
+
+use std::{fmt, path::PathBuf};
+
+use bch_bindgen::errcode::{bch_errcode, BchError};
+use uuid::Uuid;
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// No devices were found for a device specification
+    DeviceNotFound(String),
+    /// A device has no bcachefs superblock
+    NotBcachefs(PathBuf),
+    /// An encrypted filesystem is locked, and no passphrase was available
+    KeyMissing(Uuid),
+    /// The passphrase given doesn't unlock the filesystem
+    WrongPassphrase(Uuid),
+    /// The filesystem has errors that fsck must repair first
+    NeedsFsck(String),
+    /// The running kernel doesn't support the filesystem's metadata version,
+    /// or has no bcachefs support at all (`kernel_version` is `None`)
+    KernelTooOld {
+        fs_version:     u16,
+        kernel_version: Option<u16>,
+    },
+}
+
+fn version_str(v: u16) -> String {
+    format!("{}.{}", v >> 10, v & 0x3ff)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DeviceNotFound(dev) => write!(f, "no devices found for {dev}"),
+            Error::NotBcachefs(path) => write!(f, "{}: not a bcachefs device", path.display()),
+            Error::KeyMissing(uuid) => {
+                write!(f, "{uuid} is encrypted, and no passphrase was given")
+            }
+            Error::WrongPassphrase(uuid) => write!(f, "wrong passphrase for {uuid}"),
+            Error::NeedsFsck(why) => write!(f, "{why}: run fsck"),
+            Error::KernelTooOld {
+                fs_version,
+                kernel_version: Some(k),
+            } => write!(
+                f,
+                "filesystem version {} is newer than the running kernel supports ({})",
+                version_str(*fs_version),
+                version_str(*k)
+            ),
+            Error::KernelTooOld {
+                kernel_version: None,
+                ..
+            } => write!(f, "the running kernel has no bcachefs support"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::DeviceNotFound(_) => 64,
+            Error::NotBcachefs(_) => 65,
+            Error::KeyMissing(_) => 66,
+            Error::WrongPassphrase(_) => 67,
+            Error::NeedsFsck(_) => 68,
+            Error::KernelTooOld { .. } => 69,
+        }
+    }
+
+    /// The errno the C API returns for this error
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::DeviceNotFound(_) => libc::ENOENT,
+            Error::NotBcachefs(_) => libc::EINVAL,
+            Error::KeyMissing(_) => libc::ENOKEY,
+            Error::WrongPassphrase(_) => libc::EKEYREJECTED,
+            Error::NeedsFsck(_) => libc::EUCLEAN,
+            Error::KernelTooOld { .. } => libc::EOPNOTSUPP,
+        }
+    }
+
+    /// The [`Error`] underlying `e`, if there is one
+    ///
+    /// Errors from libbcachefs that mean the filesystem needs repair are
+    /// reported as [`Error::NeedsFsck`].
+    pub fn find(e: &anyhow::Error) -> Option<Error> {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<Error>() {
+                return Some(e.clone());
+            }
+
+            if let Some(b) = cause.downcast_ref::<BchError>() {
+                if b.matches(bch_errcode::BCH_ERR_fsck) {
+                    return Some(Error::NeedsFsck(b.to_string()));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The exit code for a command that failed with `e`
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    Error::find(e).map_or(1, |e| e.exit_code())
+}
@@ -0,0 +1,166 @@
+//! C ABI for mounting and unlocking
+//!
+//! These are exported from the shared library (`libbcachefs.so`), with a
+//! header generated by cbindgen (`make bcachefs_mount.h`), so that systemd and
+//! installers can mount bcachefs filesystems without spawning the `bcachefs`
+//! binary.
+//!
+//! All functions return 0 (or a non-negative length) on success, and a
+//! negative errno on failure. Device arguments accept anything `bcachefs mount`
+//! does: `UUID=<uuid>`, a colon separated list of devices, or a single member
+//! device.
+
+use std::{
+    ffi::{c_char, c_int, CStr},
+    panic, ptr,
+};
+
+use anyhow::Result;
+use bch_bindgen::{bcachefs::bch_sb_handle, errcode::BchError};
+
+use crate::{
+    device,
+    key::{KeyHandle, Passphrase},
+    mount, ErrnoError, Error,
+};
+
+fn error_to_errno(e: &anyhow::Error) -> c_int {
+    if let Some(e) = e.downcast_ref::<Error>() {
+        e.errno()
+    } else if let Some(e) = e.downcast_ref::<ErrnoError>() {
+        e.0 .0
+    } else if let Some(e) = e.downcast_ref::<BchError>() {
+        e.errno()
+    } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        e.raw_os_error().unwrap_or(libc::EIO)
+    } else {
+        libc::EINVAL
+    }
+}
+
+/// Run `f`, converting errors (and panics - we can't unwind into C) to negative
+/// errnos
+fn ffi_call(f: impl FnOnce() -> Result<c_int> + panic::UnwindSafe) -> c_int {
+    match panic::catch_unwind(f) {
+        Ok(Ok(ret)) => ret,
+        Ok(Err(e)) => -error_to_errno(&e),
+        Err(_) => -libc::EIO,
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(ErrnoError(errno::Errno(libc::EINVAL)).into());
+    }
+
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+fn find_devices(dev: &str) -> Result<(String, Vec<bch_sb_handle>)> {
+    let udev_info = device::udev_bcachefs_info()?;
+    device::find_devices(&udev_info, dev)
+}
+
+/// Find the member devices of the filesystem with UUID `uuid`
+///
+/// Writes a colon separated, nul terminated list of devices, suitable for
+/// passing to mount(2), to `buf`. Returns the length of the list (not including
+/// the terminating nul), `-ENOENT` if no devices were found, or `-ERANGE` if
+/// `buflen` is too small.
+///
+/// # Safety
+///
+/// `uuid` must be a valid C string, and `buf` must point to at least `buflen`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bcachefs_probe_devices_by_uuid(
+    uuid: *const c_char,
+    buf: *mut c_char,
+    buflen: usize,
+) -> c_int {
+    ffi_call(|| {
+        let uuid = str_arg(uuid)?;
+        let (devs, _) = find_devices(&format!("UUID={}", uuid))?;
+
+        if buf.is_null() || devs.len() >= buflen {
+            return Err(ErrnoError(errno::Errno(libc::ERANGE)).into());
+        }
+
+        ptr::copy_nonoverlapping(devs.as_ptr().cast(), buf, devs.len());
+        *buf.add(devs.len()) = 0;
+
+        Ok(devs.len() as c_int)
+    })
+}
+
+/// Unlock an encrypted filesystem, by adding its key to the user keyring
+///
+/// Returns 0 if the filesystem was unlocked, was already unlocked, or isn't
+/// encrypted; `-EKEYREJECTED` if the passphrase is wrong.
+///
+/// # Safety
+///
+/// `dev` and `passphrase` must be valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn bcachefs_unlock_with_passphrase(
+    dev: *const c_char,
+    passphrase: *const c_char,
+) -> c_int {
+    ffi_call(|| {
+        let dev = str_arg(dev)?;
+        let passphrase = Passphrase::new(str_arg(passphrase)?)?;
+        let (_, sbs) = find_devices(dev)?;
+        let sb = &sbs[0];
+
+        if !device::sb_is_encrypted(sb) || KeyHandle::new_from_search(&sb.sb().uuid()).is_ok() {
+            return Ok(0);
+        }
+
+        KeyHandle::new(sb, &passphrase).map(|_| 0)
+    })
+}
+
+/// Mount a filesystem at `target`, with `options` in the same format as
+/// `mount -o` (may be NULL, for the defaults set with `set-option`, if any)
+///
+/// Encrypted filesystems must be unlocked first, see
+/// [`bcachefs_unlock_with_passphrase`]; otherwise this returns `-ENOKEY`.
+/// Returns `-EOPNOTSUPP` if the running kernel can't mount the filesystem's
+/// metadata version.
+///
+/// # Safety
+///
+/// `dev` and `target` must be valid C strings, and `options` must be either
+/// NULL or a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn bcachefs_mount(
+    dev: *const c_char,
+    target: *const c_char,
+    options: *const c_char,
+) -> c_int {
+    ffi_call(|| {
+        let dev = str_arg(dev)?;
+        let target = str_arg(target)?;
+        let options = if options.is_null() {
+            ""
+        } else {
+            str_arg(options)?
+        };
+
+        let (devs, sbs) = find_devices(dev)?;
+
+        if device::sb_is_encrypted(&sbs[0])
+            && KeyHandle::new_from_search(&sbs[0].sb().uuid()).is_err()
+        {
+            return Err(Error::KeyMissing(sbs[0].sb().uuid()).into());
+        }
+
+        let options = mount::options_or_default(options, &sbs[0]);
+        let perms = mount::MountpointPerms::from_options(&options)?;
+        let (data, mountflags) = mount::parse_mount_options(options);
+        mount::mount(devs, target, "bcachefs", mountflags, data)
+            .map_err(|e| mount::check_kernel_version(&sbs[0]).err().unwrap_or(e))?;
+        perms.apply(target)?;
+        Ok(0)
+    })
+}
@@ -1,3 +1,5 @@
+//! Unlocking encrypted filesystems
+
 use std::{
     ffi::{c_long, CStr, CString},
     fs,
@@ -8,9 +10,9 @@ use std::{
     time::Duration,
 };
 
-use anyhow::{anyhow, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use bch_bindgen::{
-    bcachefs::{self, bch_key, bch_sb_handle},
+    bcachefs::{self, bch_kdf_types, bch_key, bch_sb_handle},
     c::bch2_chacha_encrypt_key,
     keyutils::{self, keyctl_search},
 };
@@ -19,7 +21,7 @@ use log::info;
 use uuid::Uuid;
 use zeroize::{ZeroizeOnDrop, Zeroizing};
 
-use crate::{c_str, ErrnoError};
+use crate::{c_str, ErrnoError, Error};
 
 const BCH_KEY_MAGIC: &str = "bch**key";
 
@@ -37,7 +39,7 @@ impl UnlockPolicy {
         info!("Using filesystem unlock policy '{self}' on {uuid}");
 
         match self {
-            Self::Fail => Err(anyhow!("no passphrase available")),
+            Self::Fail => Err(Error::KeyMissing(uuid).into()),
             Self::Wait => Ok(KeyHandle::wait_for_unlock(&uuid)?),
             Self::Ask => Passphrase::new_from_prompt().and_then(|p| KeyHandle::new(sb, &p)),
         }
@@ -62,12 +64,19 @@ impl KeyHandle {
         CString::new(format!("bcachefs:{uuid}")).unwrap()
     }
 
+    /// Fails with [`Error::WrongPassphrase`] if `passphrase` doesn't decrypt
+    /// the filesystem's key
     pub fn new(sb: &bch_sb_handle, passphrase: &Passphrase) -> Result<Self> {
         let bch_key_magic = BCH_KEY_MAGIC.as_bytes().read_u64::<LittleEndian>().unwrap();
 
         let crypt = sb.sb().crypt().unwrap();
         let crypt_ptr = ptr::addr_of!(*crypt).cast_mut();
 
+        // derive_passphrase() exits on a KDF it doesn't know:
+        if !matches!(crypt.kdf_type(), Some(bch_kdf_types::BCH_KDF_SCRYPT)) {
+            bail!("unknown passphrase KDF; this filesystem needs a newer bcachefs-tools");
+        }
+
         let mut output: bch_key =
             unsafe { bcachefs::derive_passphrase(crypt_ptr, passphrase.get().as_ptr()) };
 
@@ -83,7 +92,9 @@ impl KeyHandle {
         };
 
         ensure!(ret == 0, "chacha decryption failure");
-        ensure!(key.magic == bch_key_magic, "failed to verify passphrase");
+        if key.magic != bch_key_magic {
+            return Err(Error::WrongPassphrase(sb.sb().uuid()).into());
+        }
 
         let key_name = Self::format_key_name(&sb.sb().uuid());
         let key_name = CStr::as_ptr(&key_name);
@@ -129,7 +140,9 @@ impl KeyHandle {
         }
     }
 
-    fn wait_for_unlock(uuid: &Uuid) -> Result<Self> {
+    /// Block until the key for `uuid` appears in the keyring, e.g. after being
+    /// added by another process
+    pub fn wait_for_unlock(uuid: &Uuid) -> Result<Self> {
         loop {
             match Self::new_from_search(uuid) {
                 Err(_) => thread::sleep(Duration::from_secs(1)),
@@ -147,8 +160,12 @@ impl Passphrase {
         &self.0
     }
 
+    pub fn new(passphrase: &str) -> Result<Self> {
+        Ok(Self(CString::new(passphrase)?))
+    }
+
     // blocks indefinitely if no input is available on stdin
-    fn new_from_prompt() -> Result<Self> {
+    pub fn new_from_prompt() -> Result<Self> {
         let passphrase = if stdin().is_terminal() {
             Zeroizing::new(rpassword::prompt_password("Enter passphrase: ")?)
         } else {
@@ -0,0 +1,82 @@
+//! bcachefs-tools as a library
+//!
+//! This is the part of the `bcachefs` command that other programs - installers,
+//! bindings for other languages - need without shelling out to the binary:
+//!
+//! - [`device`]: finding the member devices of a filesystem and reading their
+//!   superblocks
+//! - [`mount`]: splitting mount options into mount flags and filesystem
+//!   options, and mounting
+//! - [`key`]: unlocking encrypted filesystems by adding their key to the
+//!   kernel keyring
+//! - [`target`]: checking target options against a filesystem's devices and
+//!   labels
+//! - [`ffi`]: a C ABI for the above
+//! - [`output`]: `--color` handling and column aligned output, for commands
+//! - [`error`]: the errors callers may want to handle, and the exit codes the
+//!   commands map them to
+//!
+//! A typical mount looks like:
+//!
+//! ```no_run
+//! use bcachefs::{device, key::KeyHandle, key::Passphrase, mount};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let udev_info = device::udev_bcachefs_info()?;
+//! let (devs, sbs) = device::find_devices(&udev_info, "UUID=...")?;
+//!
+//! if device::sb_is_encrypted(&sbs[0]) {
+//!     let passphrase = Passphrase::new("hunter2")?;
+//!     KeyHandle::new(&sbs[0], &passphrase)?;
+//! }
+//!
+//! let (data, flags) = mount::parse_mount_options("noatime,degraded");
+//! mount::mount(devs, "/mnt", "bcachefs", flags, data)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod device;
+pub mod error;
+pub mod ffi;
+pub mod key;
+pub mod mount;
+pub mod output;
+pub mod target;
+
+pub use error::Error;
+
+#[derive(Debug)]
+pub struct ErrnoError(pub errno::Errno);
+impl std::fmt::Display for ErrnoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ErrnoError {}
+
+// FIXME: Can be removed after bumping MSRV >= 1.77 in favor of `c""` literals
+#[macro_export]
+macro_rules! c_str {
+    ($lit:expr) => {
+        ::std::ffi::CStr::from_bytes_with_nul(concat!($lit, "\0").as_bytes())
+            .unwrap()
+            .as_ptr()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    #[test]
+    fn check_cstr_macro() {
+        let literal = c_str!("hello");
+
+        assert_eq!(
+            literal,
+            CStr::from_bytes_with_nul(b"hello\0").unwrap().as_ptr()
+        );
+    }
+}
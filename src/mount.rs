@@ -0,0 +1,369 @@
+//! Mount option handling and mounting
+
+use std::{
+    ffi::{CStr, CString},
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    ptr,
+};
+
+use anyhow::{anyhow, bail};
+
+use bch_bindgen::{bcachefs::bch_sb_handle, c, path_to_cstr};
+use log::{debug, info};
+
+use crate::Error;
+
+/// Newest metadata version the running kernel supports, if it has bcachefs
+/// support loaded
+pub fn kernel_metadata_version() -> Option<u16> {
+    fs::read_to_string("/sys/module/bcachefs/parameters/version")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+}
+
+/// Fails with [`Error::KernelTooOld`] if the running kernel can't mount the
+/// filesystem `sb` is from
+///
+/// Meant for explaining a failed mount: the module is loaded by mount, so
+/// before then, a missing module doesn't mean it isn't available.
+pub fn check_kernel_version(sb: &bch_sb_handle) -> anyhow::Result<()> {
+    let fs_version = u16::from_le(sb.sb().version);
+    let kernel_version = kernel_metadata_version();
+
+    match kernel_version {
+        Some(k) if k >= fs_version => Ok(()),
+        None if Path::new("/sys/module/bcachefs").exists() => Ok(()),
+        _ => Err(Error::KernelTooOld {
+            fs_version,
+            kernel_version,
+        }
+        .into()),
+    }
+}
+
+/// The default mount options set with `bcachefs set-option --mount-options`,
+/// for mounting without options
+pub fn default_mount_options(sb: &bch_sb_handle) -> Option<String> {
+    let opts = unsafe { c::bch_mount_opts_get(sb.sb().user_uuid) };
+    if opts.is_null() {
+        return None;
+    }
+
+    let ret = unsafe { CStr::from_ptr(opts) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { libc::free(opts.cast()) };
+    Some(ret)
+}
+
+/// Options to mount with: `options`, or if none were given, the defaults set
+/// for this filesystem
+pub fn options_or_default(options: &str, sb: &bch_sb_handle) -> String {
+    match options {
+        "" | "defaults" => match default_mount_options(sb) {
+            Some(opts) => {
+                info!("using default mount options: {opts}");
+                opts
+            }
+            None => options.to_owned(),
+        },
+        _ => options.to_owned(),
+    }
+}
+
+/// Mount `src` (a device, or colon separated list of devices) at `target`
+pub fn mount(
+    src: String,
+    target: impl AsRef<std::path::Path>,
+    fstype: &str,
+    mountflags: libc::c_ulong,
+    data: Option<String>,
+) -> anyhow::Result<()> {
+    // bind the CStrings to keep them alive
+    let src = CString::new(src)?;
+    let target = path_to_cstr(target);
+    let data = data.map(CString::new).transpose()?;
+    let fstype = CString::new(fstype)?;
+
+    // convert to pointers for ffi
+    let src = src.as_ptr();
+    let target = target.as_ptr();
+    let data = data.map_or(ptr::null(), |data| data.as_ptr().cast());
+    let fstype = fstype.as_ptr();
+
+    let ret = {
+        info!("mounting filesystem");
+        // REQUIRES: CAP_SYS_ADMIN
+        unsafe { libc::mount(src, target, fstype, mountflags, data) }
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(crate::ErrnoError(errno::errno()).into()),
+    }
+}
+
+/// Options that are for mount(8), systemd or other userspace tools, and that
+/// mount(8) passes on to mount helpers: these never go to the kernel
+pub fn is_userspace_option(o: &str) -> bool {
+    matches!(
+        o,
+        "defaults"
+            | "auto"
+            | "noauto"
+            | "nofail"
+            | "user"
+            | "nouser"
+            | "users"
+            | "owner"
+            | "group"
+            | "_netdev"
+    ) || o.starts_with("x-")
+        || o.starts_with("X-")
+        || o.starts_with("comment=")
+}
+
+/// Parse a comma-separated mount options and split out mountflags and filesystem
+/// specific options; userspace options (see [`is_userspace_option`]) are
+/// dropped.
+pub fn parse_mount_options(options: impl AsRef<str>) -> (Option<String>, libc::c_ulong) {
+    use either::Either::{Left, Right};
+
+    debug!("parsing mount options: {}", options.as_ref());
+    let (opts, flags) = options
+        .as_ref()
+        .split(',')
+        .map(|o| match o {
+            "dirsync" => Left(libc::MS_DIRSYNC),
+            "lazytime" => Left(1 << 25), // MS_LAZYTIME
+            "mand" => Left(libc::MS_MANDLOCK),
+            "noatime" => Left(libc::MS_NOATIME),
+            "nodev" => Left(libc::MS_NODEV),
+            "nodiratime" => Left(libc::MS_NODIRATIME),
+            "noexec" => Left(libc::MS_NOEXEC),
+            "nosuid" => Left(libc::MS_NOSUID),
+            "relatime" => Left(libc::MS_RELATIME),
+            "remount" => Left(libc::MS_REMOUNT),
+            "ro" => Left(libc::MS_RDONLY),
+            "rw" | "" => Left(0),
+            "strictatime" => Left(libc::MS_STRICTATIME),
+            "sync" => Left(libc::MS_SYNCHRONOUS),
+            o if is_userspace_option(o) => Left(0),
+            o => Right(o),
+        })
+        .fold((Vec::new(), 0), |(mut opts, flags), next| match next {
+            Left(f) => (opts, flags | f),
+            Right(o) => {
+                opts.push(o);
+                (opts, flags)
+            }
+        });
+
+    (
+        if opts.is_empty() {
+            None
+        } else {
+            Some(opts.join(","))
+        },
+        flags,
+    )
+}
+
+/// Ownership and permissions for the root of a filesystem once it's mounted,
+/// from util-linux's `X-mount.owner=`, `X-mount.group=` and `X-mount.mode=`
+/// options: for removable drives, so that the desktop user can write to them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MountpointPerms {
+    pub owner: Option<libc::uid_t>,
+    pub group: Option<libc::gid_t>,
+    pub mode:  Option<u32>,
+}
+
+/// A user or group: a name, or a numeric id
+fn lookup_id(s: &str, user: bool) -> anyhow::Result<u32> {
+    if let Ok(id) = s.parse() {
+        return Ok(id);
+    }
+
+    let name = CString::new(s)?;
+    let id = unsafe {
+        if user {
+            libc::getpwnam(name.as_ptr()).as_ref().map(|p| p.pw_uid)
+        } else {
+            libc::getgrnam(name.as_ptr()).as_ref().map(|g| g.gr_gid)
+        }
+    };
+
+    id.ok_or_else(|| anyhow!("unknown {} {s}", if user { "user" } else { "group" }))
+}
+
+impl MountpointPerms {
+    /// Parse from comma separated mount options; other options are ignored
+    pub fn from_options(options: &str) -> anyhow::Result<Self> {
+        let mut ret = Self::default();
+
+        for o in options.split(',') {
+            if let Some(owner) = o.strip_prefix("X-mount.owner=") {
+                ret.owner = Some(lookup_id(owner, true)?);
+            } else if let Some(group) = o.strip_prefix("X-mount.group=") {
+                ret.group = Some(lookup_id(group, false)?);
+            } else if let Some(mode) = o.strip_prefix("X-mount.mode=") {
+                match u32::from_str_radix(mode, 8) {
+                    Ok(mode) if mode <= 0o7777 => ret.mode = Some(mode),
+                    _ => bail!("invalid X-mount.mode {mode}: expected an octal mode"),
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Set the owner, group and mode of `target`, the root of the newly
+    /// mounted filesystem; does nothing if none were given
+    pub fn apply(&self, target: impl AsRef<Path>) -> anyhow::Result<()> {
+        let target = target.as_ref();
+
+        if self.owner.is_some() || self.group.is_some() {
+            let path = path_to_cstr(target);
+            // -1 leaves the owner or group unchanged
+            let ret = unsafe {
+                libc::chown(
+                    path.as_ptr(),
+                    self.owner.unwrap_or(u32::MAX),
+                    self.group.unwrap_or(u32::MAX),
+                )
+            };
+            if ret != 0 {
+                bail!(
+                    "mounted, but error changing owner of {}: {}",
+                    target.display(),
+                    errno::errno()
+                );
+            }
+        }
+
+        if let Some(mode) = self.mode {
+            fs::set_permissions(target, fs::Permissions::from_mode(mode)).map_err(|e| {
+                anyhow!(
+                    "mounted, but error changing mode of {}: {e}",
+                    target.display()
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One line of /etc/fstab
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabEntry {
+    pub spec:    String,
+    pub file:    String,
+    pub vfstype: String,
+    pub mntops:  String,
+    pub passno:  u32,
+}
+
+impl FstabEntry {
+    /// Whether the given option is in this entry's options
+    pub fn has_option(&self, opt: &str) -> bool {
+        self.mntops.split(',').any(|o| o == opt)
+    }
+}
+
+/// Undo the octal escapes (`\040` for a space) of fstab and /proc/mounts
+pub fn fstab_unescape(s: &str) -> String {
+    let mut ret = Vec::with_capacity(s.len());
+    let b = s.as_bytes();
+    let mut i = 0;
+
+    while i < b.len() {
+        let octal = b.get(i + 1..i + 4).and_then(|o| {
+            std::str::from_utf8(o)
+                .ok()
+                .and_then(|o| u8::from_str_radix(o, 8).ok())
+        });
+
+        match (b[i], octal) {
+            (b'\\', Some(c)) => {
+                ret.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                ret.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Parse fstab(5) formatted text
+pub fn parse_fstab(text: &str) -> Vec<FstabEntry> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| {
+            let f: Vec<_> = l.split_whitespace().map(fstab_unescape).collect();
+
+            (f.len() >= 3).then(|| FstabEntry {
+                spec:    f[0].clone(),
+                file:    f[1].clone(),
+                vfstype: f[2].clone(),
+                mntops:  f.get(3).cloned().unwrap_or_else(|| "defaults".into()),
+                passno:  f.get(5).and_then(|p| p.parse().ok()).unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+/// The bcachefs entries in an fstab file
+pub fn read_fstab(path: impl AsRef<Path>) -> anyhow::Result<Vec<FstabEntry>> {
+    Ok(parse_fstab(&fs::read_to_string(path)?)
+        .into_iter()
+        .filter(|e| e.vfstype == "bcachefs")
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn userspace_options_dropped() {
+        let (data, flags) =
+            parse_mount_options("noatime,noauto,x-systemd.automount,_netdev,degraded,nofail");
+
+        assert_eq!(data.as_deref(), Some("degraded"));
+        assert_eq!(flags, libc::MS_NOATIME);
+    }
+
+    #[test]
+    fn mountpoint_perms() {
+        let p =
+            MountpointPerms::from_options("noatime,X-mount.owner=1000,X-mount.mode=0750").unwrap();
+
+        assert_eq!(p.owner, Some(1000));
+        assert_eq!(p.group, None);
+        assert_eq!(p.mode, Some(0o750));
+        assert!(MountpointPerms::from_options("X-mount.mode=rwx").is_err());
+    }
+
+    #[test]
+    fn fstab() {
+        let e = parse_fstab(
+            "# comment\n\
+             UUID=1234 /mnt/my\\040disk bcachefs noatime,x-systemd.automount 0 2\n\
+             /dev/sda1 / ext4 defaults 0 1\n",
+        );
+
+        assert_eq!(e.len(), 2);
+        assert_eq!(e[0].file, "/mnt/my disk");
+        assert_eq!(e[0].passno, 2);
+        assert!(e[0].has_option("x-systemd.automount"));
+        assert_eq!(e[1].vfstype, "ext4");
+    }
+}
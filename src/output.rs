@@ -0,0 +1,167 @@
+//! Terminal output: `--color` handling, severity coloring, and column
+//! formatting
+//!
+//! The C commands have the equivalents in tools-util.h: `color_when_parse()`,
+//! `fprintf_severity()`.
+
+use std::{
+    env, fmt,
+    io::{stdout, IsTerminal},
+};
+
+use colored::{ColoredString, Colorize};
+
+/// When to color output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorWhen {
+    /// If stdout is a terminal, and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorWhen {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorWhen::Auto => stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            ColorWhen::Always => true,
+            ColorWhen::Never => false,
+        }
+    }
+}
+
+/// Resolve `--color`, and the older `--colorize=<bool>` if it was given
+pub fn set_color(when: ColorWhen, colorize: Option<bool>) {
+    colored::control::set_override(colorize.unwrap_or_else(|| when.enabled()));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Fixed,
+    Info,
+}
+
+impl Severity {
+    pub fn paint(self, s: &str) -> ColoredString {
+        match self {
+            Severity::Error => s.bright_red().bold(),
+            Severity::Warning => s.bright_yellow().bold(),
+            Severity::Fixed => s.green(),
+            Severity::Info => s.normal(),
+        }
+    }
+}
+
+/// A table cell; the severity, if any, colors it
+pub struct Cell {
+    text:     String,
+    severity: Option<Severity>,
+}
+
+impl Cell {
+    pub fn new(text: impl Into<String>, severity: Severity) -> Self {
+        Cell {
+            text:     text.into(),
+            severity: Some(severity),
+        }
+    }
+}
+
+impl<T: Into<String>> From<T> for Cell {
+    fn from(text: T) -> Self {
+        Cell {
+            text:     text.into(),
+            severity: None,
+        }
+    }
+}
+
+/// Column aligned output: the first column is left justified, and the rest -
+/// normally numbers - right justified
+///
+/// Widths are computed before coloring, so escape codes don't throw off
+/// alignment.
+#[derive(Default)]
+pub struct Table {
+    header: Option<Vec<String>>,
+    rows:   Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn header<S: Into<String>>(mut self, header: impl IntoIterator<Item = S>) -> Self {
+        self.header = Some(header.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn row<C: Into<Cell>>(&mut self, row: impl IntoIterator<Item = C>) {
+        self.rows.push(row.into_iter().map(Into::into).collect());
+    }
+
+    fn widths(&self) -> Vec<usize> {
+        let header = self
+            .header
+            .iter()
+            .map(|h| h.iter().map(|s| s.chars().count()).collect::<Vec<_>>());
+        let rows = self
+            .rows
+            .iter()
+            .map(|r| r.iter().map(|c| c.text.chars().count()).collect());
+
+        header.chain(rows).fold(Vec::new(), |mut widths, row| {
+            for (i, w) in row.into_iter().enumerate() {
+                match widths.get_mut(i) {
+                    Some(max) => *max = w.max(*max),
+                    None => widths.push(w),
+                }
+            }
+            widths
+        })
+    }
+}
+
+fn write_cell(
+    f: &mut fmt::Formatter,
+    i: usize,
+    width: usize,
+    text: &str,
+    painted: &dyn fmt::Display,
+) -> fmt::Result {
+    let pad = " ".repeat(width.saturating_sub(text.chars().count()));
+
+    if i == 0 {
+        write!(f, "{}{}", painted, pad)
+    } else {
+        write!(f, "  {}{}", pad, painted)
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let widths = self.widths();
+
+        if let Some(header) = &self.header {
+            for (i, h) in header.iter().enumerate() {
+                write_cell(f, i, widths[i], h, &h.bold())?;
+            }
+            writeln!(f)?;
+        }
+
+        for row in &self.rows {
+            for (i, c) in row.iter().enumerate() {
+                match c.severity {
+                    Some(s) => write_cell(f, i, widths[i], &c.text, &s.paint(&c.text))?,
+                    None => write_cell(f, i, widths[i], &c.text, &c.text)?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+//! Checking target options against a filesystem's devices and labels
+//!
+//! `foreground_target`, `background_target`, `promote_target` and
+//! `metadata_target` name a device, or a label: `ssd`, or `ssd.fast` for the
+//! devices labelled `ssd.fast` or anything under it. libbcachefs only finds
+//! out that a target doesn't exist when it goes to use it, and then can't say
+//! much more than "invalid target"; [`Targets`] checks first, and says what's
+//! there instead.
+//!
+//! A label may also be given as a glob - `ssd.*`, `hdd?` - as long as it
+//! matches exactly one label; it's replaced by the label it matches.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bch_bindgen::{bcachefs::bch_sb_handle, sb_parse::Superblock};
+use uuid::Uuid;
+
+/// The options that take a target
+pub const TARGET_OPTS: [&str; 4] = [
+    "foreground_target",
+    "background_target",
+    "promote_target",
+    "metadata_target",
+];
+
+/// Maximum length of each part of a label path (`BCH_SB_LABEL_SIZE`)
+const LABEL_PART_MAX: usize = 32;
+
+pub fn is_target_opt(name: &str) -> bool {
+    TARGET_OPTS.contains(&name)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// `none`, or empty: clears the option
+    None,
+    Device(PathBuf),
+    Label(String),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::None => write!(f, "none"),
+            Target::Device(path) => write!(f, "{}", path.display()),
+            Target::Label(label) => write!(f, "{label}"),
+        }
+    }
+}
+
+/// Check that `label` is a valid label path, as given to `format --label` or
+/// `device set-label`
+pub fn check_label(label: &str) -> Result<()> {
+    if label.is_empty() {
+        bail!("empty label");
+    }
+    if label == "none" {
+        bail!("none can't be used as a label: as a target, it means no target");
+    }
+    if label.contains(&['*', '?'][..]) {
+        bail!("invalid label {label}: labels can't contain * or ?");
+    }
+
+    for part in label.split('.') {
+        if part.is_empty() {
+            bail!("invalid label {label}: each part of a label path must be non-empty");
+        }
+        if part.len() > LABEL_PART_MAX {
+            bail!("invalid label {label}: {part} is longer than {LABEL_PART_MAX} bytes");
+        }
+    }
+
+    Ok(())
+}
+
+/// `*` matches any run of characters within one part of a label path, `?` any
+/// one character
+fn glob_match(pat: &[u8], s: &[u8]) -> bool {
+    match (pat.first(), s.first()) {
+        (None, None) => true,
+        (Some(b'*'), c) => {
+            glob_match(&pat[1..], s) || (c.is_some_and(|&c| c != b'.') && glob_match(pat, &s[1..]))
+        }
+        (Some(b'?'), Some(&c)) => c != b'.' && glob_match(&pat[1..], &s[1..]),
+        (Some(p), Some(c)) => p == c && glob_match(&pat[1..], &s[1..]),
+        _ => false,
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+
+        for j in 0..b.len() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == b[j] {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(cur)
+            };
+            prev = cur;
+        }
+    }
+
+    row[b.len()]
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+/// The devices and labels of one filesystem, that targets may refer to
+#[derive(Clone, Debug, Default)]
+pub struct Targets {
+    devices: Vec<PathBuf>,
+    labels:  Vec<String>,
+}
+
+impl Targets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The labels in a superblock
+    ///
+    /// The superblock doesn't know the paths of the other member devices; add
+    /// those with [`Targets::add_device`].
+    pub fn from_sb(sb: &bch_sb_handle) -> Result<Self> {
+        let sb = Superblock::parse(sb.bytes())?;
+        let mut targets = Self::new();
+
+        let groups: Vec<_> = match sb.disk_groups() {
+            Some(groups) => groups.collect(),
+            None => return Ok(targets),
+        };
+
+        for g in groups
+            .iter()
+            .filter(|g| !g.deleted() && !g.label().is_empty())
+        {
+            let mut path = vec![String::from_utf8_lossy(g.label()).into_owned()];
+            let mut parent = g.parent();
+
+            /* don't loop forever on a corrupt superblock: */
+            while parent != 0 && path.len() <= groups.len() {
+                match groups.get(parent - 1) {
+                    Some(p) => {
+                        path.insert(0, String::from_utf8_lossy(p.label()).into_owned());
+                        parent = p.parent();
+                    }
+                    None => break,
+                }
+            }
+
+            targets.push_label(path.join("."));
+        }
+
+        Ok(targets)
+    }
+
+    /// The devices and labels of a mounted filesystem, from sysfs
+    pub fn from_sysfs(uuid: Uuid) -> Result<Self> {
+        let sysfs = PathBuf::from(format!("/sys/fs/bcachefs/{uuid}"));
+        let mut targets = Self::new();
+
+        for entry in fs::read_dir(&sysfs)? {
+            let dir = entry?.path();
+            if !dir
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with("dev-"))
+            {
+                continue;
+            }
+
+            if let Some(name) = fs::read_link(dir.join("block"))
+                .ok()
+                .and_then(|b| b.file_name().map(|n| n.to_owned()))
+            {
+                targets.add_device(Path::new("/dev").join(name));
+            }
+
+            let label = fs::read_to_string(dir.join("label")).unwrap_or_default();
+            if !label.trim().is_empty() {
+                targets.push_label(label.trim().to_owned());
+            }
+        }
+
+        Ok(targets)
+    }
+
+    pub fn add_device(&mut self, dev: impl Into<PathBuf>) {
+        self.devices.push(dev.into());
+    }
+
+    /// Add a label, checking that it's valid; a device labelled `ssd.fast` is
+    /// also in target `ssd`
+    pub fn add_label(&mut self, label: &str) -> Result<()> {
+        check_label(label)?;
+        self.push_label(label.to_owned());
+        Ok(())
+    }
+
+    fn push_label(&mut self, label: String) {
+        let mut prefix = String::new();
+
+        for part in label.split('.') {
+            if !prefix.is_empty() {
+                prefix.push('.');
+            }
+            prefix.push_str(part);
+
+            if !self.labels.contains(&prefix) {
+                self.labels.push(prefix.clone());
+            }
+        }
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    fn labels_str(&self) -> String {
+        let mut labels = self.labels.clone();
+        labels.sort();
+        labels.join(", ")
+    }
+
+    fn no_such_label(&self, spec: &str) -> anyhow::Error {
+        if self.labels.is_empty() {
+            return anyhow!("no label {spec}: no devices in this filesystem have labels");
+        }
+
+        let closest = self
+            .labels
+            .iter()
+            .map(|l| (edit_distance(spec, l), l))
+            .min()
+            .filter(|(d, _)| *d <= 2);
+
+        match closest {
+            Some((_, l)) => anyhow!(
+                "no label {spec} - did you mean {l}? (labels: {})",
+                self.labels_str()
+            ),
+            None => anyhow!("no label {spec} (labels: {})", self.labels_str()),
+        }
+    }
+
+    fn parse_device(&self, spec: &str) -> Result<Target> {
+        let path = Path::new(spec);
+
+        if !path.exists() {
+            bail!("{spec}: no such device");
+        }
+
+        let path_canonical = canonical(path);
+        if !self.devices.iter().any(|d| canonical(d) == path_canonical) {
+            let members: Vec<_> = self
+                .devices
+                .iter()
+                .map(|d| d.display().to_string())
+                .collect();
+            bail!(
+                "{spec} is not a member of this filesystem (members: {})",
+                members.join(", ")
+            );
+        }
+
+        Ok(Target::Device(path.to_owned()))
+    }
+
+    fn parse_glob(&self, spec: &str) -> Result<Target> {
+        let matches: Vec<_> = self
+            .labels
+            .iter()
+            .filter(|l| glob_match(spec.as_bytes(), l.as_bytes()))
+            .collect();
+
+        match matches[..] {
+            [label] => Ok(Target::Label(label.clone())),
+            [] => Err(self.no_such_label(spec)),
+            _ => {
+                let matches: Vec<_> = matches.iter().map(|l| l.as_str()).collect();
+                bail!("{spec} matches more than one label: {}", matches.join(", "))
+            }
+        }
+    }
+
+    /// Parse a target: `none`, a device path, a label path, or a glob
+    /// matching one label path
+    pub fn parse(&self, spec: &str) -> Result<Target> {
+        if spec.is_empty() || spec == "none" {
+            return Ok(Target::None);
+        }
+        if spec.contains('/') {
+            return self.parse_device(spec);
+        }
+        if spec.contains(&['*', '?'][..]) {
+            return self.parse_glob(spec);
+        }
+
+        check_label(spec)?;
+
+        if self.labels.iter().any(|l| l == spec) {
+            Ok(Target::Label(spec.to_owned()))
+        } else {
+            Err(self.no_such_label(spec))
+        }
+    }
+
+    /// [`Targets::parse`], with the option name in the error
+    pub fn parse_opt(&self, name: &str, spec: &str) -> Result<Target> {
+        self.parse(spec)
+            .map_err(|e| anyhow!("invalid {name}={spec}: {e}"))
+    }
+
+    /// Check the target options in a comma separated list of options, as
+    /// given to mount: returns the options with globs replaced by the labels
+    /// they match
+    pub fn check_opts(&self, opts: &str) -> Result<String> {
+        let opts = opts
+            .split(',')
+            .map(|o| match o.split_once('=') {
+                Some((name, spec)) if is_target_opt(name) => {
+                    Ok(format!("{name}={}", self.parse_opt(name, spec)?))
+                }
+                _ => Ok(o.to_owned()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(opts.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(labels: &[&str]) -> Targets {
+        let mut t = Targets::new();
+        for l in labels {
+            t.add_label(l).unwrap();
+        }
+        t
+    }
+
+    #[test]
+    fn label_syntax() {
+        assert!(check_label("ssd").is_ok());
+        assert!(check_label("ssd.fast").is_ok());
+        assert!(check_label("").is_err());
+        assert!(check_label("none").is_err());
+        assert!(check_label("ssd..fast").is_err());
+        assert!(check_label(".ssd").is_err());
+        assert!(check_label(&"x".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn parse_labels() {
+        let t = targets(&["ssd.fast", "ssd.slow", "hdd"]);
+
+        assert_eq!(t.parse("none").unwrap(), Target::None);
+        assert_eq!(t.parse("").unwrap(), Target::None);
+        assert_eq!(t.parse("ssd").unwrap(), Target::Label("ssd".into()));
+        assert_eq!(
+            t.parse("ssd.fast").unwrap(),
+            Target::Label("ssd.fast".into())
+        );
+
+        let e = t.parse("ssd.fats").unwrap_err().to_string();
+        assert!(e.contains("did you mean ssd.fast"), "{e}");
+        assert!(t.parse("nvme").is_err());
+        assert!(targets(&[]).parse("ssd").is_err());
+    }
+
+    #[test]
+    fn parse_globs() {
+        let t = targets(&["ssd.fast", "ssd.slow", "hdd"]);
+
+        assert_eq!(t.parse("h*").unwrap(), Target::Label("hdd".into()));
+        assert_eq!(t.parse("ssd.f*").unwrap(), Target::Label("ssd.fast".into()));
+        assert_eq!(t.parse("s?d").unwrap(), Target::Label("ssd".into()));
+        /* * doesn't match across a . */
+        assert_eq!(t.parse("ssd*").unwrap(), Target::Label("ssd".into()));
+        assert!(t
+            .parse("ssd.*")
+            .unwrap_err()
+            .to_string()
+            .contains("more than one"));
+        assert!(t.parse("nvme*").is_err());
+    }
+
+    #[test]
+    fn check_mount_opts() {
+        let t = targets(&["ssd", "hdd"]);
+
+        assert_eq!(
+            t.check_opts("noatime,foreground_target=s*,background_target=hdd")
+                .unwrap(),
+            "noatime,foreground_target=ssd,background_target=hdd"
+        );
+        assert!(t.check_opts("promote_target=nvme").is_err());
+    }
+}
@@ -22,6 +22,10 @@ impl BcachefsHandle {
             inner: bcache_fs_open(path.as_ptr()),
         }
     }
+
+    pub(crate) fn uuid(&self) -> uuid::Uuid {
+        uuid::Uuid::from_bytes(self.inner.uuid.b)
+    }
 }
 
 /// I/O control commands that can be sent to a bcachefs filesystem
@@ -65,3 +65,20 @@ def test_list_dirent(tmpdir):
     # u64s 8 type dirent 4096:453699834857023875:U32_MAX len 0 ver 0: lost+found -> 4097 type dir
     last = ret.stdout.splitlines()[0]
     assert re.match(r'^.*type dirent.*: lost\+found ->.*$', last)
+
+def test_fault_inject(tmpdir):
+    dev = util.format_1g(tmpdir)
+
+    # every read fails, including the superblock's:
+    ret = util.run_bch('--fault-inject={}:0'.format(dev), 'fsck', '-n', dev)
+    assert ret.returncode != 0
+
+    # delayed but not failed:
+    ret = util.run_bch('--fault-inject=0+1M,delay=1', 'fsck', '-n', dev)
+    assert ret.returncode == 0
+
+def test_scenarios():
+    ret = util.run_bch('test')
+
+    assert ret.returncode == 0
+    assert "0 failed" in ret.stdout